@@ -119,9 +119,19 @@
             src = ./rust/tool;
             extraArgs = {
               TEST_SYSTEMD = pkgs.systemd;
+              # For signing with a key on a SoftHSM token via the pkcs11 engine of OpenSSL.
+              PKCS11_MODULE_PATH = "${pkgs.softhsm}/lib/softhsm/libsofthsm2.so";
+              OPENSSL_ENGINES = "${pkgs.libp11}/lib/engines";
+              # Also test the async installation.
+              cargoTestExtraArgs = "--features tokio";
               checkInputs = with pkgs; [
                 binutils-unwrapped
                 sbsigntool
+                openssl
+                # For the FAT image ESPs of the library API and --esp-image tests.
+                dosfstools
+                mtools
+                softhsm
               ];
             };
           };
@@ -137,7 +147,7 @@
             # Clean PATH to only contain what we need to do objcopy. Also
             # tell lanzatool where to find our UEFI binaries.
             makeWrapper ${tool}/bin/lzbt $out/bin/lzbt \
-              --set PATH ${lib.makeBinPath [ pkgs.binutils-unwrapped pkgs.sbsigntool ]} \
+              --set PATH ${lib.makeBinPath [ pkgs.binutils-unwrapped pkgs.sbsigntool pkgs.gnutar pkgs.openssl pkgs.dosfstools pkgs.mtools ]} \
               --set RUST_BACKTRACE full \
               --set LANZABOOTE_STUB ${stub}/bin/lanzaboote_stub.efi
           '';
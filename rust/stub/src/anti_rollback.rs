@@ -0,0 +1,64 @@
+//! This module reads the minimum security version that the stub
+//! enforces.
+//!
+//! lanzatool embeds the security version of a generation as the
+//! `.lzbsvn` section and stores the minimum in a UEFI variable with
+//! time-based authenticated write access. The firmware only accepts
+//! updates of such a variable that are signed with the key of its
+//! first write.
+//!
+//! The stub cannot check which key that was. It trusts that the
+//! variable was created by `lzbt setup --enroll`, signed with the db
+//! key, so that the minimum cannot be changed without the db key.
+//! Anybody who created it before could set a minimum that no
+//! generation reaches, which setup detects because it fails to
+//! write the variable then.
+
+use uefi::{
+    cstr16,
+    table::runtime::{RuntimeServices, VariableAttributes, VariableVendor},
+    unsafe_guid, Identify,
+};
+
+/// The vendor GUID of the UEFI variables of lanzaboote.
+#[unsafe_guid("bf6e8bf2-0104-4750-bafa-ea73c3a3e37e")]
+struct LanzabooteVendor;
+
+/// Read the minimum security version from the firmware.
+///
+/// Without the variable, there is no minimum. A variable that can
+/// be written without authentication is ignored, because anybody
+/// could have written it. So is a variable that cannot be read or
+/// that does not hold a 64-bit number: lanzatool never writes such a
+/// variable and treats it as a minimum of 0 as well, and refusing to
+/// boot because of it would let anybody who can write variables
+/// break every generation.
+pub fn minimum_security_version(runtime_services: &RuntimeServices) -> u64 {
+    let name = cstr16!("LanzabooteMinimumSecurityVersion");
+    let vendor = VariableVendor(LanzabooteVendor::GUID);
+
+    // The size is checked first, so that reading the variable
+    // cannot fail because of a too small buffer.
+    match runtime_services.get_variable_size(name, &vendor) {
+        Ok(8) => {}
+        _ => return 0,
+    }
+
+    let mut buf = [0; 8];
+    let (data, attributes) = match runtime_services.get_variable(name, &vendor, &mut buf) {
+        Ok(variable) => variable,
+        Err(_) => return 0,
+    };
+
+    if !attributes.contains(
+        VariableAttributes::NON_VOLATILE
+            | VariableAttributes::TIME_BASED_AUTHENTICATED_WRITE_ACCESS,
+    ) {
+        return 0;
+    }
+
+    match <[u8; 8]>::try_from(data) {
+        Ok(array) => u64::from_le_bytes(array),
+        Err(_) => 0,
+    }
+}
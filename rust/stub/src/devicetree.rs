@@ -0,0 +1,27 @@
+//! This module hands a devicetree to the Linux kernel.
+//!
+//! The kernel's EFI stub looks for the devicetree in the UEFI
+//! configuration table, where firmware on ARM boards usually installs
+//! its own.
+
+use core::{ffi::c_void, ptr};
+
+use uefi::{prelude::BootServices, table::boot::MemoryType, unsafe_guid, Identify, Result};
+
+/// The UEFI configuration table that points to the devicetree.
+#[unsafe_guid("b1b621d5-f19c-41a5-830b-d9152c69aae0")]
+struct DeviceTreeTable;
+
+/// Install a devicetree blob for the kernel, replacing the one of the
+/// firmware.
+///
+/// The blob is copied into memory that stays allocated after the
+/// stub exits, because the kernel only reads it once it is started.
+pub fn install_devicetree(boot_services: &BootServices, devicetree: &[u8]) -> Result {
+    let table = boot_services.allocate_pool(MemoryType::ACPI_RECLAIM, devicetree.len())?;
+
+    unsafe {
+        ptr::copy_nonoverlapping(devicetree.as_ptr(), table, devicetree.len());
+        boot_services.install_configuration_table(&DeviceTreeTable::GUID, table as *const c_void)
+    }
+}
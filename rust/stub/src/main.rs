@@ -5,12 +5,15 @@
 
 extern crate alloc;
 
+mod anti_rollback;
+mod devicetree;
 mod linux_loader;
 mod pe_section;
 mod uefi_helpers;
 
+use alloc::vec::Vec;
 use pe_section::{pe_section, pe_section_as_string};
-use sha2::{Digest, Sha256};
+use sha2::{Digest, Sha256, Sha384, Sha512};
 use uefi::{
     prelude::*,
     proto::{
@@ -22,11 +25,62 @@ use uefi::{
 };
 
 use crate::{
+    anti_rollback::minimum_security_version,
+    devicetree::install_devicetree,
     linux_loader::InitrdLoader,
     uefi_helpers::{booted_image_cmdline, booted_image_file, read_all},
 };
 
-type Hash = sha2::digest::Output<Sha256>;
+type Hash = Vec<u8>;
+
+/// The algorithm of the hashes of the kernel and the initrd.
+///
+/// lanzatool embeds the name of the algorithm as the `.hashalg` (or
+/// `.lzbhalg`) section. Without the section, the hashes are SHA-256 hashes.
+#[derive(Clone, Copy)]
+enum HashAlgorithm {
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl HashAlgorithm {
+    /// Look up an algorithm by its embedded name, e.g. `sha384`.
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sha256" => Some(Self::Sha256),
+            "sha384" => Some(Self::Sha384),
+            "sha512" => Some(Self::Sha512),
+            _ => None,
+        }
+    }
+
+    /// The length of a hash in bytes.
+    fn output_size(self) -> usize {
+        match self {
+            Self::Sha256 => 32,
+            Self::Sha384 => 48,
+            Self::Sha512 => 64,
+        }
+    }
+
+    fn digest(self, data: &[u8]) -> Hash {
+        match self {
+            Self::Sha256 => Sha256::digest(data).to_vec(),
+            Self::Sha384 => Sha384::digest(data).to_vec(),
+            Self::Sha512 => Sha512::digest(data).to_vec(),
+        }
+    }
+}
+
+/// The version of the protocol between lanzatool and the stub.
+///
+/// This describes the sections that lanzatool embeds into the stub
+/// and is checked by lanzatool before it assembles an image. It must
+/// be bumped whenever the section layout changes incompatibly.
+#[used]
+#[link_section = ".lzbtver"]
+static PROTOCOL_VERSION: [u8; 4] = 2u32.to_le_bytes();
 
 /// Print the startup logo on boot.
 fn print_logo(output: &mut Output) -> Result<()> {
@@ -45,26 +99,76 @@ fn print_logo(output: &mut Output) -> Result<()> {
     ))
 }
 
+/// Where the kernel to be booted comes from.
+enum Kernel {
+    /// A file on the volume that contains the lanzaboote binary.
+    File {
+        /// The filename of the kernel. This filename is relative to
+        /// the root of the volume that contains the lanzaboote
+        /// binary.
+        filename: CString16,
+
+        /// The cryptographic hash of the kernel.
+        hash: Hash,
+    },
+
+    /// The `.linux` section of the lanzaboote binary itself. It is
+    /// covered by the signature of the binary, so there is no
+    /// separate hash.
+    Embedded(Vec<u8>),
+}
+
+/// Where the initrd to be passed to the kernel comes from.
+enum Initrd {
+    /// A file on the volume that contains the lanzaboote binary.
+    File {
+        /// The filename of the initrd. This filename is relative to
+        /// the root of the volume that contains the lanzaboote
+        /// binary.
+        filename: CString16,
+
+        /// The cryptographic hash of the initrd. This hash is
+        /// computed over the whole PE binary, not only the embedded
+        /// initrd.
+        hash: Hash,
+
+        /// The number of bytes at the start of the initrd that are
+        /// covered by `hash`. If this is not set, the hash covers the
+        /// whole initrd. Anything after the prefix (e.g. appended
+        /// initrd secrets) is not verified.
+        hash_length: Option<usize>,
+    },
+
+    /// The `.initrd` section of the lanzaboote binary itself. Like
+    /// an embedded kernel, it is covered by the signature of the
+    /// binary.
+    Embedded(Vec<u8>),
+}
+
 /// The configuration that is embedded at build time.
 ///
 /// After lanzaboote is built, lanzatool needs to embed configuration
 /// into the binary. This struct represents that information.
 struct EmbeddedConfiguration {
-    /// The filename of the kernel to be booted. This filename is
-    /// relative to the root of the volume that contains the
-    /// lanzaboote binary.
-    kernel_filename: CString16,
+    /// The kernel to be booted.
+    kernel: Kernel,
+
+    /// The initrd to be passed to the kernel. Kernels that need no
+    /// initrd are booted without one.
+    initrd: Option<Initrd>,
 
-    /// The cryptographic hash of the kernel.
-    kernel_hash: Hash,
+    /// The devicetree to hand to the kernel instead of the one of the
+    /// firmware. It is embedded as the `.dtb` section and thus
+    /// covered by the signature of the binary.
+    devicetree: Option<Vec<u8>>,
 
-    /// The filename of the initrd to be passed to the kernel. See
-    /// `kernel_filename` for how to interpret these filenames.
-    initrd_filename: CString16,
+    /// The security version of the generation, which is embedded as
+    /// the `.lzbsvn` section. Images without one have security
+    /// version 0.
+    security_version: u64,
 
-    /// The cryptographic hash of the initrd. This hash is computed
-    /// over the whole PE binary, not only the embedded initrd.
-    initrd_hash: Hash,
+    /// The algorithm of the hashes of the kernel and the initrd.
+    hash_algorithm: HashAlgorithm,
 }
 
 /// Extract a filename from a PE section. The filename is stored as UTF-8.
@@ -74,27 +178,126 @@ fn extract_filename(file_data: &[u8], section: &str) -> Result<CString16> {
     Ok(CString16::try_from(filename.as_str()).map_err(|_| Status::INVALID_PARAMETER)?)
 }
 
-/// Extract a Blake3 hash from a PE section.
-fn extract_hash(file_data: &[u8], section: &str) -> Result<Hash> {
-    let array: [u8; 32] = pe_section(file_data, section)
-        .ok_or(Status::INVALID_PARAMETER)?
-        .try_into()
-        .map_err(|_| Status::INVALID_PARAMETER)?;
+/// Extract a hash of the given algorithm from a PE section.
+fn extract_hash(file_data: &[u8], section: &str, algorithm: HashAlgorithm) -> Result<Hash> {
+    let hash = pe_section(file_data, section).ok_or(Status::INVALID_PARAMETER)?;
+    if hash.len() != algorithm.output_size() {
+        return Err(Status::INVALID_PARAMETER.into());
+    }
+
+    Ok(hash.to_vec())
+}
+
+/// Extract the algorithm of the embedded hashes from a PE section.
+fn extract_hash_algorithm(file_data: &[u8], section: &str) -> Result<HashAlgorithm> {
+    match pe_section_as_string(file_data, section) {
+        Some(name) => HashAlgorithm::from_name(&name).ok_or(Status::INVALID_PARAMETER.into()),
+        None => Ok(HashAlgorithm::Sha256),
+    }
+}
+
+/// Extract an optional little-endian 64-bit number from a PE section.
+fn extract_u64(file_data: &[u8], section: &str) -> Result<Option<u64>> {
+    let data = match pe_section(file_data, section) {
+        Some(data) => data,
+        None => return Ok(None),
+    };
+
+    let array: [u8; 8] = data.try_into().map_err(|_| Status::INVALID_PARAMETER)?;
+
+    Ok(Some(u64::from_le_bytes(array)))
+}
+
+/// Extract an optional little-endian 64-bit length from a PE section.
+fn extract_length(file_data: &[u8], section: &str) -> Result<Option<usize>> {
+    extract_u64(file_data, section)?
+        .map(|length| usize::try_from(length).map_err(|_| Status::INVALID_PARAMETER.into()))
+        .transpose()
+}
 
-    Ok(array.into())
+/// The names of the lanzaboote-specific sections that lanzatool embeds.
+///
+/// With `--stub-profile namespaced`, lanzatool prefixes them with
+/// `.lzb`, so that they cannot collide with sections of future
+/// systemd-stub versions.
+struct SectionNames {
+    kernel_path: &'static str,
+    kernel_hash: &'static str,
+    initrd_path: &'static str,
+    initrd_hash: &'static str,
+    initrd_length: &'static str,
+    hash_algorithm: &'static str,
+}
+
+impl SectionNames {
+    const CURRENT: Self = Self {
+        kernel_path: ".kernelp",
+        kernel_hash: ".kernelh",
+        initrd_path: ".initrdp",
+        initrd_hash: ".initrdh",
+        initrd_length: ".initrdl",
+        hash_algorithm: ".hashalg",
+    };
+
+    const NAMESPACED: Self = Self {
+        kernel_path: ".lzbkrnp",
+        kernel_hash: ".lzbkrnh",
+        initrd_path: ".lzbinrp",
+        initrd_hash: ".lzbinrh",
+        initrd_length: ".lzbinrl",
+        hash_algorithm: ".lzbhalg",
+    };
+
+    /// Recognize the names by the sections that reference the kernel
+    /// and the initrd.
+    fn detect(file_data: &[u8]) -> &'static Self {
+        let names = &Self::NAMESPACED;
+        if pe_section(file_data, names.kernel_path).is_some()
+            || pe_section(file_data, names.initrd_path).is_some()
+        {
+            names
+        } else {
+            &Self::CURRENT
+        }
+    }
 }
 
 impl EmbeddedConfiguration {
     fn new(file: &mut RegularFile) -> Result<Self> {
         file.set_position(0)?;
         let file_data = read_all(file)?;
+        let names = SectionNames::detect(&file_data);
+        let hash_algorithm = extract_hash_algorithm(&file_data, names.hash_algorithm)?;
 
-        Ok(Self {
-            kernel_filename: extract_filename(&file_data, ".kernelp")?,
-            kernel_hash: extract_hash(&file_data, ".kernelh")?,
+        let kernel = match pe_section(&file_data, ".linux") {
+            Some(kernel_data) => Kernel::Embedded(kernel_data.to_vec()),
+            None => Kernel::File {
+                filename: extract_filename(&file_data, names.kernel_path)?,
+                hash: extract_hash(&file_data, names.kernel_hash, hash_algorithm)?,
+            },
+        };
 
-            initrd_filename: extract_filename(&file_data, ".initrdp")?,
-            initrd_hash: extract_hash(&file_data, ".initrdh")?,
+        Ok(Self {
+            kernel,
+
+            initrd: match (
+                pe_section(&file_data, names.initrd_path),
+                pe_section(&file_data, ".initrd"),
+            ) {
+                (Some(_), _) => Some(Initrd::File {
+                    filename: extract_filename(&file_data, names.initrd_path)?,
+                    hash: extract_hash(&file_data, names.initrd_hash, hash_algorithm)?,
+                    hash_length: extract_length(&file_data, names.initrd_length)?,
+                }),
+                (None, Some(initrd_data)) => Some(Initrd::Embedded(initrd_data.to_vec())),
+                (None, None) => None,
+            },
+
+            devicetree: pe_section(&file_data, ".dtb").map(|devicetree| devicetree.to_vec()),
+
+            security_version: extract_u64(&file_data, ".lzbsvn")?.unwrap_or(0),
+
+            hash_algorithm,
         })
     }
 }
@@ -109,8 +312,20 @@ fn main(handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
         EmbeddedConfiguration::new(&mut booted_image_file(system_table.boot_services()).unwrap())
             .expect("Failed to extract configuration from binary. Did you run lanzatool?");
 
+    if config.security_version < minimum_security_version(system_table.runtime_services()) {
+        system_table
+            .stdout()
+            .output_string(cstr16!(
+                "Security version is lower than the minimum. Refusing to load!\r\n"
+            ))
+            .unwrap();
+        return Status::SECURITY_VIOLATION;
+    }
+
     let kernel_data;
+    let kernel_hash;
     let initrd_data;
+    let initrd_hash;
 
     {
         let mut file_system = system_table
@@ -121,45 +336,75 @@ fn main(handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
             .open_volume()
             .expect("Failed to find ESP root directory");
 
-        let mut kernel_file = root
-            .open(
-                &config.kernel_filename,
-                FileMode::Read,
-                FileAttribute::empty(),
-            )
-            .expect("Failed to open kernel file for reading")
-            .into_regular_file()
-            .expect("Kernel is not a regular file");
-
-        kernel_data = read_all(&mut kernel_file).expect("Failed to read kernel file into memory");
-
-        let mut initrd_file = root
-            .open(
-                &config.initrd_filename,
-                FileMode::Read,
-                FileAttribute::empty(),
-            )
-            .expect("Failed to open initrd for reading")
-            .into_regular_file()
-            .expect("Initrd is not a regular file");
-
-        initrd_data = read_all(&mut initrd_file).expect("Failed to read kernel file into memory");
+        match config.kernel {
+            Kernel::File { filename, hash } => {
+                let mut kernel_file = root
+                    .open(&filename, FileMode::Read, FileAttribute::empty())
+                    .expect("Failed to open kernel file for reading")
+                    .into_regular_file()
+                    .expect("Kernel is not a regular file");
+
+                kernel_data =
+                    read_all(&mut kernel_file).expect("Failed to read kernel file into memory");
+                kernel_hash = Some(hash);
+            }
+            Kernel::Embedded(embedded_kernel_data) => {
+                kernel_data = embedded_kernel_data;
+                kernel_hash = None;
+            }
+        }
+
+        match config.initrd {
+            Some(Initrd::File {
+                filename,
+                hash,
+                hash_length,
+            }) => {
+                let mut initrd_file = root
+                    .open(&filename, FileMode::Read, FileAttribute::empty())
+                    .expect("Failed to open initrd for reading")
+                    .into_regular_file()
+                    .expect("Initrd is not a regular file");
+
+                initrd_data = Some(
+                    read_all(&mut initrd_file).expect("Failed to read initrd file into memory"),
+                );
+                initrd_hash = Some((hash, hash_length));
+            }
+            Some(Initrd::Embedded(embedded_initrd_data)) => {
+                initrd_data = Some(embedded_initrd_data);
+                initrd_hash = None;
+            }
+            None => {
+                initrd_data = None;
+                initrd_hash = None;
+            }
+        }
     }
 
-    if Sha256::digest(&kernel_data) != config.kernel_hash {
-        system_table
-            .stdout()
-            .output_string(cstr16!("Hash mismatch for kernel. Refusing to load!\r\n"))
-            .unwrap();
-        return Status::SECURITY_VIOLATION;
+    if let Some(kernel_hash) = kernel_hash {
+        if config.hash_algorithm.digest(&kernel_data) != kernel_hash {
+            system_table
+                .stdout()
+                .output_string(cstr16!("Hash mismatch for kernel. Refusing to load!\r\n"))
+                .unwrap();
+            return Status::SECURITY_VIOLATION;
+        }
     }
 
-    if Sha256::digest(&initrd_data) != config.initrd_hash {
-        system_table
-            .stdout()
-            .output_string(cstr16!("Hash mismatch for initrd. Refusing to load!\r\n"))
-            .unwrap();
-        return Status::SECURITY_VIOLATION;
+    if let (Some((hash, hash_length)), Some(initrd_data)) = (initrd_hash, &initrd_data) {
+        let hashed_initrd = match hash_length {
+            Some(length) => initrd_data.get(..length),
+            None => Some(&initrd_data[..]),
+        };
+
+        if hashed_initrd.map(|initrd| config.hash_algorithm.digest(initrd)) != Some(hash) {
+            system_table
+                .stdout()
+                .output_string(cstr16!("Hash mismatch for initrd. Refusing to load!\r\n"))
+                .unwrap();
+            return Status::SECURITY_VIOLATION;
+        }
     }
 
     let kernel_cmdline =
@@ -190,15 +435,24 @@ fn main(handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
         );
     }
 
-    let mut initrd_loader = InitrdLoader::new(system_table.boot_services(), handle, initrd_data)
-        .expect("Failed to load the initrd. It may not be there or it is not signed");
+    if let Some(devicetree) = &config.devicetree {
+        install_devicetree(system_table.boot_services(), devicetree)
+            .expect("Failed to install the devicetree");
+    }
+
+    let mut initrd_loader = initrd_data.map(|initrd_data| {
+        InitrdLoader::new(system_table.boot_services(), handle, initrd_data)
+            .expect("Failed to load the initrd. It may not be there or it is not signed")
+    });
     let status = system_table
         .boot_services()
         .start_image(kernel_handle)
         .status();
 
-    initrd_loader
-        .uninstall(system_table.boot_services())
-        .expect("Failed to uninstall the initrd protocols");
+    if let Some(initrd_loader) = &mut initrd_loader {
+        initrd_loader
+            .uninstall(system_table.boot_services())
+            .expect("Failed to uninstall the initrd protocols");
+    }
     status
 }
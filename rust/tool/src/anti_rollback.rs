@@ -0,0 +1,151 @@
+//! Anti-rollback protection.
+//!
+//! Every stub embeds the security version of its generation as a `.lzbsvn` section. The stub
+//! refuses to boot if it is lower than the minimum security version in a UEFI variable, so that
+//! old generations with known vulnerabilities cannot be booted anymore even though they are
+//! validly signed.
+//!
+//! The variable is time-based authenticated. The firmware binds it to the key that signs its first
+//! write and only accepts updates that are signed with the same key. That key is whatever key
+//! created the variable, which the stub cannot check. Anybody who creates the variable first (e.g.
+//! with root access) owns it and could set a minimum that no generation reaches. This is why
+//! `lzbt setup --enroll` creates the variable, signed with the db key, when it enrolls the keys,
+//! and why the installation never creates it: once it exists, nobody without the db key can
+//! change it.
+//!
+//! The remaining trust assumption is that nobody else created the variable before the keys were
+//! enrolled. Creating it fails if it exists and is owned by another key.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use time::OffsetDateTime;
+
+use crate::setup;
+use crate::signature::SigningKey;
+use crate::status;
+use crate::utils::{self, SecureTempDirExt};
+
+/// The vendor GUID of the UEFI variables of lanzaboote.
+pub const LANZABOOTE_VENDOR: &str = "bf6e8bf2-0104-4750-bafa-ea73c3a3e37e";
+
+/// The UEFI variable that stores the minimum security version as a little endian u64.
+pub const MINIMUM_SECURITY_VERSION: &str = "LanzabooteMinimumSecurityVersion";
+
+/// Attributes of the variable: non-volatile, accessible at boot time and at runtime, and only
+/// writable with a time-based authenticated update.
+const ATTRIBUTES: u32 = 0x01 | 0x02 | 0x04 | 0x20;
+
+/// The attributes that the stub requires to trust the variable: non-volatile and time-based
+/// authenticated.
+const TRUSTED_ATTRIBUTES: u32 = 0x01 | 0x20;
+
+/// Read the minimum security version from the firmware. Returns `None` if none is stored yet.
+///
+/// Like the stub, a variable that does not hold a 64-bit number or that can be written without
+/// authentication counts as a minimum of 0.
+pub fn minimum_security_version(efivars: &Path) -> Result<Option<u64>> {
+    let path = efivars.join(format!("{MINIMUM_SECURITY_VERSION}-{LANZABOOTE_VENDOR}"));
+    let contents = match fs::read(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read UEFI variable {path:?}")),
+    };
+
+    // efivarfs shows the attributes in front of the data.
+    let attributes = contents
+        .get(..4)
+        .map(|attributes| u32::from_le_bytes(attributes.try_into().expect("4 bytes")));
+    let data = contents
+        .get(4..)
+        .and_then(|data| <[u8; 8]>::try_from(data).ok());
+    match (attributes, data) {
+        (Some(attributes), Some(data)) if attributes & TRUSTED_ATTRIBUTES == TRUSTED_ATTRIBUTES => {
+            Ok(Some(u64::from_le_bytes(data)))
+        }
+        _ => {
+            println!(
+                "Warning: ignoring the malformed minimum security version in {path:?}, like the stub does."
+            );
+            Ok(Some(0))
+        }
+    }
+}
+
+/// Create the minimum security version in the firmware, signed with the key of the stubs, and
+/// return it.
+///
+/// If the variable exists already, it is written again with the same minimum. The firmware only
+/// accepts this if the variable is owned by the same key, so a variable that somebody else
+/// created is detected.
+pub fn create_minimum_security_version(efivars: &Path, signer: &SigningKey) -> Result<u64> {
+    let minimum = minimum_security_version(efivars)?.unwrap_or(0);
+    write_minimum_security_version(efivars, signer, minimum).context(
+        "Failed to create the minimum security version. If it exists already, it may be owned by \
+         another key, which could prevent every generation from booting",
+    )?;
+    Ok(minimum)
+}
+
+/// Store a new minimum security version in the firmware, signed with the key of the stubs.
+///
+/// The firmware accepts the update only if the time stamp is newer than the one of the previous
+/// update, so the system clock has to be correct.
+pub fn write_minimum_security_version(
+    efivars: &Path,
+    signer: &SigningKey,
+    version: u64,
+) -> Result<()> {
+    let update = setup::time_based_authenticated_update(
+        MINIMUM_SECURITY_VERSION,
+        LANZABOOTE_VENDOR,
+        ATTRIBUTES,
+        &version.to_le_bytes(),
+        OffsetDateTime::now_utc(),
+        |signed| {
+            let tempdir = utils::tempdir()?;
+            let data_file = tempdir.write_secure_file("signed-data", signed)?;
+            let signature_file = tempdir.path().join("signature");
+            signer.sign_detached(&data_file, &signature_file)?;
+            fs::read(&signature_file).context("Failed to read the signature of the update")
+        },
+    )?;
+    setup::write_efi_variable(
+        efivars,
+        MINIMUM_SECURITY_VERSION,
+        LANZABOOTE_VENDOR,
+        ATTRIBUTES,
+        &update,
+    )
+    .context("Failed to store the minimum security version. Is the system clock correct?")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_minimum_security_version() -> Result<()> {
+        let efivars = tempfile::tempdir()?;
+        assert_eq!(minimum_security_version(efivars.path())?, None);
+
+        let path = efivars
+            .path()
+            .join(format!("{MINIMUM_SECURITY_VERSION}-{LANZABOOTE_VENDOR}"));
+        let mut contents = ATTRIBUTES.to_le_bytes().to_vec();
+        contents.extend(7u64.to_le_bytes());
+        fs::write(&path, &contents)?;
+        assert_eq!(minimum_security_version(efivars.path())?, Some(7));
+
+        // The stub ignores malformed and unauthenticated variables, so does lzbt.
+        fs::write(&path, &contents[..8])?;
+        assert_eq!(minimum_security_version(efivars.path())?, Some(0));
+        let mut unauthenticated = 0x07u32.to_le_bytes().to_vec();
+        unauthenticated.extend(7u64.to_le_bytes());
+        fs::write(&path, &unauthenticated)?;
+        assert_eq!(minimum_security_version(efivars.path())?, Some(0));
+        Ok(())
+    }
+}
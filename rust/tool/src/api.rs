@@ -0,0 +1,95 @@
+//! The library API of lzbt.
+//!
+//! It offers the installation, the garbage collection and the verification on any
+//! [`EspFilesystem`], e.g. on a [`FatImage`] in tests that cannot mount an ESP. Each operation
+//! commits the ESP once it succeeded.
+//!
+//! The types of the fields of [`InstallOptions`] are exported as well, so that callers can
+//! configure an installation like the options of install do.
+//!
+//! All operations fail with a [`LanzabooteError`]. The failures that callers may want to handle
+//! specifically have a variant of their own, all others are [`LanzabooteError::Other`] with the
+//! context of what failed, e.g.:
+//!
+//! ```no_run
+//! # fn example(error: lanzaboote_tool::api::LanzabooteError) {
+//! use lanzaboote_tool::api::LanzabooteError;
+//!
+//! if let LanzabooteError::EspFull { needed, .. } = error {
+//!     println!("{needed} bytes are needed on the ESP");
+//! }
+//! # }
+//! ```
+
+use std::path::PathBuf;
+
+use crate::esp::EspPaths;
+use crate::gc;
+use crate::install::Installer;
+
+pub use crate::error::LanzabooteError;
+pub use crate::esp::Architecture;
+pub use crate::esp_fs::{EspFilesystem, FatImage, MountedEsp};
+pub use crate::hook::PostInstallHook;
+pub use crate::install::{
+    BootLoaderEntryMode, CmdlineProfile, FallbackLoader, InitrdHashPolicy, InstallOptions,
+    PcrSigningKey,
+};
+#[cfg(feature = "tokio")]
+pub use crate::install_async::{install_async, InstallProgress, ProgressStream};
+pub use crate::loader_conf::LoaderSetting;
+pub use crate::pe::{
+    HashAlgorithm, ImageContext, ImageSizeLimit, MachineTypePolicy, PeWriter, SectionProvider,
+    StubProfile, StubSource,
+};
+pub use crate::report::ProgressObserver;
+pub use crate::signature::{KeyPair, SigningKey};
+pub use crate::space::ImageCapacity;
+pub use crate::verify::VerifyReport;
+
+/// Install generations to an ESP like install does.
+///
+/// `configuration_limit` is the number of the newest generations to keep (0 keeps all of them).
+pub fn install(
+    esp: &mut impl EspFilesystem,
+    stub: StubSource,
+    signer: SigningKey,
+    configuration_limit: usize,
+    generations: Vec<PathBuf>,
+    options: InstallOptions,
+) -> Result<(), LanzabooteError> {
+    Installer::new(
+        stub,
+        signer,
+        configuration_limit,
+        esp.root().to_path_buf(),
+        generations,
+        InstallOptions {
+            esp_capacity: esp.capacity(),
+            ..options
+        },
+    )
+    .install()?;
+    Ok(esp.commit()?)
+}
+
+/// Remove the files that no installed stub needs from an ESP like gc does.
+pub fn collect_garbage(esp: &mut impl EspFilesystem) -> Result<(), LanzabooteError> {
+    gc::collect_garbage(&EspPaths::new(esp.root(), None))?;
+    Ok(esp.commit()?)
+}
+
+/// Verify the boot loaders and stubs of an ESP like verify does.
+///
+/// The signatures are checked against `public_keys`. Without any, every boot loader and stub only
+/// has to be signed.
+pub fn verify(
+    esp: &impl EspFilesystem,
+    public_keys: &[PathBuf],
+) -> Result<VerifyReport, LanzabooteError> {
+    Ok(crate::verify::verify(
+        &EspPaths::new(esp.root(), None),
+        1,
+        public_keys,
+    )?)
+}
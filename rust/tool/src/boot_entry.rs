@@ -0,0 +1,112 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+/// A Type #1 boot loader entry of the Boot Loader Specification.
+///
+/// Unlike a unified kernel image, the entry references the kernel and initrd as separate files.
+/// systemd-boot lets the firmware verify the signature of the kernel, but the initrd is not
+/// verified at all.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootEntry {
+    pub title: String,
+    pub version: String,
+    /// Path of the kernel relative to the root of the partition the entry is stored on.
+    pub linux: String,
+    /// Path of the initrd relative to the root of the partition the entry is stored on.
+    pub initrd: Option<String>,
+    pub options: Vec<String>,
+}
+
+/// Display the entry in the format of a `loader/entries/*.conf` file.
+impl fmt::Display for BootEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "title {}", self.title)?;
+        writeln!(f, "version {}", self.version)?;
+        writeln!(f, "linux {}", self.linux)?;
+        if let Some(initrd) = &self.initrd {
+            writeln!(f, "initrd {initrd}")?;
+        }
+        writeln!(f, "options {}", self.options.join(" "))
+    }
+}
+
+/// Convert a path on a partition to the form a boot loader entry references it by.
+///
+/// The Boot Loader Specification uses paths relative to the root of the partition with `/` as
+/// separator.
+pub fn partition_relative_path(partition: &Path, path: &Path) -> Result<String> {
+    let relative_path = path
+        .strip_prefix(partition)
+        .with_context(|| format!("{path:?} is not on the partition {partition:?}"))?;
+    let components = relative_path
+        .iter()
+        .map(|component| {
+            component
+                .to_str()
+                .with_context(|| format!("{path:?} is not valid UTF-8"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(format!("/{}", components.join("/")))
+}
+
+/// The kernel and initrd that a boot loader entry on the partition `boot` references.
+pub fn referenced_files(entry: &Path, boot: &Path) -> Result<Vec<PathBuf>> {
+    let contents = fs::read_to_string(entry)
+        .with_context(|| format!("Failed to read boot loader entry {entry:?}"))?;
+    Ok(contents
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .filter(|(key, _)| matches!(*key, "linux" | "initrd"))
+        .map(|(_, path)| boot.join(path.trim().trim_start_matches('/')))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_boot_entry() -> Result<()> {
+        let entry = BootEntry {
+            title: String::from("NixOS"),
+            version: String::from("Generation 1"),
+            linux: partition_relative_path(
+                Path::new("/boot"),
+                Path::new("/boot/EFI/nixos/kernel.efi"),
+            )?,
+            initrd: None,
+            options: vec![String::from("init=/init"), String::from("quiet")],
+        };
+        assert_eq!(
+            entry.to_string(),
+            "title NixOS\nversion Generation 1\nlinux /EFI/nixos/kernel.efi\noptions init=/init quiet\n"
+        );
+        assert!(partition_relative_path(Path::new("/efi"), Path::new("/boot/kernel.efi")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn read_referenced_files() -> Result<()> {
+        let boot = tempfile::tempdir()?;
+        let entry = BootEntry {
+            title: String::from("NixOS"),
+            version: String::from("Generation 1"),
+            linux: String::from("/EFI/nixos/kernel.efi"),
+            initrd: Some(String::from("/EFI/nixos/initrd.efi")),
+            options: vec![String::from("init=/init")],
+        };
+        let path = boot.path().join("entry.conf");
+        fs::write(&path, entry.to_string())?;
+        assert_eq!(
+            referenced_files(&path, boot.path())?,
+            [
+                boot.path().join("EFI/nixos/kernel.efi"),
+                boot.path().join("EFI/nixos/initrd.efi")
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,556 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::setup;
+use crate::status::{self, EFI_GLOBAL_VARIABLE};
+
+/// Attributes of the boot options and of `BootOrder`: non-volatile and accessible at boot time and
+/// at runtime.
+const BOOT_VARIABLE_ATTRIBUTES: u32 = 0x01 | 0x02 | 0x04;
+
+/// Attribute of an `EFI_LOAD_OPTION` that makes the firmware consider it for booting.
+const LOAD_OPTION_ACTIVE: u32 = 0x01;
+
+/// Type and subtype of the hard drive media device path node.
+const MEDIA_HARDDRIVE_DP: (u8, u8) = (0x04, 0x01);
+/// Type and subtype of the file path media device path node.
+const MEDIA_FILEPATH_DP: (u8, u8) = (0x04, 0x04);
+/// The node that terminates a device path.
+const END_ENTIRE_DEVICE_PATH: [u8; 4] = [0x7f, 0xff, 0x04, 0x00];
+
+/// The directory that the stubs are installed to, as the firmware sees it.
+const STUB_DIRECTORY: &str = "\\EFI\\Linux\\";
+
+/// The GPT partition that the firmware loads a file from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Partition {
+    /// The number of the partition in the partition table, starting at 1.
+    pub number: u32,
+    /// The first logical block of the partition.
+    pub start: u64,
+    /// The size of the partition in logical blocks.
+    pub size: u64,
+    /// The unique partition GUID in its binary (mixed endian) representation.
+    pub guid: [u8; 16],
+}
+
+impl Partition {
+    /// Find the partition that the filesystem of a path is stored on.
+    ///
+    /// The position of the partition is read from sysfs and its GUID from the symlinks that udev
+    /// creates in `/dev/disk/by-partuuid`.
+    pub fn of(path: &Path) -> Result<Self> {
+        let device = fs::metadata(path)
+            .with_context(|| format!("Failed to read {path:?}"))?
+            .dev();
+        let sysfs = PathBuf::from(format!(
+            "/sys/dev/block/{}:{}",
+            device_major(device),
+            device_minor(device)
+        ));
+        let read_number = |name: &str| -> Result<u64> {
+            let path = sysfs.join(name);
+            fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read {path:?}"))?
+                .trim()
+                .parse()
+                .with_context(|| format!("Malformed {path:?}"))
+        };
+
+        let number = read_number("partition")
+            .with_context(|| format!("{path:?} is not stored on a partition"))?;
+        // sysfs counts in sectors of 512 bytes, the firmware in logical blocks of the disk.
+        let block_size = read_number("../queue/logical_block_size")?;
+        if block_size < 512 || block_size % 512 != 0 {
+            return Err(anyhow::anyhow!(
+                "Unsupported logical block size {block_size}"
+            ));
+        }
+
+        Ok(Self {
+            number: u32::try_from(number).context("Partition number is out of range")?,
+            start: read_number("start")? * 512 / block_size,
+            size: read_number("size")? * 512 / block_size,
+            guid: partition_guid(device)
+                .with_context(|| format!("{path:?} is not stored on a GPT partition"))?,
+        })
+    }
+}
+
+/// The unique GUID of the partition with a device number, as udev names it.
+fn partition_guid(device: u64) -> Result<[u8; 16]> {
+    let by_partuuid = Path::new("/dev/disk/by-partuuid");
+    for entry in
+        fs::read_dir(by_partuuid).with_context(|| format!("Failed to read {by_partuuid:?}"))?
+    {
+        let entry = entry.with_context(|| format!("Failed to read {by_partuuid:?}"))?;
+        let metadata = match fs::metadata(entry.path()) {
+            Ok(metadata) => metadata,
+            // Dangling symlinks of removed disks are of no interest.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}", entry.path())),
+        };
+        if metadata.file_type().is_block_device() && metadata.rdev() == device {
+            // MBR partitions have an ID like 1234abcd-01 instead of a GUID.
+            return setup::parse_guid(&entry.file_name().to_string_lossy());
+        }
+    }
+    Err(anyhow::anyhow!(
+        "No partition GUID found in {by_partuuid:?}"
+    ))
+}
+
+/// The major number of a device number in the encoding of glibc.
+fn device_major(device: u64) -> u64 {
+    ((device >> 8) & 0xfff) | ((device >> 32) & !0xfff)
+}
+
+/// The minor number of a device number in the encoding of glibc.
+fn device_minor(device: u64) -> u64 {
+    (device & 0xff) | ((device >> 12) & !0xff)
+}
+
+/// A boot option that the firmware boots a stub with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootOption {
+    /// The name the firmware shows in its boot menu.
+    pub description: String,
+    /// The UEFI path of the stub on its partition, e.g. `\EFI\Linux\nixos-generation-1.efi`.
+    pub path: String,
+}
+
+impl BootOption {
+    /// Encode the boot option as an `EFI_LOAD_OPTION`.
+    fn to_load_option(&self, partition: &Partition) -> Vec<u8> {
+        let mut hard_drive = partition.number.to_le_bytes().to_vec();
+        hard_drive.extend_from_slice(&partition.start.to_le_bytes());
+        hard_drive.extend_from_slice(&partition.size.to_le_bytes());
+        hard_drive.extend_from_slice(&partition.guid);
+        // The partition is part of a GPT and identified by its GUID.
+        hard_drive.extend_from_slice(&[0x02, 0x02]);
+
+        let mut device_path = device_path_node(MEDIA_HARDDRIVE_DP, &hard_drive);
+        device_path.extend(device_path_node(
+            MEDIA_FILEPATH_DP,
+            &encode_utf16(&self.path),
+        ));
+        device_path.extend_from_slice(&END_ENTIRE_DEVICE_PATH);
+
+        let mut load_option = LOAD_OPTION_ACTIVE.to_le_bytes().to_vec();
+        load_option.extend_from_slice(
+            &u16::try_from(device_path.len())
+                .expect("The device path of a boot option is short")
+                .to_le_bytes(),
+        );
+        load_option.extend(encode_utf16(&self.description));
+        load_option.extend(device_path);
+        load_option
+    }
+}
+
+fn device_path_node((node_type, subtype): (u8, u8), data: &[u8]) -> Vec<u8> {
+    let length = u16::try_from(4 + data.len()).expect("A device path node is short");
+    let mut node = vec![node_type, subtype];
+    node.extend_from_slice(&length.to_le_bytes());
+    node.extend_from_slice(data);
+    node
+}
+
+/// The nodes of the device path of an `EFI_LOAD_OPTION`, up to the first malformed one.
+fn device_path_nodes(load_option: &[u8]) -> Option<impl Iterator<Item = &[u8]>> {
+    let device_path_length =
+        usize::from(u16::from_le_bytes(load_option.get(4..6)?.try_into().ok()?));
+    let description_length = load_option
+        .get(6..)?
+        .chunks_exact(2)
+        .position(|c| c == [0, 0])?;
+    let start = 6 + 2 * (description_length + 1);
+    let mut device_path = load_option.get(start..start + device_path_length)?;
+
+    Some(std::iter::from_fn(move || {
+        let length = usize::from(u16::from_le_bytes(device_path.get(2..4)?.try_into().ok()?));
+        let node = device_path.get(..length).filter(|_| length >= 4)?;
+        device_path = &device_path[length..];
+        Some(node)
+    }))
+}
+
+/// The UEFI path of the file that an `EFI_LOAD_OPTION` boots, if it names one.
+fn load_option_path(load_option: &[u8]) -> Option<String> {
+    device_path_nodes(load_option)?
+        .find(|node| (node[0], node[1]) == MEDIA_FILEPATH_DP)
+        .and_then(|node| decode_utf16(&node[4..]))
+}
+
+/// The unique GUID of the partition that an `EFI_LOAD_OPTION` boots from, if it names one.
+fn load_option_partition_guid(load_option: &[u8]) -> Option<[u8; 16]> {
+    device_path_nodes(load_option)?
+        .find(|node| (node[0], node[1]) == MEDIA_HARDDRIVE_DP)
+        // The node header, the partition number, start and size precede the signature.
+        .and_then(|node| node.get(24..40)?.try_into().ok())
+}
+
+/// Whether a boot option boots a stub that lanzaboote installed on the partition with `guid`.
+///
+/// The stubs of other ESPs (e.g. of a mirrored ESP or of another NixOS on a second disk) have the
+/// same paths, so the partition has to match as well.
+fn is_own_stub(load_option: &[u8], path: &str, guid: &[u8; 16]) -> bool {
+    is_stub_path(path) && load_option_partition_guid(load_option).as_ref() == Some(guid)
+}
+
+/// Whether a path is the path of a stub that lanzaboote installs.
+fn is_stub_path(path: &str) -> bool {
+    path.len() > STUB_DIRECTORY.len()
+        && path.is_char_boundary(STUB_DIRECTORY.len())
+        && path[..STUB_DIRECTORY.len()].eq_ignore_ascii_case(STUB_DIRECTORY)
+        && path[STUB_DIRECTORY.len()..].starts_with("nixos-")
+}
+
+/// Make the boot options of the firmware match the stubs that are installed.
+///
+/// Every stub gets a boot option on `partition`. Boot options of stubs on `partition` that are gone
+/// are removed, all other ones (e.g. of other operating systems or other ESPs) are kept. The stubs
+/// move to the front of `BootOrder` in the given order, so the first one is booted by default.
+/// Variables are only written if they change.
+pub fn sync_boot_options(
+    efivars: &Path,
+    partition: &Partition,
+    stubs: &[BootOption],
+) -> Result<()> {
+    let existing = boot_options(efivars)?;
+    let mut used: BTreeSet<u16> = existing.iter().map(|(number, _)| *number).collect();
+    let mut stale: Vec<(u16, String)> = existing
+        .iter()
+        .filter_map(|(number, load_option)| {
+            load_option_path(load_option)
+                .filter(|path| is_own_stub(load_option, path, &partition.guid))
+                .map(|path| (*number, path))
+        })
+        .collect();
+
+    let mut stub_order = Vec::new();
+    for stub in stubs {
+        let load_option = stub.to_load_option(partition);
+        let number = match stale
+            .iter()
+            .position(|(_, path)| path.eq_ignore_ascii_case(&stub.path))
+        {
+            Some(position) => stale.remove(position).0,
+            None => {
+                let number = (0..=u16::MAX)
+                    .find(|number| !used.contains(number))
+                    .context("No free boot option number left")?;
+                used.insert(number);
+                number
+            }
+        };
+
+        let name = boot_option_name(number);
+        let unchanged = existing
+            .iter()
+            .any(|(existing, data)| *existing == number && *data == load_option);
+        if !unchanged {
+            println!("Writing boot option {name} for {}...", stub.path);
+            setup::write_efi_variable(
+                efivars,
+                &name,
+                EFI_GLOBAL_VARIABLE,
+                BOOT_VARIABLE_ATTRIBUTES,
+                &load_option,
+            )?;
+        }
+        stub_order.push(number);
+    }
+
+    for (number, path) in &stale {
+        let name = boot_option_name(*number);
+        println!("Removing boot option {name} for {path}...");
+        setup::remove_efi_variable(efivars, &name, EFI_GLOBAL_VARIABLE)?;
+    }
+
+    let boot_order = read_boot_order(efivars)?;
+    let new_boot_order: Vec<u16> = stub_order
+        .iter()
+        .copied()
+        .chain(boot_order.iter().copied().filter(|number| {
+            !stub_order.contains(number) && !stale.iter().any(|(stale, _)| stale == number)
+        }))
+        .collect();
+    if new_boot_order != boot_order {
+        write_boot_order(efivars, &new_boot_order)?;
+    }
+    Ok(())
+}
+
+/// Remove the boot options of the stubs that are no longer installed, e.g. after an uninstall.
+///
+/// `boot` is the mountpoint of `partition`, which the stubs were installed to. Boot options of
+/// other operating systems, of other partitions and of stubs that still exist are kept.
+pub fn remove_stale_boot_options(efivars: &Path, partition: &Partition, boot: &Path) -> Result<()> {
+    let stale: Vec<(u16, String)> = boot_options(efivars)?
+        .iter()
+        .filter_map(|(number, load_option)| {
+            load_option_path(load_option)
+                .filter(|path| {
+                    is_own_stub(load_option, path, &partition.guid)
+                        && !boot.join(local_path(path)).exists()
+                })
+                .map(|path| (*number, path))
+        })
+        .collect();
+
+    for (number, path) in &stale {
+        let name = boot_option_name(*number);
+        println!("Removing boot option {name} for {path}...");
+        setup::remove_efi_variable(efivars, &name, EFI_GLOBAL_VARIABLE)?;
+    }
+
+    let boot_order = read_boot_order(efivars)?;
+    let new_boot_order: Vec<u16> = boot_order
+        .iter()
+        .copied()
+        .filter(|number| !stale.iter().any(|(stale, _)| stale == number))
+        .collect();
+    if new_boot_order != boot_order {
+        write_boot_order(efivars, &new_boot_order)?;
+    }
+    Ok(())
+}
+
+/// The path of a file relative to the root of its partition, given its UEFI path.
+fn local_path(path: &str) -> PathBuf {
+    PathBuf::from(path.trim_start_matches('\\').replace('\\', "/"))
+}
+
+/// The name of the variable of a boot option, e.g. `Boot000A`.
+fn boot_option_name(number: u16) -> String {
+    format!("Boot{number:04X}")
+}
+
+/// Read all boot options (`Boot####`) with their numbers.
+fn boot_options(efivars: &Path) -> Result<Vec<(u16, Vec<u8>)>> {
+    let suffix = format!("-{EFI_GLOBAL_VARIABLE}");
+    let mut numbers = Vec::new();
+    for entry in fs::read_dir(efivars).with_context(|| format!("Failed to read {efivars:?}"))? {
+        let file_name = entry
+            .with_context(|| format!("Failed to read {efivars:?}"))?
+            .file_name();
+        let number = file_name
+            .to_str()
+            .and_then(|name| name.strip_suffix(&suffix))
+            .and_then(|name| name.strip_prefix("Boot"))
+            .filter(|number| {
+                number.len() == 4 && number.chars().all(|c| matches!(c, '0'..='9' | 'A'..='F'))
+            })
+            .and_then(|number| u16::from_str_radix(number, 16).ok());
+        numbers.extend(number);
+    }
+    numbers.sort_unstable();
+
+    let mut options = Vec::new();
+    for number in numbers {
+        let name = boot_option_name(number);
+        if let Some(data) = status::read_efi_variable(efivars, &name, EFI_GLOBAL_VARIABLE)? {
+            options.push((number, data));
+        }
+    }
+    Ok(options)
+}
+
+fn read_boot_order(efivars: &Path) -> Result<Vec<u16>> {
+    Ok(
+        status::read_efi_variable(efivars, "BootOrder", EFI_GLOBAL_VARIABLE)?
+            .unwrap_or_default()
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect(),
+    )
+}
+
+fn write_boot_order(efivars: &Path, boot_order: &[u16]) -> Result<()> {
+    let data: Vec<u8> = boot_order
+        .iter()
+        .flat_map(|number| number.to_le_bytes())
+        .collect();
+    setup::write_efi_variable(
+        efivars,
+        "BootOrder",
+        EFI_GLOBAL_VARIABLE,
+        BOOT_VARIABLE_ATTRIBUTES,
+        &data,
+    )
+}
+
+/// Encode a string as NUL terminated UCS-2 as UEFI expects it.
+fn encode_utf16(s: &str) -> Vec<u8> {
+    s.encode_utf16()
+        .chain([0])
+        .flat_map(u16::to_le_bytes)
+        .collect()
+}
+
+fn decode_utf16(data: &[u8]) -> Option<String> {
+    let units: Vec<u16> = data
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|&c| c != 0)
+        .collect();
+    String::from_utf16(&units).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PARTITION: Partition = Partition {
+        number: 1,
+        start: 2048,
+        size: 1048576,
+        guid: [
+            0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e,
+            0xc9, 0x3b,
+        ],
+    };
+
+    fn stub(version: u64) -> BootOption {
+        BootOption {
+            description: format!("nixos-generation-{version}"),
+            path: format!("\\EFI\\Linux\\nixos-generation-{version}.efi"),
+        }
+    }
+
+    fn write_boot_variable(efivars: &Path, name: &str, data: &[u8]) -> Result<()> {
+        setup::write_efi_variable(
+            efivars,
+            name,
+            EFI_GLOBAL_VARIABLE,
+            BOOT_VARIABLE_ATTRIBUTES,
+            data,
+        )
+    }
+
+    #[test]
+    fn encode_load_option() {
+        let load_option = stub(1).to_load_option(&PARTITION);
+        assert_eq!(load_option[..4], LOAD_OPTION_ACTIVE.to_le_bytes());
+        // The hard drive node is 42 bytes long, the file path node 4 bytes plus the path.
+        let path_length = 2 * ("\\EFI\\Linux\\nixos-generation-1.efi".len() + 1);
+        assert_eq!(
+            usize::from(u16::from_le_bytes([load_option[4], load_option[5]])),
+            42 + 4 + path_length + 4
+        );
+        assert!(load_option.ends_with(&END_ENTIRE_DEVICE_PATH));
+        assert_eq!(
+            load_option_path(&load_option).as_deref(),
+            Some("\\EFI\\Linux\\nixos-generation-1.efi")
+        );
+        assert_eq!(load_option_path(&load_option[..10]), None);
+        assert_eq!(
+            load_option_partition_guid(&load_option),
+            Some(PARTITION.guid)
+        );
+    }
+
+    #[test]
+    fn recognize_stub_paths() {
+        assert!(is_stub_path("\\EFI\\Linux\\nixos-generation-1.efi"));
+        assert!(is_stub_path("\\efi\\linux\\nixos-generation-1.efi"));
+        assert!(!is_stub_path("\\EFI\\Linux\\arch-linux.efi"));
+        assert!(!is_stub_path("\\EFI\\BOOT\\BOOTX64.EFI"));
+    }
+
+    #[test]
+    fn sync_boot_options_with_stubs() -> Result<()> {
+        let efivars = tempfile::tempdir()?;
+        let other = BootOption {
+            description: String::from("Windows Boot Manager"),
+            path: String::from("\\EFI\\Microsoft\\Boot\\bootmgfw.efi"),
+        };
+        write_boot_variable(
+            efivars.path(),
+            "Boot0000",
+            &other.to_load_option(&PARTITION),
+        )?;
+        write_boot_variable(
+            efivars.path(),
+            "Boot0001",
+            &stub(1).to_load_option(&PARTITION),
+        )?;
+        // The stub of a mirrored ESP on another disk.
+        let mirror = Partition {
+            guid: [0xaa; 16],
+            ..PARTITION
+        };
+        write_boot_variable(efivars.path(), "Boot0004", &stub(1).to_load_option(&mirror))?;
+        write_boot_variable(efivars.path(), "BootOrder", &[0, 0, 1, 0, 4, 0])?;
+
+        sync_boot_options(efivars.path(), &PARTITION, &[stub(3), stub(2)])?;
+
+        let options = boot_options(efivars.path())?;
+        let paths: Vec<(u16, Option<String>)> = options
+            .iter()
+            .map(|(number, data)| (*number, load_option_path(data)))
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                (0, Some(other.path.clone())),
+                (2, Some(stub(3).path)),
+                (3, Some(stub(2).path)),
+                (4, Some(stub(1).path)),
+            ]
+        );
+        assert_eq!(read_boot_order(efivars.path())?, [2, 3, 0, 4]);
+
+        // The boot options of the stubs that are still installed keep their numbers.
+        sync_boot_options(efivars.path(), &PARTITION, &[stub(4), stub(3)])?;
+        assert_eq!(read_boot_order(efivars.path())?, [1, 2, 0, 4]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn remove_boot_options_of_removed_stubs() -> Result<()> {
+        let efivars = tempfile::tempdir()?;
+        let boot = tempfile::tempdir()?;
+        fs::create_dir_all(boot.path().join("EFI/Linux"))?;
+        fs::write(boot.path().join("EFI/Linux/nixos-generation-2.efi"), "")?;
+        sync_boot_options(efivars.path(), &PARTITION, &[stub(2), stub(1)])?;
+        let other_disk = Partition {
+            guid: [0xaa; 16],
+            ..PARTITION
+        };
+        write_boot_variable(
+            efivars.path(),
+            "Boot0002",
+            &stub(1).to_load_option(&other_disk),
+        )?;
+        write_boot_variable(efivars.path(), "BootOrder", &[0, 0, 1, 0, 2, 0, 5, 0])?;
+
+        remove_stale_boot_options(efivars.path(), &PARTITION, boot.path())?;
+
+        let paths: Vec<(u16, Option<String>)> = boot_options(efivars.path())?
+            .iter()
+            .map(|(number, data)| (*number, load_option_path(data)))
+            .collect();
+        assert_eq!(paths, [(0, Some(stub(2).path)), (2, Some(stub(1).path))]);
+        assert_eq!(read_boot_order(efivars.path())?, [0, 2, 5]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn split_device_numbers() {
+        // 259:3, e.g. nvme0n1p3
+        let device = (259 << 8) | 3;
+        assert_eq!((device_major(device), device_minor(device)), (259, 3));
+        // 8:273, a minor number above 255
+        let device = (8 << 8) | (1 << 20) | 17;
+        assert_eq!((device_major(device), device_minor(device)), (8, 273));
+    }
+}
@@ -0,0 +1,141 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{Context, Result};
+use walkdir::WalkDir;
+
+use crate::esp::{self, EspPaths};
+use crate::pe;
+use crate::status::{EFI_GLOBAL_VARIABLE, IMAGE_SECURITY_DATABASE, LOADER_VARIABLE};
+use crate::utils;
+
+/// The UEFI variables that describe the boot process, as names and vendor GUIDs.
+///
+/// Variables that hold secrets (e.g. `LoaderSystemToken`, which seeds the random seed) are
+/// deliberately absent.
+const BUNDLED_VARIABLES: [(&str, &str); 16] = [
+    ("SecureBoot", EFI_GLOBAL_VARIABLE),
+    ("SetupMode", EFI_GLOBAL_VARIABLE),
+    ("OsIndications", EFI_GLOBAL_VARIABLE),
+    ("OsIndicationsSupported", EFI_GLOBAL_VARIABLE),
+    ("PK", EFI_GLOBAL_VARIABLE),
+    ("KEK", EFI_GLOBAL_VARIABLE),
+    ("db", IMAGE_SECURITY_DATABASE),
+    ("dbx", IMAGE_SECURITY_DATABASE),
+    ("LoaderEntrySelected", LOADER_VARIABLE),
+    ("LoaderEntryDefault", LOADER_VARIABLE),
+    ("LoaderEntryOneShot", LOADER_VARIABLE),
+    ("LoaderEntries", LOADER_VARIABLE),
+    ("LoaderInfo", LOADER_VARIABLE),
+    ("LoaderFirmwareInfo", LOADER_VARIABLE),
+    ("LoaderFirmwareType", LOADER_VARIABLE),
+    ("StubInfo", LOADER_VARIABLE),
+];
+
+/// Collect the files lanzaboote manages on the ESP and the relevant UEFI variables into a tarball.
+///
+/// Nothing is modified, so the bundle can be created on a system that fails to boot its newest
+/// generation. The files keep their paths relative to the partition they are on below `esp/` and
+/// `xbootldr/`, the UEFI variables are stored as efivarfs files below `efivars/`. The random seed
+/// is never included. Neither are the initrds and the stubs with an embedded initrd, which may
+/// contain the initrd secrets (e.g. the host key of an SSH server in the initrd), unless
+/// `include_secrets` is set.
+pub fn collect_bundle(
+    esp_paths: &EspPaths,
+    efivars: &Path,
+    out: &Path,
+    include_secrets: bool,
+) -> Result<()> {
+    let staging = utils::tempdir()?;
+
+    for path in managed_files(esp_paths)? {
+        if !include_secrets && may_contain_initrd_secrets(esp_paths, &path) {
+            println!(
+                "Leaving out {}, which may contain initrd secrets (see --include-secrets)",
+                path.display()
+            );
+            continue;
+        }
+        let (partition, root) =
+            if esp_paths.boot != esp_paths.esp && path.starts_with(&esp_paths.boot) {
+                ("xbootldr", &esp_paths.boot)
+            } else {
+                ("esp", &esp_paths.esp)
+            };
+        let relative = path
+            .strip_prefix(root)
+            .with_context(|| format!("{path:?} is not on the partition {root:?}"))?;
+        stage(&path, &staging.path().join(partition).join(relative))?;
+    }
+
+    for (name, vendor) in BUNDLED_VARIABLES {
+        let file_name = format!("{name}-{vendor}");
+        let path = efivars.join(&file_name);
+        if path.exists() {
+            stage(&path, &staging.path().join("efivars").join(file_name))?;
+        }
+    }
+
+    let output = Command::new("tar")
+        .arg("--create")
+        .arg("--file")
+        .arg(out)
+        .arg("--directory")
+        .arg(staging.path())
+        .arg(".")
+        .output()
+        .context("Failed to run tar")?;
+    if !output.status.success() {
+        io::stderr()
+            .write_all(&output.stderr)
+            .context("Failed to write output of tar to stderr")?;
+        return Err(anyhow::anyhow!("Failed to write bundle to {out:?}"));
+    }
+    Ok(())
+}
+
+/// The files on the ESP (and XBOOTLDR partition) that lanzaboote manages.
+fn managed_files(esp_paths: &EspPaths) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    // Lanzaboote takes full control over the EFI/nixos directory, which also holds the manifest.
+    if esp_paths.nixos.exists() {
+        for entry in WalkDir::new(&esp_paths.nixos) {
+            let entry = entry.with_context(|| format!("Failed to read {:?}", esp_paths.nixos))?;
+            if entry.file_type().is_file() {
+                files.push(entry.into_path());
+            }
+        }
+    }
+    files.extend(esp::nixos_images(&esp_paths.linux)?);
+    files.extend(
+        [&esp_paths.systemd_boot, &esp_paths.loader_conf]
+            .into_iter()
+            .chain(esp_paths.efi_fallbacks.values())
+            .filter(|path| path.exists())
+            .cloned(),
+    );
+    Ok(files)
+}
+
+/// Whether a managed file is an initrd or a stub that embeds one.
+///
+/// Stubs that cannot be parsed are treated like stubs with an initrd, because a bundle is usually
+/// made of an ESP that is broken in some way.
+fn may_contain_initrd_secrets(esp_paths: &EspPaths, path: &Path) -> bool {
+    if path.starts_with(&esp_paths.nixos) {
+        return path.to_string_lossy().ends_with("-initrd.efi");
+    }
+    path.starts_with(&esp_paths.linux) && !matches!(pe::section_range(path, ".initrd"), Ok(None))
+}
+
+/// Copy a file into the staging directory of the bundle.
+fn stage(from: &Path, to: &Path) -> Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory {parent:?}"))?;
+    }
+    fs::copy(from, to).with_context(|| format!("Failed to copy {from:?} into the bundle"))?;
+    Ok(())
+}
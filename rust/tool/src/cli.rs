@@ -1,10 +1,44 @@
-use std::path::PathBuf;
+use std::fs;
+use std::io::{self, Read};
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
+use anyhow::{anyhow, Context, Result};
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
 
-use crate::install;
-use crate::signature::KeyPair;
+use crate::boot_options::Partition;
+use crate::bundle;
+use crate::compare;
+use crate::config::Config;
+use crate::esp::{self, Architecture, EspPaths};
+use crate::esp_fs::{EspFilesystem, FatImage};
+use crate::gc;
+use crate::generation::{self, GenerationLink};
+use crate::hook::{PostInstallHook, SectionProviderCommand};
+use crate::install::{
+    self, BootLoaderEntryMode, CmdlineProfile, FallbackLoader, InitrdHashPolicy, InstallOptions,
+    PcrSigningKey,
+};
+use crate::list;
+use crate::loader_conf::{self, ConsoleMode, LoaderSetting, Timeout};
+use crate::manifest::Manifest;
+use crate::migrate;
+use crate::pe::{
+    self, HashAlgorithm, ImageSizeLimit, MachineTypePolicy, PeWriter, SectionProvider, StubProfile,
+    StubSource,
+};
+use crate::sbat;
+use crate::setup::{self, PkiBundle};
+use crate::signature::{KeyPair, Pkcs11Signer, RemoteSigner, SigningKey};
+use crate::signing_request;
+use crate::status;
+use crate::uninstall;
+use crate::utils;
+use crate::verify;
 
 #[derive(Parser)]
 pub struct Cli {
@@ -15,10 +49,546 @@ pub struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     Install(InstallCommand),
+    /// Check a configuration file of install and the files it refers to without installing
+    CheckConfig(CheckConfigCommand),
+    /// Remove all files that lanzaboote installed from the ESP
+    Uninstall(UninstallCommand),
+    /// Show a read-only summary of the state of the ESP
+    Status(StatusCommand),
+    /// Reconstruct the manifest of an ESP that was installed without one or adopt an ESP that
+    /// systemd-boot was installed to
+    Migrate(MigrateCommand),
+    /// Check that the boot loaders and stubs are signed and that the kernels and initrds match the
+    /// hashes embedded into the installed stubs
+    Verify(VerifyCommand),
+    /// Check the chain of trust from the firmware over systemd-boot to the stubs, kernels and
+    /// initrds
+    VerifyChain(VerifyChainCommand),
+    /// Install a new kernel or initrd for a stub, embed their hashes and sign the stub again
+    RefreshHashes(RefreshHashesCommand),
+    /// List the generations whose stubs reference a kernel or initrd on the ESP
+    References(ReferencesCommand),
+    /// List the generations of a system profile and their specialisations
+    Generations(GenerationsCommand),
+    /// List the installed stubs and their kernel command lines
+    List(ListCommand),
+    /// Print the UEFI path of a file on the ESP, e.g. to create a boot entry for it
+    UefiPath(UefiPathCommand),
+    /// Predict the PCR values that systemd-stub extends when booting a stub
+    PredictPcrs(PredictPcrsCommand),
+    /// Write an unsigned copy of a stub and print the signing request for it as JSON
+    ExportForSigning(ExportForSigningCommand),
+    /// Attach a detached signature to a stub that was exported for signing
+    ImportSigned(ImportSignedCommand),
+    /// Remove the kernels and initrds that no installed stub references
+    Gc(GcCommand),
+    /// Collect the files lanzaboote manages on the ESP and the relevant UEFI variables into a
+    /// tarball for offline inspection
+    Bundle(BundleCommand),
+    /// Create the Secure Boot keys (PK, KEK and db) and optionally enroll them into the firmware
+    Setup(SetupCommand),
 }
 
 #[derive(Parser)]
 struct InstallCommand {
+    /// TOML file with the ESP, keys, retention and section options. Options given on the command
+    /// line take precedence
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// sbsign Public Key
+    #[arg(long)]
+    public_key: Option<PathBuf>,
+
+    /// sbsign Private Key
+    #[arg(long)]
+    private_key: Option<PathBuf>,
+
+    /// Read the PEM encoded sbsign Private Key from stdin instead of a file. The key is never
+    /// written to disk
+    #[arg(long, conflicts_with = "private_key")]
+    private_key_stdin: bool,
+
+    /// PKCS#11 URI of the private key on a hardware token (e.g. a YubiKey or an HSM) to sign with
+    /// via the pkcs11 engine of OpenSSL instead of a private key file
+    #[arg(long, value_name = "URI", conflicts_with_all = ["private_key", "private_key_stdin"])]
+    pkcs11_key: Option<String>,
+
+    /// Unix socket of a signing server that holds the private key. The unsigned files are sent
+    /// to it and it answers with detached signatures
+    #[arg(
+        long,
+        value_name = "SOCKET",
+        conflicts_with_all = ["private_key", "private_key_stdin", "pkcs11_key"]
+    )]
+    sign_server: Option<PathBuf>,
+
+    /// Verify every signature right after signing and sign again up to this many times if it does
+    /// not verify, e.g. because of a flaky hardware token
+    #[arg(long, default_value_t = 0)]
+    signing_retries: u32,
+
+    /// Configuration limit, i.e. how many of the newest generations to keep. Defaults to 1
+    #[arg(long, visible_alias = "keep-last", value_name = "N")]
+    configuration_limit: Option<usize>,
+
+    /// Also keep the generations that were created within DURATION (e.g. 14d or 2w), even
+    /// beyond the configuration limit
+    #[arg(long, value_name = "DURATION", value_parser = utils::parse_duration)]
+    keep_since: Option<Duration>,
+
+    /// Mountpoint of a further ESP to keep in sync with the ESP, e.g. on a RAID1 (can be given
+    /// multiple times). A failure on one ESP does not keep the others from being installed to.
+    #[arg(
+        long = "mirror-esp",
+        value_name = "ESP",
+        conflicts_with = "compare_stub"
+    )]
+    mirror_esps: Vec<PathBuf>,
+
+    /// Which part of the initrd the embedded hash covers. With `base`, anyone who can write to the
+    /// ESP can append a cpio archive to the initrd that overrides /init, which defeats the
+    /// integrity of the initrd. Combine it with --sign-initrd, otherwise the install warns
+    #[arg(long, value_enum, default_value_t = InitrdHashPolicy::Full)]
+    initrd_hash: InitrdHashPolicy,
+
+    /// Algorithm of the hashes of the kernel and the initrd that the stub verifies
+    #[arg(long, value_enum, default_value_t = HashAlgorithm::Sha256)]
+    hash_algorithm: HashAlgorithm,
+
+    /// Name kernels and initrds after their content hash to share them between generations
+    #[arg(long)]
+    content_addressed: bool,
+
+    /// Command to run after a successful installation. It receives a JSON report on stdin
+    #[arg(long)]
+    post_install_hook: Option<PathBuf>,
+
+    /// Argument to pass to the post-install hook (can be given multiple times)
+    #[arg(
+        long = "post-install-hook-arg",
+        requires = "post_install_hook",
+        allow_hyphen_values = true
+    )]
+    post_install_hook_args: Vec<String>,
+
+    /// Only warn instead of failing the installation when the post-install hook fails
+    #[arg(long, requires = "post_install_hook")]
+    post_install_hook_warn_only: bool,
+
+    /// Fixed modification time to set on all installed files. Defaults to SOURCE_DATE_EPOCH, if it
+    /// is set
+    #[arg(long)]
+    mtime: Option<i64>,
+
+    /// Only print how the stubs are assembled: the objcopy commands or, with the native PE writer,
+    /// the sections it adds and where. Nothing is installed to the ESP
+    #[arg(long)]
+    show_commands: bool,
+
+    /// Pass the objcopy target of the architecture of the generation explicitly instead of letting
+    /// objcopy detect the format of the stub, which can go wrong when cross-building
+    #[arg(long)]
+    explicit_objcopy_target: bool,
+
+    /// How the sections are attached to the stubs. Only the objcopy writer needs binutils
+    #[arg(long, value_enum, default_value_t = PeWriter::Native)]
+    pe_writer: PeWriter,
+
+    /// XBOOTLDR partition mountpoint to install the kernels, initrds and stubs to
+    #[arg(long)]
+    xbootldr: Option<PathBuf>,
+
+    /// Install to the FAT image file at the path of the ESP instead of a mounted ESP, e.g. when
+    /// building disk images without root. The image is written with mtools
+    #[arg(long, conflicts_with_all = ["mirror_esps", "efi_boot_entries"])]
+    esp_image: bool,
+
+    /// Additional section to embed into the stubs as NAME=PATH (can be given multiple times)
+    #[arg(long = "extra-section", value_parser = parse_extra_section)]
+    extra_sections: Vec<(String, PathBuf)>,
+
+    /// Command that provides additional sections for every stub (can be given multiple times). It
+    /// receives the context of the stub as JSON on stdin and a directory in which every file
+    /// becomes a section of the same name
+    #[arg(long = "section-provider")]
+    section_providers: Vec<PathBuf>,
+
+    /// Additional boot entry per generation with extra kernel parameters as NAME=PARAMS (can be
+    /// given multiple times)
+    #[arg(long = "cmdline-profile", value_parser = CmdlineProfile::parse)]
+    cmdline_profiles: Vec<CmdlineProfile>,
+
+    /// Additional rescue boot entry for the newest generation that boots with only these kernel
+    /// parameters instead of the ones of the generation
+    #[arg(long, value_name = "PARAMS", num_args = 0..=1, default_missing_value = install::SAFE_MODE_KERNEL_PARAMS)]
+    safe_mode: Option<String>,
+
+    /// Warn about generations that use more than this many bytes on the ESP
+    #[arg(long)]
+    size_warning_threshold: Option<u64>,
+
+    /// Print how long each phase of the installation and each generation took and how often the
+    /// ESP was read
+    #[arg(long)]
+    timings: bool,
+
+    /// Warn about stubs that are larger than this many bytes, which some firmware fails to load
+    #[arg(long, default_value_t = pe::DEFAULT_IMAGE_SIZE_LIMIT)]
+    stub_size_limit: u64,
+
+    /// Refuse to install stubs that are larger than the stub size limit instead of warning
+    #[arg(long)]
+    enforce_stub_size_limit: bool,
+
+    /// Version of a known-good generation to always keep as a recovery boot entry
+    #[arg(long)]
+    pinned_recovery: Option<u64>,
+
+    /// Version of a generation to never install, e.g. because it is known to be broken (can be
+    /// given multiple times)
+    #[arg(long = "exclude-generation")]
+    excluded_generations: Vec<u64>,
+
+    /// Toplevel of the running system, which is warned about if it is excluded
+    #[arg(long, default_value = "/run/current-system")]
+    current_system: PathBuf,
+
+    /// How strictly the machine type of the stub has to match the system
+    #[arg(long, value_enum, default_value_t = MachineTypePolicy::Strict)]
+    machine_type_check: MachineTypePolicy,
+
+    /// The section naming conventions of the stub to assemble images for
+    #[arg(long, value_enum, default_value_t = StubProfile::Current)]
+    stub_profile: StubProfile,
+
+    /// Make sure that the new files fit onto the ESP, removing pruned generations first if needed
+    #[arg(long)]
+    check_free_space: bool,
+
+    /// Prune the oldest generations (except for the newest, the default and the pinned recovery
+    /// generation) if the new files do not fit onto the ESP otherwise. Implies --check-free-space
+    #[arg(long)]
+    auto_prune: bool,
+
+    /// Warn if less than this percentage of the ESP is free after the installation
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=100))]
+    free_space_warning_percentage: Option<u8>,
+
+    /// Read the kernel version shown in the boot menu from the kernel image
+    #[arg(long)]
+    kernel_version_from_image: bool,
+
+    /// UEFI architecture of the machine to install systemd-boot and the default fallback boot
+    /// loader for. Defaults to the architecture of the newest generation
+    #[arg(long, value_enum)]
+    architecture: Option<Architecture>,
+
+    /// Fallback boot loader to install as ARCH or ARCH=PATH to a systemd-boot build (can be given
+    /// multiple times). Defaults to the architecture of systemd-boot with the systemd-boot of the
+    /// generation
+    #[arg(long = "fallback-loader", value_parser = FallbackLoader::parse)]
+    fallback_loaders: Vec<FallbackLoader>,
+
+    /// systemd-boot EFI binary to install instead of the one of the generation (e.g. to pin a
+    /// version or use a patched build)
+    #[arg(long)]
+    systemd_boot: Option<PathBuf>,
+
+    /// EFI binary to install without signing it, e.g. a shim signed by a third party (can be given
+    /// multiple times)
+    #[arg(long = "install-verbatim")]
+    verbatim: Vec<PathBuf>,
+
+    /// Make the newest installed generation the default boot entry of systemd-boot
+    #[arg(long)]
+    make_default: bool,
+
+    /// Make this generation the default boot entry of systemd-boot instead of the newest one
+    #[arg(long, value_name = "VERSION", conflicts_with = "make_default")]
+    default_generation: Option<u64>,
+
+    /// How long systemd-boot shows the boot menu, in seconds or menu-force, menu-hidden or
+    /// menu-disabled
+    #[arg(long, value_parser = loader_conf::parse_timeout)]
+    timeout: Option<Timeout>,
+
+    /// Keep the kernel parameters from being edited in the boot menu (editor no)
+    #[arg(long)]
+    disable_editor: bool,
+
+    /// Resolution of the console of systemd-boot
+    #[arg(long, value_enum)]
+    console_mode: Option<ConsoleMode>,
+
+    /// Setting of loader.conf as KEY=VALUE that is restored on every installation (can be given
+    /// multiple times)
+    #[arg(long = "loader-setting", value_parser = LoaderSetting::parse)]
+    loader_settings: Vec<LoaderSetting>,
+
+    /// File to write the store paths of the installed generations to (one per line), so that they
+    /// can be registered as GC roots
+    #[arg(long)]
+    store_roots_file: Option<PathBuf>,
+
+    /// Directory to read the UEFI variables from
+    #[arg(long, default_value = "/sys/firmware/efi/efivars")]
+    efivars: PathBuf,
+
+    /// Install even though Secure Boot is enabled and the certificate of the public key is not
+    /// enrolled, so that the firmware would refuse to boot the stubs
+    #[arg(long)]
+    force: bool,
+
+    /// Only print the boot entries and files on the ESP that the installation would remove,
+    /// without changing anything
+    #[arg(long, conflicts_with = "compare_stub")]
+    dry_run: bool,
+
+    /// Write a detached signature (INITRD.sig) next to every initrd on the ESP, which the verify
+    /// command checks
+    #[arg(long)]
+    sign_initrd: bool,
+
+    /// Allow private keys in the initrd secrets, e.g. the SSH host key of an SSH server in the
+    /// initrd. All other files are still scanned and refused if they contain private keys
+    #[arg(long)]
+    allow_keys_in_initrd_secrets: bool,
+
+    /// Write a detached signature next to every credential and system extension that the
+    /// generations install into the companion directories (STUB.extra.d) of their stubs
+    #[arg(long)]
+    sign_extra_files: bool,
+
+    /// Write a JSON sidecar (STUB.meta) with the embedded command line, os-release summary and
+    /// referenced files next to every stub. The sidecar is informational and not signed
+    #[arg(long)]
+    write_metadata: bool,
+
+    /// Print the PCR 11 values that every stub is expected to produce in each boot phase, e.g. to
+    /// plan systemd-cryptenroll. On its own, no PCR policy is signed (see --pcr-private-key)
+    #[arg(long)]
+    preview_pcrs: bool,
+
+    /// PEM encoded private key to sign a TPM2 PCR policy for every stub with, which is embedded
+    /// as .pcrsig, so that disk encryption can be bound to all stubs signed by it. Needs
+    /// --embed-kernel
+    #[arg(long, requires_all = ["pcr_public_key", "embed_kernel"])]
+    pcr_private_key: Option<PathBuf>,
+
+    /// PEM encoded public key of the PCR policy signing key, which is embedded as .pcrpkey
+    #[arg(long, requires = "pcr_private_key")]
+    pcr_public_key: Option<PathBuf>,
+
+    /// Embed SBAT metadata that describes lzbt and the kernel of the generation into every stub,
+    /// merged with the SBAT metadata of the stub, so that it can be revoked by shim and the
+    /// firmware
+    #[arg(long)]
+    sbat: bool,
+
+    /// Additional SBAT entry (e.g. "linux.vendor,1,Vendor,linux,6.1.1,https://example.com") to
+    /// embed (can be given multiple times). An entry replaces the generated one of the same
+    /// component
+    #[arg(long = "sbat-entry", value_name = "CSV", value_parser = sbat::parse_entry, requires = "sbat")]
+    sbat_entries: Vec<String>,
+
+    /// Boot the stubs directly from the firmware instead of installing systemd-boot. Every stub gets
+    /// a boot option (Boot####) in the UEFI variables, the default generation comes first in the
+    /// BootOrder and the boot options of removed stubs are deleted
+    #[arg(long, conflicts_with = "boot_counting")]
+    efi_boot_entries: bool,
+
+    /// Raise the minimum security version in the firmware (a UEFI variable signed with the signing
+    /// key, which `lzbt setup --enroll` creates) to the security version of the newest generation.
+    /// The stubs refuse to boot generations with a lower security version
+    #[arg(long)]
+    anti_rollback: bool,
+
+    /// Install new stubs with a boot counter (nixos-generation-N+TRIES.efi), so that systemd-boot
+    /// counts the boot attempts and falls back to the previous generation once they are used up.
+    /// Needs the boot to be assessed as good, e.g. by systemd-bless-boot.service
+    #[arg(long)]
+    boot_counting: bool,
+
+    /// The number of boot attempts of a new stub with --boot-counting
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..), requires = "boot_counting")]
+    boot_counting_tries: u32,
+
+    /// Write a Type #1 boot loader entry (loader/entries/nixos-bls-*.conf) for every generation
+    /// that boots its signed kernel directly, next to (also, the default) or instead of its stub.
+    /// Nothing verifies the initrd of such an entry
+    #[arg(
+        long,
+        value_enum,
+        value_name = "MODE",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "also"
+    )]
+    boot_loader_entries: Option<BootLoaderEntryMode>,
+
+    /// Skip generations whose toplevel, kernel parameters and stub are unchanged since the
+    /// previous installation
+    #[arg(long)]
+    incremental: bool,
+
+    /// Only garbage collect the files that the previous installation installed instead of walking
+    /// the ESP, except for a full sweep every INTERVAL installations
+    #[arg(long, value_name = "INTERVAL")]
+    incremental_gc: Option<u32>,
+
+    /// Maximum number of files to sign and copy to the ESP at the same time. Defaults to the
+    /// number of CPUs
+    #[arg(long, default_value_t = utils::default_concurrency())]
+    copy_concurrency: usize,
+
+    /// Maximum number of stubs (of all generations) to assemble and sign at the same time.
+    /// Defaults to the number of CPUs
+    #[arg(long, default_value_t = utils::default_concurrency())]
+    stub_concurrency: usize,
+
+    /// Embed the kernel into the stubs (hybrid UKI). The initrd is still installed as a separate
+    /// file
+    #[arg(long)]
+    embed_kernel: bool,
+
+    /// Embed the initrd into the stubs as well, so that every generation is a single,
+    /// self-contained UKI that does not reference any other file on the ESP
+    #[arg(long, requires = "embed_kernel")]
+    embed_initrd: bool,
+
+    /// Instead of installing, assemble the stub of the only generation into a temporary ESP and
+    /// compare it byte by byte against this reference
+    #[arg(long)]
+    compare_stub: Option<PathBuf>,
+
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint). Must be omitted if the
+    /// configuration file sets it
+    esp: Option<PathBuf>,
+
+    /// List of generation links (e.g. /nix/var/nix/profiles/system-*-link)
+    generations: Vec<PathBuf>,
+}
+
+#[derive(Parser)]
+struct CheckConfigCommand {
+    /// TOML configuration file to check
+    config: PathBuf,
+}
+
+#[derive(Parser)]
+struct UninstallCommand {
+    /// XBOOTLDR partition mountpoint the kernels, initrds and stubs were installed to
+    #[arg(long)]
+    xbootldr: Option<PathBuf>,
+
+    /// Also remove the boot options of the stubs (see --efi-boot-entries) from the UEFI variables
+    /// in this directory, e.g. /sys/firmware/efi/efivars
+    #[arg(long)]
+    efivars: Option<PathBuf>,
+
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    esp: PathBuf,
+}
+
+#[derive(Parser)]
+struct StatusCommand {
+    /// XBOOTLDR partition mountpoint the kernels, initrds and stubs were installed to
+    #[arg(long)]
+    xbootldr: Option<PathBuf>,
+
+    /// Directory to read the UEFI variables from
+    #[arg(long, default_value = "/sys/firmware/efi/efivars")]
+    efivars: PathBuf,
+
+    /// Toplevel of the current system to compare the installed systemd-boot to
+    #[arg(long, default_value = "/run/current-system")]
+    system: PathBuf,
+
+    /// sysfs directory of the TPM to report on
+    #[arg(long, default_value = "/sys/class/tpm/tpm0")]
+    tpm: PathBuf,
+
+    /// Also count the EFI binaries signed with the key of this certificate, with another key, or
+    /// not at all
+    #[arg(long)]
+    public_key: Option<PathBuf>,
+
+    /// Print the status as JSON, including the installed generations
+    #[arg(long)]
+    json: bool,
+
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    esp: PathBuf,
+}
+
+#[derive(Parser)]
+struct MigrateCommand {
+    /// Adopt an ESP that the systemd-boot module of NixOS was installed to: install signed stubs
+    /// for the generations of its boot loader entries and remove the unsigned kernels, initrds and
+    /// entries they supersede. All arguments of install apply. Without it, only the ESP and
+    /// --xbootldr can be given
+    #[arg(long)]
+    from_systemd_boot: bool,
+
+    /// Copy the files of systemd-boot to DIR before they are removed
+    #[arg(long, value_name = "DIR", requires = "from_systemd_boot")]
+    quarantine: Option<PathBuf>,
+
+    /// System profile whose generations the boot loader entries of systemd-boot belong to. Only
+    /// used if no generations are given
+    #[arg(long, default_value = "/nix/var/nix/profiles/system")]
+    profile: PathBuf,
+
+    #[command(flatten)]
+    install: InstallCommand,
+}
+
+#[derive(Parser)]
+struct VerifyCommand {
+    /// XBOOTLDR partition mountpoint the kernels, initrds and stubs were installed to
+    #[arg(long)]
+    xbootldr: Option<PathBuf>,
+
+    /// Certificate that the boot loaders, the stubs and the detached signatures of the initrds
+    /// have to be signed with (can be given multiple times, any of them is accepted). Without
+    /// one, they only have to be signed
+    #[arg(long = "public-key")]
+    public_keys: Vec<PathBuf>,
+
+    /// Maximum number of stubs to verify at the same time. Defaults to the number of CPUs
+    #[arg(long, default_value_t = utils::default_concurrency())]
+    concurrency: usize,
+
+    /// Print the report as JSON
+    #[arg(long)]
+    json: bool,
+
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    esp: PathBuf,
+}
+
+#[derive(Parser)]
+struct VerifyChainCommand {
+    /// XBOOTLDR partition mountpoint the kernels, initrds and stubs were installed to
+    #[arg(long)]
+    xbootldr: Option<PathBuf>,
+
+    /// Directory to read the UEFI variables from
+    #[arg(long, default_value = "/sys/firmware/efi/efivars")]
+    efivars: PathBuf,
+
+    /// Certificate that has to be enrolled in the firmware and that everything has to be signed
+    /// with
+    #[arg(long)]
+    public_key: PathBuf,
+
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    esp: PathBuf,
+}
+
+#[derive(Parser)]
+struct RefreshHashesCommand {
     /// sbsign Public Key
     #[arg(long)]
     public_key: PathBuf,
@@ -27,43 +597,913 @@ struct InstallCommand {
     #[arg(long)]
     private_key: PathBuf,
 
-    /// Configuration limit
-    #[arg(long, default_value_t = 1)]
-    configuration_limit: usize,
+    /// XBOOTLDR partition mountpoint the kernels, initrds and stubs were installed to
+    #[arg(long)]
+    xbootldr: Option<PathBuf>,
+
+    /// Kernel to sign and install in place of the one that the stub references
+    #[arg(long)]
+    kernel: Option<PathBuf>,
+
+    /// Initrd to install in place of the one that the stub references
+    #[arg(long)]
+    initrd: Option<PathBuf>,
 
     /// EFI system partition mountpoint (e.g. efiSysMountPoint)
     esp: PathBuf,
 
-    /// List of generation links (e.g. /nix/var/nix/profiles/system-*-link)
-    generations: Vec<PathBuf>,
+    /// Stub whose kernel or initrd to replace
+    stub: PathBuf,
+}
+
+#[derive(Parser)]
+struct ReferencesCommand {
+    /// XBOOTLDR partition mountpoint the kernels, initrds and stubs were installed to
+    #[arg(long)]
+    xbootldr: Option<PathBuf>,
+
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    esp: PathBuf,
+
+    /// Kernel or initrd on the ESP
+    file: PathBuf,
+}
+
+#[derive(Parser)]
+struct GenerationsCommand {
+    /// System profile whose generations to list
+    #[arg(default_value = "/nix/var/nix/profiles/system")]
+    profile: PathBuf,
+}
+
+#[derive(Parser)]
+struct ListCommand {
+    /// XBOOTLDR partition mountpoint the kernels, initrds and stubs were installed to
+    #[arg(long)]
+    xbootldr: Option<PathBuf>,
+
+    /// Print the stubs as JSON with the full kernel command lines
+    #[arg(long)]
+    json: bool,
+
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    esp: PathBuf,
+}
+
+#[derive(Parser)]
+struct UefiPathCommand {
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    esp: PathBuf,
+
+    /// File on the ESP, either absolute or relative to the ESP. Defaults to the installed
+    /// systemd-boot
+    target: Option<PathBuf>,
+}
+
+#[derive(Parser)]
+struct PredictPcrsCommand {
+    /// Stub to predict the PCR values for
+    stub: PathBuf,
+}
+
+#[derive(Parser)]
+struct ExportForSigningCommand {
+    /// Stub to export
+    stub: PathBuf,
+
+    /// Where to write the unsigned copy of the stub. Store the signing request next to it.
+    out: PathBuf,
+}
+
+#[derive(Parser)]
+struct ImportSignedCommand {
+    /// Signing request that was printed when exporting the stub, stored next to the stub
+    request: PathBuf,
+
+    /// Detached signature of the stub (e.g. created by sbsign --detached)
+    signature: PathBuf,
+}
+
+#[derive(Parser)]
+struct BundleCommand {
+    /// XBOOTLDR partition mountpoint the kernels, initrds and stubs were installed to
+    #[arg(long)]
+    xbootldr: Option<PathBuf>,
+
+    /// Directory to read the UEFI variables from
+    #[arg(long, default_value = "/sys/firmware/efi/efivars")]
+    efivars: PathBuf,
+
+    /// Also include the initrds and the stubs with an embedded initrd, which may contain the
+    /// initrd secrets
+    #[arg(long)]
+    include_secrets: bool,
+
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    esp: PathBuf,
+
+    /// Where to write the tarball
+    out: PathBuf,
+}
+
+#[derive(Parser)]
+struct SetupCommand {
+    /// Directory to create the keys in, with the same layout as sbctl (e.g. keys/db/db.key and
+    /// keys/db/db.pem). Existing keys are used instead of creating new ones
+    #[arg(long, default_value = "/etc/secureboot")]
+    pki_bundle: PathBuf,
+
+    /// Common name of the certificates, to which the kind of key is appended
+    #[arg(long, default_value = "Lanzaboote")]
+    common_name: String,
+
+    /// Also write the EFI signature lists (.esl) and the signed updates (.auth) of the keys next to
+    /// them, e.g. to enroll them from the firmware setup
+    #[arg(long)]
+    export: bool,
+
+    /// Enroll the keys into the firmware, which has to be in setup mode. Only these keys are
+    /// enrolled, so firmware drivers that are signed by Microsoft (e.g. of graphics cards) are
+    /// not loaded anymore
+    #[arg(long)]
+    enroll: bool,
+
+    /// Directory to read and write the UEFI variables
+    #[arg(long, default_value = "/sys/firmware/efi/efivars")]
+    efivars: PathBuf,
+}
+
+#[derive(Parser)]
+struct GcCommand {
+    /// XBOOTLDR partition mountpoint the kernels, initrds and stubs were installed to
+    #[arg(long)]
+    xbootldr: Option<PathBuf>,
+
+    /// Only list the files that would be removed
+    #[arg(long)]
+    dry_run: bool,
+
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    esp: PathBuf,
 }
 
 impl Cli {
+    /// Parse the command line arguments like `Parser::parse`.
+    ///
+    /// Plain migrate shares its arguments with `migrate --from-systemd-boot`, but ignores all
+    /// arguments of install except the ESP and the XBOOTLDR partition. Giving any of them is
+    /// rejected instead of silently ignored.
+    pub fn parse_arguments() -> Self {
+        let matches = Self::command().get_matches();
+        if let Some(("migrate", migrate_matches)) = matches.subcommand() {
+            if let Err(e) = check_migrate_arguments(migrate_matches) {
+                e.exit();
+            }
+        }
+        Self::from_arg_matches(&matches).unwrap_or_else(|e| e.exit())
+    }
+
     pub fn call(self) -> Result<()> {
         self.commands.call()
     }
 }
 
+/// Reject the arguments that plain migrate would ignore.
+fn check_migrate_arguments(matches: &ArgMatches) -> Result<(), clap::Error> {
+    if matches.get_flag("from_systemd_boot") {
+        return Ok(());
+    }
+    let command = Cli::command();
+    let migrate = command
+        .find_subcommand("migrate")
+        .expect("migrate is a subcommand");
+    let ignored = migrate.get_arguments().find(|arg| {
+        !matches!(arg.get_id().as_str(), "esp" | "xbootldr")
+            && matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine)
+    });
+    match ignored {
+        Some(arg) => Err(migrate.clone().error(
+            ErrorKind::ArgumentConflict,
+            format!("The argument '{arg}' can only be given with --from-systemd-boot"),
+        )),
+        None => Ok(()),
+    }
+}
+
 impl Commands {
     pub fn call(self) -> Result<()> {
         match self {
             Commands::Install(args) => install(args),
+            Commands::CheckConfig(args) => check_config(args),
+            Commands::Uninstall(args) => uninstall(args),
+            Commands::Status(args) => status(args),
+            Commands::Migrate(args) => migrate(args),
+            Commands::Verify(args) => verify(args),
+            Commands::VerifyChain(args) => verify_chain(args),
+            Commands::RefreshHashes(args) => refresh_hashes(args),
+            Commands::References(args) => references(args),
+            Commands::Generations(args) => generations(args),
+            Commands::List(args) => list(args),
+            Commands::UefiPath(args) => uefi_path(args),
+            Commands::PredictPcrs(args) => predict_pcrs(args),
+            Commands::ExportForSigning(args) => export_for_signing(args),
+            Commands::Gc(args) => gc(args),
+            Commands::ImportSigned(args) => {
+                signing_request::import_signed(&args.request, &args.signature).map(|_| ())
+            }
+            Commands::Bundle(args) => {
+                let esp_paths = EspPaths::new(&args.esp, args.xbootldr.as_deref());
+                bundle::collect_bundle(&esp_paths, &args.efivars, &args.out, args.include_secrets)
+            }
+            Commands::Setup(args) => setup(args),
         }
     }
 }
 
 fn install(args: InstallCommand) -> Result<()> {
-    let lanzaboote_stub =
-        std::env::var("LANZABOOTE_STUB").context("Failed to read LANZABOOTE_STUB env variable")?;
+    // Either a single stub or a directory with a stub per UEFI architecture.
+    let lanzaboote_stub = StubSource::new(PathBuf::from(
+        std::env::var("LANZABOOTE_STUB").context("Failed to read LANZABOOTE_STUB env variable")?,
+    ));
+
+    let config = match &args.config {
+        Some(path) => Config::read(path)?,
+        None => Config::default(),
+    };
+    let (esp, generations) = esp_and_generations(config.esp.clone(), args.esp, args.generations)?;
+    let esp_image = args.esp_image;
+
+    let public_key = args.public_key.or(config.public_key).context(
+        "The public key is given neither on the command line nor in the configuration file",
+    )?;
+    let signer = if let Some(key_uri) = args.pkcs11_key {
+        SigningKey::new(Pkcs11Signer::new(&public_key, key_uri))
+    } else if let Some(socket) = &args.sign_server {
+        SigningKey::new(RemoteSigner::new(&public_key, socket))
+    } else if args.private_key_stdin {
+        let public_key = fs::read(&public_key)
+            .with_context(|| format!("Failed to read public key {public_key:?}"))?;
+        let mut private_key = Vec::new();
+        io::stdin()
+            .read_to_end(&mut private_key)
+            .context("Failed to read private key from stdin")?;
+        SigningKey::new(KeyPair::from_pem(&public_key, &private_key)?)
+    } else {
+        let private_key = args.private_key.or(config.private_key).context(
+            "The private key is given neither on the command line nor in the configuration file",
+        )?;
+        SigningKey::new(KeyPair::new(&public_key, &private_key))
+    }
+    .with_signing_retries(args.signing_retries);
+
+    // The installed files get the time of the build unless an explicit time is given.
+    let build_epoch = utils::build_epoch();
+    let mtime = args.mtime.or_else(|| {
+        build_epoch.and_then(|build_epoch| i64::try_from(utils::unix_seconds(build_epoch)).ok())
+    });
+    let editor = if args.disable_editor {
+        Some(false)
+    } else {
+        config.editor
+    };
+    let mut options = InstallOptions {
+        initrd_hash_policy: args.initrd_hash,
+        hash_algorithm: args.hash_algorithm,
+        content_addressed: args.content_addressed,
+        post_install_hook: args.post_install_hook.map(|command| PostInstallHook {
+            command,
+            args: args.post_install_hook_args,
+            fail_on_error: !args.post_install_hook_warn_only,
+        }),
+        mtime,
+        build_epoch,
+        show_commands: args.show_commands,
+        xbootldr: args.xbootldr.or(config.xbootldr),
+        // Extra sections are overridden one by one.
+        extra_sections: config
+            .extra_sections
+            .into_iter()
+            .chain(args.extra_sections)
+            .collect(),
+        section_providers: cli_or_config(args.section_providers, config.section_providers)
+            .into_iter()
+            .map(|command| Arc::new(SectionProviderCommand { command }) as Arc<dyn SectionProvider>)
+            .collect(),
+        cmdline_profiles: args.cmdline_profiles,
+        safe_mode: args
+            .safe_mode
+            .map(|params| params.split_whitespace().map(String::from).collect()),
+        size_warning_threshold: args.size_warning_threshold,
+        print_timings: args.timings,
+        progress: None,
+        stub_size_limit: Some(ImageSizeLimit {
+            bytes: args.stub_size_limit,
+            enforce: args.enforce_stub_size_limit,
+        }),
+        pinned_recovery: args.pinned_recovery.or(config.pinned_recovery),
+        excluded_generations: cli_or_config(args.excluded_generations, config.excluded_generations)
+            .into_iter()
+            .collect(),
+        current_system: args.current_system,
+        machine_type_policy: args.machine_type_check,
+        stub_profile: args.stub_profile,
+        explicit_objcopy_target: args.explicit_objcopy_target,
+        pe_writer: args.pe_writer,
+        check_free_space: args.check_free_space || args.auto_prune,
+        auto_prune: args.auto_prune,
+        free_space_warning_percentage: args.free_space_warning_percentage,
+        esp_capacity: None,
+        kernel_version_from_image: args.kernel_version_from_image,
+        architecture: args.architecture,
+        fallback_loaders: args.fallback_loaders,
+        systemd_boot: args.systemd_boot,
+        copy_concurrency: args.copy_concurrency,
+        stub_concurrency: args.stub_concurrency,
+        embed_kernel: args.embed_kernel,
+        embed_initrd: args.embed_initrd,
+        verbatim: args.verbatim,
+        make_default: args.make_default,
+        default_generation: args.default_generation.or(config.default_generation),
+        loader_settings: loader_conf::dedup_settings(
+            [
+                args.timeout
+                    .or(config.timeout)
+                    .map(|timeout| LoaderSetting::new("timeout", timeout.to_string())),
+                editor
+                    .map(|editor| LoaderSetting::new("editor", if editor { "yes" } else { "no" })),
+                args.console_mode
+                    .or(config.console_mode)
+                    .map(|mode| LoaderSetting::new("console-mode", mode.as_str())),
+            ]
+            .into_iter()
+            .flatten()
+            // Explicit settings take precedence.
+            .chain(args.loader_settings)
+            .collect(),
+        ),
+        sign_initrds: args.sign_initrd,
+        allow_initrd_secret_keys: args.allow_keys_in_initrd_secrets,
+        sign_extra_files: args.sign_extra_files,
+        write_metadata: args.write_metadata,
+        preview_pcrs: args.preview_pcrs,
+        pcr_signing_key: args.pcr_private_key.zip(args.pcr_public_key).map(
+            |(private_key, public_key)| PcrSigningKey {
+                public_key,
+                private_key,
+            },
+        ),
+        sbat: args.sbat,
+        sbat_entries: args.sbat_entries,
+        efi_boot_entries: args.efi_boot_entries,
+        anti_rollback: args.anti_rollback,
+        boot_counting_tries: args.boot_counting.then_some(args.boot_counting_tries),
+        boot_loader_entries: args.boot_loader_entries,
+        incremental: args.incremental,
+        incremental_gc: args.incremental_gc,
+        store_roots_file: args.store_roots_file,
+        efivars: args.efivars,
+        force: args.force,
+        keep_since: args.keep_since,
+        dry_run: args.dry_run,
+    };
+
+    if let Some(reference) = &args.compare_stub {
+        return compare_stub(
+            lanzaboote_stub,
+            signer,
+            generations,
+            InstallOptions {
+                post_install_hook: None,
+                xbootldr: None,
+                // Nothing is booted from the staging ESP.
+                force: true,
+                efi_boot_entries: false,
+                anti_rollback: false,
+                ..options
+            },
+            reference,
+        );
+    }
+
+    let mirror_esps = cli_or_config(args.mirror_esps, config.mirror_esps);
+    if !mirror_esps.is_empty() && options.xbootldr.is_some() {
+        return Err(anyhow!("Mirrored ESPs cannot share an XBOOTLDR partition"));
+    }
+    if !mirror_esps.is_empty() && esp_image {
+        return Err(anyhow!("An ESP image cannot be mirrored"));
+    }
+    options.xbootldr = options
+        .xbootldr
+        .map(|xbootldr| esp::open_xbootldr(&xbootldr))
+        .transpose()?;
+
+    let configuration_limit = args
+        .configuration_limit
+        .or(config.configuration_limit)
+        .unwrap_or(1);
+    // The commands are only printed: the stubs are assembled on a staging ESP without objcopy.
+    if options.show_commands {
+        let staging_esp = utils::tempdir()?;
+        return install::Installer::new(
+            lanzaboote_stub,
+            signer,
+            configuration_limit,
+            staging_esp.path().to_path_buf(),
+            generations,
+            InstallOptions {
+                post_install_hook: None,
+                xbootldr: None,
+                // Nothing is booted from the staging ESP.
+                force: true,
+                efi_boot_entries: false,
+                anti_rollback: false,
+                ..options
+            },
+        )
+        .install();
+    }
+
+    let installer = |esp, esp_capacity| {
+        install::Installer::new(
+            lanzaboote_stub,
+            signer,
+            configuration_limit,
+            esp,
+            generations,
+            InstallOptions {
+                esp_capacity,
+                ..options
+            },
+        )
+    };
+    if esp_image {
+        let mut image = FatImage::open(&esp)?;
+        installer(image.root().to_path_buf(), image.capacity()).install()?;
+        return image.commit();
+    }
+    if mirror_esps.is_empty() {
+        return installer(open_esp(&esp)?.root().to_path_buf(), None).install();
+    }
+
+    // Every ESP is installed to even if another one fails, e.g. because its disk is broken.
+    let mut installer = installer(esp.clone(), None);
+    let esps: Vec<PathBuf> = [esp].into_iter().chain(mirror_esps).collect();
+    let mut failures = Vec::new();
+    for esp in &esps {
+        println!("Installing to ESP {}...", esp.display());
+        let result = open_esp(esp).and_then(|handle| {
+            installer.retarget(handle.root().to_path_buf());
+            installer.install()
+        });
+        if let Err(e) = result {
+            failures.push(format!("{esp:?}: {e:#}"));
+        }
+    }
+
+    if !failures.is_empty() {
+        return Err(anyhow!(
+            "Failed to install to {} of {} ESPs: {}",
+            failures.len(),
+            esps.len(),
+            failures.join("; ")
+        ));
+    }
+    Ok(())
+}
+
+/// Split the positional arguments of install into the ESP and the generations.
+///
+/// With the ESP in the configuration file, all positional arguments are generations.
+fn esp_and_generations(
+    config_esp: Option<PathBuf>,
+    esp: Option<PathBuf>,
+    generations: Vec<PathBuf>,
+) -> Result<(PathBuf, Vec<PathBuf>)> {
+    match config_esp {
+        Some(config_esp) => Ok((config_esp, esp.into_iter().chain(generations).collect())),
+        None => Ok((
+            esp.context(
+                "The ESP is given neither on the command line nor in the configuration file",
+            )?,
+            generations,
+        )),
+    }
+}
+
+fn check_config(args: CheckConfigCommand) -> Result<()> {
+    let problems = Config::parse(&args.config)?.check();
+    for problem in &problems {
+        println!("{problem}");
+    }
+
+    if !problems.is_empty() {
+        return Err(anyhow!(
+            "{} problems found in the configuration file {:?}",
+            problems.len(),
+            args.config
+        ));
+    }
+    Ok(())
+}
 
-    let key_pair = KeyPair::new(&args.public_key, &args.private_key);
+/// Assemble the stub of a generation without touching the ESP and compare it against a reference.
+///
+/// The generation is installed to a temporary ESP. Because all paths embedded in the stub are
+/// relative to the partition they are installed to, the stub is the same as on the real ESP or
+/// XBOOTLDR partition.
+fn compare_stub(
+    lanzaboote_stub: StubSource,
+    signer: SigningKey,
+    generations: Vec<PathBuf>,
+    options: InstallOptions,
+    reference: &Path,
+) -> Result<()> {
+    if generations.len() != 1 {
+        return Err(anyhow!(
+            "Comparing against a reference stub requires exactly one generation"
+        ));
+    }
+    let version = GenerationLink::from_path(&generations[0])?.version;
 
+    let staging_esp = utils::tempdir()?;
     install::Installer::new(
-        PathBuf::from(lanzaboote_stub),
-        key_pair,
-        args.configuration_limit,
-        args.esp,
-        args.generations,
+        lanzaboote_stub,
+        signer,
+        0,
+        staging_esp.path().to_path_buf(),
+        generations,
+        options,
     )
-    .install()
+    .install()?;
+
+    let produced = staging_esp
+        .path()
+        .join(format!("EFI/Linux/nixos-generation-{version}.efi"));
+    compare::compare_with_reference(&produced, reference)
+}
+
+fn uninstall(args: UninstallCommand) -> Result<()> {
+    let esp = open_esp(&args.esp)?;
+    let esp_paths = EspPaths::new(esp, args.xbootldr.as_deref());
+    // Only the boot options of the partition that the stubs were installed to are removed.
+    let partition = match &args.efivars {
+        Some(_) => Some(Partition::of(&esp_paths.boot)?),
+        None => None,
+    };
+    uninstall::uninstall(&esp_paths, args.efivars.as_deref().zip(partition.as_ref()))
+}
+
+fn migrate(args: MigrateCommand) -> Result<()> {
+    if args.from_systemd_boot {
+        return migrate_from_systemd_boot(args);
+    }
+
+    let esp = open_esp(args.install.esp.as_deref().context("The ESP is missing")?)?;
+    let report = migrate::migrate(&EspPaths::new(esp, args.install.xbootldr.as_deref()))?;
+
+    if report.already_migrated {
+        println!("The ESP already has a manifest, nothing to migrate.");
+        return Ok(());
+    }
+    for path in &report.recorded {
+        println!("Recorded {}", path.display());
+    }
+    for path in &report.unverified {
+        println!(
+            "Could not verify {}, it will be rewritten by the next installation",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Adopt an ESP that systemd-boot was installed to.
+///
+/// The boot loader entries of systemd-boot are mapped to the generations of the system profile,
+/// which are then installed like by install. The files of systemd-boot that the installation does
+/// not adopt are only removed afterwards, so that a failed installation leaves them bootable.
+fn migrate_from_systemd_boot(mut args: MigrateCommand) -> Result<()> {
+    let config = match &args.install.config {
+        Some(path) => Config::read(path)?,
+        None => Config::default(),
+    };
+    let uses_config_esp = config.esp.is_some();
+    let (esp, generations) = esp_and_generations(
+        config.esp,
+        args.install.esp.take(),
+        std::mem::take(&mut args.install.generations),
+    )?;
+    let xbootldr = args.install.xbootldr.clone().or(config.xbootldr);
+    let esp_paths = EspPaths::new(open_esp(&esp)?, xbootldr.as_deref());
+
+    let inventory = migrate::systemd_boot_inventory(&esp_paths)?;
+    if inventory.is_empty() {
+        println!("No files of systemd-boot found, nothing to migrate.");
+        return Ok(());
+    }
+    for (path, version) in &inventory.entries {
+        println!(
+            "Found boot loader entry {} of generation {version}",
+            path.display()
+        );
+    }
+
+    let generations = if generations.is_empty() {
+        let (links, missing) = migrate::generation_links(&inventory, &args.profile)?;
+        for version in missing {
+            println!(
+                "Warning: generation {version} is not in the profile {} anymore, its boot loader entries are removed without replacement",
+                args.profile.display()
+            );
+        }
+        links
+    } else {
+        generations
+    };
+    if generations.is_empty() {
+        return Err(anyhow!(
+            "None of the boot loader entries of systemd-boot belongs to a generation of the profile {:?}",
+            args.profile
+        ));
+    }
+
+    let dry_run = args.install.dry_run;
+    if let (Some(quarantine), false) = (&args.quarantine, dry_run) {
+        for path in migrate::quarantine(&esp_paths, &inventory, quarantine)? {
+            println!("Quarantined {}", path.display());
+        }
+    }
+
+    args.install.esp = (!uses_config_esp).then_some(esp);
+    args.install.generations = generations;
+    install(args.install)?;
+    if dry_run {
+        return Ok(());
+    }
+
+    let report = migrate::remove_superseded(&esp_paths, &inventory)?;
+    for path in &report.adopted {
+        println!("Adopted {}", path.display());
+    }
+    for path in &report.removed {
+        println!("Removed superseded {}", path.display());
+    }
+    Ok(())
+}
+
+/// Verify the boot loaders and all installed stubs and report every unsigned or inconsistent one.
+///
+/// Nothing on the ESP is modified.
+fn verify(args: VerifyCommand) -> Result<()> {
+    let esp = args
+        .esp
+        .canonicalize()
+        .with_context(|| format!("Failed to open ESP {:?}", args.esp))?;
+    let esp_paths = EspPaths::new(esp, args.xbootldr.as_deref());
+
+    let report = verify::verify(&esp_paths, args.concurrency, &args.public_keys)?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print!("{report}");
+    }
+
+    let failures = report.failures();
+    if failures > 0 {
+        return Err(anyhow!(
+            "{failures} boot loaders or stubs are unsigned or inconsistent"
+        ));
+    }
+    Ok(())
+}
+
+fn verify_chain(args: VerifyChainCommand) -> Result<()> {
+    let esp = args
+        .esp
+        .canonicalize()
+        .with_context(|| format!("Failed to open ESP {:?}", args.esp))?;
+    let esp_paths = EspPaths::new(esp, args.xbootldr.as_deref());
+
+    let report = verify::verify_chain(&esp_paths, &args.efivars, &args.public_key)?;
+    print!("{report}");
+
+    let broken_links = report.broken_links().count();
+    if broken_links > 0 {
+        return Err(anyhow!(
+            "The chain of trust is broken at {broken_links} links"
+        ));
+    }
+    Ok(())
+}
+
+/// Repair a stub whose kernel or initrd was replaced and record it as intact in the manifest.
+fn refresh_hashes(args: RefreshHashesCommand) -> Result<()> {
+    let esp_paths = EspPaths::new(&args.esp, args.xbootldr.as_deref());
+    let signer = SigningKey::new(KeyPair::new(&args.public_key, &args.private_key));
+    let written = pe::refresh_hashes(
+        &args.stub,
+        &esp_paths.boot,
+        &signer,
+        args.kernel.as_deref(),
+        args.initrd.as_deref(),
+    )?;
+
+    // Otherwise, the next installation would consider the files modified and rewrite them.
+    if esp_paths.manifest.exists() {
+        let mut manifest = Manifest::read(&esp_paths);
+        for path in iter::once(&args.stub).chain(&written) {
+            if manifest.contains(path) {
+                manifest.record(path)?;
+            }
+        }
+        manifest.write(&esp_paths.manifest)?;
+    }
+    Ok(())
+}
+
+/// Remove the files that no installed stub needs or, in a dry run, list them.
+fn gc(args: GcCommand) -> Result<()> {
+    let esp_paths = EspPaths::new(&args.esp, args.xbootldr.as_deref());
+
+    if args.dry_run {
+        let live_files = gc::installed_files(&esp_paths)?;
+        for orphan in gc::find_orphans(&esp_paths, &live_files)? {
+            println!("{}", orphan.display());
+        }
+        return Ok(());
+    }
+
+    gc::collect_garbage(&esp_paths)
+}
+
+/// Print the versions of the generations that reference a file, one per line.
+fn references(args: ReferencesCommand) -> Result<()> {
+    let esp_paths = EspPaths::new(&args.esp, args.xbootldr.as_deref());
+    let references = esp::references(&esp_paths, &args.file)?;
+    for version in &references.versions {
+        println!("{version}");
+    }
+
+    for (stub, error) in &references.unreadable {
+        println!("Warning: failed to read the references of {stub:?}: {error}");
+    }
+    if !references.unreadable.is_empty() {
+        return Err(anyhow!(
+            "{} stubs could not be read, so more generations may reference {:?}",
+            references.unreadable.len(),
+            args.file
+        ));
+    }
+    Ok(())
+}
+
+/// Print the generations of a profile as lanzaboote would install them.
+fn generations(args: GenerationsCommand) -> Result<()> {
+    for generation in generation::discover_generations(&args.profile)? {
+        let bootspec = &generation.spec.bootspec;
+        println!("Generation {generation}: {}", bootspec.label);
+        for name in bootspec.specialisation.keys() {
+            println!("Generation {generation}, specialisation {name}");
+        }
+    }
+    Ok(())
+}
+
+/// Print the installed stubs, one per line or as JSON.
+fn list(args: ListCommand) -> Result<()> {
+    let esp_paths = EspPaths::new(&args.esp, args.xbootldr.as_deref());
+    let stubs = list::list(&esp_paths)?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&stubs)?);
+    } else {
+        for stub in stubs {
+            println!("{stub}");
+        }
+    }
+    Ok(())
+}
+
+/// Print the UEFI path of a file on the ESP.
+fn uefi_path(args: UefiPathCommand) -> Result<()> {
+    let target = match args.target {
+        Some(target) => args.esp.join(target),
+        None => EspPaths::new(&args.esp, None).systemd_boot,
+    };
+    println!("{}", pe::uefi_path_for(&args.esp, &target)?);
+    Ok(())
+}
+
+/// Print the predicted PCR values of a stub in hex, one per line.
+fn predict_pcrs(args: PredictPcrsCommand) -> Result<()> {
+    let prediction = pe::predict_pcrs(&args.stub)?;
+    println!("11: {:x}", prediction.pcr11);
+    Ok(())
+}
+
+/// Export a stub for signing on another host and print the signing request.
+fn export_for_signing(args: ExportForSigningCommand) -> Result<()> {
+    let request = signing_request::export_for_signing(&args.stub, &args.out)?;
+    println!("{}", serde_json::to_string_pretty(&request)?);
+    Ok(())
+}
+
+/// Print the status of the ESP.
+///
+/// Unlike the other commands, this does not require the ESP to be writable because nothing is
+/// modified.
+fn status(args: StatusCommand) -> Result<()> {
+    let esp = args
+        .esp
+        .canonicalize()
+        .with_context(|| format!("Failed to open ESP {:?}", args.esp))?;
+    let esp_paths = EspPaths::new(esp, args.xbootldr.as_deref());
+    let status = status::status(&esp_paths, &args.efivars, &args.system, &args.tpm)?;
+    let signatures = args
+        .public_key
+        .as_deref()
+        .map(|public_key| status::signature_summary(&esp_paths, public_key))
+        .transpose()?;
+
+    if args.json {
+        let report = status::StatusReport {
+            status,
+            generations: status::generations(
+                &esp_paths,
+                &args.efivars,
+                args.public_key.as_deref(),
+            )?,
+            signatures,
+        };
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print!("{status}");
+        if let Some(signatures) = signatures {
+            print!("{signatures}");
+        }
+    }
+    Ok(())
+}
+
+/// Create the Secure Boot keys, export and enroll them.
+fn setup(args: SetupCommand) -> Result<()> {
+    let bundle = PkiBundle::new(&args.pki_bundle);
+    let report = setup::setup(
+        &bundle,
+        &args.common_name,
+        args.export,
+        args.enroll.then_some(args.efivars.as_path()),
+    )?;
+
+    if report.created {
+        println!(
+            "Created the Secure Boot keys in {} with owner GUID {}",
+            args.pki_bundle.display(),
+            report.owner
+        );
+    } else {
+        println!(
+            "Using the existing Secure Boot keys in {}",
+            args.pki_bundle.display()
+        );
+    }
+    for path in &report.exported {
+        println!("Wrote {}", path.display());
+    }
+    for key in &report.enrolled {
+        println!("Enrolled {}", key.name());
+    }
+    if let Some(minimum) = report.minimum_security_version {
+        println!("The minimum security version of the anti-rollback protection is {minimum}");
+    }
+    Ok(())
+}
+
+/// Open the ESP and warn if it does not look like one.
+fn open_esp(path: &Path) -> Result<esp::EspHandle> {
+    let esp = esp::open_esp(path)?;
+    if !esp.is_fat() {
+        println!(
+            "Warning: the ESP {} is not formatted with FAT. The firmware may not be able to read it.",
+            esp.root().display()
+        );
+    }
+    Ok(esp)
+}
+
+/// Lists from the command line replace the ones from the configuration file instead of adding to
+/// them.
+fn cli_or_config<T>(cli: Vec<T>, config: Vec<T>) -> Vec<T> {
+    if cli.is_empty() {
+        config
+    } else {
+        cli
+    }
+}
+
+/// Parse an extra section given as NAME=PATH.
+fn parse_extra_section(value: &str) -> Result<(String, PathBuf)> {
+    let (name, path) = value
+        .split_once('=')
+        .with_context(|| format!("Extra section {value:?} is not of the form NAME=PATH"))?;
+    Ok((name.to_owned(), PathBuf::from(path)))
 }
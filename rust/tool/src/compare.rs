@@ -0,0 +1,52 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+
+/// Compare a produced file byte by byte against a reference.
+///
+/// This catches nondeterminism in the assembly of the stubs. The error reports the first offset
+/// at which the files differ.
+pub fn compare_with_reference(produced: &Path, reference: &Path) -> Result<()> {
+    let produced_data =
+        fs::read(produced).with_context(|| format!("Failed to read produced file {produced:?}"))?;
+    let reference_data = fs::read(reference)
+        .with_context(|| format!("Failed to read reference file {reference:?}"))?;
+
+    match first_difference(&produced_data, &reference_data) {
+        None => {
+            println!("{} matches {}", produced.display(), reference.display());
+            Ok(())
+        }
+        Some(offset) => Err(anyhow!(
+            "{} differs from the reference {} at offset {offset:#x} ({} vs. {} bytes)",
+            produced.display(),
+            reference.display(),
+            produced_data.len(),
+            reference_data.len()
+        )),
+    }
+}
+
+/// Find the first offset at which two byte strings differ.
+///
+/// If one is a prefix of the other, they differ at the end of the shorter one.
+fn first_difference(a: &[u8], b: &[u8]) -> Option<usize> {
+    a.iter()
+        .zip(b)
+        .position(|(a, b)| a != b)
+        .or_else(|| (a.len() != b.len()).then_some(a.len().min(b.len())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_first_difference() {
+        assert_eq!(first_difference(b"lanzaboote", b"lanzaboote"), None);
+        assert_eq!(first_difference(b"lanzaboote", b"lanzaBoote"), Some(5));
+        assert_eq!(first_difference(b"lanza", b"lanzaboote"), Some(5));
+        assert_eq!(first_difference(b"lanzaboote", b""), Some(0));
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::esp;
+use crate::loader_conf::{ConsoleMode, Timeout};
+use crate::pe;
+
+/// The configuration file of `lzbt install`.
+///
+/// Every setting is optional and falls back to the command line. Options that are given on the
+/// command line take precedence over the ones in the file. Relative paths are relative to the
+/// directory of the file.
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    /// EFI system partition mountpoint.
+    pub esp: Option<PathBuf>,
+    /// Mountpoints of further ESPs that are kept in sync with the ESP, e.g. on a RAID1.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mirror_esps: Vec<PathBuf>,
+    /// XBOOTLDR partition mountpoint to install the kernels, initrds and stubs to.
+    pub xbootldr: Option<PathBuf>,
+    pub public_key: Option<PathBuf>,
+    pub private_key: Option<PathBuf>,
+    /// How many generations to install. 0 installs all of them.
+    pub configuration_limit: Option<usize>,
+    /// Version of a known-good generation to always keep.
+    pub pinned_recovery: Option<u64>,
+    /// How long systemd-boot shows the boot menu, in seconds or menu-force, menu-hidden or
+    /// menu-disabled.
+    pub timeout: Option<Timeout>,
+    /// Version of the generation to make the default boot entry.
+    pub default_generation: Option<u64>,
+    /// Whether the kernel parameters can be edited in the boot menu.
+    pub editor: Option<bool>,
+    pub console_mode: Option<ConsoleMode>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub excluded_generations: Vec<u64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub section_providers: Vec<PathBuf>,
+    /// Additional sections by name with the files they are read from.
+    ///
+    /// This has to be the last field because TOML needs tables to come after all plain values.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub extra_sections: BTreeMap<String, PathBuf>,
+}
+
+impl Config {
+    /// Read and validate a configuration file.
+    pub fn read(path: &Path) -> Result<Self> {
+        let config = Self::parse(path)?;
+        let problems = config.problems();
+        if !problems.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Invalid configuration file {path:?}: {}",
+                problems.join("; ")
+            ));
+        }
+        Ok(config)
+    }
+
+    /// Read a configuration file without validating the settings.
+    pub fn parse(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read configuration file {path:?}"))?;
+        let mut config: Self = toml::from_str(&contents)
+            .with_context(|| format!("Failed to parse configuration file {path:?}"))?;
+        config.resolve_paths(path.parent().unwrap_or_else(|| Path::new("")));
+        Ok(config)
+    }
+
+    /// Make the relative paths relative to `directory` instead of the working directory.
+    fn resolve_paths(&mut self, directory: &Path) {
+        let resolve = |path: &mut PathBuf| {
+            if path.is_relative() {
+                *path = directory.join(&*path);
+            }
+        };
+
+        self.esp.iter_mut().for_each(resolve);
+        self.mirror_esps.iter_mut().for_each(resolve);
+        self.xbootldr.iter_mut().for_each(resolve);
+        self.public_key.iter_mut().for_each(resolve);
+        self.private_key.iter_mut().for_each(resolve);
+        self.section_providers.iter_mut().for_each(resolve);
+        self.extra_sections.values_mut().for_each(resolve);
+    }
+
+    /// Check the settings and the files they refer to and describe every problem.
+    ///
+    /// Nothing is written, in particular not to the ESP.
+    pub fn check(&self) -> Vec<String> {
+        let mut problems = self.problems();
+
+        if let Some(esp) = &self.esp {
+            if let Err(e) = esp::open_esp(esp) {
+                problems.push(format!("esp: {e:#}"));
+            }
+        }
+        for esp in &self.mirror_esps {
+            if let Err(e) = esp::open_esp(esp) {
+                problems.push(format!("mirror-esps: {e:#}"));
+            }
+        }
+        if let Some(xbootldr) = &self.xbootldr {
+            if let Err(e) = esp::open_xbootldr(xbootldr) {
+                problems.push(format!("xbootldr: {e:#}"));
+            }
+        }
+        for (setting, path) in [
+            ("public-key", &self.public_key),
+            ("private-key", &self.private_key),
+        ] {
+            if let Some(path) = path {
+                if let Err(e) = fs::File::open(path) {
+                    problems.push(format!("{setting}: Failed to read {path:?}: {e}"));
+                }
+            }
+        }
+        for (name, path) in &self.extra_sections {
+            if let Err(e) = fs::File::open(path) {
+                problems.push(format!(
+                    "extra-sections: Failed to read {path:?} of {name}: {e}"
+                ));
+            }
+        }
+        for command in &self.section_providers {
+            let executable = fs::metadata(command)
+                .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+                .unwrap_or(false);
+            if !executable {
+                problems.push(format!(
+                    "section-providers: {command:?} is not an executable file"
+                ));
+            }
+        }
+
+        problems
+    }
+
+    /// Describe the settings whose validity does not follow from their types.
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        for name in self.extra_sections.keys() {
+            if let Err(e) =
+                pe::validate_section_name(name).and_then(|()| pe::validate_extra_section_name(name))
+            {
+                problems.push(format!("extra-sections: {e}"));
+            }
+        }
+        if !self.mirror_esps.is_empty() && self.xbootldr.is_some() {
+            problems
+                .push("mirror-esps: Mirrored ESPs cannot share an XBOOTLDR partition".to_owned());
+        }
+        // NixOS numbers the generations from 1.
+        for (setting, version) in [
+            ("pinned-recovery", self.pinned_recovery),
+            ("default-generation", self.default_generation),
+        ]
+        .into_iter()
+        .chain(
+            self.excluded_generations
+                .iter()
+                .map(|version| ("excluded-generations", Some(*version))),
+        ) {
+            if version == Some(0) {
+                problems.push(format!("{setting}: There is no generation 0"));
+            }
+        }
+        if let Some(version) = self.pinned_recovery {
+            if self.excluded_generations.contains(&version) {
+                problems.push(format!(
+                    "pinned-recovery: The pinned recovery generation {version} is excluded"
+                ));
+            }
+        }
+        if let Some(version) = self.default_generation {
+            if self.excluded_generations.contains(&version) {
+                problems.push(format!(
+                    "default-generation: The default generation {version} is excluded"
+                ));
+            }
+        }
+
+        problems
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loader_conf::MenuTimeout;
+
+    #[test]
+    fn round_trip_config() -> Result<()> {
+        let config = Config {
+            esp: Some(PathBuf::from("/boot")),
+            public_key: Some(PathBuf::from("/etc/secureboot/keys/db/db.pem")),
+            private_key: Some(PathBuf::from("/etc/secureboot/keys/db/db.key")),
+            configuration_limit: Some(5),
+            pinned_recovery: Some(3),
+            timeout: Some(Timeout::Seconds(0)),
+            editor: Some(false),
+            console_mode: Some(ConsoleMode::Max),
+            excluded_generations: vec![4],
+            extra_sections: BTreeMap::from([(
+                ".splash".to_owned(),
+                PathBuf::from("/etc/splash.bmp"),
+            )]),
+            ..Config::default()
+        };
+
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("lanzaboote.toml");
+        fs::write(&path, toml::to_string(&config)?)?;
+        assert_eq!(Config::read(&path)?, config);
+        Ok(())
+    }
+
+    #[test]
+    fn read_special_timeouts() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("lanzaboote.toml");
+
+        fs::write(&path, "timeout = 3\n")?;
+        assert_eq!(Config::read(&path)?.timeout, Some(Timeout::Seconds(3)));
+
+        fs::write(&path, "timeout = \"menu-hidden\"\n")?;
+        assert_eq!(
+            Config::read(&path)?.timeout,
+            Some(Timeout::Menu(MenuTimeout::MenuHidden))
+        );
+
+        fs::write(&path, "timeout = \"forever\"\n")?;
+        assert!(Config::read(&path).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reject_invalid_config() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("lanzaboote.toml");
+
+        fs::write(&path, "configuration-limt = 2\n")?;
+        assert!(Config::read(&path).is_err());
+
+        fs::write(&path, "pinned-recovery = 2\nexcluded-generations = [2]\n")?;
+        assert!(Config::read(&path).is_err());
+
+        fs::write(&path, "[extra-sections]\n\".linux\" = \"/etc/kernel\"\n")?;
+        assert!(Config::read(&path).is_err());
+
+        fs::write(&path, "default-generation = 0\n")?;
+        assert!(Config::read(&path).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_relative_paths() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("lanzaboote.toml");
+        fs::write(
+            &path,
+            r#"esp = "/boot"
+public-key = "keys/db.pem"
+
+[extra-sections]
+".splash" = "splash.bmp"
+"#,
+        )?;
+
+        let config = Config::read(&path)?;
+        assert_eq!(config.esp, Some(PathBuf::from("/boot")));
+        assert_eq!(config.public_key, Some(tmpdir.path().join("keys/db.pem")));
+        assert_eq!(
+            config.extra_sections[".splash"],
+            tmpdir.path().join("splash.bmp")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn check_referenced_files() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let public_key = tmpdir.path().join("db.pem");
+        fs::write(&public_key, "")?;
+
+        let config = Config {
+            esp: Some(tmpdir.path().to_path_buf()),
+            public_key: Some(public_key),
+            private_key: Some(tmpdir.path().join("db.key")),
+            ..Config::default()
+        };
+        let problems = config.check();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("private-key:"));
+        Ok(())
+    }
+}
@@ -0,0 +1,99 @@
+use std::ffi::OsString;
+use std::fmt;
+use std::path::PathBuf;
+
+/// Failures that callers may want to handle specifically.
+///
+/// Inside lzbt, these errors are passed around as `anyhow::Error` like all others so that
+/// context can be attached on the way up. The library API converts them back at its boundary, so
+/// that its users can match on them. All other failures become [`LanzabooteError::Other`].
+#[derive(Debug)]
+pub enum LanzabooteError {
+    /// objcopy failed to assemble an image.
+    ObjcopyFailed { args: Vec<OsString> },
+    /// sbsign failed to sign a file.
+    SigningFailed { args: Vec<OsString> },
+    /// The new files do not fit onto the ESP, even after removing pruned generations.
+    EspFull {
+        needed: u64,
+        available: u64,
+        removable: u64,
+    },
+    /// The stub cannot be used to assemble an image.
+    InvalidStub { reason: String },
+    /// A file does not match the hash that is embedded into a stub.
+    HashMismatch { path: PathBuf, stub: PathBuf },
+    /// A temporary file or directory cannot be created because of missing permissions.
+    TempDirNotWritable { path: PathBuf },
+    /// Any other failure, with the context of what failed.
+    Other(anyhow::Error),
+}
+
+impl fmt::Display for LanzabooteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::ObjcopyFailed { args } => write!(f, "Failed to wrap in pe with args `{args:?}`"),
+            Self::SigningFailed { args } => {
+                write!(f, "Failed to sign file using sbsign with args `{args:?}`")
+            }
+            Self::EspFull {
+                needed,
+                available,
+                removable,
+            } => write!(
+                f,
+                "Not enough space on the ESP: {needed} bytes are needed, but only {available} bytes are available even after removing {removable} bytes of pruned generations"
+            ),
+            Self::InvalidStub { reason } => write!(f, "{reason}"),
+            Self::HashMismatch { path, stub } => write!(
+                f,
+                "{path:?} does not match the hash embedded into the stub {stub:?}"
+            ),
+            Self::TempDirNotWritable { path } => write!(
+                f,
+                "Permission denied to create the temporary path {path:?}. Set TMPDIR to a directory that is writable by the current user"
+            ),
+            Self::Other(error) => write!(f, "{error:#}"),
+        }
+    }
+}
+
+impl std::error::Error for LanzabooteError {}
+
+impl From<anyhow::Error> for LanzabooteError {
+    /// Recover the failure that callers may handle from underneath the context it got on the way
+    /// up, or wrap the error as it is.
+    fn from(error: anyhow::Error) -> Self {
+        match error.downcast::<Self>() {
+            Ok(error) => error,
+            Err(error) => Self::Other(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+
+    use super::*;
+
+    #[test]
+    fn recover_error_from_underneath_its_context() {
+        let error = Err::<(), _>(LanzabooteError::EspFull {
+            needed: 2,
+            available: 1,
+            removable: 0,
+        })
+        .context("Failed to install")
+        .context("Failed to install to the ESP")
+        .unwrap_err();
+        assert!(matches!(
+            LanzabooteError::from(error),
+            LanzabooteError::EspFull { needed: 2, .. }
+        ));
+
+        let error = LanzabooteError::from(anyhow::anyhow!("Something else"));
+        assert!(matches!(error, LanzabooteError::Other(_)));
+        assert_eq!(error.to_string(), "Something else");
+    }
+}
@@ -1,80 +1,519 @@
-use std::array::IntoIter;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
 use std::path::{Path, PathBuf};
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
+use nix::sys::{statfs, statvfs};
 
+use crate::fat;
 use crate::generation::Generation;
+use crate::pe;
+use crate::utils;
 
+/// A validated ESP mountpoint.
+///
+/// Can be obtained via `open_esp`.
+#[derive(Debug)]
+pub struct EspHandle {
+    root: PathBuf,
+    is_fat: bool,
+}
+
+impl EspHandle {
+    /// The canonical path of the ESP mountpoint.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Whether the ESP is formatted with FAT, as required by the UEFI specification.
+    pub fn is_fat(&self) -> bool {
+        self.is_fat
+    }
+}
+
+impl AsRef<Path> for EspHandle {
+    fn as_ref(&self) -> &Path {
+        &self.root
+    }
+}
+
+/// Canonicalize and validate the mountpoint of an ESP.
+///
+/// The path must be an existing directory that is mounted read-write. Whether it is formatted
+/// with FAT is recorded in the handle, but not enforced, so that the ESP can be staged on another
+/// file system.
+pub fn open_esp(path: &Path) -> Result<EspHandle> {
+    let root = path
+        .canonicalize()
+        .with_context(|| format!("Failed to open ESP {path:?}"))?;
+    if !root.is_dir() {
+        return Err(anyhow!("The ESP {path:?} is not a directory"));
+    }
+
+    let flags = statvfs::statvfs(&root)
+        .with_context(|| format!("Failed to determine the mount options of the ESP {path:?}"))?
+        .flags();
+    ensure_writable(&root, flags, "ESP")?;
+
+    let is_fat = statfs::statfs(&root)
+        .with_context(|| format!("Failed to determine the file system of the ESP {path:?}"))?
+        .filesystem_type()
+        == statfs::MSDOS_SUPER_MAGIC;
+
+    Ok(EspHandle { root, is_fat })
+}
+
+/// Canonicalize and validate the mountpoint of an XBOOTLDR partition like `open_esp`.
+///
+/// Unlike the ESP, a missing XBOOTLDR partition would not be noticed otherwise, because the
+/// directories on it are created as needed. If it is not mounted, the kernels and stubs would end
+/// up on the file system below the mountpoint, where the boot loader cannot find them.
+pub fn open_xbootldr(path: &Path) -> Result<PathBuf> {
+    let root = path
+        .canonicalize()
+        .with_context(|| format!("Failed to open XBOOTLDR partition {path:?}"))?;
+    if !root.is_dir() {
+        return Err(anyhow!(
+            "The XBOOTLDR partition {path:?} is not a directory"
+        ));
+    }
+
+    let flags = statvfs::statvfs(&root)
+        .with_context(|| {
+            format!("Failed to determine the mount options of the XBOOTLDR partition {path:?}")
+        })?
+        .flags();
+    ensure_writable(&root, flags, "XBOOTLDR partition")?;
+
+    Ok(root)
+}
+
+/// Make sure that a boot partition is not mounted read-only.
+///
+/// Otherwise, the installation would only fail at the first write after the expensive work of
+/// hashing and signing.
+fn ensure_writable(root: &Path, flags: statvfs::FsFlags, partition: &str) -> Result<()> {
+    if flags.contains(statvfs::FsFlags::ST_RDONLY) {
+        return Err(anyhow!(
+            "The {partition} {} is mounted read-only. Remount it read-write (e.g. with `mount -o remount,rw {}`) and try again.",
+            root.display(),
+            root.display()
+        ));
+    }
+    Ok(())
+}
+
+/// The UEFI architecture of a machine, which determines the file names of systemd-boot and of the
+/// fallback boot loader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Architecture {
+    Ia32,
+    X64,
+    Arm,
+    Aa64,
+    Riscv64,
+    Loongarch64,
+}
+
+impl Architecture {
+    /// The architecture of the running system.
+    pub fn host() -> Option<Self> {
+        match std::env::consts::ARCH {
+            "x86" => Some(Self::Ia32),
+            "x86_64" => Some(Self::X64),
+            "arm" => Some(Self::Arm),
+            "aarch64" => Some(Self::Aa64),
+            "riscv64" => Some(Self::Riscv64),
+            "loongarch64" => Some(Self::Loongarch64),
+            _ => None,
+        }
+    }
+
+    /// The architecture that a Nix system double (e.g. `aarch64-linux`) boots with.
+    pub fn for_system(system: &str) -> Option<Self> {
+        let name = pe::uefi_architecture_for_system(system)?;
+        [
+            Self::Ia32,
+            Self::X64,
+            Self::Arm,
+            Self::Aa64,
+            Self::Riscv64,
+            Self::Loongarch64,
+        ]
+        .into_iter()
+        .find(|architecture| architecture.name() == name)
+    }
+
+    /// The name that UEFI uses in file names, e.g. `aa64` in `BOOTAA64.EFI`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Ia32 => "ia32",
+            Self::X64 => "x64",
+            Self::Arm => "arm",
+            Self::Aa64 => "aa64",
+            Self::Riscv64 => "riscv64",
+            Self::Loongarch64 => "loongarch64",
+        }
+    }
+}
+
+impl Default for Architecture {
+    /// The architecture of the running system, or x64 if it has no UEFI architecture.
+    fn default() -> Self {
+        Self::host().unwrap_or(Self::X64)
+    }
+}
+
+/// Paths to the boot files that are not specific to a generation.
+///
+/// The boot loader and its configuration are always installed to the ESP. The kernels, initrds
+/// and stubs are installed to the boot partition. Usually, this is the ESP as well. If an
+/// XBOOTLDR partition is used, it is this partition instead.
 pub struct EspPaths {
     pub esp: PathBuf,
+    pub boot: PathBuf,
     pub efi: PathBuf,
     pub nixos: PathBuf,
-    pub kernel: PathBuf,
-    pub initrd: PathBuf,
+    pub manifest: PathBuf,
     pub linux: PathBuf,
-    pub lanzaboote_image: PathBuf,
     pub efi_fallback_dir: PathBuf,
-    pub efi_fallback: PathBuf,
+    /// Fallback boot loaders (e.g. `BOOTX64.EFI`) by UEFI architecture (e.g. `x64`).
+    pub efi_fallbacks: BTreeMap<String, PathBuf>,
+    /// The architecture of systemd-boot and of the default fallback boot loader.
+    pub architecture: Architecture,
     pub systemd: PathBuf,
     pub systemd_boot: PathBuf,
+    pub loader: PathBuf,
+    pub loader_conf: PathBuf,
+    pub random_seed: PathBuf,
+    /// Directory of the Type #1 boot loader entries next to the kernels and initrds.
+    pub entries: PathBuf,
 }
 
 impl EspPaths {
-    pub fn new(esp: impl AsRef<Path>, generation: &Generation) -> Result<Self> {
+    pub fn new(esp: impl AsRef<Path>, xbootldr: Option<&Path>) -> Self {
         let esp = esp.as_ref();
+        let boot = xbootldr.unwrap_or(esp);
+        let architecture = Architecture::default();
         let efi = esp.join("EFI");
-        let efi_nixos = efi.join("nixos");
-        let efi_linux = efi.join("Linux");
+        let efi_nixos = boot.join("EFI/nixos");
+        let efi_linux = boot.join("EFI/Linux");
         let efi_systemd = efi.join("systemd");
         let efi_efi_fallback_dir = efi.join("BOOT");
+        let loader = esp.join("loader");
 
-        let bootspec = &generation.spec.bootspec;
-
-        Ok(Self {
+        Self {
             esp: esp.to_path_buf(),
+            boot: boot.to_path_buf(),
             efi,
             nixos: efi_nixos.clone(),
-            kernel: efi_nixos.join(nixos_path(&bootspec.kernel, "bzImage")?),
-            initrd: efi_nixos.join(nixos_path(
-                bootspec
-                    .initrd
-                    .as_ref()
-                    .context("Lanzaboote does not support missing initrd yet")?,
-                "initrd",
-            )?),
-            linux: efi_linux.clone(),
-            lanzaboote_image: efi_linux.join(generation_path(generation)),
+            manifest: efi_nixos.join("manifest.json"),
+            linux: efi_linux,
             efi_fallback_dir: efi_efi_fallback_dir.clone(),
-            efi_fallback: efi_efi_fallback_dir.join("BOOTX64.EFI"),
+            efi_fallbacks: BTreeMap::from([(
+                architecture.name().to_owned(),
+                fallback_path(&efi_efi_fallback_dir, architecture.name()),
+            )]),
+            architecture,
             systemd: efi_systemd.clone(),
-            systemd_boot: efi_systemd.join("systemd-bootx64.efi"),
-        })
+            systemd_boot: systemd_boot_path(&efi_systemd, architecture),
+            loader: loader.clone(),
+            loader_conf: loader.join("loader.conf"),
+            random_seed: loader.join("random-seed"),
+            entries: boot.join("loader/entries"),
+        }
+    }
+
+    /// Install systemd-boot and the default fallback boot loader for another architecture than the
+    /// one of the running system.
+    ///
+    /// This has to be called before choosing other fallback architectures.
+    pub fn with_architecture(mut self, architecture: Architecture) -> Self {
+        self.architecture = architecture;
+        self.systemd_boot = systemd_boot_path(&self.systemd, architecture);
+        self.efi_fallbacks = BTreeMap::from([(
+            architecture.name().to_owned(),
+            fallback_path(&self.efi_fallback_dir, architecture.name()),
+        )]);
+        self
+    }
+
+    /// Install fallback boot loaders for the given UEFI architectures instead of the default one.
+    ///
+    /// This makes removable media bootable on firmware of several architectures.
+    pub fn with_fallback_architectures<'a>(
+        mut self,
+        architectures: impl IntoIterator<Item = &'a str>,
+    ) -> Self {
+        self.efi_fallbacks = architectures
+            .into_iter()
+            .map(|architecture| {
+                (
+                    architecture.to_owned(),
+                    fallback_path(&self.efi_fallback_dir, architecture),
+                )
+            })
+            .collect();
+        self
     }
 
     /// Return the used file paths to store as garbage collection roots.
-    pub fn to_iter(&self) -> IntoIter<&PathBuf, 11> {
+    pub fn to_iter(&self) -> impl Iterator<Item = &PathBuf> {
         [
             &self.esp,
+            &self.boot,
             &self.efi,
             &self.nixos,
-            &self.kernel,
-            &self.initrd,
+            &self.manifest,
             &self.linux,
-            &self.lanzaboote_image,
             &self.efi_fallback_dir,
-            &self.efi_fallback,
             &self.systemd,
             &self.systemd_boot,
+            &self.loader,
+            &self.random_seed,
+            &self.entries,
         ]
         .into_iter()
+        .chain(self.efi_fallbacks.values())
+    }
+}
+
+/// Path of systemd-boot for a UEFI architecture, e.g. `EFI/systemd/systemd-bootaa64.efi`.
+fn systemd_boot_path(efi_systemd: &Path, architecture: Architecture) -> PathBuf {
+    efi_systemd.join(format!("systemd-boot{}.efi", architecture.name()))
+}
+
+/// Path of the fallback boot loader for a UEFI architecture, e.g. `EFI/BOOT/BOOTAA64.EFI`.
+fn fallback_path(efi_fallback_dir: &Path, architecture: &str) -> PathBuf {
+    efi_fallback_dir.join(format!("BOOT{}.EFI", architecture.to_uppercase()))
+}
+
+/// Paths to the boot files of a specific generation.
+#[derive(Clone)]
+pub struct EspGenerationPaths {
+    /// The kernel on the ESP. If it is embedded into the stub, it is not stored separately.
+    pub kernel: Option<PathBuf>,
+    /// The initrd on the ESP. Kernels that need no initrd are booted without one.
+    pub initrd: Option<PathBuf>,
+    pub lanzaboote_image: PathBuf,
+}
+
+impl EspGenerationPaths {
+    pub fn new(esp_paths: &EspPaths, generation: &Generation) -> Result<Self> {
+        let bootspec = &generation.spec.bootspec;
+
+        Ok(Self {
+            kernel: Some(
+                esp_paths
+                    .nixos
+                    .join(nixos_path(&bootspec.kernel, "bzImage")?),
+            ),
+            initrd: bootspec
+                .initrd
+                .as_ref()
+                .map(|initrd| nixos_path(initrd, "initrd"))
+                .transpose()?
+                .map(|initrd| esp_paths.nixos.join(initrd)),
+            lanzaboote_image: esp_paths.linux.join(generation_path(generation)),
+        })
+    }
+
+    /// Name the kernel and initrd after the hash of their contents.
+    ///
+    /// This way, generations that use identical kernels or initrds share the same files on the
+    /// ESP instead of storing a copy per generation.
+    pub fn content_addressed(
+        mut self,
+        esp_paths: &EspPaths,
+        kernel: &Path,
+        initrd: Option<&Path>,
+    ) -> Result<Self> {
+        self.kernel = Some(
+            esp_paths
+                .nixos
+                .join(content_addressed_path(kernel, "bzImage")?),
+        );
+        if let Some(initrd) = initrd {
+            self.initrd = Some(
+                esp_paths
+                    .nixos
+                    .join(content_addressed_path(initrd, "initrd")?),
+            );
+        }
+        Ok(self)
+    }
+
+    /// Embed the kernel into the stub instead of storing it on the ESP.
+    ///
+    /// Only the initrd remains a separate file.
+    pub fn embed_kernel(mut self) -> Self {
+        self.kernel = None;
+        self
+    }
+
+    /// Embed the initrd into the stub instead of storing it on the ESP.
+    ///
+    /// Together with an embedded kernel, the stub is the only file of the generation.
+    pub fn embed_initrd(mut self) -> Self {
+        self.initrd = None;
+        self
+    }
+
+    /// Return the used file paths to store as garbage collection roots.
+    pub fn to_iter(&self) -> impl Iterator<Item = &PathBuf> {
+        self.kernel
+            .iter()
+            .chain(&self.initrd)
+            .chain([&self.lanzaboote_image])
+    }
+}
+
+/// Whether a file in the `EFI/Linux` directory belongs to NixOS.
+///
+/// This directory is potentially shared with other distributions. The names of all images that
+/// lanzaboote installs there start with "nixos-".
+pub fn is_nixos_image(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .map_or(false, |n| n.starts_with("nixos-"))
+}
+
+/// The stubs in the `EFI/Linux` directory that belong to NixOS, sorted by name.
+pub fn nixos_images(linux: &Path) -> Result<Vec<PathBuf>> {
+    if !linux.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut images = Vec::new();
+    for entry in fs::read_dir(linux).with_context(|| format!("Failed to read {linux:?}"))? {
+        let path = entry?.path();
+        // Skip other files of NixOS, e.g. the metadata sidecars of the stubs.
+        if is_nixos_image(&path)
+            && path
+                .extension()
+                .map_or(false, |extension| extension == "efi")
+        {
+            images.push(path);
+        }
+    }
+    images.sort();
+    Ok(images)
+}
+
+/// The version of the generation that an image in the `EFI/Linux` directory belongs to.
+pub fn image_version(path: &Path) -> Option<u64> {
+    let name = path
+        .file_name()?
+        .to_str()?
+        .strip_prefix("nixos-generation-")?;
+    let end = name
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(name.len());
+    name[..end].parse().ok()
+}
+
+/// Strip the boot counter of systemd-boot from the name of a stub.
+///
+/// With boot counting, systemd-boot renames `nixos-generation-1+3.efi` to `nixos-generation-1+2-1.efi`
+/// on the first boot attempt and to `nixos-generation-1.efi` once the boot was assessed as good.
+/// All these names refer to the same boot entry.
+pub fn without_boot_counter(path: &Path) -> PathBuf {
+    let is_number = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    let is_counter = |counter: &str| match counter.split_once('-') {
+        Some((left, done)) => is_number(left) && is_number(done),
+        None => is_number(counter),
+    };
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_suffix(".efi"))
+        .and_then(|stem| stem.rsplit_once('+'))
+        .filter(|(name, counter)| !name.is_empty() && is_counter(counter))
+        .map(|(name, _)| name);
+    match name {
+        Some(name) => path.with_file_name(format!("{name}.efi")),
+        None => path.to_path_buf(),
+    }
+}
+
+/// Whether the boot counter of a stub has no tries left, e.g. `nixos-generation-1+0-3.efi`.
+///
+/// systemd-boot considers such entries bad and only boots them when chosen explicitly.
+pub fn boot_counter_exhausted(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_suffix(".efi"))
+        .and_then(|stem| stem.rsplit_once('+'))
+        .filter(|_| without_boot_counter(path) != path)
+        .map_or(false, |(_, counter)| {
+            counter.split('-').next().map_or(false, |left| left == "0")
+        })
+}
+
+/// Path of a stub with a fresh boot counter of the given number of tries.
+pub fn with_boot_counter(path: &Path, tries: u32) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .expect("Stub paths always have a file name")
+        .to_string_lossy();
+    path.with_file_name(format!("{stem}+{tries}.efi"))
+}
+
+/// The installed stub of a boot entry under any of its boot counting names.
+///
+/// Returns `None` if no stub of the entry is installed.
+pub fn installed_image(path: &Path) -> Result<Option<PathBuf>> {
+    if path.exists() {
+        return Ok(Some(path.to_path_buf()));
+    }
+    let entry = without_boot_counter(path);
+    match path.parent() {
+        Some(dir) => Ok(nixos_images(dir)?
+            .into_iter()
+            .find(|image| without_boot_counter(image) == entry)),
+        None => Ok(None),
+    }
+}
+
+/// The generations whose stubs reference a file, and the stubs whose references are unknown.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct References {
+    pub versions: Vec<u64>,
+    /// Stubs that could not be read, with the reason. Any of them may reference the file as well.
+    pub unreadable: Vec<(PathBuf, String)>,
+}
+
+/// The versions of the generations whose stubs reference a kernel or initrd on the ESP.
+///
+/// With content addressed kernels and initrds, a single file can be shared by several generations.
+/// A file that no stub references can safely be deleted. A stub that cannot be read does not hide
+/// the references of all others, it is reported instead.
+pub fn references(esp_paths: &EspPaths, file: &Path) -> Result<References> {
+    let file = file
+        .canonicalize()
+        .with_context(|| format!("Failed to resolve {file:?}"))?;
+    let boot = esp_paths
+        .boot
+        .canonicalize()
+        .with_context(|| format!("Failed to resolve {:?}", esp_paths.boot))?;
+
+    let mut versions = BTreeSet::new();
+    let mut unreadable = Vec::new();
+    for stub in nixos_images(&esp_paths.linux)? {
+        match pe::referenced_files(&stub, &boot) {
+            Ok(files) if files.contains(&file) => versions.extend(image_version(&stub)),
+            Ok(_) => {}
+            Err(e) => unreadable.push((stub, format!("{e:#}"))),
+        }
     }
+    Ok(References {
+        versions: versions.into_iter().collect(),
+        unreadable,
+    })
 }
 
 fn nixos_path(path: impl AsRef<Path>, name: &str) -> Result<PathBuf> {
-    let resolved = path
-        .as_ref()
-        .read_link()
-        .unwrap_or_else(|_| path.as_ref().into());
+    let resolved = utils::resolve_symlink(path.as_ref())?;
 
     let parent_final_component = resolved
         .parent()
@@ -84,20 +523,156 @@ fn nixos_path(path: impl AsRef<Path>, name: &str) -> Result<PathBuf> {
 
     let nixos_filename = format!("{}-{}.efi", parent_final_component, name);
 
-    Ok(PathBuf::from(nixos_filename))
+    Ok(PathBuf::from(fat::sanitize_fat_name(&nixos_filename)))
+}
+
+fn content_addressed_path(path: &Path, name: &str) -> Result<PathBuf> {
+    let hash = pe::file_hash(path).with_context(|| format!("Failed to hash {:?}", path))?;
+
+    Ok(PathBuf::from(format!("{:x}-{}.efi", hash, name)))
 }
 
 fn generation_path(generation: &Generation) -> PathBuf {
     if let Some(specialisation_name) = generation.is_specialised() {
-        PathBuf::from(format!(
+        PathBuf::from(fat::sanitize_fat_name(&format!(
             "nixos-generation-{}-specialisation-{}.efi",
             generation, specialisation_name
-        ))
+        )))
     } else {
         PathBuf::from(format!("nixos-generation-{}.efi", generation))
     }
 }
 
+/// Path of the Type #1 boot loader entry that boots the same generation as a stub.
+///
+/// The name of the entry starts with `nixos-bls-` instead of `nixos-`, e.g.
+/// `nixos-bls-generation-1.conf`, so that the boot menu and patterns for the default entry can
+/// tell it from the stub. The boot counter of the stub is not part of the name of the entry.
+pub fn boot_entry_path(esp_paths: &EspPaths, stub: &Path) -> PathBuf {
+    let stub = without_boot_counter(stub);
+    let name = stub
+        .file_name()
+        .expect("Stub paths always have a file name")
+        .to_string_lossy();
+    let name = name.strip_prefix("nixos-").unwrap_or(&name);
+    esp_paths
+        .entries
+        .join(Path::new(&format!("nixos-bls-{name}")).with_extension("conf"))
+}
+
+/// The Type #1 boot loader entries in the entries directory that lanzaboote wrote for the stubs,
+/// sorted by name.
+///
+/// The entries that systemd-boot installed for NixOS before lanzaboote, e.g.
+/// `nixos-generation-1.conf`, are not included.
+pub fn nixos_boot_entries(entries: &Path) -> Result<Vec<PathBuf>> {
+    if !entries.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut boot_entries = Vec::new();
+    for entry in fs::read_dir(entries).with_context(|| format!("Failed to read {entries:?}"))? {
+        let path = entry?.path();
+        let is_boot_entry = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map_or(false, |name| {
+                name.starts_with("nixos-bls-") && name.ends_with(".conf")
+            });
+        if is_boot_entry {
+            boot_entries.push(path);
+        }
+    }
+    boot_entries.sort();
+    Ok(boot_entries)
+}
+
+/// Path of the informational metadata sidecar of a stub.
+///
+/// The sidecar keeps its name when systemd-boot renames the stub to count boot attempts.
+pub fn metadata_path(stub: &Path) -> PathBuf {
+    let mut metadata = without_boot_counter(stub).into_os_string();
+    metadata.push(".meta");
+    PathBuf::from(metadata)
+}
+
+/// Path of the companion directory of a stub, from which systemd-stub picks up credentials and
+/// system extensions.
+///
+/// Like the metadata sidecar, the directory keeps its name when systemd-boot renames the stub to
+/// count boot attempts.
+pub fn extra_dir_path(stub: &Path) -> PathBuf {
+    let mut extra_dir = without_boot_counter(stub).into_os_string();
+    extra_dir.push(".extra.d");
+    PathBuf::from(extra_dir)
+}
+
+/// The companion directory of a stub and the files in it, if it exists.
+pub fn extra_files(stub: &Path) -> Result<Vec<PathBuf>> {
+    let extra_dir = extra_dir_path(stub);
+    if !extra_dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut files = vec![extra_dir.clone()];
+    for entry in
+        fs::read_dir(&extra_dir).with_context(|| format!("Failed to read {extra_dir:?}"))?
+    {
+        files.push(entry?.path());
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Whether a file in the `EFI/Linux` directory belongs to NixOS, either as an image or in the
+/// companion directory of one.
+pub fn is_nixos_file(path: &Path) -> bool {
+    is_nixos_image(path) || path.parent().map_or(false, is_nixos_image)
+}
+
+/// Path of the detached signature of a file, e.g. of an initrd.
+pub fn detached_signature_path(path: &Path) -> PathBuf {
+    let mut signature = path.as_os_str().to_owned();
+    signature.push(".sig");
+    PathBuf::from(signature)
+}
+
+/// Path of the stub for a cmdline profile of a generation.
+///
+/// The name starts with the name of the stub of the generation so that systemd-boot lists the
+/// profiles next to the generation.
+pub fn cmdline_profile_image_path(
+    esp_paths: &EspPaths,
+    generation: &Generation,
+    profile: &str,
+) -> PathBuf {
+    let generation_path = generation_path(generation);
+    let stem = generation_path
+        .file_stem()
+        .expect("Generation paths always have a file name")
+        .to_string_lossy();
+    esp_paths.linux.join(fat::sanitize_fat_name(&format!(
+        "{stem}-profile-{profile}.efi"
+    )))
+}
+
+/// Path of the stub for the safe mode entry of a generation.
+pub fn safe_mode_image_path(esp_paths: &EspPaths, generation: &Generation) -> PathBuf {
+    esp_paths
+        .linux
+        .join(format!("nixos-generation-{}-safe-mode.efi", generation))
+}
+
+/// Path of the stub for the pinned recovery generation.
+///
+/// The recovery stub has its own name so that it is distinct from the regular stub that was
+/// installed for the generation before it was pinned.
+pub fn recovery_image_path(esp_paths: &EspPaths, generation: &Generation) -> PathBuf {
+    esp_paths
+        .linux
+        .join(format!("nixos-generation-{}-recovery.efi", generation))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -115,4 +690,193 @@ mod tests {
         assert_eq!(generated_filename, expected_filename);
         Ok(())
     }
+
+    #[test]
+    fn name_fallback_boot_loaders_after_architecture() {
+        let esp_paths = EspPaths::new("/efi", None).with_fallback_architectures(["x64", "aa64"]);
+        let fallbacks: Vec<&PathBuf> = esp_paths.efi_fallbacks.values().collect();
+        assert_eq!(
+            fallbacks,
+            [
+                Path::new("/efi/EFI/BOOT/BOOTAA64.EFI"),
+                Path::new("/efi/EFI/BOOT/BOOTX64.EFI")
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_version_of_image() {
+        let version = |name: &str| image_version(Path::new(name));
+
+        assert_eq!(version("nixos-generation-12.efi"), Some(12));
+        assert_eq!(
+            version("nixos-generation-3-specialisation-work.efi"),
+            Some(3)
+        );
+        assert_eq!(version("nixos-generation-7-recovery.efi"), Some(7));
+        assert_eq!(version("ubuntu.efi"), None);
+        assert_eq!(version("nixos-generation-5+2-1.efi"), Some(5));
+    }
+
+    #[test]
+    fn strip_boot_counter() {
+        let strip = |name: &str| without_boot_counter(Path::new(name));
+
+        assert_eq!(
+            strip("/boot/EFI/Linux/nixos-generation-1+3.efi"),
+            Path::new("/boot/EFI/Linux/nixos-generation-1.efi")
+        );
+        assert_eq!(
+            strip("nixos-generation-1-safe-mode+0-3.efi"),
+            Path::new("nixos-generation-1-safe-mode.efi")
+        );
+        assert_eq!(
+            strip("nixos-generation-1.efi"),
+            Path::new("nixos-generation-1.efi")
+        );
+        assert_eq!(
+            strip("nixos-generation-1-profile-a+b.efi"),
+            Path::new("nixos-generation-1-profile-a+b.efi")
+        );
+        assert_eq!(
+            with_boot_counter(Path::new("nixos-generation-1.efi"), 3),
+            Path::new("nixos-generation-1+3.efi")
+        );
+    }
+
+    #[test]
+    fn detect_exhausted_boot_counter() {
+        let exhausted = |name: &str| boot_counter_exhausted(Path::new(name));
+
+        assert!(exhausted("nixos-generation-1+0-3.efi"));
+        assert!(exhausted("nixos-generation-1+0.efi"));
+        assert!(!exhausted("nixos-generation-1+1-2.efi"));
+        assert!(!exhausted("nixos-generation-1+3.efi"));
+        assert!(!exhausted("nixos-generation-1.efi"));
+        assert!(!exhausted("nixos-generation-1-profile-a+0-b.efi"));
+    }
+
+    #[test]
+    fn find_stub_under_boot_counting_name() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let stub = tmpdir.path().join("nixos-generation-1.efi");
+        let counted = tmpdir.path().join("nixos-generation-1+2-1.efi");
+        std::fs::write(&counted, b"stub")?;
+
+        assert_eq!(installed_image(&stub)?, Some(counted.clone()));
+        assert_eq!(
+            installed_image(&tmpdir.path().join("nixos-generation-1+3.efi"))?,
+            Some(counted)
+        );
+        assert_eq!(
+            installed_image(&tmpdir.path().join("nixos-generation-2.efi"))?,
+            None
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn open_valid_esp() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+
+        let esp = open_esp(&tmpdir.path().join("."))?;
+
+        assert_eq!(esp.root(), tmpdir.path().canonicalize()?);
+        Ok(())
+    }
+
+    #[test]
+    fn reject_nonexistent_esp() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+
+        assert!(open_esp(&tmpdir.path().join("nonexistent")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reject_regular_file_as_esp() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let file = tmpdir.path().join("file");
+        std::fs::write(&file, b"not an ESP")?;
+
+        let error = open_esp(&file).unwrap_err();
+
+        assert!(error.to_string().contains("is not a directory"));
+        Ok(())
+    }
+
+    #[test]
+    fn reject_read_only_esp() {
+        let esp = Path::new("/boot");
+
+        let error = ensure_writable(esp, statvfs::FsFlags::ST_RDONLY, "ESP").unwrap_err();
+
+        assert!(error.to_string().contains("mounted read-only"));
+        assert!(ensure_writable(esp, statvfs::FsFlags::ST_NOSUID, "ESP").is_ok());
+    }
+
+    #[test]
+    fn name_boot_loaders_after_architecture() {
+        let esp_paths = EspPaths::new("/efi", None).with_architecture(Architecture::Aa64);
+
+        assert_eq!(
+            esp_paths.systemd_boot,
+            Path::new("/efi/EFI/systemd/systemd-bootaa64.efi")
+        );
+        assert_eq!(
+            esp_paths.efi_fallbacks.values().collect::<Vec<_>>(),
+            [Path::new("/efi/EFI/BOOT/BOOTAA64.EFI")]
+        );
+    }
+
+    #[test]
+    fn architecture_of_nix_systems() {
+        assert_eq!(
+            Architecture::for_system("aarch64-linux"),
+            Some(Architecture::Aa64)
+        );
+        assert_eq!(
+            Architecture::for_system("x86_64-linux"),
+            Some(Architecture::X64)
+        );
+        assert_eq!(Architecture::for_system("powerpc64le-linux"), None);
+    }
+
+    #[test]
+    fn split_boot_files_between_esp_and_xbootldr() {
+        let esp_paths =
+            EspPaths::new("/efi", Some(Path::new("/boot"))).with_architecture(Architecture::X64);
+
+        assert_eq!(
+            esp_paths.systemd_boot,
+            Path::new("/efi/EFI/systemd/systemd-bootx64.efi")
+        );
+        assert_eq!(
+            esp_paths.efi_fallbacks["x64"],
+            Path::new("/efi/EFI/BOOT/BOOTX64.EFI")
+        );
+        assert_eq!(esp_paths.random_seed, Path::new("/efi/loader/random-seed"));
+        assert_eq!(esp_paths.nixos, Path::new("/boot/EFI/nixos"));
+        assert_eq!(esp_paths.linux, Path::new("/boot/EFI/Linux"));
+    }
+
+    #[test]
+    fn content_addressed_path_only_depends_on_contents() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let first = tmpdir.path().join("first");
+        let second = tmpdir.path().join("second");
+        std::fs::write(&first, b"initrd")?;
+        std::fs::write(&second, b"initrd")?;
+
+        let expected_filename = PathBuf::from(
+            "09e6c018d2c8c4903308613dd1b72484d57eadf12ec50ddc8f52e5accce470f2-initrd.efi",
+        );
+
+        assert_eq!(content_addressed_path(&first, "initrd")?, expected_filename);
+        assert_eq!(
+            content_addressed_path(&second, "initrd")?,
+            expected_filename
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,386 @@
+//! The file systems that the ESP of an installation is stored on.
+//!
+//! The installation, the garbage collection and the verification operate on a directory tree.
+//! For a mounted ESP, this is its mountpoint. Other file systems stage the tree in a directory and
+//! write it to the partition once the operation is done, so that nothing has to be mounted.
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{Context, Result};
+use tempfile::TempDir;
+
+use crate::esp::{self, EspHandle};
+use crate::pe;
+use crate::space::ImageCapacity;
+use crate::utils;
+
+/// A file system that holds an ESP.
+pub trait EspFilesystem {
+    /// The directory in which the files of the ESP are read and written.
+    fn root(&self) -> &Path;
+
+    /// Write the changes in `root` to the partition.
+    fn commit(&mut self) -> Result<()>;
+
+    /// The space of the partition if `root` only stages its files, so that the free space of
+    /// `root` says nothing about it.
+    fn capacity(&self) -> Option<ImageCapacity> {
+        None
+    }
+}
+
+/// An ESP that is mounted, so that its files are changed in place.
+#[derive(Debug)]
+pub struct MountedEsp(EspHandle);
+
+impl MountedEsp {
+    /// Open a mounted ESP like install does, i.e. make sure that it is mounted read-write.
+    pub fn open(mountpoint: &Path) -> Result<Self> {
+        esp::open_esp(mountpoint).map(Self)
+    }
+}
+
+impl EspFilesystem for MountedEsp {
+    fn root(&self) -> &Path {
+        self.0.root()
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// An ESP in a FAT image file that is accessed with mtools instead of being mounted.
+///
+/// This backs `install --esp-image` for building disk images and tests that exercise complete
+/// installations without root. The files are staged in a temporary directory: opening the image
+/// copies all of its files there and committing copies all staged files to a freshly formatted
+/// image, which then replaces the old one. Thus, the image always holds what a real FAT ESP would
+/// after the operation, e.g. no file modes and no symlinks, and a failed commit leaves the
+/// previous ESP intact. The new image keeps the volume serial number, the label, the FAT type and
+/// the cluster size of the old one, so that e.g. `/etc/fstab` entries by UUID or label still
+/// match.
+pub struct FatImage {
+    image: PathBuf,
+    size_kib: u64,
+    /// The parameters of the file system of an existing image. New images get the defaults of
+    /// mkfs.fat.
+    parameters: Option<FatParameters>,
+    capacity: ImageCapacity,
+    staging: TempDir,
+}
+
+/// The parameters of a FAT file system that formatting it again has to preserve.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct FatParameters {
+    /// 12, 16 or 32.
+    fat_type: u8,
+    bytes_per_sector: u16,
+    sectors_per_cluster: u8,
+    volume_id: u32,
+    /// The volume label, unless it is unset (`NO NAME`).
+    label: Option<String>,
+    /// Number of clusters of the data area.
+    clusters: u32,
+}
+
+impl FatParameters {
+    /// Read the parameters from the boot sector of a FAT file system.
+    fn from_boot_sector(boot_sector: &[u8]) -> Result<Self> {
+        let u16_at = |offset: usize| -> Result<u16> {
+            Ok(u16::from_le_bytes(
+                boot_sector
+                    .get(offset..offset + 2)
+                    .context("Truncated FAT boot sector")?
+                    .try_into()?,
+            ))
+        };
+        let u32_at = |offset: usize| -> Result<u32> {
+            Ok(u32::from_le_bytes(
+                boot_sector
+                    .get(offset..offset + 4)
+                    .context("Truncated FAT boot sector")?
+                    .try_into()?,
+            ))
+        };
+        if boot_sector.get(510..512) != Some(&[0x55, 0xaa][..]) {
+            return Err(anyhow::anyhow!("Missing FAT boot sector signature"));
+        }
+
+        let bytes_per_sector = u16_at(11)?;
+        let sectors_per_cluster = boot_sector[13];
+        if bytes_per_sector == 0 || sectors_per_cluster == 0 {
+            return Err(anyhow::anyhow!("Malformed FAT boot sector"));
+        }
+        let reserved_sectors = u32::from(u16_at(14)?);
+        let fats = u32::from(boot_sector[16]);
+        let root_entries = u32::from(u16_at(17)?);
+        let total_sectors = match u16_at(19)? {
+            0 => u32_at(32)?,
+            sectors => u32::from(sectors),
+        };
+        let fat_sectors = match u16_at(22)? {
+            0 => u32_at(36)?,
+            sectors => u32::from(sectors),
+        };
+
+        // The FAT type is determined by the number of clusters alone, see the FAT specification.
+        let sector_size = u32::from(bytes_per_sector);
+        let root_sectors = (root_entries * 32 + sector_size - 1) / sector_size;
+        let data_sectors = total_sectors
+            .checked_sub(reserved_sectors + fats * fat_sectors + root_sectors)
+            .context("Malformed FAT boot sector")?;
+        let clusters = data_sectors / u32::from(sectors_per_cluster);
+        let fat_type = match clusters {
+            clusters if clusters < 4085 => 12,
+            clusters if clusters < 65525 => 16,
+            _ => 32,
+        };
+
+        // The extended boot record follows the FAT32 specific fields on FAT32.
+        let extended = if fat_type == 32 { 64 } else { 36 };
+        if boot_sector[extended + 2] != 0x29 {
+            return Err(anyhow::anyhow!(
+                "FAT boot sector has no volume serial number"
+            ));
+        }
+        let volume_id = u32_at(extended + 3)?;
+        let label = String::from_utf8_lossy(&boot_sector[extended + 7..extended + 18])
+            .trim_end()
+            .to_owned();
+
+        Ok(Self {
+            fat_type,
+            bytes_per_sector,
+            sectors_per_cluster,
+            volume_id,
+            label: (!label.is_empty() && label != "NO NAME").then_some(label),
+            clusters,
+        })
+    }
+
+    /// Read the parameters of the FAT file system in an image file.
+    fn read(image: &Path) -> Result<Self> {
+        let mut boot_sector = [0; 512];
+        fs::File::open(image)
+            .and_then(|mut file| file.read_exact(&mut boot_sector))
+            .with_context(|| format!("Failed to read the boot sector of FAT image {image:?}"))?;
+        Self::from_boot_sector(&boot_sector)
+            .with_context(|| format!("Failed to read FAT image {image:?}"))
+    }
+
+    /// The space of the data area.
+    fn capacity(&self) -> ImageCapacity {
+        let cluster_size = u64::from(self.bytes_per_sector) * u64::from(self.sectors_per_cluster);
+        ImageCapacity {
+            total: u64::from(self.clusters) * cluster_size,
+            cluster_size,
+        }
+    }
+
+    /// The arguments of mkfs.fat that select these parameters.
+    fn mkfs_args(&self) -> Vec<String> {
+        let mut args = vec![
+            "-F".to_owned(),
+            self.fat_type.to_string(),
+            "-S".to_owned(),
+            self.bytes_per_sector.to_string(),
+            "-s".to_owned(),
+            self.sectors_per_cluster.to_string(),
+            "-i".to_owned(),
+            format!("{:08x}", self.volume_id),
+        ];
+        if let Some(label) = &self.label {
+            args.extend(["-n".to_owned(), label.clone()]);
+        }
+        args
+    }
+}
+
+impl FatImage {
+    /// Create a FAT image of `size_mib` mebibytes with an empty ESP at `image`.
+    pub fn create(image: &Path, size_mib: u64) -> Result<Self> {
+        let size_kib = size_mib * 1024;
+        format(image, size_kib, None)?;
+        Ok(Self {
+            image: image.to_path_buf(),
+            size_kib,
+            parameters: None,
+            capacity: FatParameters::read(image)?.capacity(),
+            staging: utils::tempdir()?,
+        })
+    }
+
+    /// Open an existing FAT image and stage its files.
+    pub fn open(image: &Path) -> Result<Self> {
+        let parameters = FatParameters::read(image)?;
+        let fat_image = Self {
+            image: image.to_path_buf(),
+            size_kib: pe::file_size(image)? / 1024,
+            capacity: parameters.capacity(),
+            parameters: Some(parameters),
+            staging: utils::tempdir()?,
+        };
+
+        // mcopy fails if the pattern for the files of an empty image matches nothing.
+        let listing = mtools("mdir", image, ["-b", "::/"])?;
+        if !listing.trim().is_empty() {
+            let staging = fat_image.staging.path().display().to_string();
+            mtools("mcopy", image, ["-s", "-m", "::/*", staging.as_str()])?;
+        }
+        Ok(fat_image)
+    }
+
+    /// The path of the image file.
+    pub fn image(&self) -> &Path {
+        &self.image
+    }
+}
+
+impl EspFilesystem for FatImage {
+    fn root(&self) -> &Path {
+        self.staging.path()
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(self.staging.path())
+            .with_context(|| format!("Failed to read {:?}", self.staging.path()))?
+        {
+            files.push(entry?.path().display().to_string());
+        }
+        files.sort();
+
+        // The new image is written next to the old one and only renamed over it once all files
+        // are copied, so that a failing mcopy does not destroy the previous ESP.
+        utils::atomic_replace(&self.image, |staging| {
+            format(staging, self.size_kib, self.parameters.as_ref())?;
+            if files.is_empty() {
+                return Ok(());
+            }
+            let args = ["-s", "-m"]
+                .into_iter()
+                .chain(files.iter().map(String::as_str))
+                .chain(["::/"]);
+            mtools("mcopy", staging, args).map(|_| ())
+        })
+        .with_context(|| format!("Failed to write the ESP to FAT image {:?}", self.image))
+    }
+
+    fn capacity(&self) -> Option<ImageCapacity> {
+        Some(self.capacity)
+    }
+}
+
+/// Create an empty FAT image of `size_kib` kibibytes at `image`, with the defaults of mkfs.fat
+/// unless `parameters` are given.
+fn format(image: &Path, size_kib: u64, parameters: Option<&FatParameters>) -> Result<()> {
+    // mkfs.fat only creates new image files.
+    if image.exists() {
+        fs::remove_file(image).with_context(|| format!("Failed to remove old image {image:?}"))?;
+    }
+    run(Command::new("mkfs.fat")
+        .args(parameters.map(FatParameters::mkfs_args).unwrap_or_default())
+        .arg("-C")
+        .arg(image)
+        .arg(size_kib.to_string()))
+    .with_context(|| format!("Failed to format FAT image {image:?}"))?;
+    Ok(())
+}
+
+/// Run an mtools command on an image and return its output.
+fn mtools<'a>(
+    command: &str,
+    image: &Path,
+    args: impl IntoIterator<Item = &'a str>,
+) -> Result<String> {
+    run(Command::new(command).arg("-i").arg(image).args(args))
+        .with_context(|| format!("Failed to run {command} on FAT image {image:?}"))
+}
+
+fn run(command: &mut Command) -> Result<String> {
+    let output = command
+        .output()
+        .with_context(|| format!("Failed to run {:?}", command.get_program()))?;
+    if !output.status.success() {
+        io::stderr()
+            .write_all(&output.stderr)
+            .context("Failed to write error output to stderr")?;
+        return Err(anyhow::anyhow!(
+            "{:?} exited with {}",
+            command.get_program(),
+            output.status
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A boot sector as mkfs.fat writes it, reduced to the fields that are read.
+    fn boot_sector(fat32: bool, total_sectors: u32, fat_sectors: u32, label: &[u8; 11]) -> Vec<u8> {
+        let mut sector = vec![0; 512];
+        sector[11..13].copy_from_slice(&512u16.to_le_bytes());
+        sector[13] = if fat32 { 1 } else { 4 };
+        sector[14..16].copy_from_slice(&(if fat32 { 32u16 } else { 4 }).to_le_bytes());
+        sector[16] = 2;
+        sector[17..19].copy_from_slice(&(if fat32 { 0u16 } else { 512 }).to_le_bytes());
+        sector[32..36].copy_from_slice(&total_sectors.to_le_bytes());
+        let extended = if fat32 {
+            sector[36..40].copy_from_slice(&fat_sectors.to_le_bytes());
+            64
+        } else {
+            sector[22..24].copy_from_slice(&(fat_sectors as u16).to_le_bytes());
+            36
+        };
+        sector[extended + 2] = 0x29;
+        sector[extended + 3..extended + 7].copy_from_slice(&0x1234_abcdu32.to_le_bytes());
+        sector[extended + 7..extended + 18].copy_from_slice(label);
+        sector[510..512].copy_from_slice(&[0x55, 0xaa]);
+        sector
+    }
+
+    #[test]
+    fn read_parameters_from_boot_sector() -> Result<()> {
+        let parameters =
+            FatParameters::from_boot_sector(&boot_sector(false, 131072, 128, b"ESP        "))?;
+        assert_eq!(
+            parameters,
+            FatParameters {
+                fat_type: 16,
+                bytes_per_sector: 512,
+                sectors_per_cluster: 4,
+                volume_id: 0x1234_abcd,
+                label: Some("ESP".to_owned()),
+                clusters: 32695,
+            }
+        );
+        assert_eq!(
+            parameters.capacity(),
+            ImageCapacity {
+                total: 32695 * 2048,
+                cluster_size: 2048,
+            }
+        );
+        assert_eq!(
+            parameters.mkfs_args(),
+            ["-F", "16", "-S", "512", "-s", "4", "-i", "1234abcd", "-n", "ESP"]
+        );
+
+        let parameters =
+            FatParameters::from_boot_sector(&boot_sector(true, 1048576, 8065, b"NO NAME    "))?;
+        assert_eq!(parameters.fat_type, 32);
+        assert_eq!(parameters.sectors_per_cluster, 1);
+        assert_eq!(parameters.label, None);
+
+        let mut sector = boot_sector(false, 131072, 128, b"ESP        ");
+        sector[510] = 0;
+        assert!(FatParameters::from_boot_sector(&sector).is_err());
+        Ok(())
+    }
+}
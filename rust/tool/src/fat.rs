@@ -0,0 +1,91 @@
+use sha2::{Digest, Sha256};
+
+/// The maximum length of a long file name on FAT in UTF-16 code units.
+const MAX_NAME_LENGTH: usize = 255;
+
+/// The number of hex digits of the hash that disambiguates truncated names.
+const HASH_SUFFIX_LENGTH: usize = 16;
+
+/// Extensions longer than this are truncated like the rest of the name.
+const MAX_EXTENSION_LENGTH: usize = 16;
+
+/// Whether a character may appear in a long file name on FAT.
+fn is_valid_fat_char(c: char) -> bool {
+    !c.is_control() && !matches!(c, '"' | '*' | '/' | ':' | '<' | '>' | '?' | '\\' | '|')
+}
+
+/// Turn a name into a valid file name on FAT.
+///
+/// Disallowed characters are replaced by `_`. A name that is still too long is truncated and gets
+/// a hash of the original name appended (before the extension), so that distinct long names remain
+/// distinct.
+pub fn sanitize_fat_name(name: &str) -> String {
+    let sanitized: String = name
+        .chars()
+        .map(|c| if is_valid_fat_char(c) { c } else { '_' })
+        .collect();
+
+    if utf16_length(&sanitized) <= MAX_NAME_LENGTH {
+        return sanitized;
+    }
+
+    let (stem, extension) = match sanitized.rfind('.') {
+        Some(index) if sanitized.len() - index <= MAX_EXTENSION_LENGTH => sanitized.split_at(index),
+        _ => (sanitized.as_str(), ""),
+    };
+    let hash = format!("{:x}", Sha256::digest(name.as_bytes()));
+    let suffix = format!("-{}{extension}", &hash[..HASH_SUFFIX_LENGTH]);
+
+    let mut budget = MAX_NAME_LENGTH - utf16_length(&suffix);
+    let mut truncated = String::new();
+    for c in stem.chars() {
+        if c.len_utf16() > budget {
+            break;
+        }
+        budget -= c.len_utf16();
+        truncated.push(c);
+    }
+    truncated + &suffix
+}
+
+fn utf16_length(name: &str) -> usize {
+    name.chars().map(char::len_utf16).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_valid_fat_name(name: &str) -> bool {
+        utf16_length(name) <= MAX_NAME_LENGTH && name.chars().all(is_valid_fat_char)
+    }
+
+    #[test]
+    fn keep_valid_names() {
+        let name = "0p9vz7z4kbqc3kn8pn4vysd6bkfkx5im-linux-6.1.1-bzImage.efi";
+        assert_eq!(sanitize_fat_name(name), name);
+    }
+
+    #[test]
+    fn replace_invalid_characters() {
+        let sanitized = sanitize_fat_name("nixos-generation-1-specialisation-a:b|c?.efi");
+        assert_eq!(sanitized, "nixos-generation-1-specialisation-a_b_c_.efi");
+        assert!(is_valid_fat_name(&sanitized));
+    }
+
+    #[test]
+    fn truncate_long_names_to_distinct_valid_names() {
+        let first = format!("{}a.efi", "x".repeat(300));
+        let second = format!("{}b.efi", "x".repeat(300));
+
+        let first_sanitized = sanitize_fat_name(&first);
+        let second_sanitized = sanitize_fat_name(&second);
+
+        assert!(is_valid_fat_name(&first_sanitized));
+        assert!(is_valid_fat_name(&second_sanitized));
+        assert_eq!(utf16_length(&first_sanitized), MAX_NAME_LENGTH);
+        assert!(first_sanitized.ends_with(".efi"));
+        assert_ne!(first_sanitized, second_sanitized);
+        assert_eq!(first_sanitized, sanitize_fat_name(&first));
+    }
+}
@@ -1,10 +1,16 @@
 use std::collections::HashSet;
 use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
 
 use anyhow::{Context, Result};
 use walkdir::{DirEntry, WalkDir};
 
+use crate::boot_entry;
+use crate::esp::{self, EspPaths};
+use crate::manifest::Manifest;
+use crate::pe;
+
 /// Keeps track of the garbage collection roots.
 ///
 /// The internal HashSet contains all the paths still in use. These paths
@@ -51,19 +57,9 @@ impl Roots {
     where
         P: FnMut(&Path) -> bool,
     {
-        // Find all the paths not used anymore.
-        let entries_not_in_use = WalkDir::new(directory.as_ref())
-            .into_iter()
-            .filter(|e| !self.in_use(e.as_ref().ok()))
-            .filter(|e| match e.as_ref().ok() {
-                Some(e) => predicate(e.path()),
-                None => false,
-            });
-
         // Remove all entries not in use.
-        for e in entries_not_in_use {
-            let entry = e?;
-            let path = entry.path();
+        for path in self.garbage_with_filter(directory, &mut predicate)? {
+            let path = path.as_path();
             println!("'{}' not in use anymore. Removing...", path.display());
 
             if path.is_dir() {
@@ -78,6 +74,147 @@ impl Roots {
 
         Ok(())
     }
+
+    /// Find the paths that `collect_garbage_with_filter` would delete without deleting them.
+    pub fn garbage_with_filter<P>(
+        &self,
+        directory: impl AsRef<Path>,
+        mut predicate: P,
+    ) -> Result<Vec<PathBuf>>
+    where
+        P: FnMut(&Path) -> bool,
+    {
+        WalkDir::new(directory.as_ref())
+            .into_iter()
+            .filter(|e| !self.in_use(e.as_ref().ok()))
+            .filter(|e| match e.as_ref().ok() {
+                Some(e) => predicate(e.path()),
+                None => false,
+            })
+            .map(|e| Ok(e?.into_path()))
+            .collect()
+    }
+}
+
+/// Collect the garbage in the directories of the ESP that lanzaboote manages.
+pub fn collect_orphans(esp_paths: &EspPaths, live_files: &Roots) -> Result<()> {
+    // Only collect garbage in these two directories. This way, no files that do not belong to
+    // the NixOS installation are deleted. Lanzatool takes full control over the esp/EFI/nixos
+    // directory and deletes ALL files that it doesn't know about. Dual- or multiboot setups
+    // that need files in this directory will NOT work.
+    live_files.collect_garbage(&esp_paths.nixos)?;
+    // The esp/EFI/Linux directory is assumed to be potentially shared with other distros.
+    // Thus, only files that start with "nixos-" and the files in their companion directories are
+    // garbage collected (i.e. potentially deleted).
+    live_files.collect_garbage_with_filter(&esp_paths.linux, esp::is_nixos_file)?;
+    // The same applies to the boot loader entries.
+    live_files.collect_garbage_with_filter(&esp_paths.entries, esp::is_nixos_image)
+}
+
+/// Remove the kernels and initrds that no installed stub references and all other files that
+/// lanzaboote does not know about.
+///
+/// Unlike the garbage collection of an installation, this keeps the stubs of all generations.
+pub fn collect_garbage(esp_paths: &EspPaths) -> Result<()> {
+    collect_orphans(esp_paths, &installed_files(esp_paths)?)?;
+    if esp_paths.manifest.exists() {
+        let mut manifest = Manifest::read(esp_paths);
+        manifest.retain_existing();
+        manifest.write(&esp_paths.manifest)?;
+    }
+    Ok(())
+}
+
+/// Collect the garbage among the files that a previous installation installed.
+///
+/// Unlike `collect_orphans`, this does not walk the managed directories. Thus, it is much faster
+/// on an ESP with many generations, but misses files that were never recorded in the manifest
+/// (e.g. because an installation was interrupted).
+pub fn collect_previous_files(
+    esp_paths: &EspPaths,
+    live_files: &Roots,
+    previous_files: &[PathBuf],
+) -> Result<()> {
+    for path in previous_files {
+        // systemd-boot renames stubs to count the boot attempts.
+        let path = &match esp::installed_image(path)? {
+            Some(image) if path.starts_with(&esp_paths.linux) => image,
+            _ => path.clone(),
+        };
+        // Only collect the files that `collect_orphans` would collect.
+        let is_managed = path.starts_with(&esp_paths.nixos)
+            || (path.starts_with(&esp_paths.linux) && esp::is_nixos_file(path))
+            || (path.starts_with(&esp_paths.entries) && esp::is_nixos_image(path));
+        if !is_managed || live_files.0.contains(path) || !path.exists() {
+            continue;
+        }
+
+        println!("'{}' not in use anymore. Removing...", path.display());
+        fs::remove_file(path).with_context(|| format!("Failed to remove file: {:?}", path))?;
+        // The companion directory of a removed stub is empty once its files are removed.
+        if let Some(parent) = path.parent().filter(|parent| {
+            parent.starts_with(&esp_paths.linux)
+                && esp::is_nixos_image(parent)
+                && !live_files.0.contains(*parent)
+        }) {
+            match fs::remove_dir(parent) {
+                Ok(()) => {}
+                // Other files of the directory are still to be removed, the last one removes it.
+                Err(e) if e.raw_os_error() == Some(nix::libc::ENOTEMPTY) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed to remove directory {parent:?}"))
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// List the files that `collect_orphans` would delete without deleting anything.
+pub fn find_orphans(esp_paths: &EspPaths, live_files: &Roots) -> Result<Vec<PathBuf>> {
+    let mut orphans = live_files.garbage_with_filter(&esp_paths.nixos, |_| true)?;
+    orphans.extend(live_files.garbage_with_filter(&esp_paths.linux, esp::is_nixos_file)?);
+    orphans.extend(live_files.garbage_with_filter(&esp_paths.entries, esp::is_nixos_image)?);
+    Ok(orphans)
+}
+
+/// The files of an ESP that are in use if all installed stubs are kept.
+///
+/// These are the files lanzaboote always installs, the stubs and boot loader entries of all
+/// generations and all kernels and initrds they reference.
+pub fn installed_files(esp_paths: &EspPaths) -> Result<Roots> {
+    let mut roots = Roots::new();
+    roots.extend(esp_paths.to_iter());
+    for stub in esp::nixos_images(&esp_paths.linux)? {
+        let referenced = pe::referenced_files(&stub, &esp_paths.boot)
+            .with_context(|| format!("Failed to read the files referenced by {stub:?}"))?;
+        let signatures: Vec<PathBuf> = referenced
+            .iter()
+            .map(|path| esp::detached_signature_path(path))
+            .collect();
+        roots.extend(&referenced);
+        roots.extend(&signatures);
+        roots.extend([
+            &stub,
+            &esp::metadata_path(&stub),
+            &esp::boot_entry_path(esp_paths, &stub),
+        ]);
+        roots.extend(&esp::extra_files(&stub)?);
+    }
+    // Boot loader entries that are installed instead of stubs reference the kernels and initrds
+    // themselves.
+    for entry in esp::nixos_boot_entries(&esp_paths.entries)? {
+        let referenced = boot_entry::referenced_files(&entry, &esp_paths.boot)?;
+        let signatures: Vec<PathBuf> = referenced
+            .iter()
+            .map(|path| esp::detached_signature_path(path))
+            .collect();
+        roots.extend(&referenced);
+        roots.extend(&signatures);
+        roots.extend([&entry]);
+    }
+    Ok(roots)
 }
 
 #[cfg(test)]
@@ -189,6 +326,57 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn list_orphans_without_deleting_them() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let esp_paths = EspPaths::new(tmpdir.path(), None);
+        fs::create_dir_all(&esp_paths.nixos)?;
+        fs::create_dir_all(&esp_paths.linux)?;
+
+        let used_kernel = create_file(esp_paths.nixos.join("used-bzImage.efi"))?;
+        let orphaned_kernel = create_file(esp_paths.nixos.join("orphaned-bzImage.efi"))?;
+        let orphaned_stub = create_file(esp_paths.linux.join("nixos-generation-1.efi"))?;
+        let foreign_stub = create_file(esp_paths.linux.join("arch-linux.efi"))?;
+
+        let mut roots = Roots::new();
+        roots.extend(esp_paths.to_iter());
+        roots.extend([&used_kernel]);
+
+        let mut orphans = find_orphans(&esp_paths, &roots)?;
+        orphans.sort();
+        assert_eq!(orphans, [orphaned_stub.clone(), orphaned_kernel.clone()]);
+        for path in [used_kernel, orphaned_kernel, orphaned_stub, foreign_stub] {
+            assert!(path.exists());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn collect_previous_stub_renamed_by_boot_counting() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let esp_paths = EspPaths::new(tmpdir.path(), None);
+        fs::create_dir_all(&esp_paths.linux)?;
+
+        let pruned_stub = create_file(esp_paths.linux.join("nixos-generation-1+2-1.efi"))?;
+        let kept_stub = create_file(esp_paths.linux.join("nixos-generation-2+1-2.efi"))?;
+
+        let mut roots = Roots::new();
+        roots.extend(esp_paths.to_iter());
+        roots.extend([&kept_stub]);
+        collect_previous_files(
+            &esp_paths,
+            &roots,
+            &[
+                esp_paths.linux.join("nixos-generation-1+3.efi"),
+                esp_paths.linux.join("nixos-generation-2+3.efi"),
+            ],
+        )?;
+
+        assert!(!pruned_stub.exists());
+        assert!(kept_stub.exists());
+        Ok(())
+    }
+
     fn create_file(path: PathBuf) -> Result<PathBuf> {
         fs::File::create(&path)?;
         Ok(path)
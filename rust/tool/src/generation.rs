@@ -1,20 +1,39 @@
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt;
 use std::fs;
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 use anyhow::{anyhow, Context, Result};
 use bootspec::generation::Generation as BootspecGeneration;
 use bootspec::BootJson;
 use bootspec::SpecialisationName;
+use serde::Deserialize;
 
 /// (Possibly) extended Bootspec.
 ///
-/// This struct currently does not have any extensions. We keep it around so that extension becomes
-/// easy if/when we have to do it.
+/// The extensions are read from the `lanzaboote` extension of the bootspec document.
 #[derive(Debug, Clone)]
 pub struct ExtendedBootJson {
     pub bootspec: BootJson,
+    /// The devicetree blob that the stub hands to the kernel, e.g. on ARM boards whose firmware
+    /// does not provide one.
+    pub devicetree: Option<PathBuf>,
+    /// systemd credentials (`*.cred`) that systemd-stub passes to the system.
+    pub credentials: Vec<PathBuf>,
+    /// System extension images (`*.raw`) that systemd-stub passes to the initrd.
+    pub sysexts: Vec<PathBuf>,
+    /// The security version of the generation, which is raised when a generation fixes a
+    /// vulnerability that older generations must not be booted with anymore.
+    pub security_version: u64,
+}
+
+impl ExtendedBootJson {
+    /// The credentials and system extensions that are installed next to the stubs.
+    pub fn extra_files(&self) -> impl Iterator<Item = &PathBuf> {
+        self.credentials.iter().chain(&self.sysexts)
+    }
 }
 
 /// A system configuration.
@@ -25,7 +44,7 @@ pub struct ExtendedBootJson {
 /// contains most of the information necessary to install the generation onto the EFI System
 /// Partition. The only information missing is the version number which is encoded in the file name
 /// of the generation link.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Generation {
     /// Profile symlink index
     version: u64,
@@ -38,32 +57,38 @@ pub struct Generation {
 impl Generation {
     pub fn from_link(link: &GenerationLink) -> Result<Self> {
         let bootspec_path = link.path.join("boot.json");
-        let generation: BootspecGeneration = serde_json::from_slice(
-            &fs::read(bootspec_path).context("Failed to read bootspec file")?,
-        )
-        .context("Failed to parse bootspec json")?;
-
-        let bootspec: BootJson = generation
-            .try_into()
-            .map_err(|err: &'static str| anyhow!(err))?;
+        let spec =
+            parse_bootspec(&fs::read(bootspec_path).context("Failed to read bootspec file")?)?;
 
         Ok(Self {
             version: link.version,
             specialisation_name: None,
-            spec: ExtendedBootJson { bootspec },
+            spec,
         })
     }
 
+    /// Build a specialisation of the generation.
+    ///
+    /// The extensions only exist at the top level of the document, so the specialisation inherits
+    /// them.
     pub fn specialise(&self, name: &SpecialisationName, bootspec: &BootJson) -> Result<Self> {
         Ok(Self {
             version: self.version,
             specialisation_name: Some(name.clone()),
             spec: ExtendedBootJson {
                 bootspec: bootspec.clone(),
+                devicetree: self.spec.devicetree.clone(),
+                credentials: self.spec.credentials.clone(),
+                sysexts: self.spec.sysexts.clone(),
+                security_version: self.spec.security_version,
             },
         })
     }
 
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
     pub fn is_specialised(&self) -> Option<SpecialisationName> {
         self.specialisation_name.clone()
     }
@@ -75,20 +100,67 @@ impl Generation {
     ///
     /// This is currently implemented by poking around the filesystem to find the necessary data.
     /// Ideally, the needed data should be included in the bootspec.
-    pub fn describe(&self) -> Result<String> {
-        let toplevel = &self.spec.bootspec.toplevel.0;
-
-        let nixos_version = fs::read_to_string(toplevel.join("nixos-version"))
-            .unwrap_or_else(|_| String::from("Unknown"));
-        let kernel_version =
-            read_kernel_version(toplevel).context("Failed to read kernel version.")?;
-        let build_time = read_build_time(toplevel).unwrap_or_else(|_| String::from("Unknown"));
+    ///
+    /// If `kernel_version_from_image` is set, the kernel version is read from the setup header of
+    /// the kernel image instead, falling back to the toplevel if the image does not carry one.
+    ///
+    /// If `build_epoch` is set, it is shown as the build date instead of the modification time of
+    /// the toplevel.
+    pub fn describe(
+        &self,
+        kernel_version_from_image: bool,
+        build_epoch: Option<SystemTime>,
+    ) -> Result<String> {
+        let kernel_version = self.kernel_version(kernel_version_from_image)?;
+        let specialisation = self
+            .specialisation_name
+            .as_ref()
+            .map(|name| format!(", Specialisation {name}"))
+            .unwrap_or_default();
 
         Ok(format!(
-            "Generation {} NixOS {}, Linux Kernel {}, Built on {}",
-            self.version, nixos_version, kernel_version, build_time
+            "Generation {} NixOS {}, Linux Kernel {}, Built on {}{}",
+            self.version,
+            self.nixos_version(),
+            kernel_version,
+            self.build_date(build_epoch),
+            specialisation
         ))
     }
+
+    /// The NixOS version of the generation, or `Unknown` if the toplevel does not name one.
+    pub fn nixos_version(&self) -> String {
+        fs::read_to_string(self.spec.bootspec.toplevel.0.join("nixos-version"))
+            .unwrap_or_else(|_| String::from("Unknown"))
+    }
+
+    /// The date the generation was built on, or `Unknown` if it cannot be determined.
+    ///
+    /// If `build_epoch` is set, it is used instead of the modification time of the toplevel.
+    pub fn build_date(&self, build_epoch: Option<SystemTime>) -> String {
+        match build_epoch {
+            Some(build_epoch) => time::OffsetDateTime::from(build_epoch).date().to_string(),
+            None => read_build_time(&self.spec.bootspec.toplevel.0)
+                .unwrap_or_else(|_| String::from("Unknown")),
+        }
+    }
+
+    /// The version of the kernel of the generation.
+    ///
+    /// If `kernel_version_from_image` is set, it is read from the setup header of the kernel
+    /// image, falling back to the toplevel if the image does not carry one.
+    pub fn kernel_version(&self, kernel_version_from_image: bool) -> Result<String> {
+        let image_kernel_version = if kernel_version_from_image {
+            read_image_kernel_version(&self.spec.bootspec.kernel)?
+        } else {
+            None
+        };
+        match image_kernel_version {
+            Some(kernel_version) => Ok(kernel_version),
+            None => read_kernel_version(&self.spec.bootspec.toplevel.0)
+                .context("Failed to read kernel version."),
+        }
+    }
 }
 
 impl fmt::Display for Generation {
@@ -97,6 +169,113 @@ impl fmt::Display for Generation {
     }
 }
 
+/// Bootspec schema versions that lzbt understands.
+const SUPPORTED_BOOTSPEC_VERSIONS: [&str; 1] = ["v1"];
+
+/// Where the lanzaboote extension names the devicetree blob of a generation.
+const DEVICETREE_POINTER: &str = "/v1/extensions/lanzaboote/devicetree";
+/// Where the lanzaboote extension lists the systemd credentials of a generation.
+const CREDENTIALS_POINTER: &str = "/v1/extensions/lanzaboote/credentials";
+/// Where the lanzaboote extension lists the system extension images of a generation.
+const SYSEXTS_POINTER: &str = "/v1/extensions/lanzaboote/sysexts";
+/// Where the lanzaboote extension stores the security version of a generation.
+const SECURITY_VERSION_POINTER: &str = "/v1/extensions/lanzaboote/securityVersion";
+
+/// Parse a bootspec document.
+///
+/// The top-level key of the document names its schema version. It is checked before the document
+/// is deserialized, so that a document of a newer version is reported as such instead of as a
+/// generic parse error.
+fn parse_bootspec(data: &[u8]) -> Result<ExtendedBootJson> {
+    let document: serde_json::Value =
+        serde_json::from_slice(data).context("Failed to parse bootspec json")?;
+    let versions: Vec<&str> = document
+        .as_object()
+        .context("Bootspec is not a JSON object")?
+        .keys()
+        .map(String::as_str)
+        .collect();
+
+    if !versions
+        .iter()
+        .any(|version| SUPPORTED_BOOTSPEC_VERSIONS.contains(version))
+    {
+        return Err(anyhow!(
+            "Unsupported bootspec version {}, lzbt supports {}",
+            if versions.is_empty() {
+                String::from("(none)")
+            } else {
+                versions.join(", ")
+            },
+            SUPPORTED_BOOTSPEC_VERSIONS.join(", ")
+        ));
+    }
+
+    let devicetree = document
+        .pointer(DEVICETREE_POINTER)
+        .map(PathBuf::deserialize)
+        .transpose()
+        .context("The devicetree of the lanzaboote bootspec extension is not a path")?;
+    let credentials = extension_files(&document, CREDENTIALS_POINTER, "cred")
+        .context("Invalid credentials in the lanzaboote bootspec extension")?;
+    let sysexts = extension_files(&document, SYSEXTS_POINTER, "raw")
+        .context("Invalid system extensions in the lanzaboote bootspec extension")?;
+    if let Some(name) = duplicate_file_name(credentials.iter().chain(&sysexts)) {
+        return Err(anyhow!(
+            "The lanzaboote bootspec extension names several files {name:?}"
+        ));
+    }
+    let security_version = document
+        .pointer(SECURITY_VERSION_POINTER)
+        .map(u64::deserialize)
+        .transpose()
+        .context("The security version of the lanzaboote bootspec extension is not a number")?
+        .unwrap_or(0);
+    let generation: BootspecGeneration =
+        serde_json::from_value(document).context("Failed to parse bootspec json")?;
+    let bootspec = generation
+        .try_into()
+        .map_err(|err: &'static str| anyhow!(err))?;
+    Ok(ExtendedBootJson {
+        bootspec,
+        devicetree,
+        credentials,
+        sysexts,
+        security_version,
+    })
+}
+
+/// Read a list of files from the lanzaboote extension, which all need the given extension to be
+/// picked up by systemd-stub.
+fn extension_files(
+    document: &serde_json::Value,
+    pointer: &str,
+    extension: &str,
+) -> Result<Vec<PathBuf>> {
+    let files = document
+        .pointer(pointer)
+        .map(Vec::<PathBuf>::deserialize)
+        .transpose()
+        .context("Not a list of paths")?
+        .unwrap_or_default();
+    for file in &files {
+        if file.extension().map_or(true, |e| e != extension) {
+            return Err(anyhow!("{file:?} does not end with .{extension}"));
+        }
+    }
+    Ok(files)
+}
+
+/// The first file name that several of the files share, which would overwrite each other on the
+/// ESP.
+fn duplicate_file_name<'a>(files: impl Iterator<Item = &'a PathBuf>) -> Option<String> {
+    let mut seen = BTreeSet::new();
+    files
+        .filter_map(|file| file.file_name())
+        .find(|name| !seen.insert(*name))
+        .map(|name| name.to_string_lossy().into_owned())
+}
+
 /// Read the kernel version from the name of a directory inside the toplevel directory.
 ///
 /// The path looks something like this: $toplevel/kernel-modules/lib/modules/6.1.1
@@ -116,6 +295,46 @@ fn read_kernel_version(toplevel: &Path) -> Result<String> {
     Ok(String::from(file_name))
 }
 
+/// Offset of the "HdrS" magic in the setup header of an x86 bzImage.
+const SETUP_HEADER_MAGIC_OFFSET: usize = 0x202;
+/// Offset of the pointer to the kernel version string in the setup header.
+const SETUP_HEADER_KERNEL_VERSION_OFFSET: usize = 0x20e;
+
+/// Read the kernel version from the setup header of a bzImage.
+///
+/// Returns `None` if the kernel is not a bzImage or does not carry a version string.
+fn read_image_kernel_version(kernel: &Path) -> Result<Option<String>> {
+    let data = fs::read(kernel).with_context(|| format!("Failed to read kernel {kernel:?}"))?;
+    Ok(bzimage_kernel_version(&data))
+}
+
+/// Extract the kernel version from the setup header of a bzImage.
+///
+/// The setup header points to a NUL terminated string like `6.1.1 (nixbld@localhost) #1-NixOS
+/// SMP ...`, relative to the start of the setup header at offset 0x200. The version is its first
+/// word.
+pub fn bzimage_kernel_version(data: &[u8]) -> Option<String> {
+    if data.get(SETUP_HEADER_MAGIC_OFFSET..SETUP_HEADER_MAGIC_OFFSET + 4)? != b"HdrS" {
+        return None;
+    }
+
+    let pointer =
+        data.get(SETUP_HEADER_KERNEL_VERSION_OFFSET..SETUP_HEADER_KERNEL_VERSION_OFFSET + 2)?;
+    let pointer = u16::from_le_bytes([pointer[0], pointer[1]]);
+    if pointer == 0 {
+        return None;
+    }
+
+    let start = 0x200 + usize::from(pointer);
+    let version_string = data.get(start..)?;
+    let end = version_string.iter().position(|&c| c == 0)?;
+    let version = std::str::from_utf8(&version_string[..end])
+        .ok()?
+        .split_whitespace()
+        .next()?;
+    Some(version.to_owned())
+}
+
 fn read_build_time(path: &Path) -> Result<String> {
     let build_time = time::OffsetDateTime::from_unix_timestamp(fs::metadata(path)?.mtime())?
         .date()
@@ -127,7 +346,7 @@ fn read_build_time(path: &Path) -> Result<String> {
 ///
 /// Can be built from a symlink in /nix/var/nix/profiles/ alone because the name of the
 /// symlink encodes the version number.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct GenerationLink {
     pub version: u64,
     pub path: PathBuf,
@@ -142,6 +361,68 @@ impl GenerationLink {
     }
 }
 
+/// Discover the generations of a system profile, sorted by version.
+///
+/// `profile` is the path of the profile itself (e.g. `/nix/var/nix/profiles/system`). Its
+/// generations are the links next to it that are named after it (e.g. `system-42-link`). Their
+/// specialisations are part of their bootspec. Malformed generations are skipped like during the
+/// installation.
+pub fn discover_generations(profile: &Path) -> Result<Vec<Generation>> {
+    let directory = profile
+        .parent()
+        .with_context(|| format!("Failed to find directory of profile {profile:?}"))?;
+    let profile_name = profile
+        .file_name()
+        .and_then(|x| x.to_str())
+        .with_context(|| format!("Failed to extract name of profile {profile:?}"))?;
+
+    let mut links = Vec::new();
+    for entry in fs::read_dir(directory)
+        .with_context(|| format!("Failed to read profile directory {directory:?}"))?
+    {
+        let path = entry?.path();
+        let is_generation_link = path
+            .file_name()
+            .and_then(|x| x.to_str())
+            .and_then(|x| x.strip_prefix(profile_name))
+            .and_then(|x| x.strip_prefix('-'))
+            .and_then(|x| x.strip_suffix("-link"))
+            .map_or(false, |x| x.parse::<u64>().is_ok());
+        if is_generation_link {
+            links.push(GenerationLink::from_path(path)?);
+        }
+    }
+    links.sort_by_key(|l| l.version);
+
+    let mut generations = Vec::new();
+    for link in links {
+        match Generation::from_link(&link) {
+            Ok(generation) => generations.push(generation),
+            Err(e) => println!("Malformed generation {:?}: {:?}", link.path, e),
+        }
+    }
+    Ok(generations)
+}
+
+/// Make sure that no two generation links share the same version.
+///
+/// The version determines the file names on the ESP. Links with the same version (e.g. from
+/// different profiles) would thus silently overwrite each other.
+pub fn ensure_unique_versions(links: &[GenerationLink]) -> Result<()> {
+    let mut seen: BTreeMap<u64, &Path> = BTreeMap::new();
+    for link in links {
+        if let Some(other) = seen.insert(link.version, &link.path) {
+            return Err(anyhow!(
+                "The generation links {:?} and {:?} both have version {}",
+                other,
+                link.path,
+                link.version
+            ));
+        }
+    }
+    Ok(())
+}
+
 /// Parse version number from a path.
 ///
 /// Expects a path in the format of "system-{version}-link".
@@ -161,10 +442,176 @@ fn parse_version(path: impl AsRef<Path>) -> Result<u64> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn read_kernel_version_from_bzimage_header() {
+        let header = include_bytes!("../tests/fixtures/bzimage-header");
+        assert_eq!(bzimage_kernel_version(header), Some(String::from("6.1.1")));
+    }
+
+    #[test]
+    fn ignore_kernel_without_bzimage_header() {
+        assert_eq!(bzimage_kernel_version(&[0; 0x600]), None);
+        assert_eq!(bzimage_kernel_version(b"MZ"), None);
+    }
+
     #[test]
     fn parse_version_correctly() {
         let path = Path::new("system-2-link");
         let parsed_version = parse_version(path).unwrap();
         assert_eq!(parsed_version, 2,);
     }
+
+    fn bootspec_v1(specialisation: serde_json::Value) -> serde_json::Value {
+        serde_json::json!({
+            "init": "/nix/store/toplevel/init",
+            "initrd": "/nix/store/initrd/initrd",
+            "kernel": "/nix/store/kernel/bzImage",
+            "kernelParams": ["loglevel=4"],
+            "label": "LanzaOS",
+            "toplevel": "/nix/store/toplevel",
+            "system": "x86_64-linux",
+            "specialisation": specialisation,
+        })
+    }
+
+    fn write_generation(
+        profiles: &Path,
+        version: u64,
+        specialisation: serde_json::Value,
+    ) -> Result<()> {
+        let bootspec = serde_json::json!({ "v1": bootspec_v1(specialisation) });
+        let link = profiles.join(format!("system-{version}-link"));
+        fs::create_dir(&link)?;
+        fs::write(link.join("boot.json"), serde_json::to_vec(&bootspec)?)?;
+        Ok(())
+    }
+
+    #[test]
+    fn discover_generations_of_profile() -> Result<()> {
+        let profiles = tempfile::tempdir()?;
+        write_generation(profiles.path(), 2, serde_json::json!({}))?;
+        write_generation(profiles.path(), 10, serde_json::json!({}))?;
+        let specialisation = serde_json::json!({ "work": bootspec_v1(serde_json::json!({})) });
+        write_generation(profiles.path(), 1, specialisation)?;
+        // Neither belongs to the system profile.
+        fs::create_dir(profiles.path().join("system"))?;
+        fs::create_dir(profiles.path().join("other-3-link"))?;
+
+        let generations = discover_generations(&profiles.path().join("system"))?;
+
+        let versions: Vec<u64> = generations.iter().map(Generation::version).collect();
+        assert_eq!(versions, [1, 2, 10]);
+        assert_eq!(generations[0].spec.bootspec.specialisation.len(), 1);
+        assert!(generations[1].spec.bootspec.specialisation.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_supported_bootspec_version() -> Result<()> {
+        let document = serde_json::json!({ "v1": bootspec_v1(serde_json::json!({})) });
+        let spec = parse_bootspec(&serde_json::to_vec(&document)?)?;
+        assert_eq!(spec.bootspec.label, "LanzaOS");
+        assert_eq!(spec.devicetree, None);
+        assert!(spec.credentials.is_empty());
+        assert_eq!(spec.security_version, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_extra_files_extension() -> Result<()> {
+        let mut bootspec = bootspec_v1(serde_json::json!({}));
+        bootspec["extensions"] = serde_json::json!({
+            "lanzaboote": {
+                "credentials": ["/nix/store/creds/firstboot.cred"],
+                "sysexts": ["/nix/store/exts/debug-tools.raw"]
+            }
+        });
+        let document = serde_json::json!({ "v1": bootspec.clone() });
+        let spec = parse_bootspec(&serde_json::to_vec(&document)?)?;
+        assert_eq!(
+            spec.extra_files().collect::<Vec<_>>(),
+            [
+                Path::new("/nix/store/creds/firstboot.cred"),
+                Path::new("/nix/store/exts/debug-tools.raw")
+            ]
+        );
+
+        bootspec["extensions"]["lanzaboote"]["sysexts"] =
+            serde_json::json!(["/nix/store/exts/debug-tools.img"]);
+        let document = serde_json::json!({ "v1": bootspec.clone() });
+        assert!(parse_bootspec(&serde_json::to_vec(&document)?).is_err());
+
+        bootspec["extensions"]["lanzaboote"]["sysexts"] =
+            serde_json::json!(["/nix/store/a/tools.raw", "/nix/store/b/tools.raw"]);
+        let document = serde_json::json!({ "v1": bootspec });
+        assert!(parse_bootspec(&serde_json::to_vec(&document)?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_devicetree_extension() -> Result<()> {
+        let mut bootspec = bootspec_v1(serde_json::json!({}));
+        bootspec["extensions"] = serde_json::json!({
+            "lanzaboote": { "devicetree": "/nix/store/dtbs/rockchip/rk3399-rockpro64.dtb" }
+        });
+        let document = serde_json::json!({ "v1": bootspec });
+        let spec = parse_bootspec(&serde_json::to_vec(&document)?)?;
+        assert_eq!(
+            spec.devicetree,
+            Some(PathBuf::from(
+                "/nix/store/dtbs/rockchip/rk3399-rockpro64.dtb"
+            ))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse_security_version_extension() -> Result<()> {
+        let mut bootspec = bootspec_v1(serde_json::json!({}));
+        bootspec["extensions"] = serde_json::json!({ "lanzaboote": { "securityVersion": 3 } });
+        let document = serde_json::json!({ "v1": bootspec.clone() });
+        assert_eq!(
+            parse_bootspec(&serde_json::to_vec(&document)?)?.security_version,
+            3
+        );
+
+        bootspec["extensions"]["lanzaboote"]["securityVersion"] = serde_json::json!(-1);
+        let document = serde_json::json!({ "v1": bootspec });
+        assert!(parse_bootspec(&serde_json::to_vec(&document)?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reject_unknown_bootspec_version() -> Result<()> {
+        let document = serde_json::json!({ "v2": bootspec_v1(serde_json::json!({})) });
+        let error = parse_bootspec(&serde_json::to_vec(&document)?).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Unsupported bootspec version v2, lzbt supports v1"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_malformed_bootspec() {
+        let error = parse_bootspec(br#"{"v1": {"label": "#).unwrap_err();
+        assert_eq!(error.to_string(), "Failed to parse bootspec json");
+        let error = parse_bootspec(b"[]").unwrap_err();
+        assert_eq!(error.to_string(), "Bootspec is not a JSON object");
+    }
+
+    #[test]
+    fn reject_duplicate_generation_versions() -> Result<()> {
+        let links = [
+            GenerationLink::from_path("/nix/var/nix/profiles/system-1-link")?,
+            GenerationLink::from_path("/nix/var/nix/profiles/system-2-link")?,
+            GenerationLink::from_path("/nix/var/nix/profiles/system-profiles/test/system-1-link")?,
+        ];
+
+        let error = ensure_unique_versions(&links).unwrap_err();
+
+        assert!(error.to_string().contains("both have version 1"));
+        assert!(ensure_unique_versions(&links[..2]).is_ok());
+        Ok(())
+    }
 }
@@ -0,0 +1,133 @@
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::pe::{ImageContext, SectionProvider};
+use crate::report::InstallReport;
+
+/// A user-provided command that is run after a successful installation.
+///
+/// The command receives the `InstallReport` as JSON on stdin. This can be used to, for example,
+/// back up the ESP or notify monitoring.
+#[derive(Debug)]
+pub struct PostInstallHook {
+    pub command: PathBuf,
+    pub args: Vec<String>,
+    /// Whether a failing hook fails the installation or only results in a warning.
+    pub fail_on_error: bool,
+}
+
+impl PostInstallHook {
+    pub fn run(&self, report: &InstallReport) -> Result<()> {
+        println!("Running post-install hook {}...", self.command.display());
+
+        match self.execute(report) {
+            Err(e) if !self.fail_on_error => {
+                println!("Warning: post-install hook failed: {e:?}");
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    fn execute(&self, report: &InstallReport) -> Result<()> {
+        let report_json =
+            serde_json::to_vec(report).context("Failed to serialize installation report")?;
+
+        let mut child = Command::new(&self.command)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to run post-install hook {:?}", self.command))?;
+
+        let mut stdin = child
+            .stdin
+            .take()
+            .context("Failed to open stdin of post-install hook")?;
+        // The hook is free to ignore the report and exit before reading it.
+        if let Err(e) = stdin.write_all(&report_json) {
+            if e.kind() != ErrorKind::BrokenPipe {
+                return Err(e).context("Failed to write report to post-install hook");
+            }
+        }
+        drop(stdin);
+
+        let status = child
+            .wait()
+            .context("Failed to wait for post-install hook")?;
+        if !status.success() {
+            return Err(anyhow!(
+                "Post-install hook {:?} failed with {}",
+                self.command,
+                status
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// A user-provided command that provides additional sections for every image.
+///
+/// The command receives the `ImageContext` as JSON on stdin and an empty directory as its only
+/// argument. Every file it creates in the directory is embedded as a section named like the file,
+/// in the order of their names.
+#[derive(Debug)]
+pub struct SectionProviderCommand {
+    pub command: PathBuf,
+}
+
+impl SectionProvider for SectionProviderCommand {
+    fn sections(&self, context: &ImageContext) -> Result<Vec<(String, Vec<u8>)>> {
+        let context_json =
+            serde_json::to_vec(context).context("Failed to serialize image context")?;
+        let directory = tempfile::tempdir()?;
+
+        let mut child = Command::new(&self.command)
+            .arg(directory.path())
+            .stdin(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to run section provider {:?}", self.command))?;
+
+        let mut stdin = child
+            .stdin
+            .take()
+            .context("Failed to open stdin of section provider")?;
+        // The provider is free to ignore the context and exit before reading it.
+        if let Err(e) = stdin.write_all(&context_json) {
+            if e.kind() != ErrorKind::BrokenPipe {
+                return Err(e).context("Failed to write image context to section provider");
+            }
+        }
+        drop(stdin);
+
+        let status = child
+            .wait()
+            .context("Failed to wait for section provider")?;
+        if !status.success() {
+            return Err(anyhow!(
+                "Section provider {:?} failed with {}",
+                self.command,
+                status
+            ));
+        }
+
+        let mut sections = Vec::new();
+        for entry in fs::read_dir(directory.path())? {
+            let path = entry?.path();
+            let name = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .with_context(|| format!("Invalid section name {path:?}"))?
+                .to_owned();
+            let contents =
+                fs::read(&path).with_context(|| format!("Failed to read section {path:?}"))?;
+            sections.push((name, contents));
+        }
+        sections.sort();
+        Ok(sections)
+    }
+}
@@ -1,87 +1,914 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fs;
-use std::os::unix::prelude::PermissionsExt;
+use std::iter;
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use nix::unistd::sync;
+use sha2::{Digest, Sha256};
 
-use crate::esp::EspPaths;
-use crate::gc::Roots;
-use crate::generation::{Generation, GenerationLink};
+use crate::anti_rollback;
+use crate::boot_entry::{self, BootEntry};
+use crate::boot_options::{self, BootOption};
+use crate::esp::{self, Architecture, EspGenerationPaths, EspPaths};
+use crate::gc::{self, Roots};
+use crate::generation::{self, Generation, GenerationLink};
+use crate::hook::PostInstallHook;
+use crate::loader_conf::{LoaderConf, LoaderSetting};
+use crate::manifest::{self, GenerationRecord, Manifest};
 use crate::os_release::OsRelease;
-use crate::pe;
-use crate::signature::KeyPair;
-use crate::utils::SecureTempDirExt;
+use crate::pe::{
+    self, HashAlgorithm, ImageOptions, ImageSizeLimit, InitrdHashMode, MachineTypePolicy,
+    PcrPolicy, PeWriter, SectionProvider, StubLayout, StubProfile, StubSource,
+};
+use crate::report::{
+    InstallReport, InstalledGeneration, OperationCounts, ProgressObserver, Timings,
+};
+use crate::sbat;
+use crate::secret_scan;
+use crate::signature::{self, SigningKey};
+use crate::space::{self, ImageCapacity, SpaceEstimate, SpacePlan, SpaceUsage};
+use crate::status::{self, SecureBootState};
+use crate::utils::{self, SecureTempDirExt};
+
+/// Which part of the initrd the hash embedded into the stub covers.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum InitrdHashPolicy {
+    /// Hash the whole initrd including the appended initrd secrets.
+    #[default]
+    Full,
+    /// Hash only the base initrd. The appended initrd secrets are not verified.
+    ///
+    /// Beware that this gives up the integrity of the initrd: anyone who can write to the ESP can
+    /// append a cpio archive that overrides files of the base initrd, e.g. /init.
+    Base,
+}
+
+/// How Type #1 boot loader entries are written for the generations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum BootLoaderEntryMode {
+    /// Write a boot loader entry next to the stub of every generation.
+    Also,
+    /// Write boot loader entries instead of installing stubs. The initrds are then not verified
+    /// at all, because only the firmware verifies the signature of the kernel.
+    Instead,
+}
+
+/// Optional behavior of the installer.
+#[derive(Debug, Default)]
+pub struct InstallOptions {
+    /// Which part of the initrd the embedded hash covers.
+    pub initrd_hash_policy: InitrdHashPolicy,
+    /// The algorithm of the hashes of the kernel and the initrd embedded into the stub.
+    pub hash_algorithm: HashAlgorithm,
+    /// Name kernels and initrds after their content hash.
+    pub content_addressed: bool,
+    /// Command to run after a successful installation.
+    pub post_install_hook: Option<PostInstallHook>,
+    /// Fixed modification time (in seconds since the Unix epoch) to set on all installed files.
+    pub mtime: Option<i64>,
+    /// Time of the build (usually from `SOURCE_DATE_EPOCH`) to use for the time stamps of the
+    /// stubs and the build date in the boot menu.
+    pub build_epoch: Option<SystemTime>,
+    /// Print how the stubs are assembled: the objcopy commands or, with the native PE writer, the
+    /// sections it adds. objcopy is not run, the native PE writer assembles the stubs instead.
+    pub show_commands: bool,
+    /// Mountpoint of a separate XBOOTLDR partition for the kernels, initrds and stubs.
+    pub xbootldr: Option<PathBuf>,
+    /// Additional sections to embed into the stubs.
+    pub extra_sections: HashMap<String, PathBuf>,
+    /// Additional boot entries per generation that only differ in their kernel parameters.
+    pub cmdline_profiles: Vec<CmdlineProfile>,
+    /// Kernel parameters of an additional rescue boot entry for the newest generation. They
+    /// replace the kernel parameters of the generation.
+    pub safe_mode: Option<Vec<String>>,
+    /// Warn about generations that use more bytes on the ESP.
+    pub size_warning_threshold: Option<u64>,
+    /// Print how long each phase of the installation and each generation took and how often the
+    /// ESP was read.
+    pub print_timings: bool,
+    /// Told about each phase of the installation and each generation as it starts.
+    pub progress: Option<Arc<dyn ProgressObserver>>,
+    /// Warn about (or reject) stubs that are too large for some firmware to load.
+    pub stub_size_limit: Option<ImageSizeLimit>,
+    /// Version of a known-good generation that is always kept as a recovery boot entry.
+    pub pinned_recovery: Option<u64>,
+    /// Versions of generations that are never installed, e.g. because they are known to be broken.
+    pub excluded_generations: BTreeSet<u64>,
+    /// Toplevel of the running system, which is checked against the excluded generations.
+    pub current_system: PathBuf,
+    /// How strictly the machine type of the stub is checked.
+    pub machine_type_policy: MachineTypePolicy,
+    /// The section names that the stub expects.
+    pub stub_profile: StubProfile,
+    /// Providers of additional sections to embed into every stub.
+    pub section_providers: Vec<Arc<dyn SectionProvider>>,
+    /// Pass the objcopy target of the machine type of the generation explicitly instead of letting
+    /// objcopy detect the format of the stub.
+    pub explicit_objcopy_target: bool,
+    /// How the sections are attached to the stubs.
+    pub pe_writer: PeWriter,
+    /// Make sure that the new files fit onto the ESP before installing them.
+    pub check_free_space: bool,
+    /// Prune the oldest generations if the new files do not fit onto the ESP otherwise. Needs
+    /// `check_free_space`.
+    pub auto_prune: bool,
+    /// Warn if less than this percentage of the ESP is free after the installation.
+    pub free_space_warning_percentage: Option<u8>,
+    /// The space of the image that the ESP is staged for, if the free space of the ESP directory
+    /// says nothing about it.
+    pub esp_capacity: Option<ImageCapacity>,
+    /// Read the kernel version for the boot menu from the kernel image instead of the toplevel.
+    pub kernel_version_from_image: bool,
+    /// The UEFI architecture of the machine, which systemd-boot is installed for. Defaults to the
+    /// architecture of the newest generation.
+    pub architecture: Option<Architecture>,
+    /// Fallback boot loaders to install. If empty, only the one for the default architecture is
+    /// installed.
+    pub fallback_loaders: Vec<FallbackLoader>,
+    /// systemd-boot binary to install instead of the one of the generation.
+    pub systemd_boot: Option<PathBuf>,
+    /// Maximum number of files that are signed and copied to the ESP at the same time. Values
+    /// below 2 copy one file after the other.
+    pub copy_concurrency: usize,
+    /// Maximum number of stubs to assemble and sign at the same time. Values below 2 assemble one
+    /// stub after the other.
+    pub stub_concurrency: usize,
+    /// Embed the kernel into the stubs instead of storing it on the ESP. The initrd stays a
+    /// separate file unless `embed_initrd` is set as well.
+    pub embed_kernel: bool,
+    /// Embed the initrd into the stubs as well, so that they are self-contained UKIs and nothing
+    /// but the stubs is installed for a generation. Only takes effect together with
+    /// `embed_kernel`.
+    pub embed_initrd: bool,
+    /// EFI binaries that are installed as they are instead of being signed, e.g. a shim that is
+    /// already signed by a third party.
+    pub verbatim: Vec<PathBuf>,
+    /// Make the newest installed generation the default boot entry of systemd-boot.
+    pub make_default: bool,
+    /// Make this generation the default boot entry of systemd-boot instead of the newest one.
+    pub default_generation: Option<u64>,
+    /// Settings of `loader.conf` that are restored whenever they drift.
+    pub loader_settings: Vec<LoaderSetting>,
+    /// Write a detached signature next to every initrd on the ESP.
+    pub sign_initrds: bool,
+    /// Allow private keys in the initrd secrets, e.g. the SSH host key of an SSH server in the
+    /// initrd. All other files on the ESP are still refused if they contain private keys.
+    pub allow_initrd_secret_keys: bool,
+    /// Write a detached signature next to every credential and system extension on the ESP.
+    pub sign_extra_files: bool,
+    /// Write a JSON sidecar with what is embedded into every stub next to it.
+    pub write_metadata: bool,
+    /// Print the predicted values of PCR 11 in each boot phase for every stub without signing a
+    /// PCR policy for them.
+    pub preview_pcrs: bool,
+    /// Sign a TPM2 PCR policy for every stub with this key pair. Needs an embedded kernel.
+    pub pcr_signing_key: Option<PcrSigningKey>,
+    /// Embed SBAT metadata that describes lzbt and the kernel of the generation into every stub.
+    pub sbat: bool,
+    /// Further SBAT entries to embed, which replace the generated ones of the same component.
+    pub sbat_entries: Vec<String>,
+    /// Boot the stubs directly from the firmware instead of installing systemd-boot, with a boot
+    /// option (`Boot####`) for every stub that is kept in sync with the installed stubs.
+    pub efi_boot_entries: bool,
+    /// Raise the minimum security version in the firmware to the one of the newest generation,
+    /// so that the stubs of generations with a lower security version refuse to boot.
+    pub anti_rollback: bool,
+    /// Install new stubs with a boot counter of this many tries, so that systemd-boot falls back
+    /// to another entry once the generation failed to boot as often.
+    pub boot_counting_tries: Option<u32>,
+    /// Write a Type #1 boot loader entry that references the kernel and initrd of every
+    /// generation, next to or instead of its stub. Nothing verifies the initrd of such an entry.
+    pub boot_loader_entries: Option<BootLoaderEntryMode>,
+    /// Skip generations that are unchanged since the previous installation entirely, i.e. whose
+    /// bootspec and image options are the same.
+    pub incremental: bool,
+    /// Only garbage collect the files that the previous installation installed, except for a full
+    /// sweep of the ESP every this many installations.
+    pub incremental_gc: Option<u32>,
+    /// File to write the store paths of the installed generations to, so that Nix can register
+    /// them as GC roots.
+    pub store_roots_file: Option<PathBuf>,
+    /// Directory to read the UEFI variables from, e.g. to check that the signing key is enrolled.
+    pub efivars: PathBuf,
+    /// Install even though Secure Boot is enabled and the signing key is not enrolled.
+    pub force: bool,
+    /// Also keep the generations that are newer than this, even beyond the configuration limit.
+    pub keep_since: Option<Duration>,
+    /// Only print the boot entries and files that the installation would remove.
+    pub dry_run: bool,
+}
+
+/// The PEM encoded key pair that signs the TPM2 PCR policies of the stubs.
+#[derive(Debug, Clone)]
+pub struct PcrSigningKey {
+    pub public_key: PathBuf,
+    pub private_key: PathBuf,
+}
+
+/// Kernel parameters of the safe mode boot entry unless others are given.
+pub const SAFE_MODE_KERNEL_PARAMS: &str = "single nomodeset";
+
+/// An additional boot entry that appends kernel parameters to the ones of the generation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CmdlineProfile {
+    pub name: String,
+    pub kernel_params: Vec<String>,
+}
+
+impl CmdlineProfile {
+    /// Parse a cmdline profile given as NAME=PARAMS.
+    ///
+    /// The name becomes part of the file name of the stub and thus may only contain ASCII
+    /// alphanumeric characters, `-` and `_`. The parameters are separated by whitespace.
+    pub fn parse(value: &str) -> Result<Self> {
+        let (name, kernel_params) = value
+            .split_once('=')
+            .with_context(|| format!("Cmdline profile {value:?} is not of the form NAME=PARAMS"))?;
+
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(anyhow::anyhow!("Invalid cmdline profile name {name:?}"));
+        }
+
+        Ok(Self {
+            name: name.to_owned(),
+            kernel_params: kernel_params.split_whitespace().map(String::from).collect(),
+        })
+    }
+}
+
+/// A fallback boot loader for a UEFI architecture.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FallbackLoader {
+    /// UEFI architecture, e.g. `x64` or `aa64`.
+    pub architecture: String,
+    /// The systemd-boot build to install. Defaults to the one of the generation.
+    pub source: Option<PathBuf>,
+}
+
+impl FallbackLoader {
+    /// UEFI architectures that have a fallback boot loader path.
+    const ARCHITECTURES: [&'static str; 6] =
+        ["ia32", "x64", "arm", "aa64", "riscv64", "loongarch64"];
+
+    /// Parse a fallback boot loader given as ARCH or ARCH=PATH.
+    pub fn parse(value: &str) -> Result<Self> {
+        let (architecture, source) = match value.split_once('=') {
+            Some((architecture, source)) => (architecture, Some(PathBuf::from(source))),
+            None => (value, None),
+        };
+
+        if !Self::ARCHITECTURES.contains(&architecture) {
+            return Err(anyhow::anyhow!(
+                "Unknown UEFI architecture {architecture:?}, expected one of {}",
+                Self::ARCHITECTURES.join(", ")
+            ));
+        }
+
+        Ok(Self {
+            architecture: architecture.to_owned(),
+            source,
+        })
+    }
+}
 
 pub struct Installer {
     gc_roots: Roots,
-    lanzaboote_stub: PathBuf,
-    key_pair: KeyPair,
+    /// The store paths that the installed generations were assembled from.
+    store_paths: BTreeSet<PathBuf>,
+    manifest: Manifest,
+    report: InstallReport,
+    /// How often the current installation did the operations that grow with the size of the ESP.
+    counts: OperationCounts,
+    lanzaboote_stub: StubSource,
+    /// The layouts of the stubs by their path, read when the first image is assembled from them.
+    stub_layouts: BTreeMap<PathBuf, StubLayout>,
+    signer: SigningKey,
     configuration_limit: usize,
-    esp: PathBuf,
+    esp_paths: EspPaths,
     generation_links: Vec<PathBuf>,
+    /// Version of the newest generation that is installed, which gets the safe mode entry.
+    newest_generation: Option<u64>,
+    /// Versions of the generations that are installed exactly as by the previous installation.
+    unchanged_generations: BTreeSet<u64>,
+    /// Generations whose files are installed, but whose stubs are not assembled yet.
+    pending_generations: Vec<PendingGeneration>,
+    /// Number of generations whose stubs were assembled.
+    installed_generations: usize,
+    /// The boot loaders (systemd-boot and the fallback boot loaders) to install after the stubs,
+    /// as their sources and destinations.
+    pending_boot_loaders: Vec<(PathBuf, PathBuf)>,
+    options: InstallOptions,
+}
+
+/// How much space installing a set of generations needs and frees on the ESP.
+struct SpaceRequirement {
+    estimate: SpaceEstimate,
+    /// What the added bytes are made of.
+    usage: SpaceUsage,
+    /// The existing files of pruned generations that no kept stub references.
+    pruned: BTreeSet<PathBuf>,
+}
+
+/// A generation whose stubs are assembled after the files of all generations are on the ESP, so
+/// that the stubs of several generations can be assembled concurrently.
+struct PendingGeneration {
+    generation: Generation,
+    /// Holds the inputs of the stubs, e.g. the os-release files and the signed kernel to embed.
+    _tempdir: tempfile::TempDir,
+    stub_layout: StubLayout,
+    esp_gen_paths: EspGenerationPaths,
+    image_options: ImageOptions,
+    /// The stubs that are not installed yet.
+    stubs: Vec<PendingStub>,
+    /// The paths of all stubs of the generation, including the ones that are already installed.
+    images: Vec<PathBuf>,
+    /// The length of the base initrd, if the initrd secrets appended to it may contain keys.
+    initrd_secrets_offset: Option<usize>,
+}
+
+/// A stub of a generation that is not installed yet.
+struct PendingStub {
+    os_release: PathBuf,
+    kernel_cmdline: Vec<String>,
+    image_path: PathBuf,
+}
+
+impl PendingGeneration {
+    /// Assemble a stub of the generation and sign it onto the ESP.
+    fn install(&self, stub: &PendingStub, boot: &Path, signer: &SigningKey) -> Result<()> {
+        // Every stub gets its own directory, because the stubs are assembled concurrently.
+        let tempdir = utils::tempdir()?;
+        let lanzaboote_image = pe::lanzaboote_image(
+            &tempdir,
+            &self.stub_layout,
+            &stub.os_release,
+            &stub.kernel_cmdline,
+            &self.esp_gen_paths,
+            boot,
+            &self.image_options,
+        )
+        .with_context(|| format!("Failed to assemble stub {:?}", stub.image_path))?;
+
+        // The embedded initrd ends with the initrd secrets.
+        let allowed = match (
+            self.initrd_secrets_offset,
+            &self.image_options.embedded_initrd,
+        ) {
+            (Some(offset), Some(_)) => pe::section_range(&lanzaboote_image, ".initrd")?
+                .map(|section| section.start + offset..section.end),
+            _ => None,
+        };
+        match allowed {
+            Some(allowed) => {
+                secret_scan::ensure_no_private_keys_outside(&lanzaboote_image, allowed)
+            }
+            None => secret_scan::ensure_no_private_keys(&[lanzaboote_image.as_path()]),
+        }
+        .with_context(|| format!("Refusing to install stub {:?}", stub.image_path))?;
+
+        sign_and_copy(signer, &lanzaboote_image, &stub.image_path)
+            .context("Failed to install lanzaboote")
+    }
 }
 
 impl Installer {
     pub fn new(
-        lanzaboote_stub: PathBuf,
-        key_pair: KeyPair,
+        lanzaboote_stub: StubSource,
+        signer: SigningKey,
         configuration_limit: usize,
         esp: PathBuf,
         generation_links: Vec<PathBuf>,
+        options: InstallOptions,
     ) -> Self {
         Self {
             gc_roots: Roots::new(),
+            store_paths: BTreeSet::new(),
+            manifest: Manifest::default(),
+            report: InstallReport::new(&esp),
+            counts: OperationCounts::default(),
             lanzaboote_stub,
-            key_pair,
+            stub_layouts: BTreeMap::new(),
+            signer,
             configuration_limit,
-            esp,
+            esp_paths: esp_paths_for(esp, &options, options.architecture.unwrap_or_default()),
             generation_links,
+            newest_generation: None,
+            unchanged_generations: BTreeSet::new(),
+            pending_generations: Vec::new(),
+            installed_generations: 0,
+            pending_boot_loaders: Vec::new(),
+            options,
         }
     }
 
+    /// Install to another ESP from now on, e.g. to the mirror of the ESP on a RAID1.
+    ///
+    /// Everything that was recorded about the previous ESP is forgotten, so the next installation
+    /// reads the manifest of the new ESP and signs and copies all files that it lacks.
+    pub fn retarget(&mut self, esp: PathBuf) {
+        self.gc_roots = Roots::new();
+        self.store_paths = BTreeSet::new();
+        self.manifest = Manifest::default();
+        self.report = InstallReport::new(&esp);
+        self.esp_paths = esp_paths_for(
+            esp,
+            &self.options,
+            self.options.architecture.unwrap_or_default(),
+        );
+        self.newest_generation = None;
+        self.unchanged_generations = BTreeSet::new();
+    }
+
     pub fn install(&mut self) -> Result<()> {
+        let mut timings = Timings::new(self.options.print_timings, self.options.progress.clone());
+        self.counts = OperationCounts::default();
+
+        if !self.options.force {
+            self.ensure_signing_key_enrolled()?;
+        }
+
+        if self.options.boot_loader_entries == Some(BootLoaderEntryMode::Instead)
+            && (!self.options.cmdline_profiles.is_empty() || self.options.safe_mode.is_some())
+        {
+            return Err(anyhow!(
+                "Cmdline profiles and the safe mode entry need stubs, so they cannot be combined with boot loader entries instead of stubs"
+            ));
+        }
+
+        if self.options.initrd_hash_policy == InitrdHashPolicy::Base && !self.options.sign_initrds {
+            let warning = "the embedded hash only covers the base initrd, so anyone who can write to the ESP can append a cpio archive that overrides e.g. /init. Pass --sign-initrd, so that verify detects modified initrds";
+            println!("Warning: {warning}");
+            self.report.warnings.push(warning.to_owned());
+        }
+
+        // Find the partition of the boot options before anything is installed.
+        let partition = if self.options.efi_boot_entries {
+            Some(boot_options::Partition::of(&self.esp_paths.boot)?)
+        } else {
+            None
+        };
+
+        timings.start("reading the manifest");
+        self.manifest = Manifest::read(&self.esp_paths);
+        self.counts.manifest_read();
+        // Modified files are forgotten, but they are still garbage if they are not used anymore.
+        let previous_files = self.manifest.paths();
+        self.manifest.forget_modified();
+        let certificate = format!("{:x}", pe::file_hash(self.signer.public_key())?);
+        if self.manifest.set_certificate(certificate) {
+            println!("The signing certificate changed, signing all files again...");
+        }
+        self.manifest.set_signed_initrds(self.options.sign_initrds);
+        timings.end();
+
         let mut links = self
             .generation_links
             .iter()
             .map(GenerationLink::from_path)
             .collect::<Result<Vec<GenerationLink>>>()?;
+        generation::ensure_unique_versions(&links)?;
+        links = self.remove_excluded_generations(links);
+
+        // The pinned recovery generation is exempt from the configuration limit.
+        let pinned_recovery = self.options.pinned_recovery.and_then(|version| {
+            let position = links.iter().position(|l| l.version == version);
+            if position.is_none() {
+                println!("Warning: the pinned recovery generation {version} does not exist");
+            }
+            position.map(|position| links.remove(position))
+        });
 
         // A configuration limit of 0 means there is no limit.
         if self.configuration_limit > 0 {
             // Sort the links by version.
             links.sort_by_key(|l| l.version);
 
-            // Only install the number of generations configured.
+            // Only install the number of generations configured and the ones that are recent
+            // enough to be kept regardless.
+            let keep_since = self
+                .options
+                .keep_since
+                .and_then(|duration| SystemTime::now().checked_sub(duration));
             links = links
                 .into_iter()
                 .rev()
-                .take(self.configuration_limit)
+                .enumerate()
+                .filter(|(position, link)| {
+                    *position < self.configuration_limit
+                        || keep_since.map_or(false, |keep_since| {
+                            generation_link_time(link).map_or(false, |time| time >= keep_since)
+                        })
+                })
+                .map(|(_, link)| link)
                 .collect()
         };
-        self.install_links(links)?;
-
-        // Only collect garbage in these two directories. This way, no files that do not belong to
-        // the NixOS installation are deleted. Lanzatool takes full control over the esp/EFI/nixos
-        // directory and deletes ALL files that it doesn't know about. Dual- or multiboot setups
-        // that need files in this directory will NOT work.
-        self.gc_roots.collect_garbage(self.esp.join("EFI/nixos"))?;
-        // The esp/EFI/Linux directory is assumed to be potentially shared with other distros.
-        // Thus, only files that start with "nixos-" are garbage collected (i.e. potentially
-        // deleted).
-        self.gc_roots
-            .collect_garbage_with_filter(self.esp.join("EFI/Linux"), |p| {
-                p.file_name()
-                    .and_then(|n| n.to_str())
-                    .map_or(false, |n| n.starts_with("nixos-"))
-            })?;
+        links.extend(pinned_recovery);
+        self.newest_generation = links.iter().map(|link| link.version).max();
+        // When cross-installing, the running system has another architecture than the machine.
+        if self.options.architecture.is_none() {
+            if let Some(architecture) = newest_generation_architecture(&links) {
+                self.esp_paths =
+                    esp_paths_for(self.esp_paths.esp.clone(), &self.options, architecture);
+            }
+        }
+        self.ensure_unique_stub_paths(&links)?;
+        if self.options.dry_run {
+            return self.print_removals(&links);
+        }
+        // The leftovers of an interrupted installation would otherwise stay on the ESP forever.
+        for directory in [
+            &self.esp_paths.linux,
+            &self.esp_paths.nixos,
+            &self.esp_paths.systemd,
+            &self.esp_paths.efi_fallback_dir,
+            &self.esp_paths.loader,
+            &self.esp_paths.entries,
+        ] {
+            utils::remove_stale_staging_files(directory)?;
+        }
+        if self.options.check_free_space {
+            timings.start("checking free space");
+            links = self.ensure_free_space(links)?;
+            timings.end();
+        }
+        let security_versions = if self.options.anti_rollback {
+            security_versions(&links)
+        } else {
+            BTreeMap::new()
+        };
+        self.install_links(links, &mut timings)?;
+
+        timings.start("collecting garbage");
+        self.gc_roots.extend(self.esp_paths.to_iter());
+        self.collect_garbage(&previous_files)?;
+
+        if let Some(partition) = &partition {
+            timings.start("updating the boot options");
+            self.update_boot_options(partition)?;
+        }
+
+        // The minimum is only raised once the stubs of the newest generation are installed, so
+        // that there always is a generation left that may be booted.
+        if self.options.anti_rollback {
+            timings.start("updating the minimum security version");
+            self.update_minimum_security_version(&security_versions)?;
+        }
+
+        timings.start("writing the manifest");
+        self.manifest.retain_existing();
+        self.manifest.write(&self.esp_paths.manifest)?;
+        self.set_mtime(&self.esp_paths.manifest)?;
+
+        self.update_loader_conf()?;
+
+        if let Some(path) = &self.options.store_roots_file {
+            self.write_store_roots(path)?;
+        }
+
+        if let Some(percentage) = self.options.free_space_warning_percentage {
+            let (free, total) = match self.image_capacity() {
+                Some(capacity) => (capacity.free_space(&self.esp_paths.esp)?, capacity.total),
+                None => (
+                    space::free_space(&self.esp_paths.boot)?,
+                    space::total_space(&self.esp_paths.boot)?,
+                ),
+            };
+            self.report.check_free_space(free, total, percentage);
+        }
+
+        if let Some(hook) = &self.options.post_install_hook {
+            hook.run(&self.report)?;
+        }
 
+        timings.print();
+        if self.options.print_timings {
+            self.counts.print();
+        }
+        Ok(())
+    }
+
+    /// Print the stubs of the generations that the installation would drop and the files on the
+    /// ESP that only they use, without changing anything.
+    ///
+    /// Files that are replaced because a kept generation changed are not listed.
+    fn print_removals(&self, links: &[GenerationLink]) -> Result<()> {
+        let kept: BTreeSet<u64> = links.iter().map(|link| link.version).collect();
+
+        let mut live_files = Roots::new();
+        live_files.extend(self.esp_paths.to_iter());
+        let mut dropped_stubs = Vec::new();
+        self.counts.esp_scan();
+        for stub in esp::nixos_images(&self.esp_paths.linux)? {
+            // Stubs that are not named after a generation are none of lanzaboote's business.
+            if esp::image_version(&stub).map_or(false, |version| !kept.contains(&version)) {
+                dropped_stubs.push(stub);
+                continue;
+            }
+            self.counts.stub_parse();
+            let referenced = pe::referenced_files(&stub, &self.esp_paths.boot)
+                .with_context(|| format!("Failed to read the files referenced by {stub:?}"))?;
+            let signatures: Vec<PathBuf> = referenced
+                .iter()
+                .map(|path| esp::detached_signature_path(path))
+                .collect();
+            live_files.extend(&referenced);
+            live_files.extend(&signatures);
+            live_files.extend([
+                &stub,
+                &esp::boot_entry_path(&self.esp_paths, &stub),
+                &esp::metadata_path(&stub),
+            ]);
+            live_files.extend(&esp::extra_files(&stub)?);
+        }
+
+        for stub in &dropped_stubs {
+            println!("Would remove the boot entry {}", stub.display());
+        }
+        for path in gc::find_orphans(&self.esp_paths, &live_files)? {
+            if !dropped_stubs.contains(&path) {
+                println!("Would remove {}", path.display());
+            }
+        }
         Ok(())
     }
 
-    fn install_links(&mut self, links: Vec<GenerationLink>) -> Result<()> {
+    /// Remove the excluded generations, so that they neither are installed nor count against the
+    /// configuration limit.
+    ///
+    /// Excluding the running system is allowed, but it cannot be booted again afterwards.
+    fn remove_excluded_generations(&self, links: Vec<GenerationLink>) -> Vec<GenerationLink> {
+        let excluded = &self.options.excluded_generations;
+        if excluded.is_empty() {
+            return links;
+        }
+
+        let current_system = self.options.current_system.canonicalize().ok();
+        let (excluded, included): (Vec<_>, Vec<_>) = links
+            .into_iter()
+            .partition(|link| excluded.contains(&link.version));
+        for link in excluded {
+            println!("Excluding generation {}...", link.version);
+            if current_system.is_some() && link.path.canonicalize().ok() == current_system {
+                println!(
+                    "Warning: the excluded generation {} is the running system. It will not be bootable after the next reboot!",
+                    link.version
+                );
+            }
+        }
+        included
+    }
+
+    /// Make sure that the firmware accepts the stubs if Secure Boot is enabled.
+    ///
+    /// Otherwise, the firmware refuses to boot any of the newly signed stubs.
+    fn ensure_signing_key_enrolled(&self) -> Result<()> {
+        if status::secure_boot_state(&self.options.efivars)? != SecureBootState::Enabled {
+            return Ok(());
+        }
+
+        let public_key = self.signer.public_key();
+        let certificate = signature::certificate_der(
+            &fs::read(public_key)
+                .with_context(|| format!("Failed to read public key {public_key:?}"))?,
+        )?;
+        if !status::is_certificate_enrolled(&self.options.efivars, &certificate)? {
+            return Err(anyhow::anyhow!(
+                "Secure Boot is enabled, but the certificate {public_key:?} is not enrolled in db. The firmware would refuse to boot the signed stubs. Use --force to install anyway"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Make sure that the files of the generations to install fit onto the ESP and return the
+    /// generations to install.
+    ///
+    /// If the new files only fit once the files of pruned generations are gone, they are removed
+    /// before the installation instead of by the garbage collection afterwards. If they do not fit
+    /// at all, the oldest generations that would have to be pruned are suggested, or pruned right
+    /// away with `auto_prune`.
+    fn ensure_free_space(&self, links: Vec<GenerationLink>) -> Result<Vec<GenerationLink>> {
+        let requirement = self.space_requirement(&links)?;
+        let (links, requirement) = match requirement.estimate.plan() {
+            Ok(_) => (links, requirement),
+            Err(e) => {
+                println!("The new files need {}.", requirement.usage);
+                match self.prune_to_fit(&links)? {
+                    Some((pruned, remaining, requirement)) if self.options.auto_prune => {
+                        println!(
+                            "Pruning generations {} to free up space on the ESP...",
+                            list_versions(&pruned)
+                        );
+                        (remaining, requirement)
+                    }
+                    Some((pruned, _, _)) => {
+                        println!(
+                            "Pruning generations {} would free up enough space. Use --auto-prune to prune them during the installation.",
+                            list_versions(&pruned)
+                        );
+                        return Err(e);
+                    }
+                    None => return Err(e),
+                }
+            }
+        };
+
+        if requirement.estimate.plan()? == SpacePlan::RemoveFirst {
+            println!("Removing pruned generations first to free up space on the ESP...");
+            for path in requirement.pruned {
+                fs::remove_file(&path)
+                    .with_context(|| format!("Failed to remove pruned file {path:?}"))?;
+            }
+        }
+
+        Ok(links)
+    }
+
+    /// Find the oldest generations whose pruning makes the new files fit onto the ESP.
+    ///
+    /// The newest, the default and the pinned recovery generation are never pruned. Returns the
+    /// pruned versions, the remaining links and what installing them requires.
+    fn prune_to_fit(
+        &self,
+        links: &[GenerationLink],
+    ) -> Result<Option<(Vec<u64>, Vec<GenerationLink>, SpaceRequirement)>> {
+        let newest = links.iter().map(|link| link.version).max();
+        let mut candidates: Vec<u64> = links
+            .iter()
+            .map(|link| link.version)
+            .filter(|version| {
+                Some(*version) != newest
+                    && Some(*version) != self.options.default_generation
+                    && Some(*version) != self.options.pinned_recovery
+            })
+            .collect();
+        candidates.sort();
+
+        let mut remaining = links.to_vec();
+        let mut pruned = Vec::new();
+        for version in candidates {
+            remaining.retain(|link| link.version != version);
+            pruned.push(version);
+            let requirement = self.space_requirement(&remaining)?;
+            if requirement.estimate.plan().is_ok() {
+                return Ok(Some((pruned, remaining, requirement)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Compute how much space installing the generations of `links` needs and frees on the ESP.
+    ///
+    /// Existing files are only counted as removed if they belong to generations that are pruned
+    /// and no kept stub references them. Files that are not recorded in the manifest, e.g. stubs
+    /// with NixOS names that were copied by hand, are never counted as removed.
+    fn space_requirement(&self, links: &[GenerationLink]) -> Result<SpaceRequirement> {
+        let versions: BTreeSet<u64> = links.iter().map(|l| l.version).collect();
+
+        let mut usage = SpaceUsage::default();
+        let mut kept = BTreeSet::new();
+        for generation in &generations_with_specialisations(links)? {
+            let bootspec = &generation.spec.bootspec;
+            let stub = self.lanzaboote_stub.select(&bootspec.system)?;
+            let mut esp_gen_paths = EspGenerationPaths::new(&self.esp_paths, generation)?;
+            if let Some(stub) = esp::installed_image(&esp_gen_paths.lanzaboote_image)? {
+                esp_gen_paths.lanzaboote_image = stub;
+            }
+            if self.options.embed_kernel {
+                esp_gen_paths = esp_gen_paths.embed_kernel();
+                if !esp_gen_paths.lanzaboote_image.exists() {
+                    usage.kernels += pe::file_size(&bootspec.kernel)?;
+                }
+            }
+            if self.embeds_initrd() {
+                esp_gen_paths = esp_gen_paths.embed_initrd();
+                if let Some(initrd) = bootspec
+                    .initrd
+                    .as_ref()
+                    .filter(|_| !esp_gen_paths.lanzaboote_image.exists())
+                {
+                    usage.initrds += pe::file_size(initrd)?;
+                }
+            }
+            usage.images += new_file_size(&mut kept, &stub, &esp_gen_paths.lanzaboote_image)?;
+            if let (Some(from), Some(to)) = (&bootspec.initrd, &esp_gen_paths.initrd) {
+                usage.initrds += new_file_size(&mut kept, from, to)?;
+            }
+            if let Some(to) = &esp_gen_paths.kernel {
+                usage.kernels += new_file_size(&mut kept, &bootspec.kernel, to)?;
+            }
+        }
+
+        let mut pruned = Vec::new();
+        self.counts.esp_scan();
+        for path in esp::nixos_images(&self.esp_paths.linux)? {
+            self.counts.stub_parse();
+            let references = pe::referenced_files(&path, &self.esp_paths.boot)?;
+            match esp::image_version(&path) {
+                Some(version) if !versions.contains(&version) && self.manifest.contains(&path) => {
+                    pruned.push(path);
+                    pruned.extend(references);
+                }
+                _ => kept.extend(references),
+            }
+        }
+        let pruned: BTreeSet<PathBuf> = pruned
+            .into_iter()
+            .filter(|path| !kept.contains(path) && path.exists() && self.manifest.contains(path))
+            .collect();
+
+        Ok(SpaceRequirement {
+            estimate: SpaceEstimate {
+                free: match self.image_capacity() {
+                    Some(capacity) => capacity.free_space(&self.esp_paths.esp)?,
+                    None => space::free_space(&self.esp_paths.boot)?,
+                },
+                added: usage.total(),
+                removed: pruned.iter().map(pe::file_size).sum::<Result<u64>>()?,
+            },
+            usage,
+            pruned,
+        })
+    }
+
+    /// The space of the image that the generations are installed to, unless they are installed to
+    /// a mounted XBOOTLDR partition instead.
+    fn image_capacity(&self) -> Option<ImageCapacity> {
+        self.options
+            .esp_capacity
+            .filter(|_| self.esp_paths.boot == self.esp_paths.esp)
+    }
+
+    /// Make sure that no two boot entries are installed to the same stub.
+    ///
+    /// Otherwise, one would silently overwrite the other. For example, the cmdline profile `b` of
+    /// the specialisation `a` has the same name as the specialisation `a-profile-b`.
+    fn ensure_unique_stub_paths(&self, links: &[GenerationLink]) -> Result<()> {
+        let mut stub_paths: BTreeMap<PathBuf, String> = BTreeMap::new();
+        for generation in &generations_with_specialisations(links)? {
+            let description = match generation.is_specialised() {
+                Some(name) => format!("specialisation {name} of generation {generation}"),
+                None => format!("generation {generation}"),
+            };
+
+            let is_recovery = generation.is_specialised().is_none()
+                && self.options.pinned_recovery == Some(generation.version());
+            let image_path = if is_recovery {
+                esp::recovery_image_path(&self.esp_paths, generation)
+            } else {
+                EspGenerationPaths::new(&self.esp_paths, generation)?.lanzaboote_image
+            };
+
+            let entries = iter::once((image_path, description.clone()))
+                .chain(self.options.cmdline_profiles.iter().map(|profile| {
+                    (
+                        esp::cmdline_profile_image_path(&self.esp_paths, generation, &profile.name),
+                        format!("cmdline profile {} of {description}", profile.name),
+                    )
+                }))
+                .chain(self.is_safe_mode_generation(generation).then(|| {
+                    (
+                        esp::safe_mode_image_path(&self.esp_paths, generation),
+                        format!("safe mode entry of {description}"),
+                    )
+                }));
+            for (path, description) in entries {
+                if let Some(other) = stub_paths.get(&path) {
+                    return Err(anyhow::anyhow!(
+                        "The {other} and the {description} would both be installed to {path:?}"
+                    ));
+                }
+                stub_paths.insert(path, description);
+            }
+        }
+        Ok(())
+    }
+
+    fn install_links(&mut self, links: Vec<GenerationLink>, timings: &mut Timings) -> Result<()> {
+        let records = self.generation_records(&links)?;
+        let diff = manifest::diff_generations(&self.manifest, &records);
+        self.manifest.set_generations(records);
+        self.unchanged_generations = diff.unchanged.clone();
+
+        // Scan the ESP only once instead of once per unchanged generation.
+        let installed_stubs = if self.options.incremental {
+            self.counts.esp_scan();
+            installed_stubs_by_version(&self.esp_paths.linux)?
+        } else {
+            BTreeMap::new()
+        };
+
         for link in links {
+            timings.start(format!("generation {}", link.version));
+
             let generation_result = Generation::from_link(&link)
                 .with_context(|| format!("Failed to build generation from link: {link:?}"));
 
@@ -95,9 +922,31 @@ impl Installer {
                 }
             };
 
+            let stubs = installed_stubs
+                .get(&generation.version())
+                .map_or(&[][..], Vec::as_slice);
+            // A generation that only became the newest one lacks the safe mode entry.
+            let safe_mode_image_path = esp::safe_mode_image_path(&self.esp_paths, &generation);
+            let has_safe_mode_entry = !self.is_safe_mode_generation(&generation)
+                || stubs
+                    .iter()
+                    .any(|stub| esp::without_boot_counter(stub) == safe_mode_image_path);
+            if self.options.incremental
+                && diff.unchanged.contains(&generation.version())
+                && has_safe_mode_entry
+                && self.keep_unchanged_generation(stubs)?
+            {
+                println!("Generation {generation} is unchanged, skipping...");
+                // The toplevel references the kernels and initrds of all its specialisations.
+                self.record_store_paths([&generation.spec.bootspec.toplevel.0]);
+                self.report_kept_generation(&generation, stubs)?;
+                continue;
+            }
+
             println!("Installing generation {generation}");
 
-            self.install_generation(&generation)
+            let esp_gen_paths = self
+                .install_generation(&generation)
                 .context("Failed to install generation")?;
 
             for (name, bootspec) in &generation.spec.bootspec.specialisation {
@@ -105,127 +954,1276 @@ impl Installer {
 
                 println!("Installing specialisation: {name} of generation: {generation}");
 
-                self.install_generation(&specialised_generation)
+                let specialised_esp_gen_paths = self
+                    .install_generation(&specialised_generation)
                     .context("Failed to install specialisation")?;
+                self.manifest.record_specialisation(
+                    &specialised_esp_gen_paths.lanzaboote_image,
+                    &esp_gen_paths.lanzaboote_image,
+                )?;
+            }
+
+            // Every pending generation holds its inputs (e.g. the signed kernel to embed) in a
+            // temporary directory. Assemble the stubs in batches so that they don't pile up.
+            if self.pending_generations.len() >= self.options.stub_concurrency.max(1) {
+                self.install_pending_stubs()
+                    .context("Failed to install generation")?;
+            }
+        }
+
+        timings.start("assembling stubs");
+        self.install_pending_stubs()
+            .context("Failed to install generation")?;
+
+        // Sync files to persistent storage. This may improve the
+        // chance of a consistent boot directory in case the system
+        // crashes.
+        sync();
+
+        if self.installed_generations > 0 {
+            println!(
+                "Successfully installed lanzaboote to '{}'",
+                self.esp_paths.esp.display()
+            );
+        }
+
+        timings.start("installing the boot loaders");
+        self.install_boot_loaders()?;
+        timings.end();
+        Ok(())
+    }
+
+    /// Install systemd-boot and the fallback boot loaders.
+    ///
+    /// They are replaced last, once everything they may boot is complete on the ESP. An
+    /// interrupted installation leaves the previous boot loaders in place.
+    fn install_boot_loaders(&mut self) -> Result<()> {
+        let boot_loaders = std::mem::take(&mut self.pending_boot_loaders);
+        if boot_loaders.is_empty() {
+            return Ok(());
+        }
+
+        let files = boot_loaders
+            .iter()
+            .map(|(from, to)| (from.as_path(), to.as_path()))
+            .collect::<Vec<_>>();
+        install_signed_concurrently(
+            &self.signer,
+            &mut self.manifest,
+            &files,
+            &self.options.verbatim,
+            self.options.copy_concurrency,
+        )?;
+
+        install_random_seed(&self.esp_paths.random_seed)
+            .context("Failed to install systemd-boot random seed")
+    }
+
+    /// Record what the generations of the links are assembled from.
+    ///
+    /// Malformed generations are skipped like during the installation.
+    fn generation_records(
+        &self,
+        links: &[GenerationLink],
+    ) -> Result<BTreeMap<u64, GenerationRecord>> {
+        let mut stub_hashes: BTreeMap<PathBuf, String> = BTreeMap::new();
+        let mut records = BTreeMap::new();
+        for generation in links
+            .iter()
+            .filter_map(|link| Generation::from_link(link).ok())
+        {
+            let bootspec = &generation.spec.bootspec;
+            let stub = self.lanzaboote_stub.select(&bootspec.system)?;
+            let stub_hash = match stub_hashes.get(&stub) {
+                Some(stub_hash) => stub_hash.clone(),
+                None => {
+                    let stub_hash = format!("{:x}", pe::file_hash(&stub)?);
+                    stub_hashes.insert(stub, stub_hash.clone());
+                    stub_hash
+                }
+            };
+            let record = GenerationRecord {
+                toplevel: bootspec.toplevel.0.clone(),
+                cmdline: assemble_kernel_cmdline(&bootspec.init, bootspec.kernel_params.clone()),
+                stub: stub_hash,
+                options: self.options_hash(&generation)?,
+            };
+            records.insert(generation.version(), record);
+        }
+        Ok(records)
+    }
+
+    /// Hash everything the images of a generation are assembled with besides its bootspec: the
+    /// options of the installation, the files they name and the signing certificate.
+    ///
+    /// Section providers are only recorded by their description, so the sections they provide
+    /// have to be deterministic.
+    fn options_hash(&self, generation: &Generation) -> Result<String> {
+        let options = &self.options;
+        let file_hash =
+            |path: &Path| -> Result<String> { Ok(format!("{:x}", pe::file_hash(path)?)) };
+
+        let extra_sections = options
+            .extra_sections
+            .iter()
+            .map(|(name, path)| Ok((name, file_hash(path)?)))
+            .collect::<Result<BTreeMap<_, _>>>()?;
+        let mut generations = vec![generation.clone()];
+        for (name, bootspec) in &generation.spec.bootspec.specialisation {
+            generations.push(generation.specialise(name, bootspec)?);
+        }
+        let generations = generations
+            .iter()
+            .map(|generation| {
+                let devicetree = generation.spec.devicetree.as_deref().map(file_hash);
+                Ok((devicetree.transpose()?, generation.spec.security_version))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let pcr_public_key = options
+            .pcr_signing_key
+            .as_ref()
+            .map(|key| file_hash(&key.public_key))
+            .transpose()?;
+
+        let description = format!(
+            "{:?}",
+            (
+                (
+                    options.hash_algorithm,
+                    options.initrd_hash_policy,
+                    options.machine_type_policy,
+                    options.stub_profile,
+                    options.pe_writer,
+                    options.stub_size_limit,
+                    options.explicit_objcopy_target,
+                ),
+                (
+                    options.embed_kernel,
+                    options.embed_initrd,
+                    options.content_addressed,
+                    options.kernel_version_from_image,
+                    options.build_epoch,
+                ),
+                (
+                    options.sbat,
+                    &options.sbat_entries,
+                    &options.cmdline_profiles,
+                    &options.safe_mode,
+                    options.pinned_recovery,
+                ),
+                (extra_sections, &options.section_providers, pcr_public_key),
+                (
+                    options.sign_initrds,
+                    options.sign_extra_files,
+                    options.write_metadata,
+                ),
+                generations,
+                file_hash(self.signer.public_key())?,
+            )
+        );
+        Ok(format!("{:x}", Sha256::digest(description.as_bytes())))
+    }
+
+    /// Report the generation and its specialisations that an unchanged generation kept.
+    fn report_kept_generation(&mut self, generation: &Generation, stubs: &[PathBuf]) -> Result<()> {
+        let mut generations = vec![generation.clone()];
+        for (name, bootspec) in &generation.spec.bootspec.specialisation {
+            generations.push(generation.specialise(name, bootspec)?);
+        }
+        for generation in &generations {
+            let image = if generation.is_specialised().is_none()
+                && self.options.pinned_recovery == Some(generation.version())
+            {
+                esp::recovery_image_path(&self.esp_paths, generation)
+            } else {
+                EspGenerationPaths::new(&self.esp_paths, generation)?.lanzaboote_image
+            };
+            let stub = match stubs
+                .iter()
+                .find(|stub| esp::without_boot_counter(stub) == image)
+            {
+                Some(stub) => stub,
+                None => continue,
+            };
+            let mut kept = InstalledGeneration::kept(generation, stub, &self.esp_paths.boot)?;
+            if let Some(threshold) = self.options.size_warning_threshold {
+                kept.check_size(threshold);
             }
+            self.report.generations.push(kept);
         }
         Ok(())
     }
 
-    fn install_generation(&mut self, generation: &Generation) -> Result<()> {
-        let bootspec = &generation.spec.bootspec;
+    /// Keep the installed stubs of an unchanged generation and the files they reference.
+    ///
+    /// Returns whether all of them are still intact. Otherwise, the generation has to be
+    /// installed again.
+    fn keep_unchanged_generation(&mut self, stubs: &[PathBuf]) -> Result<bool> {
+        if stubs.is_empty() {
+            return Ok(false);
+        }
 
-        let esp_paths = EspPaths::new(&self.esp, generation)?;
-        self.gc_roots.extend(esp_paths.to_iter());
+        let mut files = stubs.to_vec();
+        for stub in stubs {
+            self.counts.stub_parse();
+            files.extend(pe::referenced_files(stub, &self.esp_paths.boot)?);
+        }
+        if !files
+            .iter()
+            .all(|file| file.exists() && self.manifest.contains(file))
+        {
+            return Ok(false);
+        }
+
+        let sidecars: Vec<PathBuf> = files
+            .iter()
+            .map(|file| esp::detached_signature_path(file))
+            .chain(stubs.iter().map(|stub| esp::metadata_path(stub)))
+            .chain(
+                stubs
+                    .iter()
+                    .map(|stub| esp::boot_entry_path(&self.esp_paths, stub)),
+            )
+            .filter(|sidecar| sidecar.exists() && self.manifest.contains(sidecar))
+            .collect();
+        for stub in stubs {
+            self.gc_roots.extend(&esp::extra_files(stub)?);
+        }
+        self.gc_roots.extend(&files);
+        self.gc_roots.extend(&sidecars);
+        Ok(true)
+    }
+
+    /// Delete the files on the ESP that are not in use anymore.
+    ///
+    /// With incremental garbage collection, only the files that the previous installation
+    /// recorded in the manifest are candidates, except for a periodic full sweep that also finds
+    /// stray files.
+    fn collect_garbage(&mut self, previous_files: &[PathBuf]) -> Result<()> {
+        let full_sweep = match self.options.incremental_gc {
+            // Without a previous manifest, nothing is known about the files on the ESP.
+            Some(interval) => previous_files.is_empty() || self.manifest.needs_full_sweep(interval),
+            None => true,
+        };
+
+        self.counts.garbage_collection();
+        if full_sweep {
+            gc::collect_orphans(&self.esp_paths, &self.gc_roots)?;
+        } else {
+            gc::collect_previous_files(&self.esp_paths, &self.gc_roots, previous_files)?;
+        }
+        self.manifest.record_garbage_collection(full_sweep);
+        Ok(())
+    }
+
+    /// Whether the generation gets the additional safe mode boot entry.
+    ///
+    /// Only the base configuration of the newest generation gets one.
+    fn is_safe_mode_generation(&self, generation: &Generation) -> bool {
+        self.options.safe_mode.is_some()
+            && generation.is_specialised().is_none()
+            && self.newest_generation == Some(generation.version())
+    }
+
+    fn install_generation(&mut self, generation: &Generation) -> Result<EspGenerationPaths> {
+        let bootspec = &generation.spec.bootspec;
 
         let kernel_cmdline =
             assemble_kernel_cmdline(&bootspec.init, bootspec.kernel_params.clone());
 
-        // This tempdir must live for the entire lifetime of the current function.
-        let tempdir = tempfile::tempdir()?;
+        // This tempdir must live until the stubs of the generation are assembled.
+        let tempdir = utils::tempdir()?;
 
-        let os_release = OsRelease::from_generation(generation)
-            .context("Failed to build OsRelease from generation.")?;
+        // Only the base configuration of the pinned generation becomes the recovery entry.
+        let is_recovery = generation.is_specialised().is_none()
+            && self.options.pinned_recovery == Some(generation.version());
+
+        let mut os_release = OsRelease::from_generation(
+            generation,
+            self.options.kernel_version_from_image,
+            self.options.build_epoch,
+        )
+        .context("Failed to build OsRelease from generation.")?;
+        if is_recovery {
+            os_release.set_recovery();
+        }
         let os_release_path = tempdir
             .write_secure_file("os-release", os_release.to_string().as_bytes())
             .context("Failed to write os-release file.")?;
 
-        println!("Appending secrets to initrd...");
+        // Copy and hash the actual contents of kernels and initrds that are symlinks.
+        let kernel =
+            utils::resolve_symlink(&bootspec.kernel).context("Failed to resolve kernel")?;
 
-        let initrd_location = tempdir.path().join("initrd");
-        copy(
-            bootspec
-                .initrd
-                .as_ref()
-                .context("Lanzaboote does not support missing initrd yet")?,
-            &initrd_location,
-        )?;
-        if let Some(initrd_secrets_script) = &bootspec.initrd_secrets {
-            append_initrd_secrets(initrd_secrets_script, &initrd_location)?;
+        // Kernels that need no initrd are booted without one.
+        let base_initrd = bootspec
+            .initrd
+            .as_ref()
+            .map(|initrd| utils::resolve_symlink(initrd).context("Failed to resolve initrd"))
+            .transpose()?;
+
+        self.record_store_paths(
+            [&bootspec.toplevel.0, &kernel]
+                .into_iter()
+                .chain(&base_initrd),
+        );
+        let initrd_location = match &base_initrd {
+            Some(base_initrd) => {
+                let initrd_location = tempdir.path().join("initrd");
+                // The initrd secrets are appended to this copy, before it is hashed, so that the
+                // hash covers the initrd that is actually loaded.
+                copy(base_initrd, &initrd_location)?;
+                utils::set_mode(&initrd_location, utils::PRIVATE_FILE_MODE)?;
+                if let Some(initrd_secrets_script) = &bootspec.initrd_secrets {
+                    println!("Appending secrets to initrd...");
+                    append_initrd_secrets(initrd_secrets_script, &initrd_location)?;
+                }
+                Some(initrd_location)
+            }
+            None if bootspec.initrd_secrets.is_some() => {
+                return Err(anyhow::anyhow!(
+                    "The generation has initrd secrets, but no initrd to append them to"
+                ))
+            }
+            None => None,
+        };
+
+        let initrd_hash_mode = match (
+            self.options.initrd_hash_policy,
+            &bootspec.initrd_secrets,
+            &base_initrd,
+        ) {
+            (InitrdHashPolicy::Base, Some(_), Some(base_initrd)) => InitrdHashMode::Prefix(
+                fs::metadata(base_initrd)
+                    .with_context(|| format!("Failed to read metadata of {base_initrd:?}"))?
+                    .len(),
+            ),
+            _ => InitrdHashMode::Full,
+        };
+
+        let stub_layout = self
+            .stub_layout(&bootspec.system)
+            .context("Failed to assemble stub")?;
+        if generation.spec.extra_files().next().is_some() && !stub_layout.loads_extra_files() {
+            return Err(anyhow::anyhow!(
+                "The generation has extra files (credentials or system extensions), but the stub {:?} is not systemd-stub and would not load them",
+                stub_layout.path
+            ));
+        }
+        let esp_paths = &self.esp_paths;
+        let mut esp_gen_paths = EspGenerationPaths::new(esp_paths, generation)?;
+        if self.options.content_addressed {
+            esp_gen_paths =
+                esp_gen_paths.content_addressed(esp_paths, &kernel, initrd_location.as_deref())?;
+        }
+        if self.options.embed_kernel {
+            esp_gen_paths = esp_gen_paths.embed_kernel();
+        }
+        if self.embeds_initrd() {
+            esp_gen_paths = esp_gen_paths.embed_initrd();
+        }
+        if is_recovery {
+            esp_gen_paths.lanzaboote_image = esp::recovery_image_path(esp_paths, generation);
+        }
+        esp_gen_paths.lanzaboote_image =
+            self.counted_image_path(&esp_gen_paths.lanzaboote_image, generation)?;
+        self.gc_roots.extend(esp_gen_paths.to_iter());
+
+        let systemd_boot_dir = bootspec.toplevel.0.join("systemd/lib/systemd/boot/efi");
+        let systemd_boot = self.options.systemd_boot.clone().unwrap_or_else(|| {
+            systemd_boot_dir.join(format!("systemd-boot{}.efi", esp_paths.architecture.name()))
+        });
+
+        // Every fallback boot loader is installed from the systemd-boot build of its
+        // architecture.
+        let fallback_sources = esp_paths
+            .efi_fallbacks
+            .keys()
+            .map(|architecture| {
+                self.options
+                    .fallback_loaders
+                    .iter()
+                    .find(|l| &l.architecture == architecture)
+                    .and_then(|l| l.source.clone())
+                    .unwrap_or_else(|| {
+                        if architecture == esp_paths.architecture.name() {
+                            systemd_boot.clone()
+                        } else {
+                            systemd_boot_dir.join(format!("systemd-boot{architecture}.efi"))
+                        }
+                    })
+            })
+            .collect::<Vec<_>>();
+
+        // The firmware boots the stubs directly without a boot loader.
+        let boot_loaders: Vec<(&PathBuf, &PathBuf)> = if self.options.efi_boot_entries {
+            Vec::new()
+        } else {
+            fallback_sources
+                .iter()
+                .zip(esp_paths.efi_fallbacks.values())
+                .chain([(&systemd_boot, &esp_paths.systemd_boot)])
+                .collect()
+        };
+        for (source, _) in &boot_loaders {
+            pe::ensure_no_lanzaboote_sections(source)
+                .context("Refusing to install systemd-boot")?;
+        }
+
+        // Nothing of the generation is copied to the ESP before all of its files are scanned.
+        // The stubs are scanned once they are assembled.
+        let initrd_secrets_offset = match &base_initrd {
+            Some(base_initrd)
+                if self.options.allow_initrd_secret_keys && bootspec.initrd_secrets.is_some() =>
+            {
+                let length = fs::metadata(base_initrd)
+                    .with_context(|| format!("Failed to read metadata of {base_initrd:?}"))?
+                    .len();
+                Some(usize::try_from(length).context("The initrd is too large")?)
+            }
+            _ => None,
+        };
+        let scanned_files = [&kernel]
+            .into_iter()
+            .chain(boot_loaders.iter().map(|(from, _)| *from))
+            .chain(generation.spec.extra_files())
+            .map(PathBuf::as_path)
+            .collect::<Vec<_>>();
+        secret_scan::ensure_no_private_keys(&scanned_files)?;
+        if let Some(initrd) = initrd_location
+            .as_ref()
+            .filter(|_| esp_gen_paths.initrd.is_some())
+        {
+            let scanned = match initrd_secrets_offset {
+                Some(offset) => {
+                    secret_scan::ensure_no_private_keys_outside(initrd, offset..usize::MAX)
+                }
+                None => secret_scan::ensure_no_private_keys(&[initrd.as_path()]),
+            };
+            scanned.context(
+                "Refusing to install initrd (pass --allow-keys-in-initrd-secrets if its secrets \
+                 are supposed to contain keys)",
+            )?;
         }
 
-        let systemd_boot = bootspec
-            .toplevel
-            .0
-            .join("systemd/lib/systemd/boot/efi/systemd-bootx64.efi");
+        // The boot loaders are installed once the stubs of all generations are, so that they
+        // never boot stubs that are not written completely.
+        for (from, to) in boot_loaders {
+            if !self
+                .pending_boot_loaders
+                .iter()
+                .any(|(_, other)| other == to)
+            {
+                self.pending_boot_loaders.push((from.clone(), to.clone()));
+            }
+        }
 
-        [
-            (&systemd_boot, &esp_paths.efi_fallback),
-            (&systemd_boot, &esp_paths.systemd_boot),
-            (&bootspec.kernel, &esp_paths.kernel),
-        ]
-        .into_iter()
-        .try_for_each(|(from, to)| install_signed(&self.key_pair, from, to))?;
+        let signed_files = esp_gen_paths
+            .kernel
+            .iter()
+            .map(|to| (kernel.as_path(), to.as_path()))
+            .collect::<Vec<_>>();
+        install_signed_concurrently(
+            &self.signer,
+            &mut self.manifest,
+            &signed_files,
+            &self.options.verbatim,
+            self.options.copy_concurrency,
+        )?;
 
         // The initrd doesn't need to be signed. Lanzaboote has its
         // hash embedded and will refuse loading it when the hash
         // mismatches.
-        install(&initrd_location, &esp_paths.initrd).context("Failed to install initrd to ESP")?;
+        if let Some((from, to)) = initrd_location.as_ref().zip(esp_gen_paths.initrd.as_ref()) {
+            let rewritten = !(to.exists() && self.manifest.contains(to));
+            install(&mut self.manifest, from, to).context("Failed to install initrd to ESP")?;
 
-        let lanzaboote_image = pe::lanzaboote_image(
-            &tempdir,
-            &self.lanzaboote_stub,
-            &os_release_path,
-            &kernel_cmdline,
-            &esp_paths.kernel,
-            &esp_paths.initrd,
-            &esp_paths.esp,
-        )
-        .context("Failed to assemble stub")?;
+            if self.options.sign_initrds {
+                self.write_detached_signature(to, rewritten)
+                    .context("Failed to sign initrd")?;
+            }
+        }
 
-        install_signed(
-            &self.key_pair,
-            &lanzaboote_image,
-            &esp_paths.lanzaboote_image,
-        )
-        .context("Failed to install lanzaboote")?;
+        // An embedded kernel is loaded from memory, so it has to be signed before embedding it.
+        let embedded_kernel = if self.options.embed_kernel {
+            let signed_kernel = tempdir.path().join("kernel");
+            self.signer
+                .sign_and_copy(&kernel, &signed_kernel)
+                .context("Failed to sign kernel to embed")?;
+            Some(signed_kernel)
+        } else {
+            None
+        };
 
-        // Sync files to persistent storage. This may improve the
-        // chance of a consistent boot directory in case the system
-        // crashes.
-        sync();
+        // Every cmdline profile gets its own stub. Only the .osrel and .cmdline sections differ,
+        // all stubs reference the same kernel and initrd on the ESP.
+        let mut images = vec![(
+            os_release_path,
+            kernel_cmdline,
+            esp_gen_paths.lanzaboote_image.clone(),
+        )];
+        for profile in &self.options.cmdline_profiles {
+            let mut os_release = OsRelease::from_generation(
+                generation,
+                self.options.kernel_version_from_image,
+                self.options.build_epoch,
+            )
+            .context("Failed to build OsRelease from generation.")?;
+            os_release.set_cmdline_profile(&profile.name);
+            let os_release_path = tempdir
+                .write_secure_file(
+                    &format!("os-release-{}", profile.name),
+                    os_release.to_string().as_bytes(),
+                )
+                .context("Failed to write os-release file.")?;
+
+            let mut kernel_cmdline = images[0].1.clone();
+            kernel_cmdline.extend(profile.kernel_params.iter().cloned());
+
+            let image_path = self.counted_image_path(
+                &esp::cmdline_profile_image_path(esp_paths, generation, &profile.name),
+                generation,
+            )?;
+            self.gc_roots.extend([&image_path]);
+            images.push((os_release_path, kernel_cmdline, image_path));
+        }
+        if let Some(kernel_params) = self
+            .options
+            .safe_mode
+            .as_ref()
+            .filter(|_| self.is_safe_mode_generation(generation))
+        {
+            let mut os_release = OsRelease::from_generation(
+                generation,
+                self.options.kernel_version_from_image,
+                self.options.build_epoch,
+            )
+            .context("Failed to build OsRelease from generation.")?;
+            os_release.set_safe_mode();
+            let os_release_path = tempdir
+                .write_secure_file("os-release-safe-mode", os_release.to_string().as_bytes())
+                .context("Failed to write os-release file.")?;
+
+            // The kernel parameters of the generation are dropped because they may be what
+            // keeps it from booting.
+            let kernel_cmdline = assemble_kernel_cmdline(&bootspec.init, kernel_params.clone());
+
+            let image_path = self.counted_image_path(
+                &esp::safe_mode_image_path(esp_paths, generation),
+                generation,
+            )?;
+            self.gc_roots.extend([&image_path]);
+            images.push((os_release_path, kernel_cmdline, image_path));
+        }
+
+        let sbat_entries = if self.options.sbat {
+            let kernel_version =
+                generation.kernel_version(self.options.kernel_version_from_image)?;
+            let mut entries = vec![
+                sbat::lanzaboote_entry(),
+                sbat::kernel_entry(&kernel_version),
+            ];
+            entries.extend(self.options.sbat_entries.iter().cloned());
+            entries
+        } else {
+            Vec::new()
+        };
+
+        let image_options = ImageOptions {
+            initrd_hash_mode,
+            hash_algorithm: self.options.hash_algorithm,
+            show_commands: self.options.show_commands,
+            extra_sections: self.options.extra_sections.clone(),
+            machine_type: pe::machine_type_for_system(&bootspec.system),
+            machine_type_policy: self.options.machine_type_policy,
+            stub_profile: self.options.stub_profile,
+            pe_writer: self.options.pe_writer,
+            section_providers: self.options.section_providers.clone(),
+            size_limit: self.options.stub_size_limit,
+            objcopy_target: if self.options.explicit_objcopy_target {
+                pe::machine_type_for_system(&bootspec.system).and_then(pe::objcopy_target)
+            } else {
+                None
+            },
+            embedded_kernel,
+            // The stub is signed as a whole, so the embedded initrd needs no signature of
+            // its own.
+            embedded_initrd: initrd_location.filter(|_| self.embeds_initrd()),
+            devicetree: generation.spec.devicetree.clone(),
+            security_version: generation.spec.security_version,
+            // systemd-measure comes from the systemd of the generation.
+            pcr_policy: self.options.pcr_signing_key.as_ref().map(|key| PcrPolicy {
+                systemd_measure: bootspec
+                    .toplevel
+                    .0
+                    .join("systemd/lib/systemd/systemd-measure"),
+                public_key: key.public_key.clone(),
+                private_key: key.private_key.clone(),
+            }),
+            sbat_entries,
+            timestamp: self
+                .options
+                .build_epoch
+                .and_then(|build_epoch| u32::try_from(utils::unix_seconds(build_epoch)).ok()),
+        };
+
+        // The stubs are assembled once the files of all generations are on the ESP.
+        let stubs_instead = self.options.boot_loader_entries == Some(BootLoaderEntryMode::Instead);
+        let mut stubs = Vec::new();
+        for (os_release, kernel_cmdline, image_path) in images.iter().filter(|_| !stubs_instead) {
+            if image_path.exists() && self.manifest.contains(image_path) {
+                println!("{} already exists, skipping...", image_path.display());
+            } else {
+                stubs.push(PendingStub {
+                    os_release: os_release.clone(),
+                    kernel_cmdline: kernel_cmdline.clone(),
+                    image_path: image_path.clone(),
+                });
+            }
+        }
+
+        let extra_files = self.install_extra_files(generation, &images)?;
+
+        // Also set the modification time of files that already existed so that the ESP state
+        // does not depend on the history of installations.
+        let boot_loader_files: Vec<&PathBuf> = if self.options.efi_boot_entries {
+            Vec::new()
+        } else {
+            [&esp_paths.systemd_boot, &esp_paths.random_seed]
+                .into_iter()
+                .chain(esp_paths.efi_fallbacks.values())
+                .collect()
+        };
+        boot_loader_files
+            .into_iter()
+            .chain(&esp_gen_paths.kernel)
+            .chain(&esp_gen_paths.initrd)
+            .chain(&extra_files)
+            .try_for_each(|path| self.set_mtime(path))?;
+
+        if self.options.boot_loader_entries.is_some() {
+            let entry = self
+                .write_boot_entry(generation, &esp_gen_paths, &images[0].1)
+                .context("Failed to write boot loader entry")?;
+            // Without a stub, the boot loader entry is what boots the generation, e.g. as the
+            // default entry.
+            if stubs_instead {
+                esp_gen_paths.lanzaboote_image = entry;
+            }
+        }
+
+        self.pending_generations.push(PendingGeneration {
+            generation: generation.clone(),
+            _tempdir: tempdir,
+            stub_layout,
+            esp_gen_paths: esp_gen_paths.clone(),
+            image_options,
+            stubs,
+            images: images
+                .into_iter()
+                .filter(|_| !stubs_instead)
+                .map(|(_, _, image_path)| image_path)
+                .collect(),
+            initrd_secrets_offset,
+        });
+
+        Ok(esp_gen_paths)
+    }
+
+    /// Assemble and sign the stubs of the generations installed so far, up to
+    /// `stub_concurrency` of them at the same time.
+    ///
+    /// The stubs are recorded in the manifest in the order of the generations, so that the
+    /// manifest does not depend on which stub was finished first. The temporary inputs of the
+    /// generations are removed once their stubs are installed.
+    fn install_pending_stubs(&mut self) -> Result<()> {
+        let pending = std::mem::take(&mut self.pending_generations);
+        let stubs: Vec<(&PendingGeneration, &PendingStub)> = pending
+            .iter()
+            .flat_map(|generation| generation.stubs.iter().map(move |stub| (generation, stub)))
+            .collect();
+
+        let signer = &self.signer;
+        let boot = &self.esp_paths.boot;
+        utils::run_concurrently(
+            &stubs,
+            self.options.stub_concurrency,
+            |(generation, stub)| generation.install(stub, boot, signer),
+        )?;
+
+        for generation in &pending {
+            for stub in &generation.stubs {
+                self.manifest.record(&stub.image_path)?;
+            }
+
+            for image_path in &generation.images {
+                if self.options.write_metadata {
+                    self.write_stub_metadata(image_path)?;
+                }
+
+                if self.options.preview_pcrs {
+                    let prediction = pe::predict_pcrs(image_path)?;
+                    println!("Predicted PCR 11 values of {}:", image_path.display());
+                    for (phases, value) in prediction.phases() {
+                        println!("  {phases}: {value:x}");
+                    }
+                }
+
+                // Some firmware refuses to load binaries with a wrong checksum.
+                if !pe::verify_checksum(image_path)? {
+                    println!("Warning: the stub {image_path:?} has an incorrect PE checksum");
+                }
+
+                self.set_mtime(image_path)?;
+            }
+
+            let mut installed_generation =
+                InstalledGeneration::new(&generation.generation, &generation.esp_gen_paths)?;
+            if let Some(threshold) = self.options.size_warning_threshold {
+                installed_generation.check_size(threshold);
+            }
+            self.report.generations.push(installed_generation);
+        }
+        self.installed_generations += pending.len();
+        Ok(())
+    }
+
+    /// The path to install a stub of a generation to with boot counting.
+    ///
+    /// A stub that is already installed keeps the name that systemd-boot gave it, so that
+    /// reinstalling a generation neither resets nor duplicates its boot counter. Only a stub that
+    /// used up all of its tries gets a fresh counter once the generation changed, because
+    /// systemd-boot would never try its new contents otherwise. Without boot counting, stubs are
+    /// installed without counter.
+    fn counted_image_path(&self, path: &Path, generation: &Generation) -> Result<PathBuf> {
+        let tries = match self.options.boot_counting_tries {
+            Some(tries) => tries,
+            None => return Ok(path.to_path_buf()),
+        };
+        let changed = !self.unchanged_generations.contains(&generation.version());
+        match esp::installed_image(path)? {
+            Some(installed) if changed && esp::boot_counter_exhausted(&installed) => {
+                println!(
+                    "Resetting the exhausted boot counter of {}, because the generation changed",
+                    installed.display()
+                );
+                Ok(esp::with_boot_counter(path, tries))
+            }
+            Some(installed) => Ok(installed),
+            None => Ok(esp::with_boot_counter(path, tries)),
+        }
+    }
+
+    /// Write the metadata sidecar of an installed stub.
+    ///
+    /// The sidecar is derived from the installed stub and only informational. It is not signed, so
+    /// nothing may rely on it.
+    fn write_stub_metadata(&mut self, stub: &Path) -> Result<()> {
+        let metadata = pe::stub_metadata(stub, &self.esp_paths.boot)?;
+        let path = esp::metadata_path(stub);
+        let contents =
+            serde_json::to_vec_pretty(&metadata).context("Failed to serialize stub metadata")?;
+        utils::atomic_write(&path, contents, utils::PUBLIC_FILE_MODE)
+            .with_context(|| format!("Failed to write stub metadata to {path:?}"))?;
+        self.manifest.record(&path)?;
+        self.set_mtime(&path)?;
+        self.gc_roots.extend([&path]);
+        Ok(())
+    }
+
+    /// Whether the initrd is embedded into the stubs, which requires the kernel to be embedded.
+    fn embeds_initrd(&self) -> bool {
+        self.options.embed_kernel && self.options.embed_initrd
+    }
+
+    /// Write a Type #1 boot loader entry that boots the kernel and initrd of the stub directly and
+    /// return its path.
+    ///
+    /// The firmware verifies the signature of the kernel when systemd-boot loads it, but nothing
+    /// verifies the initrd.
+    fn write_boot_entry(
+        &mut self,
+        generation: &Generation,
+        esp_gen_paths: &EspGenerationPaths,
+        kernel_cmdline: &[String],
+    ) -> Result<PathBuf> {
+        let boot = &self.esp_paths.boot;
+        let kernel = esp_gen_paths.kernel.as_ref().with_context(|| {
+            format!("The kernel of generation {generation} is embedded into the stub")
+        })?;
+        let entry = BootEntry {
+            title: generation.spec.bootspec.label.clone(),
+            version: generation.describe(
+                self.options.kernel_version_from_image,
+                self.options.build_epoch,
+            )?,
+            linux: boot_entry::partition_relative_path(boot, kernel)?,
+            initrd: esp_gen_paths
+                .initrd
+                .as_ref()
+                .map(|initrd| boot_entry::partition_relative_path(boot, initrd))
+                .transpose()?,
+            options: kernel_cmdline.to_vec(),
+        };
+
+        let path = esp::boot_entry_path(&self.esp_paths, &esp_gen_paths.lanzaboote_image);
+        utils::atomic_write(&path, entry.to_string(), utils::PUBLIC_FILE_MODE)
+            .with_context(|| format!("Failed to write boot loader entry to {path:?}"))?;
+        self.manifest.record(&path)?;
+        self.set_mtime(&path)?;
+        self.gc_roots.extend([&path]);
+        Ok(path)
+    }
 
-        println!(
-            "Successfully installed lanzaboote to '{}'",
-            esp_paths.esp.display()
+    /// Restore the managed settings of `loader.conf` if they drifted.
+    ///
+    /// With `make_default`, the newest installed generation (but never a specialisation) is
+    /// managed as the default entry, with `default_generation` the given one. Settings that are
+    /// not managed are kept. The file is only rewritten if a setting changed.
+    fn update_loader_conf(&self) -> Result<()> {
+        let mut settings = self.options.loader_settings.clone();
+        if let Some(version) = self.options.default_generation {
+            match self.generation_entry(Some(version)) {
+                Some(entry) => settings.push(LoaderSetting::new("default", entry)),
+                None => println!(
+                    "Warning: the default generation {version} is not installed, keeping the default entry"
+                ),
+            }
+        } else if self.options.make_default {
+            match self.generation_entry(None) {
+                Some(entry) => settings.push(LoaderSetting::new("default", entry)),
+                None => println!("Warning: no generation was installed that could be the default"),
+            }
+        }
+        if settings.is_empty() {
+            return Ok(());
+        }
+
+        let mut loader_conf = LoaderConf::read(&self.esp_paths.loader_conf)?;
+        let changes = loader_conf.enforce(&settings);
+        if changes.is_empty() {
+            return Ok(());
+        }
+
+        for change in changes {
+            println!("Updating loader.conf: {change}");
+        }
+        loader_conf.write(&self.esp_paths.loader_conf)?;
+        self.set_mtime(&self.esp_paths.loader_conf)
+    }
+
+    /// Record the store paths that contain the given paths.
+    fn record_store_paths<'a>(&mut self, paths: impl IntoIterator<Item = &'a PathBuf>) {
+        let store_dir = utils::store_dir();
+        self.store_paths.extend(
+            paths
+                .into_iter()
+                .filter_map(|path| utils::store_path(&store_dir, path)),
         );
+    }
+
+    /// Write the store paths of the installed generations to a file, one per line.
+    ///
+    /// Registering these as GC roots keeps Nix from collecting the store paths that the stubs on
+    /// the ESP were assembled from.
+    fn write_store_roots(&self, path: &Path) -> Result<()> {
+        let contents: String = self
+            .store_paths
+            .iter()
+            .map(|store_path| format!("{}\n", store_path.display()))
+            .collect();
+        utils::atomic_write(path, contents, utils::PUBLIC_FILE_MODE)
+            .with_context(|| format!("Failed to write store roots to {path:?}"))
+    }
+
+    /// Copy the credentials and system extensions of a generation into the companion directory of
+    /// every stub of it, where systemd-stub picks them up. Returns the installed files.
+    ///
+    /// Copies with the same contents are hard linked if the filesystem supports it.
+    fn install_extra_files(
+        &mut self,
+        generation: &Generation,
+        images: &[(PathBuf, Vec<String>, PathBuf)],
+    ) -> Result<Vec<PathBuf>> {
+        let mut installed = Vec::new();
+        if generation.spec.extra_files().next().is_none() {
+            return Ok(installed);
+        }
+
+        for (_, _, image_path) in images {
+            let extra_dir = esp::extra_dir_path(image_path);
+            self.gc_roots.extend([&extra_dir]);
+            for from in generation.spec.extra_files() {
+                let to = extra_dir.join(from.file_name().with_context(|| {
+                    format!("The extra file {from:?} of the generation has no file name")
+                })?);
+                let rewritten = !(to.exists() && self.manifest.contains(&to));
+                install(&mut self.manifest, from, &to)
+                    .with_context(|| format!("Failed to install {from:?} to ESP"))?;
+                if self.options.sign_extra_files {
+                    self.write_detached_signature(&to, rewritten)
+                        .with_context(|| format!("Failed to sign {to:?}"))?;
+                }
+                installed.push(to);
+            }
+        }
+        self.gc_roots.extend(&installed);
+        Ok(installed)
+    }
+
+    /// Write a detached signature next to a file on the ESP, unless it has one already and the
+    /// file was not rewritten.
+    fn write_detached_signature(&mut self, path: &Path, rewritten: bool) -> Result<()> {
+        let signature = esp::detached_signature_path(path);
+        self.gc_roots.extend([&signature]);
+        if rewritten || !(signature.exists() && self.manifest.contains(&signature)) {
+            println!("Signing {}...", path.display());
+            self.signer.sign_detached(path, &signature)?;
+            utils::set_esp_mode(&signature, utils::PUBLIC_FILE_MODE)?;
+            self.manifest.record(&signature)?;
+        }
+        Ok(())
+    }
+
+    /// Create a boot option for every stub that is left on the ESP after the garbage collection
+    /// and remove the ones of the stubs that were removed.
+    ///
+    /// The default generation (or else the newest one) comes first in the boot order, followed by
+    /// the other generations from the newest to the oldest.
+    fn update_boot_options(&self, partition: &boot_options::Partition) -> Result<()> {
+        let mut stubs = esp::nixos_images(&self.esp_paths.linux)?;
+        let default = self
+            .generation_entry(self.options.default_generation)
+            .or_else(|| self.generation_entry(None));
+        stubs.sort_by_key(|stub| {
+            let is_default = stub
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                == default;
+            (!is_default, Reverse(esp::image_version(stub)), stub.clone())
+        });
 
+        let stubs = stubs
+            .iter()
+            .map(|stub| {
+                Ok(BootOption {
+                    description: stub
+                        .file_stem()
+                        .map(|stem| stem.to_string_lossy().into_owned())
+                        .unwrap_or_default(),
+                    path: pe::uefi_path_for(&self.esp_paths.boot, stub)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        boot_options::sync_boot_options(&self.options.efivars, partition, &stubs)
+    }
+
+    /// Raise the minimum security version in the firmware to the security version of the newest
+    /// generation. It is never lowered, and never created here (see [`anti_rollback`]).
+    fn update_minimum_security_version(
+        &self,
+        security_versions: &BTreeMap<u64, u64>,
+    ) -> Result<()> {
+        let newest = match self
+            .newest_generation
+            .and_then(|version| security_versions.get(&version))
+        {
+            Some(newest) => *newest,
+            None => return Ok(()),
+        };
+        let minimum = match anti_rollback::minimum_security_version(&self.options.efivars)? {
+            Some(minimum) => minimum,
+            None => {
+                return Err(anyhow::anyhow!(
+                    "The minimum security version does not exist. Run `lzbt setup --enroll` to create it, signed with the signing key"
+                ))
+            }
+        };
+        if newest < minimum {
+            println!(
+                "Warning: the newest generation has the security version {newest}, below the minimum {minimum}, and refuses to boot."
+            );
+        }
+        if newest <= minimum {
+            return Ok(());
+        }
+
+        anti_rollback::write_minimum_security_version(&self.options.efivars, &self.signer, newest)?;
+        println!("Raised the minimum security version from {minimum} to {newest}.");
+        let refused: Vec<String> = security_versions
+            .iter()
+            .filter(|(_, security_version)| **security_version < newest)
+            .map(|(version, _)| version.to_string())
+            .collect();
+        if !refused.is_empty() {
+            println!(
+                "Warning: the stubs of these generations have a lower security version and refuse to boot now: {}",
+                refused.join(", ")
+            );
+        }
         Ok(())
     }
+
+    /// The name systemd-boot derives for the entry of an installed generation (or of the newest
+    /// one), which is the file name of its stub.
+    fn generation_entry(&self, version: Option<u64>) -> Option<String> {
+        self.report
+            .generations
+            .iter()
+            .filter(|generation| generation.specialisation.is_none())
+            .filter(|generation| version.map_or(true, |version| generation.version == version))
+            .max_by_key(|generation| generation.version)
+            // systemd-boot identifies entries without their boot counter.
+            .map(|generation| esp::without_boot_counter(&generation.stub))
+            .and_then(|stub| {
+                stub.file_name()
+                    .map(|entry| entry.to_string_lossy().into_owned())
+            })
+    }
+
+    /// The layout of the stub for a Nix system double, which is read and parsed only once per
+    /// installation.
+    fn stub_layout(&mut self, system: &str) -> Result<StubLayout> {
+        let stub = self.lanzaboote_stub.select(system)?;
+        if let Some(stub_layout) = self.stub_layouts.get(&stub) {
+            return Ok(stub_layout.clone());
+        }
+
+        println!("Reading lanzaboote stub {}...", stub.display());
+        let stub_layout = StubLayout::read(&stub)?;
+        self.counts.stub_layout_read();
+        self.stub_layouts.insert(stub, stub_layout.clone());
+        Ok(stub_layout)
+    }
+
+    /// Set the fixed modification time on an installed file, if one is configured.
+    fn set_mtime(&self, path: &Path) -> Result<()> {
+        if let Some(mtime) = self.options.mtime {
+            filetime::set_file_mtime(path, utils::fat_timestamp(mtime))
+                .with_context(|| format!("Failed to set modification time of {path:?}"))?;
+        }
+        Ok(())
+    }
+}
+
+/// The installed stubs in the `EFI/Linux` directory grouped by the version of their generation.
+fn installed_stubs_by_version(linux: &Path) -> Result<BTreeMap<u64, Vec<PathBuf>>> {
+    let mut stubs: BTreeMap<u64, Vec<PathBuf>> = BTreeMap::new();
+    for stub in esp::nixos_images(linux)? {
+        if let Some(version) = esp::image_version(&stub) {
+            stubs.entry(version).or_default().push(stub);
+        }
+    }
+    Ok(stubs)
 }
 
-/// Install a PE file. The PE gets signed in the process.
+/// Build the generations of the links including their specialisations.
 ///
-/// The file is only signed and copied if it doesn't exist at the destination
-fn install_signed(key_pair: &KeyPair, from: &Path, to: &Path) -> Result<()> {
-    if to.exists() {
-        println!("{} already exists, skipping...", to.display());
+/// Malformed generations are skipped like during the installation.
+fn generations_with_specialisations(links: &[GenerationLink]) -> Result<Vec<Generation>> {
+    let mut generations = Vec::new();
+    for link in links {
+        let generation = match Generation::from_link(link) {
+            Ok(generation) => generation,
+            Err(_) => continue,
+        };
+        let specialisations = generation
+            .spec
+            .bootspec
+            .specialisation
+            .iter()
+            .map(|(name, bootspec)| generation.specialise(name, bootspec))
+            .collect::<Result<Vec<_>>>()?;
+        generations.push(generation);
+        generations.extend(specialisations);
+    }
+    Ok(generations)
+}
+
+/// The size of `from` if it is installed to `to` and `to` is not counted yet.
+///
+/// Generations share their kernels and initrds on the ESP, so these are only counted once.
+fn new_file_size(counted: &mut BTreeSet<PathBuf>, from: &Path, to: &Path) -> Result<u64> {
+    if counted.insert(to.to_path_buf()) && !to.exists() {
+        pe::file_size(from)
     } else {
-        println!("Signing and installing {}...", to.display());
+        Ok(0)
+    }
+}
+
+/// List generation versions for a message, e.g. `1, 2`.
+fn list_versions(versions: &[u64]) -> String {
+    versions
+        .iter()
+        .map(u64::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The security versions of the generations of the links by their version.
+///
+/// Malformed generations are skipped like during the installation.
+fn security_versions(links: &[GenerationLink]) -> BTreeMap<u64, u64> {
+    links
+        .iter()
+        .filter_map(|link| {
+            Generation::from_link(link)
+                .ok()
+                .map(|generation| (link.version, generation.spec.security_version))
+        })
+        .collect()
+}
+
+/// Install several PE files, signing and copying up to `concurrency` of them at the same time.
+///
+/// A file is only signed and copied if it doesn't exist at the destination or if it was not
+/// completely written by a previous installation.
+///
+/// The files are recorded in the manifest in the order they are given once all of them are
+/// written, so that the manifest does not depend on which copy finished first. Files whose source
+/// is in `verbatim` are copied without signing them.
+fn install_signed_concurrently(
+    signer: &SigningKey,
+    manifest: &mut Manifest,
+    files: &[(&Path, &Path)],
+    verbatim: &[PathBuf],
+    concurrency: usize,
+) -> Result<()> {
+    let pending = files
+        .iter()
+        .filter(|(_, to)| {
+            let installed = to.exists() && manifest.contains(to);
+            if installed {
+                println!("{} already exists, skipping...", to.display());
+            }
+            !installed
+        })
+        .collect::<Vec<_>>();
+
+    // Files with the same source (e.g. systemd-boot and the default fallback boot loader) are
+    // only signed once. The others are linked to (or copied from) the first one.
+    let (unique, duplicates): (Vec<_>, Vec<_>) = pending
+        .iter()
+        .enumerate()
+        .partition(|(index, (from, _))| !pending[..*index].iter().any(|(other, _)| other == from));
+    let unique: Vec<_> = unique.into_iter().map(|(_, file)| *file).collect();
+
+    utils::run_concurrently(&unique, concurrency, |(from, to)| {
+        if verbatim.iter().any(|path| path == from) {
+            copy_verbatim(from, to)
+        } else {
+            sign_and_copy(signer, from, to)
+        }
+    })?;
+
+    for (_, (from, to)) in duplicates {
+        let (_, original) = unique
+            .iter()
+            .find(|(other, _)| other == from)
+            .expect("Every duplicate has an original");
+        println!("Installing {} from {}...", to.display(), original.display());
         ensure_parent_dir(to);
-        key_pair
-            .sign_and_copy(from, to)
-            .with_context(|| format!("Failed to copy and sign file from {:?} to {:?}", from, to))?;
+        utils::link_or_copy(original, to)?;
+        utils::set_esp_mode(to, utils::PUBLIC_FILE_MODE)?;
     }
 
-    Ok(())
+    pending.iter().try_for_each(|(_, to)| manifest.record(to))
+}
+
+/// Sign a PE file and copy it to the ESP.
+///
+/// The signed file atomically replaces an existing one, so that a failed update (e.g. of the
+/// fallback boot loader) leaves the previous binary intact.
+fn sign_and_copy(signer: &SigningKey, from: &Path, to: &Path) -> Result<()> {
+    println!("Signing and installing {}...", to.display());
+    ensure_parent_dir(to);
+    utils::atomic_replace(to, |staging| signer.sign_and_copy(from, staging))
+        .with_context(|| format!("Failed to copy and sign file from {:?} to {:?}", from, to))?;
+    utils::set_esp_mode(to, utils::PUBLIC_FILE_MODE)
+}
+
+/// Copy a PE file to the ESP without signing it.
+fn copy_verbatim(from: &Path, to: &Path) -> Result<()> {
+    println!("Installing {} without signing it...", to.display());
+    copy(from, to)?;
+    utils::set_esp_mode(to, utils::PUBLIC_FILE_MODE)
 }
 
 /// Install an arbitrary file
 ///
-/// The file is only copied if it doesn't exist at the destination
-fn install(from: &Path, to: &Path) -> Result<()> {
-    if to.exists() {
+/// The file is only copied if it doesn't exist at the destination or if it was not completely
+/// written by a previous installation.
+fn install(manifest: &mut Manifest, from: &Path, to: &Path) -> Result<()> {
+    if to.exists() && manifest.contains(to) {
         println!("{} already exists, skipping...", to.display());
     } else {
         println!("Installing {}...", to.display());
         ensure_parent_dir(to);
-        copy(from, to)?;
+        // Generations often share files with identical contents under different names.
+        let hash = pe::file_hash(from).with_context(|| format!("Failed to hash {from:?}"))?;
+        match manifest.find_identical(to, &format!("{hash:x}")) {
+            Some(original) => {
+                utils::link_or_copy(&original, to)?;
+            }
+            None => copy(from, to)?,
+        }
+        utils::set_esp_mode(to, utils::PUBLIC_FILE_MODE)?;
+        manifest.record(to)?;
     }
 
     Ok(())
 }
 
+/// Size of the random seed in bytes. This matches what `bootctl` creates.
+const RANDOM_SEED_SIZE: usize = 32;
+
+/// Initialize the random seed of systemd-boot, if it doesn't exist yet.
+///
+/// systemd-boot passes entropy from the random seed to the kernel early during boot and refreshes
+/// it on every boot. Thus, an existing random seed is never overwritten.
+fn install_random_seed(path: &Path) -> Result<()> {
+    if path.exists() {
+        return Ok(());
+    }
+
+    println!("Initializing random seed {}...", path.display());
+    let mut seed = [0; RANDOM_SEED_SIZE];
+    getrandom::getrandom(&mut seed).context("Failed to generate random seed")?;
+
+    utils::atomic_write(path, seed, utils::PRIVATE_FILE_MODE)
+        .with_context(|| format!("Failed to write random seed to {}", path.display()))
+}
+
+/// When a generation link was created, i.e. when the generation was built or activated.
+fn generation_link_time(link: &GenerationLink) -> Result<SystemTime> {
+    fs::symlink_metadata(&link.path)
+        .and_then(|metadata| metadata.modified())
+        .with_context(|| format!("Failed to read the time of generation link {:?}", link.path))
+}
+
+/// The UEFI architecture of the system that the newest generation was built for.
+fn newest_generation_architecture(links: &[GenerationLink]) -> Option<Architecture> {
+    let newest = links.iter().max_by_key(|link| link.version)?;
+    let generation = Generation::from_link(newest).ok()?;
+    Architecture::for_system(&generation.spec.bootspec.system)
+}
+
+/// The paths on the ESP (and XBOOTLDR partition) that an installation with these options for a
+/// machine of `architecture` uses.
+fn esp_paths_for(esp: PathBuf, options: &InstallOptions, architecture: Architecture) -> EspPaths {
+    let esp_paths = EspPaths::new(esp, options.xbootldr.as_deref()).with_architecture(architecture);
+    if options.fallback_loaders.is_empty() {
+        esp_paths
+    } else {
+        esp_paths.with_fallback_architectures(
+            options
+                .fallback_loaders
+                .iter()
+                .map(|l| l.architecture.as_str()),
+        )
+    }
+}
+
 pub fn append_initrd_secrets(
     append_initrd_secrets_path: &Path,
     initrd_path: &PathBuf,
@@ -254,20 +2252,21 @@ fn assemble_kernel_cmdline(init: &Path, kernel_params: Vec<String>) -> Vec<Strin
     kernel_cmdline
 }
 
+/// Copy a file.
+///
+/// The copy atomically replaces an existing file, so that an interrupted installation never
+/// leaves a partially written file behind.
+///
+/// `fs::copy` carries over the permissions of the source (which are read-only in the Nix store),
+/// so callers set the mode of the copy explicitly.
 fn copy(from: &Path, to: &Path) -> Result<()> {
     ensure_parent_dir(to);
-    fs::copy(from, to)
-        .with_context(|| format!("Failed to copy from {} to {}", from.display(), to.display()))?;
-
-    // Set permission of all files copied to 0o755
-    let mut perms = fs::metadata(to)
-        .with_context(|| format!("File {} doesn't have metadata", to.display()))?
-        .permissions();
-    perms.set_mode(0o755);
-    fs::set_permissions(to, perms)
-        .with_context(|| format!("Failed to set permissions to: {}", to.display()))?;
-
-    Ok(())
+    utils::atomic_replace(to, |staging| {
+        fs::copy(from, staging).with_context(|| {
+            format!("Failed to copy from {} to {}", from.display(), to.display())
+        })?;
+        Ok(())
+    })
 }
 
 // Ensures the parent directory of an arbitrary path exists
@@ -0,0 +1,104 @@
+//! An async variant of the installation for programs that run on tokio.
+//!
+//! Hashing, copying and signing block, so the installation runs on the blocking thread pool of
+//! tokio instead of the executor. It is the same installation as [`crate::api::install`].
+
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+
+use futures_core::Stream;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use crate::api;
+use crate::error::LanzabooteError;
+use crate::esp_fs::EspFilesystem;
+use crate::install::InstallOptions;
+use crate::pe::StubSource;
+use crate::report::ProgressObserver;
+use crate::signature::SigningKey;
+
+/// A phase of an installation that started, e.g. "generation 3" or "collecting garbage".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstallProgress {
+    pub phase: String,
+}
+
+/// The progress of an installation by [`install_async`].
+///
+/// The stream ends once the installation is done, whether it succeeded or not.
+#[derive(Debug)]
+pub struct ProgressStream(UnboundedReceiver<InstallProgress>);
+
+impl Stream for ProgressStream {
+    type Item = InstallProgress;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        self.0.poll_recv(cx)
+    }
+}
+
+/// Sends the phases to the progress stream and to the observer of the options, if any.
+#[derive(Debug)]
+struct ChannelObserver {
+    sender: UnboundedSender<InstallProgress>,
+    observer: Option<Arc<dyn ProgressObserver>>,
+}
+
+impl ProgressObserver for ChannelObserver {
+    fn phase_started(&self, phase: &str) {
+        if let Some(observer) = &self.observer {
+            observer.phase_started(phase);
+        }
+        // Nobody may be interested in the progress anymore.
+        let _ = self.sender.send(InstallProgress {
+            phase: phase.to_owned(),
+        });
+    }
+}
+
+/// Install generations to an ESP like [`api::install`] without blocking the executor.
+///
+/// Nothing happens until the returned future is polled. It completes with the ESP once the
+/// installation is done. Meanwhile, the stream yields the phases of the installation.
+pub fn install_async<E>(
+    mut esp: E,
+    stub: StubSource,
+    signer: SigningKey,
+    configuration_limit: usize,
+    generations: Vec<PathBuf>,
+    mut options: InstallOptions,
+) -> (
+    ProgressStream,
+    impl Future<Output = Result<E, LanzabooteError>> + Send + 'static,
+)
+where
+    E: EspFilesystem + Send + 'static,
+{
+    let (sender, receiver) = mpsc::unbounded_channel();
+    options.progress = Some(Arc::new(ChannelObserver {
+        sender,
+        observer: options.progress.take(),
+    }));
+
+    let installation = async move {
+        tokio::task::spawn_blocking(move || {
+            api::install(
+                &mut esp,
+                stub,
+                signer,
+                configuration_limit,
+                generations,
+                options,
+            )?;
+            Ok(esp)
+        })
+        .await
+        .map_err(|e| {
+            LanzabooteError::Other(anyhow::Error::new(e).context("Failed to run the installation"))
+        })?
+    };
+    (ProgressStream(receiver), installation)
+}
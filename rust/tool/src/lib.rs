@@ -0,0 +1,42 @@
+//! lzbt installs NixOS generations as signed stubs for lanzaboote.
+//!
+//! Besides the command line interface in [`cli`], the installation, the garbage collection and
+//! the verification are available as a library in [`api`]. With the `tokio` feature, the
+//! installation is also available as an async function.
+
+mod anti_rollback;
+pub mod api;
+mod boot_entry;
+mod boot_options;
+mod bundle;
+pub mod cli;
+mod compare;
+mod config;
+mod error;
+mod esp;
+mod esp_fs;
+mod fat;
+mod gc;
+mod generation;
+mod hook;
+mod install;
+#[cfg(feature = "tokio")]
+mod install_async;
+mod list;
+mod loader_conf;
+mod manifest;
+mod migrate;
+mod os_release;
+mod pe;
+mod pe_writer;
+mod report;
+mod sbat;
+mod secret_scan;
+mod setup;
+mod signature;
+mod signing_request;
+mod space;
+mod status;
+mod uninstall;
+mod utils;
+mod verify;
@@ -0,0 +1,91 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::esp::{self, EspPaths};
+use crate::pe;
+
+/// The number of characters of a kernel command line that the listing shows.
+const CMDLINE_WIDTH: usize = 80;
+
+/// A stub that is installed to the ESP.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct ListedStub {
+    pub stub: PathBuf,
+    /// The version of the generation the stub belongs to, if its name carries one.
+    pub version: Option<u64>,
+    /// The kernel command line embedded into the stub, if it has a `.cmdline` section.
+    pub cmdline: Option<String>,
+}
+
+/// Display the stub on a single line with a truncated kernel command line.
+impl fmt::Display for ListedStub {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = self.stub.file_name().map_or_else(
+            || self.stub.display().to_string(),
+            |n| n.to_string_lossy().into_owned(),
+        );
+        match self.version {
+            Some(version) => write!(f, "Generation {version} ({name})")?,
+            None => write!(f, "{name}")?,
+        }
+        match &self.cmdline {
+            Some(cmdline) => write!(f, ": {}", truncate(cmdline, CMDLINE_WIDTH)),
+            None => write!(f, ": no kernel command line"),
+        }
+    }
+}
+
+/// List the stubs installed to the ESP with their kernel command lines, sorted by generation.
+pub fn list(esp_paths: &EspPaths) -> Result<Vec<ListedStub>> {
+    let mut stubs = esp::nixos_images(&esp_paths.linux)?
+        .into_iter()
+        .map(|stub| list_stub(&stub))
+        .collect::<Result<Vec<_>>>()?;
+    stubs.sort_by(|a, b| (a.version, &a.stub).cmp(&(b.version, &b.stub)));
+    Ok(stubs)
+}
+
+fn list_stub(stub: &Path) -> Result<ListedStub> {
+    Ok(ListedStub {
+        stub: stub.to_path_buf(),
+        version: esp::image_version(stub),
+        cmdline: pe::stub_cmdline(stub)?,
+    })
+}
+
+/// Shorten a string to at most `width` characters, marking the truncation with an ellipsis.
+fn truncate(s: &str, width: usize) -> String {
+    if s.chars().count() <= width {
+        return s.to_string();
+    }
+    let mut truncated: String = s.chars().take(width.saturating_sub(3)).collect();
+    truncated.push_str("...");
+    truncated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncate_long_cmdlines() {
+        assert_eq!(truncate("init=/init", 80), "init=/init");
+        assert_eq!(truncate("init=/init loglevel=4", 12), "init=/ini...");
+    }
+
+    #[test]
+    fn display_stub_without_cmdline() {
+        let stub = ListedStub {
+            stub: PathBuf::from("/boot/EFI/Linux/nixos-generation-1.efi"),
+            version: Some(1),
+            cmdline: None,
+        };
+        assert_eq!(
+            stub.to_string(),
+            "Generation 1 (nixos-generation-1.efi): no kernel command line"
+        );
+    }
+}
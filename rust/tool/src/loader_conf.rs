@@ -0,0 +1,331 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::utils;
+
+/// A setting of `loader.conf` that lanzaboote enforces on every installation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoaderSetting {
+    pub key: String,
+    pub value: String,
+}
+
+impl LoaderSetting {
+    pub fn new(key: &str, value: impl Into<String>) -> Self {
+        Self {
+            key: key.to_owned(),
+            value: value.into(),
+        }
+    }
+
+    /// Parse a setting given as KEY=VALUE.
+    pub fn parse(value: &str) -> Result<Self> {
+        let (key, value) = value
+            .split_once('=')
+            .with_context(|| format!("Loader setting {value:?} is not of the form KEY=VALUE"))?;
+
+        if key.is_empty() || key.contains(char::is_whitespace) || value.contains('\n') {
+            return Err(anyhow::anyhow!("Invalid loader setting {key:?}"));
+        }
+
+        Ok(Self {
+            key: key.to_owned(),
+            value: value.trim().to_owned(),
+        })
+    }
+}
+
+/// How long systemd-boot shows the boot menu.
+///
+/// In the configuration file, it is given as a number of seconds or as one of the special values
+/// that systemd-boot understands, just like on the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Timeout {
+    Seconds(u32),
+    Menu(MenuTimeout),
+}
+
+/// The special timeouts of systemd-boot that are not a number of seconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MenuTimeout {
+    /// Show the menu until an entry is chosen.
+    MenuForce,
+    /// Hide the menu, but show it when a key is pressed.
+    MenuHidden,
+    /// Never show the menu.
+    MenuDisabled,
+}
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Seconds(seconds) => write!(f, "{seconds}"),
+            Self::Menu(MenuTimeout::MenuForce) => write!(f, "menu-force"),
+            Self::Menu(MenuTimeout::MenuHidden) => write!(f, "menu-hidden"),
+            Self::Menu(MenuTimeout::MenuDisabled) => write!(f, "menu-disabled"),
+        }
+    }
+}
+
+/// Parse a timeout given as seconds or as one of the special values that systemd-boot
+/// understands.
+pub fn parse_timeout(value: &str) -> Result<Timeout> {
+    match value {
+        "menu-force" => Ok(Timeout::Menu(MenuTimeout::MenuForce)),
+        "menu-hidden" => Ok(Timeout::Menu(MenuTimeout::MenuHidden)),
+        "menu-disabled" => Ok(Timeout::Menu(MenuTimeout::MenuDisabled)),
+        _ => value.parse::<u32>().map(Timeout::Seconds).with_context(|| {
+            format!(
+                "Invalid timeout {value:?}, expected seconds, menu-force, menu-hidden or menu-disabled"
+            )
+        }),
+    }
+}
+
+/// The resolution of the console of systemd-boot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConsoleMode {
+    /// The standard 80x25 mode.
+    #[value(name = "0")]
+    #[serde(rename = "0")]
+    Standard,
+    /// 80x50, if the firmware supports it.
+    #[value(name = "1")]
+    #[serde(rename = "1")]
+    Extended,
+    /// The first non-standard mode of the firmware.
+    #[value(name = "2")]
+    #[serde(rename = "2")]
+    NonStandard,
+    /// Pick a suitable mode automatically.
+    Auto,
+    /// The highest resolution the firmware supports.
+    Max,
+    /// Keep the mode the firmware selected.
+    Keep,
+}
+
+impl ConsoleMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Standard => "0",
+            Self::Extended => "1",
+            Self::NonStandard => "2",
+            Self::Auto => "auto",
+            Self::Max => "max",
+            Self::Keep => "keep",
+        }
+    }
+}
+
+/// Drop the settings that a later setting of the same key overrides.
+pub fn dedup_settings(settings: Vec<LoaderSetting>) -> Vec<LoaderSetting> {
+    let mut deduplicated: Vec<LoaderSetting> = Vec::new();
+    for setting in settings {
+        deduplicated.retain(|s| s.key != setting.key);
+        deduplicated.push(setting);
+    }
+    deduplicated
+}
+
+/// The `loader.conf` of systemd-boot.
+///
+/// Only the settings lanzaboote manages are changed. All other lines, including comments, are
+/// kept as they are.
+#[derive(Debug, Default)]
+pub struct LoaderConf {
+    lines: Vec<String>,
+}
+
+impl LoaderConf {
+    /// Read a `loader.conf`. A missing file is treated like an empty one.
+    pub fn read(path: &Path) -> Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(contents) => Ok(Self {
+                lines: contents.lines().map(String::from).collect(),
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {path:?}")),
+        }
+    }
+
+    /// The value of the first occurrence of a key.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.lines
+            .iter()
+            .find(|l| line_key(l) == Some(key))
+            .map(|l| l.trim_start()[key.len()..].trim())
+    }
+
+    /// The default entry, if it names a concrete one.
+    ///
+    /// Patterns and special entries like `@saved` are only resolved by systemd-boot at boot time.
+    pub fn default_entry(&self) -> Option<&str> {
+        self.get("default").filter(|entry| {
+            !entry.starts_with('@') && !entry.contains(|c| matches!(c, '*' | '?' | '['))
+        })
+    }
+
+    /// Set a key, replacing its first occurrence and dropping all others.
+    pub fn set(&mut self, key: &str, value: &str) {
+        let mut found = false;
+        self.lines.retain(|l| {
+            let duplicate = found && line_key(l) == Some(key);
+            found |= line_key(l) == Some(key);
+            !duplicate
+        });
+
+        let line = format!("{key} {value}");
+        match self.lines.iter_mut().find(|l| line_key(l) == Some(key)) {
+            Some(existing) => *existing = line,
+            None => self.lines.push(line),
+        }
+    }
+
+    /// Enforce settings, returning a description of every setting that had to be changed.
+    pub fn enforce(&mut self, settings: &[LoaderSetting]) -> Vec<String> {
+        let mut changes = Vec::new();
+        for setting in settings {
+            let current = self.get(&setting.key).map(String::from);
+            if current.as_deref() != Some(setting.value.as_str()) {
+                changes.push(match current {
+                    Some(current) => {
+                        format!(
+                            "{} changed from {current:?} to {:?}",
+                            setting.key, setting.value
+                        )
+                    }
+                    None => format!("{} set to {:?}", setting.key, setting.value),
+                });
+                self.set(&setting.key, &setting.value);
+            }
+        }
+        changes
+    }
+
+    /// Atomically replace `path` with this `loader.conf`.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        utils::atomic_write(path, self.to_string(), utils::PUBLIC_FILE_MODE)
+    }
+}
+
+impl fmt::Display for LoaderConf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for line in &self.lines {
+            writeln!(f, "{line}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The key of a line of `loader.conf`, or `None` for empty lines and comments.
+fn line_key(line: &str) -> Option<&str> {
+    let line = line.trim_start();
+    if line.starts_with('#') {
+        return None;
+    }
+    line.split_whitespace().next()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_default_entry() {
+        let mut loader_conf = LoaderConf::default();
+        loader_conf.set("default", "nixos-generation-2.efi");
+        assert_eq!(loader_conf.to_string(), "default nixos-generation-2.efi\n");
+    }
+
+    #[test]
+    fn replace_default_entry_and_keep_other_lines() {
+        let mut loader_conf = LoaderConf {
+            lines: [
+                "# Managed by NixOS",
+                "timeout 3",
+                "default nixos-generation-1.efi",
+                "editor no",
+                "default @saved",
+            ]
+            .map(String::from)
+            .to_vec(),
+        };
+        loader_conf.set("default", "nixos-generation-2.efi");
+        assert_eq!(
+            loader_conf.to_string(),
+            "# Managed by NixOS\ntimeout 3\ndefault nixos-generation-2.efi\neditor no\n"
+        );
+    }
+
+    #[test]
+    fn enforce_only_drifted_settings() -> Result<()> {
+        let mut loader_conf = LoaderConf {
+            lines: ["timeout 3", "editor yes"].map(String::from).to_vec(),
+        };
+        let settings = [
+            LoaderSetting::parse("timeout=3")?,
+            LoaderSetting::parse("editor=no")?,
+            LoaderSetting::parse("console-mode=max")?,
+        ];
+
+        let changes = loader_conf.enforce(&settings);
+
+        assert_eq!(
+            changes,
+            [
+                "editor changed from \"yes\" to \"no\"",
+                "console-mode set to \"max\""
+            ]
+        );
+        assert_eq!(
+            loader_conf.to_string(),
+            "timeout 3\neditor no\nconsole-mode max\n"
+        );
+        assert!(loader_conf.enforce(&settings).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_timeouts() -> Result<()> {
+        assert_eq!(parse_timeout("5")?, Timeout::Seconds(5));
+        assert_eq!(parse_timeout("menu-force")?.to_string(), "menu-force");
+        for value in ["0", "5", "menu-force", "menu-hidden", "menu-disabled"] {
+            assert_eq!(parse_timeout(value)?.to_string(), value);
+        }
+        assert!(parse_timeout("-1").is_err());
+        assert!(parse_timeout("forever").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn later_settings_override_earlier_ones() {
+        let settings = dedup_settings(vec![
+            LoaderSetting::new("timeout", "3"),
+            LoaderSetting::new("editor", "no"),
+            LoaderSetting::new("timeout", "0"),
+        ]);
+        assert_eq!(
+            settings,
+            [
+                LoaderSetting::new("editor", "no"),
+                LoaderSetting::new("timeout", "0")
+            ]
+        );
+    }
+
+    #[test]
+    fn reject_malformed_loader_settings() {
+        assert!(LoaderSetting::parse("editor").is_err());
+        assert!(LoaderSetting::parse("=no").is_err());
+        assert!(LoaderSetting::parse("console mode=max").is_err());
+    }
+}
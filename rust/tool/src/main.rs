@@ -1,18 +1,7 @@
-mod cli;
-mod esp;
-mod gc;
-mod generation;
-mod install;
-mod os_release;
-mod pe;
-mod signature;
-mod utils;
-
 use anyhow::Result;
-use clap::Parser;
 
-use cli::Cli;
+use lanzaboote_tool::cli::Cli;
 
 fn main() -> Result<()> {
-    Cli::parse().call()
+    Cli::parse_arguments().call()
 }
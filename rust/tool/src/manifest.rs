@@ -0,0 +1,516 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::esp::EspPaths;
+use crate::pe;
+use crate::utils;
+
+/// Record of the files that lanzaboote has completely written to the ESP (and the XBOOTLDR
+/// partition, if one is used).
+///
+/// A file that exists on the ESP, but is not recorded in the manifest, may be the result of an
+/// interrupted installation. Such files are rewritten on the next installation.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    /// The ESP the paths in `files` are relative to.
+    #[serde(skip)]
+    esp: PathBuf,
+    /// The partition the paths in `boot_files` and `specialisations` are relative to.
+    #[serde(skip)]
+    boot: PathBuf,
+    /// SHA-256 hashes of the installed files keyed by their path relative to the ESP.
+    files: BTreeMap<PathBuf, String>,
+    /// SHA-256 hashes of the files installed to a separate XBOOTLDR partition keyed by their path
+    /// relative to this partition.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    boot_files: BTreeMap<PathBuf, String>,
+    /// Images of specialisations mapped to the image of the generation they belong to.
+    #[serde(default)]
+    specialisations: BTreeMap<PathBuf, PathBuf>,
+    /// What the installed generations were assembled from, keyed by their version.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    generations: BTreeMap<u64, GenerationRecord>,
+    /// Number of incremental garbage collections since the last full sweep of the ESP.
+    #[serde(default)]
+    incremental_collections: u32,
+    /// SHA-256 hash of the certificate of the key that the installed files are signed with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    certificate: Option<String>,
+    /// Whether a detached signature was written next to every initrd.
+    #[serde(default)]
+    signed_initrds: bool,
+}
+
+/// What a generation was assembled from, used to detect whether it changed since it was installed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GenerationRecord {
+    pub toplevel: PathBuf,
+    pub cmdline: Vec<String>,
+    /// SHA-256 hash of the lanzaboote stub the images were assembled from.
+    pub stub: String,
+    /// SHA-256 hash of everything else the images were assembled with: the options of the
+    /// installation, the files they name (e.g. the devicetree) and the signing certificate.
+    #[serde(default)]
+    pub options: String,
+}
+
+/// How the generations to install differ from the ones recorded by the previous installation.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct GenerationDiff {
+    /// Generations that were not installed before or whose record changed.
+    pub added: BTreeSet<u64>,
+    /// Generations that were installed before, but are not installed anymore.
+    pub removed: BTreeSet<u64>,
+    /// Generations that are installed exactly as before.
+    pub unchanged: BTreeSet<u64>,
+}
+
+/// Compare the generations to install to the ones recorded in the previous manifest.
+///
+/// A generation is unchanged if it has the same toplevel, kernel command line, stub and options as
+/// before.
+pub fn diff_generations(
+    previous: &Manifest,
+    current: &BTreeMap<u64, GenerationRecord>,
+) -> GenerationDiff {
+    let mut diff = GenerationDiff::default();
+    for (version, record) in current {
+        if previous.generations.get(version) == Some(record) {
+            diff.unchanged.insert(*version);
+        } else {
+            diff.added.insert(*version);
+        }
+    }
+    diff.removed = previous
+        .generations
+        .keys()
+        .filter(|version| !current.contains_key(version))
+        .copied()
+        .collect();
+    diff
+}
+
+/// The partition an installed file is stored on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Partition {
+    Esp,
+    Boot,
+}
+
+impl Manifest {
+    /// Read the manifest from the ESP.
+    ///
+    /// A missing or corrupt manifest (e.g. because writing it was interrupted) is not an error.
+    /// Instead, an empty manifest is returned, which causes all files to be rewritten.
+    pub fn read(esp_paths: &EspPaths) -> Self {
+        let path = &esp_paths.manifest;
+        let result = fs::read(path)
+            .context("Failed to read manifest")
+            .and_then(|data| serde_json::from_slice(&data).context("Failed to parse manifest"));
+
+        let manifest = match result {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                println!(
+                    "Warning: ignoring manifest {}, all files will be rewritten: {:?}",
+                    path.display(),
+                    e
+                );
+                Self::default()
+            }
+        };
+
+        Self {
+            esp: esp_paths.esp.clone(),
+            boot: esp_paths.boot.clone(),
+            ..manifest
+        }
+    }
+
+    /// Create an empty manifest for the ESP.
+    pub fn new(esp_paths: &EspPaths) -> Self {
+        Self {
+            esp: esp_paths.esp.clone(),
+            boot: esp_paths.boot.clone(),
+            ..Self::default()
+        }
+    }
+
+    /// Atomically write the manifest to the ESP.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let data = serde_json::to_vec_pretty(self).context("Failed to serialize manifest")?;
+        utils::atomic_write(path, data, utils::PRIVATE_FILE_MODE)
+            .with_context(|| format!("Failed to write manifest to {}", path.display()))
+    }
+
+    /// Whether a file was completely written to the ESP.
+    pub fn contains(&self, path: &Path) -> bool {
+        self.relative_path(path).map_or(false, |(partition, path)| {
+            self.files(partition).contains_key(path)
+        })
+    }
+
+    /// Whether `file` still has the hash that was recorded for `path`.
+    ///
+    /// `file` differs from `path` if the recorded file was renamed since, e.g. a stub by
+    /// systemd-boot to count the boot attempts.
+    pub fn is_unchanged(&self, path: &Path, file: &Path) -> Result<bool> {
+        let recorded = match self.relative_path(path) {
+            Ok((partition, path)) => self.files(partition).get(path),
+            Err(_) => None,
+        };
+        match recorded {
+            Some(hash) => {
+                let current =
+                    pe::file_hash(file).with_context(|| format!("Failed to hash {:?}", file))?;
+                Ok(*hash == format!("{:x}", current))
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Record a file that was completely written to the ESP.
+    pub fn record(&mut self, path: &Path) -> Result<()> {
+        let hash = pe::file_hash(path).with_context(|| format!("Failed to hash {:?}", path))?;
+        let (partition, relative_path) = self.relative_path(path)?;
+        let relative_path = relative_path.to_path_buf();
+        self.files_mut(partition)
+            .insert(relative_path, format!("{:x}", hash));
+        Ok(())
+    }
+
+    /// Find another recorded file with the given SHA-256 hash on the partition of `path`.
+    ///
+    /// Files on other partitions are not considered, because they cannot be hard linked.
+    pub fn find_identical(&self, path: &Path, hash: &str) -> Option<PathBuf> {
+        let (partition, relative_path) = self.relative_path(path).ok()?;
+        let root = match partition {
+            Partition::Esp => &self.esp,
+            Partition::Boot => &self.boot,
+        };
+        self.files(partition)
+            .iter()
+            .filter(|(other, other_hash)| *other != relative_path && *other_hash == hash)
+            .map(|(other, _)| root.join(other))
+            .find(|other| other.exists())
+    }
+
+    /// Record that an image belongs to a specialisation of the generation with the parent image.
+    pub fn record_specialisation(&mut self, image: &Path, parent_image: &Path) -> Result<()> {
+        let relative_image = self.boot_relative_path(image)?.to_path_buf();
+        let relative_parent_image = self.boot_relative_path(parent_image)?.to_path_buf();
+        self.specialisations
+            .insert(relative_image, relative_parent_image);
+        Ok(())
+    }
+
+    /// The absolute paths of all recorded files.
+    pub fn paths(&self) -> Vec<PathBuf> {
+        let esp = &self.esp;
+        let boot = &self.boot;
+        self.files
+            .keys()
+            .map(|path| esp.join(path))
+            .chain(self.boot_files.keys().map(|path| boot.join(path)))
+            .collect()
+    }
+
+    /// Whether the next garbage collection has to sweep the whole ESP, which happens after every
+    /// `interval - 1` incremental ones.
+    pub fn needs_full_sweep(&self, interval: u32) -> bool {
+        self.incremental_collections.saturating_add(1) >= interval
+    }
+
+    /// Count a garbage collection towards the next full sweep.
+    pub fn record_garbage_collection(&mut self, full_sweep: bool) {
+        self.incremental_collections = if full_sweep {
+            0
+        } else {
+            self.incremental_collections.saturating_add(1)
+        };
+    }
+
+    /// Replace the records of the installed generations.
+    pub fn set_generations(&mut self, generations: BTreeMap<u64, GenerationRecord>) {
+        self.generations = generations;
+    }
+
+    /// Record the certificate of the key that the files are signed with.
+    ///
+    /// If the recorded files were signed with another key (e.g. because it was rotated), they are
+    /// forgotten, so that the installation signs them again. Returns whether this is the case.
+    pub fn set_certificate(&mut self, certificate: String) -> bool {
+        let changed = self
+            .certificate
+            .as_ref()
+            .map_or(false, |previous| *previous != certificate);
+        if changed {
+            self.files.clear();
+            self.boot_files.clear();
+        }
+        self.certificate = Some(certificate);
+        changed
+    }
+
+    /// Record whether a detached signature is written next to every initrd.
+    ///
+    /// Once this is the case, an initrd without a detached signature fails the verification.
+    pub fn set_signed_initrds(&mut self, signed_initrds: bool) {
+        self.signed_initrds = signed_initrds;
+    }
+
+    /// Whether a detached signature was written next to every initrd.
+    pub fn signed_initrds(&self) -> bool {
+        self.signed_initrds
+    }
+
+    /// Forget all files whose contents do not match the recorded hash anymore.
+    ///
+    /// This detects files that were corrupted or modified outside of lanzaboote. Because they are
+    /// not recorded in the manifest anymore, they are rewritten by the installation.
+    pub fn forget_modified(&mut self) {
+        let esp = &self.esp;
+        let boot = &self.boot;
+        let unmodified = |root: &Path, path: &Path, hash: &str| {
+            let path = root.join(path);
+            match pe::file_hash(&path) {
+                Ok(actual_hash) if format!("{:x}", actual_hash) == hash => true,
+                Ok(_) => {
+                    println!(
+                        "Warning: {} was modified since it was installed, rewriting...",
+                        path.display()
+                    );
+                    false
+                }
+                // The file will be rewritten anyway.
+                Err(_) => false,
+            }
+        };
+        self.files.retain(|path, hash| unmodified(esp, path, hash));
+        self.boot_files
+            .retain(|path, hash| unmodified(boot, path, hash));
+    }
+
+    /// Forget all files that do not exist on the ESP anymore (e.g. because they were garbage
+    /// collected).
+    pub fn retain_existing(&mut self) {
+        let esp = &self.esp;
+        let boot = &self.boot;
+        self.files.retain(|path, _| esp.join(path).exists());
+        self.boot_files.retain(|path, _| boot.join(path).exists());
+        self.specialisations
+            .retain(|image, _| boot.join(image).exists());
+    }
+
+    fn files(&self, partition: Partition) -> &BTreeMap<PathBuf, String> {
+        match partition {
+            Partition::Esp => &self.files,
+            Partition::Boot => &self.boot_files,
+        }
+    }
+
+    fn files_mut(&mut self, partition: Partition) -> &mut BTreeMap<PathBuf, String> {
+        match partition {
+            Partition::Esp => &mut self.files,
+            Partition::Boot => &mut self.boot_files,
+        }
+    }
+
+    /// Find the partition of a file and its path relative to this partition.
+    ///
+    /// Without a separate XBOOTLDR partition, all files are on the ESP.
+    fn relative_path<'a>(&self, path: &'a Path) -> Result<(Partition, &'a Path)> {
+        if let Ok(relative_path) = path.strip_prefix(&self.esp) {
+            return Ok((Partition::Esp, relative_path));
+        }
+        self.boot_relative_path(path)
+            .map(|relative_path| (Partition::Boot, relative_path))
+    }
+
+    fn boot_relative_path<'a>(&self, path: &'a Path) -> Result<&'a Path> {
+        path.strip_prefix(&self.boot)
+            .with_context(|| format!("{:?} is not on the boot partition {:?}", path, self.boot))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(toplevel: &str, cmdline: &str) -> GenerationRecord {
+        GenerationRecord {
+            toplevel: PathBuf::from(toplevel),
+            cmdline: vec![String::from(cmdline)],
+            stub: String::from("0123"),
+            options: String::from("4567"),
+        }
+    }
+
+    #[test]
+    fn diff_generations_against_previous_manifest() {
+        let mut previous = Manifest::default();
+        previous.set_generations(BTreeMap::from([
+            (1, record("/nix/store/a-system", "init=/a")),
+            (2, record("/nix/store/b-system", "init=/b")),
+            (3, record("/nix/store/c-system", "init=/c")),
+            (5, record("/nix/store/e-system", "init=/e")),
+        ]));
+        let current = BTreeMap::from([
+            (2, record("/nix/store/b-system", "init=/b")),
+            // The kernel parameters of generation 3 changed since the previous installation.
+            (3, record("/nix/store/c-system", "init=/c quiet")),
+            (4, record("/nix/store/d-system", "init=/d")),
+            // Generation 5 is installed with another devicetree.
+            (
+                5,
+                GenerationRecord {
+                    options: String::from("89ab"),
+                    ..record("/nix/store/e-system", "init=/e")
+                },
+            ),
+        ]);
+
+        assert_eq!(
+            diff_generations(&previous, &current),
+            GenerationDiff {
+                added: BTreeSet::from([3, 4, 5]),
+                removed: BTreeSet::from([1]),
+                unchanged: BTreeSet::from([2]),
+            }
+        );
+    }
+
+    #[test]
+    fn treat_missing_manifest_as_empty() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let esp_paths = EspPaths::new(tmpdir.path(), None);
+
+        let manifest = Manifest::read(&esp_paths);
+
+        assert!(manifest.files.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn treat_truncated_manifest_as_empty() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let esp_paths = EspPaths::new(tmpdir.path(), None);
+        fs::create_dir_all(&esp_paths.nixos)?;
+        fs::write(
+            &esp_paths.manifest,
+            br#"{"files":{"EFI/nixos/kernel-bzImage.efi":"ab"#,
+        )?;
+
+        let manifest = Manifest::read(&esp_paths);
+
+        assert!(manifest.files.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn roundtrip_manifest() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let esp = tmpdir.path();
+        let esp_paths = EspPaths::new(esp, None);
+        let installed_file = esp.join("installed-file");
+        fs::write(&installed_file, b"installed")?;
+
+        let mut manifest = Manifest::read(&esp_paths);
+        manifest.record(&installed_file)?;
+        manifest.write(&esp_paths.manifest)?;
+
+        let manifest = Manifest::read(&esp_paths);
+        assert!(manifest.files.contains_key(Path::new("installed-file")));
+        assert!(manifest.contains(&installed_file));
+        assert!(!manifest.contains(&esp.join("interrupted-file")));
+        Ok(())
+    }
+
+    #[test]
+    fn forget_modified_files() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let esp = tmpdir.path();
+        let unmodified_file = esp.join("unmodified-file");
+        let modified_file = esp.join("modified-file");
+        fs::write(&unmodified_file, b"installed")?;
+        fs::write(&modified_file, b"installed")?;
+
+        let mut manifest = Manifest::read(&EspPaths::new(esp, None));
+        manifest.record(&unmodified_file)?;
+        manifest.record(&modified_file)?;
+        fs::write(&modified_file, b"corrupted")?;
+        manifest.forget_modified();
+
+        assert!(manifest.contains(&unmodified_file));
+        assert!(!manifest.contains(&modified_file));
+        Ok(())
+    }
+
+    #[test]
+    fn forget_files_signed_with_another_certificate() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let esp = tmpdir.path();
+        let installed_file = esp.join("installed-file");
+        fs::write(&installed_file, b"installed")?;
+
+        let mut manifest = Manifest::read(&EspPaths::new(esp, None));
+        manifest.record(&installed_file)?;
+        // The certificate of a manifest without one is unknown.
+        assert!(!manifest.set_certificate(String::from("0123")));
+        assert!(!manifest.set_certificate(String::from("0123")));
+        assert!(manifest.contains(&installed_file));
+
+        assert!(manifest.set_certificate(String::from("4567")));
+        assert!(!manifest.contains(&installed_file));
+        Ok(())
+    }
+
+    #[test]
+    fn record_files_relative_to_their_partition() -> Result<()> {
+        let esp = tempfile::tempdir()?;
+        let xbootldr = tempfile::tempdir()?;
+        let esp_paths = EspPaths::new(esp.path(), Some(xbootldr.path()));
+        let esp_file = esp.path().join("esp-file");
+        let boot_file = xbootldr.path().join("boot-file");
+        fs::write(&esp_file, b"esp")?;
+        fs::write(&boot_file, b"boot")?;
+
+        let mut manifest = Manifest::read(&esp_paths);
+        manifest.record(&esp_file)?;
+        manifest.record(&boot_file)?;
+
+        assert!(manifest.files.contains_key(Path::new("esp-file")));
+        assert!(manifest.boot_files.contains_key(Path::new("boot-file")));
+        assert!(manifest.contains(&boot_file));
+        Ok(())
+    }
+
+    #[test]
+    fn forget_garbage_collected_specialisations() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let esp = tmpdir.path();
+        let parent_image = esp.join("nixos-generation-1.efi");
+        let kept_image = esp.join("nixos-generation-1-specialisation-kept.efi");
+        let collected_image = esp.join("nixos-generation-1-specialisation-collected.efi");
+        fs::write(&parent_image, b"parent")?;
+        fs::write(&kept_image, b"kept")?;
+
+        let mut manifest = Manifest::read(&EspPaths::new(esp, None));
+        manifest.record_specialisation(&kept_image, &parent_image)?;
+        manifest.record_specialisation(&collected_image, &parent_image)?;
+        manifest.retain_existing();
+
+        assert_eq!(
+            manifest.specialisations,
+            BTreeMap::from([(
+                PathBuf::from("nixos-generation-1-specialisation-kept.efi"),
+                PathBuf::from("nixos-generation-1.efi")
+            )])
+        );
+        Ok(())
+    }
+}
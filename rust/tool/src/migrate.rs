@@ -0,0 +1,344 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::esp::{self, EspPaths};
+use crate::manifest::Manifest;
+use crate::pe;
+
+/// The outcome of migrating an ESP.
+#[derive(Debug, Default)]
+pub struct MigrationReport {
+    /// Whether the ESP already had a manifest, so that nothing had to be done.
+    pub already_migrated: bool,
+    /// Files that were recorded in the reconstructed manifest.
+    pub recorded: Vec<PathBuf>,
+    /// Stubs that could not be verified. They are rewritten by the next installation.
+    pub unverified: Vec<PathBuf>,
+}
+
+/// Migrate an ESP that was installed by a version of lanzaboote without a manifest.
+///
+/// The manifest is reconstructed from the installed stubs: a stub is recorded together with its
+/// kernel and initrd if their contents still match the hashes embedded into the stub. The file
+/// names have not changed, so no files need to be renamed. Migrating an ESP that already has a
+/// manifest does nothing.
+pub fn migrate(esp_paths: &EspPaths) -> Result<MigrationReport> {
+    if esp_paths.manifest.exists() {
+        return Ok(MigrationReport {
+            already_migrated: true,
+            ..MigrationReport::default()
+        });
+    }
+
+    let mut report = MigrationReport::default();
+    let mut manifest = Manifest::new(esp_paths);
+
+    for stub in esp::nixos_images(&esp_paths.linux)? {
+        let references = match pe::stub_references(&stub, &esp_paths.boot) {
+            Ok(references) => references,
+            Err(e) => {
+                println!("Warning: not migrating {}: {e:#}", stub.display());
+                report.unverified.push(stub);
+                continue;
+            }
+        };
+
+        let mut verified = true;
+        for reference in &references {
+            verified &= reference.path.exists() && reference.matches()?;
+        }
+        if !verified {
+            report.unverified.push(stub);
+            continue;
+        }
+
+        for path in references
+            .into_iter()
+            .map(|reference| reference.path)
+            .chain([stub.clone()])
+        {
+            if !manifest.contains(&path) {
+                manifest.record(&path)?;
+                report.recorded.push(path);
+            }
+        }
+
+        if let Some(parent) = specialisation_parent(&stub) {
+            if parent.exists() {
+                manifest.record_specialisation(&stub, &parent)?;
+            }
+        }
+    }
+
+    manifest.write(&esp_paths.manifest)?;
+    Ok(report)
+}
+
+/// The boot loader entries and files that the systemd-boot module of NixOS installed.
+#[derive(Debug, Default)]
+pub struct SystemdBootInventory {
+    /// The Type #1 boot loader entries of NixOS by the version of the generation they boot.
+    pub entries: Vec<(PathBuf, u64)>,
+    /// The kernels and initrds that the entries reference and that lanzaboote does not manage.
+    pub files: Vec<PathBuf>,
+}
+
+impl SystemdBootInventory {
+    /// Whether systemd-boot left nothing on the ESP.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty() && self.files.is_empty()
+    }
+
+    /// The versions of the generations that the entries boot.
+    pub fn versions(&self) -> BTreeSet<u64> {
+        self.entries.iter().map(|(_, version)| *version).collect()
+    }
+
+    fn paths(&self) -> impl Iterator<Item = &PathBuf> {
+        self.entries.iter().map(|(path, _)| path).chain(&self.files)
+    }
+}
+
+/// The outcome of adopting an ESP that systemd-boot was installed to.
+#[derive(Debug, Default)]
+pub struct AdoptionReport {
+    /// Files of systemd-boot that lanzaboote installed itself, e.g. kernels of the same name.
+    pub adopted: Vec<PathBuf>,
+    /// Files of systemd-boot that the installation superseded and that were removed.
+    pub removed: Vec<PathBuf>,
+}
+
+/// Find the boot loader entries and files that systemd-boot left on an ESP.
+///
+/// The systemd-boot module of NixOS names its entries `nixos-generation-N.conf` (or
+/// `nixos-generation-N-specialisation-NAME.conf`) and installs the kernels and initrds they
+/// reference to `EFI/nixos`, like lanzaboote does. Only the files that the `linux` and `initrd`
+/// lines of these entries reference are part of the inventory, so that the files of other entries
+/// and operating systems are left alone. Files that lanzaboote recorded in its manifest are not
+/// part of the inventory either.
+pub fn systemd_boot_inventory(esp_paths: &EspPaths) -> Result<SystemdBootInventory> {
+    // An ESP that was never installed to by lanzaboote has no manifest yet.
+    let manifest = if esp_paths.manifest.exists() {
+        Manifest::read(esp_paths)
+    } else {
+        Manifest::new(esp_paths)
+    };
+
+    let mut inventory = SystemdBootInventory::default();
+    if !esp_paths.entries.is_dir() {
+        return Ok(inventory);
+    }
+    let dir = &esp_paths.entries;
+    for entry in fs::read_dir(dir).with_context(|| format!("Failed to read {dir:?}"))? {
+        let path = entry?.path();
+        if !path.is_file()
+            || path.extension().map_or(true, |e| e != "conf")
+            || manifest.contains(&path)
+        {
+            continue;
+        }
+        if let Some(version) = esp::image_version(&path) {
+            inventory.entries.push((path, version));
+        }
+    }
+    inventory.entries.sort();
+
+    let mut files = BTreeSet::new();
+    for (path, _) in &inventory.entries {
+        for file in referenced_files(&esp_paths.boot, path)? {
+            if file.is_file() && !manifest.contains(&file) {
+                files.insert(file);
+            }
+        }
+    }
+    inventory.files = files.into_iter().collect();
+    Ok(inventory)
+}
+
+/// The files that the `linux` and `initrd` lines of a Type #1 boot loader entry reference.
+///
+/// The paths are relative to the root of the partition the entry is stored on. Paths that leave
+/// the partition are ignored.
+fn referenced_files(boot: &Path, entry: &Path) -> Result<Vec<PathBuf>> {
+    let contents =
+        fs::read_to_string(entry).with_context(|| format!("Failed to read {entry:?}"))?;
+    Ok(contents
+        .lines()
+        .filter_map(|line| {
+            let (key, value) = line.trim().split_once(char::is_whitespace)?;
+            matches!(key, "linux" | "initrd").then(|| Path::new(value.trim()))
+        })
+        .filter(|path| {
+            path.components()
+                .all(|component| !matches!(component, Component::ParentDir))
+        })
+        .map(|path| boot.join(path.strip_prefix("/").unwrap_or(path)))
+        .collect())
+}
+
+/// Map the versions of the boot loader entries to the generation links of a system profile.
+///
+/// Returns the links and the versions whose generations are not in the profile anymore.
+pub fn generation_links(
+    inventory: &SystemdBootInventory,
+    profile: &Path,
+) -> Result<(Vec<PathBuf>, Vec<u64>)> {
+    let profile_name = profile
+        .file_name()
+        .and_then(|name| name.to_str())
+        .with_context(|| format!("Failed to extract name of profile {profile:?}"))?;
+
+    let (links, missing): (Vec<_>, Vec<_>) = inventory
+        .versions()
+        .into_iter()
+        .map(|version| {
+            let link = profile.with_file_name(format!("{profile_name}-{version}-link"));
+            (version, link)
+        })
+        .partition(|(_, link)| link.exists());
+    Ok((
+        links.into_iter().map(|(_, link)| link).collect(),
+        missing.into_iter().map(|(version, _)| version).collect(),
+    ))
+}
+
+/// Copy the files of systemd-boot to `quarantine` before the installation removes them.
+///
+/// The files keep their paths relative to the partition they are stored on, so that they can be
+/// copied back if needed.
+pub fn quarantine(
+    esp_paths: &EspPaths,
+    inventory: &SystemdBootInventory,
+    quarantine: &Path,
+) -> Result<Vec<PathBuf>> {
+    let mut quarantined = Vec::new();
+    for path in inventory.paths() {
+        let relative_path = path
+            .strip_prefix(&esp_paths.boot)
+            .with_context(|| format!("{path:?} is not on the boot partition"))?;
+        let destination = quarantine.join(relative_path);
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory {parent:?}"))?;
+        }
+        fs::copy(path, &destination)
+            .with_context(|| format!("Failed to copy {path:?} to {destination:?}"))?;
+        quarantined.push(destination);
+    }
+    Ok(quarantined)
+}
+
+/// Remove the files of systemd-boot that an installation superseded.
+///
+/// Every file that the installation recorded in the manifest is adopted and kept. All other files
+/// of the inventory are removed, unless the garbage collection of the installation removed them
+/// already.
+pub fn remove_superseded(
+    esp_paths: &EspPaths,
+    inventory: &SystemdBootInventory,
+) -> Result<AdoptionReport> {
+    let manifest = Manifest::read(esp_paths);
+    let mut report = AdoptionReport::default();
+    for path in inventory.paths() {
+        if manifest.contains(path) {
+            report.adopted.push(path.clone());
+            continue;
+        }
+        if path.exists() {
+            fs::remove_file(path).with_context(|| format!("Failed to remove {path:?}"))?;
+        }
+        report.removed.push(path.clone());
+    }
+    Ok(report)
+}
+
+/// The image of the generation that the image of a specialisation belongs to.
+fn specialisation_parent(image: &Path) -> Option<PathBuf> {
+    let name = image.file_name()?.to_str()?;
+    let (generation, _) = name.split_once("-specialisation-")?;
+    Some(image.with_file_name(format!("{generation}.efi")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_parent_of_specialisation() {
+        assert_eq!(
+            specialisation_parent(Path::new(
+                "EFI/Linux/nixos-generation-3-specialisation-work.efi"
+            )),
+            Some(PathBuf::from("EFI/Linux/nixos-generation-3.efi"))
+        );
+        assert_eq!(
+            specialisation_parent(Path::new("EFI/Linux/nixos-generation-3.efi")),
+            None
+        );
+    }
+
+    #[test]
+    fn do_nothing_if_already_migrated() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let esp_paths = EspPaths::new(tmpdir.path(), None);
+        Manifest::new(&esp_paths).write(&esp_paths.manifest)?;
+
+        let report = migrate(&esp_paths)?;
+        assert!(report.already_migrated);
+        assert!(report.recorded.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn inventory_systemd_boot_files() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let esp_paths = EspPaths::new(tmpdir.path(), None);
+        fs::create_dir_all(&esp_paths.entries)?;
+        fs::create_dir_all(&esp_paths.nixos)?;
+        for (name, contents) in [
+            (
+                "nixos-generation-3.conf",
+                "title NixOS\nlinux /EFI/nixos/kernel-bzImage.efi\ninitrd /EFI/nixos/initrd.efi\n",
+            ),
+            (
+                "nixos-generation-3-specialisation-work.conf",
+                "linux /EFI/nixos/kernel-bzImage.efi\ninitrd /EFI/nixos/../other/initrd.efi\n",
+            ),
+            (
+                "nixos-generation-4.conf",
+                "linux /EFI/nixos/managed-bzImage.efi\n",
+            ),
+            ("other-os.conf", "linux /EFI/nixos/other-bzImage.efi\n"),
+        ] {
+            fs::write(esp_paths.entries.join(name), contents)?;
+        }
+        for name in [
+            "kernel-bzImage.efi",
+            "initrd.efi",
+            "managed-bzImage.efi",
+            "other-bzImage.efi",
+            "unreferenced-bzImage.efi",
+        ] {
+            fs::write(esp_paths.nixos.join(name), name)?;
+        }
+        let mut manifest = Manifest::new(&esp_paths);
+        manifest.record(&esp_paths.nixos.join("managed-bzImage.efi"))?;
+        manifest.write(&esp_paths.manifest)?;
+
+        let inventory = systemd_boot_inventory(&esp_paths)?;
+        assert_eq!(inventory.versions(), BTreeSet::from([3, 4]));
+        assert_eq!(inventory.entries.len(), 3);
+        // Neither files of other entries nor files no entry references are removed.
+        assert_eq!(
+            inventory.files,
+            vec![
+                esp_paths.nixos.join("initrd.efi"),
+                esp_paths.nixos.join("kernel-bzImage.efi"),
+            ]
+        );
+        Ok(())
+    }
+}
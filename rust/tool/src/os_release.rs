@@ -1,7 +1,8 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt;
+use std::time::SystemTime;
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 
 use crate::generation::Generation;
 
@@ -16,7 +17,11 @@ use crate::generation::Generation;
 pub struct OsRelease(BTreeMap<&'static str, String>);
 
 impl OsRelease {
-    pub fn from_generation(generation: &Generation) -> Result<Self> {
+    pub fn from_generation(
+        generation: &Generation,
+        kernel_version_from_image: bool,
+        build_epoch: Option<SystemTime>,
+    ) -> Result<Self> {
         let mut map = BTreeMap::new();
 
         // Because of a null pointer dereference, `bootctl` segfaults when no ID field is present
@@ -26,16 +31,73 @@ impl OsRelease {
         // Because the ID field here does not have the same meaning as in a real os-release file,
         // it is fine to use a dummy value.
         map.insert("ID", String::from("lanza"));
-        map.insert("PRETTY_NAME", generation.spec.bootspec.label.clone());
+
+        // The boot menu shows the PRETTY_NAME, so it has to tell the generations apart.
+        let specialisation = generation
+            .is_specialised()
+            .map(|name| format!(", Specialisation {name}"))
+            .unwrap_or_default();
+        map.insert(
+            "PRETTY_NAME",
+            format!(
+                "{} (Generation {}, NixOS {}, Built on {}{})",
+                generation.spec.bootspec.label,
+                generation.version(),
+                generation.nixos_version(),
+                generation.build_date(build_epoch),
+                specialisation
+            ),
+        );
         map.insert(
-            "VERSION_ID",
+            "VERSION",
             generation
-                .describe()
+                .describe(kernel_version_from_image, build_epoch)
                 .context("Failed to describe generation.")?,
         );
 
+        // systemd-boot sorts the entries of the same ID by their IMAGE_VERSION (falling back to
+        // VERSION and VERSION_ID) from the newest to the oldest. Unlike the description in
+        // VERSION, the generation number sorts reliably. The specialisations of a generation have
+        // the same version and follow its main entry because their file names sort after it.
+        let sort_key = generation.version().to_string();
+        map.insert("VERSION_ID", sort_key.clone());
+        map.insert("IMAGE_VERSION", sort_key);
+
         Ok(Self(map))
     }
+
+    /// Mark the os-release as belonging to the pinned recovery generation.
+    pub fn set_recovery(&mut self) {
+        if let Some(pretty_name) = self.0.get_mut("PRETTY_NAME") {
+            pretty_name.push_str(" (Recovery)");
+        }
+    }
+
+    /// Mark the os-release as belonging to the safe mode entry of the generation.
+    ///
+    /// systemd-boot sorts unified kernel images by their `IMAGE_ID` (falling back to `ID`) first,
+    /// so an `IMAGE_ID` that sorts after the `ID` of the other entries moves this one to the end
+    /// of the boot menu.
+    pub fn set_safe_mode(&mut self) {
+        if let Some(pretty_name) = self.0.get_mut("PRETTY_NAME") {
+            pretty_name.push_str(" (Safe Mode)");
+        }
+        self.0.insert("IMAGE_ID", String::from("lanza-safe-mode"));
+    }
+
+    /// Mark the os-release as belonging to a cmdline profile of the generation.
+    ///
+    /// The profile keeps the version of the generation, so that systemd-boot sorts its entry next
+    /// to the one of the generation. Because its file name sorts after the one of the generation,
+    /// it follows the entry of the generation like a specialisation does.
+    pub fn set_cmdline_profile(&mut self, name: &str) {
+        if let Some(pretty_name) = self.0.get_mut("PRETTY_NAME") {
+            pretty_name.push_str(&format!(" (Profile {name})"));
+        }
+        if let Some(version) = self.0.get_mut("VERSION") {
+            version.push_str(&format!(", Profile {name}"));
+        }
+    }
 }
 
 /// Display OsRelease in the format of an os-release file.
@@ -47,3 +109,78 @@ impl fmt::Display for OsRelease {
         Ok(())
     }
 }
+
+/// Keys that need to be present so that systemd-boot can display a boot menu entry.
+const REQUIRED_KEYS: [&str; 2] = ["ID", "PRETTY_NAME"];
+
+/// Validate that the contents of an os-release file are well-formed.
+///
+/// Every line that is neither empty nor a comment must be a `KEY=VALUE` assignment with a valid
+/// key. Additionally, all required keys must be present. Otherwise, systemd-boot may display a
+/// blank or broken boot menu entry.
+pub fn validate(contents: &str) -> Result<()> {
+    let mut keys = BTreeSet::new();
+
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let key = line
+            .split_once('=')
+            .map(|(key, _)| key)
+            .filter(|key| is_valid_key(key))
+            .with_context(|| format!("Malformed os-release line {}: {:?}", index + 1, line))?;
+        keys.insert(key);
+    }
+
+    for key in REQUIRED_KEYS {
+        if !keys.contains(key) {
+            return Err(anyhow!("os-release is missing the required key {key}"));
+        }
+    }
+
+    Ok(())
+}
+
+/// Check whether a string is a valid os-release key.
+///
+/// Keys consist of upper case letters, digits and underscores, and do not start with a digit.
+fn is_valid_key(key: &str) -> bool {
+    key.starts_with(|c: char| c.is_ascii_uppercase() || c == '_')
+        && key
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_well_formed_os_release() {
+        let contents = "# Comment\nID=lanza\n\nPRETTY_NAME=\"LanzaOS\"\nVERSION_ID=1\n";
+        assert!(validate(contents).is_ok());
+    }
+
+    #[test]
+    fn reject_malformed_os_release_line() {
+        let contents = "ID=lanza\nPRETTY_NAME=LanzaOS\nthis is not an assignment\n";
+        let error = validate(contents).unwrap_err();
+        assert!(error.to_string().contains("line 3"));
+    }
+
+    #[test]
+    fn reject_invalid_os_release_key() {
+        let contents = "ID=lanza\nPRETTY_NAME=LanzaOS\npretty-name=LanzaOS\n";
+        assert!(validate(contents).is_err());
+    }
+
+    #[test]
+    fn reject_os_release_without_required_keys() {
+        let contents = "ID=lanza\n";
+        let error = validate(contents).unwrap_err();
+        assert!(error.to_string().contains("PRETTY_NAME"));
+    }
+}
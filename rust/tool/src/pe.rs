@@ -1,17 +1,613 @@
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsString;
+use std::fmt;
 use std::fs;
+use std::io::{self, Read};
+use std::ops::Range;
 use std::os::unix::fs::MetadataExt;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::process::Command;
+use std::sync::Arc;
 
 use anyhow::{Context, Result};
-use goblin::pe::PE;
-use sha2::{Digest, Sha256};
+use goblin::pe::{header, PE};
+use serde::Serialize;
+use sha2::{Digest, Sha256, Sha384, Sha512};
 
-use crate::utils::SecureTempDirExt;
+use crate::error::LanzabooteError;
+use crate::esp::{self, EspGenerationPaths};
+use crate::generation;
+use crate::os_release;
+use crate::pe_writer::{self, NewSection};
+use crate::sbat;
+use crate::signature::SigningKey;
+use crate::utils::{self, SecureTempDirExt};
 
 type Hash = sha2::digest::Output<Sha256>;
 
+/// The version of the protocol between lzbt and the stub.
+///
+/// The protocol version describes the sections that lzbt embeds into the stub. The stub declares
+/// the version it implements in its `.lzbtver` section. This must match the version in the stub
+/// and has to be bumped whenever the sections change in a way that older stubs do not understand.
+///
+/// - Version 1: the kernel and the initrd are referenced by `.kernelp`/`.kernelh` and
+///   `.initrdp`/`.initrdh`, hashed with SHA-256.
+/// - Version 2: the hash algorithm is named in `.hashalg`, the initrd is optional, the kernel and
+///   the initrd can be embedded as `.linux` and `.initrd`, a devicetree as `.dtb` and the security
+///   version as `.lzbsvn`, and the references can use the namespaced `.lzb*` names.
+const STUB_PROTOCOL_VERSION: u32 = 2;
+
+/// Which part of the initrd the embedded `.initrdh` hash covers.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum InitrdHashMode {
+    /// Hash the whole initrd as it is installed to the ESP.
+    #[default]
+    Full,
+    /// Hash only the first `n` bytes of the initrd.
+    ///
+    /// This is used to cover only the base initrd and not the initrd secrets appended to it. The
+    /// length of the prefix is embedded into the `.initrdl` section, so that the stub only
+    /// verifies this part of the initrd.
+    Prefix(u64),
+}
+
+/// The algorithm of the hashes of the kernel and the initrd that are embedded into the stub.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl HashAlgorithm {
+    const ALL: [HashAlgorithm; 3] = [Self::Sha256, Self::Sha384, Self::Sha512];
+
+    /// The name of the algorithm as it is embedded into the stub, e.g. `sha384`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha256",
+            Self::Sha384 => "sha384",
+            Self::Sha512 => "sha512",
+        }
+    }
+
+    /// Look up an algorithm by the name that is embedded into the stub.
+    fn from_name(name: &[u8]) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|algorithm| algorithm.name().as_bytes() == name)
+    }
+
+    /// The length of a hash in bytes.
+    fn output_size(self) -> usize {
+        match self {
+            Self::Sha256 => 32,
+            Self::Sha384 => 48,
+            Self::Sha512 => 64,
+        }
+    }
+
+    /// Hash everything that `reader` yields as it is read. Returns the hash and the number of
+    /// bytes it covers.
+    fn digest_reader(self, reader: &mut impl Read) -> io::Result<(Vec<u8>, u64)> {
+        fn digest<D: Digest + io::Write>(reader: &mut impl Read) -> io::Result<(Vec<u8>, u64)> {
+            let mut hasher = D::new();
+            let length = io::copy(reader, &mut hasher)?;
+            Ok((hasher.finalize().to_vec(), length))
+        }
+
+        match self {
+            Self::Sha256 => digest::<Sha256>(reader),
+            Self::Sha384 => digest::<Sha384>(reader),
+            Self::Sha512 => digest::<Sha512>(reader),
+        }
+    }
+}
+
+/// Optional behavior when assembling a lanzaboote image.
+#[derive(Debug, Default, Clone)]
+pub struct ImageOptions {
+    /// Which part of the initrd the embedded hash covers.
+    pub initrd_hash_mode: InitrdHashMode,
+    /// The algorithm of the embedded hashes of the kernel and the initrd.
+    ///
+    /// Any other algorithm than SHA-256 is embedded into the `.hashalg` section, so that the stub
+    /// verifies the files with the same algorithm. Stubs that predate this section verify with
+    /// SHA-256 and thus refuse to boot such images.
+    pub hash_algorithm: HashAlgorithm,
+    /// Print how the image is assembled instead of running objcopy.
+    pub show_commands: bool,
+    /// Additional sections (e.g. `.ucode`) mapped to the file with their contents.
+    pub extra_sections: HashMap<String, PathBuf>,
+    /// The machine type of the firmware the image is supposed to run on, if known.
+    pub machine_type: Option<u16>,
+    /// How strictly the machine type of the stub is checked.
+    pub machine_type_policy: MachineTypePolicy,
+    /// BFD target to pass to objcopy for the stub and the image instead of letting it detect the
+    /// format, which can go wrong when cross-building.
+    pub objcopy_target: Option<&'static str>,
+    /// Signed kernel to embed as a `.linux` section instead of referencing the kernel on the ESP.
+    ///
+    /// The embedded kernel is covered by the signature of the image, so no hash is embedded for
+    /// it. The initrd is still referenced unless it is embedded as well.
+    pub embedded_kernel: Option<PathBuf>,
+    /// Initrd (including the initrd secrets) to embed as an `.initrd` section instead of
+    /// referencing the initrd on the ESP.
+    ///
+    /// Together with an embedded kernel, this makes the image a self-contained UKI.
+    pub embedded_initrd: Option<PathBuf>,
+    /// Devicetree blob to embed as a `.dtb` section, which the stub installs for the kernel.
+    ///
+    /// Like an embedded kernel, it is covered by the signature of the image.
+    pub devicetree: Option<PathBuf>,
+    /// Security version of the generation to embed as a `.lzbsvn` section. The stub refuses to
+    /// boot images whose security version is lower than the minimum stored in the firmware.
+    ///
+    /// Without a security version (i.e. 0), no section is embedded.
+    pub security_version: u64,
+    /// Fixed time stamp (in seconds since the Unix epoch) to write into the COFF header of the
+    /// image instead of the one of the stub.
+    pub timestamp: Option<u32>,
+    /// Providers of additional sections, which are consulted in order.
+    pub section_providers: Vec<Arc<dyn SectionProvider>>,
+    /// The size above which firmware may fail to load the image.
+    pub size_limit: Option<ImageSizeLimit>,
+    /// The section names that the stub expects.
+    pub stub_profile: StubProfile,
+    /// How the sections are attached to the stub.
+    pub pe_writer: PeWriter,
+    /// Sign a TPM2 PCR policy for the image and embed it as `.pcrsig` and `.pcrpkey` sections.
+    pub pcr_policy: Option<PcrPolicy>,
+    /// SBAT entries (lines of CSV) to merge into the `.sbat` section of the stub, so that the
+    /// image can be revoked by its components. If the stub has no `.sbat` section, one is added.
+    pub sbat_entries: Vec<String>,
+}
+
+/// How the TPM2 PCR policy of an image is signed.
+///
+/// The policy covers the values of PCR 11 that systemd-stub measures the sections of the image
+/// into, so that disk encryption can be bound to every image that the key signs instead of to the
+/// hashes of one image.
+#[derive(Debug, Clone)]
+pub struct PcrPolicy {
+    /// The `systemd-measure` binary that signs the policy.
+    pub systemd_measure: PathBuf,
+    /// PEM encoded key pair to sign the policy with. The public key is embedded as `.pcrpkey`.
+    pub public_key: PathBuf,
+    pub private_key: PathBuf,
+}
+
+/// How sections are attached to the stub.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum PeWriter {
+    /// Run objcopy.
+    Objcopy,
+    /// Write the sections into the PE binary directly, which does not need binutils.
+    #[default]
+    Native,
+}
+
+/// A size limit that is generous enough for all firmware we know of, even with an embedded kernel.
+pub const DEFAULT_IMAGE_SIZE_LIMIT: u64 = 32 * 1024 * 1024;
+
+/// The size above which firmware may fail to load an image.
+///
+/// Some older firmware fails to load large PE images from FAT (e.g. above 16 MiB).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageSizeLimit {
+    pub bytes: u64,
+    /// Whether exceeding the limit is an error instead of a warning.
+    pub enforce: bool,
+}
+
+/// What a section provider knows about the image it provides sections for.
+#[derive(Debug, Serialize)]
+pub struct ImageContext<'a> {
+    pub os_release: &'a str,
+    pub kernel_cmdline: &'a [String],
+    /// The machine type of the firmware the image is supposed to run on, if known.
+    pub machine_type: Option<u16>,
+}
+
+/// A source of sections that are embedded into every image after the ones lzbt embeds itself.
+///
+/// This lets downstream projects embed their own data without changing how images are assembled.
+pub trait SectionProvider: fmt::Debug + Send + Sync {
+    /// The names and contents of the sections to embed, in the order they are embedded.
+    fn sections(&self, context: &ImageContext) -> Result<Vec<(String, Vec<u8>)>>;
+}
+
+/// How strictly the machine type of the stub has to match the machine type of the firmware.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum MachineTypePolicy {
+    /// The machine types have to match exactly.
+    #[default]
+    Strict,
+    /// Also accept stubs that some firmware can run in a compatibility mode (e.g. an x86 stub on
+    /// x86_64 firmware).
+    Permissive,
+    /// Accept any machine type, but warn about mismatches.
+    Warn,
+}
+
+impl MachineTypePolicy {
+    /// Decide about a mismatch of machine types, where `compatible` tells whether it can run in a
+    /// compatibility mode. A mismatch that is only warned about is accepted.
+    fn accepts(self, compatible: bool, reason: &str) -> bool {
+        match self {
+            Self::Strict => false,
+            Self::Permissive => compatible,
+            Self::Warn => {
+                println!("Warning: {reason}");
+                true
+            }
+        }
+    }
+}
+
+/// Pairs of firmware and stub machine types that some firmware supports in a compatibility mode.
+const COMPATIBLE_MACHINE_TYPES: [(u16, u16); 2] = [
+    (header::COFF_MACHINE_X86_64, header::COFF_MACHINE_X86),
+    (header::COFF_MACHINE_ARM64, header::COFF_MACHINE_ARMNT),
+];
+
+/// The PE machine type of the firmware for a Nix system double (e.g. `x86_64-linux`).
+pub fn machine_type_for_system(system: &str) -> Option<u16> {
+    match system.split('-').next()? {
+        "x86_64" => Some(header::COFF_MACHINE_X86_64),
+        "i686" => Some(header::COFF_MACHINE_X86),
+        "aarch64" => Some(header::COFF_MACHINE_ARM64),
+        "armv7l" => Some(header::COFF_MACHINE_ARMNT),
+        _ => None,
+    }
+}
+
+/// The UEFI architecture (e.g. `x64`) for a Nix system double (e.g. `x86_64-linux`).
+pub fn uefi_architecture_for_system(system: &str) -> Option<&'static str> {
+    match system.split('-').next()? {
+        "x86_64" => Some("x64"),
+        "i686" => Some("ia32"),
+        "aarch64" => Some("aa64"),
+        "armv7l" => Some("arm"),
+        "riscv64" => Some("riscv64"),
+        "loongarch64" => Some("loongarch64"),
+        _ => None,
+    }
+}
+
+/// Where to find the lanzaboote stub that the images are assembled from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StubSource {
+    /// A single stub for all generations.
+    File(PathBuf),
+    /// A directory with one stub per UEFI architecture, e.g. `lanzaboote_stub_x64.efi`.
+    ///
+    /// This allows a multi-architecture builder to install generations of any system with the
+    /// same configuration.
+    Directory(PathBuf),
+}
+
+impl StubSource {
+    /// A directory of stubs if `path` is a directory, otherwise a single stub.
+    pub fn new(path: PathBuf) -> Self {
+        if path.is_dir() {
+            Self::Directory(path)
+        } else {
+            Self::File(path)
+        }
+    }
+
+    /// The stub to assemble the images of a generation of a Nix system double from.
+    pub fn select(&self, system: &str) -> Result<PathBuf> {
+        match self {
+            Self::File(stub) => Ok(stub.clone()),
+            Self::Directory(directory) => {
+                let architecture = uefi_architecture_for_system(system).with_context(|| {
+                    format!("Failed to select a stub for {system}, which has no known UEFI architecture")
+                })?;
+                let stub = directory.join(format!("lanzaboote_stub_{architecture}.efi"));
+                if !stub.exists() {
+                    return Err(anyhow::anyhow!(
+                        "There is no stub for the UEFI architecture {architecture} in {directory:?}, expected {stub:?}"
+                    ));
+                }
+                Ok(stub)
+            }
+        }
+    }
+}
+
+/// The BFD target of objcopy for PE binaries of a machine type.
+pub fn objcopy_target(machine: u16) -> Option<&'static str> {
+    match machine {
+        header::COFF_MACHINE_X86_64 => Some("pei-x86-64"),
+        header::COFF_MACHINE_X86 => Some("pei-i386"),
+        header::COFF_MACHINE_ARM64 => Some("pei-aarch64-little"),
+        header::COFF_MACHINE_ARMNT => Some("pei-arm-little"),
+        _ => None,
+    }
+}
+
+/// Check that the stub can run on firmware of the expected machine type.
+fn check_machine_type(expected: u16, actual: u16, policy: MachineTypePolicy) -> Result<()> {
+    if expected == actual {
+        return Ok(());
+    }
+    let reason = format!(
+        "The stub has the machine type {actual:#x}, but the firmware has the machine type {expected:#x}"
+    );
+    if policy.accepts(
+        COMPATIBLE_MACHINE_TYPES.contains(&(expected, actual)),
+        &reason,
+    ) {
+        return Ok(());
+    }
+    Err(LanzabooteError::InvalidStub { reason }.into())
+}
+
+/// How many bytes of a kernel image are read to determine its machine type.
+const KERNEL_HEADER_SIZE: u64 = 0x1000;
+
+/// Offset of the magic of an arm64 `Image`.
+const ARM64_IMAGE_MAGIC_OFFSET: usize = 0x38;
+
+/// Offsets of the "HdrS" magic, the boot protocol version and the `xloadflags` in the setup
+/// header of an x86 bzImage.
+const BZIMAGE_MAGIC_OFFSET: usize = 0x202;
+const BZIMAGE_VERSION_OFFSET: usize = 0x206;
+const BZIMAGE_XLOADFLAGS_OFFSET: usize = 0x236;
+
+/// `xloadflags` bit of a 64-bit kernel.
+const XLF_KERNEL_64: u8 = 0x1;
+
+/// The PE machine type that a kernel image is built for, if it can be determined.
+///
+/// Kernels with an EFI stub are PE binaries themselves. Otherwise, the machine type is read from
+/// the ELF header, the magic of an arm64 `Image` or the setup header of an x86 bzImage.
+fn kernel_machine_type(data: &[u8]) -> Option<u16> {
+    if let Ok(header) = header::Header::parse(data) {
+        return Some(header.coff_header.machine);
+    }
+
+    let read_u16 = |offset: usize| -> Option<u16> {
+        Some(u16::from_le_bytes(
+            data.get(offset..offset + 2)?.try_into().ok()?,
+        ))
+    };
+
+    // Only little endian ELF files are considered.
+    if data.starts_with(b"\x7fELF\x02\x01") || data.starts_with(b"\x7fELF\x01\x01") {
+        return match read_u16(0x12)? {
+            3 => Some(header::COFF_MACHINE_X86),
+            40 => Some(header::COFF_MACHINE_ARMNT),
+            62 => Some(header::COFF_MACHINE_X86_64),
+            183 => Some(header::COFF_MACHINE_ARM64),
+            _ => None,
+        };
+    }
+
+    let has_magic =
+        |offset: usize, magic: &[u8]| data.get(offset..offset + magic.len()) == Some(magic);
+
+    if has_magic(ARM64_IMAGE_MAGIC_OFFSET, b"ARM\x64") {
+        return Some(header::COFF_MACHINE_ARM64);
+    }
+
+    if has_magic(BZIMAGE_MAGIC_OFFSET, b"HdrS") {
+        let xloadflags = *data.get(BZIMAGE_XLOADFLAGS_OFFSET)?;
+        if xloadflags & XLF_KERNEL_64 != 0 {
+            return Some(header::COFF_MACHINE_X86_64);
+        }
+        // Older boot protocols have no xloadflags, so their kernels may be 64-bit as well.
+        if read_u16(BZIMAGE_VERSION_OFFSET)? >= 0x020c {
+            return Some(header::COFF_MACHINE_X86);
+        }
+    }
+
+    None
+}
+
+/// Check that the kernel is built for the machine type of the stub that boots it.
+///
+/// Kernels of an unknown format are accepted. In permissive mode, so are kernels that boot from a
+/// stub in a compatibility mode (e.g. an x86_64 kernel in mixed mode from an x86 stub).
+fn check_kernel_machine_type(
+    kernel: &Path,
+    stub_machine: u16,
+    policy: MachineTypePolicy,
+) -> Result<()> {
+    let mut data = Vec::new();
+    fs::File::open(kernel)
+        .and_then(|file| file.take(KERNEL_HEADER_SIZE).read_to_end(&mut data))
+        .with_context(|| format!("Failed to read kernel {kernel:?}"))?;
+
+    let kernel_machine = match kernel_machine_type(&data) {
+        Some(kernel_machine) if kernel_machine != stub_machine => kernel_machine,
+        _ => return Ok(()),
+    };
+    let reason = format!(
+        "The kernel {kernel:?} has the machine type {kernel_machine:#x}, but the stub has the machine type {stub_machine:#x}"
+    );
+    if policy.accepts(
+        COMPATIBLE_MACHINE_TYPES.contains(&(kernel_machine, stub_machine)),
+        &reason,
+    ) {
+        return Ok(());
+    }
+    Err(anyhow::anyhow!(reason))
+}
+
+/// The conventions for naming the sections that lzbt embeds into the stub.
+///
+/// The sections that systemd-stub defines (`.osrel`, `.cmdline`, `.linux` and `.initrd`) keep their names,
+/// but the lanzaboote-specific ones that reference the kernel and initrd differ between stubs.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum StubProfile {
+    /// The names that all releases of the lanzaboote stub expect.
+    #[default]
+    Current,
+    /// The lanzaboote-specific sections are prefixed with `.lzb`, so that they cannot collide with
+    /// sections that future versions of systemd-stub define. The lanzaboote stub of this release
+    /// understands these names as well.
+    Namespaced,
+}
+
+/// The names of the sections that lzbt embeds into the stub itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct SectionNames {
+    os_release: &'static str,
+    cmdline: &'static str,
+    initrd_path: &'static str,
+    kernel_path: &'static str,
+    initrd_hash: &'static str,
+    kernel_hash: &'static str,
+    initrd_length: &'static str,
+    hash_algorithm: &'static str,
+    linux: &'static str,
+    initrd: &'static str,
+}
+
+impl SectionNames {
+    fn all(&self) -> [&'static str; 10] {
+        [
+            self.os_release,
+            self.cmdline,
+            self.initrd_path,
+            self.kernel_path,
+            self.initrd_hash,
+            self.kernel_hash,
+            self.initrd_length,
+            self.hash_algorithm,
+            self.linux,
+            self.initrd,
+        ]
+    }
+}
+
+impl StubProfile {
+    const ALL: [StubProfile; 2] = [StubProfile::Current, StubProfile::Namespaced];
+
+    fn section_names(self) -> SectionNames {
+        match self {
+            Self::Current => SectionNames {
+                os_release: ".osrel",
+                cmdline: ".cmdline",
+                initrd_path: ".initrdp",
+                kernel_path: ".kernelp",
+                initrd_hash: ".initrdh",
+                kernel_hash: ".kernelh",
+                initrd_length: ".initrdl",
+                hash_algorithm: ".hashalg",
+                linux: ".linux",
+                initrd: ".initrd",
+            },
+            Self::Namespaced => SectionNames {
+                os_release: ".osrel",
+                cmdline: ".cmdline",
+                initrd_path: ".lzbinrp",
+                kernel_path: ".lzbkrnp",
+                initrd_hash: ".lzbinrh",
+                kernel_hash: ".lzbkrnh",
+                initrd_length: ".lzbinrl",
+                hash_algorithm: ".lzbhalg",
+                linux: ".linux",
+                initrd: ".initrd",
+            },
+        }
+    }
+
+    /// Recognize the profile of an installed stub by the lanzaboote-specific sections it carries.
+    ///
+    /// A stub that carries none of them (i.e. one with an embedded kernel and without an initrd)
+    /// gets the default profile, which names its remaining sections like all others.
+    fn detect(pe: &PE, file_data: &[u8]) -> Self {
+        Self::ALL
+            .into_iter()
+            .find(|profile| {
+                let names = profile.section_names();
+                [names.initrd_path, names.kernel_path]
+                    .into_iter()
+                    .any(|name| pe_section(pe, file_data, name).is_some())
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// Names of the sections that lzbt embeds into the stub itself under any profile.
+fn lanzaboote_sections() -> Vec<&'static str> {
+    let mut sections = Vec::new();
+    for name in StubProfile::ALL
+        .into_iter()
+        .flat_map(|profile| profile.section_names().all())
+    {
+        if !sections.contains(&name) {
+            sections.push(name);
+        }
+    }
+    sections
+}
+
+/// The maximum length of a PE section name.
+const MAX_SECTION_NAME_LENGTH: usize = 8;
+
+/// The layout of a lanzaboote stub that determines where the sections are placed.
+///
+/// The stub is the same for all generations of an installation, so it is read and parsed only
+/// once and the layout is reused for every image.
+#[derive(Debug, Clone)]
+pub struct StubLayout {
+    pub path: PathBuf,
+    /// Where the first section is placed.
+    offset: u64,
+    image_base: u64,
+    /// The alignment of the addresses of the sections in memory.
+    section_alignment: u64,
+    machine: u16,
+    size: u64,
+    /// The SBAT metadata of the stub, which describes the code of the stub itself.
+    sbat: Option<String>,
+    /// Whether the stub is systemd-stub, which loads the credentials and system extensions from
+    /// the companion directory of the image.
+    systemd_stub: bool,
+}
+
+impl StubLayout {
+    /// Read a stub and make sure that it implements the protocol version lzbt speaks.
+    pub fn read(path: &Path) -> Result<Self> {
+        let data = fs::read(path).context("Failed to read PE binary file")?;
+        let pe = PE::parse(&data).context("Failed to parse PE binary file")?;
+        // systemd-stub identifies itself like systemd-boot, e.g.
+        // `#### LoaderInfo: systemd-stub 254 ####`.
+        let systemd_stub = pe_section(&pe, &data, ".sdmagic").map_or(false, |magic| {
+            magic
+                .windows(b"systemd-stub".len())
+                .any(|window| window == b"systemd-stub")
+        });
+        check_protocol_version(stub_protocol_version(&pe, &data)?, systemd_stub)
+            .with_context(|| format!("Refusing to use incompatible stub {:?}", path))?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            offset: stub_offset(&pe),
+            image_base: image_base(&pe),
+            section_alignment: section_alignment(&pe),
+            machine: pe.header.coff_header.machine,
+            size: data.len() as u64,
+            sbat: pe_section(&pe, &data, ".sbat")
+                .map(|sbat| String::from_utf8_lossy(sbat).into_owned()),
+            systemd_stub,
+        })
+    }
+
+    /// Whether the stub loads the credentials and system extensions from the companion directory
+    /// (`STUB.extra.d`) of the image. Only systemd-stub does, the lanzaboote stub ignores them.
+    pub fn loads_extra_files(&self) -> bool {
+        self.systemd_stub
+    }
+}
+
 /// Attach all information that lanzaboote needs into the PE binary.
 ///
 /// When this function is called the referenced files already need to
@@ -21,144 +617,1630 @@ pub fn lanzaboote_image(
     // Because the returned path of this function is inside the tempdir as well, the tempdir must
     // live longer than the function. This is why it cannot be created inside the function.
     tempdir: &tempfile::TempDir,
-    lanzaboote_stub: &Path,
+    stub: &StubLayout,
     os_release: &Path,
     kernel_cmdline: &[String],
-    kernel_path: &Path,
-    initrd_path: &Path,
+    esp_gen_paths: &EspGenerationPaths,
     esp: &Path,
+    options: &ImageOptions,
 ) -> Result<PathBuf> {
+    let initrd_hash_mode = options.initrd_hash_mode;
+    let hash_algorithm = options.hash_algorithm;
+    let names = options.stub_profile.section_names();
+    let kernel_path = match (&options.embedded_kernel, &esp_gen_paths.kernel) {
+        (Some(_), _) => None,
+        (None, Some(kernel_path)) => Some(kernel_path),
+        (None, None) => {
+            return Err(anyhow::anyhow!(
+                "The kernel is neither on the ESP nor embedded"
+            ))
+        }
+    };
+    let initrd_path = esp_gen_paths.initrd.as_ref();
+
+    // Validate this before reading (and hashing) any files so that a misconfiguration is reported
+    // as such instead of as an I/O error.
+    if let Some(kernel_path) = kernel_path {
+        ensure_on_esp(esp, kernel_path, "kernel")?;
+    }
+    if let Some(initrd_path) = initrd_path {
+        ensure_on_esp(esp, initrd_path, "initrd")?;
+    }
+
+    if let Some(machine_type) = options.machine_type {
+        check_machine_type(machine_type, stub.machine, options.machine_type_policy)
+            .with_context(|| format!("Refusing to use incompatible stub {:?}", stub.path))?;
+    }
+    // A kernel for another architecture would fail only when booting it.
+    if let Some(kernel) = options.embedded_kernel.as_ref().or(kernel_path) {
+        check_kernel_machine_type(kernel, stub.machine, options.machine_type_policy)?;
+    }
+    if let Some(devicetree) = &options.devicetree {
+        check_devicetree(devicetree)?;
+    }
+
+    let os_release_contents =
+        fs::read_to_string(os_release).context("Failed to read os-release file")?;
+    os_release::validate(&os_release_contents).context("Invalid os-release")?;
+
     // objcopy can only copy files into the PE binary. That's why we
     // have to write the contents of some bootspec properties to disk.
     let kernel_cmdline_file =
         tempdir.write_secure_file("kernel-cmdline", kernel_cmdline.join(" "))?;
 
-    let kernel_path_file =
-        tempdir.write_secure_file("kernel-path", esp_relative_uefi_path(esp, kernel_path)?)?;
-    let kernel_hash_file =
-        tempdir.write_secure_file("kernel-hash", file_hash(kernel_path)?.as_slice())?;
-
-    let initrd_path_file =
-        tempdir.write_secure_file("initrd-path", esp_relative_uefi_path(esp, initrd_path)?)?;
-    let initrd_hash_file =
-        tempdir.write_secure_file("initrd-hash", file_hash(initrd_path)?.as_slice())?;
-
-    let os_release_offs = stub_offset(lanzaboote_stub)?;
-    let kernel_cmdline_offs = os_release_offs + file_size(os_release)?;
-    let initrd_path_offs = kernel_cmdline_offs + file_size(&kernel_cmdline_file)?;
-    let kernel_path_offs = initrd_path_offs + file_size(&initrd_path_file)?;
-    let initrd_hash_offs = kernel_path_offs + file_size(&kernel_path_file)?;
-    let kernel_hash_offs = initrd_hash_offs + file_size(&initrd_hash_file)?;
-
-    let sections = vec![
-        s(".osrel", os_release, os_release_offs),
-        s(".cmdline", kernel_cmdline_file, kernel_cmdline_offs),
-        s(".initrdp", initrd_path_file, initrd_path_offs),
-        s(".kernelp", kernel_path_file, kernel_path_offs),
-        s(".initrdh", initrd_hash_file, initrd_hash_offs),
-        s(".kernelh", kernel_hash_file, kernel_hash_offs),
-    ];
+    let mut files = vec![
+        (names.os_release, os_release.to_path_buf()),
+        (names.cmdline, kernel_cmdline_file),
+    ];
+
+    // Without an initrd, the stub boots the kernel directly. The remaining sections are still laid
+    // out consecutively.
+    if let Some(initrd_path) = initrd_path {
+        let initrd_path_file =
+            tempdir.write_secure_file("initrd-path", esp_relative_uefi_path(esp, initrd_path)?)?;
+        let initrd_hash_file = tempdir.write_secure_file(
+            "initrd-hash",
+            embedded_hash(initrd_path, initrd_hash_mode, hash_algorithm)?,
+        )?;
+        files.push((names.initrd_path, initrd_path_file));
+        files.push((names.initrd_hash, initrd_hash_file));
+    }
+
+    if let Some(kernel_path) = kernel_path {
+        let kernel_path_file =
+            tempdir.write_secure_file("kernel-path", esp_relative_uefi_path(esp, kernel_path)?)?;
+        let kernel_hash_file = tempdir.write_secure_file(
+            "kernel-hash",
+            embedded_hash(kernel_path, InitrdHashMode::Full, hash_algorithm)?,
+        )?;
+        files.push((names.kernel_path, kernel_path_file));
+        files.push((names.kernel_hash, kernel_hash_file));
+    }
+
+    if let (Some(_), InitrdHashMode::Prefix(length)) = (initrd_path, initrd_hash_mode) {
+        let initrd_length_file =
+            tempdir.write_secure_file("initrd-length", length.to_le_bytes())?;
+        files.push((names.initrd_length, initrd_length_file));
+    }
+
+    // Images with the default algorithm stay the same as before the algorithm was configurable.
+    if (initrd_path.is_some() || kernel_path.is_some()) && hash_algorithm != HashAlgorithm::Sha256 {
+        let hash_algorithm_file =
+            tempdir.write_secure_file("hash-algorithm", hash_algorithm.name())?;
+        files.push((names.hash_algorithm, hash_algorithm_file));
+    }
+
+    if let Some(devicetree) = &options.devicetree {
+        files.push((".dtb", devicetree.clone()));
+    }
+
+    if options.security_version > 0 {
+        let security_version_file = tempdir
+            .write_secure_file("security-version", options.security_version.to_le_bytes())?;
+        files.push((".lzbsvn", security_version_file));
+    }
+
+    // The kernel and the initrd are by far the largest sections and go last.
+    if let Some(embedded_kernel) = &options.embedded_kernel {
+        files.push((names.linux, embedded_kernel.clone()));
+    }
+    if let Some(embedded_initrd) = &options.embedded_initrd {
+        files.push((names.initrd, embedded_initrd.clone()));
+    }
+
+    let alignment = stub.section_alignment;
+    let mut sections = layout_sections(stub.offset, alignment, files)?;
+
+    append_extra_sections(&mut sections, alignment, &options.extra_sections)?;
+
+    append_provided_sections(
+        tempdir,
+        &mut sections,
+        alignment,
+        &options.section_providers,
+        &ImageContext {
+            os_release: &os_release_contents,
+            kernel_cmdline,
+            machine_type: options.machine_type,
+        },
+    )?;
+
+    if options.devicetree.is_some()
+        && sections
+            .iter()
+            .filter(|section| section.name == ".dtb")
+            .count()
+            > 1
+    {
+        return Err(anyhow::anyhow!(
+            "The .dtb section is embedded by lzbt for the devicetree of the generation"
+        ));
+    }
+    // Extra sections must not be able to change the security version of the image.
+    let security_version_sections = sections
+        .iter()
+        .filter(|section| section.name == ".lzbsvn")
+        .count();
+    if security_version_sections != usize::from(options.security_version > 0) {
+        return Err(anyhow::anyhow!(
+            "The .lzbsvn section is embedded by lzbt for the security version of the generation"
+        ));
+    }
+
+    // The SBAT metadata of the stub is extended in place. Only a stub without any gets a new
+    // section.
+    let sbat = if options.sbat_entries.is_empty() {
+        stub.sbat.clone()
+    } else {
+        Some(sbat::merge(stub.sbat.as_deref(), &options.sbat_entries))
+    };
+    let sbat_file = sbat
+        .map(|sbat| tempdir.write_secure_file("sbat", sbat))
+        .transpose()?;
+    if !options.sbat_entries.is_empty() {
+        if sections.iter().any(|section| section.name == ".sbat") {
+            return Err(anyhow::anyhow!(
+                "The .sbat section is embedded by lzbt when SBAT entries are given"
+            ));
+        }
+        if let (None, Some(sbat_file)) = (&stub.sbat, &sbat_file) {
+            let base = end_of_sections(&sections)?;
+            sections.extend(layout_sections(
+                base,
+                alignment,
+                vec![(".sbat", sbat_file.clone())],
+            )?);
+        }
+    }
+
+    if let Some(pcr_policy) = &options.pcr_policy {
+        append_pcr_policy(
+            tempdir,
+            &mut sections,
+            alignment,
+            sbat_file.as_deref(),
+            pcr_policy,
+        )?;
+    }
+
+    ensure_sections_fit(stub.image_base, &sections)?;
+
+    let image_path = tempdir.path().join("lanzaboote-stub.efi");
+    if options.show_commands {
+        match options.pe_writer {
+            PeWriter::Objcopy => {
+                let plan = objcopy_plan(&sections, options.objcopy_target, &stub.path, &image_path);
+                let plan: Vec<_> = plan.iter().map(|arg| arg.to_string_lossy()).collect();
+                println!("objcopy {}", plan.join(" "));
+            }
+            PeWriter::Native => {
+                for line in native_plan(&sections, &stub.path) {
+                    println!("{line}");
+                }
+            }
+        }
+    }
+    // The objcopy commands are only shown. The native PE writer lays out the sections the same
+    // way, so it assembles the stub instead.
+    let pe_writer = if options.show_commands {
+        PeWriter::Native
+    } else {
+        options.pe_writer
+    };
+    attach_sections(
+        pe_writer,
+        stub,
+        &sections,
+        options.objcopy_target,
+        &image_path,
+        options.timestamp,
+    )?;
+    if let (Some(sbat_file), false) = (&sbat_file, options.sbat_entries.is_empty()) {
+        if stub.sbat.is_some() {
+            replace_sbat(&image_path, sbat_file)?;
+        }
+    }
+    ensure_expected_image_size(&image_path, stub.size, &sections)?;
+    if let Some(limit) = options.size_limit {
+        check_image_size_limit(file_size(&image_path)?, limit)?;
+    }
+    Ok(image_path)
+}
+
+/// The magic number at the start of every flattened devicetree blob, stored big endian.
+const DEVICETREE_MAGIC: [u8; 4] = [0xd0, 0x0d, 0xfe, 0xed];
+
+/// Make sure that a file is a flattened devicetree blob.
+///
+/// The stub hands the blob to the kernel as is, so anything else (e.g. a devicetree source file)
+/// would only fail when booting.
+fn check_devicetree(path: &Path) -> Result<()> {
+    let mut magic = [0; 4];
+    fs::File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .with_context(|| format!("Failed to read devicetree {path:?}"))?;
+    if magic != DEVICETREE_MAGIC {
+        return Err(anyhow::anyhow!(
+            "{path:?} is not a flattened devicetree blob (.dtb)"
+        ));
+    }
+    Ok(())
+}
+
+/// Warn about (or, if the limit is enforced, reject) an image that exceeds the size limit.
+fn check_image_size_limit(size: u64, limit: ImageSizeLimit) -> Result<()> {
+    if size <= limit.bytes {
+        return Ok(());
+    }
+    let message = format!(
+        "The assembled image is {size} bytes, which is more than the limit of {} bytes. Some firmware fails to load images this large",
+        limit.bytes
+    );
+    if limit.enforce {
+        return Err(anyhow::anyhow!(message));
+    }
+    println!("Warning: {message}");
+    Ok(())
+}
+
+/// How many times larger than its inputs an assembled image may be.
+///
+/// objcopy pads every section to the file alignment, which is small compared to the stub. An
+/// image that is much larger than its inputs points to objcopy misbehaving.
+const MAX_IMAGE_GROWTH_FACTOR: u64 = 2;
+
+/// Make sure that the assembled image is not much larger than the stub and its sections.
+fn ensure_expected_image_size(image: &Path, stub_size: u64, sections: &[Section]) -> Result<()> {
+    let expected = sections
+        .iter()
+        .map(|section| file_size(&section.file_path))
+        .sum::<Result<u64>>()?
+        + stub_size;
+    let actual = file_size(image)?;
+
+    if actual > expected.saturating_mul(MAX_IMAGE_GROWTH_FACTOR) {
+        return Err(anyhow::anyhow!(
+            "The assembled image {image:?} is {actual} bytes, but the stub and its sections are only {expected} bytes"
+        ));
+    }
+    Ok(())
+}
+
+/// Compute the SHA 256 hash of a file.
+///
+/// The file is hashed as it is read, so that it is never in memory as a whole.
+pub fn file_hash(file: &Path) -> Result<Hash> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut fs::File::open(file)?, &mut hasher)?;
+    Ok(hasher.finalize())
+}
+
+/// Compute the hash of a kernel or an initrd that is embedded into the stub.
+///
+/// Like [`file_hash`], this streams the file instead of reading it into memory, which matters
+/// for initrds of hundreds of megabytes.
+fn embedded_hash(file: &Path, mode: InitrdHashMode, algorithm: HashAlgorithm) -> Result<Vec<u8>> {
+    let mut file_handle = fs::File::open(file)?;
+    match mode {
+        InitrdHashMode::Full => Ok(algorithm.digest_reader(&mut file_handle)?.0),
+        InitrdHashMode::Prefix(length) => {
+            let (hash, hashed) = algorithm.digest_reader(&mut file_handle.take(length))?;
+            if hashed < length {
+                return Err(anyhow::anyhow!(
+                    "{file:?} is shorter than the {length} bytes to hash"
+                ));
+            }
+            Ok(hash)
+        }
+    }
+}
+
+/// Assemble the objcopy arguments that attach the sections to the stub.
+///
+/// This is exactly what `wrap_in_pe` passes to objcopy. Without a `target`, objcopy detects the
+/// format of the stub by itself.
+pub fn objcopy_plan(
+    sections: &[Section],
+    target: Option<&str>,
+    stub: &Path,
+    output: &Path,
+) -> Vec<OsString> {
+    let mut args: Vec<OsString> = target
+        .into_iter()
+        .flat_map(|target| ["--input-target", target, "--output-target", target])
+        .map(OsString::from)
+        .collect();
+    args.extend(sections.iter().flat_map(Section::to_objcopy));
+
+    [stub.as_os_str(), output.as_os_str()]
+        .iter()
+        .for_each(|a| args.push(a.into()));
+
+    args
+}
+
+/// Describe how the native PE writer attaches the sections to the stub, one line per section.
+///
+/// Every line names the section, the address it is mapped at and the file it is read from.
+pub fn native_plan(sections: &[Section], stub: &Path) -> Vec<String> {
+    sections
+        .iter()
+        .map(|section| {
+            format!(
+                "add section {} at {:#x} from {} to {}",
+                section.name,
+                section.offset,
+                section.file_path.display(),
+                stub.display()
+            )
+        })
+        .collect()
+}
+
+/// Attach sections to a PE binary stub with the chosen writer.
+fn attach_sections(
+    writer: PeWriter,
+    stub: &StubLayout,
+    sections: &[Section],
+    objcopy_target: Option<&str>,
+    output: &Path,
+    timestamp: Option<u32>,
+) -> Result<()> {
+    match writer {
+        PeWriter::Objcopy => wrap_in_pe(&stub.path, sections, objcopy_target, output, timestamp),
+        PeWriter::Native => {
+            wrap_in_pe_natively(&stub.path, stub.image_base, sections, output, timestamp)
+        }
+    }
+}
+
+/// Take a PE binary stub and attach sections to it.
+///
+/// The resulting binary is then written to a newly created file at the provided output path.
+fn wrap_in_pe(
+    stub: &Path,
+    sections: &[Section],
+    target: Option<&str>,
+    output: &Path,
+    timestamp: Option<u32>,
+) -> Result<()> {
+    let args = objcopy_plan(sections, target, stub, output);
+
+    let status = Command::new("objcopy")
+        .args(&args)
+        .status()
+        .context("Failed to run objcopy command")?;
+    if !status.success() {
+        return Err(LanzabooteError::ObjcopyFailed { args }.into());
+    }
+
+    ensure_native_pe_format(output)?;
+    ensure_section_placement(output, sections)?;
+
+    if let Some(timestamp) = timestamp {
+        set_timestamp(output, timestamp)?;
+    }
+
+    // objcopy leaves the checksum stale after adding sections.
+    update_checksum(output)
+}
+
+/// Attach sections to a PE binary stub like `wrap_in_pe`, but without objcopy.
+fn wrap_in_pe_natively(
+    stub: &Path,
+    image_base: u64,
+    sections: &[Section],
+    output: &Path,
+    timestamp: Option<u32>,
+) -> Result<()> {
+    let stub_data = fs::read(stub).with_context(|| format!("Failed to read PE binary {stub:?}"))?;
+    let new_sections = sections
+        .iter()
+        .map(|section| {
+            Ok(NewSection {
+                name: &section.name,
+                virtual_address: section
+                    .offset
+                    .checked_sub(image_base)
+                    .and_then(|address| u32::try_from(address).ok())
+                    .with_context(|| format!("Section {} is outside of the image", section.name))?,
+                data: fs::read(&section.file_path)
+                    .with_context(|| format!("Failed to read {:?}", section.file_path))?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let data = pe_writer::add_sections(&stub_data, &new_sections)
+        .with_context(|| format!("Failed to add sections to {stub:?}"))?;
+    fs::write(output, data).with_context(|| format!("Failed to write PE binary {output:?}"))?;
+
+    if let Some(timestamp) = timestamp {
+        set_timestamp(output, timestamp)?;
+    }
+    update_checksum(output)
+}
+
+/// Make sure that objcopy placed every section at the address it was laid out at.
+///
+/// objcopy may round `--change-section-vma` up to the section alignment of the stub. Every
+/// following section is laid out relative to the previous one, so a silently moved section would
+/// make the sections overlap.
+fn ensure_section_placement(path: &Path, sections: &[Section]) -> Result<()> {
+    let data = fs::read(path).with_context(|| format!("Failed to read PE binary {path:?}"))?;
+    let pe = PE::parse(&data).with_context(|| format!("Failed to parse PE binary {path:?}"))?;
+    let image_base = image_base(&pe);
+    let placed: Vec<(&str, u64)> = pe
+        .sections
+        .iter()
+        .filter_map(|section| {
+            let name = section.name().ok()?;
+            Some((name, image_base + u64::from(section.virtual_address)))
+        })
+        .collect();
+    check_section_placement(&placed, sections)
+        .with_context(|| format!("objcopy misplaced a section in {path:?}"))
+}
+
+/// Compare the addresses of the sections in an image to the ones they were laid out at.
+///
+/// objcopy appends the added sections to the section table, so the last section of a name is the
+/// added one, even if the stub already has a section of the same name.
+fn check_section_placement(placed: &[(&str, u64)], sections: &[Section]) -> Result<()> {
+    for section in sections {
+        match placed.iter().rev().find(|(name, _)| *name == section.name) {
+            Some(&(_, address)) if address == section.offset => {}
+            Some(&(_, address)) => {
+                return Err(anyhow::anyhow!(
+                    "Section {} is at {address:#x} instead of {:#x}",
+                    section.name,
+                    section.offset
+                ))
+            }
+            None => return Err(anyhow::anyhow!("Section {} is missing", section.name)),
+        }
+    }
+    Ok(())
+}
+
+/// Machine types that UEFI only runs as PE32+ binaries.
+const SIXTY_FOUR_BIT_MACHINE_TYPES: [u16; 2] =
+    [header::COFF_MACHINE_X86_64, header::COFF_MACHINE_ARM64];
+
+/// Make sure that objcopy produced a PE32+ binary for a 64-bit machine type.
+///
+/// Some objcopy builds default to a target with PE32 headers, which 64-bit firmware refuses to
+/// run.
+fn ensure_native_pe_format(path: &Path) -> Result<()> {
+    let data = fs::read(path).with_context(|| format!("Failed to read PE binary {path:?}"))?;
+    let pe = PE::parse(&data).with_context(|| format!("Failed to parse PE binary {path:?}"))?;
+    check_pe_format(
+        pe.header.coff_header.machine,
+        pe.header
+            .optional_header
+            .map(|optional_header| optional_header.standard_fields.magic),
+    )
+    .with_context(|| format!("objcopy produced an unusable PE binary {path:?}"))
+}
+
+fn check_pe_format(machine: u16, magic: Option<u16>) -> Result<()> {
+    if SIXTY_FOUR_BIT_MACHINE_TYPES.contains(&machine)
+        && magic != Some(goblin::pe::optional_header::MAGIC_64)
+    {
+        return Err(anyhow::anyhow!(
+            "The PE binary for the 64-bit machine type {machine:#x} does not have PE32+ headers"
+        ));
+    }
+    Ok(())
+}
+
+/// Offset of the time stamp field relative to the PE signature (after the signature, the machine
+/// type and the number of sections).
+const TIMESTAMP_OFFSET: usize = 4 + 2 + 2;
+
+/// Overwrite the time stamp in the COFF header of a PE binary.
+///
+/// The checksum has to be updated afterwards.
+fn set_timestamp(path: &Path, timestamp: u32) -> Result<()> {
+    let mut data = fs::read(path).with_context(|| format!("Failed to read PE binary {path:?}"))?;
+    let offset = pe_offset(&data)? + TIMESTAMP_OFFSET;
+    if data.len() < offset + 4 {
+        return Err(anyhow::anyhow!("PE binary is truncated"));
+    }
+    data[offset..offset + 4].copy_from_slice(&timestamp.to_le_bytes());
+    fs::write(path, data).with_context(|| format!("Failed to write PE binary {path:?}"))
+}
+
+/// Offset of the PE signature pointer in the DOS header.
+const PE_POINTER_OFFSET: usize = 0x3c;
+/// Offset of the checksum field relative to the PE signature (after the signature, the COFF file
+/// header and the first 64 bytes of the optional header).
+const CHECKSUM_OFFSET: usize = 4 + 20 + 64;
+
+/// Locate the PE signature of a PE binary.
+fn pe_offset(data: &[u8]) -> Result<usize> {
+    let pe_pointer = data
+        .get(PE_POINTER_OFFSET..PE_POINTER_OFFSET + 4)
+        .context("PE binary is truncated")?;
+    let pe_offset = u32::from_le_bytes(pe_pointer.try_into()?) as usize;
+    if data.get(pe_offset..pe_offset + 4) != Some(&b"PE\0\0"[..]) {
+        return Err(anyhow::anyhow!("PE binary has no PE signature"));
+    }
+    Ok(pe_offset)
+}
+
+/// Locate the checksum field in the optional header of a PE binary.
+fn checksum_offset(data: &[u8]) -> Result<usize> {
+    let offset = pe_offset(data)? + CHECKSUM_OFFSET;
+    if data.len() < offset + 4 {
+        return Err(anyhow::anyhow!("PE binary is truncated"));
+    }
+    Ok(offset)
+}
+
+/// Offset of the data directories relative to the optional header of a PE32 binary.
+const PE32_DATA_DIRECTORIES_OFFSET: usize = 96;
+/// Offset of the data directories relative to the optional header of a PE32+ binary.
+const PE32_PLUS_DATA_DIRECTORIES_OFFSET: usize = 112;
+/// Offset of the certificate table entry relative to the data directories.
+const CERTIFICATE_TABLE_ENTRY_OFFSET: usize = 4 * 8;
+
+/// Locate the certificate table entry in the data directories of a PE binary.
+fn certificate_table_entry_offset(data: &[u8]) -> Result<usize> {
+    let pe = PE::parse(data).context("Failed to parse PE binary")?;
+    let optional_header = pe
+        .header
+        .optional_header
+        .context("PE binary has no optional header")?;
+    let data_directories = pe_offset(data)?
+        + 4
+        + 20
+        + match optional_header.standard_fields.magic {
+            goblin::pe::optional_header::MAGIC_64 => PE32_PLUS_DATA_DIRECTORIES_OFFSET,
+            _ => PE32_DATA_DIRECTORIES_OFFSET,
+        };
+    Ok(data_directories + CERTIFICATE_TABLE_ENTRY_OFFSET)
+}
+
+/// Compute the Authenticode digest of a PE binary.
+///
+/// This is the hash that a signature of the binary covers. It skips the checksum, the certificate
+/// table entry and the certificate table itself, so it does not change when the binary is signed.
+pub fn authenticode_digest(data: &[u8]) -> Result<Hash> {
+    let pe = PE::parse(data).context("Failed to parse PE binary")?;
+    let optional_header = pe
+        .header
+        .optional_header
+        .context("PE binary has no optional header")?;
+
+    let checksum = checksum_offset(data)?;
+    let certificate_entry = certificate_table_entry_offset(data)?;
+    let size_of_headers = optional_header.windows_fields.size_of_headers as usize;
+    if certificate_entry + 8 > size_of_headers || size_of_headers > data.len() {
+        return Err(anyhow::anyhow!("PE binary is truncated"));
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.update(&data[..checksum]);
+    hasher.update(&data[checksum + 4..certificate_entry]);
+    hasher.update(&data[certificate_entry + 8..size_of_headers]);
+
+    let mut sections = pe
+        .sections
+        .iter()
+        .filter(|section| section.size_of_raw_data > 0)
+        .collect::<Vec<_>>();
+    sections.sort_by_key(|section| section.pointer_to_raw_data);
+
+    let mut hashed = size_of_headers;
+    for section in sections {
+        let start = section.pointer_to_raw_data as usize;
+        let size = section.size_of_raw_data as usize;
+        hasher.update(
+            data.get(start..start + size)
+                .context("PE section is truncated")?,
+        );
+        hashed += size;
+    }
+
+    let certificate_table_size = optional_header
+        .data_directories
+        .get_certificate_table()
+        .as_ref()
+        .map_or(0, |table| table.size as usize);
+    let end = data
+        .len()
+        .checked_sub(certificate_table_size)
+        .context("PE certificate table is truncated")?;
+    if let Some(remainder) = data.get(hashed..end) {
+        hasher.update(remainder);
+    }
+
+    Ok(hasher.finalize())
+}
+
+/// Whether a PE binary carries a signature.
+pub fn is_signed(data: &[u8]) -> Result<bool> {
+    let pe = PE::parse(data).context("Failed to parse PE binary")?;
+    Ok(pe.header.optional_header.map_or(false, |optional_header| {
+        optional_header
+            .data_directories
+            .get_certificate_table()
+            .as_ref()
+            .map_or(false, |table| table.size > 0)
+    }))
+}
+
+/// Compute the checksum of a PE binary as defined by the PE format.
+///
+/// The checksum is the sum of all 16-bit words of the file with the carries folded back in,
+/// counting the checksum field itself as zero, plus the length of the file. The field does not
+/// have to be aligned to a word, so it is masked byte by byte.
+fn compute_checksum(data: &[u8], checksum_offset: usize) -> u32 {
+    let checksum_field = checksum_offset..checksum_offset + 4;
+    let byte = |offset: usize| match data.get(offset) {
+        Some(&byte) if !checksum_field.contains(&offset) => byte,
+        _ => 0,
+    };
+    let mut sum: u64 = 0;
+    for offset in (0..data.len()).step_by(2) {
+        sum += u64::from(u16::from_le_bytes([byte(offset), byte(offset + 1)]));
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum = (sum & 0xffff) + (sum >> 16);
+
+    (sum as u32).wrapping_add(data.len() as u32)
+}
+
+/// Recompute the checksum of a PE binary and write it into its header.
+fn update_checksum(path: &Path) -> Result<()> {
+    let mut data = fs::read(path).with_context(|| format!("Failed to read PE binary {path:?}"))?;
+    let offset = checksum_offset(&data)?;
+    let checksum = compute_checksum(&data, offset);
+    data[offset..offset + 4].copy_from_slice(&checksum.to_le_bytes());
+    fs::write(path, data).with_context(|| format!("Failed to write PE binary {path:?}"))
+}
+
+/// Check whether the checksum in the header of a PE binary matches its contents.
+pub fn verify_checksum(pe: &Path) -> Result<bool> {
+    let data = fs::read(pe).with_context(|| format!("Failed to read PE binary {pe:?}"))?;
+    let offset = checksum_offset(&data)?;
+    let stored = u32::from_le_bytes(data[offset..offset + 4].try_into()?);
+    Ok(stored == compute_checksum(&data, offset))
+}
+
+pub struct Section {
+    name: String,
+    file_path: PathBuf,
+    offset: u64,
+}
+
+impl Section {
+    /// Create objcopy `-add-section` command line parameters that
+    /// attach the section to a PE file.
+    fn to_objcopy(&self) -> Vec<OsString> {
+        // There is unfortunately no format! for OsString, so we cannot
+        // just format a path.
+        let mut map_str: OsString = format!("{}=", self.name).into();
+        map_str.push(&self.file_path);
+
+        vec![
+            OsString::from("--add-section"),
+            map_str,
+            OsString::from("--change-section-vma"),
+            format!("{}={:#x}", self.name, self.offset).into(),
+        ]
+    }
+}
+
+fn s(name: &str, file_path: impl AsRef<Path>, offset: u64) -> Result<Section> {
+    validate_section_name(name)?;
+    Ok(Section {
+        name: name.to_owned(),
+        file_path: file_path.as_ref().into(),
+        offset,
+    })
+}
+
+/// Make sure that a section name can be represented in a PE section header.
+///
+/// PE section names are at most 8 bytes long. Only a conservative set of ASCII characters is
+/// accepted. In particular, `=` is rejected because objcopy uses it to separate the section name
+/// from its argument.
+pub fn validate_section_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.len() > MAX_SECTION_NAME_LENGTH {
+        return Err(anyhow::anyhow!(
+            "Invalid section name {name:?}: PE section names are 1 to {MAX_SECTION_NAME_LENGTH} bytes long"
+        ));
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '$' | '-')))
+    {
+        return Err(anyhow::anyhow!(
+            "Invalid section name {name:?}: {c:?} is not allowed in PE section names"
+        ));
+    }
+    Ok(())
+}
+
+/// Assign consecutive offsets to sections of the given sizes.
+///
+/// The first section starts at `base` (aligned to `alignment`). Every following section starts
+/// at the end of the previous one, rounded up to the next multiple of `alignment`.
+fn compute_layout<'a>(base: u64, sizes: &[(&'a str, u64)], alignment: u64) -> Vec<(&'a str, u64)> {
+    let mut offset = base;
+    sizes
+        .iter()
+        .map(|&(name, size)| {
+            let section_offset = pe_writer::align_up(offset as usize, alignment as usize) as u64;
+            offset = section_offset + size;
+            (name, section_offset)
+        })
+        .collect()
+}
+
+/// Create the sections for the given files, laid out consecutively starting at `base`.
+///
+/// Every section starts at a multiple of `alignment`, which has to be the section alignment of
+/// the stub. The PE loader of the firmware relies on it.
+fn layout_sections(base: u64, alignment: u64, files: Vec<(&str, PathBuf)>) -> Result<Vec<Section>> {
+    let sizes = files
+        .iter()
+        .map(|(name, path)| Ok((*name, file_size(path)?)))
+        .collect::<Result<Vec<_>>>()?;
+
+    compute_layout(base, &sizes, alignment)
+        .into_iter()
+        .zip(files)
+        .map(|((name, offset), (_, path))| s(name, path, offset))
+        .collect()
+}
+
+/// Append user-provided sections after the sections that lzbt embeds itself.
+///
+/// The sections are appended in the order of their names so that the resulting image does not
+/// depend on the iteration order of the map.
+fn append_extra_sections(
+    sections: &mut Vec<Section>,
+    alignment: u64,
+    extra_sections: &HashMap<String, PathBuf>,
+) -> Result<()> {
+    let mut names: Vec<&String> = extra_sections.keys().collect();
+    names.sort();
+
+    for name in &names {
+        validate_extra_section_name(name)?;
+    }
+
+    let base = end_of_sections(sections)?;
+    let files = names
+        .into_iter()
+        .map(|name| (name.as_str(), extra_sections[name].clone()))
+        .collect();
+    sections.extend(layout_sections(base, alignment, files)?);
+
+    Ok(())
+}
+
+/// Append the sections of the section providers after all other sections.
+///
+/// The contents are written to files in `tempdir` because objcopy can only embed files.
+fn append_provided_sections(
+    tempdir: &tempfile::TempDir,
+    sections: &mut Vec<Section>,
+    alignment: u64,
+    providers: &[Arc<dyn SectionProvider>],
+    context: &ImageContext,
+) -> Result<()> {
+    let mut provided = Vec::new();
+    for provider in providers {
+        provided.extend(
+            provider
+                .sections(context)
+                .with_context(|| format!("Section provider {provider:?} failed"))?,
+        );
+    }
+    if provided.is_empty() {
+        return Ok(());
+    }
+
+    let mut files = Vec::new();
+    for (index, (name, contents)) in provided.iter().enumerate() {
+        validate_extra_section_name(name)?;
+        if sections.iter().any(|section| &section.name == name)
+            || provided[..index].iter().any(|(other, _)| other == name)
+        {
+            return Err(anyhow::anyhow!(
+                "The section {name} is provided more than once"
+            ));
+        }
+        let path = tempdir.write_secure_file(&format!("provided-section-{index}"), contents)?;
+        files.push((name.as_str(), path));
+    }
+
+    let base = end_of_sections(sections)?;
+    sections.extend(layout_sections(base, alignment, files)?);
+
+    Ok(())
+}
+
+/// The offset right after the last section, where further sections can be appended.
+fn end_of_sections(sections: &[Section]) -> Result<u64> {
+    match sections.last() {
+        Some(last) => Ok(last.offset + file_size(&last.file_path)?),
+        None => Err(anyhow::anyhow!(
+            "Cannot append sections to an empty section list"
+        )),
+    }
+}
+
+/// Replace the `.sbat` section that an image inherited from its stub with the merged metadata.
+fn replace_sbat(image: &Path, sbat_file: &Path) -> Result<()> {
+    let data = fs::read(image).with_context(|| format!("Failed to read PE binary {image:?}"))?;
+    let sbat = fs::read(sbat_file).context("Failed to read the merged SBAT metadata")?;
+    let data = pe_writer::replace_section_data(&data, ".sbat", &sbat)
+        .context("Failed to merge the SBAT entries into the .sbat section of the stub")?;
+    fs::write(image, data).with_context(|| format!("Failed to write PE binary {image:?}"))?;
+    update_checksum(image)
+}
+
+/// Append the public key of the PCR policy as `.pcrpkey` and the signed policy as `.pcrsig`.
+///
+/// The public key is measured as well, so it has to be part of the sections that the policy is
+/// computed from. The signature is not measured and thus goes last. The `.sbat` section of the
+/// stub is not among `sections`, so its (merged) contents are passed separately.
+fn append_pcr_policy(
+    tempdir: &tempfile::TempDir,
+    sections: &mut Vec<Section>,
+    alignment: u64,
+    stub_sbat: Option<&Path>,
+    pcr_policy: &PcrPolicy,
+) -> Result<()> {
+    for name in [".pcrpkey", ".pcrsig"] {
+        if sections.iter().any(|section| section.name == name) {
+            return Err(anyhow::anyhow!(
+                "The {name} section is embedded by lzbt when it signs the PCR policy"
+            ));
+        }
+    }
+    if !sections.iter().any(|section| section.name == ".linux") {
+        return Err(anyhow::anyhow!(
+            "A PCR policy can only be signed for an image with an embedded kernel"
+        ));
+    }
+
+    let base = end_of_sections(sections)?;
+    sections.extend(layout_sections(
+        base,
+        alignment,
+        vec![(".pcrpkey", pcr_policy.public_key.clone())],
+    )?);
+
+    let mut command = Command::new(&pcr_policy.systemd_measure);
+    command
+        .arg("sign")
+        .arg("--bank=sha256")
+        .arg(prefixed_path("--private-key=", &pcr_policy.private_key))
+        .arg(prefixed_path("--public-key=", &pcr_policy.public_key));
+    for section in sections
+        .iter()
+        .filter(|section| MEASURED_SECTIONS.contains(&section.name.as_str()))
+    {
+        let option = format!("--{}=", section.name.trim_start_matches('.'));
+        command.arg(prefixed_path(&option, &section.file_path));
+    }
+    if let Some(sbat) = stub_sbat.filter(|_| !sections.iter().any(|s| s.name == ".sbat")) {
+        command.arg(prefixed_path("--sbat=", sbat));
+    }
+
+    let output = command.output().with_context(|| {
+        format!(
+            "Failed to run {:?} to sign the PCR policy",
+            pcr_policy.systemd_measure
+        )
+    })?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "Failed to sign the PCR policy: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    let signature = tempdir.write_secure_file("pcrsig", output.stdout)?;
+    let base = end_of_sections(sections)?;
+    sections.extend(layout_sections(
+        base,
+        alignment,
+        vec![(".pcrsig", signature)],
+    )?);
+    Ok(())
+}
+
+/// An option followed directly by a path, e.g. `--linux=/tmp/kernel`.
+fn prefixed_path(option: &str, path: &Path) -> OsString {
+    let mut argument = OsString::from(option);
+    argument.push(path);
+    argument
+}
+
+/// Make sure that a user-provided section does not interfere with the sections of lzbt.
+pub fn validate_extra_section_name(name: &str) -> Result<()> {
+    if lanzaboote_sections().contains(&name) {
+        return Err(anyhow::anyhow!(
+            "The section {name} is embedded by lzbt and cannot be provided as an extra section"
+        ));
+    }
+    Ok(())
+}
+
+/// Make sure that a file referenced by the stub is located on the ESP.
+///
+/// The stub can only load files from the ESP it was loaded from. Thus, the files need to be
+/// copied into the ESP before the stub image is assembled.
+fn ensure_on_esp(esp: &Path, path: &Path, name: &str) -> Result<()> {
+    if !path.starts_with(esp) {
+        return Err(anyhow::anyhow!(
+            "The {} {:?} is not on the ESP {:?}. The {} must be copied into the ESP before imaging.",
+            name,
+            path,
+            esp,
+            name
+        ));
+    }
+    Ok(())
+}
+
+/// The UEFI path by which the firmware or systemd-boot loads a file on the ESP, e.g. to create a
+/// boot entry for it.
+///
+/// The file does not need to exist yet, but it has to be located under the ESP. Paths that leave
+/// the ESP via `..` are rejected.
+pub fn uefi_path_for(esp: &Path, target: &Path) -> Result<String> {
+    let under_esp = target.strip_prefix(esp).map_or(false, |relative_path| {
+        relative_path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+    });
+    if !under_esp {
+        return Err(anyhow::anyhow!(
+            "{target:?} is not located under the ESP {esp:?}"
+        ));
+    }
+    esp_relative_uefi_path(esp, target)
+}
+
+/// Convert a path to an UEFI path relative to the specified ESP.
+fn esp_relative_uefi_path(esp: &Path, path: &Path) -> Result<String> {
+    let relative_path = path
+        .strip_prefix(esp)
+        .with_context(|| format!("Failed to strip esp prefix: {:?} from: {:?}", esp, path))?;
+    let uefi_path = uefi_path(relative_path)?;
+    Ok(format!("\\{}", &uefi_path))
+}
+
+/// Convert a path to a UEFI string representation.
+///
+/// UEFI represents paths as UCS-2 strings, i.e. every character is a single UTF-16 code unit.
+/// Characters outside of the Basic Multilingual Plane need a UTF-16 surrogate pair and cannot be
+/// represented. Such paths and paths with control characters are rejected.
+///
+/// The path is not Unicode normalized. It is passed to the firmware exactly as it is stored on
+/// the ESP.
+fn uefi_path(path: &Path) -> Result<String> {
+    let path_str = match path.to_str() {
+        Some(path_str) => path_str,
+        None => {
+            let component = path
+                .components()
+                .find(|component| component.as_os_str().to_str().is_none())
+                .map_or(path.as_os_str(), |component| component.as_os_str());
+            return Err(anyhow::anyhow!(
+                "Failed to convert {path:?} to an UEFI path: the component {component:?} is not valid UTF-8. UEFI paths can only contain characters that are representable in UCS-2. Rename the file (or the store path it comes from) so that its name is valid UTF-8"
+            ));
+        }
+    };
+
+    if let Some(c) = path_str
+        .chars()
+        .find(|c| c.len_utf16() != 1 || c.is_control())
+    {
+        return Err(anyhow::anyhow!(
+            "Failed to convert {:?} to an UEFI path: {:?} cannot be represented in UCS-2",
+            path,
+            c
+        ));
+    }
+
+    Ok(path_str.replace('/', "\\"))
+}
+
+/// The section alignment from the optional header of a PE binary.
+fn section_alignment(pe: &PE) -> u64 {
+    pe.header
+        .optional_header
+        .map_or(1, |header| {
+            u64::from(header.windows_fields.section_alignment)
+        })
+        .max(1)
+}
+
+fn stub_offset(pe: &PE) -> u64 {
+    let image_base = image_base(pe);
+
+    // The Virtual Memory Address (VMA) is relative to the image base, aka the image base
+    // needs to be added to the virtual address to get the actual (but still virtual address)
+    u64::from(
+        pe.sections
+            .last()
+            .map(|s| s.virtual_size + s.virtual_address)
+            .expect("Failed to calculate offset"),
+    ) + image_base
+}
+
+/// Extract the data of a section of a PE binary.
+fn pe_section<'a>(pe: &PE, file_data: &'a [u8], section_name: &str) -> Option<&'a [u8]> {
+    pe.sections
+        .iter()
+        .find(|s| s.name().map_or(false, |name| name == section_name))
+        .and_then(|s| {
+            let section_start = usize::try_from(s.pointer_to_raw_data).ok()?;
+            let section_size = usize::try_from(s.virtual_size.min(s.size_of_raw_data)).ok()?;
+            file_data.get(section_start..section_start.checked_add(section_size)?)
+        })
+}
+
+/// The part of a PE file that holds the data of a section, if the file has the section.
+pub fn section_range(path: &Path, section_name: &str) -> Result<Option<Range<usize>>> {
+    let data = fs::read(path).with_context(|| format!("Failed to read {path:?}"))?;
+    let pe = PE::parse(&data).with_context(|| format!("Failed to parse {path:?}"))?;
+    Ok(pe
+        .sections
+        .iter()
+        .find(|s| s.name().map_or(false, |name| name == section_name))
+        .map(|s| {
+            let start = s.pointer_to_raw_data as usize;
+            start..start + s.virtual_size.min(s.size_of_raw_data) as usize
+        }))
+}
+
+/// A file that an installed stub references.
+pub struct StubReference {
+    /// Path of the file on the partition the stub is installed to.
+    pub path: PathBuf,
+    /// The path as it is embedded into the stub, e.g. `\EFI\nixos\kernel.efi`.
+    uefi_path: String,
+    /// The hash of the file that is embedded into the stub.
+    hash: Vec<u8>,
+    /// The section the hash is embedded into.
+    hash_section: &'static str,
+    /// Which part of the file the hash covers.
+    hash_mode: InitrdHashMode,
+    /// The algorithm of the hash.
+    hash_algorithm: HashAlgorithm,
+    /// Whether the file is the kernel rather than the initrd.
+    kernel: bool,
+}
+
+impl StubReference {
+    /// Whether the file is the kernel of the stub rather than its initrd.
+    pub fn is_kernel(&self) -> bool {
+        self.kernel
+    }
+
+    /// Whether the file still has the hash that is embedded into the stub.
+    pub fn matches(&self) -> Result<bool> {
+        Ok(embedded_hash(&self.path, self.hash_mode, self.hash_algorithm)? == self.hash)
+    }
+
+    /// Whether the hash covers only the beginning of the file, i.e. the base initrd without the
+    /// appended initrd secrets.
+    pub fn covers_prefix_only(&self) -> bool {
+        matches!(self.hash_mode, InitrdHashMode::Prefix(_))
+    }
+}
+
+/// Read the kernel and initrd that an installed stub references.
+///
+/// The paths in the stub are relative to the partition `boot` that the stub is installed to. A
+/// kernel that is embedded into the stub is not referenced, neither is an initrd if the stub boots
+/// without one.
+pub fn stub_references(stub: &Path, boot: &Path) -> Result<Vec<StubReference>> {
+    let data = fs::read(stub).with_context(|| format!("Failed to read stub {stub:?}"))?;
+    let pe = PE::parse(&data).with_context(|| format!("Failed to parse stub {stub:?}"))?;
+    let section = |name: &str| {
+        pe_section(&pe, &data, name).with_context(|| format!("Stub {stub:?} has no {name} section"))
+    };
+
+    let names = StubProfile::detect(&pe, &data).section_names();
+
+    let initrd_hash_mode = match pe_section(&pe, &data, names.initrd_length) {
+        Some(length) => {
+            InitrdHashMode::Prefix(u64::from_le_bytes(length.try_into().with_context(
+                || format!("Malformed {} section in stub {stub:?}", names.initrd_length),
+            )?))
+        }
+        None => InitrdHashMode::Full,
+    };
+    let hash_algorithm = match pe_section(&pe, &data, names.hash_algorithm) {
+        Some(name) => HashAlgorithm::from_name(name).with_context(|| {
+            format!(
+                "Unknown hash algorithm {:?} in stub {stub:?}",
+                String::from_utf8_lossy(name)
+            )
+        })?,
+        None => HashAlgorithm::Sha256,
+    };
+
+    let embeds_kernel = pe_section(&pe, &data, names.linux).is_some();
+    let has_initrd = pe_section(&pe, &data, names.initrd_path).is_some();
+
+    [
+        (
+            !embeds_kernel,
+            names.kernel_path,
+            names.kernel_hash,
+            InitrdHashMode::Full,
+            true,
+        ),
+        (
+            has_initrd,
+            names.initrd_path,
+            names.initrd_hash,
+            initrd_hash_mode,
+            false,
+        ),
+    ]
+    .into_iter()
+    .filter(|(referenced, _, _, _, _)| *referenced)
+    .map(|(_, path_section, hash_section, hash_mode, kernel)| {
+        let uefi_path = std::str::from_utf8(section(path_section)?)
+            .with_context(|| format!("Malformed {path_section} section in stub {stub:?}"))?;
+        let hash = section(hash_section)?;
+        if hash.len() != hash_algorithm.output_size() {
+            return Err(anyhow::anyhow!(
+                "The {hash_section} section in stub {stub:?} is no {} hash",
+                hash_algorithm.name()
+            ));
+        }
+        Ok(StubReference {
+            path: boot.join(uefi_path.trim_start_matches('\\').replace('\\', "/")),
+            uefi_path: uefi_path.to_owned(),
+            hash: hash.to_vec(),
+            hash_section,
+            hash_mode,
+            hash_algorithm,
+            kernel,
+        })
+    })
+    .collect()
+}
+
+/// Make sure that the kernel and initrd that a stub references have the hashes embedded into it.
+///
+/// This detects files that changed after the stub was assembled, which the stub would refuse to
+/// boot.
+pub fn verify_stub(stub: &Path, boot: &Path) -> Result<()> {
+    for reference in stub_references(stub, boot)? {
+        let matches = reference
+            .matches()
+            .with_context(|| format!("Failed to hash {:?}", reference.path))?;
+        if !matches {
+            return Err(LanzabooteError::HashMismatch {
+                path: reference.path,
+                stub: stub.to_owned(),
+            }
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// Install a new kernel or initrd in place of the ones that a stub references, embed their hashes
+/// and sign the stub again.
+///
+/// This updates a stub without assembling it again. The new kernel is signed and the detached
+/// signature of the new initrd is renewed if the old one had one. Only the hash sections change,
+/// all other sections of the stub are kept as they are. The new hashes cover the same part of the
+/// files as the old ones.
+///
+/// A referenced file without a new source has to match the stub still: it is unknown where a
+/// modified file on the ESP came from, so its hash is not embedded. Returns the files that were
+/// written to the ESP besides the stub.
+pub fn refresh_hashes(
+    stub: &Path,
+    boot: &Path,
+    signer: &SigningKey,
+    kernel: Option<&Path>,
+    initrd: Option<&Path>,
+) -> Result<Vec<PathBuf>> {
+    let mut data = fs::read(stub).with_context(|| format!("Failed to read stub {stub:?}"))?;
+    let pe = PE::parse(&data).with_context(|| format!("Failed to parse stub {stub:?}"))?;
+
+    let references = stub_references(stub, boot)?;
+    for (source, is_kernel, name) in [(kernel, true, "kernel"), (initrd, false, "initrd")] {
+        if source.is_some() && !references.iter().any(|r| r.is_kernel() == is_kernel) {
+            return Err(anyhow::anyhow!(
+                "Stub {stub:?} does not reference a {name} on the ESP"
+            ));
+        }
+    }
+    for reference in references.iter().filter(|reference| {
+        let source = if reference.is_kernel() {
+            kernel
+        } else {
+            initrd
+        };
+        source.is_none()
+    }) {
+        if !reference.matches()? {
+            return Err(anyhow::anyhow!(
+                "{:?} does not match the hash embedded into {stub:?} and its source is unknown, pass the file to install in its place with --kernel or --initrd",
+                reference.path
+            ));
+        }
+    }
+
+    let mut written = Vec::new();
+    let mut replacements = Vec::new();
+    for reference in references {
+        match (reference.is_kernel(), kernel, initrd) {
+            (true, Some(kernel), _) => {
+                utils::atomic_replace(&reference.path, |staging| {
+                    signer.sign_and_copy(kernel, staging)
+                })
+                .with_context(|| format!("Failed to install kernel to {:?}", reference.path))?;
+                utils::set_esp_mode(&reference.path, utils::PUBLIC_FILE_MODE)?;
+                written.push(reference.path.clone());
+            }
+            (false, _, Some(initrd)) => {
+                utils::atomic_replace(&reference.path, |staging| {
+                    fs::copy(initrd, staging)
+                        .map(|_| ())
+                        .with_context(|| format!("Failed to copy {initrd:?}"))
+                })
+                .with_context(|| format!("Failed to install initrd to {:?}", reference.path))?;
+                utils::set_esp_mode(&reference.path, utils::PUBLIC_FILE_MODE)?;
+                written.push(reference.path.clone());
+                let signature = esp::detached_signature_path(&reference.path);
+                if signature.exists() {
+                    signer.sign_detached(&reference.path, &signature)?;
+                    written.push(signature);
+                }
+            }
+            _ => {}
+        }
+
+        let hash = embedded_hash(
+            &reference.path,
+            reference.hash_mode,
+            reference.hash_algorithm,
+        )
+        .with_context(|| format!("Failed to hash {:?}", reference.path))?;
+        let section = pe
+            .sections
+            .iter()
+            .find(|section| {
+                section
+                    .name()
+                    .map_or(false, |name| name == reference.hash_section)
+            })
+            .with_context(|| format!("Stub {stub:?} has no {} section", reference.hash_section))?;
+        if section.virtual_size as usize != hash.len()
+            || (section.size_of_raw_data as usize) < hash.len()
+        {
+            return Err(anyhow::anyhow!(
+                "Malformed {} section in stub {stub:?}",
+                reference.hash_section
+            ));
+        }
+        replacements.push((section.pointer_to_raw_data as usize, hash));
+    }
+    let certificate_table = pe
+        .header
+        .optional_header
+        .and_then(|optional_header| *optional_header.data_directories.get_certificate_table())
+        .filter(|table| table.size > 0);
+
+    // The signature no longer covers the stub, so it is removed before signing again.
+    if let Some(table) = certificate_table {
+        let entry = certificate_table_entry_offset(&data)?;
+        data[entry..entry + 8].fill(0);
+        data.truncate(table.virtual_address as usize);
+    }
+    for (offset, hash) in replacements {
+        data.get_mut(offset..offset + hash.len())
+            .with_context(|| format!("Stub {stub:?} is truncated"))?
+            .copy_from_slice(&hash);
+    }
+
+    let tempdir = utils::tempdir()?;
+    let unsigned = tempdir.path().join("lanzaboote-stub.efi");
+    fs::write(&unsigned, data).with_context(|| format!("Failed to write {unsigned:?}"))?;
+    update_checksum(&unsigned)?;
+
+    utils::atomic_replace(stub, |staging| signer.sign_and_copy(&unsigned, staging))
+        .with_context(|| format!("Failed to sign stub {stub:?}"))?;
+    utils::set_esp_mode(stub, utils::PUBLIC_FILE_MODE)?;
+    Ok(written)
+}
+
+/// Read the kernel command line that is embedded into a stub.
+///
+/// Returns `None` if the stub has no `.cmdline` section, e.g. because it was not assembled by
+/// lanzaboote.
+pub fn stub_cmdline(stub: &Path) -> Result<Option<String>> {
+    let data = fs::read(stub).with_context(|| format!("Failed to read stub {stub:?}"))?;
+    let pe = PE::parse(&data).with_context(|| format!("Failed to parse stub {stub:?}"))?;
+    let names = StubProfile::detect(&pe, &data).section_names();
+    pe_section(&pe, &data, names.cmdline)
+        .map(|cmdline| {
+            let cmdline = std::str::from_utf8(cmdline)
+                .with_context(|| format!("Malformed {} section in stub {stub:?}", names.cmdline))?;
+            Ok(cmdline.trim_end_matches('\0').to_string())
+        })
+        .transpose()
+}
+
+/// Read the version of the kernel that a stub boots, whether it is embedded or referenced.
+///
+/// Returns `None` if the kernel is not a bzImage with a version string or if the referenced
+/// kernel is missing from the partition the stub is installed to.
+pub fn stub_kernel_version(stub: &Path, boot: &Path) -> Result<Option<String>> {
+    let data = fs::read(stub).with_context(|| format!("Failed to read stub {stub:?}"))?;
+    let pe = PE::parse(&data).with_context(|| format!("Failed to parse stub {stub:?}"))?;
+    let names = StubProfile::detect(&pe, &data).section_names();
+
+    if let Some(kernel) = pe_section(&pe, &data, names.linux) {
+        return Ok(generation::bzimage_kernel_version(kernel));
+    }
+
+    let uefi_path = match pe_section(&pe, &data, names.kernel_path) {
+        Some(uefi_path) => std::str::from_utf8(uefi_path)
+            .with_context(|| format!("Malformed {} section in stub {stub:?}", names.kernel_path))?,
+        None => return Ok(None),
+    };
+    let kernel = boot.join(uefi_path.trim_start_matches('\\').replace('\\', "/"));
+    match fs::read(&kernel) {
+        Ok(kernel) => Ok(generation::bzimage_kernel_version(&kernel)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read kernel {kernel:?}")),
+    }
+}
+
+/// The os-release fields that identify a generation in the metadata of its stub.
+const OS_RELEASE_SUMMARY_FIELDS: [&str; 6] = [
+    "ID",
+    "NAME",
+    "PRETTY_NAME",
+    "VERSION",
+    "VERSION_ID",
+    "IMAGE_VERSION",
+];
+
+/// What an installed stub embeds, in a form that can be inspected without parsing the PE binary.
+#[derive(Debug, Serialize)]
+pub struct StubMetadata {
+    pub cmdline: Option<String>,
+    /// The identifying fields of the embedded os-release.
+    pub os_release: BTreeMap<String, String>,
+    pub files: Vec<ReferencedFileMetadata>,
+}
+
+/// A file that a stub references with the hash embedded for it.
+#[derive(Debug, Serialize)]
+pub struct ReferencedFileMetadata {
+    /// The path as it is embedded into the stub.
+    pub path: String,
+    /// The name of the algorithm of the embedded hash, e.g. `sha256`.
+    pub hash_algorithm: &'static str,
+    /// The embedded hash in hexadecimal.
+    pub hash: String,
+    /// The embedded hash again if it is a SHA-256 hash, which is where sidecars written before the
+    /// algorithm was configurable put it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// The number of bytes the hash covers, if it covers only the beginning of the file.
+    pub hashed_bytes: Option<u64>,
+}
 
-    let image_path = tempdir.path().join("lanzaboote-stub.efi");
-    wrap_in_pe(lanzaboote_stub, sections, &image_path)?;
-    Ok(image_path)
+/// Collect what an installed stub embeds.
+pub fn stub_metadata(stub: &Path, boot: &Path) -> Result<StubMetadata> {
+    let data = fs::read(stub).with_context(|| format!("Failed to read stub {stub:?}"))?;
+    let pe = PE::parse(&data).with_context(|| format!("Failed to parse stub {stub:?}"))?;
+    let names = StubProfile::detect(&pe, &data).section_names();
+
+    let os_release = pe_section(&pe, &data, names.os_release)
+        .map(String::from_utf8_lossy)
+        .unwrap_or_default();
+    let os_release = os_release
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .filter(|(key, _)| OS_RELEASE_SUMMARY_FIELDS.contains(key))
+        .map(|(key, value)| (key.to_owned(), value.trim_matches('"').to_owned()))
+        .collect();
+
+    let files = stub_references(stub, boot)?
+        .into_iter()
+        .map(|reference| {
+            let hash: String = reference
+                .hash
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect();
+            ReferencedFileMetadata {
+                hash_algorithm: reference.hash_algorithm.name(),
+                sha256: (reference.hash_algorithm == HashAlgorithm::Sha256).then(|| hash.clone()),
+                hash,
+                hashed_bytes: match reference.hash_mode {
+                    InitrdHashMode::Full => None,
+                    InitrdHashMode::Prefix(length) => Some(length),
+                },
+                path: reference.uefi_path,
+            }
+        })
+        .collect();
+
+    Ok(StubMetadata {
+        cmdline: stub_cmdline(stub)?,
+        os_release,
+        files,
+    })
 }
 
-/// Compute the SHA 256 hash of a file.
-fn file_hash(file: &Path) -> Result<Hash> {
-    Ok(Sha256::digest(fs::read(file)?))
+/// Read the paths of the kernel and initrd that an installed stub references.
+pub fn referenced_files(stub: &Path, boot: &Path) -> Result<Vec<PathBuf>> {
+    Ok(stub_references(stub, boot)?
+        .into_iter()
+        .map(|reference| reference.path)
+        .collect())
 }
 
-/// Take a PE binary stub and attach sections to it.
+/// Make sure that a PE binary does not carry any of the sections that make up a lanzaboote stub.
 ///
-/// The resulting binary is then written to a newly created file at the provided output path.
-fn wrap_in_pe(stub: &Path, sections: Vec<Section>, output: &Path) -> Result<()> {
-    let mut args: Vec<OsString> = sections.iter().flat_map(Section::to_objcopy).collect();
+/// This detects a misconfiguration where a (wrapped) stub is about to be installed in place of
+/// systemd-boot.
+pub fn ensure_no_lanzaboote_sections(path: &Path) -> Result<()> {
+    let data = fs::read(path).with_context(|| format!("Failed to read PE binary {path:?}"))?;
+    let pe = PE::parse(&data).with_context(|| format!("Failed to parse PE binary {path:?}"))?;
 
-    [stub.as_os_str(), output.as_os_str()]
-        .iter()
-        .for_each(|a| args.push(a.into()));
+    let found: Vec<&str> = lanzaboote_sections()
+        .into_iter()
+        .chain([".lzbtver"])
+        .filter(|name| pe_section(&pe, &data, name).is_some())
+        .collect();
 
-    let status = Command::new("objcopy")
-        .args(&args)
-        .status()
-        .context("Failed to run objcopy command")?;
-    if !status.success() {
+    if !found.is_empty() {
         return Err(anyhow::anyhow!(
-            "Failed to wrap in pe with args `{:?}`",
-            &args
+            "{:?} contains the lanzaboote sections {}. Is it a lanzaboote stub instead of systemd-boot?",
+            path,
+            found.join(", ")
         ));
     }
-
     Ok(())
 }
 
-struct Section {
-    name: &'static str,
-    file_path: PathBuf,
-    offset: u64,
+/// The sections of a unified kernel image that systemd-stub measures, in the order it measures
+/// them.
+const MEASURED_SECTIONS: [&str; 9] = [
+    ".linux", ".osrel", ".cmdline", ".initrd", ".splash", ".dtb", ".uname", ".sbat", ".pcrpkey",
+];
+
+/// The PCR values that systemd-stub would extend when booting a stub.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PcrPrediction {
+    /// PCR 11, into which systemd-stub measures the sections of the image.
+    pub pcr11: Hash,
 }
 
-impl Section {
-    /// Create objcopy `-add-section` command line parameters that
-    /// attach the section to a PE file.
-    fn to_objcopy(&self) -> Vec<OsString> {
-        // There is unfortunately no format! for OsString, so we cannot
-        // just format a path.
-        let mut map_str: OsString = format!("{}=", self.name).into();
-        map_str.push(&self.file_path);
+/// The boot phases that systemd-pcrphase measures into PCR 11 after systemd-stub, in the order
+/// they happen.
+const BOOT_PHASES: [&str; 4] = ["enter-initrd", "leave-initrd", "sysinit", "ready"];
 
-        vec![
-            OsString::from("--add-section"),
-            map_str,
-            OsString::from("--change-section-vma"),
-            format!("{}={:#x}", self.name, self.offset).into(),
-        ]
+impl PcrPrediction {
+    /// The values of PCR 11 after every boot phase.
+    ///
+    /// The phases are named by the path that leads to them, e.g. `enter-initrd:leave-initrd`,
+    /// like `systemd-measure --phase` expects them.
+    pub fn phases(&self) -> Vec<(String, Hash)> {
+        let mut pcr = self.pcr11;
+        (1..=BOOT_PHASES.len())
+            .map(|count| {
+                pcr = extend_pcr(pcr, BOOT_PHASES[count - 1].as_bytes());
+                (BOOT_PHASES[..count].join(":"), pcr)
+            })
+            .collect()
     }
 }
 
-fn s(name: &'static str, file_path: impl AsRef<Path>, offset: u64) -> Section {
-    Section {
-        name,
-        file_path: file_path.as_ref().into(),
-        offset,
-    }
+/// Predict the PCR values that systemd-stub extends for the sections embedded into a stub.
+///
+/// This allows computing TPM sealing policies for a generation before booting it. PCR 11 is
+/// assumed to be zero before the stub runs.
+pub fn predict_pcrs(stub: &Path) -> Result<PcrPrediction> {
+    let data = fs::read(stub).with_context(|| format!("Failed to read stub {stub:?}"))?;
+    let pe = PE::parse(&data).with_context(|| format!("Failed to parse stub {stub:?}"))?;
+
+    Ok(PcrPrediction {
+        pcr11: measure_sections(
+            MEASURED_SECTIONS
+                .iter()
+                .filter_map(|name| Some((*name, pe_section(&pe, &data, name)?))),
+        ),
+    })
 }
 
-/// Convert a path to an UEFI path relative to the specified ESP.
-fn esp_relative_uefi_path(esp: &Path, path: &Path) -> Result<String> {
-    let relative_path = path
-        .strip_prefix(esp)
-        .with_context(|| format!("Failed to strip esp prefix: {:?} from: {:?}", esp, path))?;
-    let uefi_path = uefi_path(relative_path)?;
-    Ok(format!("\\{}", &uefi_path))
+/// Extend a zero PCR with the name (including the terminating NUL) and the contents of every
+/// section, like systemd-stub does.
+fn measure_sections<'a>(sections: impl Iterator<Item = (&'a str, &'a [u8])>) -> Hash {
+    sections.fold(Hash::default(), |pcr, (name, data)| {
+        let pcr = extend_pcr(pcr, format!("{name}\0").as_bytes());
+        extend_pcr(pcr, data)
+    })
 }
 
-/// Convert a path to a UEFI string representation.
+/// Extend a SHA-256 PCR with the hash of `data`.
+fn extend_pcr(pcr: Hash, data: &[u8]) -> Hash {
+    Sha256::new()
+        .chain_update(pcr)
+        .chain_update(Sha256::digest(data))
+        .finalize()
+}
+
+/// Read the protocol version that the stub declares in its `.lzbtver` section.
+fn stub_protocol_version(pe: &PE, file_data: &[u8]) -> Result<Option<u32>> {
+    pe_section(pe, file_data, ".lzbtver")
+        .map(|data| -> Result<u32> {
+            let version: [u8; 4] = data
+                .try_into()
+                .context("Malformed .lzbtver section in stub")?;
+            Ok(u32::from_le_bytes(version))
+        })
+        .transpose()
+}
+
+/// Check that the stub implements the protocol version that lzbt speaks.
 ///
-/// This might not _necessarily_ produce a valid UEFI path, since some UEFI implementations might
-/// not support UTF-8 strings. A Rust String, however, is _always_ valid UTF-8.
-fn uefi_path(path: &Path) -> Result<String> {
-    path.to_str()
-        .to_owned()
-        .map(|x| x.replace('/', "\\"))
-        .with_context(|| format!("Failed to convert {:?} to an UEFI path", path))
+/// Lanzaboote stubs that do not declare a version predate the `.lzbtver` section and implement
+/// version 1, so they are rejected. systemd-stub does not declare a version either, but reads the
+/// embedded `.linux`, `.initrd` and `.dtb` sections itself and is accepted.
+fn check_protocol_version(version: Option<u32>, systemd_stub: bool) -> Result<()> {
+    let version = match version {
+        Some(version) => version,
+        None if systemd_stub => return Ok(()),
+        None => 1,
+    };
+    if version != STUB_PROTOCOL_VERSION {
+        return Err(LanzabooteError::InvalidStub {
+            reason: format!(
+                "The stub implements protocol version {version}, but lzbt requires version {STUB_PROTOCOL_VERSION}. Are lzbt and the stub from the same lanzaboote version?"
+            ),
+        }
+        .into());
+    }
+    Ok(())
 }
 
-fn stub_offset(binary: &Path) -> Result<u64> {
-    let pe_binary = fs::read(binary).context("Failed to read PE binary file")?;
-    let pe = PE::parse(&pe_binary).context("Failed to parse PE binary file")?;
+/// Make sure that all sections fit into the address space of a PE image.
+///
+/// The virtual address and size of a PE section are 32-bit values relative to the image base.
+/// objcopy silently truncates section addresses that do not fit, which results in a corrupt PE
+/// binary. Refuse to create such a binary instead.
+fn ensure_sections_fit(image_base: u64, sections: &[Section]) -> Result<()> {
+    for section in sections {
+        let section_end = section
+            .offset
+            .checked_add(file_size(&section.file_path)?)
+            .and_then(|end| end.checked_sub(image_base));
 
-    let image_base = image_base(&pe);
+        if !matches!(section_end, Some(end) if end <= u64::from(u32::MAX)) {
+            return Err(anyhow::anyhow!(
+                "Section {} at {:#x} does not fit into the 32-bit address space of the PE image",
+                section.name,
+                section.offset
+            ));
+        }
+    }
 
-    // The Virtual Memory Address (VMA) is relative to the image base, aka the image base
-    // needs to be added to the virtual address to get the actual (but still virtual address)
-    Ok(u64::from(
-        pe.sections
-            .last()
-            .map(|s| s.virtual_size + s.virtual_address)
-            .expect("Failed to calculate offset"),
-    ) + image_base)
+    Ok(())
 }
 
 fn image_base(pe: &PE) -> u64 {
@@ -169,7 +2251,7 @@ fn image_base(pe: &PE) -> u64 {
         .image_base
 }
 
-fn file_size(path: impl AsRef<Path>) -> Result<u64> {
+pub fn file_size(path: impl AsRef<Path>) -> Result<u64> {
     Ok(fs::metadata(&path)
         .with_context(|| {
             format!(
@@ -184,6 +2266,131 @@ fn file_size(path: impl AsRef<Path>) -> Result<u64> {
 mod tests {
     use super::*;
 
+    /// A minimal PE binary that consists of the headers only.
+    fn minimal_pe() -> Vec<u8> {
+        let mut data = vec![0; 0x200];
+        data[..2].copy_from_slice(b"MZ");
+        data[PE_POINTER_OFFSET..PE_POINTER_OFFSET + 4].copy_from_slice(&0x80u32.to_le_bytes());
+        data[0x80..0x84].copy_from_slice(b"PE\0\0");
+        for (index, byte) in data[0x84..].iter_mut().enumerate() {
+            *byte = index as u8;
+        }
+        data
+    }
+
+    #[test]
+    fn reject_oversized_image() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let stub_size = 1000;
+        let cmdline = tmpdir.write_secure_file("cmdline", [0u8; 100])?;
+        let sections = [s(".cmdline", cmdline, 0x1000)?];
+
+        // A faithful objcopy output has about the size of its inputs.
+        let image = tmpdir.write_secure_file("image.efi", [0u8; 1536])?;
+        ensure_expected_image_size(&image, stub_size, &sections)?;
+
+        // A misbehaving objcopy pads the output excessively.
+        let image = tmpdir.write_secure_file("image.efi", vec![0u8; 1 << 20])?;
+        let error = ensure_expected_image_size(&image, stub_size, &sections).unwrap_err();
+        assert!(error.to_string().contains("is 1048576 bytes"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_correct_checksum() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("image.efi");
+        fs::write(&path, minimal_pe())?;
+        assert!(!verify_checksum(&path)?);
+
+        update_checksum(&path)?;
+        assert!(verify_checksum(&path)?);
+
+        let mut data = fs::read(&path)?;
+        data[0x100] ^= 0xff;
+        fs::write(&path, data)?;
+        assert!(!verify_checksum(&path)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn compute_checksum_of_odd_length_file() {
+        let mut data = minimal_pe();
+        data.push(0x01);
+        let offset = checksum_offset(&data).unwrap();
+        assert_eq!(
+            compute_checksum(&data, offset),
+            compute_checksum(&data[..data.len() - 1], offset) + 2
+        );
+    }
+
+    #[test]
+    fn checksum_of_fixture_stub_matches_objcopy() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let copy = tmpdir.path().join("stub.efi");
+        // objcopy computes the checksum of the PE binaries it writes.
+        let status = Command::new("objcopy")
+            .arg("tests/fixtures/stub.efi")
+            .arg(&copy)
+            .status()?;
+        assert!(status.success());
+
+        let data = fs::read(&copy)?;
+        let offset = checksum_offset(&data)?;
+        let stored = u32::from_le_bytes(data[offset..offset + 4].try_into()?);
+        assert_ne!(stored, 0);
+        assert_eq!(compute_checksum(&data, offset), stored);
+
+        // The fixture itself carries no checksum.
+        let fixture = Path::new("tests/fixtures/stub.efi");
+        assert!(!verify_checksum(fixture)?);
+        let path = tmpdir.path().join("fixture.efi");
+        fs::copy(fixture, &path)?;
+        update_checksum(&path)?;
+        assert!(verify_checksum(&path)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn compute_checksum_at_odd_offset() -> Result<()> {
+        // Move the PE signature of the fixture stub by one byte, so that the checksum field is
+        // not aligned to a word.
+        let fixture = fs::read("tests/fixtures/stub.efi")?;
+        let pe = pe_offset(&fixture)?;
+        let mut data = fixture[..pe].to_vec();
+        data.push(0);
+        data.extend(&fixture[pe..]);
+        data[PE_POINTER_OFFSET..PE_POINTER_OFFSET + 4]
+            .copy_from_slice(&(pe as u32 + 1).to_le_bytes());
+        let offset = checksum_offset(&data)?;
+        assert_eq!(offset % 2, 1);
+
+        // Whatever the field contains does not contribute to the checksum.
+        let checksum = compute_checksum(&data, offset);
+        data[offset..offset + 4].copy_from_slice(&[0xff; 4]);
+        assert_eq!(compute_checksum(&data, offset), checksum);
+
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("stub.efi");
+        fs::write(&path, &data)?;
+        update_checksum(&path)?;
+        assert!(verify_checksum(&path)?);
+        // The bytes around the field are untouched.
+        let updated = fs::read(&path)?;
+        assert_eq!(updated[..offset], data[..offset]);
+        assert_eq!(updated[offset + 4..], data[offset + 4..]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reject_checksum_of_non_pe_file() {
+        assert!(checksum_offset(&[0; 0x100]).is_err());
+    }
+
     #[test]
     fn convert_to_valid_uefi_path_relative_to_esp() {
         let esp = Path::new("esp");
@@ -193,6 +2400,18 @@ mod tests {
         assert_eq!(converted_path, expected_path);
     }
 
+    #[test]
+    fn uefi_path_of_systemd_boot() {
+        let esp_paths = crate::esp::EspPaths::new("/boot", None)
+            .with_architecture(crate::esp::Architecture::X64);
+        assert_eq!(
+            uefi_path_for(&esp_paths.esp, &esp_paths.systemd_boot).unwrap(),
+            "\\EFI\\systemd\\systemd-bootx64.efi"
+        );
+        assert!(uefi_path_for(&esp_paths.esp, Path::new("/etc/systemd-bootx64.efi")).is_err());
+        assert!(uefi_path_for(&esp_paths.esp, Path::new("/boot/../etc/secret")).is_err());
+    }
+
     #[test]
     fn convert_to_valid_uefi_path() {
         let path = Path::new("lanzaboote/is/great.txt");
@@ -200,4 +2419,656 @@ mod tests {
         let expected_path = String::from("lanzaboote\\is\\great.txt");
         assert_eq!(converted_path, expected_path);
     }
+
+    #[test]
+    fn convert_non_ascii_path_to_uefi_path() {
+        let path = Path::new("lanzaboote/ist/großartig.txt");
+        let converted_path = uefi_path(path).unwrap();
+        let expected_path = String::from("lanzaboote\\ist\\großartig.txt");
+        assert_eq!(converted_path, expected_path);
+    }
+
+    #[test]
+    fn reject_uefi_path_outside_of_basic_multilingual_plane() {
+        let path = Path::new("lanzaboote/is/🚀.txt");
+        assert!(uefi_path(path).is_err());
+    }
+
+    #[test]
+    fn reject_non_utf8_uefi_path() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = Path::new(std::ffi::OsStr::from_bytes(b"EFI/nixos/kernel-\xff.efi"));
+        let error = uefi_path(path).unwrap_err().to_string();
+        assert!(error.contains("the component \"kernel-\\xFF.efi\" is not valid UTF-8"));
+    }
+
+    #[test]
+    fn reject_kernel_outside_of_esp_before_hashing() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let esp = Path::new("/boot");
+        let esp_gen_paths = EspGenerationPaths {
+            // Neither file exists. The error must be reported before they are read.
+            kernel: Some(PathBuf::from("/nix/store/kernel/bzImage")),
+            initrd: Some(PathBuf::from("/boot/EFI/nixos/initrd.efi")),
+            lanzaboote_image: PathBuf::from("/boot/EFI/Linux/nixos-generation-1.efi"),
+        };
+
+        // The layout is never consulted because the kernel is rejected first.
+        let stub = StubLayout {
+            path: PathBuf::from("/nonexistent/stub.efi"),
+            offset: 0,
+            image_base: 0,
+            section_alignment: 0x1000,
+            machine: header::COFF_MACHINE_X86_64,
+            size: 0,
+            sbat: None,
+        };
+        let error = lanzaboote_image(
+            &tempdir,
+            &stub,
+            Path::new("/nonexistent/os-release"),
+            &[],
+            &esp_gen_paths,
+            esp,
+            &ImageOptions::default(),
+        )
+        .unwrap_err();
+
+        assert!(error
+            .to_string()
+            .contains("kernel must be copied into the ESP before imaging"));
+        Ok(())
+    }
+
+    #[test]
+    fn check_size_limit_of_image() {
+        let limit = |enforce| ImageSizeLimit {
+            bytes: 0x1000,
+            enforce,
+        };
+
+        assert!(check_image_size_limit(0x1000, limit(true)).is_ok());
+        assert!(check_image_size_limit(0x1001, limit(false)).is_ok());
+        assert!(check_image_size_limit(0x1001, limit(true)).is_err());
+    }
+
+    #[test]
+    fn plan_objcopy_invocation() -> Result<()> {
+        let sections = [
+            s(".osrel", "/tmp/os-release", 0x20000)?,
+            s(".cmdline", "/tmp/kernel-cmdline", 0x20100)?,
+        ];
+
+        let plan = objcopy_plan(
+            &sections,
+            None,
+            Path::new("/stub.efi"),
+            Path::new("/image.efi"),
+        );
+
+        assert_eq!(
+            plan,
+            [
+                "--add-section",
+                ".osrel=/tmp/os-release",
+                "--change-section-vma",
+                ".osrel=0x20000",
+                "--add-section",
+                ".cmdline=/tmp/kernel-cmdline",
+                "--change-section-vma",
+                ".cmdline=0x20100",
+                "/stub.efi",
+                "/image.efi",
+            ]
+            .map(OsString::from)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn plan_native_layout() -> Result<()> {
+        let sections = [
+            s(".osrel", "/tmp/os-release", 0x20000)?,
+            s(".cmdline", "/tmp/kernel-cmdline", 0x20100)?,
+        ];
+
+        assert_eq!(
+            native_plan(&sections, Path::new("/stub.efi")),
+            [
+                "add section .osrel at 0x20000 from /tmp/os-release to /stub.efi",
+                "add section .cmdline at 0x20100 from /tmp/kernel-cmdline to /stub.efi",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn plan_objcopy_invocation_with_explicit_target() -> Result<()> {
+        let sections = [s(".osrel", "/tmp/os-release", 0x20000)?];
+        // Cross-building for aarch64 does not depend on the machine type of the host.
+        let target = machine_type_for_system("aarch64-linux").and_then(objcopy_target);
+
+        let plan = objcopy_plan(
+            &sections,
+            target,
+            Path::new("/stub.efi"),
+            Path::new("/image.efi"),
+        );
+
+        assert_eq!(
+            plan,
+            [
+                "--input-target",
+                "pei-aarch64-little",
+                "--output-target",
+                "pei-aarch64-little",
+                "--add-section",
+                ".osrel=/tmp/os-release",
+                "--change-section-vma",
+                ".osrel=0x20000",
+                "/stub.efi",
+                "/image.efi",
+            ]
+            .map(OsString::from)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn compute_packed_layout() {
+        let layout = compute_layout(0x1000, &[(".osrel", 0x10), (".cmdline", 0x3)], 1);
+
+        assert_eq!(layout, [(".osrel", 0x1000), (".cmdline", 0x1010)]);
+    }
+
+    #[test]
+    fn compute_aligned_layout() {
+        let layout = compute_layout(
+            0x1001,
+            &[(".osrel", 0x10), (".cmdline", 0x1000), (".initrdp", 0x1)],
+            0x1000,
+        );
+
+        assert_eq!(
+            layout,
+            [
+                (".osrel", 0x2000),
+                (".cmdline", 0x3000),
+                (".initrdp", 0x4000)
+            ]
+        );
+    }
+
+    #[test]
+    fn compute_layout_with_varied_sizes() {
+        let layout = compute_layout(0, &[(".a", 0), (".b", 1), (".c", 0x7), (".d", 0x20)], 0x8);
+
+        assert_eq!(layout, [(".a", 0), (".b", 0), (".c", 0x8), (".d", 0x10)]);
+    }
+
+    #[test]
+    fn append_extra_section_after_lanzaboote_sections() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let cmdline = tempdir.write_secure_file("kernel-cmdline", "init=/init")?;
+        let myext = tempdir.write_secure_file("myext", "my extension")?;
+        let mut sections = vec![s(".cmdline", &cmdline, 0x20000)?];
+
+        append_extra_sections(
+            &mut sections,
+            0x1000,
+            &HashMap::from([(String::from(".myext"), myext.clone())]),
+        )?;
+
+        assert_eq!(sections.len(), 2);
+        assert_eq!(sections[1].name, ".myext");
+        assert_eq!(sections[1].file_path, myext);
+        assert_eq!(sections[1].offset, 0x21000);
+        Ok(())
+    }
+
+    #[test]
+    fn reject_colliding_extra_section() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let cmdline = tempdir.write_secure_file("kernel-cmdline", "init=/init")?;
+        let mut sections = vec![s(".cmdline", &cmdline, 0x20000)?];
+
+        let error = append_extra_sections(
+            &mut sections,
+            1,
+            &HashMap::from([(String::from(".cmdline"), cmdline.clone())]),
+        )
+        .unwrap_err();
+
+        assert!(error.to_string().contains(".cmdline"));
+        assert_eq!(sections.len(), 1);
+        Ok(())
+    }
+
+    #[derive(Debug)]
+    struct CmdlineLength;
+
+    impl SectionProvider for CmdlineLength {
+        fn sections(&self, context: &ImageContext) -> Result<Vec<(String, Vec<u8>)>> {
+            let length = context.kernel_cmdline.join(" ").len() as u64;
+            Ok(vec![(
+                String::from(".cmdlen"),
+                length.to_le_bytes().to_vec(),
+            )])
+        }
+    }
+
+    #[test]
+    fn append_provided_section_after_all_other_sections() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let cmdline = tempdir.write_secure_file("kernel-cmdline", "init=/init")?;
+        let myext = tempdir.write_secure_file("myext", "my extension")?;
+        let mut sections = vec![s(".cmdline", &cmdline, 0x20000)?];
+        append_extra_sections(
+            &mut sections,
+            1,
+            &HashMap::from([(String::from(".myext"), myext.clone())]),
+        )?;
+
+        let providers: Vec<Arc<dyn SectionProvider>> = vec![Arc::new(CmdlineLength)];
+        append_provided_sections(
+            &tempdir,
+            &mut sections,
+            1,
+            &providers,
+            &ImageContext {
+                os_release: "ID=nixos\n",
+                kernel_cmdline: &[String::from("init=/init")],
+                machine_type: None,
+            },
+        )?;
+
+        assert_eq!(sections.len(), 3);
+        assert_eq!(sections[2].name, ".cmdlen");
+        assert_eq!(fs::read(&sections[2].file_path)?, 10u64.to_le_bytes());
+        assert_eq!(sections[2].offset, sections[1].offset + file_size(&myext)?);
+        Ok(())
+    }
+
+    #[test]
+    fn reject_provided_section_colliding_with_other_sections() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let cmdline = tempdir.write_secure_file("kernel-cmdline", "init=/init")?;
+        let mut sections = vec![s(".cmdline", &cmdline, 0x20000)?];
+
+        let providers: Vec<Arc<dyn SectionProvider>> =
+            vec![Arc::new(CmdlineLength), Arc::new(CmdlineLength)];
+        let error = append_provided_sections(
+            &tempdir,
+            &mut sections,
+            1,
+            &providers,
+            &ImageContext {
+                os_release: "ID=nixos\n",
+                kernel_cmdline: &[],
+                machine_type: None,
+            },
+        )
+        .unwrap_err();
+
+        assert!(error
+            .to_string()
+            .contains(".cmdlen is provided more than once"));
+        assert_eq!(sections.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn reject_section_name_longer_than_8_bytes() {
+        assert!(validate_section_name(".ninechar").is_err());
+    }
+
+    #[test]
+    fn accept_section_name_of_8_bytes() {
+        assert!(validate_section_name(".initrdl").is_ok());
+    }
+
+    #[test]
+    fn reject_section_name_with_invalid_character() {
+        let error = validate_section_name(".my=ext").unwrap_err();
+        assert!(error.to_string().contains("'='"));
+    }
+
+    #[test]
+    fn prefix_hash_covers_only_base_initrd() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let base_initrd = b"base initrd";
+        let initrd =
+            tempdir.write_secure_file("initrd", [&base_initrd[..], b"secrets"].concat())?;
+
+        let hash = embedded_hash(
+            &initrd,
+            InitrdHashMode::Prefix(base_initrd.len() as u64),
+            HashAlgorithm::Sha256,
+        )?;
+
+        assert_eq!(hash, Sha256::digest(base_initrd).to_vec());
+        assert_ne!(
+            hash,
+            embedded_hash(&initrd, InitrdHashMode::Full, HashAlgorithm::Sha256)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn prefix_hash_fails_for_too_short_initrd() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let initrd = tempdir.write_secure_file("initrd", b"initrd")?;
+
+        assert!(
+            embedded_hash(&initrd, InitrdHashMode::Prefix(1024), HashAlgorithm::Sha256).is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn hash_with_configured_algorithm() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let kernel = tempdir.write_secure_file("kernel", b"kernel")?;
+
+        for algorithm in HashAlgorithm::ALL {
+            let hash = embedded_hash(&kernel, InitrdHashMode::Full, algorithm)?;
+            assert_eq!(hash.len(), algorithm.output_size());
+            assert_eq!(
+                HashAlgorithm::from_name(algorithm.name().as_bytes()),
+                Some(algorithm)
+            );
+        }
+        assert_eq!(
+            embedded_hash(&kernel, InitrdHashMode::Full, HashAlgorithm::Sha512)?,
+            Sha512::digest(b"kernel").to_vec()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn predict_pcr11_from_sections() {
+        let sections = [
+            (".osrel", &b"ID=nixos\n"[..]),
+            (".cmdline", &b"init=/init"[..]),
+        ];
+        assert_eq!(
+            format!("{:x}", measure_sections(sections.into_iter())),
+            "e0e63e072a2ec7c0ba3241b30c9bc56ca1263f25f029e2ae40208f245403d9e0"
+        );
+        assert_eq!(measure_sections(std::iter::empty()), Hash::default());
+    }
+
+    #[test]
+    fn accept_compatible_stub_protocol_version() {
+        assert!(check_protocol_version(Some(STUB_PROTOCOL_VERSION), false).is_ok());
+        assert!(check_protocol_version(None, true).is_ok());
+    }
+
+    #[test]
+    fn reject_incompatible_stub_protocol_version() {
+        let error = check_protocol_version(Some(STUB_PROTOCOL_VERSION + 1), false).unwrap_err();
+        assert!(error.to_string().contains("protocol version"));
+        // Stubs without a version implement version 1.
+        assert!(check_protocol_version(Some(1), false).is_err());
+        assert!(check_protocol_version(None, false).is_err());
+        assert!(matches!(
+            error.downcast_ref::<LanzabooteError>(),
+            Some(LanzabooteError::InvalidStub { .. })
+        ));
+    }
+
+    #[test]
+    fn accept_compatible_machine_type_only_in_permissive_mode() {
+        let x86_64 = header::COFF_MACHINE_X86_64;
+        let x86 = header::COFF_MACHINE_X86;
+
+        assert!(check_machine_type(x86_64, x86, MachineTypePolicy::Permissive).is_ok());
+        let error = check_machine_type(x86_64, x86, MachineTypePolicy::Strict).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<LanzabooteError>(),
+            Some(LanzabooteError::InvalidStub { .. })
+        ));
+    }
+
+    #[test]
+    fn require_pe32_plus_for_64_bit_machine_types() {
+        use goblin::pe::optional_header::{MAGIC_32, MAGIC_64};
+
+        assert!(check_pe_format(header::COFF_MACHINE_X86_64, Some(MAGIC_64)).is_ok());
+        assert!(check_pe_format(header::COFF_MACHINE_X86_64, Some(MAGIC_32)).is_err());
+        assert!(check_pe_format(header::COFF_MACHINE_ARM64, None).is_err());
+        assert!(check_pe_format(header::COFF_MACHINE_X86, Some(MAGIC_32)).is_ok());
+    }
+
+    #[test]
+    fn detect_kernel_machine_type() {
+        let mut arm64_image = vec![0; 0x40];
+        arm64_image[ARM64_IMAGE_MAGIC_OFFSET..].copy_from_slice(b"ARM\x64");
+        assert_eq!(
+            kernel_machine_type(&arm64_image),
+            Some(header::COFF_MACHINE_ARM64)
+        );
+
+        let mut bzimage = vec![0; 0x240];
+        bzimage[BZIMAGE_MAGIC_OFFSET..BZIMAGE_MAGIC_OFFSET + 4].copy_from_slice(b"HdrS");
+        bzimage[BZIMAGE_VERSION_OFFSET..BZIMAGE_VERSION_OFFSET + 2]
+            .copy_from_slice(&0x020fu16.to_le_bytes());
+        bzimage[BZIMAGE_XLOADFLAGS_OFFSET] = XLF_KERNEL_64;
+        assert_eq!(
+            kernel_machine_type(&bzimage),
+            Some(header::COFF_MACHINE_X86_64)
+        );
+
+        let mut elf = b"\x7fELF\x02\x01".to_vec();
+        elf.resize(0x40, 0);
+        elf[0x12..0x14].copy_from_slice(&183u16.to_le_bytes());
+        assert_eq!(kernel_machine_type(&elf), Some(header::COFF_MACHINE_ARM64));
+
+        assert_eq!(kernel_machine_type(&[0; 0x600]), None);
+    }
+
+    #[test]
+    fn reject_kernel_of_other_architecture() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let kernel = tempdir.path().join("Image");
+        let mut arm64_image = vec![0; 0x40];
+        arm64_image[ARM64_IMAGE_MAGIC_OFFSET..].copy_from_slice(b"ARM\x64");
+        fs::write(&kernel, arm64_image)?;
+
+        let arm64 = header::COFF_MACHINE_ARM64;
+        let x86_64 = header::COFF_MACHINE_X86_64;
+        assert!(check_kernel_machine_type(&kernel, arm64, MachineTypePolicy::Strict).is_ok());
+        for policy in [MachineTypePolicy::Strict, MachineTypePolicy::Permissive] {
+            let error = check_kernel_machine_type(&kernel, x86_64, policy).unwrap_err();
+            assert!(error
+                .to_string()
+                .contains("has the machine type 0xaa64, but the stub has the machine type 0x8664"));
+        }
+        // A mismatch is only warned about.
+        assert!(check_kernel_machine_type(&kernel, x86_64, MachineTypePolicy::Warn).is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn accept_exact_machine_type() {
+        let x86_64 = header::COFF_MACHINE_X86_64;
+
+        assert!(check_machine_type(x86_64, x86_64, MachineTypePolicy::Strict).is_ok());
+        assert!(check_machine_type(x86_64, x86_64, MachineTypePolicy::Permissive).is_ok());
+        assert!(check_machine_type(
+            x86_64,
+            header::COFF_MACHINE_ARM64,
+            MachineTypePolicy::Permissive
+        )
+        .is_err());
+        assert!(
+            check_machine_type(x86_64, header::COFF_MACHINE_ARM64, MachineTypePolicy::Warn).is_ok()
+        );
+    }
+
+    #[test]
+    fn select_stub_by_architecture() -> Result<()> {
+        let directory = tempfile::tempdir()?;
+        for architecture in ["x64", "aa64"] {
+            fs::write(
+                directory
+                    .path()
+                    .join(format!("lanzaboote_stub_{architecture}.efi")),
+                architecture,
+            )?;
+        }
+        let source = StubSource::new(directory.path().to_path_buf());
+        assert!(matches!(source, StubSource::Directory(_)));
+
+        for (system, architecture) in [("x86_64-linux", "x64"), ("aarch64-linux", "aa64")] {
+            let stub = source.select(system)?;
+            assert_eq!(fs::read_to_string(stub)?, architecture);
+        }
+
+        let error = source.select("riscv64-linux").unwrap_err().to_string();
+        assert!(error.contains("riscv64"));
+        assert!(error.contains("lanzaboote_stub_riscv64.efi"));
+        assert!(source.select("mips-linux").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn detect_misplaced_section() -> Result<()> {
+        let sections = [
+            s(".osrel", "/tmp/os-release", 0x20000)?,
+            s(".cmdline", "/tmp/kernel-cmdline", 0x20100)?,
+        ];
+
+        let exact = [
+            (".text", 0x1000),
+            (".osrel", 0x20000),
+            (".cmdline", 0x20100),
+        ];
+        assert!(check_section_placement(&exact, &sections).is_ok());
+
+        // objcopy rounded the address of .cmdline up to the section alignment.
+        let rounded = [(".osrel", 0x20000), (".cmdline", 0x21000)];
+        let error = check_section_placement(&rounded, &sections).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains(".cmdline is at 0x21000 instead of 0x20100"));
+
+        let missing = [(".osrel", 0x20000)];
+        assert!(check_section_placement(&missing, &sections).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn attach_sections_to_fixture_stub() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let stub = StubLayout::read(Path::new("tests/fixtures/stub.efi"))?;
+        let contents: [(&str, &[u8]); 6] = [
+            (".osrel", b"ID=lanza\n"),
+            (".cmdline", b"init=/nix/store/init"),
+            (".initrdp", b"\\EFI\\nixos\\initrd.efi"),
+            (".kernelp", b"\\EFI\\nixos\\kernel.efi"),
+            (".initrdh", &[0x11; 32]),
+            (".kernelh", &[0x22; 32]),
+        ];
+        let files = contents
+            .iter()
+            .map(|(name, data)| Ok((*name, tmpdir.write_secure_file(&name[1..], data)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let sections = layout_sections(stub.offset, stub.section_alignment, files)?;
+
+        // Without binutils, only the native writer is tested.
+        let objcopy_available = Command::new("objcopy")
+            .arg("--version")
+            .output()
+            .map_or(false, |output| output.status.success());
+        let writers = [PeWriter::Native]
+            .into_iter()
+            .chain(objcopy_available.then_some(PeWriter::Objcopy));
+
+        for writer in writers {
+            let image = tmpdir.path().join(format!("{writer:?}.efi"));
+            attach_sections(writer, &stub, &sections, None, &image, None)?;
+
+            let data = fs::read(&image)?;
+            let pe = PE::parse(&data)?;
+            for (name, expected) in contents {
+                assert_eq!(
+                    pe_section(&pe, &data, name),
+                    Some(expected),
+                    "{name} attached by {writer:?}"
+                );
+            }
+            assert_eq!(
+                pe_section(&pe, &data, ".text"),
+                Some(&[0x31, 0xc0, 0xc3][..])
+            );
+            // The firmware only loads images whose sections are aligned.
+            assert_eq!(stub.section_alignment, 0x1000);
+            for section in &pe.sections {
+                assert_eq!(
+                    u64::from(section.virtual_address) % stub.section_alignment,
+                    0,
+                    "{:?} attached by {writer:?} is not aligned",
+                    section.name()
+                );
+            }
+            ensure_section_placement(&image, &sections)?;
+            assert!(verify_checksum(&image)?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn report_hash_mismatch_of_referenced_kernel() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let boot = tmpdir.path().join("boot");
+        fs::create_dir_all(boot.join("EFI/nixos"))?;
+        let kernel = boot.join("EFI/nixos/kernel.efi");
+        fs::write(&kernel, "kernel")?;
+
+        let stub = StubLayout::read(Path::new("tests/fixtures/stub.efi"))?;
+        let kernel_path = tmpdir.write_secure_file("kernel-path", "\\EFI\\nixos\\kernel.efi")?;
+        let kernel_hash = tmpdir.write_secure_file(
+            "kernel-hash",
+            embedded_hash(&kernel, InitrdHashMode::Full, HashAlgorithm::Sha256)?,
+        )?;
+        let sections = layout_sections(
+            stub.offset,
+            stub.section_alignment,
+            vec![(".kernelp", kernel_path), (".kernelh", kernel_hash)],
+        )?;
+        let image = tmpdir.path().join("stub.efi");
+        attach_sections(PeWriter::Native, &stub, &sections, None, &image, None)?;
+        verify_stub(&image, &boot)?;
+
+        fs::write(&kernel, "replaced kernel")?;
+        let error = verify_stub(&image, &boot).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<LanzabooteError>(),
+            Some(LanzabooteError::HashMismatch { path, stub: mismatched }) if path == &kernel && mismatched == &image
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn reject_sections_beyond_32_bit_address_space() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let image_base = 0x1_0000_0000;
+        let data = tempdir.write_secure_file("data", [0; 16])?;
+
+        // This section ends exactly at the end of the 32-bit address space.
+        let last_offset = image_base + u64::from(u32::MAX) - 16;
+        let fitting = vec![s(".first", &data, last_offset)?];
+        assert!(ensure_sections_fit(image_base, &fitting).is_ok());
+
+        let overflowing = vec![
+            s(".first", &data, last_offset)?,
+            s(".second", &data, last_offset + 16)?,
+        ];
+        let error = ensure_sections_fit(image_base, &overflowing).unwrap_err();
+        assert!(error.to_string().contains(".second"));
+        Ok(())
+    }
 }
@@ -0,0 +1,466 @@
+use anyhow::{Context, Result};
+use goblin::pe::PE;
+
+/// Size of an entry in the section table.
+const SECTION_HEADER_SIZE: usize = 40;
+/// Size of an entry in the debug directory.
+const DEBUG_DIRECTORY_ENTRY_SIZE: usize = 28;
+
+/// Offsets relative to the PE signature.
+const NUMBER_OF_SECTIONS_OFFSET: usize = 4 + 2;
+const POINTER_TO_SYMBOL_TABLE_OFFSET: usize = 4 + 8;
+const OPTIONAL_HEADER_OFFSET: usize = 4 + 20;
+
+/// Offsets relative to the optional header. They are the same for PE32 and PE32+.
+const SIZE_OF_INITIALIZED_DATA_OFFSET: usize = 8;
+const SIZE_OF_IMAGE_OFFSET: usize = 56;
+const SIZE_OF_HEADERS_OFFSET: usize = 60;
+
+/// Offsets of the data directories relative to the optional header.
+const PE32_DATA_DIRECTORIES_OFFSET: usize = 96;
+const PE32_PLUS_DATA_DIRECTORIES_OFFSET: usize = 112;
+/// Index of the debug directory in the data directories.
+const DEBUG_DIRECTORY_INDEX: usize = 6;
+
+/// Characteristics of the added sections: initialized, read-only data.
+///
+/// This is what objcopy uses for sections added with `--add-section`.
+const SECTION_CHARACTERISTICS: u32 = 0x4000_0040;
+
+/// A section to add to a PE binary.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NewSection<'a> {
+    pub name: &'a str,
+    /// Address of the section relative to the image base.
+    pub virtual_address: u32,
+    pub data: Vec<u8>,
+}
+
+/// Append sections to a PE binary without objcopy.
+///
+/// The virtual addresses of the sections have to be multiples of the section alignment of the
+/// binary. The section headers are added to the end of the section table. If the headers of the
+/// binary have no room left for them, the raw data of all existing sections is moved back, as long
+/// as the headers still end before the address of the first section. The contents of the new
+/// sections are appended to the end of the file. The checksum is left stale.
+pub fn add_sections(stub: &[u8], sections: &[NewSection]) -> Result<Vec<u8>> {
+    let pe = PE::parse(stub).context("Failed to parse PE binary")?;
+    let optional_header = pe
+        .header
+        .optional_header
+        .context("PE binary has no optional header")?;
+    if optional_header
+        .data_directories
+        .get_certificate_table()
+        .as_ref()
+        .map_or(false, |table| table.size > 0)
+    {
+        return Err(anyhow::anyhow!(
+            "Refusing to add sections to a signed PE binary"
+        ));
+    }
+
+    let windows_fields = optional_header.windows_fields;
+    let file_alignment = windows_fields.file_alignment.max(1);
+    let section_alignment = windows_fields.section_alignment.max(1);
+
+    let pe_offset = pe.header.dos_header.pe_pointer as usize;
+    let optional_header_offset = pe_offset + OPTIONAL_HEADER_OFFSET;
+    let section_table =
+        optional_header_offset + usize::from(pe.header.coff_header.size_of_optional_header);
+    let section_table_end =
+        section_table + (pe.sections.len() + sections.len()) * SECTION_HEADER_SIZE;
+
+    let first_raw_data = pe
+        .sections
+        .iter()
+        .filter(|section| section.pointer_to_raw_data > 0 && section.size_of_raw_data > 0)
+        .map(|section| section.pointer_to_raw_data as usize)
+        .min()
+        .unwrap_or(stub.len());
+
+    // The loader maps the headers to the start of the image, so they must end before the first
+    // section begins.
+    let size_of_headers = align_up(section_table_end, file_alignment as usize);
+    let first_virtual_address = pe
+        .sections
+        .iter()
+        .map(|section| section.virtual_address as usize)
+        .min();
+    if let Some(address) = first_virtual_address.filter(|address| size_of_headers > *address) {
+        return Err(anyhow::anyhow!(
+            "The headers with {} sections end at {size_of_headers:#x}, which is past the first section at {address:#x}",
+            pe.sections.len() + sections.len()
+        ));
+    }
+
+    let mut data = stub.to_vec();
+    if size_of_headers > first_raw_data {
+        let shift = align_up(size_of_headers - first_raw_data, file_alignment as usize);
+        shift_raw_data(&mut data, &pe, first_raw_data, shift, section_table)?;
+    }
+    write_u32(
+        &mut data,
+        optional_header_offset + SIZE_OF_HEADERS_OFFSET,
+        u32::try_from(size_of_headers)
+            .context("PE headers are too large")?
+            .max(windows_fields.size_of_headers),
+    );
+
+    let mut size_of_image = windows_fields.size_of_image;
+    let mut size_of_initialized_data = optional_header.standard_fields.size_of_initialized_data;
+    let mut header_offset = section_table + pe.sections.len() * SECTION_HEADER_SIZE;
+    for section in sections {
+        if section.virtual_address % section_alignment != 0 {
+            return Err(anyhow::anyhow!(
+                "Section {} at {:#x} is not aligned to the section alignment {section_alignment:#x}",
+                section.name,
+                section.virtual_address
+            ));
+        }
+        let size = u32::try_from(section.data.len())
+            .with_context(|| format!("Section {} is too large", section.name))?;
+        let size_of_raw_data = align_up(size as usize, file_alignment as usize);
+        let pointer_to_raw_data = if size_of_raw_data > 0 {
+            let pointer = align_up(data.len(), file_alignment as usize);
+            data.resize(pointer, 0);
+            data.extend_from_slice(&section.data);
+            data.resize(pointer + size_of_raw_data, 0);
+            pointer
+        } else {
+            0
+        };
+
+        let mut name = [0; 8];
+        name.get_mut(..section.name.len())
+            .with_context(|| format!("Section name {} is too long", section.name))?
+            .copy_from_slice(section.name.as_bytes());
+
+        let header = &mut data[header_offset..header_offset + SECTION_HEADER_SIZE];
+        header.fill(0);
+        header[..8].copy_from_slice(&name);
+        header[8..12].copy_from_slice(&size.to_le_bytes());
+        header[12..16].copy_from_slice(&section.virtual_address.to_le_bytes());
+        header[16..20].copy_from_slice(&(size_of_raw_data as u32).to_le_bytes());
+        header[20..24].copy_from_slice(&(pointer_to_raw_data as u32).to_le_bytes());
+        header[36..40].copy_from_slice(&SECTION_CHARACTERISTICS.to_le_bytes());
+        header_offset += SECTION_HEADER_SIZE;
+
+        let end = section
+            .virtual_address
+            .checked_add(size)
+            .with_context(|| format!("Section {} does not fit into the image", section.name))?;
+        size_of_image =
+            size_of_image.max(align_up(end as usize, section_alignment as usize) as u32);
+        size_of_initialized_data = size_of_initialized_data.wrapping_add(size_of_raw_data as u32);
+    }
+
+    let number_of_sections = u16::try_from(pe.sections.len() + sections.len())
+        .context("PE binary has too many sections")?;
+    data[pe_offset + NUMBER_OF_SECTIONS_OFFSET..pe_offset + NUMBER_OF_SECTIONS_OFFSET + 2]
+        .copy_from_slice(&number_of_sections.to_le_bytes());
+    write_u32(
+        &mut data,
+        optional_header_offset + SIZE_OF_IMAGE_OFFSET,
+        size_of_image,
+    );
+    write_u32(
+        &mut data,
+        optional_header_offset + SIZE_OF_INITIALIZED_DATA_OFFSET,
+        size_of_initialized_data,
+    );
+
+    Ok(data)
+}
+
+/// Replace the contents of an existing section of a PE binary.
+///
+/// If the new contents fit into the raw data of the section and do not reach into the address
+/// range of the following section, they are written in place. The rest of the raw data is zeroed
+/// and the virtual size is set to the length of the new contents. Otherwise the section is
+/// relocated: its old raw data is zeroed and its header is pointed at the new contents, which are
+/// appended to the end of the file and mapped after the end of the image. The checksum is left
+/// stale.
+pub fn replace_section_data(image: &[u8], name: &str, contents: &[u8]) -> Result<Vec<u8>> {
+    let pe = PE::parse(image).context("Failed to parse PE binary")?;
+    if pe
+        .header
+        .optional_header
+        .and_then(|header| {
+            header
+                .data_directories
+                .get_certificate_table()
+                .as_ref()
+                .copied()
+        })
+        .map_or(false, |table| table.size > 0)
+    {
+        return Err(anyhow::anyhow!(
+            "Refusing to modify sections of a signed PE binary"
+        ));
+    }
+
+    let (index, section) = pe
+        .sections
+        .iter()
+        .enumerate()
+        .find(|(_, section)| section.name().map_or(false, |n| n == name))
+        .with_context(|| format!("PE binary has no {name} section"))?;
+    let size =
+        u32::try_from(contents.len()).with_context(|| format!("Section {name} is too large"))?;
+    let next_section = pe
+        .sections
+        .iter()
+        .map(|other| other.virtual_address)
+        .filter(|address| *address > section.virtual_address)
+        .min();
+    let fits = size <= section.size_of_raw_data
+        && next_section.map_or(true, |next| section.virtual_address + size <= next);
+
+    let mut data = image.to_vec();
+    let start = section.pointer_to_raw_data as usize;
+    let raw_data = data
+        .get_mut(start..start + section.size_of_raw_data as usize)
+        .with_context(|| format!("Section {name} is truncated"))?;
+    raw_data.fill(0);
+
+    let pe_offset = pe.header.dos_header.pe_pointer as usize;
+    let optional_header_offset = pe_offset + OPTIONAL_HEADER_OFFSET;
+    let section_table =
+        optional_header_offset + usize::from(pe.header.coff_header.size_of_optional_header);
+    let header_offset = section_table + index * SECTION_HEADER_SIZE;
+    if fits {
+        raw_data[..contents.len()].copy_from_slice(contents);
+    } else {
+        relocate_section(
+            &mut data,
+            &pe,
+            optional_header_offset,
+            header_offset,
+            contents,
+        )
+        .with_context(|| format!("Failed to relocate section {name}"))?;
+    }
+    write_u32(&mut data, header_offset + 8, size);
+
+    Ok(data)
+}
+
+/// Append `contents` to the end of the file, map them after the end of the image and point the
+/// section header at `header_offset` to them.
+///
+/// The size of the image and of the initialized data are updated. The virtual size is left to the
+/// caller.
+fn relocate_section(
+    data: &mut Vec<u8>,
+    pe: &PE,
+    optional_header_offset: usize,
+    header_offset: usize,
+    contents: &[u8],
+) -> Result<()> {
+    let optional_header = pe
+        .header
+        .optional_header
+        .context("PE binary has no optional header")?;
+    let windows_fields = optional_header.windows_fields;
+    let file_alignment = windows_fields.file_alignment.max(1) as usize;
+    let section_alignment = windows_fields.section_alignment.max(1) as usize;
+
+    let virtual_address = align_up(windows_fields.size_of_image as usize, section_alignment);
+    let size_of_raw_data = align_up(contents.len(), file_alignment);
+    let pointer_to_raw_data = align_up(data.len(), file_alignment);
+    data.resize(pointer_to_raw_data, 0);
+    data.extend_from_slice(contents);
+    data.resize(pointer_to_raw_data + size_of_raw_data, 0);
+
+    let size_of_image = u32::try_from(align_up(
+        virtual_address + contents.len(),
+        section_alignment,
+    ))
+    .context("The section does not fit into the image")?;
+    let old_size_of_raw_data = read_u32(data, header_offset + 16)?;
+    let size_of_initialized_data = optional_header
+        .standard_fields
+        .size_of_initialized_data
+        .wrapping_sub(old_size_of_raw_data)
+        .wrapping_add(size_of_raw_data as u32);
+
+    write_u32(data, header_offset + 12, virtual_address as u32);
+    write_u32(data, header_offset + 16, size_of_raw_data as u32);
+    write_u32(
+        data,
+        header_offset + 20,
+        u32::try_from(pointer_to_raw_data).context("PE binary is too large")?,
+    );
+    write_u32(
+        data,
+        optional_header_offset + SIZE_OF_IMAGE_OFFSET,
+        size_of_image,
+    );
+    write_u32(
+        data,
+        optional_header_offset + SIZE_OF_INITIALIZED_DATA_OFFSET,
+        size_of_initialized_data,
+    );
+
+    Ok(())
+}
+
+/// Move the raw data of all sections (and everything after it) back by `shift` bytes to make
+/// room for more section headers.
+///
+/// Besides the section table, the file offsets in the debug directory and of the COFF symbol
+/// table are updated.
+fn shift_raw_data(
+    data: &mut Vec<u8>,
+    pe: &PE,
+    first_raw_data: usize,
+    shift: usize,
+    section_table: usize,
+) -> Result<()> {
+    let shift_u32 = u32::try_from(shift).context("PE headers are too large")?;
+    let moved = |pointer: u32| pointer as usize >= first_raw_data;
+
+    for (index, section) in pe.sections.iter().enumerate() {
+        if section.pointer_to_raw_data > 0 && moved(section.pointer_to_raw_data) {
+            let offset = section_table + index * SECTION_HEADER_SIZE + 20;
+            write_u32(data, offset, section.pointer_to_raw_data + shift_u32);
+        }
+    }
+
+    let pe_offset = pe.header.dos_header.pe_pointer as usize;
+    let symbol_table = pe.header.coff_header.pointer_to_symbol_table;
+    if symbol_table > 0 && moved(symbol_table) {
+        write_u32(
+            data,
+            pe_offset + POINTER_TO_SYMBOL_TABLE_OFFSET,
+            symbol_table + shift_u32,
+        );
+    }
+
+    if let Some(debug_directory) = debug_directory_offset(data, pe)? {
+        let (offset, size) = debug_directory;
+        for entry in (offset..offset + size).step_by(DEBUG_DIRECTORY_ENTRY_SIZE) {
+            let pointer_offset = entry + 24;
+            let pointer = read_u32(data, pointer_offset)?;
+            if pointer > 0 && moved(pointer) {
+                write_u32(data, pointer_offset, pointer + shift_u32);
+            }
+        }
+    }
+
+    let raw_data = data.split_off(first_raw_data);
+    data.resize(first_raw_data + shift, 0);
+    data.extend(raw_data);
+    Ok(())
+}
+
+/// Locate the debug directory in the file, if the binary has one.
+fn debug_directory_offset(data: &[u8], pe: &PE) -> Result<Option<(usize, usize)>> {
+    let optional_header = match pe.header.optional_header {
+        Some(optional_header) => optional_header,
+        None => return Ok(None),
+    };
+    let data_directories = pe.header.dos_header.pe_pointer as usize
+        + OPTIONAL_HEADER_OFFSET
+        + match optional_header.standard_fields.magic {
+            goblin::pe::optional_header::MAGIC_64 => PE32_PLUS_DATA_DIRECTORIES_OFFSET,
+            _ => PE32_DATA_DIRECTORIES_OFFSET,
+        };
+    if optional_header.windows_fields.number_of_rva_and_sizes as usize <= DEBUG_DIRECTORY_INDEX {
+        return Ok(None);
+    }
+
+    let entry = data_directories + DEBUG_DIRECTORY_INDEX * 8;
+    let (rva, size) = (read_u32(data, entry)?, read_u32(data, entry + 4)?);
+    if size == 0 {
+        return Ok(None);
+    }
+    let section = pe
+        .sections
+        .iter()
+        .find(|section| {
+            rva >= section.virtual_address
+                && rva + size <= section.virtual_address + section.size_of_raw_data
+        })
+        .context("The debug directory is outside of all sections")?;
+    let offset = (rva - section.virtual_address + section.pointer_to_raw_data) as usize;
+    if offset + size as usize > data.len() {
+        return Err(anyhow::anyhow!("The debug directory is truncated"));
+    }
+    Ok(Some((offset, size as usize)))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    Ok(u32::from_le_bytes(
+        data.get(offset..offset + 4)
+            .context("PE binary is truncated")?
+            .try_into()?,
+    ))
+}
+
+fn write_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Round `value` up to the next multiple of `alignment`.
+pub fn align_up(value: usize, alignment: usize) -> usize {
+    (value + alignment - 1) / alignment * alignment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_up_to_file_alignment() {
+        assert_eq!(align_up(0, 0x200), 0);
+        assert_eq!(align_up(1, 0x200), 0x200);
+        assert_eq!(align_up(0x200, 0x200), 0x200);
+    }
+
+    #[test]
+    fn grow_the_headers_up_to_the_first_section() -> Result<()> {
+        let stub = std::fs::read("tests/fixtures/stub.efi")?;
+        let new_sections = |count: u32| {
+            (0..count)
+                .map(|i| NewSection {
+                    name: ".extra",
+                    virtual_address: 0x2000 + i * 0x1000,
+                    data: vec![0xaa; 16],
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // The headers of the fixture only have room for two more section headers, so the raw data
+        // is moved back.
+        let data = add_sections(&stub, &new_sections(3))?;
+        let pe = PE::parse(&data)?;
+        assert_eq!(pe.sections.len(), 4);
+        assert_eq!(
+            pe.header
+                .optional_header
+                .unwrap()
+                .windows_fields
+                .size_of_headers,
+            0x400
+        );
+        assert_eq!(pe.sections[0].pointer_to_raw_data, 0x400);
+        assert_eq!(&data[0x400..0x600], &stub[0x200..0x400]);
+
+        // The first section of the fixture starts at 0x1000, which the headers must not reach.
+        assert!(add_sections(&stub, &new_sections(100)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reject_binaries_that_are_not_pe() {
+        let section = NewSection {
+            name: ".osrel",
+            virtual_address: 0x1000,
+            data: b"ID=lanza\n".to_vec(),
+        };
+        assert!(add_sections(b"not a PE binary", &[section]).is_err());
+        assert!(replace_section_data(b"not a PE binary", ".sbat", b"sbat").is_err());
+    }
+}
@@ -0,0 +1,286 @@
+use std::fmt;
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::esp::EspGenerationPaths;
+use crate::generation::Generation;
+use crate::pe;
+
+/// A summary of what an installation wrote to the ESP.
+///
+/// The report is serialized to JSON and handed to the post-install hook.
+#[derive(Debug, Serialize)]
+pub struct InstallReport {
+    pub esp: PathBuf,
+    pub generations: Vec<InstalledGeneration>,
+    pub warnings: Vec<String>,
+}
+
+impl InstallReport {
+    pub fn new(esp: &Path) -> Self {
+        Self {
+            esp: esp.to_path_buf(),
+            generations: Vec::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Warn if less than `percentage` percent of the ESP are free after the installation.
+    ///
+    /// This is only informational, so that generations can be pruned before the next
+    /// installation fails.
+    pub fn check_free_space(&mut self, free: u64, total: u64, percentage: u8) {
+        if free.saturating_mul(100) < total.saturating_mul(u64::from(percentage)) {
+            let warning = format!(
+                "Only {free} of {total} bytes are free on the ESP, which is below the threshold of {percentage}%"
+            );
+            println!("Warning: {warning}");
+            self.warnings.push(warning);
+        }
+    }
+}
+
+/// Something that is told about the phases of an installation as they start, e.g. to show the
+/// progress of the installation.
+pub trait ProgressObserver: fmt::Debug + Send + Sync {
+    /// Called when `phase` (e.g. "generation 3" or "collecting garbage") starts.
+    fn phase_started(&self, phase: &str);
+}
+
+/// How long the phases of an installation took.
+///
+/// Printed at the end of the installation if enabled, so that slow steps can be found without
+/// attaching a profiler. The observer, if any, is told about every phase as it starts.
+#[derive(Debug)]
+pub struct Timings {
+    enabled: bool,
+    observer: Option<Arc<dyn ProgressObserver>>,
+    current: Option<(String, Instant)>,
+    phases: Vec<(String, Duration)>,
+}
+
+impl Timings {
+    pub fn new(enabled: bool, observer: Option<Arc<dyn ProgressObserver>>) -> Self {
+        Self {
+            enabled,
+            observer,
+            current: None,
+            phases: Vec::new(),
+        }
+    }
+
+    /// End the current phase (if any) and start the next one.
+    pub fn start(&mut self, phase: impl Into<String>) {
+        self.end();
+        let phase = phase.into();
+        if let Some(observer) = &self.observer {
+            observer.phase_started(&phase);
+        }
+        if self.enabled {
+            self.current = Some((phase, Instant::now()));
+        }
+    }
+
+    /// End the current phase, so that the time until the next one starts is not counted.
+    pub fn end(&mut self) {
+        if let Some((phase, started)) = self.current.take() {
+            self.phases.push((phase, started.elapsed()));
+        }
+    }
+
+    /// Print the durations of all phases.
+    pub fn print(&mut self) {
+        self.end();
+        for (phase, duration) in &self.phases {
+            println!("Timing: {phase} took {:.3}s", duration.as_secs_f64());
+        }
+    }
+}
+
+/// How often an installation did the operations whose cost grows with the size of the ESP.
+///
+/// Printed together with the timings. Unlike the timings, the counts do not depend on how busy
+/// the machine is, so they show whether an installation reads the ESP more often than necessary.
+#[derive(Debug, Default)]
+pub struct OperationCounts {
+    manifest_reads: AtomicU64,
+    esp_scans: AtomicU64,
+    stub_layout_reads: AtomicU64,
+    stub_parses: AtomicU64,
+    garbage_collections: AtomicU64,
+}
+
+impl OperationCounts {
+    /// The manifest of the ESP was read.
+    pub fn manifest_read(&self) {
+        self.manifest_reads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The stubs in the `EFI/Linux` directory were listed.
+    pub fn esp_scan(&self) {
+        self.esp_scans.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A lanzaboote stub that images are assembled from was read and parsed.
+    pub fn stub_layout_read(&self) {
+        self.stub_layout_reads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// An installed stub was parsed to find the files it references.
+    pub fn stub_parse(&self) {
+        self.stub_parses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The files that are not in use anymore were collected.
+    pub fn garbage_collection(&self) {
+        self.garbage_collections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn print(&self) {
+        for (operation, count) in [
+            ("manifest reads", &self.manifest_reads),
+            ("ESP scans", &self.esp_scans),
+            ("lanzaboote stub reads", &self.stub_layout_reads),
+            ("stub parses", &self.stub_parses),
+            ("garbage collections", &self.garbage_collections),
+        ] {
+            println!("Count: {operation}: {}", count.load(Ordering::Relaxed));
+        }
+    }
+}
+
+/// A generation (or specialisation) that was installed to the ESP.
+#[derive(Debug, Serialize)]
+pub struct InstalledGeneration {
+    pub version: u64,
+    pub specialisation: Option<String>,
+    pub stub: PathBuf,
+    /// The kernel on the ESP, if it is not embedded into the stub.
+    pub kernel: Option<PathBuf>,
+    /// The initrd on the ESP, if the generation uses one.
+    pub initrd: Option<PathBuf>,
+    /// Bytes on the ESP used by the stub, kernel and initrd together.
+    ///
+    /// Kernels and initrds shared with other generations are counted for every generation.
+    pub size: u64,
+    pub warnings: Vec<String>,
+}
+
+impl InstalledGeneration {
+    pub fn new(generation: &Generation, esp_gen_paths: &EspGenerationPaths) -> Result<Self> {
+        let size = esp_gen_paths
+            .to_iter()
+            .map(pe::file_size)
+            .sum::<Result<u64>>()?;
+
+        Ok(Self {
+            version: generation.version(),
+            specialisation: generation.is_specialised().map(|name| name.to_string()),
+            stub: esp_gen_paths.lanzaboote_image.clone(),
+            kernel: esp_gen_paths.kernel.clone(),
+            initrd: esp_gen_paths.initrd.clone(),
+            size,
+            warnings: Vec::new(),
+        })
+    }
+
+    /// Describe a generation whose stub a previous installation installed and that was kept.
+    pub fn kept(generation: &Generation, stub: &Path, boot: &Path) -> Result<Self> {
+        let references = pe::stub_references(stub, boot)?;
+        let referenced = |kernel: bool| {
+            references
+                .iter()
+                .find(|reference| reference.is_kernel() == kernel)
+                .map(|reference| reference.path.clone())
+        };
+        let size = iter::once(stub)
+            .chain(references.iter().map(|reference| reference.path.as_path()))
+            .map(pe::file_size)
+            .sum::<Result<u64>>()?;
+
+        Ok(Self {
+            version: generation.version(),
+            specialisation: generation.is_specialised().map(|name| name.to_string()),
+            stub: stub.to_path_buf(),
+            kernel: referenced(true),
+            initrd: referenced(false),
+            size,
+            warnings: Vec::new(),
+        })
+    }
+
+    /// Warn if the generation uses more than `threshold` bytes on the ESP.
+    ///
+    /// This is only informational. The generation is installed regardless.
+    pub fn check_size(&mut self, threshold: u64) {
+        if self.size > threshold {
+            let warning = format!(
+                "Generation {} uses {} bytes on the ESP, which exceeds the threshold of {} bytes",
+                self.version, self.size, threshold
+            );
+            println!("Warning: {warning}");
+            self.warnings.push(warning);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn installed_generation(size: u64) -> InstalledGeneration {
+        InstalledGeneration {
+            version: 1,
+            specialisation: None,
+            stub: PathBuf::from("EFI/Linux/nixos-generation-1.efi"),
+            kernel: Some(PathBuf::from("EFI/nixos/kernel.efi")),
+            initrd: Some(PathBuf::from("EFI/nixos/initrd.efi")),
+            size,
+            warnings: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn warn_about_nearly_full_esp() {
+        let mut report = InstallReport::new(Path::new("/boot"));
+
+        report.check_free_space(9, 100, 10);
+
+        assert_eq!(report.warnings.len(), 1);
+        assert!(report.warnings[0].contains("below the threshold of 10%"));
+    }
+
+    #[test]
+    fn do_not_warn_about_esp_with_enough_free_space() {
+        let mut report = InstallReport::new(Path::new("/boot"));
+
+        report.check_free_space(10, 100, 10);
+
+        assert!(report.warnings.is_empty());
+    }
+
+    #[test]
+    fn warn_about_generation_exceeding_threshold() {
+        let mut generation = installed_generation(100 * 1024 * 1024);
+
+        generation.check_size(64 * 1024 * 1024);
+
+        assert_eq!(generation.warnings.len(), 1);
+        assert!(generation.warnings[0].contains("exceeds the threshold"));
+    }
+
+    #[test]
+    fn do_not_warn_about_small_generation() {
+        let mut generation = installed_generation(32 * 1024 * 1024);
+
+        generation.check_size(64 * 1024 * 1024);
+
+        assert!(generation.warnings.is_empty());
+    }
+}
@@ -0,0 +1,126 @@
+use anyhow::{Context, Result};
+
+/// The first entry of every SBAT section, which declares the version of the format.
+pub const SBAT_HEADER: &str =
+    "sbat,1,SBAT Version,sbat,1,https://github.com/rhboot/shim/blob/main/SBAT.md";
+
+/// The number of fields of an SBAT entry: component name, generation, vendor name, vendor package
+/// name, vendor version and vendor URL.
+const SBAT_FIELDS: usize = 6;
+
+/// Validate an SBAT entry given as a line of CSV.
+///
+/// Revocation works on the component name and generation, so the generation has to be a number.
+pub fn parse_entry(entry: &str) -> Result<String> {
+    let entry = entry.trim();
+    let fields: Vec<&str> = entry.split(',').collect();
+    if fields.len() != SBAT_FIELDS || entry.contains('\n') || entry.contains('"') {
+        return Err(anyhow::anyhow!(
+            "SBAT entry {entry:?} does not consist of {SBAT_FIELDS} comma separated fields"
+        ));
+    }
+    if fields[0].is_empty() {
+        return Err(anyhow::anyhow!(
+            "SBAT entry {entry:?} has no component name"
+        ));
+    }
+    fields[1]
+        .parse::<u32>()
+        .with_context(|| format!("The generation of SBAT entry {entry:?} is not a number"))?;
+    Ok(entry.to_owned())
+}
+
+/// The entry that describes lzbt, which assembled the image.
+pub fn lanzaboote_entry() -> String {
+    format!(
+        "lanzaboote,1,Lanzaboote,lanzaboote,{},https://github.com/nix-community/lanzaboote",
+        env!("CARGO_PKG_VERSION")
+    )
+}
+
+/// The entry that describes the kernel of a generation.
+pub fn kernel_entry(kernel_version: &str) -> String {
+    format!("linux.nixos,1,NixOS,linux,{kernel_version},https://nixos.org")
+}
+
+/// Merge entries into the SBAT metadata of a stub.
+///
+/// The entries of the stub describe its own code and are kept. An entry replaces an earlier one
+/// of the same component, so that the generation of a component can be raised. The header always
+/// comes first.
+pub fn merge(existing: Option<&str>, entries: &[String]) -> String {
+    let mut merged: Vec<&str> = Vec::new();
+    let lines = existing
+        .into_iter()
+        .flat_map(|existing| existing.lines())
+        .map(|line| line.trim_end_matches('\0').trim())
+        .filter(|line| !line.is_empty())
+        .chain(entries.iter().map(String::as_str));
+    for line in lines {
+        let component = component_name(line);
+        if component == component_name(SBAT_HEADER) {
+            continue;
+        }
+        match merged
+            .iter_mut()
+            .find(|entry| component_name(entry) == component)
+        {
+            Some(entry) => *entry = line,
+            None => merged.push(line),
+        }
+    }
+
+    let mut sbat = format!("{SBAT_HEADER}\n");
+    for entry in merged {
+        sbat.push_str(entry);
+        sbat.push('\n');
+    }
+    sbat
+}
+
+fn component_name(entry: &str) -> &str {
+    entry.split(',').next().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_with_sbat_of_stub() {
+        let stub = format!(
+            "{SBAT_HEADER}\nsystemd,1,The systemd Developers,systemd,252,https://systemd.io/\n\0\0"
+        );
+        let entries = [
+            lanzaboote_entry(),
+            kernel_entry("6.1.1"),
+            String::from("systemd,2,The systemd Developers,systemd,252,https://systemd.io/"),
+        ];
+
+        let merged = merge(Some(&stub), &entries);
+
+        assert_eq!(
+            merged,
+            format!(
+                "{SBAT_HEADER}\nsystemd,2,The systemd Developers,systemd,252,https://systemd.io/\n{}\nlinux.nixos,1,NixOS,linux,6.1.1,https://nixos.org\n",
+                lanzaboote_entry()
+            )
+        );
+    }
+
+    #[test]
+    fn add_header_to_stub_without_sbat() {
+        assert_eq!(
+            merge(None, &[lanzaboote_entry()]),
+            format!("{SBAT_HEADER}\n{}\n", lanzaboote_entry())
+        );
+    }
+
+    #[test]
+    fn reject_malformed_entries() {
+        assert!(parse_entry("shim,3,UEFI shim,shim,1,https://github.com/rhboot/shim").is_ok());
+        assert!(parse_entry("shim,3,UEFI shim").is_err());
+        assert!(parse_entry("shim,three,UEFI shim,shim,1,https://github.com/rhboot/shim").is_err());
+        assert!(parse_entry(",3,UEFI shim,shim,1,https://github.com/rhboot/shim").is_err());
+    }
+}
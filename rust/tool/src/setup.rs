@@ -0,0 +1,585 @@
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{Context, Result};
+use time::OffsetDateTime;
+
+use crate::anti_rollback;
+use crate::signature::{self, KeyPair, SigningKey};
+use crate::status::{
+    self, SecureBootState, EFI_CERT_X509_GUID, EFI_GLOBAL_VARIABLE, IMAGE_SECURITY_DATABASE,
+};
+use crate::utils::{self, SecureTempDirExt};
+
+/// Attributes of the Secure Boot variables: non-volatile, accessible at boot time and at runtime,
+/// and only writable with a time based authenticated update.
+const SECURE_BOOT_VARIABLE_ATTRIBUTES: u32 = 0x01 | 0x02 | 0x04 | 0x20;
+
+/// Revision of `WIN_CERTIFICATE` that UEFI expects.
+const WIN_CERT_REVISION: u16 = 0x0200;
+/// Type of `WIN_CERTIFICATE_UEFI_GUID`.
+const WIN_CERT_TYPE_EFI_GUID: u16 = 0x0ef1;
+/// Certificate type `EFI_CERT_TYPE_PKCS7_GUID` of an authenticated variable update.
+const EFI_CERT_TYPE_PKCS7_GUID: &str = "4aafd29d-68df-49ee-8aa9-347d375665a7";
+
+/// How long the generated certificates are valid. The firmware does not check the validity
+/// period, but tools that verify signatures with the certificates do.
+const CERTIFICATE_VALIDITY_DAYS: u32 = 365 * 20;
+
+/// One of the Secure Boot keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecureBootKey {
+    /// The Platform Key, which authorizes updates of the KEK.
+    Pk,
+    /// The Key Exchange Key, which authorizes updates of db.
+    Kek,
+    /// The key in the signature database that lzbt signs the boot files with.
+    Db,
+}
+
+impl SecureBootKey {
+    /// The keys in the order in which they are enrolled. Enrolling the PK ends setup mode, so it
+    /// goes last.
+    pub const ENROLLMENT_ORDER: [SecureBootKey; 3] = [Self::Db, Self::Kek, Self::Pk];
+
+    /// The name of the key, which is also the name of its UEFI variable.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Pk => "PK",
+            Self::Kek => "KEK",
+            Self::Db => "db",
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Self::Pk => "Platform Key",
+            Self::Kek => "Key Exchange Key",
+            Self::Db => "Signature Database Key",
+        }
+    }
+
+    fn vendor(self) -> &'static str {
+        match self {
+            Self::Pk | Self::Kek => EFI_GLOBAL_VARIABLE,
+            Self::Db => IMAGE_SECURITY_DATABASE,
+        }
+    }
+
+    /// The key that signs updates of this key. The PK signs itself.
+    fn signer(self) -> Self {
+        match self {
+            Self::Pk | Self::Kek => Self::Pk,
+            Self::Db => Self::Kek,
+        }
+    }
+}
+
+/// The layout of the key files, which is the same as the one of sbctl.
+///
+/// Every key lives in its own directory below `keys`, e.g. `keys/db/db.key` with the certificate
+/// `keys/db/db.pem`. The GUID that owns the enrolled certificates is stored in `GUID`.
+#[derive(Debug, Clone)]
+pub struct PkiBundle {
+    pub root: PathBuf,
+}
+
+impl PkiBundle {
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    fn dir(&self, key: SecureBootKey) -> PathBuf {
+        self.root.join("keys").join(key.name())
+    }
+
+    fn file(&self, key: SecureBootKey, extension: &str) -> PathBuf {
+        self.dir(key).join(format!("{}.{extension}", key.name()))
+    }
+
+    pub fn private_key(&self, key: SecureBootKey) -> PathBuf {
+        self.file(key, "key")
+    }
+
+    pub fn certificate(&self, key: SecureBootKey) -> PathBuf {
+        self.file(key, "pem")
+    }
+
+    /// The `EFI_SIGNATURE_LIST` with the certificate.
+    pub fn signature_list(&self, key: SecureBootKey) -> PathBuf {
+        self.file(key, "esl")
+    }
+
+    /// The authenticated update of the UEFI variable with the signature list.
+    pub fn authenticated_update(&self, key: SecureBootKey) -> PathBuf {
+        self.file(key, "auth")
+    }
+
+    fn guid(&self) -> PathBuf {
+        self.root.join("GUID")
+    }
+
+    fn key_pair_exists(&self, key: SecureBootKey) -> bool {
+        self.private_key(key).exists() || self.certificate(key).exists()
+    }
+}
+
+/// What `setup` did.
+#[derive(Debug, Default)]
+pub struct SetupReport {
+    /// Whether the keys were created or already existed.
+    pub created: bool,
+    pub owner: String,
+    /// The signature lists and authenticated updates that were written.
+    pub exported: Vec<PathBuf>,
+    /// The keys that were enrolled into the firmware.
+    pub enrolled: Vec<SecureBootKey>,
+    /// The minimum security version that the anti-rollback protection starts with, if it was
+    /// created or already existed and is owned by the db key.
+    pub minimum_security_version: Option<u64>,
+}
+
+/// Create the PK, KEK and db key pairs, unless all of them exist already.
+///
+/// If `export` is set, the signature lists (`.esl`) and authenticated updates (`.auth`) of the
+/// keys are written next to them, e.g. to enroll them from the firmware setup. If `efivars` is
+/// given, the keys are enrolled via efivarfs, which needs the firmware to be in setup mode. The
+/// minimum security version of the anti-rollback protection is created at the same time, signed
+/// with the db key, so that nobody else can create it first.
+pub fn setup(
+    bundle: &PkiBundle,
+    common_name: &str,
+    export: bool,
+    efivars: Option<&Path>,
+) -> Result<SetupReport> {
+    // Fail before creating any keys, so that setup can simply be run again.
+    if let Some(efivars) = efivars {
+        let state = status::secure_boot_state(efivars)?;
+        if state != SecureBootState::SetupMode {
+            return Err(anyhow::anyhow!(
+                "Refusing to enroll the keys because the firmware is not in setup mode (Secure Boot is {state}). Clear the Platform Key in the firmware setup first."
+            ));
+        }
+    }
+
+    let existing: Vec<_> = SecureBootKey::ENROLLMENT_ORDER
+        .into_iter()
+        .filter(|key| bundle.key_pair_exists(*key))
+        .collect();
+    let created = match existing.len() {
+        0 => {
+            for key in SecureBootKey::ENROLLMENT_ORDER {
+                generate_key_pair(bundle, key, common_name)?;
+            }
+            true
+        }
+        3 => false,
+        _ => {
+            return Err(anyhow::anyhow!(
+                "Refusing to overwrite the existing keys {} in {:?}",
+                existing
+                    .iter()
+                    .map(|key| key.name())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                bundle.root
+            ))
+        }
+    };
+    let owner = owner_guid(bundle)?;
+
+    let mut report = SetupReport {
+        created,
+        owner: owner.clone(),
+        ..SetupReport::default()
+    };
+    if !export && efivars.is_none() {
+        return Ok(report);
+    }
+
+    if let Some(efivars) = efivars {
+        let db = SecureBootKey::Db;
+        let signer = SigningKey::new(KeyPair::new(
+            &bundle.certificate(db),
+            &bundle.private_key(db),
+        ));
+        report.minimum_security_version = Some(anti_rollback::create_minimum_security_version(
+            efivars, &signer,
+        )?);
+    }
+
+    let timestamp = OffsetDateTime::now_utc();
+    for key in SecureBootKey::ENROLLMENT_ORDER {
+        let certificate = fs::read(bundle.certificate(key))
+            .with_context(|| format!("Failed to read the certificate of {}", key.name()))?;
+        let list = signature_list(
+            &parse_guid(&owner)?,
+            &signature::certificate_der(&certificate)?,
+        );
+        let update = authenticated_update(bundle, key, &list, timestamp)?;
+
+        if export {
+            for (path, contents) in [
+                (bundle.signature_list(key), &list),
+                (bundle.authenticated_update(key), &update),
+            ] {
+                fs::write(&path, contents).with_context(|| format!("Failed to write {path:?}"))?;
+                report.exported.push(path);
+            }
+        }
+        if let Some(efivars) = efivars {
+            write_efi_variable(
+                efivars,
+                key.name(),
+                key.vendor(),
+                SECURE_BOOT_VARIABLE_ATTRIBUTES,
+                &update,
+            )
+            .with_context(|| format!("Failed to enroll {}", key.name()))?;
+            report.enrolled.push(key);
+        }
+    }
+
+    Ok(report)
+}
+
+/// Generate a self-signed RSA key pair with openssl.
+///
+/// The directory of the key is only accessible by its owner, so that the private key is never
+/// readable by anyone else, not even while openssl writes it.
+fn generate_key_pair(bundle: &PkiBundle, key: SecureBootKey, common_name: &str) -> Result<()> {
+    let dir = bundle.dir(key);
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&dir)
+        .with_context(|| format!("Failed to create {dir:?}"))?;
+    utils::set_mode(&dir, 0o700)?;
+
+    let output = Command::new("openssl")
+        .args([
+            "req", "-new", "-x509", "-newkey", "rsa:4096", "-sha256", "-nodes",
+        ])
+        .args(["-days", &CERTIFICATE_VALIDITY_DAYS.to_string()])
+        .args([
+            "-subj",
+            &format!("/CN={common_name} {}/", key.description()),
+        ])
+        .arg("-keyout")
+        .arg(bundle.private_key(key))
+        .arg("-out")
+        .arg(bundle.certificate(key))
+        .output()
+        .context("Failed to run openssl")?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "Failed to generate the {}: {}",
+            key.description(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    utils::set_mode(&bundle.private_key(key), utils::PRIVATE_FILE_MODE)
+}
+
+/// Read the GUID that owns the enrolled certificates, or create one.
+fn owner_guid(bundle: &PkiBundle) -> Result<String> {
+    let path = bundle.guid();
+    if path.exists() {
+        let guid = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read owner GUID {path:?}"))?
+            .trim()
+            .to_owned();
+        parse_guid(&guid).with_context(|| format!("Invalid owner GUID in {path:?}"))?;
+        return Ok(guid);
+    }
+
+    let mut bytes = [0; 16];
+    getrandom::getrandom(&mut bytes).context("Failed to generate owner GUID")?;
+    // Version 4 (random), variant 1.
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+    let guid = format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    );
+    fs::write(&path, &guid).with_context(|| format!("Failed to write owner GUID {path:?}"))?;
+    Ok(guid)
+}
+
+/// Encode a GUID like `8be4df61-93ca-11d2-aa0d-00e098032b8c` in its binary (mixed endian)
+/// representation, in which the first three groups are little endian.
+pub fn parse_guid(guid: &str) -> Result<[u8; 16]> {
+    let groups: Vec<&str> = guid.split('-').collect();
+    let lengths: Vec<usize> = groups.iter().map(|group| group.len()).collect();
+    if lengths != [8, 4, 4, 4, 12] {
+        return Err(anyhow::anyhow!("{guid:?} is not a GUID"));
+    }
+
+    let mut bytes = Vec::with_capacity(16);
+    for (index, group) in groups.iter().enumerate() {
+        let mut group_bytes = (0..group.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&group[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .with_context(|| format!("{guid:?} is not a GUID"))?;
+        if index < 3 {
+            group_bytes.reverse();
+        }
+        bytes.extend(group_bytes);
+    }
+    Ok(bytes.try_into().expect("A GUID has 16 bytes"))
+}
+
+/// Build an `EFI_SIGNATURE_LIST` with a single X.509 certificate (in DER encoding).
+fn signature_list(owner: &[u8; 16], certificate: &[u8]) -> Vec<u8> {
+    let signature_size = 16 + certificate.len() as u32;
+    let mut list = EFI_CERT_X509_GUID.to_vec();
+    list.extend((28 + signature_size).to_le_bytes());
+    list.extend(0u32.to_le_bytes());
+    list.extend(signature_size.to_le_bytes());
+    list.extend(owner);
+    list.extend(certificate);
+    list
+}
+
+/// Encode a time as `EFI_TIME` in UTC.
+fn efi_time(time: OffsetDateTime) -> [u8; 16] {
+    let time = time.to_offset(time::UtcOffset::UTC);
+    let mut efi_time = [0; 16];
+    efi_time[..2].copy_from_slice(&(time.year() as u16).to_le_bytes());
+    efi_time[2] = u8::from(time.month());
+    efi_time[3] = time.day();
+    efi_time[4] = time.hour();
+    efi_time[5] = time.minute();
+    efi_time[6] = time.second();
+    // The nanoseconds, the time zone and the daylight saving time flags stay zero.
+    efi_time
+}
+
+/// Build an `EFI_VARIABLE_AUTHENTICATION_2` update of the variable of `key` with `list`, signed
+/// by the key that authorizes updates of it.
+fn authenticated_update(
+    bundle: &PkiBundle,
+    key: SecureBootKey,
+    list: &[u8],
+    timestamp: OffsetDateTime,
+) -> Result<Vec<u8>> {
+    let signer = key.signer();
+    time_based_authenticated_update(
+        key.name(),
+        key.vendor(),
+        SECURE_BOOT_VARIABLE_ATTRIBUTES,
+        list,
+        timestamp,
+        |signed| {
+            cms_sign(
+                signed,
+                &bundle.private_key(signer),
+                &bundle.certificate(signer),
+            )
+        },
+    )
+}
+
+/// Build an `EFI_VARIABLE_AUTHENTICATION_2` update that writes `data` to a time-based
+/// authenticated variable.
+///
+/// `sign` makes a detached signature of the data it is given and returns it as a DER encoded
+/// PKCS#7 `ContentInfo`.
+pub fn time_based_authenticated_update(
+    name: &str,
+    vendor: &str,
+    attributes: u32,
+    data: &[u8],
+    timestamp: OffsetDateTime,
+    sign: impl FnOnce(&[u8]) -> Result<Vec<u8>>,
+) -> Result<Vec<u8>> {
+    let timestamp = efi_time(timestamp);
+
+    // The signature covers the name (in UTF-16 without the terminating NUL), the vendor GUID,
+    // the attributes and the time stamp of the variable as well as its new contents.
+    let mut signed: Vec<u8> = name
+        .encode_utf16()
+        .flat_map(|unit| unit.to_le_bytes())
+        .collect();
+    signed.extend(parse_guid(vendor)?);
+    signed.extend(attributes.to_le_bytes());
+    signed.extend(timestamp);
+    signed.extend(data);
+
+    let signature = pkcs7_signed_data(&sign(&signed)?)?;
+
+    let mut update = timestamp.to_vec();
+    let certificate_length = u32::try_from(4 + 2 + 2 + 16 + signature.len())
+        .context("The signature of the update is too large")?;
+    update.extend(certificate_length.to_le_bytes());
+    update.extend(WIN_CERT_REVISION.to_le_bytes());
+    update.extend(WIN_CERT_TYPE_EFI_GUID.to_le_bytes());
+    update.extend(parse_guid(EFI_CERT_TYPE_PKCS7_GUID)?);
+    update.extend(signature);
+    update.extend(data);
+    Ok(update)
+}
+
+/// Make a detached PKCS#7 signature with openssl, which is returned as a DER encoded
+/// `ContentInfo`.
+fn cms_sign(data: &[u8], private_key: &Path, certificate: &Path) -> Result<Vec<u8>> {
+    let tempdir = utils::tempdir()?;
+    let data_file = tempdir.write_secure_file("signed-data", data)?;
+    let output = Command::new("openssl")
+        .args(["cms", "-sign", "-binary", "-noattr", "-md", "sha256"])
+        .args(["-outform", "DER"])
+        .arg("-in")
+        .arg(&data_file)
+        .arg("-signer")
+        .arg(certificate)
+        .arg("-inkey")
+        .arg(private_key)
+        .output()
+        .context("Failed to run openssl")?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "Failed to sign with {private_key:?}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(output.stdout)
+}
+
+/// Extract the `SignedData` from a DER encoded PKCS#7 `ContentInfo`, which is what UEFI expects
+/// in an authenticated variable update.
+fn pkcs7_signed_data(content_info: &[u8]) -> Result<Vec<u8>> {
+    let (header, _) = der_element(content_info, 0x30).context("Malformed ContentInfo")?;
+    let content = &content_info[header..];
+    let (header, length) = der_element(content, 0x06).context("Malformed content type")?;
+    let content = &content[header + length..];
+    let (header, length) = der_element(content, 0xa0).context("Malformed content")?;
+    content
+        .get(header..header + length)
+        .map(<[u8]>::to_vec)
+        .context("Truncated content")
+}
+
+/// Parse the header of a DER element with the expected tag and return the lengths of the header
+/// and of the contents.
+fn der_element(data: &[u8], tag: u8) -> Result<(usize, usize)> {
+    match data {
+        [actual, ..] if *actual != tag => Err(anyhow::anyhow!(
+            "Expected tag {tag:#04x}, found {actual:#04x}"
+        )),
+        [_, length, ..] if *length < 0x80 => Ok((2, usize::from(*length))),
+        [_, length, rest @ ..] if (0x81..=0x84).contains(length) => {
+            let octets = usize::from(length & 0x7f);
+            let length = rest
+                .get(..octets)
+                .context("Truncated length")?
+                .iter()
+                .fold(0, |value, octet| value << 8 | usize::from(*octet));
+            Ok((2 + octets, length))
+        }
+        [_, _, ..] => Err(anyhow::anyhow!("Unsupported length encoding")),
+        _ => Err(anyhow::anyhow!("Truncated element")),
+    }
+}
+
+/// Write a UEFI variable via efivarfs.
+///
+/// The attributes and the contents have to be written at once.
+pub fn write_efi_variable(
+    efivars: &Path,
+    name: &str,
+    vendor: &str,
+    attributes: u32,
+    data: &[u8],
+) -> Result<()> {
+    let path = efivars.join(format!("{name}-{vendor}"));
+    if path.exists() {
+        make_mutable(&path)?;
+    }
+
+    let mut contents = attributes.to_le_bytes().to_vec();
+    contents.extend(data);
+    fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .mode(0o644)
+        .open(&path)
+        .and_then(|mut file| file.write_all(&contents))
+        .with_context(|| format!("Failed to write UEFI variable {path:?}"))
+}
+
+/// Delete a UEFI variable via efivarfs.
+pub fn remove_efi_variable(efivars: &Path, name: &str, vendor: &str) -> Result<()> {
+    let path = efivars.join(format!("{name}-{vendor}"));
+    make_mutable(&path)?;
+    fs::remove_file(&path).with_context(|| format!("Failed to remove UEFI variable {path:?}"))
+}
+
+/// efivarfs marks existing variables immutable, so that they are not removed by accident, which
+/// has to be undone before they are changed.
+fn make_mutable(path: &Path) -> Result<()> {
+    let status = Command::new("chattr")
+        .arg("-i")
+        .arg(path)
+        .status()
+        .context("Failed to run chattr")?;
+    if !status.success() {
+        return Err(anyhow::anyhow!("Failed to make {path:?} writable"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_guid_in_mixed_endian() -> Result<()> {
+        assert_eq!(
+            parse_guid(EFI_CERT_TYPE_PKCS7_GUID)?,
+            [
+                0x9d, 0xd2, 0xaf, 0x4a, 0xdf, 0x68, 0xee, 0x49, 0x8a, 0xa9, 0x34, 0x7d, 0x37, 0x56,
+                0x65, 0xa7
+            ]
+        );
+        assert!(parse_guid("8be4df61-93ca-11d2-aa0d").is_err());
+        assert!(parse_guid("8be4df61-93ca-11d2-aa0d-00e098032bxx").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn extract_signed_data_from_content_info() -> Result<()> {
+        // ContentInfo { contentType: 1.2.840.113549.1.7.2, content: [0] { SEQUENCE { 0x05 } } }
+        let content_info = [
+            0x30, 0x12, 0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x02, 0xa0,
+            0x05, 0x30, 0x03, 0x02, 0x01, 0x05,
+        ];
+        assert_eq!(
+            pkcs7_signed_data(&content_info)?,
+            [0x30, 0x03, 0x02, 0x01, 0x05]
+        );
+        assert!(pkcs7_signed_data(&content_info[..16]).is_err());
+        assert!(pkcs7_signed_data(&[0x31, 0x00]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn encode_time_in_utc() {
+        let time = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+        assert_eq!(
+            efi_time(time),
+            [0xe7, 0x07, 11, 14, 22, 13, 20, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+        );
+    }
+}
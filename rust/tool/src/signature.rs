@@ -1,13 +1,112 @@
-use std::ffi::OsString;
-use std::io::Write;
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
 use anyhow::{Context, Result};
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
+use serde::Serialize;
 
+use crate::error::LanzabooteError;
+use crate::signing_request;
+use crate::utils;
+
+/// Something that makes Secure Boot signatures of PE binaries and detached signatures of other
+/// files.
+///
+/// The private key does not need to be accessible to lzbt. It may be stored on a hardware token
+/// or on another machine.
+pub trait Signer: Send + Sync {
+    /// The certificate of the key, which the signatures are verified with.
+    fn public_key(&self) -> &Path;
+
+    /// Sign the PE binary `from` and write the signed binary to `to`.
+    fn sign(&self, from: &Path, to: &Path) -> Result<()>;
+
+    /// Write a detached CMS signature of an arbitrary file (e.g. an initrd) in DER encoding.
+    fn sign_detached(&self, file: &Path, signature: &Path) -> Result<()>;
+}
+
+/// A signer together with how it is used during an installation.
+pub struct SigningKey {
+    signer: Box<dyn Signer>,
+    /// How often a signature that does not verify is made again. Without retries, signatures are
+    /// not verified after signing.
+    signing_retries: u32,
+}
+
+impl SigningKey {
+    pub fn new(signer: impl Signer + 'static) -> Self {
+        Self {
+            signer: Box::new(signer),
+            signing_retries: 0,
+        }
+    }
+
+    /// Verify every signature right after signing and sign again up to `retries` times if it does
+    /// not verify.
+    ///
+    /// Signers backed by hardware tokens occasionally produce signatures that do not verify.
+    pub fn with_signing_retries(self, retries: u32) -> Self {
+        Self {
+            signing_retries: retries,
+            ..self
+        }
+    }
+
+    pub fn public_key(&self) -> &Path {
+        self.signer.public_key()
+    }
+
+    pub fn sign_and_copy(&self, from: &Path, to: &Path) -> Result<()> {
+        if self.signing_retries == 0 {
+            return self.signer.sign(from, to);
+        }
+
+        let attempts = self.signing_retries + 1;
+        let mut attempt = 1;
+        loop {
+            // Every attempt signs the source again and verifies the freshly written output.
+            self.signer.sign(from, to)?;
+            match verify_signature(to, self.public_key()) {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt == attempts => {
+                    return Err(e.context(format!(
+                        "The signature of {from:?} did not verify after {attempts} attempts"
+                    )))
+                }
+                Err(e) => println!(
+                    "Warning: the signature of {} does not verify (attempt {attempt} of {attempts}), signing again: {e:#}",
+                    from.display()
+                ),
+            }
+            attempt += 1;
+        }
+    }
+
+    /// Write a detached signature of a file.
+    ///
+    /// Unlike the Secure Boot signatures of PE binaries, the firmware never checks this
+    /// signature. It allows verifying files independently of the stubs that embed their hashes.
+    pub fn sign_detached(&self, file: &Path, signature: &Path) -> Result<()> {
+        utils::atomic_replace(signature, |staging| {
+            self.signer.sign_detached(file, staging)
+        })
+    }
+}
+
+/// A private key in a PEM file.
 pub struct KeyPair {
     pub private_key: PathBuf,
     pub public_key: PathBuf,
+    /// In-memory files that back the key paths if the keys were provided in memory.
+    memory_files: Vec<File>,
 }
 
 impl KeyPair {
@@ -15,32 +114,440 @@ impl KeyPair {
         Self {
             public_key: public_key.into(),
             private_key: private_key.into(),
+            memory_files: Vec::new(),
         }
     }
 
-    pub fn sign_and_copy(&self, from: &Path, to: &Path) -> Result<()> {
-        let args: Vec<OsString> = vec![
-            OsString::from("--key"),
-            self.private_key.clone().into(),
-            OsString::from("--cert"),
-            self.public_key.clone().into(),
-            from.as_os_str().to_owned(),
-            OsString::from("--output"),
-            to.as_os_str().to_owned(),
-        ];
-
-        let output = Command::new("sbsign").args(&args).output()?;
-
-        if !output.status.success() {
-            std::io::stderr()
-                .write_all(&output.stderr)
-                .context("Failed to write output of sbsign to stderr")?;
+    /// Create a key pair from PEM encoded keys in memory.
+    ///
+    /// The keys are never written to a file system. Instead, they are stored in anonymous
+    /// in-memory files that sbsign and openssl open via `/dev/fd`. No other child process of
+    /// lzbt inherits them.
+    pub fn from_pem(public_key: &[u8], private_key: &[u8]) -> Result<Self> {
+        let (public_key_path, public_key_file) = memory_file("lzbt-public-key", public_key)?;
+        let (private_key_path, private_key_file) = memory_file("lzbt-private-key", private_key)?;
+
+        Ok(Self {
+            public_key: public_key_path,
+            private_key: private_key_path,
+            memory_files: vec![public_key_file, private_key_file],
+        })
+    }
+}
+
+impl Signer for KeyPair {
+    fn public_key(&self) -> &Path {
+        &self.public_key
+    }
+
+    fn sign(&self, from: &Path, to: &Path) -> Result<()> {
+        sbsign(
+            None,
+            self.private_key.as_os_str(),
+            &self.public_key,
+            from,
+            to,
+            &self.memory_files,
+        )
+    }
+
+    fn sign_detached(&self, file: &Path, signature: &Path) -> Result<()> {
+        openssl_cms_sign(
+            None,
+            self.private_key.as_os_str(),
+            &self.public_key,
+            file,
+            signature,
+            &self.memory_files,
+        )
+    }
+}
+
+/// A key on a PKCS#11 token (e.g. a YubiKey or an HSM) that is used via the pkcs11 engine of
+/// OpenSSL.
+pub struct Pkcs11Signer {
+    public_key: PathBuf,
+    /// The PKCS#11 URI of the private key, e.g. `pkcs11:token=db;object=db;type=private`.
+    key_uri: String,
+}
+
+impl Pkcs11Signer {
+    pub fn new(public_key: &Path, key_uri: String) -> Self {
+        Self {
+            public_key: public_key.into(),
+            key_uri,
+        }
+    }
+}
+
+impl Signer for Pkcs11Signer {
+    fn public_key(&self) -> &Path {
+        &self.public_key
+    }
+
+    fn sign(&self, from: &Path, to: &Path) -> Result<()> {
+        sbsign(
+            Some(PKCS11_ENGINE),
+            OsStr::new(&self.key_uri),
+            &self.public_key,
+            from,
+            to,
+            &[],
+        )
+    }
+
+    fn sign_detached(&self, file: &Path, signature: &Path) -> Result<()> {
+        openssl_cms_sign(
+            Some(PKCS11_ENGINE),
+            OsStr::new(&self.key_uri),
+            &self.public_key,
+            file,
+            signature,
+            &[],
+        )
+    }
+}
+
+/// The OpenSSL engine that provides access to PKCS#11 tokens.
+const PKCS11_ENGINE: &str = "pkcs11";
+
+/// A signing server that is reached via a Unix socket, so that the private key never has to be
+/// on the machine that installs.
+///
+/// Every file is signed over a new connection. lzbt sends a JSON header line (e.g.
+/// `{"kind":"authenticode","size":1024}`), followed by `size` bytes of the file. The server
+/// answers with a DER encoded detached signature and closes the connection:
+///
+/// - For `authenticode`, the file is an unsigned PE binary and the signature is the PKCS#7
+///   signature that `sbsign --detached` makes. lzbt attaches it to the binary.
+/// - For `detached`, the signature is a CMS signature like the one that
+///   `openssl cms -sign -binary -noattr -outform DER` makes.
+pub struct RemoteSigner {
+    public_key: PathBuf,
+    socket: PathBuf,
+}
+
+/// The header of a request to a signing server.
+#[derive(Serialize)]
+struct RemoteSigningRequest {
+    kind: &'static str,
+    size: u64,
+}
+
+impl RemoteSigner {
+    pub fn new(public_key: &Path, socket: &Path) -> Self {
+        Self {
+            public_key: public_key.into(),
+            socket: socket.into(),
+        }
+    }
+
+    /// Send a file to the signing server and write the signature it answers with to `signature`.
+    fn request_signature(&self, kind: &'static str, file: &Path, signature: &Path) -> Result<()> {
+        let contents = fs::read(file).with_context(|| format!("Failed to read {file:?}"))?;
+        let mut header = serde_json::to_vec(&RemoteSigningRequest {
+            kind,
+            size: contents.len() as u64,
+        })?;
+        header.push(b'\n');
+
+        let mut stream = UnixStream::connect(&self.socket).with_context(|| {
+            format!("Failed to connect to the signing server {:?}", self.socket)
+        })?;
+        stream
+            .write_all(&header)
+            .and_then(|()| stream.write_all(&contents))
+            .and_then(|()| stream.shutdown(Shutdown::Write))
+            .with_context(|| format!("Failed to send {file:?} to the signing server"))?;
+
+        let mut response = Vec::new();
+        stream
+            .read_to_end(&mut response)
+            .context("Failed to receive the signature from the signing server")?;
+        if response.is_empty() {
             return Err(anyhow::anyhow!(
-                "Failed to sign file using sbsign with args `{:?}`",
-                &args
+                "The signing server {:?} refused to sign {file:?}",
+                self.socket
             ));
         }
+        fs::write(signature, response)
+            .with_context(|| format!("Failed to write signature {signature:?}"))
+    }
+}
+
+impl Signer for RemoteSigner {
+    fn public_key(&self) -> &Path {
+        &self.public_key
+    }
+
+    fn sign(&self, from: &Path, to: &Path) -> Result<()> {
+        fs::copy(from, to).with_context(|| format!("Failed to copy {from:?} to {to:?}"))?;
+
+        let tempdir = utils::tempdir()?;
+        let signature = tempdir.path().join("signature");
+        self.request_signature("authenticode", to, &signature)?;
+        signing_request::import_signed(to, &signature)
+    }
+
+    fn sign_detached(&self, file: &Path, signature: &Path) -> Result<()> {
+        self.request_signature("detached", file, signature)
+    }
+}
+
+/// Sign a PE binary with sbsign. With an `engine`, `key` is passed to the engine instead of
+/// being read as a file. sbsign inherits `key_files`, so that it can open them via `/dev/fd`.
+fn sbsign(
+    engine: Option<&str>,
+    key: &OsStr,
+    cert: &Path,
+    from: &Path,
+    to: &Path,
+    key_files: &[File],
+) -> Result<()> {
+    let mut args: Vec<OsString> = Vec::new();
+    if let Some(engine) = engine {
+        args.extend([OsString::from("--engine"), OsString::from(engine)]);
+    }
+    args.extend([
+        OsString::from("--key"),
+        key.to_owned(),
+        OsString::from("--cert"),
+        cert.as_os_str().to_owned(),
+        from.as_os_str().to_owned(),
+        OsString::from("--output"),
+        to.as_os_str().to_owned(),
+    ]);
+
+    let mut command = Command::new("sbsign");
+    command.args(&args);
+    inherit_files(&mut command, key_files);
+    let output = command.output()?;
+
+    if !output.status.success() {
+        std::io::stderr()
+            .write_all(&output.stderr)
+            .context("Failed to write output of sbsign to stderr")?;
+        return Err(LanzabooteError::SigningFailed { args }.into());
+    }
+
+    Ok(())
+}
+
+/// Write a detached CMS signature with openssl. With an `engine`, `key` is passed to the engine
+/// instead of being read as a file. openssl inherits `key_files`, so that it can open them via
+/// `/dev/fd`.
+fn openssl_cms_sign(
+    engine: Option<&str>,
+    key: &OsStr,
+    cert: &Path,
+    file: &Path,
+    signature: &Path,
+    key_files: &[File],
+) -> Result<()> {
+    let mut command = Command::new("openssl");
+    inherit_files(&mut command, key_files);
+    command.args(["cms", "-sign", "-binary", "-noattr", "-outform", "DER"]);
+    if let Some(engine) = engine {
+        command.args(["-engine", engine, "-keyform", "engine"]);
+    }
+    let output = command
+        .arg("-in")
+        .arg(file)
+        .arg("-signer")
+        .arg(cert)
+        .arg("-inkey")
+        .arg(key)
+        .arg("-out")
+        .arg(signature)
+        .output()
+        .context("Failed to run openssl")?;
+    if !output.status.success() {
+        std::io::stderr()
+            .write_all(&output.stderr)
+            .context("Failed to write output of openssl to stderr")?;
+        return Err(anyhow::anyhow!("Failed to sign {file:?}"));
+    }
+    Ok(())
+}
+
+/// Check a detached signature written by `KeyPair::sign_detached` against `public_key`.
+pub fn verify_detached_signature(file: &Path, signature: &Path, public_key: &Path) -> Result<()> {
+    let output = Command::new("openssl")
+        .args(["cms", "-verify", "-binary", "-inform", "DER"])
+        .args(["-partial_chain", "-purpose", "any", "-out", "/dev/null"])
+        .arg("-in")
+        .arg(signature)
+        .arg("-content")
+        .arg(file)
+        .arg("-CAfile")
+        .arg(public_key)
+        .output()
+        .context("Failed to run openssl")?;
+
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "The signature {signature:?} of {file:?} does not match the key of {public_key:?}"
+        ));
+    }
+    Ok(())
+}
+
+/// Check that a PE binary is signed with the key of the certificate `public_key`.
+pub fn verify_signature(path: &Path, public_key: &Path) -> Result<()> {
+    let output = Command::new("sbverify")
+        .arg("--cert")
+        .arg(public_key)
+        .arg(path)
+        .output()
+        .context("Failed to run sbverify")?;
+
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "{path:?} is not signed with the key of {public_key:?}"
+        ));
+    }
+    Ok(())
+}
+
+/// Decode the DER encoding of the first certificate in a PEM file.
+pub fn certificate_der(pem: &[u8]) -> Result<Vec<u8>> {
+    let pem = std::str::from_utf8(pem).context("Certificate is not PEM encoded")?;
+    let base64 = pem
+        .split_once("-----BEGIN CERTIFICATE-----")
+        .and_then(|(_, rest)| rest.split_once("-----END CERTIFICATE-----"))
+        .map(|(base64, _)| base64)
+        .context("Failed to find a certificate in the PEM file")?;
+    utils::decode_base64(base64).context("Malformed base64 in PEM certificate")
+}
+
+/// Encode a DER encoded certificate as PEM.
+pub fn certificate_pem(der: &[u8]) -> String {
+    let base64 = utils::encode_base64(der);
+    let mut pem = String::from("-----BEGIN CERTIFICATE-----\n");
+    for line in base64.as_bytes().chunks(64) {
+        pem.push_str(std::str::from_utf8(line).expect("base64 is ASCII"));
+        pem.push('\n');
+    }
+    pem.push_str("-----END CERTIFICATE-----\n");
+    pem
+}
+
+/// Check whether the certificate `issuer` issued the certificate `certificate` (both in DER
+/// encoding).
+///
+/// The firmware accepts a binary whose signing certificate was issued by a certificate in db,
+/// which does not have to be a self-signed root. Like the firmware, the validity period is not
+/// checked. A certificate that openssl cannot parse is not the issuer of anything.
+pub fn is_issued_by(certificate: &[u8], issuer: &[u8]) -> Result<bool> {
+    let tempdir = utils::tempdir()?;
+    let certificate_path = tempdir.path().join("certificate.pem");
+    let issuer_path = tempdir.path().join("issuer.pem");
+    fs::write(&certificate_path, certificate_pem(certificate))
+        .context("Failed to write certificate")?;
+    fs::write(&issuer_path, certificate_pem(issuer)).context("Failed to write certificate")?;
+
+    let output = Command::new("openssl")
+        .arg("verify")
+        .arg("-partial_chain")
+        .arg("-no_check_time")
+        .arg("-no-CApath")
+        .arg("-CAfile")
+        .arg(&issuer_path)
+        .arg(&certificate_path)
+        .output()
+        .context("Failed to run openssl")?;
+    Ok(output.status.success())
+}
+
+/// Store data in an anonymous in-memory file.
+///
+/// Returns the file and a path under which child processes can open it. The file descriptor is
+/// close-on-exec, so that only the child processes that are passed to [`inherit_files`] get it,
+/// and not e.g. the hooks or objcopy.
+fn memory_file(name: &str, contents: &[u8]) -> Result<(PathBuf, File)> {
+    let name = CString::new(name).context("Invalid in-memory file name")?;
+    let fd = memfd_create(&name, MemFdCreateFlag::MFD_CLOEXEC)
+        .context("Failed to create in-memory file")?;
+    // SAFETY: memfd_create returned a new file descriptor that is not owned by anything else.
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    file.write_all(contents)
+        .context("Failed to write to in-memory file")?;
+
+    Ok((PathBuf::from(format!("/dev/fd/{fd}")), file))
+}
+
+/// Let the child process of `command` inherit `files` although they are close-on-exec.
+fn inherit_files(command: &mut Command, files: &[File]) {
+    if files.is_empty() {
+        return;
+    }
+    let fds: Vec<RawFd> = files.iter().map(AsRawFd::as_raw_fd).collect();
+    // SAFETY: The closure runs between fork and exec and only calls fcntl, which is
+    // async-signal-safe. It does not allocate.
+    unsafe {
+        command.pre_exec(move || {
+            for &fd in &fds {
+                fcntl(fd, FcntlArg::F_SETFD(FdFlag::empty()))
+                    .map_err(|e| io::Error::from_raw_os_error(e as i32))?;
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_pem_certificate() -> Result<()> {
+        let pem = b"-----BEGIN CERTIFICATE-----\nTWFu\nTWE=\n-----END CERTIFICATE-----\n";
+        assert_eq!(certificate_der(pem)?, b"ManMa");
+        assert!(
+            certificate_der(b"-----BEGIN CERTIFICATE-----\n!\n-----END CERTIFICATE-----").is_err()
+        );
+        assert!(certificate_der(b"no certificate").is_err());
+        assert_eq!(
+            certificate_der(certificate_pem(b"ManMa").as_bytes())?,
+            b"ManMa"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn report_failed_signing() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let key_pair = KeyPair::new(
+            Path::new("tests/fixtures/uefi-keys/db.pem"),
+            &tmpdir.path().join("missing.key"),
+        );
+
+        let error = key_pair
+            .sign(
+                Path::new("tests/fixtures/stub.efi"),
+                &tmpdir.path().join("signed.efi"),
+            )
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<LanzabooteError>(),
+            Some(LanzabooteError::SigningFailed { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn memory_files_are_only_inherited_on_request() -> Result<()> {
+        let (path, file) = memory_file("lzbt-test-key", b"secret")?;
+
+        let inherited = Command::new("cat").arg(&path).output()?;
+        assert!(!inherited.status.success());
 
+        let mut command = Command::new("cat");
+        command.arg(&path);
+        inherit_files(&mut command, std::slice::from_ref(&file));
+        let inherited = command.output()?;
+        assert!(inherited.status.success());
+        assert_eq!(inherited.stdout, b"secret");
         Ok(())
     }
 }
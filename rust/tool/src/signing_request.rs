@@ -0,0 +1,140 @@
+use std::ffi::OsString;
+use std::fs;
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::pe;
+
+/// A stub that is to be signed on a separate (e.g. air-gapped) host.
+///
+/// The request is serialized to JSON so that it can travel to the signing host together with the
+/// unsigned stub. The signing host produces a detached signature (e.g. with `sbsign --detached`)
+/// that covers `digest`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SigningRequest {
+    /// The unsigned stub, relative to the directory of the request. Both are kept in the same
+    /// directory, wherever they are moved to.
+    pub stub: PathBuf,
+    /// The hex encoded SHA-256 Authenticode digest of the stub.
+    pub digest: String,
+}
+
+/// Write an unsigned copy of `stub` to `out` and describe what needs to be signed.
+///
+/// A signature the stub already carries is removed from the copy. The request is meant to be
+/// stored next to `out`.
+pub fn export_for_signing(stub: &Path, out: &Path) -> Result<SigningRequest> {
+    let name = out
+        .file_name()
+        .with_context(|| format!("{out:?} is not a file name"))?;
+    fs::copy(stub, out).with_context(|| format!("Failed to copy {stub:?} to {out:?}"))?;
+
+    let data = fs::read(out).with_context(|| format!("Failed to read stub {out:?}"))?;
+    if pe::is_signed(&data)? {
+        run_sbattach(&[OsString::from("--remove"), out.as_os_str().to_owned()])?;
+    }
+
+    Ok(SigningRequest {
+        stub: PathBuf::from(name),
+        digest: stub_digest(out)?,
+    })
+}
+
+/// Attach a detached signature that was created for a signing request to its stub.
+///
+/// The stub must not have changed since it was exported and the signature has to cover its
+/// digest. Otherwise, the firmware would refuse to boot the signed stub. Returns the signed stub.
+pub fn import_signed(request: &Path, signature: &Path) -> Result<PathBuf> {
+    let data =
+        fs::read(request).with_context(|| format!("Failed to read signing request {request:?}"))?;
+    let parsed: SigningRequest = serde_json::from_slice(&data)
+        .with_context(|| format!("Failed to parse signing request {request:?}"))?;
+    if !parsed
+        .stub
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(anyhow!(
+            "The stub {:?} of the signing request {request:?} is not relative to the request",
+            parsed.stub
+        ));
+    }
+    let stub = request
+        .parent()
+        .unwrap_or_else(|| Path::new(""))
+        .join(&parsed.stub);
+
+    let digest = stub_digest(&stub)?;
+    if digest != parsed.digest {
+        return Err(anyhow!(
+            "{stub:?} changed since it was exported for signing: its digest is {digest} instead of {}",
+            parsed.digest
+        ));
+    }
+    let signature_data =
+        fs::read(signature).with_context(|| format!("Failed to read signature {signature:?}"))?;
+    if !covers_digest(&signature_data, &digest) {
+        return Err(anyhow!(
+            "{signature:?} does not sign the digest {digest} of {stub:?}"
+        ));
+    }
+
+    run_sbattach(&[
+        OsString::from("--attach"),
+        signature.as_os_str().to_owned(),
+        stub.as_os_str().to_owned(),
+    ])?;
+    Ok(stub)
+}
+
+/// The hex encoded Authenticode digest of a stub, which does not change when it is signed.
+fn stub_digest(stub: &Path) -> Result<String> {
+    let data = fs::read(stub).with_context(|| format!("Failed to read stub {stub:?}"))?;
+    Ok(format!("{:x}", pe::authenticode_digest(&data)?))
+}
+
+/// Whether a DER encoded Authenticode signature covers the hex encoded SHA-256 `digest`.
+///
+/// The signature embeds the digest as the OCTET STRING of the `DigestInfo` of its
+/// `SpcIndirectDataContent`.
+fn covers_digest(signature: &[u8], digest: &str) -> bool {
+    let digest = match hex_decode(digest) {
+        Some(digest) => digest,
+        None => return false,
+    };
+    let mut encoded = vec![0x04, digest.len() as u8];
+    encoded.extend(digest);
+    signature
+        .windows(encoded.len())
+        .any(|window| window == encoded)
+}
+
+fn hex_decode(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn run_sbattach(args: &[OsString]) -> Result<()> {
+    let output = Command::new("sbattach")
+        .args(args)
+        .output()
+        .context("Failed to run sbattach")?;
+
+    if !output.status.success() {
+        std::io::stderr()
+            .write_all(&output.stderr)
+            .context("Failed to write output of sbattach to stderr")?;
+        return Err(anyhow!("Failed to run sbattach with args `{args:?}`"));
+    }
+
+    Ok(())
+}
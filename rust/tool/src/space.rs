@@ -0,0 +1,212 @@
+use std::fmt;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use nix::sys::statvfs;
+use walkdir::WalkDir;
+
+use crate::error::LanzabooteError;
+
+/// How the new files of an installation fit onto the ESP.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SpacePlan {
+    /// The new files fit next to all existing files.
+    Install,
+    /// The new files only fit after the files of pruned generations have been removed.
+    RemoveFirst,
+}
+
+/// The space an installation needs and frees on the ESP.
+///
+/// Usually, new files are installed before the files of pruned generations are garbage
+/// collected. Thus, the peak usage includes both. Only if this does not fit, the pruned files are
+/// removed first.
+#[derive(Debug)]
+pub struct SpaceEstimate {
+    /// Bytes available on the ESP.
+    pub free: u64,
+    /// Bytes of the files that will be added.
+    pub added: u64,
+    /// Bytes of the files of pruned generations that will be removed.
+    pub removed: u64,
+}
+
+impl SpaceEstimate {
+    pub fn plan(&self) -> Result<SpacePlan> {
+        if self.added <= self.free {
+            Ok(SpacePlan::Install)
+        } else if self.added <= self.free.saturating_add(self.removed) {
+            Ok(SpacePlan::RemoveFirst)
+        } else {
+            Err(LanzabooteError::EspFull {
+                needed: self.added,
+                available: self.free,
+                removable: self.removed,
+            }
+            .into())
+        }
+    }
+}
+
+/// The bytes of the new files of an installation by their kind.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SpaceUsage {
+    /// Kernels on the ESP and kernels embedded into images.
+    pub kernels: u64,
+    /// Initrds on the ESP and initrds embedded into images.
+    pub initrds: u64,
+    /// The stubs that the images are assembled from.
+    pub images: u64,
+}
+
+impl SpaceUsage {
+    pub fn total(&self) -> u64 {
+        self.kernels + self.initrds + self.images
+    }
+}
+
+impl fmt::Display for SpaceUsage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes ({} bytes of kernels, {} bytes of initrds and {} bytes of images)",
+            self.total(),
+            self.kernels,
+            self.initrds,
+            self.images
+        )
+    }
+}
+
+/// Bytes available to unprivileged users on the file system containing `path`.
+#[allow(clippy::useless_conversion)]
+pub fn free_space(path: &Path) -> Result<u64> {
+    let stat = statvfs::statvfs(path)
+        .with_context(|| format!("Failed to determine the free space of {path:?}"))?;
+    Ok(u64::from(stat.blocks_available()) * u64::from(stat.fragment_size()))
+}
+
+/// Total size in bytes of the file system containing `path`.
+#[allow(clippy::useless_conversion)]
+pub fn total_space(path: &Path) -> Result<u64> {
+    let stat = statvfs::statvfs(path)
+        .with_context(|| format!("Failed to determine the size of {path:?}"))?;
+    Ok(u64::from(stat.blocks()) * u64::from(stat.fragment_size()))
+}
+
+/// The space of a file system image whose files are staged in a directory.
+///
+/// The free space of the staging directory says nothing about the image, so it is computed from
+/// the staged files instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageCapacity {
+    /// Bytes of the data area of the image.
+    pub total: u64,
+    /// Bytes of the unit in which space is allocated to files and directories.
+    pub cluster_size: u64,
+}
+
+impl ImageCapacity {
+    /// Bytes left in the image once the files staged in `root` are written to it.
+    ///
+    /// Every file and directory occupies whole clusters.
+    pub fn free_space(&self, root: &Path) -> Result<u64> {
+        let mut used = 0;
+        for entry in WalkDir::new(root) {
+            let entry = entry.with_context(|| format!("Failed to read {root:?}"))?;
+            let size = if entry.file_type().is_dir() {
+                self.cluster_size
+            } else {
+                entry
+                    .metadata()
+                    .with_context(|| format!("Failed to read the size of {:?}", entry.path()))?
+                    .len()
+            };
+            let clusters = (size + self.cluster_size - 1) / self.cluster_size;
+            used += clusters * self.cluster_size;
+        }
+        Ok(self.total.saturating_sub(used))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn install_directly_if_new_files_fit() -> Result<()> {
+        let estimate = SpaceEstimate {
+            free: 100,
+            added: 100,
+            removed: 0,
+        };
+        assert_eq!(estimate.plan()?, SpacePlan::Install);
+        Ok(())
+    }
+
+    #[test]
+    fn remove_pruned_files_first_if_only_net_usage_fits() -> Result<()> {
+        let estimate = SpaceEstimate {
+            free: 100,
+            added: 150,
+            removed: 60,
+        };
+        assert_eq!(estimate.plan()?, SpacePlan::RemoveFirst);
+        Ok(())
+    }
+
+    #[test]
+    fn describe_space_usage() {
+        let usage = SpaceUsage {
+            kernels: 10,
+            initrds: 20,
+            images: 3,
+        };
+        assert_eq!(
+            usage.to_string(),
+            "33 bytes (10 bytes of kernels, 20 bytes of initrds and 3 bytes of images)"
+        );
+    }
+
+    #[test]
+    fn count_whole_clusters_of_staged_files() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        std::fs::create_dir(root.path().join("EFI"))?;
+        std::fs::write(root.path().join("EFI/small"), [0; 10])?;
+        std::fs::write(root.path().join("EFI/large"), [0; 5000])?;
+        std::fs::write(root.path().join("empty"), [])?;
+
+        let capacity = ImageCapacity {
+            total: 100 * 4096,
+            cluster_size: 4096,
+        };
+        // The root and EFI directories, one cluster for the small file and two for the large one.
+        assert_eq!(capacity.free_space(root.path())?, 95 * 4096);
+
+        let full = ImageCapacity {
+            total: 4096,
+            cluster_size: 4096,
+        };
+        assert_eq!(full.free_space(root.path())?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn abort_if_net_usage_does_not_fit() {
+        let estimate = SpaceEstimate {
+            free: 100,
+            added: 150,
+            removed: 40,
+        };
+        let error = estimate.plan().unwrap_err();
+        assert!(error.to_string().contains("Not enough space on the ESP"));
+        assert!(matches!(
+            error.downcast_ref::<LanzabooteError>(),
+            Some(LanzabooteError::EspFull {
+                needed: 150,
+                available: 100,
+                removable: 40
+            })
+        ));
+    }
+}
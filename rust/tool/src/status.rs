@@ -0,0 +1,853 @@
+use std::collections::BTreeSet;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use walkdir::WalkDir;
+
+use crate::esp::{self, EspPaths};
+use crate::loader_conf::LoaderConf;
+use crate::pe;
+use crate::signature;
+
+/// Vendor GUID of the global UEFI variables (e.g. `SecureBoot`).
+pub const EFI_GLOBAL_VARIABLE: &str = "8be4df61-93ca-11d2-aa0d-00e098032b8c";
+/// Vendor GUID of the Secure Boot signature databases (e.g. `db`).
+pub const IMAGE_SECURITY_DATABASE: &str = "d719b2cb-3d3a-4596-a3bc-dad00e67656f";
+/// Vendor GUID of the variables of the Boot Loader Interface (e.g. `LoaderEntrySelected`).
+pub const LOADER_VARIABLE: &str = "4a67b082-0a4c-41cf-b6c7-440b29bb8c4f";
+
+/// Bit in `OsIndications` and `OsIndicationsSupported` that requests booting into the firmware
+/// setup.
+const EFI_OS_INDICATIONS_BOOT_TO_FW_UI: u64 = 0x1;
+
+/// Signature type of an `EFI_SIGNATURE_LIST` with X.509 certificates (`EFI_CERT_X509_GUID`) in its
+/// binary (mixed endian) representation.
+pub const EFI_CERT_X509_GUID: [u8; 16] = [
+    0xa1, 0x59, 0xc0, 0xa5, 0xe4, 0x94, 0xa7, 0x4a, 0x87, 0xb5, 0xab, 0x15, 0x5c, 0x2b, 0xf0, 0x72,
+];
+
+/// Signature type of an `EFI_SIGNATURE_LIST` with SHA-256 hashes of binaries
+/// (`EFI_CERT_SHA256_GUID`) in its binary (mixed endian) representation.
+pub const EFI_CERT_SHA256_GUID: [u8; 16] = [
+    0x26, 0x16, 0xc4, 0xc1, 0x4c, 0x50, 0x92, 0x40, 0xac, 0xa9, 0x41, 0xf9, 0x36, 0x93, 0x43, 0x28,
+];
+
+/// Marker that systemd-boot embeds in front of its version.
+const LOADER_INFO_MARKER: &[u8] = b"#### LoaderInfo: ";
+
+/// Whether the firmware enforces Secure Boot.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SecureBootState {
+    Enabled,
+    Disabled,
+    /// The firmware has no Platform Key enrolled and accepts new keys.
+    SetupMode,
+    /// The system was not booted via UEFI or does not expose the variables.
+    Unknown,
+}
+
+impl fmt::Display for SecureBootState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = match self {
+            Self::Enabled => "enabled",
+            Self::Disabled => "disabled",
+            Self::SetupMode => "setup mode",
+            Self::Unknown => "unknown",
+        };
+        write!(f, "{state}")
+    }
+}
+
+/// Whether the installed systemd-boot is the one of the current system.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SystemdBootState {
+    UpToDate(String),
+    OutOfDate { installed: String, current: String },
+    NotInstalled,
+    Unknown,
+}
+
+impl fmt::Display for SystemdBootState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UpToDate(version) => write!(f, "up to date ({version})"),
+            Self::OutOfDate { installed, current } => {
+                write!(f, "out of date (installed {installed}, current {current})")
+            }
+            Self::NotInstalled => write!(f, "not installed"),
+            Self::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// Whether a TPM is present and which PCR banks it has active.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TpmInfo {
+    NotPresent,
+    Present {
+        /// The major version of the TPM specification, e.g. 2 for TPM 2.0, if the kernel exposes
+        /// it.
+        major_version: Option<u32>,
+        /// The hash algorithms of the active PCR banks, e.g. `sha256`.
+        pcr_banks: BTreeSet<String>,
+    },
+}
+
+impl fmt::Display for TpmInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NotPresent => write!(f, "not present"),
+            Self::Present {
+                major_version,
+                pcr_banks,
+            } => {
+                match major_version {
+                    Some(1) => write!(f, "TPM 1.2")?,
+                    Some(2) => write!(f, "TPM 2.0")?,
+                    Some(version) => write!(f, "TPM {version}")?,
+                    None => write!(f, "present")?,
+                }
+                if pcr_banks.is_empty() {
+                    write!(f, " (no PCR banks found)")
+                } else {
+                    let banks: Vec<_> = pcr_banks.iter().map(String::as_str).collect();
+                    write!(f, " (PCR banks: {})", banks.join(", "))
+                }
+            }
+        }
+    }
+}
+
+/// The boot entries that systemd-boot exposes via the Boot Loader Interface.
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct LoaderEntries {
+    /// The entry that was booted (`LoaderEntrySelected`).
+    pub selected: Option<String>,
+    /// The entry that is booted by default (`LoaderEntryDefault`).
+    pub default: Option<String>,
+    /// The entry that is booted once on the next boot (`LoaderEntryOneShot`).
+    pub oneshot: Option<String>,
+}
+
+/// A read-only summary of the state of the ESP.
+#[derive(Debug, Serialize)]
+pub struct Status {
+    pub esp: PathBuf,
+    pub secure_boot: SecureBootState,
+    pub installed_generations: BTreeSet<u64>,
+    pub current_generation: Option<u64>,
+    pub loader_entries: LoaderEntries,
+    /// Whether the next boot goes into the firmware setup, if the firmware supports that.
+    pub boot_into_firmware: Option<bool>,
+    pub systemd_boot: SystemdBootState,
+    pub tpm: TpmInfo,
+}
+
+/// Display the status in a human readable form.
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "ESP: {}", self.esp.display())?;
+        writeln!(f, "Secure Boot: {}", self.secure_boot)?;
+        writeln!(
+            f,
+            "Installed generations: {}",
+            self.installed_generations.len()
+        )?;
+        match self.current_generation {
+            Some(version) => writeln!(f, "Current generation: {version}")?,
+            None => writeln!(f, "Current generation: unknown")?,
+        }
+        for (name, entry) in [
+            ("Selected entry", &self.loader_entries.selected),
+            ("Default entry", &self.loader_entries.default),
+            ("One-shot entry", &self.loader_entries.oneshot),
+        ] {
+            writeln!(f, "{name}: {}", entry.as_deref().unwrap_or("not set"))?;
+        }
+        match self.boot_into_firmware {
+            Some(true) => writeln!(f, "Boot into firmware: requested")?,
+            Some(false) => writeln!(f, "Boot into firmware: not requested")?,
+            None => writeln!(f, "Boot into firmware: not supported")?,
+        }
+        writeln!(f, "systemd-boot: {}", self.systemd_boot)?;
+        writeln!(f, "TPM: {}", self.tpm)
+    }
+}
+
+/// How many EFI binaries on the ESP are signed with which key.
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct SignatureSummary {
+    /// Binaries signed with the key of the given certificate.
+    pub ours: usize,
+    /// Binaries signed with some other key.
+    pub other: usize,
+    pub unsigned: usize,
+    /// Files named like EFI binaries that are not PE binaries (or cannot be read), with the reason.
+    pub invalid: Vec<InvalidBinary>,
+}
+
+/// A file named like an EFI binary whose signature cannot be checked.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct InvalidBinary {
+    pub path: PathBuf,
+    pub error: String,
+}
+
+impl fmt::Display for SignatureSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Signed with our key: {}", self.ours)?;
+        writeln!(f, "Signed with another key: {}", self.other)?;
+        writeln!(f, "Unsigned: {}", self.unsigned)?;
+        for invalid in &self.invalid {
+            writeln!(f, "Invalid: {}: {}", invalid.path.display(), invalid.error)?;
+        }
+        Ok(())
+    }
+}
+
+/// How an EFI binary on the ESP is signed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SignatureState {
+    /// Signed with the key of the given certificate.
+    Ours,
+    /// Signed with some other key.
+    Other,
+    /// Signed, but no certificate was given to tell with which key.
+    Signed,
+    Unsigned,
+}
+
+/// Check how an EFI binary is signed, comparing the signature to `certificate` if one is given.
+pub fn signature_state(path: &Path, certificate: Option<&Path>) -> Result<SignatureState> {
+    let data = fs::read(path).with_context(|| format!("Failed to read {path:?}"))?;
+    if !pe::is_signed(&data).with_context(|| format!("Failed to parse {path:?}"))? {
+        return Ok(SignatureState::Unsigned);
+    }
+    Ok(match certificate {
+        None => SignatureState::Signed,
+        Some(certificate) if signature::verify_signature(path, certificate).is_ok() => {
+            SignatureState::Ours
+        }
+        Some(_) => SignatureState::Other,
+    })
+}
+
+/// Count the EFI binaries on the ESP (and the XBOOTLDR partition) by their signature.
+///
+/// Only the signatures are checked, not the hashes of the kernels and initrds the stubs
+/// reference, which makes this much cheaper than `verify`.
+pub fn signature_summary(esp_paths: &EspPaths, certificate: &Path) -> Result<SignatureSummary> {
+    let mut roots = vec![&esp_paths.esp];
+    if esp_paths.boot != esp_paths.esp {
+        roots.push(&esp_paths.boot);
+    }
+
+    let mut summary = SignatureSummary::default();
+    for root in roots {
+        for entry in WalkDir::new(root) {
+            let entry = entry.with_context(|| format!("Failed to read directory {root:?}"))?;
+            let is_efi_binary = entry.file_type().is_file()
+                && entry
+                    .path()
+                    .extension()
+                    .map_or(false, |extension| extension.eq_ignore_ascii_case("efi"));
+            if !is_efi_binary {
+                continue;
+            }
+
+            // A single broken file must not hide the signatures of all others.
+            match signature_state(entry.path(), Some(certificate)) {
+                Ok(SignatureState::Ours) => summary.ours += 1,
+                Ok(SignatureState::Other | SignatureState::Signed) => summary.other += 1,
+                Ok(SignatureState::Unsigned) => summary.unsigned += 1,
+                Err(e) => summary.invalid.push(InvalidBinary {
+                    path: entry.path().to_path_buf(),
+                    error: format!("{e:#}"),
+                }),
+            }
+        }
+    }
+    Ok(summary)
+}
+
+/// The status of the ESP together with its installed generations, as printed by `status --json`.
+#[derive(Debug, Serialize)]
+pub struct StatusReport {
+    #[serde(flatten)]
+    pub status: Status,
+    pub generations: Vec<GenerationStatus>,
+    /// The signatures of all EFI binaries, if a certificate was given to compare them to.
+    pub signatures: Option<SignatureSummary>,
+}
+
+/// A stub of a generation that is installed to the ESP.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct GenerationStatus {
+    pub stub: PathBuf,
+    /// The version of the generation the stub belongs to, if its name carries one.
+    pub version: Option<u64>,
+    /// The version of the kernel the stub boots, if it can be read from the kernel image.
+    pub kernel_version: Option<String>,
+    pub cmdline: Option<String>,
+    pub signature: SignatureState,
+    /// The stub followed by the kernel and initrd it references.
+    pub files: Vec<FileSize>,
+    /// Whether systemd-boot boots this stub unless another entry is chosen in the menu.
+    pub default: bool,
+}
+
+/// The size of a file that belongs to an installed generation.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct FileSize {
+    pub path: PathBuf,
+    /// The size in bytes, or `None` if the file is missing.
+    pub size: Option<u64>,
+}
+
+/// Describe the stubs installed to the ESP, sorted by generation.
+///
+/// The signatures are compared to `certificate` if one is given. The default entry is the one set
+/// from the running system (`LoaderEntryDefault`), then the one named in `loader.conf`, and else
+/// the newest generation because systemd-boot sorts it first.
+pub fn generations(
+    esp_paths: &EspPaths,
+    efivars: &Path,
+    certificate: Option<&Path>,
+) -> Result<Vec<GenerationStatus>> {
+    let mut stubs = esp::nixos_images(&esp_paths.linux)?;
+    stubs.sort_by_key(|stub| (esp::image_version(stub), stub.clone()));
+    let default = default_entry(esp_paths, efivars, &stubs)?;
+
+    stubs
+        .iter()
+        .map(|stub| {
+            let references = pe::stub_references(stub, &esp_paths.boot)?;
+            let files = std::iter::once(stub)
+                .chain(references.iter().map(|reference| &reference.path))
+                .map(|path| {
+                    Ok(FileSize {
+                        path: path.clone(),
+                        size: file_size(path)?,
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            Ok(GenerationStatus {
+                stub: stub.clone(),
+                version: esp::image_version(stub),
+                kernel_version: pe::stub_kernel_version(stub, &esp_paths.boot)?,
+                cmdline: pe::stub_cmdline(stub)?,
+                signature: signature_state(stub, certificate)?,
+                files,
+                default: default.as_deref().map_or(false, |default| {
+                    esp::without_boot_counter(stub).file_name() == Some(OsStr::new(default))
+                }),
+            })
+        })
+        .collect()
+}
+
+/// The name of the stub that systemd-boot boots by default.
+///
+/// Patterns and special entries like `@saved` in `loader.conf` are only resolved at boot time, so
+/// the newest generation is assumed for them.
+fn default_entry(
+    esp_paths: &EspPaths,
+    efivars: &Path,
+    stubs: &[PathBuf],
+) -> Result<Option<String>> {
+    if let Some(entry) = loader_entry(efivars, "LoaderEntryDefault")? {
+        return Ok(Some(entry));
+    }
+
+    let loader_conf = LoaderConf::read(&esp_paths.loader_conf)?;
+    if let Some(entry) = loader_conf.default_entry() {
+        return Ok(Some(entry.to_owned()));
+    }
+
+    Ok(stubs
+        .iter()
+        .filter_map(|stub| esp::image_version(stub))
+        .max()
+        .map(|version| format!("nixos-generation-{version}.efi")))
+}
+
+/// The size of a file, or `None` if it does not exist.
+fn file_size(path: &Path) -> Result<Option<u64>> {
+    match fs::metadata(path) {
+        Ok(metadata) => Ok(Some(metadata.len())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {path:?}")),
+    }
+}
+
+/// Collect the status of the ESP without modifying anything.
+///
+/// The UEFI variables are read from `efivars` (usually `/sys/firmware/efi/efivars`), the
+/// systemd-boot of the current system is taken from the toplevel `system` (usually
+/// `/run/current-system`) and the TPM is described by the sysfs directory `tpm` (usually
+/// `/sys/class/tpm/tpm0`).
+pub fn status(esp_paths: &EspPaths, efivars: &Path, system: &Path, tpm: &Path) -> Result<Status> {
+    let current_systemd_boot = system.join(format!(
+        "systemd/lib/systemd/boot/efi/systemd-boot{}.efi",
+        esp_paths.architecture.name()
+    ));
+    let loader_entries = loader_entries(efivars)?;
+
+    Ok(Status {
+        esp: esp_paths.esp.clone(),
+        secure_boot: secure_boot_state(efivars)?,
+        installed_generations: installed_generations(&esp_paths.linux)?,
+        current_generation: current_generation(&loader_entries),
+        loader_entries,
+        boot_into_firmware: boot_into_firmware(efivars)?,
+        systemd_boot: systemd_boot_state(&esp_paths.systemd_boot, &current_systemd_boot)?,
+        tpm: tpm_info(tpm)?,
+    })
+}
+
+/// Read the Secure Boot state from the UEFI variables.
+pub fn secure_boot_state(efivars: &Path) -> Result<SecureBootState> {
+    let secure_boot = read_efi_variable(efivars, "SecureBoot", EFI_GLOBAL_VARIABLE)?;
+    let setup_mode = read_efi_variable(efivars, "SetupMode", EFI_GLOBAL_VARIABLE)?;
+
+    Ok(match (secure_boot.as_deref(), setup_mode.as_deref()) {
+        (_, Some([1])) => SecureBootState::SetupMode,
+        (Some([1]), _) => SecureBootState::Enabled,
+        (Some([0]), _) => SecureBootState::Disabled,
+        _ => SecureBootState::Unknown,
+    })
+}
+
+/// Whether a certificate (in DER encoding) or the certificate that issued it is enrolled in the
+/// signature database `db`.
+///
+/// Only certificates count, not hashes of single binaries, because the stubs change with every
+/// generation.
+pub fn is_certificate_enrolled(efivars: &Path, certificate: &[u8]) -> Result<bool> {
+    is_certificate_listed(efivars, "db", certificate)
+}
+
+/// Whether a certificate (in DER encoding) or the certificate that issued it is revoked by the
+/// forbidden signature database `dbx`, so that the firmware refuses everything signed with its
+/// key.
+pub fn is_certificate_revoked(efivars: &Path, certificate: &[u8]) -> Result<bool> {
+    is_certificate_listed(efivars, "dbx", certificate)
+}
+
+/// Whether the forbidden signature database `dbx` lists the SHA-256 Authenticode digest of a
+/// binary, so that the firmware refuses it regardless of its signature.
+pub fn is_binary_revoked(efivars: &Path, digest: &[u8]) -> Result<bool> {
+    let dbx = read_efi_variable(efivars, "dbx", IMAGE_SECURITY_DATABASE)?.unwrap_or_default();
+    Ok(signature_list_entries(&dbx, EFI_CERT_SHA256_GUID)
+        .context("Malformed signature database dbx")?
+        .contains(&digest))
+}
+
+fn is_certificate_listed(efivars: &Path, database: &str, certificate: &[u8]) -> Result<bool> {
+    let data = read_efi_variable(efivars, database, IMAGE_SECURITY_DATABASE)?.unwrap_or_default();
+    let listed = signature_list_entries(&data, EFI_CERT_X509_GUID)
+        .with_context(|| format!("Malformed signature database {database}"))?;
+    if listed.contains(&certificate) {
+        return Ok(true);
+    }
+    for issuer in listed {
+        if signature::is_issued_by(certificate, issuer)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Extract the signatures of a type (e.g. X.509 certificates) from a sequence of
+/// `EFI_SIGNATURE_LIST`s.
+fn signature_list_entries(mut data: &[u8], signature_type: [u8; 16]) -> Result<Vec<&[u8]>> {
+    let read_u32 = |data: &[u8], offset: usize| -> Option<usize> {
+        let bytes = data.get(offset..offset + 4)?.try_into().ok()?;
+        usize::try_from(u32::from_le_bytes(bytes)).ok()
+    };
+
+    let mut entries = Vec::new();
+    while !data.is_empty() {
+        let (list_size, header_size, signature_size) =
+            match (read_u32(data, 16), read_u32(data, 20), read_u32(data, 24)) {
+                (Some(list_size), Some(header_size), Some(signature_size)) => {
+                    (list_size, header_size, signature_size)
+                }
+                _ => return Err(anyhow::anyhow!("Truncated signature list header")),
+            };
+        let list = data
+            .get(..list_size)
+            .context("Signature list is larger than the database")?;
+        let signatures = list
+            .get(28 + header_size..)
+            .context("Signature list header is larger than the list")?;
+        // Every signature starts with the GUID of its owner.
+        if signature_size <= 16 {
+            return Err(anyhow::anyhow!("Invalid signature size {signature_size}"));
+        }
+        if list[..16] == signature_type {
+            entries.extend(
+                signatures
+                    .chunks_exact(signature_size)
+                    .map(|signature| &signature[16..]),
+            );
+        }
+        data = &data[list_size..];
+    }
+    Ok(entries)
+}
+
+/// The versions of the generations that have a stub in the `EFI/Linux` directory.
+fn installed_generations(linux: &Path) -> Result<BTreeSet<u64>> {
+    Ok(esp::nixos_images(linux)?
+        .iter()
+        .filter_map(|path| esp::image_version(path))
+        .collect())
+}
+
+/// Read the boot entries that systemd-boot selected, uses by default and boots once next.
+pub fn loader_entries(efivars: &Path) -> Result<LoaderEntries> {
+    Ok(LoaderEntries {
+        selected: loader_entry(efivars, "LoaderEntrySelected")?,
+        default: loader_entry(efivars, "LoaderEntryDefault")?,
+        oneshot: loader_entry(efivars, "LoaderEntryOneShot")?,
+    })
+}
+
+/// Read a variable of the Boot Loader Interface that holds the name of a boot entry.
+fn loader_entry(efivars: &Path, name: &str) -> Result<Option<String>> {
+    Ok(read_efi_variable(efivars, name, LOADER_VARIABLE)?
+        .as_deref()
+        .and_then(decode_utf16))
+}
+
+/// The version of the generation that systemd-boot booted.
+fn current_generation(loader_entries: &LoaderEntries) -> Option<u64> {
+    loader_entries
+        .selected
+        .as_deref()
+        .and_then(|entry| esp::image_version(Path::new(entry)))
+}
+
+/// Whether the next boot goes into the firmware setup.
+///
+/// Returns `None` if the firmware does not support booting into its setup on request.
+fn boot_into_firmware(efivars: &Path) -> Result<Option<bool>> {
+    let read_bits = |name| -> Result<u64> {
+        Ok(read_efi_variable(efivars, name, EFI_GLOBAL_VARIABLE)?
+            .and_then(|data| data.get(..8)?.try_into().ok())
+            .map_or(0, u64::from_le_bytes))
+    };
+
+    if read_bits("OsIndicationsSupported")? & EFI_OS_INDICATIONS_BOOT_TO_FW_UI == 0 {
+        return Ok(None);
+    }
+    Ok(Some(
+        read_bits("OsIndications")? & EFI_OS_INDICATIONS_BOOT_TO_FW_UI != 0,
+    ))
+}
+
+/// Compare the version of the installed systemd-boot to the one of the current system.
+fn systemd_boot_state(installed: &Path, current: &Path) -> Result<SystemdBootState> {
+    if !installed.exists() {
+        return Ok(SystemdBootState::NotInstalled);
+    }
+
+    let installed = fs::read(installed)
+        .with_context(|| format!("Failed to read systemd-boot {installed:?}"))?;
+    let current = match fs::read(current) {
+        Ok(current) => current,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(SystemdBootState::Unknown),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to read systemd-boot {current:?}"))
+        }
+    };
+
+    Ok(
+        match (
+            systemd_boot_version(&installed),
+            systemd_boot_version(&current),
+        ) {
+            (Some(installed), Some(current)) if installed == current => {
+                SystemdBootState::UpToDate(installed)
+            }
+            (Some(installed), Some(current)) => SystemdBootState::OutOfDate { installed, current },
+            _ => SystemdBootState::Unknown,
+        },
+    )
+}
+
+/// Read whether a TPM is present and which PCR banks it has active from its sysfs directory.
+///
+/// The kernel exposes every active PCR bank as a `pcr-<algorithm>` directory. A TPM without any
+/// of them is either a TPM 1.2 or is driven by a kernel that is too old to expose them.
+pub fn tpm_info(tpm: &Path) -> Result<TpmInfo> {
+    let entries = match fs::read_dir(tpm) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(TpmInfo::NotPresent),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read TPM {tpm:?}")),
+    };
+
+    let mut pcr_banks = BTreeSet::new();
+    for entry in entries {
+        let name = entry
+            .with_context(|| format!("Failed to read TPM {tpm:?}"))?
+            .file_name();
+        if let Some(bank) = name.to_str().and_then(|name| name.strip_prefix("pcr-")) {
+            pcr_banks.insert(bank.to_owned());
+        }
+    }
+
+    let major_version = match fs::read_to_string(tpm.join("tpm_version_major")) {
+        Ok(version) => version.trim().parse().ok(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e).with_context(|| format!("Failed to read TPM {tpm:?}")),
+    };
+
+    Ok(TpmInfo::Present {
+        major_version,
+        pcr_banks,
+    })
+}
+
+/// Extract the version that systemd-boot embeds as `#### LoaderInfo: systemd-boot 252.4 ####`.
+fn systemd_boot_version(data: &[u8]) -> Option<String> {
+    let start = data
+        .windows(LOADER_INFO_MARKER.len())
+        .position(|window| window == LOADER_INFO_MARKER)?
+        + LOADER_INFO_MARKER.len();
+    let info = &data[start..];
+    let end = info.windows(5).position(|window| window == b" ####")?;
+    std::str::from_utf8(&info[..end]).ok().map(String::from)
+}
+
+/// Read the data of a UEFI variable from efivarfs.
+///
+/// The first four bytes of an efivarfs file are the attributes of the variable and are skipped.
+/// Returns `None` if the variable does not exist.
+pub fn read_efi_variable(efivars: &Path, name: &str, vendor: &str) -> Result<Option<Vec<u8>>> {
+    let path = efivars.join(format!("{name}-{vendor}"));
+    match fs::read(&path) {
+        Ok(data) => Ok(data.get(4..).map(<[u8]>::to_vec)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read UEFI variable {path:?}")),
+    }
+}
+
+/// Decode a NUL terminated UTF-16LE string as used by the Boot Loader Interface.
+fn decode_utf16(data: &[u8]) -> Option<String> {
+    let units: Vec<u16> = data
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|&c| c != 0)
+        .collect();
+    String::from_utf16(&units).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_efi_variable(efivars: &Path, name: &str, vendor: &str, data: &[u8]) -> Result<()> {
+        let mut contents = vec![0x06, 0x00, 0x00, 0x00];
+        contents.extend_from_slice(data);
+        fs::write(efivars.join(format!("{name}-{vendor}")), contents)?;
+        Ok(())
+    }
+
+    #[test]
+    fn read_secure_boot_state() -> Result<()> {
+        let efivars = tempfile::tempdir()?;
+        assert_eq!(secure_boot_state(efivars.path())?, SecureBootState::Unknown);
+
+        write_efi_variable(efivars.path(), "SecureBoot", EFI_GLOBAL_VARIABLE, &[0])?;
+        write_efi_variable(efivars.path(), "SetupMode", EFI_GLOBAL_VARIABLE, &[0])?;
+        assert_eq!(
+            secure_boot_state(efivars.path())?,
+            SecureBootState::Disabled
+        );
+
+        write_efi_variable(efivars.path(), "SecureBoot", EFI_GLOBAL_VARIABLE, &[1])?;
+        assert_eq!(secure_boot_state(efivars.path())?, SecureBootState::Enabled);
+
+        write_efi_variable(efivars.path(), "SetupMode", EFI_GLOBAL_VARIABLE, &[1])?;
+        assert_eq!(
+            secure_boot_state(efivars.path())?,
+            SecureBootState::SetupMode
+        );
+
+        Ok(())
+    }
+
+    /// Build an `EFI_SIGNATURE_LIST` with a single signature.
+    fn signature_list(signature_type: [u8; 16], data: &[u8]) -> Vec<u8> {
+        let signature_size = 16 + data.len() as u32;
+        let mut list = signature_type.to_vec();
+        list.extend_from_slice(&(28 + signature_size).to_le_bytes());
+        list.extend_from_slice(&0u32.to_le_bytes());
+        list.extend_from_slice(&signature_size.to_le_bytes());
+        list.extend_from_slice(&[0; 16]);
+        list.extend_from_slice(data);
+        list
+    }
+
+    #[test]
+    fn find_enrolled_certificates() -> Result<()> {
+        let efivars = tempfile::tempdir()?;
+        assert!(!is_certificate_enrolled(efivars.path(), b"ours")?);
+
+        let mut db = signature_list([0; 16], b"ours");
+        db.extend(signature_list(EFI_CERT_X509_GUID, b"vendor"));
+        write_efi_variable(efivars.path(), "db", IMAGE_SECURITY_DATABASE, &db)?;
+        // Only X.509 certificates count.
+        assert!(!is_certificate_enrolled(efivars.path(), b"ours")?);
+        assert!(is_certificate_enrolled(efivars.path(), b"vendor")?);
+
+        db.extend(signature_list(EFI_CERT_X509_GUID, b"ours"));
+        write_efi_variable(efivars.path(), "db", IMAGE_SECURITY_DATABASE, &db)?;
+        assert!(is_certificate_enrolled(efivars.path(), b"ours")?);
+
+        write_efi_variable(efivars.path(), "db", IMAGE_SECURITY_DATABASE, &db[..30])?;
+        assert!(is_certificate_enrolled(efivars.path(), b"ours").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn find_revoked_binaries_and_certificates() -> Result<()> {
+        let efivars = tempfile::tempdir()?;
+        assert!(!is_binary_revoked(efivars.path(), &[1; 32])?);
+
+        let mut dbx = signature_list(EFI_CERT_SHA256_GUID, &[1; 32]);
+        dbx.extend(signature_list(EFI_CERT_X509_GUID, b"revoked"));
+        write_efi_variable(efivars.path(), "dbx", IMAGE_SECURITY_DATABASE, &dbx)?;
+        assert!(is_binary_revoked(efivars.path(), &[1; 32])?);
+        assert!(!is_binary_revoked(efivars.path(), &[2; 32])?);
+        assert!(is_certificate_revoked(efivars.path(), b"revoked")?);
+        // Revoked binaries do not count as enrolled certificates.
+        assert!(!is_certificate_enrolled(efivars.path(), &[1; 32])?);
+
+        Ok(())
+    }
+
+    fn encode_utf16(entry: &str) -> Vec<u8> {
+        entry
+            .encode_utf16()
+            .chain([0])
+            .flat_map(u16::to_le_bytes)
+            .collect()
+    }
+
+    #[test]
+    fn read_current_generation_from_selected_entry() -> Result<()> {
+        let efivars = tempfile::tempdir()?;
+        assert_eq!(current_generation(&loader_entries(efivars.path())?), None);
+
+        write_efi_variable(
+            efivars.path(),
+            "LoaderEntrySelected",
+            LOADER_VARIABLE,
+            &encode_utf16("nixos-generation-42-specialisation-work.efi"),
+        )?;
+        assert_eq!(
+            current_generation(&loader_entries(efivars.path())?),
+            Some(42)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_loader_entries() -> Result<()> {
+        let efivars = tempfile::tempdir()?;
+        assert_eq!(loader_entries(efivars.path())?, LoaderEntries::default());
+
+        for (name, entry) in [
+            ("LoaderEntrySelected", "nixos-generation-2.efi"),
+            ("LoaderEntryDefault", "nixos-generation-1.efi"),
+            ("LoaderEntryOneShot", "auto-reboot-to-firmware-setup"),
+        ] {
+            write_efi_variable(efivars.path(), name, LOADER_VARIABLE, &encode_utf16(entry))?;
+        }
+        assert_eq!(
+            loader_entries(efivars.path())?,
+            LoaderEntries {
+                selected: Some(String::from("nixos-generation-2.efi")),
+                default: Some(String::from("nixos-generation-1.efi")),
+                oneshot: Some(String::from("auto-reboot-to-firmware-setup")),
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_boot_into_firmware_request() -> Result<()> {
+        let efivars = tempfile::tempdir()?;
+        assert_eq!(boot_into_firmware(efivars.path())?, None);
+
+        let indications = EFI_OS_INDICATIONS_BOOT_TO_FW_UI.to_le_bytes();
+        write_efi_variable(
+            efivars.path(),
+            "OsIndicationsSupported",
+            EFI_GLOBAL_VARIABLE,
+            &indications,
+        )?;
+        assert_eq!(boot_into_firmware(efivars.path())?, Some(false));
+
+        write_efi_variable(
+            efivars.path(),
+            "OsIndications",
+            EFI_GLOBAL_VARIABLE,
+            &indications,
+        )?;
+        assert_eq!(boot_into_firmware(efivars.path())?, Some(true));
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_tpm_info_from_sysfs() -> Result<()> {
+        let sysfs = tempfile::tempdir()?;
+        let tpm = sysfs.path().join("tpm0");
+        assert_eq!(tpm_info(&tpm)?, TpmInfo::NotPresent);
+
+        fs::create_dir(&tpm)?;
+        fs::write(tpm.join("tpm_version_major"), "2\n")?;
+        for bank in ["pcr-sha256", "pcr-sha384"] {
+            fs::create_dir(tpm.join(bank))?;
+        }
+        fs::create_dir(tpm.join("power"))?;
+        let info = tpm_info(&tpm)?;
+        assert_eq!(
+            info,
+            TpmInfo::Present {
+                major_version: Some(2),
+                pcr_banks: BTreeSet::from([String::from("sha256"), String::from("sha384")]),
+            }
+        );
+        assert_eq!(info.to_string(), "TPM 2.0 (PCR banks: sha256, sha384)");
+
+        Ok(())
+    }
+
+    #[test]
+    fn extract_systemd_boot_version() {
+        let data = b"\x00\x01#### LoaderInfo: systemd-boot 252.4 ####\x00\x02";
+        assert_eq!(
+            systemd_boot_version(data),
+            Some(String::from("systemd-boot 252.4"))
+        );
+        assert_eq!(systemd_boot_version(b"MZ"), None);
+    }
+}
@@ -0,0 +1,300 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use crate::boot_options::{self, Partition};
+use crate::esp::{self, EspPaths};
+use crate::manifest::Manifest;
+
+/// Remove everything that lanzaboote installed from the ESP.
+///
+/// Only the files and directories managed by lanzaboote are removed. Files that belong to other
+/// operating systems or vendors (e.g. `EFI/Microsoft`) are left intact. Directories are only
+/// removed when they are empty afterwards. If `efivars` names a UEFI variables directory and the
+/// partition of the stubs, the boot options that boot the removed stubs from that partition (see
+/// `--efi-boot-entries`) are removed as well.
+pub fn uninstall(esp_paths: &EspPaths, efivars: Option<(&Path, &Partition)>) -> Result<()> {
+    // The fallback boot loader is only ours if it is the systemd-boot we installed. Otherwise,
+    // another operating system has taken over the fallback path in the meantime.
+    for efi_fallback in esp_paths.efi_fallbacks.values() {
+        if is_same_file_content(efi_fallback, &esp_paths.systemd_boot)? {
+            remove_file(efi_fallback)?;
+        }
+    }
+
+    // The esp/EFI/Linux and loader/entries directories are potentially shared with other distros.
+    // With a manifest, exactly the files that lanzaboote recorded are removed from them. Without
+    // one (e.g. on an ESP that an old version installed to), they are recognized by their names.
+    if esp_paths.manifest.exists() {
+        remove_recorded_files(esp_paths, &Manifest::read(esp_paths))?;
+    } else {
+        remove_nixos_files(esp_paths)?;
+    }
+
+    // Lanzaboote takes full control over the esp/EFI/nixos directory, which holds the manifest.
+    if esp_paths.nixos.exists() {
+        println!("Removing {}...", esp_paths.nixos.display());
+        fs::remove_dir_all(&esp_paths.nixos)
+            .with_context(|| format!("Failed to remove directory: {:?}", esp_paths.nixos))?;
+    }
+
+    // loader.conf only configures the systemd-boot that lanzaboote installed.
+    remove_file(&esp_paths.systemd_boot)?;
+    remove_file(&esp_paths.random_seed)?;
+    remove_file(&esp_paths.loader_conf)?;
+
+    for directory in [
+        &esp_paths.linux,
+        &esp_paths.entries,
+        &esp_paths.systemd,
+        &esp_paths.efi_fallback_dir,
+        &esp_paths.loader,
+    ] {
+        remove_empty_dir(directory)?;
+    }
+
+    // Otherwise, the firmware keeps offering to boot stubs that are gone.
+    if let Some((efivars, partition)) = efivars {
+        boot_options::remove_stale_boot_options(efivars, partition, &esp_paths.boot)?;
+    }
+
+    Ok(())
+}
+
+/// Remove the files that the manifest records outside of esp/EFI/nixos.
+///
+/// A file that changed since it was installed belongs to somebody else now (e.g. a fallback boot
+/// loader that another operating system replaced) and is kept.
+fn remove_recorded_files(esp_paths: &EspPaths, manifest: &Manifest) -> Result<()> {
+    for recorded in manifest.paths() {
+        if recorded.starts_with(&esp_paths.nixos) {
+            continue;
+        }
+        // systemd-boot renames stubs to count the boot attempts.
+        let path = match esp::installed_image(&recorded)? {
+            Some(path) => path,
+            None => continue,
+        };
+        if !manifest.is_unchanged(&recorded, &path)? {
+            println!(
+                "Keeping {}, which changed since it was installed",
+                path.display()
+            );
+            continue;
+        }
+        remove_file(&path)?;
+
+        // The companion directory of a stub is empty once its files are removed.
+        if let Some(parent) = path.parent().filter(|parent| esp::is_nixos_image(parent)) {
+            remove_empty_dir(parent)?;
+        }
+    }
+    Ok(())
+}
+
+/// Remove the stubs, their companion directories and the boot loader entries of NixOS.
+fn remove_nixos_files(esp_paths: &EspPaths) -> Result<()> {
+    for directory in [&esp_paths.linux, &esp_paths.entries] {
+        if !directory.exists() {
+            continue;
+        }
+        for entry in fs::read_dir(directory)
+            .with_context(|| format!("Failed to read directory: {:?}", directory))?
+        {
+            let path = entry?.path();
+            if esp::is_nixos_image(&path) && path.is_dir() {
+                // The companion directory of a stub with its credentials and system extensions.
+                fs::remove_dir_all(&path)
+                    .with_context(|| format!("Failed to remove directory: {:?}", path))?;
+                println!("Removed {}", path.display());
+            } else if esp::is_nixos_image(&path) {
+                remove_file(&path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Remove a file, if it exists.
+fn remove_file(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Ok(()) => {
+            println!("Removed {}", path.display());
+            Ok(())
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("Failed to remove file: {:?}", path)),
+    }
+}
+
+/// Remove a directory, if it exists and is empty.
+fn remove_empty_dir(path: &Path) -> Result<()> {
+    let is_empty = match fs::read_dir(path) {
+        Ok(mut entries) => entries.next().is_none(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read directory: {:?}", path)),
+    };
+
+    if is_empty {
+        fs::remove_dir(path).with_context(|| format!("Failed to remove directory: {:?}", path))?;
+    }
+
+    Ok(())
+}
+
+fn is_same_file_content(a: &Path, b: &Path) -> Result<bool> {
+    if !a.exists() || !b.exists() {
+        return Ok(false);
+    }
+
+    Ok(fs::read(a)? == fs::read(b)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    use crate::boot_options::BootOption;
+    use crate::status::{self, EFI_GLOBAL_VARIABLE};
+
+    #[test]
+    fn only_remove_lanzaboote_files() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let esp_paths = EspPaths::new(tmpdir.path(), None);
+
+        let kernel = create_file(esp_paths.nixos.join("kernel-bzImage.efi"), b"kernel")?;
+        let stub = create_file(esp_paths.linux.join("nixos-generation-1.efi"), b"stub")?;
+        let systemd_boot = create_file(esp_paths.systemd_boot.clone(), b"systemd-boot")?;
+        let efi_fallback = create_file(
+            esp_paths.efi_fallbacks[esp_paths.architecture.name()].clone(),
+            b"systemd-boot",
+        )?;
+        let random_seed = create_file(esp_paths.random_seed.clone(), b"seed")?;
+        let loader_conf = create_file(esp_paths.loader_conf.clone(), b"timeout 3\n")?;
+
+        let foreign_uki = create_file(esp_paths.linux.join("ubuntu.efi"), b"ubuntu")?;
+        let foreign_boot_loader = create_file(
+            esp_paths.efi.join("Microsoft/Boot/bootmgfw.efi"),
+            b"windows",
+        )?;
+
+        uninstall(&esp_paths, None)?;
+
+        for path in [
+            &kernel,
+            &stub,
+            &systemd_boot,
+            &efi_fallback,
+            &random_seed,
+            &loader_conf,
+        ] {
+            assert!(!path.exists(), "{path:?} was not removed");
+        }
+        assert!(!esp_paths.nixos.exists());
+        assert!(!esp_paths.systemd.exists());
+        assert!(!esp_paths.loader.exists());
+
+        assert!(foreign_uki.exists());
+        assert!(foreign_boot_loader.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn only_remove_recorded_files_with_manifest() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let esp_paths = EspPaths::new(tmpdir.path(), None);
+
+        let stub = create_file(esp_paths.linux.join("nixos-generation-1.efi"), b"stub")?;
+        let entry = create_file(esp_paths.entries.join("nixos-generation-1.conf"), b"entry")?;
+        let efi_fallback = create_file(
+            esp_paths.efi_fallbacks[esp_paths.architecture.name()].clone(),
+            b"systemd-boot",
+        )?;
+        let mut manifest = Manifest::new(&esp_paths);
+        for path in [&stub, &entry, &efi_fallback] {
+            manifest.record(path)?;
+        }
+        fs::create_dir_all(&esp_paths.nixos)?;
+        manifest.write(&esp_paths.manifest)?;
+
+        // systemd-boot counts the boot attempts of the stub and another operating system takes
+        // over the fallback path.
+        let counted_stub = esp_paths.linux.join("nixos-generation-1+2-1.efi");
+        fs::rename(&stub, &counted_stub)?;
+        fs::write(&efi_fallback, b"another boot loader")?;
+        // A file that lanzaboote did not install, e.g. of another NixOS on the same ESP.
+        let foreign_stub = create_file(esp_paths.linux.join("nixos-other.efi"), b"other")?;
+
+        uninstall(&esp_paths, None)?;
+
+        assert!(!counted_stub.exists());
+        assert!(!entry.exists());
+        assert!(!esp_paths.nixos.exists());
+        assert!(!esp_paths.loader.exists());
+        assert!(efi_fallback.exists());
+        assert!(foreign_stub.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn remove_boot_options_of_stubs() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let efivars = tempfile::tempdir()?;
+        let esp_paths = EspPaths::new(tmpdir.path(), None);
+
+        create_file(esp_paths.linux.join("nixos-generation-1.efi"), b"stub")?;
+        let partition = Partition {
+            number: 1,
+            start: 2048,
+            size: 1048576,
+            guid: [0; 16],
+        };
+        let boot_option = |description: &str, path: &str| BootOption {
+            description: description.to_owned(),
+            path: path.to_owned(),
+        };
+        boot_options::sync_boot_options(
+            efivars.path(),
+            &partition,
+            &[
+                boot_option("NixOS", "\\EFI\\Linux\\nixos-generation-1.efi"),
+                boot_option("Windows", "\\EFI\\Microsoft\\Boot\\bootmgfw.efi"),
+            ],
+        )?;
+
+        uninstall(&esp_paths, Some((efivars.path(), &partition)))?;
+
+        let read_boot_variable =
+            |name: &str| status::read_efi_variable(efivars.path(), name, EFI_GLOBAL_VARIABLE);
+        assert_eq!(read_boot_variable("Boot0000")?, None);
+        assert!(read_boot_variable("Boot0001")?.is_some());
+        assert_eq!(read_boot_variable("BootOrder")?, Some(vec![1, 0]));
+        Ok(())
+    }
+
+    #[test]
+    fn keep_foreign_fallback_boot_loader() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let esp_paths = EspPaths::new(tmpdir.path(), None);
+
+        create_file(esp_paths.systemd_boot.clone(), b"systemd-boot")?;
+        let efi_fallback = create_file(
+            esp_paths.efi_fallbacks[esp_paths.architecture.name()].clone(),
+            b"windows",
+        )?;
+
+        uninstall(&esp_paths, None)?;
+
+        assert!(efi_fallback.exists());
+        Ok(())
+    }
+
+    fn create_file(path: PathBuf, contents: &[u8]) -> Result<PathBuf> {
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(&path, contents)?;
+        Ok(path)
+    }
+}
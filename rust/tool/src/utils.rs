@@ -1,10 +1,90 @@
 use std::fs;
-use std::io::Write;
-use std::os::unix::fs::OpenOptionsExt;
-use std::path::PathBuf;
+use std::io::{self, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::{Context, Result};
-use tempfile::TempDir;
+use filetime::FileTime;
+use nix::sys::statfs;
+use tempfile::{NamedTempFile, TempDir};
+
+use crate::error::LanzabooteError;
+
+/// Mode of files that only their owner may access, e.g. the random seed or files with secrets.
+pub const PRIVATE_FILE_MODE: u32 = 0o600;
+/// Mode of public files on the ESP, e.g. the EFI binaries.
+pub const PUBLIC_FILE_MODE: u32 = 0o644;
+
+/// Prefix of the files that new contents are staged in before they are renamed into place.
+const STAGING_PREFIX: &str = ".lzbt-staging-";
+/// Suffix of the backups that earlier versions made of the files they replaced.
+const BACKUP_SUFFIX: &str = ".lzbt-backup";
+
+/// Set the mode of a file explicitly instead of relying on the umask.
+pub fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+        .with_context(|| format!("Failed to set permissions of {path:?} to {mode:o}"))
+}
+
+/// Set the mode of a file on the ESP.
+///
+/// FAT cannot represent most modes. Unless it is mounted with the `quiet` option, it refuses
+/// changes that it cannot represent, which are ignored here.
+pub fn set_esp_mode(path: &Path, mode: u32) -> Result<()> {
+    match fs::set_permissions(path, fs::Permissions::from_mode(mode)) {
+        Err(e) if e.raw_os_error() == Some(nix::libc::EPERM) => Ok(()),
+        result => {
+            result.with_context(|| format!("Failed to set permissions of {path:?} to {mode:o}"))
+        }
+    }
+}
+
+/// Resolve a path to the file it points to if it is a symlink.
+///
+/// Kernels and initrds may be symlinks into other store paths. A dangling symlink is reported as
+/// such instead of surfacing as a confusing I/O error halfway through the installation.
+pub fn resolve_symlink(path: &Path) -> Result<PathBuf> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_symlink() => path
+            .canonicalize()
+            .with_context(|| format!("{path:?} is a dangling symlink")),
+        _ => Ok(path.to_path_buf()),
+    }
+}
+
+/// The directory of the Nix store, `/nix/store` unless overridden with `NIX_STORE_DIR`.
+pub fn store_dir() -> PathBuf {
+    std::env::var_os("NIX_STORE_DIR").map_or_else(|| PathBuf::from("/nix/store"), PathBuf::from)
+}
+
+/// The store path that contains a path, e.g. `/nix/store/...-linux-6.1.1` for its `bzImage`.
+///
+/// Returns `None` if the path is not inside the store.
+pub fn store_path(store_dir: &Path, path: &Path) -> Option<PathBuf> {
+    let name = path.strip_prefix(store_dir).ok()?.components().next()?;
+    Some(store_dir.join(name))
+}
+
+/// Create a temporary directory below `TMPDIR`.
+///
+/// Unlike `tempfile::tempdir`, a restrictive `TMPDIR` results in an error that names the
+/// directory instead of a bare I/O error.
+pub fn tempdir() -> Result<TempDir> {
+    tempfile::tempdir().map_err(|e| temp_path_error(&std::env::temp_dir(), e))
+}
+
+/// Explain a failure to create a temporary path, pointing out missing permissions.
+fn temp_path_error(path: &Path, error: io::Error) -> anyhow::Error {
+    let path = path.to_owned();
+    if error.kind() == io::ErrorKind::PermissionDenied {
+        anyhow::Error::new(error).context(LanzabooteError::TempDirNotWritable { path })
+    } else {
+        anyhow::Error::new(error).context(format!("Failed to create temporary path {path:?}"))
+    }
+}
 
 /// Extension for a temporary directory that enables creating secure temporary files in it.
 pub trait SecureTempDirExt {
@@ -27,12 +107,16 @@ impl SecureTempDirExt for TempDir {
     /// Create a temporary file that can only be accessed by the current Linux user.
     fn create_secure_file(&self, file_name: &str) -> Result<fs::File> {
         let path = self.path().join(file_name);
-        fs::OpenOptions::new()
+        let file = fs::OpenOptions::new()
             .create(true)
             .write(true)
-            .mode(0o600)
+            .truncate(true)
+            .mode(PRIVATE_FILE_MODE)
             .open(&path)
-            .with_context(|| format!("Failed to create tempfile: {path:?}"))
+            .map_err(|e| temp_path_error(&path, e))?;
+        // The mode only applies to newly created files.
+        set_mode(&path, PRIVATE_FILE_MODE)?;
+        Ok(file)
     }
 
     /// Create a temporary file and write a `u8` slice to it.
@@ -47,3 +131,494 @@ impl SecureTempDirExt for TempDir {
         Ok(path)
     }
 }
+
+/// Atomically replace the contents of a file.
+///
+/// The contents are written to a uniquely named temporary file in the same directory, which is
+/// synced to disk and then renamed to the destination. Thus, readers (and concurrent writers) see
+/// either the old or the new contents, but never a partially written file. The file gets the
+/// given mode if the file system supports it.
+pub fn atomic_write(path: &Path, contents: impl AsRef<[u8]>, mode: u32) -> Result<()> {
+    let directory = path
+        .parent()
+        .with_context(|| format!("Failed to find parent directory of {path:?}"))?;
+    fs::create_dir_all(directory)
+        .with_context(|| format!("Failed to create directory {directory:?}"))?;
+
+    let mut tmpfile = staging_file(directory)?;
+    set_esp_mode(tmpfile.path(), mode)?;
+    tmpfile
+        .write_all(contents.as_ref())
+        .and_then(|_| tmpfile.as_file().sync_all())
+        .with_context(|| format!("Failed to write to temporary file {:?}", tmpfile.path()))?;
+    tmpfile
+        .persist(path)
+        .with_context(|| format!("Failed to rename temporary file to {path:?}"))?;
+
+    // Also sync the directory so that the rename itself is persisted.
+    fs::File::open(directory)
+        .and_then(|directory| directory.sync_all())
+        .with_context(|| format!("Failed to sync directory {directory:?}"))?;
+
+    Ok(())
+}
+
+/// Atomically replace a file with one that `write` creates at the given path.
+///
+/// Unlike `atomic_write`, this works for external programs that write their output to a path
+/// (e.g. sbsign). The new file is staged next to the destination, synced and renamed over it. The
+/// rename is atomic, so an existing file stays in place until the new one is complete and a failed
+/// update never breaks a working file, e.g. the fallback boot loader that is the last resort to
+/// boot. No copy of the existing file is needed, so the update only takes the space of the new
+/// file.
+pub fn atomic_replace(path: &Path, write: impl FnOnce(&Path) -> Result<()>) -> Result<()> {
+    let directory = path
+        .parent()
+        .with_context(|| format!("Failed to find parent directory of {path:?}"))?;
+    // The staging file is removed when it is dropped without having been renamed.
+    let staging = staging_file(directory)?.into_temp_path();
+
+    write(&staging)?;
+    fs::File::open(&staging)
+        .and_then(|file| file.sync_all())
+        .with_context(|| format!("Failed to sync {:?}", &*staging))?;
+    staging
+        .persist(path)
+        .with_context(|| format!("Failed to rename temporary file to {path:?}"))?;
+    fs::File::open(directory)
+        .and_then(|directory| directory.sync_all())
+        .with_context(|| format!("Failed to sync directory {directory:?}"))
+}
+
+/// Create a file in `directory` to stage the new contents of a file in.
+///
+/// The staging file is on the same file system as the destination, so that it can be renamed
+/// into place.
+fn staging_file(directory: &Path) -> Result<NamedTempFile> {
+    tempfile::Builder::new()
+        .prefix(STAGING_PREFIX)
+        .tempfile_in(directory)
+        .with_context(|| format!("Failed to create temporary file in {directory:?}"))
+}
+
+/// Remove the staging files and backups that an interrupted installation left in `directory`.
+///
+/// The destination of a staging file or backup always holds either its previous or its new
+/// contents, because the staging file is only renamed into place once it is complete. Thus, the
+/// leftovers are never needed anymore.
+pub fn remove_stale_staging_files(directory: &Path) -> Result<()> {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {directory:?}")),
+    };
+    for entry in entries {
+        let path = entry
+            .with_context(|| format!("Failed to read {directory:?}"))?
+            .path();
+        let is_stale = path.file_name().map_or(false, |name| {
+            let name = name.to_string_lossy();
+            name.starts_with(STAGING_PREFIX) || name.ends_with(BACKUP_SUFFIX)
+        });
+        if is_stale && path.is_file() {
+            println!(
+                "Removing {} left by an interrupted installation...",
+                path.display()
+            );
+            fs::remove_file(&path).with_context(|| format!("Failed to remove {path:?}"))?;
+        }
+    }
+    Ok(())
+}
+
+/// Atomically replace a file with a hard link to an identical file, or with a copy of it if the
+/// file system does not support hard links.
+///
+/// FAT, which ESPs are usually formatted with, has no hard links. Returns whether the file was
+/// hard linked.
+pub fn link_or_copy(original: &Path, path: &Path) -> Result<bool> {
+    let directory = path
+        .parent()
+        .with_context(|| format!("Failed to find parent directory of {path:?}"))?;
+    let mut linked =
+        statfs::statfs(directory).map_or(false, |stat| supports_hard_links(stat.filesystem_type()));
+
+    atomic_replace(path, |staging| {
+        if linked {
+            fs::remove_file(staging)
+                .with_context(|| format!("Failed to remove temporary file {staging:?}"))?;
+            // The file system may still refuse, e.g. if the original is on another mount.
+            linked = fs::hard_link(original, staging).is_ok();
+        }
+        if !linked {
+            fs::copy(original, staging)
+                .with_context(|| format!("Failed to copy {original:?} to {staging:?}"))?;
+        }
+        Ok(())
+    })?;
+    Ok(linked)
+}
+
+fn supports_hard_links(filesystem_type: statfs::FsType) -> bool {
+    filesystem_type != statfs::MSDOS_SUPER_MAGIC
+}
+
+/// The time of the build that reproducible outputs use, taken from `SOURCE_DATE_EPOCH`.
+///
+/// This is the only place that reads the variable, so that all timestamps lanzaboote emits agree.
+/// Returns `None` if it is unset. An invalid value is ignored with a warning.
+pub fn build_epoch() -> Option<SystemTime> {
+    let value = std::env::var("SOURCE_DATE_EPOCH").ok()?;
+    match value.parse::<u64>() {
+        Ok(seconds) => Some(UNIX_EPOCH + Duration::from_secs(seconds)),
+        Err(_) => {
+            println!("Warning: ignoring invalid SOURCE_DATE_EPOCH {value:?}");
+            None
+        }
+    }
+}
+
+/// The number of seconds since the Unix epoch. Earlier times are clamped to the epoch.
+pub fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+/// Round a timestamp down to the 2-second granularity of FAT modification times.
+///
+/// Setting an already rounded timestamp ensures that the timestamps read back from the ESP are
+/// exactly the ones that were set, independent of how the FAT driver rounds.
+pub fn fat_timestamp(seconds: i64) -> FileTime {
+    FileTime::from_unix_time(seconds - seconds.rem_euclid(2), 0)
+}
+
+/// The alphabet of standard base64 (RFC 4648).
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Encode data as padded base64.
+pub fn encode_base64(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, byte)| {
+            bits | (u32::from(*byte) << (16 - 8 * i))
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64_ALPHABET[((bits >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Decode (padded) base64, ignoring whitespace.
+pub fn decode_base64(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    let mut bits: u32 = 0;
+    let mut bit_count = 0;
+    for c in encoded.bytes().filter(|c| !c.is_ascii_whitespace()) {
+        let value = match c {
+            b'=' => break,
+            _ => BASE64_ALPHABET.iter().position(|&a| a == c)?,
+        };
+        bits = ((bits << 6) | value as u32) & 0xffff;
+        bit_count += 6;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            decoded.push((bits >> bit_count) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+/// The default number of workers (files to copy, stubs to assemble or verify) at the same time:
+/// one per CPU.
+pub fn default_concurrency() -> usize {
+    thread::available_parallelism().map_or(1, |parallelism| parallelism.get())
+}
+
+/// Run `task` for every item on a pool of up to `concurrency` threads.
+///
+/// Every thread takes the next item as soon as it finished its previous one, so a slow item does
+/// not hold back the others. Once a task failed, no further items are started. All threads are
+/// joined before returning, and a task that panicked is reported as an error instead of
+/// unwinding into the caller. Returns the first error.
+pub fn run_concurrently<T: Sync>(
+    items: &[T],
+    concurrency: usize,
+    task: impl Fn(&T) -> Result<()> + Sync,
+) -> Result<()> {
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let worker = || -> Result<()> {
+        while !failed.load(Ordering::Relaxed) {
+            let item = match items.get(next.fetch_add(1, Ordering::Relaxed)) {
+                Some(item) => item,
+                None => break,
+            };
+            if let Err(e) = task(item) {
+                failed.store(true, Ordering::Relaxed);
+                return Err(e);
+            }
+        }
+        Ok(())
+    };
+
+    thread::scope(|scope| {
+        let workers = (0..concurrency.clamp(1, items.len().max(1)))
+            .map(|_| scope.spawn(worker))
+            .collect::<Vec<_>>();
+        let mut result = Ok(());
+        for worker in workers {
+            let outcome = worker.join().unwrap_or_else(|panic| {
+                failed.store(true, Ordering::Relaxed);
+                let message = panic
+                    .downcast_ref::<&str>()
+                    .copied()
+                    .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                    .unwrap_or("unknown cause");
+                Err(anyhow::anyhow!("A worker thread panicked: {message}"))
+            });
+            if result.is_ok() {
+                result = outcome;
+            }
+        }
+        result
+    })
+}
+
+/// Parse a duration given as a number with a unit, e.g. `90s`, `30m`, `12h`, `14d` or `2w`.
+pub fn parse_duration(value: &str) -> Result<Duration> {
+    let unit_start = value
+        .find(|c: char| !c.is_ascii_digit())
+        .with_context(|| format!("The duration {value:?} has no unit (s, m, h, d or w)"))?;
+    let (number, unit) = value.split_at(unit_start);
+    let number: u64 = number
+        .parse()
+        .with_context(|| format!("Invalid duration {value:?}"))?;
+    let unit_seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => {
+            return Err(anyhow::anyhow!(
+                "Unknown unit {unit:?} of duration {value:?}, expected one of s, m, h, d or w"
+            ))
+        }
+    };
+    let seconds = number
+        .checked_mul(unit_seconds)
+        .with_context(|| format!("The duration {value:?} is too long"))?;
+    Ok(Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_durations_with_units() -> Result<()> {
+        assert_eq!(parse_duration("90s")?, Duration::from_secs(90));
+        assert_eq!(parse_duration("12h")?, Duration::from_secs(12 * 60 * 60));
+        assert_eq!(
+            parse_duration("2w")?,
+            Duration::from_secs(14 * 24 * 60 * 60)
+        );
+        assert!(parse_duration("14").is_err());
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("3y").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn encode_and_decode_base64() {
+        for (data, encoded) in [
+            (&b""[..], ""),
+            (b"M", "TQ=="),
+            (b"Ma", "TWE="),
+            (b"Man", "TWFu"),
+            (b"\xff\xfe\x00\x01", "//4AAQ=="),
+        ] {
+            assert_eq!(encode_base64(data), encoded);
+            assert_eq!(decode_base64(encoded).as_deref(), Some(data));
+        }
+        // Whitespace (e.g. the line breaks in PEM files) is ignored, padding is optional.
+        assert_eq!(
+            decode_base64("TW\nFu\r\nTW E").as_deref(),
+            Some(&b"ManMa"[..])
+        );
+        assert_eq!(decode_base64("TWFu!"), None);
+    }
+
+    #[test]
+    fn run_every_item_on_a_bounded_pool() -> Result<()> {
+        let items: Vec<usize> = (0..32).collect();
+        let done = std::sync::Mutex::new(Vec::new());
+        let running = AtomicUsize::new(0);
+        let most_running = AtomicUsize::new(0);
+        run_concurrently(&items, 4, |item| {
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            most_running.fetch_max(now, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(1));
+            running.fetch_sub(1, Ordering::SeqCst);
+            done.lock().unwrap().push(*item);
+            Ok(())
+        })?;
+
+        let mut done = done.into_inner().unwrap();
+        done.sort();
+        assert_eq!(done, items);
+        assert!(most_running.into_inner() <= 4);
+        Ok(())
+    }
+
+    #[test]
+    fn report_failed_and_panicked_tasks() {
+        let items = [1, 2, 3];
+        let error = run_concurrently(&items, 2, |item| match item {
+            2 => Err(anyhow::anyhow!("Item {item} failed")),
+            _ => Ok(()),
+        })
+        .unwrap_err();
+        assert_eq!(error.to_string(), "Item 2 failed");
+
+        let error = run_concurrently(&items, 2, |item| {
+            if *item == 3 {
+                panic!("Item {item} panicked");
+            }
+            Ok(())
+        })
+        .unwrap_err();
+        assert!(error.to_string().contains("Item 3 panicked"));
+
+        assert!(run_concurrently(&[] as &[u8], 4, |_| Ok(())).is_ok());
+    }
+
+    #[test]
+    fn copy_instead_of_linking_on_fat() -> Result<()> {
+        assert!(!supports_hard_links(statfs::MSDOS_SUPER_MAGIC));
+        assert!(supports_hard_links(statfs::TMPFS_MAGIC));
+
+        let tmpdir = tempfile::tempdir()?;
+        let original = tmpdir.path().join("original");
+        let path = tmpdir.path().join("copy");
+        fs::write(&original, "contents")?;
+        fs::write(&path, "stale")?;
+        link_or_copy(&original, &path)?;
+        assert_eq!(fs::read(&path)?, b"contents");
+        Ok(())
+    }
+
+    #[test]
+    fn round_timestamps_to_fat_granularity() {
+        assert_eq!(
+            fat_timestamp(1672531200),
+            FileTime::from_unix_time(1672531200, 0)
+        );
+        assert_eq!(
+            fat_timestamp(1672531201),
+            FileTime::from_unix_time(1672531200, 0)
+        );
+        assert_eq!(fat_timestamp(-1), FileTime::from_unix_time(-2, 0));
+    }
+
+    #[test]
+    fn keep_previous_file_if_replacement_fails() -> Result<()> {
+        let directory = tempfile::tempdir()?;
+        let path = directory.path().join("BOOTX64.EFI");
+        fs::write(&path, "previous")?;
+
+        let result = atomic_replace(&path, |staging| {
+            fs::write(staging, "partial")?;
+            Err(anyhow::anyhow!("Signing failed"))
+        });
+        assert!(result.is_err());
+        assert_eq!(fs::read_to_string(&path)?, "previous");
+        // Neither the staged file nor a copy of the previous file is left behind.
+        assert_eq!(fs::read_dir(directory.path())?.count(), 1);
+
+        atomic_replace(&path, |staging| Ok(fs::write(staging, "next")?))?;
+        assert_eq!(fs::read_to_string(&path)?, "next");
+        assert_eq!(fs::read_dir(directory.path())?.count(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn remove_leftovers_of_interrupted_installation() -> Result<()> {
+        let directory = tempfile::tempdir()?;
+        let path = directory.path().join("nixos-generation-1.efi");
+        fs::write(&path, "installed")?;
+        let staging = staging_file(directory.path())?.into_temp_path().keep()?;
+        let backup = directory.path().join("nixos-generation-1.efi.lzbt-backup");
+        fs::write(&backup, "installed")?;
+
+        remove_stale_staging_files(directory.path())?;
+        assert!(path.exists());
+        assert!(!staging.exists());
+        assert!(!backup.exists());
+
+        remove_stale_staging_files(&directory.path().join("missing"))?;
+        Ok(())
+    }
+
+    #[test]
+    fn explain_unwritable_tempdir() -> Result<()> {
+        let directory = tempfile::tempdir()?;
+        set_mode(directory.path(), 0o500)?;
+        // Permissions do not apply to root. explain_permission_denied covers the explanation
+        // regardless of the user the tests run as.
+        if fs::File::create(directory.path().join("probe")).is_ok() {
+            eprintln!("Skipping explain_unwritable_tempdir: permissions do not apply to this user");
+            set_mode(directory.path(), 0o700)?;
+            return Ok(());
+        }
+
+        let error = directory
+            .write_secure_file("kernel", "kernel")
+            .expect_err("Created a file in an unwritable directory");
+        set_mode(directory.path(), 0o700)?;
+
+        assert!(matches!(
+            error.downcast_ref::<LanzabooteError>(),
+            Some(LanzabooteError::TempDirNotWritable { path }) if path == &directory.path().join("kernel")
+        ));
+        assert!(format!("{error:#}").contains("Set TMPDIR"));
+        Ok(())
+    }
+
+    #[test]
+    fn explain_permission_denied() {
+        let path = Path::new("/tmp/lanzaboote");
+        let error = temp_path_error(path, io::Error::from(io::ErrorKind::PermissionDenied));
+        assert!(matches!(
+            error.downcast_ref::<LanzabooteError>(),
+            Some(LanzabooteError::TempDirNotWritable { path: error_path }) if error_path == path
+        ));
+        assert!(format!("{error:#}").contains("Set TMPDIR"));
+
+        let error = temp_path_error(path, io::Error::from(io::ErrorKind::NotFound));
+        assert!(error.downcast_ref::<LanzabooteError>().is_none());
+        assert!(format!("{error:#}").contains("Failed to create temporary path"));
+    }
+
+    #[test]
+    fn find_store_path_of_file() {
+        let store_dir = Path::new("/nix/store");
+        assert_eq!(
+            store_path(store_dir, Path::new("/nix/store/abc-linux-6.1.1/bzImage")),
+            Some(PathBuf::from("/nix/store/abc-linux-6.1.1"))
+        );
+        assert_eq!(
+            store_path(store_dir, Path::new("/nix/store/abc-system")),
+            Some(PathBuf::from("/nix/store/abc-system"))
+        );
+        assert_eq!(store_path(store_dir, Path::new("/nix/store")), None);
+        assert_eq!(store_path(store_dir, Path::new("/boot/EFI/nixos")), None);
+    }
+}
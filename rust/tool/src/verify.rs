@@ -0,0 +1,551 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
+
+use crate::esp::{self, EspPaths};
+use crate::loader_conf::LoaderConf;
+use crate::manifest::Manifest;
+use crate::pe;
+use crate::signature;
+use crate::status;
+
+/// The outcome of verifying a single installed stub.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct StubVerification {
+    pub stub: PathBuf,
+    /// The reason the stub is inconsistent, or `None` if it is consistent.
+    pub error: Option<String>,
+    /// Whether the initrd secrets appended to the initrd are not covered by the embedded hash.
+    ///
+    /// The secrets may be regenerated after the installation, so they are not verified.
+    pub unverified_initrd_secrets: bool,
+}
+
+/// The outcome of verifying systemd-boot or a fallback boot loader.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct LoaderVerification {
+    pub loader: PathBuf,
+    /// Why the boot loader cannot be booted with Secure Boot, or `None` if it can.
+    pub error: Option<String>,
+}
+
+/// The outcome of verifying the default entry of `loader.conf`.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct DefaultEntryVerification {
+    pub entry: String,
+    /// Why the entry cannot be booted, or `None` if it can.
+    pub error: Option<String>,
+}
+
+/// The outcome of verifying the boot loaders and all installed stubs, sorted by generation.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct VerifyReport {
+    pub loaders: Vec<LoaderVerification>,
+    pub stubs: Vec<StubVerification>,
+    /// The default entry, if `loader.conf` names a concrete one.
+    pub default_entry: Option<DefaultEntryVerification>,
+}
+
+impl VerifyReport {
+    /// The number of inconsistent stubs, counting broken boot loaders and a broken default entry
+    /// as well.
+    pub fn failures(&self) -> usize {
+        self.loaders
+            .iter()
+            .map(|verification| &verification.error)
+            .chain(self.stubs.iter().map(|verification| &verification.error))
+            .chain(self.default_entry.iter().map(|default| &default.error))
+            .filter(|error| error.is_some())
+            .count()
+    }
+}
+
+/// Display the report with one line per boot loader and stub.
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for verification in &self.loaders {
+            match &verification.error {
+                None => writeln!(f, "{}: OK", verification.loader.display())?,
+                Some(error) => writeln!(f, "{}: {error}", verification.loader.display())?,
+            }
+        }
+        for verification in &self.stubs {
+            match &verification.error {
+                None if verification.unverified_initrd_secrets => writeln!(
+                    f,
+                    "{}: OK (the appended initrd secrets are not covered by the hash)",
+                    verification.stub.display()
+                )?,
+                None => writeln!(f, "{}: OK", verification.stub.display())?,
+                Some(error) => writeln!(f, "{}: {error}", verification.stub.display())?,
+            }
+        }
+        if let Some(default) = &self.default_entry {
+            match &default.error {
+                None => writeln!(f, "Default entry {}: OK", default.entry)?,
+                Some(error) => writeln!(f, "Default entry {}: {error}", default.entry)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A link of the chain of trust from the firmware to the kernel and initrd.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChainLink {
+    /// The certificate is enrolled in the signature database `db` of the firmware.
+    Firmware,
+    /// Neither the certificate nor its issuer is revoked by the forbidden signature database
+    /// `dbx`.
+    FirmwareRevocations,
+    /// systemd-boot is signed with the key of the certificate.
+    SystemdBoot(PathBuf),
+    /// A fallback boot loader is signed with the key of the certificate.
+    FallbackLoader(PathBuf),
+    /// A stub is signed with the key of the certificate.
+    StubSignature(PathBuf),
+    /// The kernel and initrd match the hashes embedded into a stub.
+    StubContents(PathBuf),
+}
+
+impl fmt::Display for ChainLink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Firmware => write!(f, "Firmware db"),
+            Self::FirmwareRevocations => write!(f, "Firmware dbx"),
+            Self::SystemdBoot(path) => write!(f, "systemd-boot {}", path.display()),
+            Self::FallbackLoader(path) => write!(f, "Fallback boot loader {}", path.display()),
+            Self::StubSignature(stub) => write!(f, "Signature of {}", stub.display()),
+            Self::StubContents(stub) => write!(f, "Contents of {}", stub.display()),
+        }
+    }
+}
+
+/// The outcome of verifying a single link of the chain of trust.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ChainLinkVerification {
+    pub link: ChainLink,
+    /// Why the chain is broken at this link, or `None` if it holds.
+    pub error: Option<String>,
+}
+
+/// The outcome of verifying the whole chain of trust, ordered from the firmware to the stubs.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ChainReport {
+    pub links: Vec<ChainLinkVerification>,
+}
+
+impl ChainReport {
+    /// The links at which the chain is broken.
+    pub fn broken_links(&self) -> impl Iterator<Item = &ChainLinkVerification> {
+        self.links
+            .iter()
+            .filter(|verification| verification.error.is_some())
+    }
+}
+
+/// Display the report with one line per link.
+impl fmt::Display for ChainReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for verification in &self.links {
+            match &verification.error {
+                None => writeln!(f, "{}: OK", verification.link)?,
+                Some(error) => writeln!(f, "{}: {error}", verification.link)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Verify the chain of trust from the firmware to the kernels and initrds.
+///
+/// The certificate `public_key` has to be enrolled in the firmware and must not be revoked,
+/// systemd-boot, the fallback boot loaders and all stubs have to be signed with its key and must
+/// not be revoked by `dbx` either, and the kernels and initrds have to match the hashes embedded
+/// into the stubs. All links are checked, so that every break of the chain is reported.
+pub fn verify_chain(
+    esp_paths: &EspPaths,
+    efivars: &Path,
+    public_key: &Path,
+) -> Result<ChainReport> {
+    let certificate = signature::certificate_der(
+        &fs::read(public_key)
+            .with_context(|| format!("Failed to read public key {public_key:?}"))?,
+    )?;
+
+    let public_keys = [public_key.to_path_buf()];
+    let signed_initrds = initrds_signed(esp_paths);
+    let check_binary = |path: &Path| {
+        check_signature(path, &public_keys).and_then(|_| check_revocation(path, efivars))
+    };
+
+    let check = |link: ChainLink, result: Result<()>| ChainLinkVerification {
+        link,
+        error: result.err().map(|e| format!("{e:#}")),
+    };
+
+    let mut links = vec![
+        check(
+            ChainLink::Firmware,
+            status::is_certificate_enrolled(efivars, &certificate).and_then(|enrolled| {
+                if enrolled {
+                    Ok(())
+                } else {
+                    Err(anyhow!("{public_key:?} is not enrolled in db"))
+                }
+            }),
+        ),
+        check(
+            ChainLink::FirmwareRevocations,
+            status::is_certificate_revoked(efivars, &certificate).and_then(|revoked| {
+                if revoked {
+                    Err(anyhow!("{public_key:?} is revoked by dbx"))
+                } else {
+                    Ok(())
+                }
+            }),
+        ),
+        check(
+            ChainLink::SystemdBoot(esp_paths.systemd_boot.clone()),
+            check_binary(&esp_paths.systemd_boot),
+        ),
+    ];
+    for loader in efi_binaries(&esp_paths.efi_fallback_dir)? {
+        links.push(check(
+            ChainLink::FallbackLoader(loader.clone()),
+            check_binary(&loader),
+        ));
+    }
+
+    let mut stubs = esp::nixos_images(&esp_paths.linux)?;
+    stubs.sort_by_key(|stub| (esp::image_version(stub), stub.clone()));
+    for stub in stubs {
+        links.push(check(
+            ChainLink::StubSignature(stub.clone()),
+            check_binary(&stub),
+        ));
+        links.push(check(
+            ChainLink::StubContents(stub.clone()),
+            check_stub(&stub, &esp_paths.boot, &public_keys, signed_initrds).map(|_| ()),
+        ));
+    }
+
+    Ok(ChainReport { links })
+}
+
+/// Whether the manifest records that a detached signature was written next to every initrd.
+fn initrds_signed(esp_paths: &EspPaths) -> bool {
+    esp_paths.manifest.exists() && Manifest::read(esp_paths).signed_initrds()
+}
+
+/// Check that a PE binary exists and is signed, with the key of one of the certificates
+/// `public_keys` if any are given.
+fn check_signature(path: &Path, public_keys: &[PathBuf]) -> Result<()> {
+    let data = fs::read(path).with_context(|| format!("Failed to read {path:?}"))?;
+    if !pe::is_signed(&data)? {
+        return Err(anyhow!("{path:?} is not signed"));
+    }
+    verify_with_any(public_keys, |public_key| {
+        signature::verify_signature(path, public_key)
+    })
+}
+
+/// Check that the Authenticode digest of a PE binary is not revoked by `dbx`.
+fn check_revocation(path: &Path, efivars: &Path) -> Result<()> {
+    let data = fs::read(path).with_context(|| format!("Failed to read {path:?}"))?;
+    if status::is_binary_revoked(efivars, &pe::authenticode_digest(&data)?)? {
+        return Err(anyhow!("{path:?} is revoked by dbx"));
+    }
+    Ok(())
+}
+
+/// Try the certificates one after the other until one of them verifies.
+///
+/// Without any certificates, there is nothing to verify. Otherwise, the error of the last
+/// certificate is returned if none of them verifies.
+fn verify_with_any(public_keys: &[PathBuf], verify: impl Fn(&Path) -> Result<()>) -> Result<()> {
+    let mut result = Ok(());
+    for public_key in public_keys {
+        result = verify(public_key);
+        if result.is_ok() {
+            break;
+        }
+    }
+    result
+}
+
+/// All installed boot loaders: systemd-boot and the fallback boot loaders of every
+/// architecture.
+///
+/// systemd-boot of the running architecture is always included, so that a missing one is
+/// reported.
+fn boot_loaders(esp_paths: &EspPaths) -> Result<Vec<PathBuf>> {
+    let mut loaders = vec![esp_paths.systemd_boot.clone()];
+    for dir in [&esp_paths.systemd, &esp_paths.efi_fallback_dir] {
+        for path in efi_binaries(dir)? {
+            if !loaders.contains(&path) {
+                loaders.push(path);
+            }
+        }
+    }
+    loaders.sort();
+    Ok(loaders)
+}
+
+/// The EFI binaries in a directory, sorted by name. A missing directory has none.
+fn efi_binaries(dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {dir:?}")),
+    };
+    let mut binaries = Vec::new();
+    for entry in entries {
+        let path = entry
+            .with_context(|| format!("Failed to read {dir:?}"))?
+            .path();
+        let is_efi = path
+            .extension()
+            .map_or(false, |extension| extension.eq_ignore_ascii_case("efi"));
+        if is_efi && path.is_file() {
+            binaries.push(path);
+        }
+    }
+    binaries.sort();
+    Ok(binaries)
+}
+
+/// Verify systemd-boot, the fallback boot loaders and all installed stubs, at most
+/// `concurrency` stubs at the same time.
+///
+/// Hashing the kernels and initrds dominates the run time, so checking several stubs at once
+/// speeds up the verification considerably. The report does not depend on the concurrency.
+///
+/// Every boot loader and stub has to be signed. If certificates are given in `public_keys`, they
+/// have to be signed with the key of one of them, as must the referenced files that have a
+/// detached signature.
+pub fn verify(
+    esp_paths: &EspPaths,
+    concurrency: usize,
+    public_keys: &[PathBuf],
+) -> Result<VerifyReport> {
+    let loaders = boot_loaders(esp_paths)?
+        .into_iter()
+        .map(|loader| LoaderVerification {
+            error: check_signature(&loader, public_keys)
+                .err()
+                .map(|e| format!("{e:#}")),
+            loader,
+        })
+        .collect();
+
+    let mut stubs = esp::nixos_images(&esp_paths.linux)?;
+    stubs.sort_by_key(|stub| (esp::image_version(stub), stub.clone()));
+
+    let signed_initrds = initrds_signed(esp_paths);
+    let mut verifications = Vec::with_capacity(stubs.len());
+    for batch in stubs.chunks(concurrency.max(1)) {
+        thread::scope(|scope| {
+            let workers = batch
+                .iter()
+                .map(|stub| {
+                    scope.spawn(move || {
+                        verify_one(stub, &esp_paths.boot, public_keys, signed_initrds)
+                    })
+                })
+                .collect::<Vec<_>>();
+            for worker in workers {
+                verifications.push(worker.join().expect("Failed to join verify worker"));
+            }
+        });
+    }
+
+    Ok(VerifyReport {
+        loaders,
+        stubs: verifications,
+        default_entry: verify_default_entry(esp_paths, public_keys)?,
+    })
+}
+
+/// Make sure that the default entry of `loader.conf` is an installed, signed and consistent stub.
+///
+/// Patterns and special entries like `@saved` are only resolved by systemd-boot at boot time, so
+/// they are not checked.
+fn verify_default_entry(
+    esp_paths: &EspPaths,
+    public_keys: &[PathBuf],
+) -> Result<Option<DefaultEntryVerification>> {
+    let loader_conf = LoaderConf::read(&esp_paths.loader_conf)?;
+    let entry = match loader_conf.default_entry() {
+        Some(entry) => entry.to_owned(),
+        None => return Ok(None),
+    };
+
+    let error = check_default_entry(esp_paths, &entry, public_keys)
+        .err()
+        .map(|e| format!("{e:#}"));
+    Ok(Some(DefaultEntryVerification { entry, error }))
+}
+
+fn check_default_entry(esp_paths: &EspPaths, entry: &str, public_keys: &[PathBuf]) -> Result<()> {
+    let path = esp_paths.linux.join(entry);
+    // The entry names the stub without the boot counter that systemd-boot may have added.
+    let stub = esp::installed_image(&path)?.with_context(|| {
+        format!("{path:?} does not exist. Was the generation of the default entry pruned?")
+    })?;
+
+    check_signature(&stub, public_keys)?;
+    pe::verify_stub(&stub, &esp_paths.boot)
+}
+
+fn verify_one(
+    stub: &Path,
+    boot: &Path,
+    public_keys: &[PathBuf],
+    signed_initrds: bool,
+) -> StubVerification {
+    let result = check_signature(stub, public_keys)
+        .and_then(|()| check_stub(stub, boot, public_keys, signed_initrds));
+    let (error, unverified_initrd_secrets) = match result {
+        Ok(unverified_initrd_secrets) => (None, unverified_initrd_secrets),
+        Err(e) => (Some(format!("{e:#}")), false),
+    };
+    StubVerification {
+        stub: stub.to_owned(),
+        error,
+        unverified_initrd_secrets,
+    }
+}
+
+/// Check a stub and the files it references.
+///
+/// An initrd whose hash covers only the base initrd is compared only up to the end of the base
+/// initrd, so that regenerated initrd secrets are not reported as a mismatch. Returns whether this
+/// is the case.
+///
+/// If the initrds were installed with detached signatures (`signed_initrds`), an initrd without
+/// one is an error, because removing the signature must not disable its verification.
+fn check_stub(
+    stub: &Path,
+    boot: &Path,
+    public_keys: &[PathBuf],
+    signed_initrds: bool,
+) -> Result<bool> {
+    pe::verify_stub(stub, boot)?;
+    let references = pe::stub_references(stub, boot)?;
+    for reference in &references {
+        let signature = esp::detached_signature_path(&reference.path);
+        if signature.exists() {
+            verify_with_any(public_keys, |public_key| {
+                signature::verify_detached_signature(&reference.path, &signature, public_key)
+            })?;
+        } else if signed_initrds && !reference.is_kernel() {
+            return Err(anyhow!(
+                "{:?} has no detached signature, but the initrds were installed with one",
+                reference.path
+            ));
+        }
+    }
+
+    // The credentials and system extensions in the companion directory are verified by their
+    // detached signatures as well. Either all of them are signed or none.
+    let extra_files: Vec<PathBuf> = esp::extra_files(stub)?
+        .into_iter()
+        .filter(|path| path.is_file() && path.extension().map_or(true, |e| e != "sig"))
+        .collect();
+    let (signed, unsigned): (Vec<&PathBuf>, Vec<&PathBuf>) = extra_files
+        .iter()
+        .partition(|path| esp::detached_signature_path(path).exists());
+    if !signed.is_empty() && !unsigned.is_empty() {
+        return Err(anyhow!(
+            "The extra files {} have no detached signature",
+            unsigned
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    }
+    for path in signed {
+        let signature = esp::detached_signature_path(path);
+        verify_with_any(public_keys, |public_key| {
+            signature::verify_detached_signature(path, &signature, public_key)
+        })?;
+    }
+    Ok(references.iter().any(pe::StubReference::covers_prefix_only))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn parallel_report_matches_serial_report() -> Result<()> {
+        let esp = tempfile::tempdir()?;
+        let esp_paths = EspPaths::new(esp.path(), None);
+        fs::create_dir_all(&esp_paths.linux)?;
+        for version in [1, 9, 10, 11] {
+            fs::write(
+                esp_paths
+                    .linux
+                    .join(format!("nixos-generation-{version}.efi")),
+                b"not a stub",
+            )?;
+        }
+
+        let serial = verify(&esp_paths, 1, &[])?;
+        let parallel = verify(&esp_paths, 3, &[])?;
+
+        assert_eq!(serial, parallel);
+        // systemd-boot is missing as well.
+        assert_eq!(serial.failures(), 5);
+        let versions: Vec<_> = serial
+            .stubs
+            .iter()
+            .filter_map(|verification| esp::image_version(&verification.stub))
+            .collect();
+        assert_eq!(versions, [1, 9, 10, 11]);
+        Ok(())
+    }
+
+    #[test]
+    fn flag_dangling_default_entry() -> Result<()> {
+        let esp = tempfile::tempdir()?;
+        let esp_paths = EspPaths::new(esp.path(), None);
+        fs::create_dir_all(&esp_paths.loader)?;
+        fs::write(&esp_paths.loader_conf, "default nixos-generation-1.efi\n")?;
+
+        let report = verify(&esp_paths, 1, &[])?;
+
+        let default_entry = report
+            .default_entry
+            .as_ref()
+            .expect("Default entry was not checked");
+        assert_eq!(default_entry.entry, "nixos-generation-1.efi");
+        assert!(default_entry
+            .error
+            .as_ref()
+            .map_or(false, |error| error.contains("does not exist")));
+        // systemd-boot is missing as well.
+        assert_eq!(report.failures(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn skip_default_entry_patterns() -> Result<()> {
+        let esp = tempfile::tempdir()?;
+        let esp_paths = EspPaths::new(esp.path(), None);
+        fs::create_dir_all(&esp_paths.loader)?;
+        fs::write(&esp_paths.loader_conf, "default nixos-*\n")?;
+
+        assert_eq!(verify(&esp_paths, 1, &[])?.default_entry, None);
+        Ok(())
+    }
+}
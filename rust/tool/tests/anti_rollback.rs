@@ -0,0 +1,143 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+use common::LANZABOOTE_VENDOR;
+
+/// Set the security version in the lanzaboote extension of the bootspec of a generation.
+fn set_security_version(generation_link: &Path, security_version: u64) -> Result<()> {
+    let bootspec_path = generation_link.join("boot.json");
+    let mut bootspec: serde_json::Value = serde_json::from_slice(&fs::read(&bootspec_path)?)?;
+    bootspec["v1"]["extensions"]["lanzaboote"]["securityVersion"] =
+        serde_json::json!(security_version);
+    fs::write(bootspec_path, serde_json::to_vec(&bootspec)?)?;
+    Ok(())
+}
+
+fn minimum_security_version_path(efivars: &Path) -> PathBuf {
+    efivars.join(format!(
+        "LanzabooteMinimumSecurityVersion-{LANZABOOTE_VENDOR}"
+    ))
+}
+
+/// Write the variable as efivarfs shows it, with only the attributes and the data.
+fn write_minimum_security_version(efivars: &Path, minimum: u64) -> Result<Vec<u8>> {
+    let mut variable = vec![0x27, 0x00, 0x00, 0x00];
+    variable.extend(minimum.to_le_bytes());
+    fs::write(minimum_security_version_path(efivars), &variable)?;
+    Ok(variable)
+}
+
+#[test]
+fn raise_minimum_security_version() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let efivars = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2]
+        .into_iter()
+        .map(|v| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), v)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+    set_security_version(&generation_links[1], 2)?;
+    // lzbt setup --enroll created the variable.
+    write_minimum_security_version(efivars.path(), 0)?;
+
+    let efivars_args = [
+        String::from("--anti-rollback"),
+        String::from("--efivars"),
+        efivars.path().display().to_string(),
+    ];
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        generation_links,
+        &efivars_args,
+    )?;
+    assert!(output0.status.success());
+    let stdout = String::from_utf8(output0.stdout)?;
+    assert!(stdout.contains("Raised the minimum security version from 0 to 2"));
+    assert!(stdout.contains("a lower security version and refuse to boot now: 1"));
+
+    let linux = esp_mountpoint.path().join("EFI/Linux");
+    let stub_data = fs::read(linux.join("nixos-generation-2.efi"))?;
+    let embedded = common::pe_section(&stub_data, ".lzbsvn").expect("Missing .lzbsvn");
+    assert_eq!(embedded[..8], 2u64.to_le_bytes());
+    let stub_data = fs::read(linux.join("nixos-generation-1.efi"))?;
+    assert!(common::pe_section(&stub_data, ".lzbsvn").is_none());
+
+    // The authenticated update ends with the new contents of the variable.
+    let variable = fs::read(minimum_security_version_path(efivars.path()))?;
+    assert_eq!(variable[..4], [0x27, 0x00, 0x00, 0x00]);
+    assert!(variable.ends_with(&2u64.to_le_bytes()));
+
+    Ok(())
+}
+
+#[test]
+fn never_lower_minimum_security_version() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let efivars = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+    set_security_version(&generation_link, 1)?;
+
+    let variable = write_minimum_security_version(efivars.path(), 3)?;
+
+    let efivars_args = [
+        String::from("--anti-rollback"),
+        String::from("--efivars"),
+        efivars.path().display().to_string(),
+    ];
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        &efivars_args,
+    )?;
+    assert!(output0.status.success());
+    assert!(!String::from_utf8(output0.stdout)?.contains("Raised the minimum security version"));
+    assert_eq!(
+        fs::read(minimum_security_version_path(efivars.path()))?,
+        variable
+    );
+
+    Ok(())
+}
+
+/// Anybody could create the variable with their own key, so only setup creates it.
+#[test]
+fn refuse_to_create_minimum_security_version() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let efivars = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+    set_security_version(&generation_link, 1)?;
+
+    let efivars_args = [
+        String::from("--anti-rollback"),
+        String::from("--efivars"),
+        efivars.path().display().to_string(),
+    ];
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        &efivars_args,
+    )?;
+    assert!(!output0.status.success());
+    assert!(String::from_utf8(output0.stderr)?.contains("lzbt setup --enroll"));
+    assert!(!minimum_security_version_path(efivars.path()).exists());
+
+    Ok(())
+}
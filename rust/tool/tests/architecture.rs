@@ -0,0 +1,41 @@
+use std::ffi::OsStr;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn install_boot_loaders_for_other_architecture() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    // The test systemd only has an x64 build, which stands in for the aa64 one.
+    let systemd_boot = format!(
+        "{}/lib/systemd/boot/efi/systemd-bootx64.efi",
+        common::systemd_location_from_env()?
+    );
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        [
+            OsStr::new("--architecture"),
+            OsStr::new("aa64"),
+            OsStr::new("--systemd-boot"),
+            OsStr::new(&systemd_boot),
+        ],
+    )?;
+    assert!(output0.status.success());
+
+    let esp = esp_mountpoint.path();
+    assert!(esp.join("EFI/systemd/systemd-bootaa64.efi").exists());
+    assert!(esp.join("EFI/BOOT/BOOTAA64.EFI").exists());
+    assert!(!esp.join("EFI/systemd/systemd-bootx64.efi").exists());
+    assert!(!esp.join("EFI/BOOT/BOOTX64.EFI").exists());
+
+    Ok(())
+}
@@ -0,0 +1,39 @@
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn remove_leftovers_of_interrupted_installation() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 =
+        common::lanzaboote_install(0, esp_mountpoint.path(), vec![generation_link.clone()])?;
+    assert!(output0.status.success());
+
+    // An installation that was interrupted before it renamed its staged files into place.
+    let staging = esp_mountpoint.path().join("EFI/Linux/.lzbt-staging-a1b2c3");
+    fs::write(&staging, "partial stub")?;
+    let backup = esp_mountpoint
+        .path()
+        .join("EFI/BOOT/BOOTX64.EFI.lzbt-backup");
+    fs::write(&backup, "previous boot loader")?;
+
+    let output1 = common::lanzaboote_install(0, esp_mountpoint.path(), vec![generation_link])?;
+    assert!(output1.status.success());
+    assert!(!staging.exists());
+    assert!(!backup.exists());
+    assert!(esp_mountpoint
+        .path()
+        .join("EFI/Linux/nixos-generation-1.efi")
+        .exists());
+    assert!(esp_mountpoint.path().join("EFI/BOOT/BOOTX64.EFI").exists());
+
+    Ok(())
+}
@@ -0,0 +1,144 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn install_stubs_with_boot_counter() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        ["--boot-counting", "--boot-counting-tries", "5"],
+    )?;
+    assert!(output0.status.success());
+
+    assert_eq!(
+        stubs(&esp_mountpoint.path().join("EFI/Linux"))?,
+        ["nixos-generation-1+5.efi"]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn keep_stubs_renamed_by_boot_counting() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2]
+        .into_iter()
+        .map(|v| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), v)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+    let linux = esp_mountpoint.path().join("EFI/Linux");
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        generation_links.clone(),
+        ["--boot-counting"],
+    )?;
+    assert!(output0.status.success());
+    assert_eq!(
+        stubs(&linux)?,
+        ["nixos-generation-1+3.efi", "nixos-generation-2+3.efi"]
+    );
+
+    // Generation 2 is being booted for the first time, generation 1 was assessed as good.
+    fs::rename(
+        linux.join("nixos-generation-2+3.efi"),
+        linux.join("nixos-generation-2+2-1.efi"),
+    )?;
+    fs::rename(
+        linux.join("nixos-generation-1+3.efi"),
+        linux.join("nixos-generation-1.efi"),
+    )?;
+
+    let output1 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        generation_links,
+        ["--boot-counting"],
+    )?;
+    assert!(output1.status.success());
+    assert_eq!(
+        stubs(&linux)?,
+        ["nixos-generation-1.efi", "nixos-generation-2+2-1.efi"]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn reset_exhausted_boot_counter_of_changed_generation() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2]
+        .into_iter()
+        .map(|v| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), v)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+    let linux = esp_mountpoint.path().join("EFI/Linux");
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        generation_links.clone(),
+        ["--boot-counting"],
+    )?;
+    assert!(output0.status.success());
+
+    // Both generations failed to boot three times.
+    for version in [1, 2] {
+        fs::rename(
+            linux.join(format!("nixos-generation-{version}+3.efi")),
+            linux.join(format!("nixos-generation-{version}+0-3.efi")),
+        )?;
+    }
+
+    // Generation 2 gets other kernel parameters, e.g. to fix what kept it from booting.
+    let bootspec_path = generation_links[1].join("boot.json");
+    let mut bootspec: serde_json::Value = serde_json::from_slice(&fs::read(&bootspec_path)?)?;
+    bootspec["v1"]["kernelParams"]
+        .as_array_mut()
+        .expect("kernelParams is an array")
+        .push(serde_json::json!("nomodeset"));
+    fs::write(&bootspec_path, serde_json::to_vec(&bootspec)?)?;
+
+    let output1 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        generation_links,
+        ["--boot-counting"],
+    )?;
+    assert!(output1.status.success());
+    assert_eq!(
+        stubs(&linux)?,
+        ["nixos-generation-1+0-3.efi", "nixos-generation-2+3.efi"]
+    );
+
+    Ok(())
+}
+
+fn stubs(linux: &Path) -> Result<Vec<String>> {
+    let mut stubs = fs::read_dir(linux)?
+        .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+        .collect::<Result<Vec<_>>>()?;
+    stubs.sort();
+    Ok(stubs)
+}
@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn write_boot_loader_entry_referencing_esp_files() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        ["--boot-loader-entries"],
+    )?;
+    assert!(output0.status.success());
+
+    let entry = fs::read_to_string(
+        esp_mountpoint
+            .path()
+            .join("loader/entries/nixos-bls-generation-1.conf"),
+    )?;
+    let fields: HashMap<&str, &str> = entry
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .collect();
+    assert_eq!(fields["title"], "LanzaOS");
+
+    // The entry references the same kernel and initrd as the stub, but with `/` as separator.
+    let stub = fs::read(
+        esp_mountpoint
+            .path()
+            .join("EFI/Linux/nixos-generation-1.efi"),
+    )?;
+    let stub_path = |name: &str| -> String {
+        String::from_utf8_lossy(common::pe_section(&stub, name).expect("Missing section"))
+            .replace('\\', "/")
+    };
+    for (key, section) in [("linux", ".kernelp"), ("initrd", ".initrdp")] {
+        let path = fields[key];
+        assert_eq!(path, stub_path(section));
+        assert!(path.starts_with("/EFI/nixos/"));
+        assert!(esp_mountpoint
+            .path()
+            .join(path.trim_start_matches('/'))
+            .exists());
+    }
+
+    let stub_cmdline =
+        String::from_utf8_lossy(common::pe_section(&stub, ".cmdline").expect("Missing section"))
+            .into_owned();
+    assert_eq!(fields["options"], stub_cmdline);
+
+    Ok(())
+}
+
+#[test]
+fn write_boot_loader_entries_instead_of_stubs() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        ["--boot-loader-entries=instead", "--make-default"],
+    )?;
+    assert!(output0.status.success());
+
+    let esp = esp_mountpoint.path();
+    assert!(!esp.join("EFI/Linux/nixos-generation-1.efi").exists());
+    let entry = fs::read_to_string(esp.join("loader/entries/nixos-bls-generation-1.conf"))?;
+    let referenced: Vec<&str> = entry
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .filter(|(key, _)| ["linux", "initrd"].contains(key))
+        .map(|(_, path)| path.trim_start_matches('/'))
+        .collect();
+    assert_eq!(referenced.len(), 2);
+    assert!(fs::read_to_string(esp.join("loader/loader.conf"))?
+        .contains("default nixos-bls-generation-1.conf"));
+
+    // The garbage collection keeps the kernel and initrd without a stub referencing them.
+    let output1 = assert_cmd::Command::cargo_bin("lzbt")?
+        .arg("gc")
+        .arg(esp)
+        .output()?;
+    assert!(output1.status.success());
+    for path in referenced {
+        assert!(esp.join(path).exists(), "{path} was removed");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,132 @@
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process;
+
+use anyhow::Result;
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+mod common;
+
+use common::{EFI_GLOBAL_VARIABLE, LOADER_VARIABLE};
+
+#[test]
+fn bundle_esp_without_secrets() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let efivars = tempdir()?;
+    let out = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), vec![generation_link])?;
+    assert!(output0.status.success());
+    assert!(esp_mountpoint.path().join("loader/random-seed").exists());
+
+    common::write_efi_variable(efivars.path(), "SecureBoot", EFI_GLOBAL_VARIABLE, &[1])?;
+    common::write_efi_variable(
+        efivars.path(),
+        "LoaderSystemToken",
+        LOADER_VARIABLE,
+        &[0x42; 32],
+    )?;
+
+    let bundle = out.path().join("bundle.tar");
+    let output1 = Command::cargo_bin("lzbt")?
+        .arg("bundle")
+        .arg("--efivars")
+        .arg(efivars.path())
+        .arg(esp_mountpoint.path())
+        .arg(&bundle)
+        .output()?;
+    assert!(output1.status.success());
+
+    let listing = process::Command::new("tar")
+        .arg("--list")
+        .arg("--file")
+        .arg(&bundle)
+        .output()?;
+    assert!(listing.status.success());
+    let entries = String::from_utf8(listing.stdout)?;
+    print!("{entries}");
+
+    for expected in [
+        "./esp/EFI/Linux/nixos-generation-1.efi",
+        "./esp/EFI/nixos/manifest.json",
+        "./esp/EFI/systemd/systemd-bootx64.efi",
+        &format!("./efivars/SecureBoot-{EFI_GLOBAL_VARIABLE}"),
+    ] {
+        assert!(
+            entries.lines().any(|entry| entry == expected),
+            "{expected} is missing from the bundle"
+        );
+    }
+    assert!(!entries.contains("random-seed"));
+    assert!(!entries.contains("LoaderSystemToken"));
+
+    // Neither the signing key nor anything else with key material ends up in the bundle.
+    let contents = fs::read(&bundle)?;
+    assert!(!contents
+        .windows(b"PRIVATE KEY".len())
+        .any(|window| window == b"PRIVATE KEY"));
+
+    Ok(())
+}
+
+/// List the entries of the bundle of an ESP.
+fn bundle_entries(esp: &Path, out: &Path, args: &[&str]) -> Result<Vec<String>> {
+    let bundle = out.join("bundle.tar");
+    let output = Command::cargo_bin("lzbt")?
+        .arg("bundle")
+        .args(args)
+        .arg("--efivars")
+        .arg(out.join("efivars"))
+        .arg(esp)
+        .arg(&bundle)
+        .output()?;
+    assert!(output.status.success());
+
+    let listing = process::Command::new("tar")
+        .arg("--list")
+        .arg("--file")
+        .arg(&bundle)
+        .output()?;
+    assert!(listing.status.success());
+    Ok(String::from_utf8(listing.stdout)?
+        .lines()
+        .map(String::from)
+        .collect())
+}
+
+#[test]
+fn leave_out_initrd_secrets() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let out = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let append_secrets = tmpdir.path().join("append-initrd-secrets");
+    fs::write(&append_secrets, "#!/bin/sh\nprintf secret >> \"$1\"\n")?;
+    fs::set_permissions(&append_secrets, fs::Permissions::from_mode(0o755))?;
+    let bootspec_path = generation_link.join("boot.json");
+    let mut bootspec: serde_json::Value = serde_json::from_slice(&fs::read(&bootspec_path)?)?;
+    bootspec["v1"]["initrdSecrets"] = serde_json::json!(append_secrets);
+    fs::write(bootspec_path, serde_json::to_vec(&bootspec)?)?;
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), vec![generation_link])?;
+    assert!(output0.status.success());
+
+    let is_initrd = |entry: &String| entry.ends_with("-initrd.efi");
+    let entries = bundle_entries(esp_mountpoint.path(), out.path(), &[])?;
+    assert!(entries.iter().any(|entry| entry.ends_with("-bzImage.efi")));
+    assert!(!entries.iter().any(is_initrd));
+
+    let entries = bundle_entries(esp_mountpoint.path(), out.path(), &["--include-secrets"])?;
+    assert!(entries.iter().any(is_initrd));
+
+    Ok(())
+}
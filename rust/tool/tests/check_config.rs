@@ -0,0 +1,85 @@
+use std::fs;
+
+use anyhow::Result;
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+#[test]
+fn report_all_problems_of_config_file() -> Result<()> {
+    let tmpdir = tempdir()?;
+    let esp = tmpdir.path().join("esp");
+    let config = tmpdir.path().join("lanzaboote.toml");
+    // The public key is found next to the configuration file, not in the working directory.
+    fs::copy(
+        "tests/fixtures/uefi-keys/db.pem",
+        tmpdir.path().join("db.pem"),
+    )?;
+    fs::write(
+        &config,
+        format!(
+            r#"esp = "{}"
+public-key = "db.pem"
+private-key = "{}"
+pinned-recovery = 2
+default-generation = 0
+excluded-generations = [2]
+
+[extra-sections]
+".cmdline" = "db.pem"
+"#,
+            esp.display(),
+            tmpdir.path().join("db.key").display()
+        ),
+    )?;
+
+    let output = Command::cargo_bin("lzbt")?
+        .arg("check-config")
+        .arg(&config)
+        .output()?;
+    let stdout = String::from_utf8(output.stdout)?;
+    print!("{stdout}");
+    print!("{}", String::from_utf8_lossy(&output.stderr));
+
+    assert!(!output.status.success());
+    assert!(stdout.contains("esp: Failed to open ESP"));
+    assert!(stdout.contains("private-key: Failed to read"));
+    assert!(!stdout.contains("public-key:"));
+    assert!(stdout.contains("pinned-recovery: The pinned recovery generation 2 is excluded"));
+    assert!(stdout.contains("default-generation: There is no generation 0"));
+    assert!(stdout.contains("extra-sections: The section .cmdline is embedded by lzbt"));
+    assert!(String::from_utf8(output.stderr)?.contains("5 problems found"));
+
+    // The ESP is not created.
+    assert!(!esp.exists());
+
+    Ok(())
+}
+
+#[test]
+fn accept_valid_config_file() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let config = tmpdir.path().join("lanzaboote.toml");
+    fs::write(
+        &config,
+        format!(
+            r#"esp = "{}"
+public-key = "{}"
+private-key = "{}"
+configuration-limit = 3
+"#,
+            esp.path().display(),
+            fs::canonicalize("tests/fixtures/uefi-keys/db.pem")?.display(),
+            fs::canonicalize("tests/fixtures/uefi-keys/db.key")?.display()
+        ),
+    )?;
+
+    let output = Command::cargo_bin("lzbt")?
+        .arg("check-config")
+        .arg(&config)
+        .output()?;
+    assert!(output.status.success());
+    assert!(fs::read_dir(esp.path())?.next().is_none());
+
+    Ok(())
+}
@@ -0,0 +1,141 @@
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn install_cmdline_profiles_sharing_kernel_and_initrd() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        [
+            "--cmdline-profile",
+            "debug=debug loglevel=7",
+            "--cmdline-profile",
+            "recovery=systemd.unit=rescue.target",
+        ],
+    )?;
+    assert!(output0.status.success());
+
+    let linux = esp_mountpoint.path().join("EFI/Linux");
+    let image = |name: &str| fs::read(linux.join(name));
+    let section = |image_data: &[u8], name: &str| -> String {
+        String::from_utf8_lossy(common::pe_section(image_data, name).expect("Missing section"))
+            .into_owned()
+    };
+
+    let default = image("nixos-generation-1.efi")?;
+    let debug = image("nixos-generation-1-profile-debug.efi")?;
+    let recovery = image("nixos-generation-1-profile-recovery.efi")?;
+
+    // All profiles reference the same kernel and initrd.
+    for profile in [&debug, &recovery] {
+        for name in [".kernelp", ".kernelh", ".initrdp", ".initrdh"] {
+            assert_eq!(section(profile, name), section(&default, name));
+        }
+    }
+    assert_eq!(
+        fs::read_dir(esp_mountpoint.path().join("EFI/nixos"))?.count(),
+        3
+    );
+
+    // Only the cmdline and os-release differ.
+    assert!(!section(&default, ".cmdline").contains("loglevel=7"));
+    assert!(section(&debug, ".cmdline").ends_with("debug loglevel=7"));
+    assert!(section(&recovery, ".cmdline").ends_with("systemd.unit=rescue.target"));
+    assert!(section(&debug, ".osrel").contains("Profile debug"));
+
+    Ok(())
+}
+
+#[test]
+fn reject_colliding_stub_paths() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    // The cmdline profile `b` of the specialisation `a` is named like the specialisation
+    // `a-profile-b`.
+    let generation_link = common::setup_generation_link_with_specialisations(
+        tmpdir.path(),
+        profiles.path(),
+        1,
+        &["a", "a-profile-b"],
+    )
+    .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        ["--cmdline-profile", "b=quiet"],
+    )?;
+    assert!(!output0.status.success());
+
+    let stderr = String::from_utf8(output0.stderr)?;
+    assert!(stderr.contains("nixos-generation-1-specialisation-a-profile-b.efi"));
+    assert!(stderr.contains("cmdline profile b of specialisation a of generation 1"));
+    assert!(stderr.contains("specialisation a-profile-b of generation 1"));
+
+    // Nothing was written before the collision was detected.
+    assert!(!esp_mountpoint.path().join("EFI/Linux").exists());
+
+    Ok(())
+}
+
+#[test]
+fn install_safe_mode_entry_for_newest_generation() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links: Vec<_> = [1, 2]
+        .into_iter()
+        .map(|version| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), version)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        &generation_links,
+        ["--safe-mode"],
+    )?;
+    assert!(output0.status.success());
+
+    let linux = esp_mountpoint.path().join("EFI/Linux");
+    let section = |image_data: &[u8], name: &str| -> String {
+        String::from_utf8_lossy(common::pe_section(image_data, name).expect("Missing section"))
+            .into_owned()
+    };
+
+    // Only the newest generation gets a safe mode entry.
+    assert!(!linux.join("nixos-generation-1-safe-mode.efi").exists());
+    let default = fs::read(linux.join("nixos-generation-2.efi"))?;
+    let safe_mode = fs::read(linux.join("nixos-generation-2-safe-mode.efi"))?;
+
+    for name in [".kernelp", ".kernelh", ".initrdp", ".initrdh"] {
+        assert_eq!(section(&safe_mode, name), section(&default, name));
+    }
+
+    // The kernel parameters of the generation are replaced.
+    let cmdline = section(&safe_mode, ".cmdline");
+    assert!(cmdline.starts_with("init="));
+    assert!(cmdline.ends_with(" single nomodeset"));
+    assert_ne!(section(&default, ".cmdline"), cmdline);
+
+    let os_release = section(&safe_mode, ".osrel");
+    assert!(os_release.contains(" (Safe Mode)\n"));
+    assert!(os_release.contains("IMAGE_ID=lanza-safe-mode\n"));
+
+    Ok(())
+}
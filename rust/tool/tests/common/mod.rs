@@ -1,4 +1,4 @@
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
 use std::fs;
 use std::io::Write;
 use std::os::unix::fs::symlink;
@@ -15,39 +15,45 @@ use serde_json::json;
 ///
 /// Creates the generation link using the specified version inside a mock profiles directory
 /// (mimicking /nix/var/nix/profiles). Returns the path to the generation link.
+#[allow(dead_code)]
 pub fn setup_generation_link(
     tmpdir: &Path,
     profiles_directory: &Path,
     version: u64,
+) -> Result<PathBuf> {
+    setup_generation_link_with_specialisations(tmpdir, profiles_directory, version, &[])
+}
+
+/// Create a mock generation link with specialisations.
+///
+/// Every specialisation shares the toplevel of the generation but appends
+/// `specialisation={name}` to the kernel parameters so that the boot entries are distinguishable.
+#[allow(dead_code)]
+pub fn setup_generation_link_with_specialisations(
+    tmpdir: &Path,
+    profiles_directory: &Path,
+    version: u64,
+    specialisations: &[&str],
 ) -> Result<PathBuf> {
     let toplevel = setup_toplevel(tmpdir).context("Failed to setup toplevel")?;
     // Explicitly set modification time so that snapshot test of os-release reliably works.
     filetime::set_file_mtime(&toplevel, filetime::FileTime::zero())?;
 
-    let bootspec = json!({
-        "v1": {
-          "init": format!("init-v{}", version),
-          "initrd": toplevel.join("initrd"),
-          "kernel": toplevel.join("kernel"),
-          "kernelParams": [
-            "amd_iommu=on",
-            "amd_iommu=pt",
-            "iommu=pt",
-            "kvm.ignore_msrs=1",
-            "kvm.report_ignored_msrs=0",
-            "udev.log_priority=3",
-            "systemd.unified_cgroup_hierarchy=1",
-            "loglevel=4"
-          ],
-          "label": "LanzaOS",
-          "toplevel": toplevel,
-          "system": "x86_64-linux",
-          "specialisation": {},
-          "extensions": {
-            "lanzaboote": { "osRelease": toplevel.join("os-release") }
-          }
-        }
-    });
+    let specialisation_bootspecs: serde_json::Map<String, serde_json::Value> = specialisations
+        .iter()
+        .map(|name| {
+            let mut bootspec = bootspec_v1(&toplevel, version);
+            bootspec["kernelParams"]
+                .as_array_mut()
+                .expect("kernelParams is an array")
+                .push(json!(format!("specialisation={name}")));
+            (name.to_string(), bootspec)
+        })
+        .collect();
+
+    let mut generation_bootspec = bootspec_v1(&toplevel, version);
+    generation_bootspec["specialisation"] = specialisation_bootspecs.into();
+    let bootspec = json!({ "v1": generation_bootspec });
 
     let generation_link_path = profiles_directory.join(format!("system-{}-link", version));
     fs::create_dir(&generation_link_path)?;
@@ -59,6 +65,32 @@ pub fn setup_generation_link(
     Ok(generation_link_path)
 }
 
+/// Build the (version 1) bootspec of a mock generation without specialisations.
+fn bootspec_v1(toplevel: &Path, version: u64) -> serde_json::Value {
+    json!({
+        "init": format!("init-v{}", version),
+        "initrd": toplevel.join("initrd"),
+        "kernel": toplevel.join("kernel"),
+        "kernelParams": [
+          "amd_iommu=on",
+          "amd_iommu=pt",
+          "iommu=pt",
+          "kvm.ignore_msrs=1",
+          "kvm.report_ignored_msrs=0",
+          "udev.log_priority=3",
+          "systemd.unified_cgroup_hierarchy=1",
+          "loglevel=4"
+        ],
+        "label": "LanzaOS",
+        "toplevel": toplevel,
+        "system": "x86_64-linux",
+        "specialisation": {},
+        "extensions": {
+          "lanzaboote": { "osRelease": toplevel.join("os-release") }
+        }
+    })
+}
+
 /// Setup a mock toplevel inside a temporary directory.
 ///
 /// Accepts the temporary directory as a parameter so that the invoking function retains control of
@@ -100,19 +132,70 @@ fn random_string(length: usize) -> String {
 }
 
 /// Call the `lanzaboote install` command.
+#[allow(dead_code)]
 pub fn lanzaboote_install(
     config_limit: u64,
     esp_mountpoint: &Path,
     generation_links: impl IntoIterator<Item = impl AsRef<OsStr>>,
+) -> Result<Output> {
+    lanzaboote_install_with_args(
+        config_limit,
+        esp_mountpoint,
+        generation_links,
+        Vec::<&str>::new(),
+    )
+}
+
+/// Call the `lanzaboote install` command with additional arguments.
+#[allow(dead_code)]
+pub fn lanzaboote_install_with_args(
+    config_limit: u64,
+    esp_mountpoint: &Path,
+    generation_links: impl IntoIterator<Item = impl AsRef<OsStr>>,
+    extra_args: impl IntoIterator<Item = impl AsRef<OsStr>>,
+) -> Result<Output> {
+    lanzaboote_install_with_env(
+        config_limit,
+        esp_mountpoint,
+        generation_links,
+        extra_args,
+        Vec::<(&str, &str)>::new(),
+    )
+}
+
+/// Call the `lanzaboote install` command with additional arguments and environment variables.
+///
+/// The variables are only set for lzbt, so that tests running in parallel do not observe them.
+#[allow(dead_code)]
+pub fn lanzaboote_install_with_env(
+    config_limit: u64,
+    esp_mountpoint: &Path,
+    generation_links: impl IntoIterator<Item = impl AsRef<OsStr>>,
+    extra_args: impl IntoIterator<Item = impl AsRef<OsStr>>,
+    envs: impl IntoIterator<Item = (impl AsRef<OsStr>, impl AsRef<OsStr>)>,
 ) -> Result<Output> {
     // To simplify the test setup, we use the systemd stub here instead of the lanzaboote stub. See
     // the comment in setup_toplevel for details.
     let test_systemd = systemd_location_from_env()?;
     let test_systemd_stub = format!("{test_systemd}/lib/systemd/boot/efi/linuxx64.efi.stub");
 
+    // Unless a test provides its own UEFI variables, install reads them from an empty directory,
+    // so that e.g. the Secure Boot interlock does not depend on the machine the tests run on.
+    let extra_args: Vec<OsString> = extra_args
+        .into_iter()
+        .map(|arg| arg.as_ref().to_owned())
+        .collect();
+    let efivars = tempfile::tempdir()?;
+    let efivars_args = if extra_args.iter().any(|arg| arg == "--efivars") {
+        Vec::new()
+    } else {
+        vec![OsString::from("--efivars"), efivars.path().into()]
+    };
+
     let mut cmd = Command::cargo_bin("lzbt")?;
     let output = cmd
         .env("LANZABOOTE_STUB", test_systemd_stub)
+        .envs(envs)
         .arg("install")
         .arg("--public-key")
         .arg("tests/fixtures/uefi-keys/db.pem")
@@ -120,6 +203,8 @@ pub fn lanzaboote_install(
         .arg("tests/fixtures/uefi-keys/db.key")
         .arg("--configuration-limit")
         .arg(config_limit.to_string())
+        .args(efivars_args)
+        .args(extra_args)
         .arg(esp_mountpoint)
         .args(generation_links)
         .output()?;
@@ -138,8 +223,157 @@ pub fn lanzaboote_install(
     Ok(output)
 }
 
+/// Extracts the data of a section of a PE file.
+#[allow(dead_code)]
+pub fn pe_section<'a>(file_data: &'a [u8], section_name: &str) -> Option<&'a [u8]> {
+    let pe_binary = goblin::pe::PE::parse(file_data).ok()?;
+
+    pe_binary
+        .sections
+        .iter()
+        .find(|s| s.name().unwrap() == section_name)
+        .and_then(|s| {
+            let section_start: usize = s.pointer_to_raw_data.try_into().ok()?;
+            assert!(s.virtual_size <= s.size_of_raw_data);
+            let section_end: usize = section_start + usize::try_from(s.virtual_size).ok()?;
+            Some(&file_data[section_start..section_end])
+        })
+}
+
+/// Read the code of a PE binary, which signing leaves untouched.
+#[allow(dead_code)]
+pub fn text_section(path: &Path) -> Result<Vec<u8>> {
+    let data = fs::read(path)?;
+    pe_section(&data, ".text")
+        .map(|section| section.to_owned())
+        .with_context(|| format!("Failed to read .text section of {path:?}"))
+}
+
+/// Read the toplevel that a generation link points to from its bootspec.
+#[allow(dead_code)]
+pub fn toplevel(generation_link: &Path) -> Result<PathBuf> {
+    let bootspec: serde_json::Value =
+        serde_json::from_slice(&fs::read(generation_link.join("boot.json"))?)?;
+    bootspec["v1"]["toplevel"]
+        .as_str()
+        .map(PathBuf::from)
+        .context("Bootspec has no toplevel")
+}
+
+/// The file name of systemd-boot for the architecture of a generation, e.g.
+/// `systemd-bootx64.efi` for an `x86_64-linux` generation.
+#[allow(dead_code)]
+pub fn systemd_boot_file_name(generation_link: &Path) -> Result<String> {
+    let bootspec: serde_json::Value =
+        serde_json::from_slice(&fs::read(generation_link.join("boot.json"))?)?;
+    let system = bootspec["v1"]["system"]
+        .as_str()
+        .context("Bootspec has no system")?;
+    let architecture = match system.split('-').next() {
+        Some("x86_64") => "x64",
+        Some("i686") => "ia32",
+        Some("aarch64") => "aa64",
+        Some("armv7l") => "arm",
+        Some("riscv64") => "riscv64",
+        Some("loongarch64") => "loongarch64",
+        _ => anyhow::bail!("Unsupported system {system}"),
+    };
+    Ok(format!("systemd-boot{architecture}.efi"))
+}
+
+/// Record the paths, contents and modification times of all files below a directory.
+#[allow(dead_code)]
+pub fn snapshot(directory: &Path) -> Result<Vec<(PathBuf, Vec<u8>, std::time::SystemTime)>> {
+    let mut files = Vec::new();
+    for entry in walkdir::WalkDir::new(directory).sort_by_file_name() {
+        let entry = entry?;
+        if entry.file_type().is_file() {
+            files.push((
+                entry.path().to_path_buf(),
+                fs::read(entry.path())?,
+                entry.metadata()?.modified()?,
+            ));
+        }
+    }
+    Ok(files)
+}
+
+/// Write a UEFI variable like efivarfs presents it, i.e. prefixed by its attributes.
+#[allow(dead_code)]
+pub fn write_efi_variable(efivars: &Path, name: &str, vendor: &str, data: &[u8]) -> Result<()> {
+    let mut contents = vec![0x06, 0x00, 0x00, 0x00];
+    contents.extend_from_slice(data);
+    fs::write(efivars.join(format!("{name}-{vendor}")), contents)?;
+    Ok(())
+}
+
+/// Vendor GUID of the global UEFI variables, e.g. `SecureBoot`.
+#[allow(dead_code)]
+pub const EFI_GLOBAL_VARIABLE: &str = "8be4df61-93ca-11d2-aa0d-00e098032b8c";
+/// Vendor GUID of the signature databases `db` and `dbx`.
+#[allow(dead_code)]
+pub const IMAGE_SECURITY_DATABASE: &str = "d719b2cb-3d3a-4596-a3bc-dad00e67656f";
+/// Vendor GUID of the variables of systemd-boot, e.g. `LoaderEntries`.
+#[allow(dead_code)]
+pub const LOADER_VARIABLE: &str = "4a67b082-0a4c-41cf-b6c7-440b29bb8c4f";
+/// Vendor GUID of the variables of lanzaboote, e.g. `LanzabooteMinimumSecurityVersion`.
+#[allow(dead_code)]
+pub const LANZABOOTE_VENDOR: &str = "bf6e8bf2-0104-4750-bafa-ea73c3a3e37e";
+/// Signature type of X.509 certificates in an `EFI_SIGNATURE_LIST`.
+#[allow(dead_code)]
+pub const EFI_CERT_X509_GUID: [u8; 16] = [
+    0xa1, 0x59, 0xc0, 0xa5, 0xe4, 0x94, 0xa7, 0x4a, 0x87, 0xb5, 0xab, 0x15, 0x5c, 0x2b, 0xf0, 0x72,
+];
+
+/// Build an `EFI_SIGNATURE_LIST` with a single signature.
+#[allow(dead_code)]
+pub fn signature_list(signature_type: [u8; 16], data: &[u8]) -> Vec<u8> {
+    let signature_size = 16 + data.len() as u32;
+    let mut list = signature_type.to_vec();
+    list.extend_from_slice(&(28 + signature_size).to_le_bytes());
+    list.extend_from_slice(&0u32.to_le_bytes());
+    list.extend_from_slice(&signature_size.to_le_bytes());
+    list.extend_from_slice(&[0; 16]);
+    list.extend_from_slice(data);
+    list
+}
+
+/// Read the DER encoding of a PEM certificate.
+#[allow(dead_code)]
+pub fn certificate_der(pem: &Path) -> Result<Vec<u8>> {
+    let output = std::process::Command::new("openssl")
+        .args(["x509", "-outform", "DER", "-in"])
+        .arg(pem)
+        .output()?;
+    assert!(output.status.success());
+    Ok(output.stdout)
+}
+
+/// Write a signature database `db` that contains the certificates (in DER encoding).
+#[allow(dead_code)]
+pub fn write_db(efivars: &Path, certificates: &[&[u8]]) -> Result<()> {
+    let db: Vec<u8> = certificates
+        .iter()
+        .flat_map(|certificate| signature_list(EFI_CERT_X509_GUID, certificate))
+        .collect();
+    write_efi_variable(efivars, "db", IMAGE_SECURITY_DATABASE, &db)
+}
+
+/// Run `lzbt verify` on an ESP and print its output for debugging.
+#[allow(dead_code)]
+pub fn lanzaboote_verify(esp_mountpoint: &Path) -> Result<Output> {
+    let output = Command::cargo_bin("lzbt")?
+        .arg("verify")
+        .arg(esp_mountpoint)
+        .output()?;
+    print!("{}", String::from_utf8_lossy(&output.stdout));
+    print!("{}", String::from_utf8_lossy(&output.stderr));
+    Ok(output)
+}
+
 /// Read location of systemd installation from an environment variable.
-fn systemd_location_from_env() -> Result<String> {
+#[allow(dead_code)]
+pub fn systemd_location_from_env() -> Result<String> {
     let error_msg = "TEST_SYSTEMD environment variable is not set. TEST_SYSTEMD has to point to a systemd installation.
 On a system with Nix installed, you can set it with: export TEST_SYSTEMD=$(nix-build '<nixpkgs>' -A systemd)";
     std::env::var("TEST_SYSTEMD").context(error_msg)
@@ -0,0 +1,51 @@
+use std::ffi::OsStr;
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn compare_produced_stub_against_reference() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 =
+        common::lanzaboote_install(0, esp_mountpoint.path(), vec![generation_link.clone()])?;
+    assert!(output0.status.success());
+
+    let reference = tmpdir.path().join("reference.efi");
+    fs::copy(
+        esp_mountpoint
+            .path()
+            .join("EFI/Linux/nixos-generation-1.efi"),
+        &reference,
+    )?;
+
+    let compare = |reference: &OsStr| {
+        common::lanzaboote_install_with_args(
+            0,
+            esp_mountpoint.path(),
+            vec![generation_link.clone()],
+            [OsStr::new("--compare-stub"), reference],
+        )
+    };
+
+    let output1 = compare(reference.as_os_str())?;
+    assert!(output1.status.success());
+
+    let mut tampered = fs::read(&reference)?;
+    tampered[0x100] ^= 0xff;
+    let tampered_reference = tmpdir.path().join("tampered.efi");
+    fs::write(&tampered_reference, tampered)?;
+
+    let output2 = compare(tampered_reference.as_os_str())?;
+    assert!(!output2.status.success());
+    assert!(String::from_utf8(output2.stderr)?.contains("at offset 0x100"));
+
+    Ok(())
+}
@@ -0,0 +1,101 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Output;
+
+use anyhow::Result;
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn read_install_options_from_config_file() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2]
+        .into_iter()
+        .map(|version| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), version)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+
+    let config = tmpdir.path().join("lanzaboote.toml");
+    fs::write(
+        &config,
+        format!(
+            r#"esp = "{}"
+public-key = "{}"
+private-key = "{}"
+configuration-limit = 1
+"#,
+            esp_mountpoint.path().display(),
+            fs::canonicalize("tests/fixtures/uefi-keys/db.pem")?.display(),
+            fs::canonicalize("tests/fixtures/uefi-keys/db.key")?.display()
+        ),
+    )?;
+
+    let stub = |version: u64| {
+        esp_mountpoint
+            .path()
+            .join(format!("EFI/Linux/nixos-generation-{version}.efi"))
+    };
+
+    // All positional arguments are generations because the config file sets the ESP.
+    let output0 = lanzaboote_install_with_config(&config, &generation_links, [])?;
+    assert!(output0.status.success());
+    assert!(!stub(1).exists());
+    assert!(stub(2).exists());
+
+    // The configuration limit of the command line takes precedence.
+    let output1 =
+        lanzaboote_install_with_config(&config, &generation_links, ["--configuration-limit", "2"])?;
+    assert!(output1.status.success());
+    assert!(stub(1).exists());
+    assert!(stub(2).exists());
+
+    Ok(())
+}
+
+#[test]
+fn reject_unknown_config_settings() -> Result<()> {
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let config = tmpdir.path().join("lanzaboote.toml");
+    fs::write(&config, "configuration-limt = 2\n")?;
+
+    let output0 = lanzaboote_install_with_config(&config, &[generation_link], [])?;
+    assert!(!output0.status.success());
+    let stderr = String::from_utf8(output0.stderr)?;
+    assert!(stderr.contains("Failed to parse configuration file"));
+
+    Ok(())
+}
+
+fn lanzaboote_install_with_config<'a>(
+    config: &Path,
+    generation_links: &[PathBuf],
+    extra_args: impl IntoIterator<Item = &'a str>,
+) -> Result<Output> {
+    let test_systemd = common::systemd_location_from_env()?;
+    let test_systemd_stub = format!("{test_systemd}/lib/systemd/boot/efi/linuxx64.efi.stub");
+
+    let efivars = tempdir()?;
+    let output = Command::cargo_bin("lzbt")?
+        .env("LANZABOOTE_STUB", test_systemd_stub)
+        .arg("install")
+        .arg("--efivars")
+        .arg(efivars.path())
+        .arg("--config")
+        .arg(config)
+        .args(extra_args)
+        .args(generation_links)
+        .output()?;
+    print!("{}", String::from_utf8_lossy(&output.stdout));
+    print!("{}", String::from_utf8_lossy(&output.stderr));
+    Ok(output)
+}
@@ -0,0 +1,122 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn share_identical_kernels_and_initrds_between_generations() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2]
+        .into_iter()
+        .map(|v| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), v)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        generation_links,
+        ["--content-addressed"],
+    )?;
+    assert!(output0.status.success());
+
+    // Both generations use the same kernel and initrd, so only one of each is stored.
+    let kernel_and_initrd_count = fs::read_dir(esp_mountpoint.path().join("EFI/nixos"))?
+        .filter(|entry| {
+            entry.as_ref().map_or(true, |entry| {
+                entry.path().extension() == Some(OsStr::new("efi"))
+            })
+        })
+        .count();
+    assert_eq!(
+        kernel_and_initrd_count, 2,
+        "Wrong number of kernels & initrds after installation"
+    );
+
+    let section = |version: u64, section_name: &str| -> Result<Vec<u8>> {
+        let stub_data = fs::read(
+            esp_mountpoint
+                .path()
+                .join(format!("EFI/Linux/nixos-generation-{version}.efi")),
+        )?;
+        common::pe_section(&stub_data, section_name)
+            .map(|data| data.to_owned())
+            .with_context(|| format!("Failed to read {section_name} PE section."))
+    };
+
+    assert_eq!(section(1, ".kernelp")?, section(2, ".kernelp")?);
+    assert_eq!(section(1, ".initrdp")?, section(2, ".initrdp")?);
+
+    Ok(())
+}
+
+#[test]
+fn report_all_generations_that_reference_a_shared_initrd() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2]
+        .into_iter()
+        .map(|v| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), v)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        generation_links,
+        ["--content-addressed"],
+    )?;
+    assert!(output0.status.success());
+
+    let stub_data = fs::read(
+        esp_mountpoint
+            .path()
+            .join("EFI/Linux/nixos-generation-1.efi"),
+    )?;
+    let initrd_path = std::str::from_utf8(
+        common::pe_section(&stub_data, ".initrdp")
+            .context("Failed to read .initrdp PE section.")?,
+    )?
+    .trim_start_matches('\\')
+    .replace('\\', "/");
+
+    let output1 = Command::cargo_bin("lzbt")?
+        .arg("references")
+        .arg(esp_mountpoint.path())
+        .arg(esp_mountpoint.path().join(&initrd_path))
+        .output()?;
+    print!("{}", String::from_utf8_lossy(&output1.stderr));
+    assert!(output1.status.success());
+    assert_eq!(String::from_utf8(output1.stdout)?, "1\n2\n");
+
+    // A broken stub does not hide the references of the others, but the result is incomplete.
+    fs::write(
+        esp_mountpoint
+            .path()
+            .join("EFI/Linux/nixos-generation-3.efi"),
+        b"not a PE binary",
+    )?;
+    let output2 = Command::cargo_bin("lzbt")?
+        .arg("references")
+        .arg(esp_mountpoint.path())
+        .arg(esp_mountpoint.path().join(&initrd_path))
+        .output()?;
+    assert!(!output2.status.success());
+    let stdout = String::from_utf8(output2.stdout)?;
+    assert!(stdout.starts_with("1\n2\n"));
+    assert!(stdout.contains("Warning: failed to read the references of"));
+
+    Ok(())
+}
@@ -0,0 +1,62 @@
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn copy_files_concurrently() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let test_systemd = common::systemd_location_from_env()?;
+    let systemd_boot_aa64 = format!("{test_systemd}/lib/systemd/boot/efi/linuxx64.efi.stub");
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        [
+            String::from("--copy-concurrency"),
+            String::from("4"),
+            String::from("--fallback-loader"),
+            String::from("x64"),
+            String::from("--fallback-loader"),
+            format!("aa64={systemd_boot_aa64}"),
+        ],
+    )?;
+    assert!(output0.status.success());
+
+    let manifest: serde_json::Value = serde_json::from_slice(&fs::read(
+        esp_mountpoint.path().join("EFI/nixos/manifest.json"),
+    )?)?;
+    let files = manifest["files"]
+        .as_object()
+        .expect("Manifest has no files");
+    for path in [
+        "EFI/BOOT/BOOTX64.EFI",
+        "EFI/BOOT/BOOTAA64.EFI",
+        "EFI/systemd/systemd-bootx64.efi",
+        "EFI/Linux/nixos-generation-1.efi",
+    ] {
+        assert!(
+            esp_mountpoint.path().join(path).exists(),
+            "{path} is missing"
+        );
+        assert!(files.contains_key(path), "{path} is not recorded");
+    }
+    assert_eq!(
+        files
+            .keys()
+            .filter(|path| path.starts_with("EFI/nixos/"))
+            .count(),
+        2,
+        "Kernel and initrd are not recorded"
+    );
+
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+/// Name the devicetree blob in the lanzaboote extension of the bootspec of a generation.
+fn set_devicetree(generation_link: &Path, devicetree: &Path) -> Result<()> {
+    let bootspec_path = generation_link.join("boot.json");
+    let mut bootspec: serde_json::Value = serde_json::from_slice(&fs::read(&bootspec_path)?)?;
+    bootspec["v1"]["extensions"]["lanzaboote"]["devicetree"] = serde_json::json!(devicetree);
+    fs::write(bootspec_path, serde_json::to_vec(&bootspec)?)?;
+    Ok(())
+}
+
+#[test]
+fn embed_devicetree_of_generation() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let devicetree = tmpdir.path().join("rk3399-rockpro64.dtb");
+    let mut devicetree_data = vec![0xd0, 0x0d, 0xfe, 0xed];
+    devicetree_data.extend_from_slice(b"devicetree");
+    fs::write(&devicetree, &devicetree_data)?;
+    set_devicetree(&generation_link, &devicetree)?;
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), vec![generation_link])?;
+    assert!(output0.status.success());
+
+    let stub_data = fs::read(
+        esp_mountpoint
+            .path()
+            .join("EFI/Linux/nixos-generation-1.efi"),
+    )?;
+    let embedded = common::pe_section(&stub_data, ".dtb").expect("Missing .dtb");
+    assert_eq!(&embedded[..devicetree_data.len()], &devicetree_data[..]);
+
+    Ok(())
+}
+
+#[test]
+fn reject_devicetree_source() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let devicetree = tmpdir.path().join("rk3399-rockpro64.dts");
+    fs::write(&devicetree, "/dts-v1/;\n/ { };\n")?;
+    set_devicetree(&generation_link, &devicetree)?;
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), vec![generation_link])?;
+    assert!(!output0.status.success());
+    assert!(String::from_utf8(output0.stderr)?.contains("is not a flattened devicetree blob"));
+
+    Ok(())
+}
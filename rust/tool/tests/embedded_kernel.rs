@@ -0,0 +1,110 @@
+use std::ffi::OsStr;
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn embed_kernel_and_reference_initrd() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        ["--embed-kernel"],
+    )?;
+    assert!(output0.status.success());
+
+    let stub_data = fs::read(
+        esp_mountpoint
+            .path()
+            .join("EFI/Linux/nixos-generation-1.efi"),
+    )?;
+    assert!(common::pe_section(&stub_data, ".linux").is_some());
+    assert!(common::pe_section(&stub_data, ".kernelp").is_none());
+    assert!(common::pe_section(&stub_data, ".kernelh").is_none());
+    assert!(common::pe_section(&stub_data, ".initrdp").is_some());
+    assert!(common::pe_section(&stub_data, ".initrdh").is_some());
+
+    // Only the initrd is stored as a separate file.
+    let installed_files: Vec<_> = fs::read_dir(esp_mountpoint.path().join("EFI/nixos"))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<_, _>>()?;
+    let installed_files: Vec<_> = installed_files
+        .into_iter()
+        .filter(|path| path.extension() == Some(OsStr::new("efi")))
+        .collect();
+    assert_eq!(installed_files.len(), 1);
+    assert!(installed_files[0]
+        .to_string_lossy()
+        .ends_with("-initrd.efi"));
+
+    Ok(())
+}
+
+#[test]
+fn embed_kernel_and_initrd() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        ["--embed-kernel", "--embed-initrd"],
+    )?;
+    assert!(output0.status.success());
+
+    let stub_data = fs::read(
+        esp_mountpoint
+            .path()
+            .join("EFI/Linux/nixos-generation-1.efi"),
+    )?;
+    assert!(common::pe_section(&stub_data, ".linux").is_some());
+    assert!(common::pe_section(&stub_data, ".initrd").is_some());
+    for section in [".kernelp", ".kernelh", ".initrdp", ".initrdh", ".initrdl"] {
+        assert!(common::pe_section(&stub_data, section).is_none());
+    }
+
+    // The stub is the only file of the generation.
+    let nixos = esp_mountpoint.path().join("EFI/nixos");
+    if nixos.exists() {
+        let installed_files = fs::read_dir(nixos)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert!(!installed_files
+            .iter()
+            .any(|path| path.extension() == Some(OsStr::new("efi"))));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn embedding_initrd_requires_embedding_kernel() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        ["--embed-initrd"],
+    )?;
+    assert!(!output0.status.success());
+
+    Ok(())
+}
@@ -0,0 +1,171 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::Result;
+use lanzaboote_tool::api::{
+    self, EspFilesystem, FatImage, HashAlgorithm, InstallOptions, KeyPair, SigningKey, StubProfile,
+    StubSource,
+};
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn install_to_blank_fat_image() -> Result<()> {
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let images = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2]
+        .into_iter()
+        .map(|v| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), v)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+
+    let image = images.path().join("esp.img");
+    let status = Command::new("mkfs.fat")
+        .arg("-C")
+        .arg(&image)
+        .arg("65536")
+        .status()?;
+    assert!(status.success());
+
+    let output0 =
+        common::lanzaboote_install_with_args(0, &image, generation_links, ["--esp-image"])?;
+    assert!(output0.status.success());
+    assert!(image.is_file());
+
+    // Read the tree back from the image.
+    let esp = FatImage::open(&image)?;
+    for path in [
+        "EFI/BOOT/BOOTX64.EFI",
+        "EFI/systemd/systemd-bootx64.efi",
+        "EFI/Linux/nixos-generation-1.efi",
+        "EFI/Linux/nixos-generation-2.efi",
+    ] {
+        assert!(esp.root().join(path).is_file(), "{path} is missing");
+    }
+    let report = api::verify(&esp, &[PathBuf::from("tests/fixtures/uefi-keys/db.pem")])?;
+    assert_eq!(report.failures(), 0, "{report:?}");
+
+    Ok(())
+}
+
+#[test]
+fn keep_the_file_system_parameters_of_the_image() -> Result<()> {
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let images = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let image = images.path().join("esp.img");
+    let status = Command::new("mkfs.fat")
+        .args(["-F", "32", "-s", "1", "-n", "LANZA", "-i", "0badcafe", "-C"])
+        .arg(&image)
+        .arg("65536")
+        .status()?;
+    assert!(status.success());
+    let boot_sector = |image: &Path| -> Result<Vec<u8>> { Ok(fs::read(image)?[..512].to_vec()) };
+    let before = boot_sector(&image)?;
+
+    let output0 =
+        common::lanzaboote_install_with_args(0, &image, vec![generation_link], ["--esp-image"])?;
+    assert!(output0.status.success());
+
+    // The cluster size, the serial number, the label and the FAT type of FAT32.
+    let after = boot_sector(&image)?;
+    assert_eq!(after[13], 1);
+    assert_eq!(&after[67..71], &0x0badcafe_u32.to_le_bytes());
+    assert_eq!(&after[71..82], b"LANZA      ");
+    assert_eq!(&after[82..90], b"FAT32   ");
+    assert_eq!(before[11..90], after[11..90]);
+
+    Ok(())
+}
+
+#[test]
+fn reject_mirroring_an_esp_image() -> Result<()> {
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let image = tempdir()?;
+    let mirror = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        &image.path().join("esp.img"),
+        vec![generation_link],
+        [
+            OsStr::new("--esp-image"),
+            OsStr::new("--mirror-esp"),
+            mirror.path().as_os_str(),
+        ],
+    )?;
+    assert!(!output0.status.success());
+
+    Ok(())
+}
+
+#[test]
+fn install_with_options_through_the_api() -> Result<()> {
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let images = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    // See common::lanzaboote_install for why the systemd stub is used.
+    let test_systemd = common::systemd_location_from_env()?;
+    let stub = StubSource::new(PathBuf::from(format!(
+        "{test_systemd}/lib/systemd/boot/efi/linuxx64.efi.stub"
+    )));
+    let signer = SigningKey::new(KeyPair::new(
+        Path::new("tests/fixtures/uefi-keys/db.pem"),
+        Path::new("tests/fixtures/uefi-keys/db.key"),
+    ));
+    let mut esp = FatImage::create(&images.path().join("esp.img"), 64)?;
+
+    api::install(
+        &mut esp,
+        stub,
+        signer,
+        0,
+        vec![generation_link],
+        InstallOptions {
+            hash_algorithm: HashAlgorithm::Sha384,
+            stub_profile: StubProfile::Namespaced,
+            ..InstallOptions::default()
+        },
+    )?;
+
+    let stub = fs::read(esp.root().join("EFI/Linux/nixos-generation-1.efi"))?;
+    assert_eq!(common::pe_section(&stub, ".lzbhalg"), Some(&b"sha384"[..]));
+
+    Ok(())
+}
+
+#[test]
+fn keep_the_previous_esp_when_a_commit_fails() -> Result<()> {
+    let images = tempdir()?;
+    let image = images.path().join("esp.img");
+
+    let mut esp = FatImage::create(&image, 2)?;
+    fs::write(esp.root().join("previous"), "previous")?;
+    esp.commit()?;
+
+    // The file does not fit into the image.
+    let mut esp = FatImage::open(&image)?;
+    fs::write(esp.root().join("large"), vec![0; 4 * 1024 * 1024])?;
+    assert!(esp.commit().is_err());
+
+    let esp = FatImage::open(&image)?;
+    assert_eq!(fs::read_to_string(esp.root().join("previous"))?, "previous");
+    assert!(!esp.root().join("large").exists());
+
+    Ok(())
+}
@@ -0,0 +1,56 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn skip_excluded_generations() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2, 3]
+        .into_iter()
+        .map(|version| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), version)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+
+    // The excluded generation does not count against the configuration limit.
+    let output0 = common::lanzaboote_install_with_args(
+        2,
+        esp_mountpoint.path(),
+        &generation_links,
+        [
+            OsStr::new("--exclude-generation"),
+            OsStr::new("2"),
+            OsStr::new("--current-system"),
+            generation_links[1].as_os_str(),
+        ],
+    )?;
+    assert!(output0.status.success());
+    let stdout = String::from_utf8(output0.stdout)?;
+    assert!(stdout.contains("Warning: the excluded generation 2 is the running system"));
+
+    let stub = |version: u64| format!("EFI/Linux/nixos-generation-{version}.efi");
+    assert!(esp_mountpoint.path().join(stub(1)).exists());
+    assert!(!esp_mountpoint.path().join(stub(2)).exists());
+    assert!(esp_mountpoint.path().join(stub(3)).exists());
+
+    let manifest: serde_json::Value = serde_json::from_slice(&fs::read(
+        esp_mountpoint.path().join("EFI/nixos/manifest.json"),
+    )?)?;
+    let files = manifest["files"]
+        .as_object()
+        .expect("Manifest has no files");
+    assert!(files.contains_key(&stub(1)));
+    assert!(!files.contains_key(&stub(2)));
+    assert!(files.contains_key(&stub(3)));
+    assert!(manifest["generations"].get("2").is_none());
+
+    Ok(())
+}
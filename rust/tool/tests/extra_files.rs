@@ -0,0 +1,188 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+/// List credentials and system extensions in the lanzaboote extension of the bootspec of a
+/// generation.
+fn set_extra_files(
+    generation_link: &Path,
+    credentials: &[PathBuf],
+    sysexts: &[PathBuf],
+) -> Result<()> {
+    let bootspec_path = generation_link.join("boot.json");
+    let mut bootspec: serde_json::Value = serde_json::from_slice(&fs::read(&bootspec_path)?)?;
+    let lanzaboote = &mut bootspec["v1"]["extensions"]["lanzaboote"];
+    lanzaboote["credentials"] = serde_json::json!(credentials);
+    lanzaboote["sysexts"] = serde_json::json!(sysexts);
+    fs::write(bootspec_path, serde_json::to_vec(&bootspec)?)?;
+    Ok(())
+}
+
+#[test]
+fn install_extra_files_next_to_stub() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let credential = tmpdir.path().join("firstboot.cred");
+    fs::write(&credential, "credential")?;
+    let sysext = tmpdir.path().join("debug-tools.raw");
+    fs::write(&sysext, "sysext")?;
+    set_extra_files(&generation_link, &[credential], &[sysext])?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link.clone()],
+        ["--sign-extra-files"],
+    )?;
+    assert!(output0.status.success());
+
+    let extra_dir = esp_mountpoint
+        .path()
+        .join("EFI/Linux/nixos-generation-1.efi.extra.d");
+    assert_eq!(fs::read(extra_dir.join("firstboot.cred"))?, b"credential");
+    assert_eq!(fs::read(extra_dir.join("debug-tools.raw"))?, b"sysext");
+    assert!(extra_dir.join("firstboot.cred.sig").exists());
+    assert!(extra_dir.join("debug-tools.raw.sig").exists());
+
+    // The detached signatures of the extra files are verified.
+    assert!(common::lanzaboote_verify(esp_mountpoint.path())?
+        .status
+        .success());
+    fs::write(extra_dir.join("debug-tools.raw"), "tampered")?;
+    let verify_output = common::lanzaboote_verify(esp_mountpoint.path())?;
+    assert!(!verify_output.status.success());
+    fs::write(extra_dir.join("debug-tools.raw"), "sysext")?;
+
+    // Files that the generation does not list anymore are garbage collected.
+    set_extra_files(
+        &generation_link,
+        &[tmpdir.path().join("firstboot.cred")],
+        &[],
+    )?;
+    let output1 = common::lanzaboote_install(0, esp_mountpoint.path(), vec![generation_link])?;
+    assert!(output1.status.success());
+    assert!(extra_dir.join("firstboot.cred").exists());
+    assert!(!extra_dir.join("firstboot.cred.sig").exists());
+    assert!(!extra_dir.join("debug-tools.raw").exists());
+
+    Ok(())
+}
+
+#[test]
+fn keep_extra_files_when_collecting_garbage() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let credential = tmpdir.path().join("firstboot.cred");
+    fs::write(&credential, "credential")?;
+    set_extra_files(&generation_link, &[credential], &[])?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        ["--sign-extra-files"],
+    )?;
+    assert!(output0.status.success());
+
+    let output1 = assert_cmd::Command::cargo_bin("lzbt")?
+        .arg("gc")
+        .arg(esp_mountpoint.path())
+        .output()?;
+    assert!(output1.status.success());
+
+    let extra_dir = esp_mountpoint
+        .path()
+        .join("EFI/Linux/nixos-generation-1.efi.extra.d");
+    assert_eq!(fs::read(extra_dir.join("firstboot.cred"))?, b"credential");
+    assert!(extra_dir.join("firstboot.cred.sig").exists());
+
+    Ok(())
+}
+
+#[test]
+fn reject_credential_without_cred_extension() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let credential = tmpdir.path().join("firstboot.txt");
+    fs::write(&credential, "credential")?;
+    set_extra_files(&generation_link, &[credential], &[])?;
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), vec![generation_link])?;
+    // Malformed generations are skipped, so nothing is installed for it.
+    assert!(!esp_mountpoint
+        .path()
+        .join("EFI/Linux/nixos-generation-1.efi")
+        .exists());
+    assert!(String::from_utf8(output0.stdout)?.contains("does not end with .cred"));
+
+    Ok(())
+}
+
+#[test]
+fn refuse_extra_files_without_systemd_stub() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let credential = tmpdir.path().join("firstboot.cred");
+    fs::write(&credential, "credential")?;
+    set_extra_files(&generation_link, &[credential], &[])?;
+
+    // Any stub but systemd-stub, which is the only one that loads the extra files. It declares the
+    // protocol version like the lanzaboote stub, so that lzbt accepts it.
+    let systemd_boot = format!(
+        "{}/lib/systemd/boot/efi/systemd-bootx64.efi",
+        common::systemd_location_from_env()?
+    );
+    let protocol_version = tmpdir.path().join("lzbtver");
+    fs::write(&protocol_version, 2u32.to_le_bytes())?;
+    let stub = tmpdir.path().join("stub.efi");
+    let status = std::process::Command::new("objcopy")
+        .arg("--add-section")
+        .arg(format!(".lzbtver={}", protocol_version.display()))
+        .arg(systemd_boot)
+        .arg(&stub)
+        .status()?;
+    assert!(status.success());
+    let efivars = tempdir()?;
+    let output0 = assert_cmd::Command::cargo_bin("lzbt")?
+        .env("LANZABOOTE_STUB", stub)
+        .arg("install")
+        .arg("--efivars")
+        .arg(efivars.path())
+        .arg("--public-key")
+        .arg("tests/fixtures/uefi-keys/db.pem")
+        .arg("--private-key")
+        .arg("tests/fixtures/uefi-keys/db.key")
+        .arg("--configuration-limit")
+        .arg("0")
+        .arg(esp_mountpoint.path())
+        .arg(&generation_link)
+        .output()?;
+    assert!(!output0.status.success());
+    assert!(String::from_utf8(output0.stderr)?.contains("is not systemd-stub"));
+    assert!(!esp_mountpoint
+        .path()
+        .join("EFI/Linux/nixos-generation-1.efi.extra.d")
+        .exists());
+
+    Ok(())
+}
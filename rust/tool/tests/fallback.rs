@@ -0,0 +1,46 @@
+use std::path::Path;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn install_fallback_loaders_for_several_architectures() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let test_systemd = common::systemd_location_from_env()?;
+    let systemd_boot_x64 = format!("{test_systemd}/lib/systemd/boot/efi/systemd-bootx64.efi");
+    // There is no aarch64 build of systemd-boot in the test environment. Any other PE binary
+    // serves to tell the fallback loaders apart.
+    let systemd_boot_aa64 = format!("{test_systemd}/lib/systemd/boot/efi/linuxx64.efi.stub");
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        [
+            String::from("--fallback-loader"),
+            String::from("x64"),
+            String::from("--fallback-loader"),
+            format!("aa64={systemd_boot_aa64}"),
+        ],
+    )?;
+    assert!(output0.status.success());
+
+    let fallback_dir = esp_mountpoint.path().join("EFI/BOOT");
+    assert_eq!(
+        common::text_section(&fallback_dir.join("BOOTX64.EFI"))?,
+        common::text_section(Path::new(&systemd_boot_x64))?
+    );
+    assert_eq!(
+        common::text_section(&fallback_dir.join("BOOTAA64.EFI"))?,
+        common::text_section(Path::new(&systemd_boot_aa64))?
+    );
+
+    Ok(())
+}
@@ -0,0 +1,260 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use lanzaboote_tool::api::{EspFilesystem, FatImage};
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn prune_generations_with_free_space_check() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2]
+        .into_iter()
+        .map(|v| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), v)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+    let stub = |version: u64| {
+        esp_mountpoint
+            .path()
+            .join(format!("EFI/Linux/nixos-generation-{version}.efi"))
+    };
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), generation_links.clone())?;
+    assert!(output0.status.success());
+
+    let output1 = common::lanzaboote_install_with_args(
+        1,
+        esp_mountpoint.path(),
+        generation_links,
+        ["--check-free-space"],
+    )?;
+    assert!(output1.status.success());
+    assert!(!stub(1).exists());
+    assert!(stub(2).exists());
+
+    Ok(())
+}
+
+#[test]
+fn keep_generations_that_fit_with_auto_prune() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2]
+        .into_iter()
+        .map(|v| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), v)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+
+    // Only generations that do not fit are pruned.
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        generation_links,
+        ["--auto-prune"],
+    )?;
+    assert!(output0.status.success());
+    assert!(!String::from_utf8(output0.stdout)?.contains("Pruning generations"));
+    for version in [1, 2] {
+        assert!(esp_mountpoint
+            .path()
+            .join(format!("EFI/Linux/nixos-generation-{version}.efi"))
+            .exists());
+    }
+
+    Ok(())
+}
+
+/// Fill an ESP image until only half of the size of the stub of generation 1 is free, so that
+/// another stub only fits once an installed one is removed.
+fn fill_up_to_half_a_stub(image: &Path) -> Result<()> {
+    let mut esp = FatImage::open(image)?;
+    let stub_size = fs::metadata(esp.root().join("EFI/Linux/nixos-generation-1.efi"))?.len();
+    let free = esp
+        .capacity()
+        .expect("FAT images have a capacity")
+        .free_space(esp.root())?;
+    fs::write(
+        esp.root().join("filler"),
+        vec![0; (free - stub_size / 2) as usize],
+    )?;
+    esp.commit()
+}
+
+#[test]
+fn remove_pruned_generations_first_if_the_esp_is_full() -> Result<()> {
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let images = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2, 3]
+        .into_iter()
+        .map(|v| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), v)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+    let image = images.path().join("esp.img");
+    FatImage::create(&image, 64)?;
+
+    let output0 =
+        common::lanzaboote_install_with_args(0, &image, &generation_links[..2], ["--esp-image"])?;
+    assert!(output0.status.success());
+    fill_up_to_half_a_stub(&image)?;
+
+    let output1 = common::lanzaboote_install_with_args(
+        0,
+        &image,
+        &generation_links[1..],
+        ["--esp-image", "--check-free-space"],
+    )?;
+    assert!(output1.status.success());
+    assert!(String::from_utf8(output1.stdout)?.contains("Removing pruned generations first"));
+
+    let esp = FatImage::open(&image)?;
+    let stub = |version: u64| {
+        esp.root()
+            .join(format!("EFI/Linux/nixos-generation-{version}.efi"))
+    };
+    assert!(!stub(1).exists());
+    assert!(stub(2).exists());
+    assert!(stub(3).exists());
+
+    Ok(())
+}
+
+#[test]
+fn do_not_count_foreign_stubs_as_removable() -> Result<()> {
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let images = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2]
+        .into_iter()
+        .map(|v| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), v)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+    let image = images.path().join("esp.img");
+    FatImage::create(&image, 64)?;
+
+    let output0 =
+        common::lanzaboote_install_with_args(0, &image, &generation_links[..1], ["--esp-image"])?;
+    assert!(output0.status.success());
+
+    // A stub with the name of a generation that lanzaboote did not install.
+    let mut esp = FatImage::open(&image)?;
+    let linux = esp.root().join("EFI/Linux");
+    fs::copy(
+        linux.join("nixos-generation-1.efi"),
+        linux.join("nixos-generation-7.efi"),
+    )?;
+    esp.commit()?;
+    fill_up_to_half_a_stub(&image)?;
+
+    let output1 = common::lanzaboote_install_with_args(
+        0,
+        &image,
+        &generation_links,
+        ["--esp-image", "--check-free-space"],
+    )?;
+    assert!(!output1.status.success());
+    assert!(String::from_utf8(output1.stderr)?.contains("Not enough space on the ESP"));
+
+    let esp = FatImage::open(&image)?;
+    assert!(esp.root().join("EFI/Linux/nixos-generation-7.efi").exists());
+
+    Ok(())
+}
+
+#[test]
+fn auto_prune_keeps_newest_default_and_pinned_generations() -> Result<()> {
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let images = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2, 3, 4, 5]
+        .into_iter()
+        .map(|v| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), v)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+    let image = images.path().join("esp.img");
+    FatImage::create(&image, 64)?;
+    let args = [
+        "--esp-image",
+        "--default-generation",
+        "1",
+        "--pinned-recovery",
+        "2",
+    ];
+
+    let output0 = common::lanzaboote_install_with_args(0, &image, &generation_links[..4], args)?;
+    assert!(output0.status.success());
+
+    // The stub of generation 5 only fits once the stub of another generation is gone.
+    fill_up_to_half_a_stub(&image)?;
+
+    let output1 = common::lanzaboote_install_with_args(
+        0,
+        &image,
+        &generation_links,
+        args.into_iter().chain(["--auto-prune"]),
+    )?;
+    assert!(output1.status.success());
+    assert!(String::from_utf8(output1.stdout)?.contains("Pruning generations 3 to free up space"));
+
+    // Generations 1 and 2 are older, but the default and the pinned recovery generation.
+    let esp = FatImage::open(&image)?;
+    let stub = |version: u64| {
+        esp.root()
+            .join(format!("EFI/Linux/nixos-generation-{version}.efi"))
+    };
+    for version in [1, 2, 4, 5] {
+        assert!(stub(version).exists(), "generation {version} was pruned");
+    }
+    assert!(!stub(3).exists());
+
+    Ok(())
+}
+
+#[test]
+fn warn_about_nearly_full_esp_after_install() -> Result<()> {
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let images = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+    let image = images.path().join("esp.img");
+    FatImage::create(&image, 64)?;
+    let args = ["--esp-image", "--free-space-warning-percentage", "10"];
+
+    let output0 = common::lanzaboote_install_with_args(0, &image, [&generation_link], args)?;
+    assert!(output0.status.success());
+    assert!(!String::from_utf8(output0.stdout)?.contains("below the threshold"));
+
+    // Fill the image until only 5% of it are free.
+    let mut esp = FatImage::open(&image)?;
+    let capacity = esp.capacity().expect("FAT images have a capacity");
+    let free = capacity.free_space(esp.root())?;
+    fs::write(
+        esp.root().join("filler"),
+        vec![0; (free - capacity.total / 20) as usize],
+    )?;
+    esp.commit()?;
+
+    let output1 = common::lanzaboote_install_with_args(0, &image, [&generation_link], args)?;
+    assert!(output1.status.success());
+    let stdout = String::from_utf8(output1.stdout)?;
+    assert!(stdout.contains(&format!("of {} bytes are free on the ESP", capacity.total)));
+    assert!(stdout.contains("below the threshold of 10%"));
+
+    Ok(())
+}
@@ -1,3 +1,4 @@
+use std::ffi::OsStr;
 use std::fs;
 use std::path::{Path, PathBuf};
 
@@ -79,6 +80,160 @@ fn keep_unrelated_files_on_esp() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn list_orphans_in_dry_run() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), vec![generation_link])?;
+    assert!(output0.status.success());
+
+    let orphan = esp_mountpoint.path().join("EFI/nixos/orphaned-bzImage.efi");
+    fs::write(&orphan, "")?;
+    let files_before = count_files(&esp_mountpoint.path().join("EFI/nixos"))?;
+
+    let output1 = lanzaboote_gc(esp_mountpoint.path(), ["--dry-run"])?;
+    assert!(output1.status.success());
+    assert_eq!(
+        String::from_utf8(output1.stdout)?,
+        format!("{}\n", orphan.display())
+    );
+    assert!(orphan.exists());
+    assert_eq!(
+        count_files(&esp_mountpoint.path().join("EFI/nixos"))?,
+        files_before
+    );
+
+    let output2 = lanzaboote_gc(esp_mountpoint.path(), Vec::<&str>::new())?;
+    assert!(output2.status.success());
+    assert!(!orphan.exists());
+    assert_eq!(
+        count_files(&esp_mountpoint.path().join("EFI/nixos"))?,
+        files_before - 1
+    );
+
+    Ok(())
+}
+
+#[test]
+fn keep_initrd_signatures() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        ["--sign-initrd"],
+    )?;
+    assert!(output0.status.success());
+
+    let nixos = esp_mountpoint.path().join("EFI/nixos");
+    let signatures = files_with_extension(&nixos, "sig")?;
+    assert!(!signatures.is_empty());
+
+    let output1 = lanzaboote_gc(esp_mountpoint.path(), Vec::<&str>::new())?;
+    assert!(output1.status.success());
+    assert_eq!(files_with_extension(&nixos, "sig")?, signatures);
+
+    Ok(())
+}
+
+#[test]
+fn keep_metadata_sidecars() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        ["--write-metadata"],
+    )?;
+    assert!(output0.status.success());
+
+    let metadata = esp_mountpoint
+        .path()
+        .join("EFI/Linux/nixos-generation-1.efi.meta");
+    assert!(metadata.exists());
+
+    let output1 = lanzaboote_gc(esp_mountpoint.path(), Vec::<&str>::new())?;
+    assert!(output1.status.success());
+    assert!(metadata.exists());
+
+    Ok(())
+}
+
+#[test]
+fn keep_boot_loader_entries() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        ["--boot-loader-entries"],
+    )?;
+    assert!(output0.status.success());
+
+    let entry = esp_mountpoint
+        .path()
+        .join("loader/entries/nixos-bls-generation-1.conf");
+    assert!(entry.exists());
+
+    let output1 = lanzaboote_gc(esp_mountpoint.path(), Vec::<&str>::new())?;
+    assert!(output1.status.success());
+    assert!(entry.exists());
+
+    Ok(())
+}
+
+fn lanzaboote_gc(
+    esp_mountpoint: &Path,
+    args: impl IntoIterator<Item = impl AsRef<OsStr>>,
+) -> Result<std::process::Output> {
+    let output = assert_cmd::Command::cargo_bin("lzbt")?
+        .arg("gc")
+        .args(args)
+        .arg(esp_mountpoint)
+        .output()?;
+    eprint!("{}", String::from_utf8_lossy(&output.stderr));
+    Ok(output)
+}
+
+/// Count the boot files in a directory, ignoring bookkeeping files such as the manifest.
 fn count_files(path: &Path) -> Result<usize> {
-    Ok(fs::read_dir(path)?.count())
+    let mut count = 0;
+    for entry in fs::read_dir(path)? {
+        if entry?.path().extension() == Some(OsStr::new("efi")) {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// The files in a directory with the given extension, sorted by name.
+fn files_with_extension(path: &Path, extension: &str) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        if path.extension() == Some(OsStr::new(extension)) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
 }
@@ -0,0 +1,56 @@
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn hard_link_identical_files() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2]
+        .into_iter()
+        .map(|version| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), version)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), generation_links)?;
+    assert!(output0.status.success());
+
+    // The generations have distinct toplevels, but their initrds have the same contents.
+    let initrds: Vec<PathBuf> = fs::read_dir(esp_mountpoint.path().join("EFI/nixos"))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .filter(|path| path.to_string_lossy().ends_with("-initrd.efi"))
+        .collect();
+    assert_eq!(initrds.len(), 2);
+    assert_eq!(inode(&initrds[0])?, inode(&initrds[1])?);
+    assert_eq!(fs::metadata(&initrds[0])?.nlink(), 2);
+
+    // systemd-boot is signed only once and shared with the fallback boot loader.
+    let systemd_boot = esp_mountpoint
+        .path()
+        .join("EFI/systemd/systemd-bootx64.efi");
+    let fallback = esp_mountpoint.path().join("EFI/BOOT/BOOTX64.EFI");
+    assert_eq!(inode(&systemd_boot)?, inode(&fallback)?);
+
+    // Both links are intact for the next installation.
+    let output1 = assert_cmd::Command::cargo_bin("lzbt")?
+        .arg("verify")
+        .arg(esp_mountpoint.path())
+        .output()?;
+    assert!(output1.status.success());
+
+    Ok(())
+}
+
+fn inode(path: &Path) -> Result<u64> {
+    Ok(fs::metadata(path)?.ino())
+}
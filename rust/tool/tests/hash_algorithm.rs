@@ -0,0 +1,81 @@
+use std::fs;
+
+use anyhow::Result;
+use assert_cmd::Command;
+use sha2::{Digest, Sha512};
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn embed_hashes_of_configured_algorithm() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        ["--hash-algorithm", "sha512"],
+    )?;
+    assert!(output0.status.success());
+
+    let stub = fs::read(
+        esp_mountpoint
+            .path()
+            .join("EFI/Linux/nixos-generation-1.efi"),
+    )?;
+    assert_eq!(common::pe_section(&stub, ".hashalg"), Some(&b"sha512"[..]));
+
+    let kernel = fs::read_dir(esp_mountpoint.path().join("EFI/nixos"))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .find(|path| path.to_string_lossy().contains("kernel"))
+        .expect("Kernel was not installed");
+    assert_eq!(
+        common::pe_section(&stub, ".kernelh"),
+        Some(Sha512::digest(fs::read(kernel)?).as_slice())
+    );
+    assert_eq!(
+        common::pe_section(&stub, ".initrdh").map(<[u8]>::len),
+        Some(64)
+    );
+
+    // The references are verified with the embedded algorithm.
+    let output1 = Command::cargo_bin("lzbt")?
+        .arg("verify")
+        .arg(esp_mountpoint.path())
+        .output()?;
+    assert!(output1.status.success());
+
+    Ok(())
+}
+
+#[test]
+fn embed_no_algorithm_for_sha256() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), vec![generation_link])?;
+    assert!(output0.status.success());
+
+    let stub = fs::read(
+        esp_mountpoint
+            .path()
+            .join("EFI/Linux/nixos-generation-1.efi"),
+    )?;
+    assert!(common::pe_section(&stub, ".hashalg").is_none());
+    assert_eq!(
+        common::pe_section(&stub, ".kernelh").map(<[u8]>::len),
+        Some(32)
+    );
+
+    Ok(())
+}
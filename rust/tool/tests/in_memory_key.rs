@@ -0,0 +1,130 @@
+use std::collections::BTreeSet;
+use std::env;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+mod common;
+
+/// Write an sbsign wrapper that appends its arguments to `log`, one per line, before running the
+/// real sbsign.
+fn write_logging_sbsign(directory: &Path, log: &Path) -> Result<()> {
+    let real_sbsign = env::var_os("PATH")
+        .iter()
+        .flat_map(env::split_paths)
+        .map(|directory| directory.join("sbsign"))
+        .find(|path| path.exists())
+        .context("Failed to find sbsign")?;
+
+    let script = format!(
+        r#"#!/bin/sh
+printf '%s\n' "$@" >> {log}
+exec {real_sbsign} "$@"
+"#,
+        log = log.display(),
+        real_sbsign = real_sbsign.display(),
+    );
+    let path = directory.join("sbsign");
+    fs::write(&path, script)?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+    Ok(())
+}
+
+/// The paths of all files below a directory, relative to it.
+fn relative_files(directory: &Path) -> Result<BTreeSet<PathBuf>> {
+    let mut files = BTreeSet::new();
+    for entry in walkdir::WalkDir::new(directory) {
+        let entry = entry?;
+        if entry.file_type().is_file() {
+            files.insert(entry.path().strip_prefix(directory)?.to_path_buf());
+        }
+    }
+    Ok(files)
+}
+
+#[test]
+fn sign_with_private_key_from_stdin() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let lzbt_tmpdir = tempdir()?;
+    let workdir = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let test_systemd = common::systemd_location_from_env()?;
+    let test_systemd_stub = format!("{test_systemd}/lib/systemd/boot/efi/linuxx64.efi.stub");
+    let private_key = fs::read("tests/fixtures/uefi-keys/db.key")?;
+    let public_key = fs::canonicalize("tests/fixtures/uefi-keys/db.pem")?;
+
+    let bin = tmpdir.path().join("bin");
+    fs::create_dir(&bin)?;
+    let sbsign_log = tmpdir.path().join("sbsign.log");
+    write_logging_sbsign(&bin, &sbsign_log)?;
+    let path = env::join_paths(
+        [bin]
+            .into_iter()
+            .chain(env::var_os("PATH").iter().flat_map(env::split_paths)),
+    )?;
+
+    let efivars = tempdir()?;
+    let output = Command::cargo_bin("lzbt")?
+        .env("LANZABOOTE_STUB", test_systemd_stub)
+        .env("TMPDIR", lzbt_tmpdir.path())
+        .env("PATH", path)
+        .current_dir(workdir.path())
+        .arg("install")
+        .arg("--efivars")
+        .arg(efivars.path())
+        .arg("--public-key")
+        .arg(&public_key)
+        .arg("--private-key-stdin")
+        .arg(esp_mountpoint.path())
+        .arg(&generation_link)
+        .write_stdin(private_key.clone())
+        .output()?;
+    print!("{}", String::from_utf8(output.stdout.clone())?);
+    print!("{}", String::from_utf8(output.stderr.clone())?);
+    assert!(output.status.success());
+
+    assert!(esp_mountpoint
+        .path()
+        .join("EFI/Linux/nixos-generation-1.efi")
+        .exists());
+
+    // sbsign only ever got the private key as an inherited file descriptor.
+    let sbsign_args = fs::read_to_string(&sbsign_log)?;
+    let args: Vec<&str> = sbsign_args.lines().collect();
+    let keys: Vec<&str> = args
+        .windows(2)
+        .filter(|pair| pair[0] == "--key")
+        .map(|pair| pair[1])
+        .collect();
+    assert!(!keys.is_empty());
+    for key in keys {
+        assert!(key.starts_with("/dev/fd/"), "sbsign got the key {key}");
+    }
+
+    // The private key was not written to the temporary directory of lzbt or the working
+    // directory.
+    assert_eq!(fs::read_dir(lzbt_tmpdir.path())?.count(), 0);
+    assert_eq!(fs::read_dir(workdir.path())?.count(), 0);
+
+    // The ESP has the same files as after installing with a key file, none of which is the key.
+    let reference_esp = tempdir()?;
+    let reference = common::lanzaboote_install(0, reference_esp.path(), [&generation_link])?;
+    assert!(reference.status.success());
+    assert_eq!(
+        relative_files(esp_mountpoint.path())?,
+        relative_files(reference_esp.path())?
+    );
+    for file in relative_files(esp_mountpoint.path())? {
+        assert_ne!(fs::read(esp_mountpoint.path().join(file))?, private_key);
+    }
+
+    Ok(())
+}
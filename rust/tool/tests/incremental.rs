@@ -0,0 +1,157 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn skip_unchanged_generations() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2]
+        .into_iter()
+        .map(|v| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), v)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+    let stub = |version: u64| {
+        esp_mountpoint
+            .path()
+            .join(format!("EFI/Linux/nixos-generation-{version}.efi"))
+    };
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        &generation_links[..1],
+        ["--incremental"],
+    )?;
+    assert!(output0.status.success());
+    let stub1 = fs::read(stub(1))?;
+
+    let output1 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        &generation_links,
+        ["--incremental"],
+    )?;
+    assert!(output1.status.success());
+    let stdout = String::from_utf8(output1.stdout)?;
+    assert!(stdout.contains("Generation 1 is unchanged, skipping..."));
+    assert!(!stdout.contains("Installing generation 1"));
+    assert!(stdout.contains("Installing generation 2"));
+
+    // The skipped generation is neither rewritten nor garbage collected.
+    assert_eq!(fs::read(stub(1))?, stub1);
+    assert!(stub(2).exists());
+
+    Ok(())
+}
+
+#[test]
+fn reinstall_generations_with_changed_options() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+    let section = tmpdir.path().join("section");
+    fs::write(&section, "first")?;
+    let extra_section = format!(".test={}", section.display());
+
+    let args = ["--incremental", "--extra-section", &extra_section];
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link.clone()],
+        args,
+    )?;
+    assert!(output0.status.success());
+
+    // The contents of the extra section changed, but not the bootspec.
+    fs::write(&section, "second")?;
+    let output1 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        args,
+    )?;
+    assert!(output1.status.success());
+    let stdout = String::from_utf8(output1.stdout)?;
+    assert!(!stdout.contains("Generation 1 is unchanged, skipping..."));
+    assert!(stdout.contains("Installing generation 1"));
+
+    let stub_data = fs::read(
+        esp_mountpoint
+            .path()
+            .join("EFI/Linux/nixos-generation-1.efi"),
+    )?;
+    let embedded = common::pe_section(&stub_data, ".test").expect("Missing .test section");
+    assert!(embedded.starts_with(b"second"));
+
+    Ok(())
+}
+
+#[test]
+fn report_skipped_generations_as_installed() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2]
+        .into_iter()
+        .map(|v| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), v)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+    let hook = tmpdir.path().join("hook");
+    fs::write(&hook, "#!/bin/sh\ncat > \"$1\"\n")?;
+    fs::set_permissions(&hook, fs::Permissions::from_mode(0o755))?;
+    let report_path = tmpdir.path().join("report.json");
+    let args = [
+        OsStr::new("--incremental"),
+        OsStr::new("--post-install-hook"),
+        hook.as_os_str(),
+        OsStr::new("--post-install-hook-arg"),
+        report_path.as_os_str(),
+    ];
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        &generation_links[..1],
+        args,
+    )?;
+    assert!(output0.status.success());
+    let output1 =
+        common::lanzaboote_install_with_args(0, esp_mountpoint.path(), &generation_links, args)?;
+    assert!(output1.status.success());
+    assert!(String::from_utf8(output1.stdout)?.contains("Generation 1 is unchanged, skipping..."));
+
+    let report: serde_json::Value = serde_json::from_slice(&fs::read(report_path)?)?;
+    let mut versions: Vec<u64> = report["generations"]
+        .as_array()
+        .expect("The report has no generations")
+        .iter()
+        .filter_map(|generation| generation["version"].as_u64())
+        .collect();
+    versions.sort();
+    assert_eq!(versions, [1, 2]);
+    let kept = report["generations"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|generation| generation["version"] == 1)
+        .expect("Generation 1 is missing from the report");
+    assert!(kept["stub"].as_str().map_or(false, |stub| stub
+        .ends_with("EFI/Linux/nixos-generation-1.efi")));
+    assert!(kept["size"].as_u64().map_or(false, |size| size > 0));
+
+    Ok(())
+}
@@ -0,0 +1,56 @@
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn collect_removed_generations_and_sweep_periodically() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2]
+        .into_iter()
+        .map(|version| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), version)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+    let install = |links: &[PathBuf]| -> Result<()> {
+        let output = common::lanzaboote_install_with_args(
+            0,
+            esp_mountpoint.path(),
+            links,
+            ["--incremental-gc", "3"],
+        )?;
+        assert!(output.status.success());
+        Ok(())
+    };
+
+    // Without a previous manifest, the whole ESP is swept.
+    install(&generation_links)?;
+
+    let linux = esp_mountpoint.path().join("EFI/Linux");
+    let stray = esp_mountpoint.path().join("EFI/nixos/stray.efi");
+    fs::write(&stray, b"stray")?;
+    let files_before = fs::read_dir(esp_mountpoint.path().join("EFI/nixos"))?.count();
+
+    // The files of the removed generation are collected, but the stray file is not found.
+    install(&generation_links[1..])?;
+    assert!(!linux.join("nixos-generation-1.efi").exists());
+    assert!(linux.join("nixos-generation-2.efi").exists());
+    assert!(stray.exists());
+    assert!(fs::read_dir(esp_mountpoint.path().join("EFI/nixos"))?.count() < files_before);
+
+    install(&generation_links[1..])?;
+    assert!(stray.exists());
+
+    // Every third installation sweeps the whole ESP.
+    install(&generation_links[1..])?;
+    assert!(!stray.exists());
+    assert!(linux.join("nixos-generation-2.efi").exists());
+
+    Ok(())
+}
@@ -0,0 +1,161 @@
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn verify_base_initrd_hash_despite_regenerated_secrets() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let append_secrets = tmpdir.path().join("append-initrd-secrets");
+    fs::write(&append_secrets, "#!/bin/sh\nprintf secret >> \"$1\"\n")?;
+    fs::set_permissions(&append_secrets, fs::Permissions::from_mode(0o755))?;
+
+    let bootspec_path = generation_link.join("boot.json");
+    let mut bootspec: serde_json::Value = serde_json::from_slice(&fs::read(&bootspec_path)?)?;
+    bootspec["v1"]["initrdSecrets"] = serde_json::json!(append_secrets);
+    let base_initrd_size = fs::metadata(
+        bootspec["v1"]["initrd"]
+            .as_str()
+            .expect("Bootspec has no initrd"),
+    )?
+    .len();
+    fs::write(bootspec_path, serde_json::to_vec(&bootspec)?)?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        ["--initrd-hash", "base"],
+    )?;
+    assert!(output0.status.success());
+
+    let initrd = fs::read_dir(esp_mountpoint.path().join("EFI/nixos"))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .find(|path| path.to_string_lossy().contains("initrd"))
+        .expect("Initrd was not installed");
+    let mut data = fs::read(&initrd)?;
+    assert!(data.ends_with(b"secret"));
+
+    // The activation regenerates the secrets after the installation.
+    data.truncate(usize::try_from(base_initrd_size)?);
+    data.extend_from_slice(b"new secret");
+    fs::write(&initrd, &data)?;
+
+    let output1 = common::lanzaboote_verify(esp_mountpoint.path())?;
+    assert!(output1.status.success());
+    assert!(String::from_utf8(output1.stdout)?
+        .contains("OK (the appended initrd secrets are not covered by the hash)"));
+
+    // The base initrd is still verified.
+    data[0] ^= 0xff;
+    fs::write(&initrd, &data)?;
+
+    let output2 = common::lanzaboote_verify(esp_mountpoint.path())?;
+    assert!(!output2.status.success());
+    assert!(String::from_utf8(output2.stdout)?
+        .contains("does not match the hash embedded into the stub"));
+
+    Ok(())
+}
+
+/// The base hash cannot tell appended secrets from any other appended data. This is what makes
+/// `--initrd-hash base` unsafe against an attacker who can write to the ESP.
+#[test]
+fn base_initrd_hash_accepts_appended_cpio_archive() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let append_secrets = tmpdir.path().join("append-initrd-secrets");
+    fs::write(&append_secrets, "#!/bin/sh\nprintf secret >> \"$1\"\n")?;
+    fs::set_permissions(&append_secrets, fs::Permissions::from_mode(0o755))?;
+
+    let bootspec_path = generation_link.join("boot.json");
+    let mut bootspec: serde_json::Value = serde_json::from_slice(&fs::read(&bootspec_path)?)?;
+    bootspec["v1"]["initrdSecrets"] = serde_json::json!(append_secrets);
+    fs::write(bootspec_path, serde_json::to_vec(&bootspec)?)?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        ["--initrd-hash", "base"],
+    )?;
+    assert!(output0.status.success());
+    assert!(String::from_utf8(output0.stdout)?
+        .contains("Warning: the embedded hash only covers the base initrd"));
+
+    let initrd = fs::read_dir(esp_mountpoint.path().join("EFI/nixos"))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .find(|path| path.to_string_lossy().contains("initrd"))
+        .expect("Initrd was not installed");
+    let mut data = fs::read(&initrd)?;
+    // The start of a newc cpio archive that replaces /init of the base initrd.
+    data.extend_from_slice(b"070701000000000000810000000000000000000000000000000000000005init\0");
+    fs::write(&initrd, &data)?;
+
+    let output1 = common::lanzaboote_verify(esp_mountpoint.path())?;
+    assert!(output1.status.success());
+    assert!(String::from_utf8(output1.stdout)?
+        .contains("OK (the appended initrd secrets are not covered by the hash)"));
+
+    Ok(())
+}
+
+#[test]
+fn hash_covers_appended_secrets() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let append_secrets = tmpdir.path().join("append-initrd-secrets");
+    fs::write(&append_secrets, "#!/bin/sh\nprintf secret >> \"$1\"\n")?;
+    fs::set_permissions(&append_secrets, fs::Permissions::from_mode(0o755))?;
+
+    let bootspec_path = generation_link.join("boot.json");
+    let mut bootspec: serde_json::Value = serde_json::from_slice(&fs::read(&bootspec_path)?)?;
+    bootspec["v1"]["initrdSecrets"] = serde_json::json!(append_secrets);
+    fs::write(bootspec_path, serde_json::to_vec(&bootspec)?)?;
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), vec![generation_link])?;
+    assert!(output0.status.success());
+
+    let output1 = common::lanzaboote_verify(esp_mountpoint.path())?;
+    assert!(output1.status.success());
+    assert!(!String::from_utf8(output1.stdout)?.contains("not covered by the hash"));
+
+    let initrd = fs::read_dir(esp_mountpoint.path().join("EFI/nixos"))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .find(|path| path.to_string_lossy().contains("initrd"))
+        .expect("Initrd was not installed");
+    let mut data = fs::read(&initrd)?;
+    assert!(data.ends_with(b"secret"));
+
+    // Tampering with the secrets is detected as well.
+    let last = data.len() - 1;
+    data[last] ^= 0xff;
+    fs::write(&initrd, &data)?;
+
+    let output2 = common::lanzaboote_verify(esp_mountpoint.path())?;
+    assert!(!output2.status.success());
+
+    Ok(())
+}
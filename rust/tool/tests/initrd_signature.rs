@@ -0,0 +1,101 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn sign_and_verify_initrd() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        &[generation_link],
+        ["--sign-initrd"],
+    )?;
+    assert!(output0.status.success());
+
+    let signatures = fs::read_dir(esp_mountpoint.path().join("EFI/nixos"))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .filter(|path| {
+            path.extension()
+                .map_or(false, |extension| extension == "sig")
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(signatures.len(), 1);
+    let signature = &signatures[0];
+    assert!(signature.with_extension("").exists());
+
+    let output1 = lanzaboote_verify_with_public_key(esp_mountpoint.path())?;
+    assert!(output1.status.success());
+
+    let mut data = fs::read(signature)?;
+    let last = data.len() - 1;
+    data[last] ^= 0xff;
+    fs::write(signature, data)?;
+
+    let output2 = lanzaboote_verify_with_public_key(esp_mountpoint.path())?;
+    assert!(!output2.status.success());
+    assert!(String::from_utf8(output2.stdout)?.contains("does not match the key"));
+
+    Ok(())
+}
+
+#[test]
+fn require_initrd_signature_once_initrds_are_signed() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        &[&generation_link],
+        ["--sign-initrd"],
+    )?;
+    assert!(output0.status.success());
+
+    let signature = fs::read_dir(esp_mountpoint.path().join("EFI/nixos"))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .find(|path| path.to_string_lossy().ends_with("-initrd.efi.sig"))
+        .expect("Initrd was not signed");
+    fs::remove_file(&signature)?;
+
+    let output1 = lanzaboote_verify_with_public_key(esp_mountpoint.path())?;
+    assert!(!output1.status.success());
+    assert!(String::from_utf8(output1.stdout)?.contains("has no detached signature"));
+
+    // Installing without signing the initrds makes the signature optional again.
+    let output2 = common::lanzaboote_install(0, esp_mountpoint.path(), [&generation_link])?;
+    assert!(output2.status.success());
+    let output3 = lanzaboote_verify_with_public_key(esp_mountpoint.path())?;
+    assert!(output3.status.success());
+
+    Ok(())
+}
+
+fn lanzaboote_verify_with_public_key(esp_mountpoint: &Path) -> Result<std::process::Output> {
+    let output = Command::cargo_bin("lzbt")?
+        .arg("verify")
+        .arg("--public-key")
+        .arg("tests/fixtures/uefi-keys/db.pem")
+        .arg(esp_mountpoint)
+        .output()?;
+    print!("{}", String::from_utf8_lossy(&output.stdout));
+    print!("{}", String::from_utf8_lossy(&output.stderr));
+    Ok(output)
+}
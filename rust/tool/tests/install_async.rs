@@ -0,0 +1,65 @@
+#![cfg(feature = "tokio")]
+
+use std::future::poll_fn;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+
+use anyhow::Result;
+use futures_core::Stream;
+use lanzaboote_tool::api::{
+    self, EspFilesystem, FatImage, InstallOptions, KeyPair, SigningKey, StubSource,
+};
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn install_async_reports_progress() -> Result<()> {
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let images = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2]
+        .into_iter()
+        .map(|v| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), v)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+
+    // See common::lanzaboote_install for why the systemd stub is used.
+    let test_systemd = common::systemd_location_from_env()?;
+    let stub = StubSource::new(PathBuf::from(format!(
+        "{test_systemd}/lib/systemd/boot/efi/linuxx64.efi.stub"
+    )));
+    let signer = SigningKey::new(KeyPair::new(
+        Path::new("tests/fixtures/uefi-keys/db.pem"),
+        Path::new("tests/fixtures/uefi-keys/db.key"),
+    ));
+    let esp = FatImage::create(&images.path().join("esp.img"), 64)?;
+
+    let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+    let (phases, esp) = runtime.block_on(async {
+        let (mut progress, installation) = api::install_async(
+            esp,
+            stub,
+            signer,
+            0,
+            generation_links,
+            InstallOptions::default(),
+        );
+        let installation = tokio::spawn(installation);
+
+        let mut phases = Vec::new();
+        while let Some(progress) = poll_fn(|cx| Pin::new(&mut progress).poll_next(cx)).await {
+            phases.push(progress.phase);
+        }
+        Ok::<_, anyhow::Error>((phases, installation.await??))
+    })?;
+
+    for phase in ["generation 1", "generation 2", "collecting garbage"] {
+        assert!(phases.iter().any(|p| p == phase), "{phase} in {phases:?}");
+    }
+    assert!(esp.root().join("EFI/Linux/nixos-generation-2.efi").exists());
+
+    Ok(())
+}
@@ -0,0 +1,66 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use lanzaboote_tool::api::{
+    self, EspFilesystem, FatImage, InstallOptions, KeyPair, SigningKey, StubSource,
+};
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn install_collect_garbage_and_verify_on_fat_image() -> Result<()> {
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let images = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2]
+        .into_iter()
+        .map(|v| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), v)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+
+    // See common::lanzaboote_install for why the systemd stub is used.
+    let test_systemd = common::systemd_location_from_env()?;
+    let stub = StubSource::new(PathBuf::from(format!(
+        "{test_systemd}/lib/systemd/boot/efi/linuxx64.efi.stub"
+    )));
+    let signer = SigningKey::new(KeyPair::new(
+        Path::new("tests/fixtures/uefi-keys/db.pem"),
+        Path::new("tests/fixtures/uefi-keys/db.key"),
+    ));
+
+    let image = images.path().join("esp.img");
+    let mut esp = FatImage::create(&image, 64)?;
+    api::install(
+        &mut esp,
+        stub,
+        signer,
+        0,
+        generation_links,
+        InstallOptions::default(),
+    )?;
+
+    // The installed files survive the round trip through the FAT image.
+    let mut esp = FatImage::open(&image)?;
+    for version in [1, 2] {
+        assert!(esp
+            .root()
+            .join(format!("EFI/Linux/nixos-generation-{version}.efi"))
+            .exists());
+    }
+
+    let orphan = esp.root().join("EFI/nixos/orphan.efi");
+    fs::write(&orphan, "not referenced by any stub")?;
+    api::collect_garbage(&mut esp)?;
+    assert!(!orphan.exists());
+
+    let esp = FatImage::open(&image)?;
+    assert!(!esp.root().join("EFI/nixos/orphan.efi").exists());
+    let report = api::verify(&esp, &[PathBuf::from("tests/fixtures/uefi-keys/db.pem")])?;
+    assert_eq!(report.failures(), 0, "{report:?}");
+
+    Ok(())
+}
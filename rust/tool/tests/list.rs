@@ -0,0 +1,61 @@
+use std::fs;
+
+use anyhow::Result;
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn list_embedded_cmdlines() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), vec![generation_link])?;
+    assert!(output0.status.success());
+
+    let linux = esp_mountpoint.path().join("EFI/Linux");
+    let stub = linux.join("nixos-generation-1.efi");
+    let stub_data = fs::read(&stub)?;
+    let embedded_cmdline = std::str::from_utf8(
+        common::pe_section(&stub_data, ".cmdline").expect("Stub has no .cmdline section"),
+    )?;
+
+    // A stub without a .cmdline section is listed without a kernel command line.
+    let test_systemd = common::systemd_location_from_env()?;
+    let foreign_stub = linux.join("nixos-foreign.efi");
+    fs::copy(
+        format!("{test_systemd}/lib/systemd/boot/efi/linuxx64.efi.stub"),
+        &foreign_stub,
+    )?;
+
+    let output1 = Command::cargo_bin("lzbt")?
+        .arg("list")
+        .arg("--json")
+        .arg(esp_mountpoint.path())
+        .output()?;
+    assert!(output1.status.success());
+
+    let listed: serde_json::Value = serde_json::from_slice(&output1.stdout)?;
+    assert_eq!(
+        listed,
+        serde_json::json!([
+            { "stub": foreign_stub, "version": null, "cmdline": null },
+            { "stub": stub, "version": 1, "cmdline": embedded_cmdline },
+        ])
+    );
+
+    let output2 = Command::cargo_bin("lzbt")?
+        .arg("list")
+        .arg(esp_mountpoint.path())
+        .output()?;
+    assert!(output2.status.success());
+    let stdout = String::from_utf8(output2.stdout)?;
+    assert!(stdout.contains("nixos-foreign.efi: no kernel command line"));
+    assert!(stdout.contains("Generation 1 (nixos-generation-1.efi): init="));
+
+    Ok(())
+}
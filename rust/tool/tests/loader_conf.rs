@@ -0,0 +1,131 @@
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn make_newest_generation_default() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2]
+        .into_iter()
+        .map(|v| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), v)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+
+    let loader_conf = esp_mountpoint.path().join("loader/loader.conf");
+    fs::create_dir_all(esp_mountpoint.path().join("loader"))?;
+    fs::write(&loader_conf, "timeout 3\ndefault nixos-generation-1.efi\n")?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        generation_links,
+        ["--make-default"],
+    )?;
+    assert!(output0.status.success());
+
+    assert_eq!(
+        fs::read_to_string(&loader_conf)?,
+        "timeout 3\ndefault nixos-generation-2.efi\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn restore_tampered_loader_conf() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+    let loader_conf = esp_mountpoint.path().join("loader/loader.conf");
+    let args = [
+        "--loader-setting",
+        "timeout=3",
+        "--loader-setting",
+        "editor=no",
+    ];
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link.clone()],
+        args,
+    )?;
+    assert!(output0.status.success());
+    assert_eq!(fs::read_to_string(&loader_conf)?, "timeout 3\neditor no\n");
+
+    fs::write(&loader_conf, "timeout 0\neditor yes\n")?;
+
+    let output1 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        args,
+    )?;
+    assert!(output1.status.success());
+    assert_eq!(fs::read_to_string(&loader_conf)?, "timeout 3\neditor no\n");
+    let stdout = String::from_utf8(output1.stdout)?;
+    assert!(stdout.contains("Updating loader.conf: editor changed from \"yes\" to \"no\""));
+
+    Ok(())
+}
+
+#[test]
+fn manage_boot_menu_settings() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2]
+        .into_iter()
+        .map(|v| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), v)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+
+    let loader_conf = esp_mountpoint.path().join("loader/loader.conf");
+    fs::create_dir_all(esp_mountpoint.path().join("loader"))?;
+    fs::write(
+        &loader_conf,
+        "# Managed by lanzaboote\nbeep yes\ntimeout 3\n",
+    )?;
+    let args = [
+        "--timeout",
+        "menu-force",
+        "--default-generation",
+        "1",
+        "--disable-editor",
+        "--console-mode",
+        "max",
+    ];
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        generation_links.clone(),
+        args,
+    )?;
+    assert!(output0.status.success());
+    assert_eq!(
+        fs::read_to_string(&loader_conf)?,
+        "# Managed by lanzaboote\nbeep yes\ntimeout menu-force\neditor no\nconsole-mode max\ndefault nixos-generation-1.efi\n"
+    );
+
+    // Nothing changes on the next installation.
+    let output1 =
+        common::lanzaboote_install_with_args(0, esp_mountpoint.path(), generation_links, args)?;
+    assert!(output1.status.success());
+    let stdout = String::from_utf8(output1.stdout)?;
+    assert!(!stdout.contains("Updating loader.conf"));
+
+    Ok(())
+}
@@ -0,0 +1,172 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn reconstruct_manifest_of_legacy_esp() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link_with_specialisations(
+        tmpdir.path(),
+        profiles.path(),
+        1,
+        &["work"],
+    )
+    .expect("Failed to setup generation link");
+
+    // An ESP installed by a version of lanzaboote without a manifest.
+    let output0 =
+        common::lanzaboote_install(0, esp_mountpoint.path(), vec![generation_link.clone()])?;
+    assert!(output0.status.success());
+    let manifest_path = esp_mountpoint.path().join("EFI/nixos/manifest.json");
+    fs::remove_file(&manifest_path)?;
+
+    let output1 = lanzaboote_migrate(esp_mountpoint.path())?;
+    assert!(output1.status.success());
+
+    let manifest: serde_json::Value = serde_json::from_slice(&fs::read(&manifest_path)?)?;
+    let files = manifest["files"]
+        .as_object()
+        .expect("Manifest has no files");
+    assert!(files.contains_key("EFI/Linux/nixos-generation-1.efi"));
+    assert!(files.contains_key("EFI/Linux/nixos-generation-1-specialisation-work.efi"));
+    assert_eq!(
+        files
+            .keys()
+            .filter(|path| path.starts_with("EFI/nixos/"))
+            .count(),
+        2,
+        "Kernel and initrd are not recorded"
+    );
+    assert_eq!(
+        manifest["specialisations"]["EFI/Linux/nixos-generation-1-specialisation-work.efi"],
+        "EFI/Linux/nixos-generation-1.efi"
+    );
+
+    // Migrating again does not change anything.
+    let migrated_manifest = fs::read(&manifest_path)?;
+    let output2 = lanzaboote_migrate(esp_mountpoint.path())?;
+    assert!(output2.status.success());
+    assert_eq!(fs::read(&manifest_path)?, migrated_manifest);
+
+    // The next installation keeps the migrated boot entries.
+    let output3 = common::lanzaboote_install(0, esp_mountpoint.path(), vec![generation_link])?;
+    assert!(output3.status.success());
+    let stdout = String::from_utf8(output3.stdout)?;
+    assert!(stdout.contains("nixos-generation-1.efi already exists, skipping..."));
+
+    Ok(())
+}
+
+#[test]
+fn adopt_systemd_boot_installation() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let quarantine = tempdir()?;
+    common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    // An ESP that the systemd-boot module of NixOS was installed to. Generation 2 was removed
+    // from the profile since.
+    let entries = esp_mountpoint.path().join("loader/entries");
+    let nixos = esp_mountpoint.path().join("EFI/nixos");
+    fs::create_dir_all(&entries)?;
+    fs::create_dir_all(&nixos)?;
+    for version in [1, 2] {
+        fs::write(
+            entries.join(format!("nixos-generation-{version}.conf")),
+            format!("title NixOS\nlinux /EFI/nixos/unsigned-{version}-bzImage.efi\n"),
+        )?;
+        fs::write(
+            nixos.join(format!("unsigned-{version}-bzImage.efi")),
+            "unsigned kernel",
+        )?;
+    }
+
+    let test_systemd = common::systemd_location_from_env()?;
+    let efivars = tempdir()?;
+    let output0 = Command::cargo_bin("lzbt")?
+        .env(
+            "LANZABOOTE_STUB",
+            format!("{test_systemd}/lib/systemd/boot/efi/linuxx64.efi.stub"),
+        )
+        .arg("migrate")
+        .arg("--from-systemd-boot")
+        .arg("--efivars")
+        .arg(efivars.path())
+        .arg("--quarantine")
+        .arg(quarantine.path())
+        .arg("--profile")
+        .arg(profiles.path().join("system"))
+        .arg("--public-key")
+        .arg("tests/fixtures/uefi-keys/db.pem")
+        .arg("--private-key")
+        .arg("tests/fixtures/uefi-keys/db.key")
+        .arg(esp_mountpoint.path())
+        .output()?;
+    let stdout = String::from_utf8(output0.stdout)?;
+    print!("{stdout}");
+    print!("{}", String::from_utf8_lossy(&output0.stderr));
+    assert!(output0.status.success());
+    assert!(stdout.contains("generation 2 is not in the profile"));
+
+    assert!(esp_mountpoint
+        .path()
+        .join("EFI/Linux/nixos-generation-1.efi")
+        .exists());
+    for version in [1, 2] {
+        let entry = format!("loader/entries/nixos-generation-{version}.conf");
+        let kernel = format!("EFI/nixos/unsigned-{version}-bzImage.efi");
+        assert!(!esp_mountpoint.path().join(&entry).exists());
+        assert!(!esp_mountpoint.path().join(&kernel).exists());
+        assert!(quarantine.path().join(&entry).exists());
+        assert!(quarantine.path().join(&kernel).exists());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn reject_install_arguments_without_from_systemd_boot() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+
+    for args in [
+        vec!["--dry-run"],
+        vec!["--public-key", "tests/fixtures/uefi-keys/db.pem"],
+        vec!["--profile", "/nix/var/nix/profiles/other"],
+    ] {
+        let output = Command::cargo_bin("lzbt")?
+            .arg("migrate")
+            .args(&args)
+            .arg(esp_mountpoint.path())
+            .output()?;
+        let stderr = String::from_utf8(output.stderr)?;
+        print!("{stderr}");
+        assert!(!output.status.success());
+        assert!(stderr.contains("can only be given with --from-systemd-boot"));
+    }
+    // Nothing was migrated.
+    assert!(!esp_mountpoint
+        .path()
+        .join("EFI/nixos/manifest.json")
+        .exists());
+
+    Ok(())
+}
+
+fn lanzaboote_migrate(esp_mountpoint: &Path) -> Result<std::process::Output> {
+    let output = Command::cargo_bin("lzbt")?
+        .arg("migrate")
+        .arg(esp_mountpoint)
+        .output()?;
+    print!("{}", String::from_utf8_lossy(&output.stdout));
+    print!("{}", String::from_utf8_lossy(&output.stderr));
+    Ok(output)
+}
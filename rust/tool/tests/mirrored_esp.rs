@@ -0,0 +1,76 @@
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn install_to_mirrored_esps() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let mirror_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        [
+            "--mirror-esp".as_ref(),
+            mirror_mountpoint.path().as_os_str(),
+        ],
+    )?;
+    assert!(output0.status.success());
+
+    for esp in [esp_mountpoint.path(), mirror_mountpoint.path()] {
+        assert!(esp.join("EFI/Linux/nixos-generation-1.efi").exists());
+        assert!(esp.join("EFI/BOOT/BOOTX64.EFI").exists());
+    }
+    assert_eq!(
+        fs::read(
+            esp_mountpoint
+                .path()
+                .join("EFI/Linux/nixos-generation-1.efi")
+        )?,
+        fs::read(
+            mirror_mountpoint
+                .path()
+                .join("EFI/Linux/nixos-generation-1.efi")
+        )?
+    );
+
+    Ok(())
+}
+
+#[test]
+fn failing_mirror_does_not_abort_others() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let mirror_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    // The mirror is missing, e.g. because its disk failed.
+    let missing_esp = mirror_mountpoint.path().join("missing");
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        ["--mirror-esp".as_ref(), missing_esp.as_os_str()],
+    )?;
+    assert!(!output0.status.success());
+    let stderr = String::from_utf8(output0.stderr)?;
+    assert!(stderr.contains("Failed to install to 1 of 2 ESPs"));
+    assert!(stderr.contains(&format!("{missing_esp:?}")));
+
+    assert!(esp_mountpoint
+        .path()
+        .join("EFI/Linux/nixos-generation-1.efi")
+        .exists());
+
+    Ok(())
+}
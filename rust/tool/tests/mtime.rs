@@ -0,0 +1,51 @@
+use std::ffi::OsStr;
+use std::fs;
+
+use anyhow::Result;
+use filetime::FileTime;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn set_fixed_mtime_on_installed_files() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        [OsStr::new("--mtime"), OsStr::new("1672531201")],
+    )?;
+    assert!(output0.status.success());
+
+    // The timestamp is rounded down to the 2-second granularity of FAT.
+    let expected_mtime = FileTime::from_unix_time(1672531200, 0);
+    let installed_files = [
+        "EFI/BOOT/BOOTX64.EFI",
+        "EFI/systemd/systemd-bootx64.efi",
+        "EFI/nixos/manifest.json",
+        "loader/random-seed",
+    ];
+    for file in installed_files {
+        let metadata = fs::metadata(esp_mountpoint.path().join(file))?;
+        assert_eq!(
+            FileTime::from_last_modification_time(&metadata),
+            expected_mtime,
+            "{file} has the wrong modification time"
+        );
+    }
+    for entry in fs::read_dir(esp_mountpoint.path().join("EFI/Linux"))? {
+        let metadata = entry?.metadata()?;
+        assert_eq!(
+            FileTime::from_last_modification_time(&metadata),
+            expected_mtime
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,79 @@
+use std::ffi::OsStr;
+use std::fs;
+
+use anyhow::Result;
+use goblin::pe::PE;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn boot_kernel_without_initrd() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let bootspec_path = generation_link.join("boot.json");
+    let mut bootspec: serde_json::Value = serde_json::from_slice(&fs::read(&bootspec_path)?)?;
+    bootspec["v1"]
+        .as_object_mut()
+        .expect("Bootspec is not an object")
+        .remove("initrd");
+    fs::write(bootspec_path, serde_json::to_vec(&bootspec)?)?;
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), vec![generation_link])?;
+    assert!(output0.status.success());
+
+    let stub_data = fs::read(
+        esp_mountpoint
+            .path()
+            .join("EFI/Linux/nixos-generation-1.efi"),
+    )?;
+    let stub = PE::parse(&stub_data)?;
+    let names = stub
+        .sections
+        .iter()
+        .map(|section| section.name().map(String::from))
+        .collect::<Result<Vec<_>, _>>()?;
+    for name in [".initrdp", ".initrdh", ".initrdl"] {
+        assert!(
+            !names.iter().any(|n| n == name),
+            "The stub has an {name} section"
+        );
+    }
+
+    // The sections that lzbt embeds are aligned and follow each other in order.
+    let section_alignment = stub
+        .header
+        .optional_header
+        .expect("The stub has no optional header")
+        .windows_fields
+        .section_alignment;
+    let embedded_sections: Vec<_> = stub
+        .sections
+        .iter()
+        .zip(&names)
+        .filter(|(_, name)| [".osrel", ".cmdline", ".kernelp", ".kernelh"].contains(&name.as_str()))
+        .map(|(section, _)| section)
+        .collect();
+    assert_eq!(embedded_sections.len(), 4);
+    for section in &embedded_sections {
+        assert_eq!(section.virtual_address % section_alignment, 0);
+    }
+    for pair in embedded_sections.windows(2) {
+        assert!(pair[1].virtual_address >= pair[0].virtual_address + pair[0].virtual_size);
+    }
+
+    // Only the kernel is stored as a separate file.
+    let installed_files = fs::read_dir(esp_mountpoint.path().join("EFI/nixos"))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .filter(|path| path.extension() == Some(OsStr::new("efi")))
+        .count();
+    assert_eq!(installed_files, 1);
+
+    Ok(())
+}
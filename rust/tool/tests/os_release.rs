@@ -1,4 +1,6 @@
 use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
 
 use anyhow::{Context, Result};
 use expect_test::expect;
@@ -23,14 +25,16 @@ fn generate_expected_os_release() -> Result<()> {
             .path()
             .join("EFI/Linux/nixos-generation-1.efi"),
     )?;
-    let os_release_section = pe_section(&stub_data, ".osrel")
+    let os_release_section = common::pe_section(&stub_data, ".osrel")
         .context("Failed to read .osrelease PE section.")?
         .to_owned();
 
     let expected = expect![[r#"
         ID=lanza
-        PRETTY_NAME=LanzaOS
-        VERSION_ID=Generation 1 NixOS 23.05, Linux Kernel 6.1.1, Built on 1970-01-01
+        IMAGE_VERSION=1
+        PRETTY_NAME=LanzaOS (Generation 1, NixOS 23.05, Built on 1970-01-01)
+        VERSION=Generation 1 NixOS 23.05, Linux Kernel 6.1.1, Built on 1970-01-01
+        VERSION_ID=1
     "#]];
 
     expected.assert_eq(&String::from_utf8(os_release_section)?);
@@ -38,17 +42,61 @@ fn generate_expected_os_release() -> Result<()> {
     Ok(())
 }
 
-fn pe_section<'a>(file_data: &'a [u8], section_name: &str) -> Option<&'a [u8]> {
-    let pe_binary = goblin::pe::PE::parse(file_data).ok()?;
+#[test]
+fn systemd_boot_sorts_entries_by_generation() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    // Generation 10 sorts before generation 2 by name, but after it by number.
+    let generation_links: Vec<PathBuf> = [1, 2, 10]
+        .into_iter()
+        .map(|version| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), version)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        generation_links,
+        ["--cmdline-profile", "debug=debug"],
+    )?;
+    assert!(output0.status.success());
+
+    // bootctl sorts the entries exactly like the boot menu of systemd-boot does.
+    let test_systemd = common::systemd_location_from_env()?;
+    let output1 = Command::new(format!("{test_systemd}/bin/bootctl"))
+        .env("SYSTEMD_RELAX_ESP_CHECKS", "1")
+        .env("SYSTEMD_RELAX_XBOOTLDR_CHECKS", "1")
+        .arg("--esp-path")
+        .arg(esp_mountpoint.path())
+        .arg("--boot-path")
+        .arg(esp_mountpoint.path())
+        .arg("--no-variables")
+        .arg("--json=short")
+        .arg("list")
+        .output()?;
+    print!("{}", String::from_utf8_lossy(&output1.stderr));
+    assert!(output1.status.success());
 
-    pe_binary
-        .sections
+    let entries: Vec<serde_json::Value> = serde_json::from_slice(&output1.stdout)?;
+    let ids: Vec<&str> = entries
         .iter()
-        .find(|s| s.name().unwrap() == section_name)
-        .and_then(|s| {
-            let section_start: usize = s.pointer_to_raw_data.try_into().ok()?;
-            assert!(s.virtual_size <= s.size_of_raw_data);
-            let section_end: usize = section_start + usize::try_from(s.virtual_size).ok()?;
-            Some(&file_data[section_start..section_end])
-        })
+        .filter_map(|entry| entry["id"].as_str())
+        .filter(|id| id.starts_with("nixos-generation-"))
+        .collect();
+    assert_eq!(
+        ids,
+        [
+            "nixos-generation-10.efi",
+            "nixos-generation-10-profile-debug.efi",
+            "nixos-generation-2.efi",
+            "nixos-generation-2-profile-debug.efi",
+            "nixos-generation-1.efi",
+            "nixos-generation-1-profile-debug.efi",
+        ]
+    );
+
+    Ok(())
 }
@@ -0,0 +1,82 @@
+use std::fs;
+use std::process::Command;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn sign_pcr_policy() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let public_key = tmpdir.path().join("tpm2-pcr-public.pem");
+    let status = Command::new("openssl")
+        .args([
+            "pkey",
+            "-pubout",
+            "-in",
+            "tests/fixtures/uefi-keys/db.key",
+            "-out",
+        ])
+        .arg(&public_key)
+        .status()?;
+    assert!(status.success());
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        [
+            "--embed-kernel".as_ref(),
+            "--pcr-private-key".as_ref(),
+            "tests/fixtures/uefi-keys/db.key".as_ref(),
+            "--pcr-public-key".as_ref(),
+            public_key.as_os_str(),
+        ],
+    )?;
+    assert!(output0.status.success());
+
+    let stub_data = fs::read(
+        esp_mountpoint
+            .path()
+            .join("EFI/Linux/nixos-generation-1.efi"),
+    )?;
+    assert_eq!(
+        common::pe_section(&stub_data, ".pcrpkey"),
+        Some(&fs::read(&public_key)?[..])
+    );
+    let signature = common::pe_section(&stub_data, ".pcrsig").expect("Missing .pcrsig");
+    let signature: serde_json::Value = serde_json::from_slice(signature)?;
+    assert!(signature["sha256"].is_array());
+
+    Ok(())
+}
+
+#[test]
+fn pcr_policy_requires_embedded_kernel() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        [
+            "--pcr-private-key",
+            "tests/fixtures/uefi-keys/db.key",
+            "--pcr-public-key",
+            "tests/fixtures/uefi-keys/db.pem",
+        ],
+    )?;
+    assert!(!output0.status.success());
+
+    Ok(())
+}
@@ -0,0 +1,73 @@
+use std::fs;
+
+use anyhow::{Context, Result};
+use assert_cmd::Command;
+use sha2::{Digest, Sha256};
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn preview_pcrs_without_signing_a_policy() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        ["--preview-pcrs"],
+    )?;
+    assert!(output0.status.success());
+    let stdout = String::from_utf8(output0.stdout)?;
+    print!("{stdout}");
+
+    let stub_path = esp_mountpoint
+        .path()
+        .join("EFI/Linux/nixos-generation-1.efi");
+    assert!(stdout.contains(&format!(
+        "Predicted PCR 11 values of {}:",
+        stub_path.display()
+    )));
+
+    // The values of the phases continue from the measurement of the sections.
+    let output1 = Command::cargo_bin("lzbt")?
+        .arg("predict-pcrs")
+        .arg(&stub_path)
+        .output()?;
+    assert!(output1.status.success());
+    let pcr11 = String::from_utf8(output1.stdout)?
+        .trim()
+        .strip_prefix("11: ")
+        .context("Missing PCR 11")?
+        .to_owned();
+
+    let mut pcr = decode_hex(&pcr11);
+    let mut phases = Vec::new();
+    for phase in ["enter-initrd", "leave-initrd", "sysinit", "ready"] {
+        pcr = Sha256::new()
+            .chain_update(&pcr)
+            .chain_update(Sha256::digest(phase))
+            .finalize()
+            .to_vec();
+        phases.push(phase);
+        let value: String = pcr.iter().map(|byte| format!("{byte:02x}")).collect();
+        assert!(stdout.contains(&format!("  {}: {value}", phases.join(":"))));
+    }
+
+    let stub = fs::read(&stub_path)?;
+    assert!(common::pe_section(&stub, ".pcrsig").is_none());
+    assert!(common::pe_section(&stub, ".pcrpkey").is_none());
+
+    Ok(())
+}
+
+fn decode_hex(hex: &str) -> Vec<u8> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(&hex[index..index + 2], 16).expect("Invalid hex"))
+        .collect()
+}
@@ -0,0 +1,35 @@
+use std::fs;
+
+use anyhow::Result;
+use goblin::pe::{header, optional_header, PE};
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn produce_pe32_plus_stub_for_x86_64() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), vec![generation_link])?;
+    assert!(output0.status.success());
+
+    let data = fs::read(
+        esp_mountpoint
+            .path()
+            .join("EFI/Linux/nixos-generation-1.efi"),
+    )?;
+    let pe = PE::parse(&data)?;
+    assert_eq!(pe.header.coff_header.machine, header::COFF_MACHINE_X86_64);
+    assert_eq!(
+        pe.header
+            .optional_header
+            .map(|optional_header| optional_header.standard_fields.magic),
+        Some(optional_header::MAGIC_64)
+    );
+
+    Ok(())
+}
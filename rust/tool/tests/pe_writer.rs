@@ -0,0 +1,155 @@
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use anyhow::Result;
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+mod common;
+
+/// The name, address and contents of every section of a PE binary.
+fn sections(data: &[u8]) -> Result<Vec<(String, u32, Vec<u8>)>> {
+    let pe = goblin::pe::PE::parse(data)?;
+    pe.sections
+        .iter()
+        .map(|section| {
+            let start = section.pointer_to_raw_data as usize;
+            let size = section.virtual_size.min(section.size_of_raw_data) as usize;
+            Ok((
+                section.name()?.to_owned(),
+                section.virtual_address,
+                data[start..start + size].to_vec(),
+            ))
+        })
+        .collect()
+}
+
+fn install_with_pe_writer(esp_mountpoint: &Path, pe_writer: &str) -> Result<Vec<u8>> {
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint,
+        vec![generation_link],
+        ["--pe-writer", pe_writer],
+    )?;
+    assert!(output.status.success());
+
+    Ok(fs::read(
+        esp_mountpoint.join("EFI/Linux/nixos-generation-1.efi"),
+    )?)
+}
+
+#[test]
+fn native_pe_writer_matches_objcopy() -> Result<()> {
+    let objcopy_esp = tempdir()?;
+    let native_esp = tempdir()?;
+    let objcopy_stub = install_with_pe_writer(objcopy_esp.path(), "objcopy")?;
+    let native_stub = install_with_pe_writer(native_esp.path(), "native")?;
+
+    // The kernels and initrds have other paths (the toplevels have random names) and other
+    // signatures (they contain the time of signing), so their paths and hashes differ.
+    let differs = |name: &str| matches!(name, ".initrdp" | ".kernelp" | ".initrdh" | ".kernelh");
+    let objcopy_sections = sections(&objcopy_stub)?;
+    let native_sections = sections(&native_stub)?;
+    assert_eq!(objcopy_sections.len(), native_sections.len());
+    for (objcopy, native) in objcopy_sections.iter().zip(&native_sections) {
+        assert_eq!(objcopy.0, native.0);
+        assert_eq!(
+            objcopy.1, native.1,
+            "Section {} is at another address",
+            objcopy.0
+        );
+        if !differs(&objcopy.0) {
+            assert_eq!(
+                objcopy.2, native.2,
+                "Section {} has other contents",
+                objcopy.0
+            );
+        }
+    }
+
+    let output = Command::cargo_bin("lzbt")?
+        .arg("verify")
+        .arg(native_esp.path())
+        .output()?;
+    assert!(output.status.success());
+
+    Ok(())
+}
+
+#[test]
+fn install_without_objcopy() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    // An objcopy that always fails shadows the real one.
+    let bin = tmpdir.path().join("bin");
+    fs::create_dir(&bin)?;
+    let objcopy = bin.join("objcopy");
+    fs::write(&objcopy, "#!/bin/sh\nexit 1\n")?;
+    fs::set_permissions(&objcopy, fs::Permissions::from_mode(0o755))?;
+    let path = std::env::join_paths(std::iter::once(bin).chain(std::env::split_paths(
+        &std::env::var_os("PATH").unwrap_or_default(),
+    )))?;
+
+    let test_systemd = common::systemd_location_from_env()?;
+    let efivars = tempdir()?;
+    let output = Command::cargo_bin("lzbt")?
+        .env(
+            "LANZABOOTE_STUB",
+            format!("{test_systemd}/lib/systemd/boot/efi/linuxx64.efi.stub"),
+        )
+        .env("PATH", path)
+        .arg("install")
+        .arg("--efivars")
+        .arg(efivars.path())
+        .arg("--public-key")
+        .arg("tests/fixtures/uefi-keys/db.pem")
+        .arg("--private-key")
+        .arg("tests/fixtures/uefi-keys/db.key")
+        .arg(esp_mountpoint.path())
+        .arg(generation_link)
+        .output()?;
+    print!("{}", String::from_utf8_lossy(&output.stderr));
+    assert!(output.status.success());
+    assert!(esp_mountpoint
+        .path()
+        .join("EFI/Linux/nixos-generation-1.efi")
+        .exists());
+
+    Ok(())
+}
+
+#[test]
+fn only_show_commands() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        ["--pe-writer", "objcopy", "--show-commands"],
+    )?;
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout)?;
+    assert!(stdout
+        .lines()
+        .any(|line| line.starts_with("objcopy ") && line.contains("--add-section")));
+
+    // Nothing is installed to the ESP.
+    assert_eq!(fs::read_dir(esp_mountpoint.path())?.count(), 0);
+
+    Ok(())
+}
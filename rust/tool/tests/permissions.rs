@@ -0,0 +1,33 @@
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn set_explicit_permissions_on_esp_files() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), vec![generation_link])?;
+    assert!(output0.status.success());
+
+    let mode = |path: &str| -> Result<u32> {
+        Ok(fs::metadata(esp_mountpoint.path().join(path))?
+            .permissions()
+            .mode()
+            & 0o777)
+    };
+
+    assert_eq!(mode("loader/random-seed")?, 0o600);
+    assert_eq!(mode("EFI/nixos/manifest.json")?, 0o600);
+    assert_eq!(mode("EFI/Linux/nixos-generation-1.efi")?, 0o644);
+    assert_eq!(mode("EFI/systemd/systemd-bootx64.efi")?, 0o644);
+
+    Ok(())
+}
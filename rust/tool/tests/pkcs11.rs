@@ -0,0 +1,127 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command as StdCommand;
+
+use anyhow::{anyhow, Result};
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+mod common;
+
+/// The PIN of the SoftHSM token that holds the test key.
+const PIN: &str = "1234";
+
+/// Create a SoftHSM token labeled `db` in `directory` and import the test key into it.
+///
+/// Returns the configuration file of SoftHSM, which every process that uses the token needs to
+/// be given via `SOFTHSM2_CONF`.
+fn setup_softhsm_token(directory: &Path) -> Result<PathBuf> {
+    let token_directory = directory.join("tokens");
+    fs::create_dir(&token_directory)?;
+    let conf = directory.join("softhsm2.conf");
+    fs::write(
+        &conf,
+        format!("directories.tokendir = {}\n", token_directory.display()),
+    )?;
+
+    let softhsm2_util = |args: &[&str]| -> Result<()> {
+        let output = StdCommand::new("softhsm2-util")
+            .env("SOFTHSM2_CONF", &conf)
+            .args(args)
+            .output()?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "Failed to run softhsm2-util with args `{args:?}`: {}",
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+        Ok(())
+    };
+    softhsm2_util(&[
+        "--init-token",
+        "--free",
+        "--label",
+        "db",
+        "--pin",
+        PIN,
+        "--so-pin",
+        "5678",
+    ])?;
+    softhsm2_util(&[
+        "--import",
+        "tests/fixtures/uefi-keys/db.key",
+        "--token",
+        "db",
+        "--label",
+        "db",
+        "--id",
+        "01",
+        "--pin",
+        PIN,
+    ])?;
+
+    Ok(conf)
+}
+
+#[test]
+fn sign_with_key_on_softhsm_token() -> Result<()> {
+    // The pkcs11 engine of OpenSSL loads the module that PKCS11_MODULE_PATH points to.
+    let softhsm_available = env::var_os("PKCS11_MODULE_PATH")
+        .map_or(false, |module| Path::new(&module).exists())
+        && StdCommand::new("softhsm2-util")
+            .arg("--version")
+            .output()
+            .is_ok();
+    if !softhsm_available {
+        eprintln!("Skipping sign_with_key_on_softhsm_token: SoftHSM is not available");
+        return Ok(());
+    }
+
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let softhsm = tempdir()?;
+    let softhsm_conf = setup_softhsm_token(softhsm.path())?;
+
+    let test_systemd = common::systemd_location_from_env()?;
+    let efivars = tempdir()?;
+    let output = Command::cargo_bin("lzbt")?
+        .env(
+            "LANZABOOTE_STUB",
+            format!("{test_systemd}/lib/systemd/boot/efi/linuxx64.efi.stub"),
+        )
+        .env("SOFTHSM2_CONF", &softhsm_conf)
+        .arg("install")
+        .arg("--efivars")
+        .arg(efivars.path())
+        .arg("--public-key")
+        .arg("tests/fixtures/uefi-keys/db.pem")
+        .arg("--pkcs11-key")
+        .arg(format!(
+            "pkcs11:token=db;object=db;type=private;pin-value={PIN}"
+        ))
+        .arg(esp_mountpoint.path())
+        .arg(generation_link)
+        .output()?;
+    print!("{}", String::from_utf8_lossy(&output.stdout));
+    print!("{}", String::from_utf8_lossy(&output.stderr));
+    assert!(output.status.success());
+
+    for path in [
+        "EFI/Linux/nixos-generation-1.efi",
+        "EFI/systemd/systemd-bootx64.efi",
+    ] {
+        let output = StdCommand::new("sbverify")
+            .arg("--cert")
+            .arg("tests/fixtures/uefi-keys/db.pem")
+            .arg(esp_mountpoint.path().join(path))
+            .output()?;
+        assert!(output.status.success(), "{path} is not signed");
+    }
+
+    Ok(())
+}
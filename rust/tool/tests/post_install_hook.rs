@@ -0,0 +1,88 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn pass_report_to_post_install_hook() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let hook = write_hook(tmpdir.path(), r#"cat > "$1""#)?;
+    let report_path = tmpdir.path().join("report.json");
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        [
+            OsStr::new("--post-install-hook"),
+            hook.as_os_str(),
+            OsStr::new("--post-install-hook-arg"),
+            report_path.as_os_str(),
+        ],
+    )?;
+    assert!(output0.status.success());
+
+    let report: serde_json::Value = serde_json::from_slice(&fs::read(report_path)?)?;
+    // The report contains the canonical path of the ESP.
+    let esp = esp_mountpoint.path().canonicalize()?;
+    assert_eq!(report["esp"], esp.to_str().unwrap());
+    assert_eq!(report["generations"][0]["version"], 1);
+    assert_eq!(
+        report["generations"][0]["stub"],
+        esp.join("EFI/Linux/nixos-generation-1.efi")
+            .to_str()
+            .unwrap()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn failing_post_install_hook_fails_installation() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let hook = write_hook(tmpdir.path(), "exit 1")?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link.clone()],
+        [OsStr::new("--post-install-hook"), hook.as_os_str()],
+    )?;
+    assert!(!output0.status.success());
+
+    let output1 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        [
+            OsStr::new("--post-install-hook"),
+            hook.as_os_str(),
+            OsStr::new("--post-install-hook-warn-only"),
+        ],
+    )?;
+    assert!(output1.status.success());
+
+    Ok(())
+}
+
+fn write_hook(directory: &Path, script: &str) -> Result<PathBuf> {
+    let path = directory.join("hook");
+    fs::write(&path, format!("#!/bin/sh\n{script}\n"))?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+    Ok(path)
+}
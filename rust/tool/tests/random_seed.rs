@@ -0,0 +1,44 @@
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn create_random_seed_on_fresh_install() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), vec![generation_link])?;
+    assert!(output0.status.success());
+
+    let random_seed = fs::read(esp_mountpoint.path().join("loader/random-seed"))?;
+    assert_eq!(random_seed.len(), 32, "Random seed has the wrong size");
+
+    Ok(())
+}
+
+#[test]
+fn keep_existing_random_seed() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let random_seed_path = esp_mountpoint.path().join("loader/random-seed");
+    let existing_seed = [0x42; 32];
+    fs::create_dir_all(esp_mountpoint.path().join("loader"))?;
+    fs::write(&random_seed_path, existing_seed)?;
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), vec![generation_link])?;
+    assert!(output0.status.success());
+
+    assert_eq!(fs::read(random_seed_path)?, existing_seed);
+
+    Ok(())
+}
@@ -0,0 +1,52 @@
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn keep_pinned_recovery_generation_beyond_configuration_limit() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2, 3]
+        .into_iter()
+        .map(|v| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), v)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+
+    // Install all 3 generations.
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), generation_links.clone())?;
+    assert!(output0.status.success());
+
+    // Prune to 1 generation, but pin the oldest one as recovery generation.
+    let output1 = common::lanzaboote_install_with_args(
+        1,
+        esp_mountpoint.path(),
+        generation_links,
+        ["--pinned-recovery", "1"],
+    )?;
+    assert!(output1.status.success());
+
+    let linux = esp_mountpoint.path().join("EFI/Linux");
+    let mut images: Vec<String> = fs::read_dir(&linux)?
+        .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+        .collect::<Result<_>>()?;
+    images.sort();
+    assert_eq!(
+        images,
+        ["nixos-generation-1-recovery.efi", "nixos-generation-3.efi"]
+    );
+
+    let recovery_image = fs::read(linux.join("nixos-generation-1-recovery.efi"))?;
+    let os_release = common::pe_section(&recovery_image, ".osrel").expect("Missing .osrel");
+    let os_release = String::from_utf8_lossy(os_release);
+    assert!(os_release.contains("PRETTY_NAME=LanzaOS (Generation 1, "));
+    assert!(os_release.contains(" (Recovery)\n"));
+
+    Ok(())
+}
@@ -0,0 +1,158 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command as StdCommand, Output};
+
+use anyhow::Result;
+use assert_cmd::Command;
+use sha2::{Digest, Sha256};
+use tempfile::tempdir;
+
+mod common;
+
+const PUBLIC_KEY: &str = "tests/fixtures/uefi-keys/db.pem";
+
+fn lanzaboote_refresh_hashes(
+    esp_mountpoint: &Path,
+    stub: &Path,
+    args: impl IntoIterator<Item = impl AsRef<OsStr>>,
+) -> Result<Output> {
+    let output = Command::cargo_bin("lzbt")?
+        .arg("refresh-hashes")
+        .arg("--public-key")
+        .arg(PUBLIC_KEY)
+        .arg("--private-key")
+        .arg("tests/fixtures/uefi-keys/db.key")
+        .args(args)
+        .arg(esp_mountpoint)
+        .arg(stub)
+        .output()?;
+    print!("{}", String::from_utf8_lossy(&output.stdout));
+    print!("{}", String::from_utf8_lossy(&output.stderr));
+    Ok(output)
+}
+
+/// The installed file in `EFI/nixos` whose name ends with `suffix`.
+fn installed_file(esp_mountpoint: &Path, suffix: &str) -> Result<PathBuf> {
+    Ok(fs::read_dir(esp_mountpoint.join("EFI/nixos"))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .find(|path| path.to_string_lossy().ends_with(suffix))
+        .expect("File was not installed"))
+}
+
+#[test]
+fn refresh_hashes_of_replaced_kernel() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), vec![generation_link])?;
+    assert!(output0.status.success());
+
+    let stub = esp_mountpoint
+        .path()
+        .join("EFI/Linux/nixos-generation-1.efi");
+    let installed_stub = fs::read(&stub)?;
+    let kernel = installed_file(esp_mountpoint.path(), "-bzImage.efi")?;
+
+    // A kernel that was modified on the ESP is not signed off without knowing its source.
+    let mut modified_kernel = fs::read(&kernel)?;
+    modified_kernel.extend_from_slice(b"modified");
+    fs::write(&kernel, &modified_kernel)?;
+    let output1 = lanzaboote_refresh_hashes(esp_mountpoint.path(), &stub, Vec::<&str>::new())?;
+    assert!(!output1.status.success());
+    assert!(String::from_utf8(output1.stderr)?.contains("its source is unknown"));
+    assert_eq!(fs::read(&stub)?, installed_stub);
+
+    // Any PE binary serves as the new kernel.
+    let new_kernel = format!(
+        "{}/lib/systemd/boot/efi/systemd-bootx64.efi",
+        common::systemd_location_from_env()?
+    );
+    let output2 = lanzaboote_refresh_hashes(
+        esp_mountpoint.path(),
+        &stub,
+        ["--kernel", new_kernel.as_str()],
+    )?;
+    assert!(output2.status.success());
+
+    let installed_kernel = fs::read(&kernel)?;
+    assert_ne!(installed_kernel, modified_kernel);
+    let refreshed_stub = fs::read(&stub)?;
+    assert_eq!(
+        common::pe_section(&refreshed_stub, ".kernelh"),
+        Some(Sha256::digest(&installed_kernel).as_slice())
+    );
+    for section in [".osrel", ".cmdline", ".kernelp", ".initrdp", ".initrdh"] {
+        assert_eq!(
+            common::pe_section(&refreshed_stub, section),
+            common::pe_section(&installed_stub, section)
+        );
+    }
+
+    let output3 = common::lanzaboote_verify(esp_mountpoint.path())?;
+    assert!(output3.status.success());
+
+    // Both the new kernel and the stub are signed.
+    for path in [&kernel, &stub] {
+        let output = StdCommand::new("sbverify")
+            .arg("--cert")
+            .arg(PUBLIC_KEY)
+            .arg(path)
+            .output()?;
+        assert!(output.status.success(), "{path:?} is not signed");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn sign_replaced_initrd_again() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        ["--sign-initrd"],
+    )?;
+    assert!(output0.status.success());
+
+    let stub = esp_mountpoint
+        .path()
+        .join("EFI/Linux/nixos-generation-1.efi");
+    let signature = installed_file(esp_mountpoint.path(), "-initrd.efi.sig")?;
+    let installed_signature = fs::read(&signature)?;
+
+    let new_initrd = tmpdir.path().join("new-initrd");
+    fs::write(&new_initrd, b"new initrd")?;
+    let output1 = lanzaboote_refresh_hashes(
+        esp_mountpoint.path(),
+        &stub,
+        [OsStr::new("--initrd"), new_initrd.as_os_str()],
+    )?;
+    assert!(output1.status.success());
+
+    assert_eq!(fs::read(signature.with_extension(""))?, b"new initrd");
+    assert_ne!(fs::read(&signature)?, installed_signature);
+
+    // The stub and the detached signature both cover the new initrd.
+    let output2 = Command::cargo_bin("lzbt")?
+        .arg("verify")
+        .arg("--public-key")
+        .arg(PUBLIC_KEY)
+        .arg(esp_mountpoint.path())
+        .output()?;
+    print!("{}", String::from_utf8_lossy(&output2.stdout));
+    assert!(output2.status.success());
+
+    Ok(())
+}
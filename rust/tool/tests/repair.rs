@@ -0,0 +1,39 @@
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn rewrite_corrupted_files() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), vec![&generation_link])?;
+    assert!(output0.status.success());
+
+    let stub = esp_mountpoint
+        .path()
+        .join("EFI/Linux/nixos-generation-1.efi");
+    let installed_stub = fs::read(&stub)?;
+    fs::write(&stub, b"corrupted")?;
+
+    let output1 = common::lanzaboote_install(0, esp_mountpoint.path(), vec![&generation_link])?;
+    assert!(output1.status.success());
+
+    let repaired_stub = fs::read(&stub)?;
+    assert_ne!(repaired_stub, b"corrupted");
+    // The signature may differ between runs, but the sections embedded by lzbt do not.
+    for section in [".osrel", ".cmdline", ".kernelp", ".initrdp"] {
+        assert_eq!(
+            common::pe_section(&repaired_stub, section),
+            common::pe_section(&installed_stub, section)
+        );
+    }
+
+    Ok(())
+}
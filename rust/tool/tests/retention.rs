@@ -0,0 +1,94 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use assert_cmd::Command;
+use filetime::FileTime;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn keep_recent_generations_beyond_limit() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2, 3]
+        .into_iter()
+        .map(|version| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), version)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+    // The first generation was created long ago.
+    filetime::set_file_mtime(&generation_links[0], FileTime::zero())?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        1,
+        esp_mountpoint.path(),
+        generation_links,
+        ["--keep-since", "1d"],
+    )?;
+    assert!(output0.status.success());
+
+    let stub = |version: u64| {
+        esp_mountpoint
+            .path()
+            .join(format!("EFI/Linux/nixos-generation-{version}.efi"))
+    };
+    assert!(!stub(1).exists());
+    assert!(stub(2).exists());
+    assert!(stub(3).exists());
+
+    Ok(())
+}
+
+#[test]
+fn dry_run_lists_removals() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2]
+        .into_iter()
+        .map(|version| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), version)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), generation_links.clone())?;
+    assert!(output0.status.success());
+
+    let test_systemd = common::systemd_location_from_env()?;
+    let efivars = tempdir()?;
+    let output1 = Command::cargo_bin("lzbt")?
+        .env(
+            "LANZABOOTE_STUB",
+            format!("{test_systemd}/lib/systemd/boot/efi/linuxx64.efi.stub"),
+        )
+        .arg("install")
+        .arg("--efivars")
+        .arg(efivars.path())
+        .arg("--public-key")
+        .arg("tests/fixtures/uefi-keys/db.pem")
+        .arg("--private-key")
+        .arg("tests/fixtures/uefi-keys/db.key")
+        .arg("--keep-last")
+        .arg("1")
+        .arg("--dry-run")
+        .arg(esp_mountpoint.path())
+        .args(generation_links)
+        .output()?;
+    print!("{}", String::from_utf8_lossy(&output1.stderr));
+    assert!(output1.status.success());
+    let stdout = String::from_utf8(output1.stdout)?;
+
+    let stub1 = esp_mountpoint
+        .path()
+        .join("EFI/Linux/nixos-generation-1.efi");
+    assert!(stdout.contains(&format!("Would remove the boot entry {}", stub1.display())));
+    assert!(!stdout.contains("nixos-generation-2.efi"));
+    // Nothing is removed.
+    assert!(stub1.exists());
+
+    Ok(())
+}
@@ -0,0 +1,135 @@
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn merge_sbat_entries_into_stub() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        [
+            "--sbat",
+            "--sbat-entry",
+            "linux.vendor,2,Vendor,linux,1.0,https://example.com",
+        ],
+    )?;
+    assert!(output0.status.success());
+
+    let stub_data = fs::read(
+        esp_mountpoint
+            .path()
+            .join("EFI/Linux/nixos-generation-1.efi"),
+    )?;
+    let sbat = common::pe_section(&stub_data, ".sbat").expect("Missing .sbat");
+    let sbat = String::from_utf8_lossy(sbat);
+    let lines: Vec<&str> = sbat.lines().collect();
+
+    assert!(lines[0].starts_with("sbat,1,"));
+    // The entries of the stub itself are kept.
+    assert!(lines.iter().any(|line| line.starts_with("systemd,")));
+    assert!(lines.iter().any(|line| line.starts_with("lanzaboote,1,")));
+    assert!(lines.iter().any(|line| line.starts_with("linux.nixos,1,")));
+    assert!(lines.contains(&"linux.vendor,2,Vendor,linux,1.0,https://example.com"));
+
+    Ok(())
+}
+
+#[test]
+fn relocate_sbat_section_that_outgrows_the_stub() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    // Together these entries are larger than the raw data of the .sbat section of the stub.
+    let entries: Vec<String> = (0..64)
+        .map(|i| format!("linux.vendor{i},1,Vendor,linux,1.0,https://example.com/{i}"))
+        .collect();
+    let mut args = vec!["--sbat".to_string()];
+    for entry in &entries {
+        args.push("--sbat-entry".to_string());
+        args.push(entry.clone());
+    }
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        args,
+    )?;
+    assert!(output0.status.success());
+
+    let stub_data = fs::read(
+        esp_mountpoint
+            .path()
+            .join("EFI/Linux/nixos-generation-1.efi"),
+    )?;
+    let sbat = common::pe_section(&stub_data, ".sbat").expect("Missing .sbat");
+    let sbat = String::from_utf8_lossy(sbat);
+    let lines: Vec<&str> = sbat.lines().collect();
+
+    assert!(lines[0].starts_with("sbat,1,"));
+    assert!(lines.iter().any(|line| line.starts_with("systemd,")));
+    for entry in &entries {
+        assert!(lines.contains(&entry.as_str()));
+    }
+
+    // The relocated section lies within the image and does not overlap any other section.
+    let pe = goblin::pe::PE::parse(&stub_data)?;
+    let size_of_image = pe
+        .header
+        .optional_header
+        .expect("Missing optional header")
+        .windows_fields
+        .size_of_image;
+    let mut ranges: Vec<(u32, u32)> = pe
+        .sections
+        .iter()
+        .map(|section| {
+            (
+                section.virtual_address,
+                section.virtual_address + section.virtual_size,
+            )
+        })
+        .collect();
+    ranges.sort();
+    for pair in ranges.windows(2) {
+        assert!(pair[0].1 <= pair[1].0, "Sections overlap: {pair:x?}");
+    }
+    assert!(ranges.last().map_or(false, |last| last.1 <= size_of_image));
+
+    let output1 = common::lanzaboote_verify(esp_mountpoint.path())?;
+    assert!(output1.status.success());
+
+    Ok(())
+}
+
+#[test]
+fn reject_malformed_sbat_entry() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        ["--sbat", "--sbat-entry", "linux.vendor,two,Vendor"],
+    )?;
+    assert!(!output0.status.success());
+
+    Ok(())
+}
@@ -0,0 +1,57 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn embed_sections_of_section_provider() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let provider = tmpdir.path().join("provider");
+    fs::write(
+        &provider,
+        "#!/bin/sh\ncat > \"$1/.ctx\"\nprintf provided > \"$1/.prov\"\n",
+    )?;
+    fs::set_permissions(&provider, fs::Permissions::from_mode(0o755))?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        [OsStr::new("--section-provider"), provider.as_os_str()],
+    )?;
+    assert!(output0.status.success());
+
+    let stub_data = fs::read(
+        esp_mountpoint
+            .path()
+            .join("EFI/Linux/nixos-generation-1.efi"),
+    )?;
+    let cmdline = common::pe_section(&stub_data, ".cmdline").expect("Stub has no .cmdline");
+    assert!(common::pe_section(&stub_data, ".osrel").is_some());
+    assert_eq!(
+        common::pe_section(&stub_data, ".prov"),
+        Some(&b"provided"[..])
+    );
+
+    let context: serde_json::Value = serde_json::from_slice(
+        common::pe_section(&stub_data, ".ctx").expect("Stub has no .ctx section"),
+    )?;
+    let kernel_cmdline: Vec<&str> = context["kernel_cmdline"]
+        .as_array()
+        .expect("kernel_cmdline is an array")
+        .iter()
+        .map(|param| param.as_str().expect("kernel parameters are strings"))
+        .collect();
+    assert_eq!(kernel_cmdline.join(" ").as_bytes(), cmdline);
+
+    Ok(())
+}
@@ -0,0 +1,197 @@
+use std::path::Path;
+use std::process::{Command as StdCommand, Output};
+
+use anyhow::Result;
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+mod common;
+
+use common::EFI_GLOBAL_VARIABLE;
+
+/// Enable Secure Boot and enroll the certificates (in DER encoding) in db.
+fn enable_secure_boot(efivars: &Path, db: &[&[u8]]) -> Result<()> {
+    common::write_efi_variable(efivars, "SecureBoot", EFI_GLOBAL_VARIABLE, &[1])?;
+    common::write_efi_variable(efivars, "SetupMode", EFI_GLOBAL_VARIABLE, &[0])?;
+    common::write_db(efivars, db)
+}
+
+#[test]
+fn refuse_install_if_signing_key_is_not_enrolled() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let efivars = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    // Secure Boot is enabled and db only has a vendor certificate.
+    enable_secure_boot(efivars.path(), &[b"not the certificate of the test keys"])?;
+
+    let efivars_args = [
+        String::from("--efivars"),
+        efivars.path().display().to_string(),
+    ];
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![&generation_link],
+        &efivars_args,
+    )?;
+    assert!(!output0.status.success());
+    assert!(String::from_utf8(output0.stderr)?.contains("is not enrolled in db"));
+    assert!(!esp_mountpoint
+        .path()
+        .join("EFI/Linux/nixos-generation-1.efi")
+        .exists());
+
+    let output1 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![&generation_link],
+        efivars_args.iter().map(String::as_str).chain(["--force"]),
+    )?;
+    assert!(output1.status.success());
+    assert!(esp_mountpoint
+        .path()
+        .join("EFI/Linux/nixos-generation-1.efi")
+        .exists());
+
+    Ok(())
+}
+
+#[test]
+fn install_if_signing_key_is_enrolled() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let efivars = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let certificate = common::certificate_der(Path::new("tests/fixtures/uefi-keys/db.pem"))?;
+    enable_secure_boot(efivars.path(), &[b"vendor certificate", &certificate])?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![&generation_link],
+        [Path::new("--efivars"), efivars.path()],
+    )?;
+    assert!(output0.status.success());
+
+    Ok(())
+}
+
+/// Run openssl and make sure that it succeeded.
+fn openssl(args: &[&str], dir: &Path) -> Result<()> {
+    let status = StdCommand::new("openssl")
+        .args(args)
+        .current_dir(dir)
+        .status()?;
+    assert!(status.success());
+    Ok(())
+}
+
+/// Install with the key pair `db.key` and `db.pem` in `keys`.
+fn lanzaboote_install_with_key(
+    esp_mountpoint: &Path,
+    generation_link: &Path,
+    keys: &Path,
+    efivars: &Path,
+) -> Result<Output> {
+    let test_systemd = common::systemd_location_from_env()?;
+    let output = Command::cargo_bin("lzbt")?
+        .env(
+            "LANZABOOTE_STUB",
+            format!("{test_systemd}/lib/systemd/boot/efi/linuxx64.efi.stub"),
+        )
+        .arg("install")
+        .arg("--efivars")
+        .arg(efivars)
+        .arg("--public-key")
+        .arg(keys.join("db.pem"))
+        .arg("--private-key")
+        .arg(keys.join("db.key"))
+        .arg(esp_mountpoint)
+        .arg(generation_link)
+        .output()?;
+    print!("{}", String::from_utf8_lossy(&output.stdout));
+    print!("{}", String::from_utf8_lossy(&output.stderr));
+    Ok(output)
+}
+
+#[test]
+fn install_if_issuer_of_signing_key_is_enrolled() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let efivars = tempdir()?;
+    let keys = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    // A certificate authority that is enrolled in db instead of the signing certificate itself.
+    let new_key = ["-newkey", "rsa:2048", "-nodes"];
+    openssl(
+        &[
+            &["req", "-x509", "-days", "1", "-subj", "/CN=CA/"][..],
+            &new_key,
+            &["-keyout", "ca.key", "-out", "ca.pem"],
+        ]
+        .concat(),
+        keys.path(),
+    )?;
+    openssl(
+        &[
+            &["req", "-subj", "/CN=db/"][..],
+            &new_key,
+            &["-keyout", "db.key", "-out", "db.csr"],
+        ]
+        .concat(),
+        keys.path(),
+    )?;
+    openssl(
+        &[
+            "x509",
+            "-req",
+            "-in",
+            "db.csr",
+            "-CA",
+            "ca.pem",
+            "-CAkey",
+            "ca.key",
+            "-CAcreateserial",
+            "-days",
+            "1",
+            "-out",
+            "db.pem",
+        ],
+        keys.path(),
+    )?;
+
+    let ca = common::certificate_der(&keys.path().join("ca.pem"))?;
+    enable_secure_boot(efivars.path(), &[&ca])?;
+    let output0 = lanzaboote_install_with_key(
+        esp_mountpoint.path(),
+        &generation_link,
+        keys.path(),
+        efivars.path(),
+    )?;
+    assert!(output0.status.success());
+
+    // A certificate authority that did not issue the signing certificate is not enough.
+    let other = common::certificate_der(Path::new("tests/fixtures/uefi-keys/db.pem"))?;
+    enable_secure_boot(efivars.path(), &[&other])?;
+    let output1 = lanzaboote_install_with_key(
+        esp_mountpoint.path(),
+        &generation_link,
+        keys.path(),
+        efivars.path(),
+    )?;
+    assert!(!output1.status.success());
+    assert!(String::from_utf8(output1.stderr)?.contains("is not enrolled in db"));
+
+    Ok(())
+}
@@ -0,0 +1,93 @@
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use anyhow::Result;
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+mod common;
+
+use common::{EFI_GLOBAL_VARIABLE, IMAGE_SECURITY_DATABASE};
+
+fn write_setup_mode(efivars: &Path, setup_mode: u8) -> Result<()> {
+    common::write_efi_variable(efivars, "SetupMode", EFI_GLOBAL_VARIABLE, &[setup_mode])
+}
+
+fn lanzaboote_setup(pki_bundle: &Path, efivars: &Path) -> Result<std::process::Output> {
+    let output = Command::cargo_bin("lzbt")?
+        .arg("setup")
+        .arg("--pki-bundle")
+        .arg(pki_bundle)
+        .arg("--export")
+        .arg("--enroll")
+        .arg("--efivars")
+        .arg(efivars)
+        .output()?;
+    print!("{}", String::from_utf8_lossy(&output.stdout));
+    print!("{}", String::from_utf8_lossy(&output.stderr));
+    Ok(output)
+}
+
+#[test]
+fn create_and_enroll_keys() -> Result<()> {
+    let pki_bundle = tempdir()?;
+    let efivars = tempdir()?;
+    write_setup_mode(efivars.path(), 1)?;
+
+    let output0 = lanzaboote_setup(pki_bundle.path(), efivars.path())?;
+    assert!(output0.status.success());
+
+    let keys = pki_bundle.path().join("keys");
+    for name in ["PK", "KEK", "db"] {
+        let private_key = keys.join(name).join(format!("{name}.key"));
+        assert_eq!(
+            fs::metadata(&private_key)?.permissions().mode() & 0o777,
+            0o600
+        );
+        assert!(keys.join(name).join(format!("{name}.pem")).exists());
+
+        let list = fs::read(keys.join(name).join(format!("{name}.esl")))?;
+        let update = fs::read(keys.join(name).join(format!("{name}.auth")))?;
+        assert!(update.ends_with(&list));
+
+        let vendor = match name {
+            "db" => IMAGE_SECURITY_DATABASE,
+            _ => EFI_GLOBAL_VARIABLE,
+        };
+        let variable = fs::read(efivars.path().join(format!("{name}-{vendor}")))?;
+        assert_eq!(variable[..4], [0x27, 0x00, 0x00, 0x00]);
+        assert_eq!(variable[4..], update[..]);
+    }
+    assert!(pki_bundle.path().join("GUID").exists());
+    // The minimum security version of the anti-rollback protection is owned by the db key.
+    assert!(efivars
+        .path()
+        .join("LanzabooteMinimumSecurityVersion-bf6e8bf2-0104-4750-bafa-ea73c3a3e37e")
+        .exists());
+
+    // The keys are reused when setup runs again, e.g. on another machine.
+    let certificate = fs::read(keys.join("db/db.pem"))?;
+    let other_efivars = tempdir()?;
+    write_setup_mode(other_efivars.path(), 1)?;
+    let output1 = lanzaboote_setup(pki_bundle.path(), other_efivars.path())?;
+    assert!(output1.status.success());
+    assert!(String::from_utf8(output1.stdout)?.contains("Using the existing Secure Boot keys"));
+    assert_eq!(fs::read(keys.join("db/db.pem"))?, certificate);
+
+    Ok(())
+}
+
+#[test]
+fn refuse_to_enroll_outside_of_setup_mode() -> Result<()> {
+    let pki_bundle = tempdir()?;
+    let efivars = tempdir()?;
+    write_setup_mode(efivars.path(), 0)?;
+
+    let output0 = lanzaboote_setup(pki_bundle.path(), efivars.path())?;
+    assert!(!output0.status.success());
+    assert!(String::from_utf8(output0.stderr)?.contains("not in setup mode"));
+    assert!(!pki_bundle.path().join("keys").exists());
+
+    Ok(())
+}
@@ -0,0 +1,93 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::process::Command as StdCommand;
+use std::{fs, thread};
+
+use anyhow::Result;
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+mod common;
+
+/// Answer every request on the socket with a detached signature made with the test key.
+fn serve_signatures(listener: UnixListener, workdir: &Path) -> Result<()> {
+    for (index, stream) in listener.incoming().enumerate() {
+        let mut stream = BufReader::new(stream?);
+        let mut header = String::new();
+        stream.read_line(&mut header)?;
+        let header: serde_json::Value = serde_json::from_str(&header)?;
+        assert_eq!(header["kind"], "authenticode");
+
+        let mut contents = Vec::new();
+        stream.read_to_end(&mut contents)?;
+        assert_eq!(contents.len() as u64, header["size"].as_u64().unwrap());
+
+        let unsigned = workdir.join(format!("unsigned-{index}.efi"));
+        let signature = workdir.join(format!("signature-{index}"));
+        fs::write(&unsigned, contents)?;
+        let status = StdCommand::new("sbsign")
+            .args(["--key", "tests/fixtures/uefi-keys/db.key"])
+            .args(["--cert", "tests/fixtures/uefi-keys/db.pem"])
+            .arg("--detached")
+            .arg("--output")
+            .arg(&signature)
+            .arg(&unsigned)
+            .status()?;
+        assert!(status.success());
+        stream.get_mut().write_all(&fs::read(&signature)?)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn sign_with_sign_server() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let server = tempdir()?;
+    let socket = server.path().join("sign.sock");
+    let listener = UnixListener::bind(&socket)?;
+    let workdir = server.path().to_path_buf();
+    thread::spawn(move || serve_signatures(listener, &workdir).expect("Signing server failed"));
+
+    let test_systemd = common::systemd_location_from_env()?;
+    let efivars = tempdir()?;
+    let output = Command::cargo_bin("lzbt")?
+        .env(
+            "LANZABOOTE_STUB",
+            format!("{test_systemd}/lib/systemd/boot/efi/linuxx64.efi.stub"),
+        )
+        .arg("install")
+        .arg("--efivars")
+        .arg(efivars.path())
+        .arg("--public-key")
+        .arg("tests/fixtures/uefi-keys/db.pem")
+        .arg("--sign-server")
+        .arg(&socket)
+        .arg("--signing-retries")
+        .arg("1")
+        .arg(esp_mountpoint.path())
+        .arg(generation_link)
+        .output()?;
+    print!("{}", String::from_utf8_lossy(&output.stdout));
+    print!("{}", String::from_utf8_lossy(&output.stderr));
+    assert!(output.status.success());
+
+    for path in [
+        "EFI/Linux/nixos-generation-1.efi",
+        "EFI/systemd/systemd-bootx64.efi",
+    ] {
+        let output = StdCommand::new("sbverify")
+            .arg("--cert")
+            .arg("tests/fixtures/uefi-keys/db.pem")
+            .arg(esp_mountpoint.path().join(path))
+            .output()?;
+        assert!(output.status.success(), "{path} is not signed");
+    }
+
+    Ok(())
+}
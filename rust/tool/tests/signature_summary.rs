@@ -0,0 +1,91 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command as StdCommand;
+
+use anyhow::Result;
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+mod common;
+
+/// Run `command` and fail if it does not succeed.
+fn run(command: &mut StdCommand) -> Result<()> {
+    let status = command.status()?;
+    assert!(status.success(), "{command:?} failed");
+    Ok(())
+}
+
+#[test]
+fn count_binaries_by_signature() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), vec![generation_link])?;
+    assert!(output0.status.success());
+
+    // Everything lanzaboote installed is signed with our key.
+    let ours = walkdir::WalkDir::new(esp_mountpoint.path())
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|entry| {
+            entry
+                .path()
+                .extension()
+                .map_or(false, |extension| extension.eq_ignore_ascii_case("efi"))
+        })
+        .count();
+    assert!(ours > 0);
+
+    let linux = esp_mountpoint.path().join("EFI/Linux");
+    let unsigned_stub = Path::new(&common::systemd_location_from_env()?)
+        .join("lib/systemd/boot/efi/linuxx64.efi.stub");
+    fs::copy(&unsigned_stub, linux.join("unsigned.efi"))?;
+    fs::copy(&unsigned_stub, linux.join("UNSIGNED.EFI"))?;
+
+    let other_key = tmpdir.path().join("other.key");
+    let other_cert = tmpdir.path().join("other.pem");
+    run(StdCommand::new("openssl")
+        .args([
+            "req", "-x509", "-newkey", "rsa:2048", "-nodes", "-days", "1",
+        ])
+        .args(["-subj", "/CN=Other/"])
+        .arg("-keyout")
+        .arg(&other_key)
+        .arg("-out")
+        .arg(&other_cert))?;
+    run(StdCommand::new("sbsign")
+        .arg("--key")
+        .arg(&other_key)
+        .arg("--cert")
+        .arg(&other_cert)
+        .arg("--output")
+        .arg(linux.join("other.efi"))
+        .arg(&unsigned_stub))?;
+    fs::write(linux.join("broken.efi"), b"not a PE binary")?;
+
+    let efivars = tempdir()?;
+    let output1 = Command::cargo_bin("lzbt")?
+        .arg("status")
+        .arg("--efivars")
+        .arg(efivars.path())
+        .arg("--system")
+        .arg(tmpdir.path())
+        .arg("--public-key")
+        .arg("tests/fixtures/uefi-keys/db.pem")
+        .arg(esp_mountpoint.path())
+        .output()?;
+    assert!(output1.status.success());
+    let stdout = String::from_utf8(output1.stdout)?;
+    assert!(stdout.contains(&format!("Signed with our key: {ours}\n")));
+    assert!(stdout.contains("Signed with another key: 1\n"));
+    assert!(stdout.contains("Unsigned: 2\n"));
+    assert!(stdout.contains(&format!(
+        "Invalid: {}: Failed to parse",
+        linux.join("broken.efi").display()
+    )));
+
+    Ok(())
+}
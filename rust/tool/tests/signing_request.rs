@@ -0,0 +1,160 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Output;
+
+use anyhow::Result;
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+mod common;
+
+/// Install a generation and export its stub for signing to `unsigned.efi` in `dir`, with the
+/// signing request stored next to it as `unsigned.json`.
+fn export_stub(dir: &Path) -> Result<(PathBuf, serde_json::Value)> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), vec![generation_link])?;
+    assert!(output0.status.success());
+
+    let stub = esp_mountpoint
+        .path()
+        .join("EFI/Linux/nixos-generation-1.efi");
+    let unsigned = dir.join("unsigned.efi");
+    let output1 = lzbt([
+        "export-for-signing".as_ref(),
+        stub.as_os_str(),
+        unsigned.as_os_str(),
+    ])?;
+    assert!(output1.status.success());
+    fs::write(dir.join("unsigned.json"), &output1.stdout)?;
+    assert!(!sbverify(&unsigned)?.status.success());
+
+    Ok((unsigned, serde_json::from_slice(&output1.stdout)?))
+}
+
+/// Sign `stub` like the signing host does and return the detached signature.
+fn sign_detached(stub: &Path) -> Result<PathBuf> {
+    let signature = stub.with_extension("sig");
+    let output = std::process::Command::new("sbsign")
+        .args(["--key", "tests/fixtures/uefi-keys/db.key"])
+        .args(["--cert", "tests/fixtures/uefi-keys/db.pem"])
+        .arg("--detached")
+        .arg(stub)
+        .arg("--output")
+        .arg(&signature)
+        .output()?;
+    assert!(output.status.success());
+    Ok(signature)
+}
+
+#[test]
+fn sign_exported_stub_on_separate_host() -> Result<()> {
+    let local = tempdir()?;
+    let (unsigned, request) = export_stub(local.path())?;
+    // The request does not refer to the local directory the stub was exported to.
+    assert_eq!(request["stub"], "unsigned.efi");
+
+    // The stub and the request travel to the signing host, which only sends back the signature.
+    let signing_host = tempdir()?;
+    let remote = signing_host.path().join("request");
+    fs::create_dir(&remote)?;
+    fs::copy(&unsigned, remote.join("unsigned.efi"))?;
+    fs::copy(
+        local.path().join("unsigned.json"),
+        remote.join("unsigned.json"),
+    )?;
+    let signature = sign_detached(&remote.join("unsigned.efi"))?;
+
+    // The exported stub may have been moved in the meantime, it is found next to the request.
+    let moved = tempdir()?;
+    let moved_request = moved.path().join("unsigned.json");
+    fs::rename(local.path().join("unsigned.json"), &moved_request)?;
+    fs::rename(&unsigned, moved.path().join("unsigned.efi"))?;
+
+    let output = lzbt([
+        "import-signed".as_ref(),
+        moved_request.as_os_str(),
+        signature.as_os_str(),
+    ])?;
+    assert!(output.status.success());
+    assert!(sbverify(&moved.path().join("unsigned.efi"))?
+        .status
+        .success());
+
+    Ok(())
+}
+
+#[test]
+fn refuse_signature_of_another_stub() -> Result<()> {
+    let local = tempdir()?;
+    let (unsigned, _) = export_stub(local.path())?;
+
+    // The signing host signed something else than what was requested.
+    let signing_host = tempdir()?;
+    let other = signing_host.path().join("other.efi");
+    fs::copy(
+        Path::new(&common::systemd_location_from_env()?)
+            .join("lib/systemd/boot/efi/linuxx64.efi.stub"),
+        &other,
+    )?;
+    let signature = sign_detached(&other)?;
+
+    let output = lzbt([
+        "import-signed".as_ref(),
+        local.path().join("unsigned.json").as_os_str(),
+        signature.as_os_str(),
+    ])?;
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)?.contains("does not sign the digest"));
+    assert!(!sbverify(&unsigned)?.status.success());
+
+    Ok(())
+}
+
+#[test]
+fn refuse_stub_changed_after_export() -> Result<()> {
+    let local = tempdir()?;
+    let (unsigned, _) = export_stub(local.path())?;
+
+    let signing_host = tempdir()?;
+    let remote = signing_host.path().join("unsigned.efi");
+    fs::copy(&unsigned, &remote)?;
+    let signature = sign_detached(&remote)?;
+
+    // Replace the exported stub with another one before the signature is imported.
+    fs::copy(
+        Path::new(&common::systemd_location_from_env()?)
+            .join("lib/systemd/boot/efi/linuxx64.efi.stub"),
+        &unsigned,
+    )?;
+
+    let output = lzbt([
+        "import-signed".as_ref(),
+        local.path().join("unsigned.json").as_os_str(),
+        signature.as_os_str(),
+    ])?;
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(stderr.contains("changed since it was exported for signing"));
+    assert!(!sbverify(&unsigned)?.status.success());
+
+    Ok(())
+}
+
+fn lzbt<'a>(args: impl IntoIterator<Item = &'a std::ffi::OsStr>) -> Result<Output> {
+    let output = Command::cargo_bin("lzbt")?.args(args).output()?;
+    print!("{}", String::from_utf8_lossy(&output.stdout));
+    print!("{}", String::from_utf8_lossy(&output.stderr));
+    Ok(output)
+}
+
+fn sbverify(stub: &Path) -> Result<Output> {
+    Ok(std::process::Command::new("sbverify")
+        .args(["--cert", "tests/fixtures/uefi-keys/db.pem"])
+        .arg(stub)
+        .output()?)
+}
@@ -0,0 +1,82 @@
+use std::env;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+mod common;
+
+/// Write an sbsign wrapper that leaves its output unsigned the first time it runs, like a glitching
+/// hardware token.
+fn write_flaky_sbsign(directory: &Path) -> Result<()> {
+    let real_sbsign = env::var_os("PATH")
+        .iter()
+        .flat_map(env::split_paths)
+        .map(|directory| directory.join("sbsign"))
+        .find(|path| path.exists())
+        .context("Failed to find sbsign")?;
+    let glitched = directory.join("glitched");
+
+    let script = format!(
+        r#"#!/bin/sh
+{real_sbsign} "$@" || exit $?
+[ -e {glitched} ] && exit 0
+touch {glitched}
+while [ $# -gt 0 ]; do
+  case "$1" in
+    --key|--cert) shift 2 ;;
+    --output) output="$2"; shift 2 ;;
+    *) input="$1"; shift ;;
+  esac
+done
+cp "$input" "$output"
+"#,
+        real_sbsign = real_sbsign.display(),
+        glitched = glitched.display(),
+    );
+    let path = directory.join("sbsign");
+    fs::write(&path, script)?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+    Ok(())
+}
+
+#[test]
+fn sign_again_if_signature_does_not_verify() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let bin = tmpdir.path().join("bin");
+    fs::create_dir(&bin)?;
+    write_flaky_sbsign(&bin)?;
+    let path: Vec<PathBuf> = [bin]
+        .into_iter()
+        .chain(env::var_os("PATH").iter().flat_map(env::split_paths))
+        .collect();
+
+    let output0 = common::lanzaboote_install_with_env(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        ["--signing-retries", "1"],
+        [("PATH", env::join_paths(path)?)],
+    )?;
+    assert!(output0.status.success());
+    let stdout = String::from_utf8(output0.stdout)?;
+    assert!(stdout.contains("does not verify (attempt 1 of 2), signing again"));
+
+    let output1 = Command::cargo_bin("lzbt")?
+        .arg("verify")
+        .arg("--public-key")
+        .arg("tests/fixtures/uefi-keys/db.pem")
+        .arg(esp_mountpoint.path())
+        .output()?;
+    assert!(output1.status.success());
+
+    Ok(())
+}
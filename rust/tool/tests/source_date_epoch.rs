@@ -0,0 +1,47 @@
+use std::fs;
+
+use anyhow::{Context, Result};
+use filetime::FileTime;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn derive_all_timestamps_from_source_date_epoch() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install_with_env(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        Vec::<&str>::new(),
+        [("SOURCE_DATE_EPOCH", "1672531201")],
+    )?;
+    assert!(output0.status.success());
+
+    let stub_path = esp_mountpoint
+        .path()
+        .join("EFI/Linux/nixos-generation-1.efi");
+    let stub_data = fs::read(&stub_path)?;
+
+    let pe_offset = u32::from_le_bytes(stub_data[0x3c..0x40].try_into()?) as usize;
+    let timestamp = u32::from_le_bytes(stub_data[pe_offset + 8..pe_offset + 12].try_into()?);
+    assert_eq!(timestamp, 1672531201);
+
+    let os_release = std::str::from_utf8(
+        common::pe_section(&stub_data, ".osrel").context("Failed to read .osrel PE section.")?,
+    )?;
+    assert!(os_release.contains("Built on 2023-01-01"));
+
+    // The modification time is rounded down to the 2-second granularity of FAT.
+    assert_eq!(
+        FileTime::from_last_modification_time(&fs::metadata(&stub_path)?),
+        FileTime::from_unix_time(1672531200, 0)
+    );
+
+    Ok(())
+}
@@ -0,0 +1,62 @@
+use std::fs;
+
+use anyhow::Result;
+use serde_json::Value;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn install_specialisations_as_separate_boot_entries() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link_with_specialisations(
+        tmpdir.path(),
+        profiles.path(),
+        1,
+        &["gaming", "work"],
+    )
+    .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), vec![generation_link])?;
+    assert!(output0.status.success());
+
+    let linux = esp_mountpoint.path().join("EFI/Linux");
+    let mut images: Vec<String> = fs::read_dir(&linux)?
+        .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+        .collect::<Result<_>>()?;
+    images.sort();
+    assert_eq!(
+        images,
+        [
+            "nixos-generation-1-specialisation-gaming.efi",
+            "nixos-generation-1-specialisation-work.efi",
+            "nixos-generation-1.efi",
+        ]
+    );
+
+    let cmdline = |image: &str| -> Result<String> {
+        let image_data = fs::read(linux.join(image))?;
+        let cmdline = common::pe_section(&image_data, ".cmdline").expect("Missing .cmdline");
+        Ok(String::from_utf8_lossy(cmdline).into_owned())
+    };
+    assert!(!cmdline("nixos-generation-1.efi")?.contains("specialisation="));
+    assert!(
+        cmdline("nixos-generation-1-specialisation-gaming.efi")?.contains("specialisation=gaming")
+    );
+    assert!(cmdline("nixos-generation-1-specialisation-work.efi")?.contains("specialisation=work"));
+
+    let manifest: Value = serde_json::from_slice(&fs::read(
+        esp_mountpoint.path().join("EFI/nixos/manifest.json"),
+    )?)?;
+    for name in ["gaming", "work"] {
+        assert_eq!(
+            manifest["specialisations"]
+                [format!("EFI/Linux/nixos-generation-1-specialisation-{name}.efi")],
+            "EFI/Linux/nixos-generation-1.efi"
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,159 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+mod common;
+
+use common::{EFI_GLOBAL_VARIABLE, LOADER_VARIABLE};
+
+#[test]
+fn report_status_of_esp() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let efivars = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2]
+        .into_iter()
+        .map(|v| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), v)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), generation_links.clone())?;
+    assert!(output0.status.success());
+
+    common::write_efi_variable(efivars.path(), "SecureBoot", EFI_GLOBAL_VARIABLE, &[1])?;
+    common::write_efi_variable(efivars.path(), "SetupMode", EFI_GLOBAL_VARIABLE, &[0])?;
+    let entry: Vec<u8> = "nixos-generation-2.efi\0"
+        .encode_utf16()
+        .flat_map(u16::to_le_bytes)
+        .collect();
+    common::write_efi_variable(
+        efivars.path(),
+        "LoaderEntrySelected",
+        LOADER_VARIABLE,
+        &entry,
+    )?;
+
+    let esp_before = common::snapshot(esp_mountpoint.path())?;
+
+    let output1 = Command::cargo_bin("lzbt")?
+        .arg("status")
+        .arg("--efivars")
+        .arg(efivars.path())
+        .arg("--system")
+        .arg(common::toplevel(&generation_links[1])?)
+        .arg("--tpm")
+        .arg(tmpdir.path().join("tpm0"))
+        .arg(esp_mountpoint.path())
+        .output()?;
+    assert!(output1.status.success());
+
+    let stdout = String::from_utf8(output1.stdout)?;
+    print!("{stdout}");
+    assert!(stdout.contains("Secure Boot: enabled"));
+    assert!(stdout.contains("Installed generations: 2"));
+    assert!(stdout.contains("Current generation: 2"));
+    assert!(stdout.contains("systemd-boot: up to date"));
+    assert!(stdout.contains("TPM: not present"));
+
+    // The status command must not modify the ESP.
+    assert_eq!(esp_before, common::snapshot(esp_mountpoint.path())?);
+
+    // The installed systemd-boot is the one for the architecture of the generations.
+    let systemd_boot = esp_mountpoint
+        .path()
+        .join("EFI/systemd")
+        .join(common::systemd_boot_file_name(&generation_links[1])?);
+    fs::write(&systemd_boot, b"#### LoaderInfo: systemd-boot 1 ####")?;
+    let output2 = Command::cargo_bin("lzbt")?
+        .arg("status")
+        .arg("--efivars")
+        .arg(efivars.path())
+        .arg("--system")
+        .arg(common::toplevel(&generation_links[1])?)
+        .arg("--tpm")
+        .arg(tmpdir.path().join("tpm0"))
+        .arg(esp_mountpoint.path())
+        .output()?;
+    assert!(output2.status.success());
+    let stdout = String::from_utf8(output2.stdout)?;
+    assert!(stdout.contains("systemd-boot: out of date (installed systemd-boot 1, current"));
+
+    Ok(())
+}
+
+#[test]
+fn report_installed_generations_as_json() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let efivars = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2]
+        .into_iter()
+        .map(|v| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), v)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), generation_links.clone())?;
+    assert!(output0.status.success());
+
+    let lanzaboote_status = || -> Result<serde_json::Value> {
+        let output = Command::cargo_bin("lzbt")?
+            .arg("status")
+            .arg("--json")
+            .arg("--efivars")
+            .arg(efivars.path())
+            .arg("--system")
+            .arg(common::toplevel(&generation_links[1])?)
+            .arg("--tpm")
+            .arg(tmpdir.path().join("tpm0"))
+            .arg("--public-key")
+            .arg("tests/fixtures/uefi-keys/db.pem")
+            .arg(esp_mountpoint.path())
+            .output()?;
+        assert!(output.status.success());
+        Ok(serde_json::from_slice(&output.stdout)?)
+    };
+
+    let status = lanzaboote_status()?;
+    let generations = status["generations"].as_array().context("No generations")?;
+    assert_eq!(generations.len(), 2);
+    for (generation, version) in generations.iter().zip([1, 2]) {
+        assert_eq!(generation["version"], version);
+        assert_eq!(generation["signature"], "ours");
+        assert!(generation["cmdline"]
+            .as_str()
+            .context("No cmdline")?
+            .contains("init="));
+
+        let files = generation["files"].as_array().context("No files")?;
+        let stub = Path::new(files[0]["path"].as_str().context("No stub path")?);
+        assert_eq!(files[0]["size"], fs::metadata(stub)?.len());
+    }
+    // Without a default entry systemd-boot boots the newest generation.
+    assert_eq!(generations[0]["default"], false);
+    assert_eq!(generations[1]["default"], true);
+
+    let entry: Vec<u8> = "nixos-generation-1.efi\0"
+        .encode_utf16()
+        .flat_map(u16::to_le_bytes)
+        .collect();
+    common::write_efi_variable(
+        efivars.path(),
+        "LoaderEntryDefault",
+        LOADER_VARIABLE,
+        &entry,
+    )?;
+    let status = lanzaboote_status()?;
+    assert_eq!(status["generations"][0]["default"], true);
+    assert_eq!(status["generations"][1]["default"], false);
+
+    Ok(())
+}
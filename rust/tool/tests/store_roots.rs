@@ -0,0 +1,47 @@
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn write_store_paths_of_installed_generations() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let roots = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2, 3]
+        .into_iter()
+        .map(|v| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), v)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+
+    // The mock toplevels, kernels and initrds live in the temporary directory, which stands in for
+    // the Nix store.
+    let roots_file = roots.path().join("roots");
+    let output0 = common::lanzaboote_install_with_env(
+        2,
+        esp_mountpoint.path(),
+        &generation_links,
+        [
+            String::from("--store-roots-file"),
+            roots_file.display().to_string(),
+        ],
+        [("NIX_STORE_DIR", tmpdir.path())],
+    )?;
+    assert!(output0.status.success());
+
+    // Only the two newest generations are installed.
+    let mut expected: Vec<String> = generation_links[1..]
+        .iter()
+        .map(|link| Ok(format!("{}\n", common::toplevel(link)?.display())))
+        .collect::<Result<_>>()?;
+    expected.sort();
+    assert_eq!(fs::read_to_string(&roots_file)?, expected.concat());
+
+    Ok(())
+}
@@ -0,0 +1,57 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use tempfile::tempdir;
+
+mod common;
+
+/// Install `count` generations to a fresh ESP, then again incrementally, and return how often
+/// the incremental installation did each of the operations that grow with the size of the ESP.
+fn count_incremental_operations(count: u64) -> Result<BTreeMap<String, u64>> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links: Vec<PathBuf> = (1..=count)
+        .map(|version| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), version)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), &generation_links)?;
+    assert!(output0.status.success());
+
+    let output1 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        &generation_links,
+        ["--incremental", "--timings"],
+    )?;
+    assert!(output1.status.success());
+    let stdout = String::from_utf8(output1.stdout)?;
+    assert!(stdout.contains(&format!("Generation {count} is unchanged, skipping...")));
+
+    stdout
+        .lines()
+        .filter_map(|line| line.strip_prefix("Count: "))
+        .map(|line| {
+            let (operation, count) = line.rsplit_once(": ").context("Malformed count")?;
+            Ok((operation.to_owned(), count.parse()?))
+        })
+        .collect()
+}
+
+#[test]
+fn incremental_install_reads_the_esp_once() -> Result<()> {
+    for count in [1, 5, 25] {
+        let counts = count_incremental_operations(count)?;
+        assert_eq!(counts["manifest reads"], 1);
+        assert_eq!(counts["ESP scans"], 1);
+        // Every unchanged stub is parsed once to check the files it references.
+        assert_eq!(counts["stub parses"], count);
+        assert_eq!(counts["garbage collections"], 1);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,148 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command as StdCommand, Output};
+
+use anyhow::Result;
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+mod common;
+
+/// Install the generations signed with another key pair than the one of the test fixtures.
+fn lanzaboote_install_with_key(
+    esp_mountpoint: &Path,
+    generation_links: &[PathBuf],
+    keys: &Path,
+) -> Result<Output> {
+    let test_systemd = common::systemd_location_from_env()?;
+    let test_systemd_stub = format!("{test_systemd}/lib/systemd/boot/efi/linuxx64.efi.stub");
+
+    let efivars = tempdir()?;
+    let output = Command::cargo_bin("lzbt")?
+        .env("LANZABOOTE_STUB", test_systemd_stub)
+        .arg("install")
+        .arg("--efivars")
+        .arg(efivars.path())
+        .arg("--public-key")
+        .arg(keys.join("db.pem"))
+        .arg("--private-key")
+        .arg(keys.join("db.key"))
+        .arg("--configuration-limit")
+        .arg("0")
+        .arg(esp_mountpoint)
+        .args(generation_links)
+        .output()?;
+    print!("{}", String::from_utf8_lossy(&output.stdout));
+    print!("{}", String::from_utf8_lossy(&output.stderr));
+    Ok(output)
+}
+
+#[test]
+fn assemble_stubs_concurrently() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let lzbt_tmpdir = tempdir()?;
+    let versions = [1, 2, 3, 4, 5];
+    let generation_links: Vec<PathBuf> = versions
+        .into_iter()
+        .map(|v| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), v)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+
+    // More generations than stubs are assembled at the same time, so they are assembled in
+    // several batches.
+    let test_systemd = common::systemd_location_from_env()?;
+    let efivars = tempdir()?;
+    let output0 = Command::cargo_bin("lzbt")?
+        .env(
+            "LANZABOOTE_STUB",
+            format!("{test_systemd}/lib/systemd/boot/efi/linuxx64.efi.stub"),
+        )
+        .env("TMPDIR", lzbt_tmpdir.path())
+        .arg("install")
+        .arg("--efivars")
+        .arg(efivars.path())
+        .arg("--public-key")
+        .arg("tests/fixtures/uefi-keys/db.pem")
+        .arg("--private-key")
+        .arg("tests/fixtures/uefi-keys/db.key")
+        .arg("--configuration-limit")
+        .arg("0")
+        .arg("--stub-concurrency")
+        .arg("2")
+        .arg(esp_mountpoint.path())
+        .args(&generation_links)
+        .output()?;
+    print!("{}", String::from_utf8_lossy(&output0.stderr));
+    assert!(output0.status.success());
+
+    // Every stub was assembled from the inputs of its own generation, no matter which worker
+    // assembled it.
+    let linux = esp_mountpoint.path().join("EFI/Linux");
+    for version in versions {
+        let stub_data = fs::read(linux.join(format!("nixos-generation-{version}.efi")))?;
+        let os_release = common::pe_section(&stub_data, ".osrel").expect("Missing .osrel");
+        let os_release = String::from_utf8_lossy(os_release);
+        assert!(
+            os_release
+                .lines()
+                .any(|line| line == format!("VERSION_ID={version}")),
+            "The stub of generation {version} has the os-release {os_release}"
+        );
+    }
+
+    // The stubs are signed, reference intact files and are recorded in the manifest.
+    let output1 = common::lanzaboote_verify(esp_mountpoint.path())?;
+    assert!(output1.status.success());
+    let manifest: serde_json::Value = serde_json::from_slice(&fs::read(
+        esp_mountpoint.path().join("EFI/nixos/manifest.json"),
+    )?)?;
+    for version in versions {
+        let stub = format!("EFI/Linux/nixos-generation-{version}.efi");
+        assert!(
+            manifest["files"].get(&stub).is_some(),
+            "{stub} is not recorded"
+        );
+    }
+
+    // The temporary inputs of all batches are removed.
+    assert_eq!(fs::read_dir(lzbt_tmpdir.path())?.count(), 0);
+
+    Ok(())
+}
+
+#[test]
+fn sign_again_after_key_change() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 =
+        common::lanzaboote_install(0, esp_mountpoint.path(), vec![generation_link.clone()])?;
+    assert!(output0.status.success());
+
+    let keys = tempdir()?;
+    let status = StdCommand::new("openssl")
+        .args([
+            "req", "-x509", "-newkey", "rsa:2048", "-nodes", "-days", "1",
+        ])
+        .args(["-subj", "/CN=Rotated db/"])
+        .arg("-keyout")
+        .arg(keys.path().join("db.key"))
+        .arg("-out")
+        .arg(keys.path().join("db.pem"))
+        .status()?;
+    assert!(status.success());
+
+    let output1 =
+        lanzaboote_install_with_key(esp_mountpoint.path(), &[generation_link], keys.path())?;
+    assert!(output1.status.success());
+    assert!(String::from_utf8(output1.stdout)?.contains("The signing certificate changed"));
+
+    Ok(())
+}
@@ -0,0 +1,51 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn read_stub_only_once_per_installation() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2, 3]
+        .into_iter()
+        .map(|v| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), v)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        generation_links,
+        ["--timings"],
+    )?;
+    assert!(output0.status.success());
+
+    let stdout = String::from_utf8(output0.stdout)?;
+    let reads: u64 = stdout
+        .lines()
+        .find_map(|line| line.strip_prefix("Count: lanzaboote stub reads: "))
+        .context("No count of the lanzaboote stub reads")?
+        .parse()?;
+    assert_eq!(reads, 1);
+
+    // All stubs were assembled from the same stub.
+    let test_systemd = common::systemd_location_from_env()?;
+    let stub_source = format!("{test_systemd}/lib/systemd/boot/efi/linuxx64.efi.stub");
+    let text = common::text_section(Path::new(&stub_source))?;
+    for version in [1, 2, 3] {
+        let stub = esp_mountpoint
+            .path()
+            .join(format!("EFI/Linux/nixos-generation-{version}.efi"));
+        assert!(fs::metadata(&stub)?.is_file());
+        assert_eq!(common::text_section(&stub)?, text);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,74 @@
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn write_metadata_sidecar_of_stub() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        ["--write-metadata"],
+    )?;
+    assert!(output0.status.success());
+
+    let stub_path = esp_mountpoint
+        .path()
+        .join("EFI/Linux/nixos-generation-1.efi");
+    let stub = fs::read(&stub_path)?;
+    let metadata: serde_json::Value = serde_json::from_slice(&fs::read(
+        esp_mountpoint
+            .path()
+            .join("EFI/Linux/nixos-generation-1.efi.meta"),
+    )?)?;
+
+    let section = |name: &str| common::pe_section(&stub, name).expect("Section is missing");
+    let text = |name: &str| String::from_utf8_lossy(section(name)).into_owned();
+    let hex = |name: &str| -> String {
+        section(name)
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    };
+
+    assert_eq!(metadata["cmdline"], text(".cmdline"));
+    assert_eq!(metadata["os_release"]["ID"], "lanza");
+    assert!(text(".osrel").contains(&format!(
+        "PRETTY_NAME={}",
+        metadata["os_release"]["PRETTY_NAME"]
+            .as_str()
+            .expect("PRETTY_NAME is missing")
+    )));
+
+    let files = metadata["files"].as_array().expect("Files are missing");
+    assert_eq!(files.len(), 2);
+    for (file, (path_section, hash_section)) in files
+        .iter()
+        .zip([(".kernelp", ".kernelh"), (".initrdp", ".initrdh")])
+    {
+        assert_eq!(file["path"], text(path_section));
+        assert_eq!(file["hash_algorithm"], "sha256");
+        assert_eq!(file["hash"], hex(hash_section));
+        // Sidecars written before the algorithm was configurable only had this field.
+        assert_eq!(file["sha256"], hex(hash_section));
+        assert!(file["hashed_bytes"].is_null());
+    }
+
+    // The sidecar is not mistaken for a stub.
+    let output1 = assert_cmd::Command::cargo_bin("lzbt")?
+        .arg("verify")
+        .arg(esp_mountpoint.path())
+        .output()?;
+    assert!(output1.status.success());
+
+    Ok(())
+}
@@ -0,0 +1,70 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn name_sections_according_to_stub_profile() -> Result<()> {
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let current_esp = tempdir()?;
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        current_esp.path(),
+        vec![&generation_link],
+        ["--stub-profile", "current"],
+    )?;
+    assert!(output0.status.success());
+
+    let namespaced_esp = tempdir()?;
+    let output1 = common::lanzaboote_install_with_args(
+        0,
+        namespaced_esp.path(),
+        vec![&generation_link],
+        ["--stub-profile", "namespaced"],
+    )?;
+    assert!(output1.status.success());
+
+    let stub = |esp: &Path| fs::read(esp.join("EFI/Linux/nixos-generation-1.efi"));
+    let current = stub(current_esp.path())?;
+    let namespaced = stub(namespaced_esp.path())?;
+
+    for (current_name, namespaced_name) in [
+        (".kernelp", ".lzbkrnp"),
+        (".kernelh", ".lzbkrnh"),
+        (".initrdp", ".lzbinrp"),
+        (".initrdh", ".lzbinrh"),
+    ] {
+        assert!(common::pe_section(&current, current_name).is_some());
+        assert!(common::pe_section(&current, namespaced_name).is_none());
+        assert!(common::pe_section(&namespaced, namespaced_name).is_some());
+        assert!(common::pe_section(&namespaced, current_name).is_none());
+        assert_eq!(
+            common::pe_section(&current, current_name),
+            common::pe_section(&namespaced, namespaced_name)
+        );
+    }
+    // The sections that systemd-stub defines keep their names.
+    for name in [".osrel", ".cmdline"] {
+        assert_eq!(
+            common::pe_section(&current, name),
+            common::pe_section(&namespaced, name)
+        );
+    }
+
+    // The verify command recognizes the profile of an installed stub.
+    let output2 = Command::cargo_bin("lzbt")?
+        .arg("verify")
+        .arg(namespaced_esp.path())
+        .output()?;
+    assert!(output2.status.success());
+
+    Ok(())
+}
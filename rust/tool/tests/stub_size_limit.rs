@@ -0,0 +1,53 @@
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn warn_about_oversized_stub() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    // A large splash image pushes the stub over the limit.
+    let splash = tmpdir.path().join("splash.bmp");
+    fs::write(&splash, vec![0x42; 2 * 1024 * 1024])?;
+    let args = |enforce: bool| {
+        let mut args = vec![
+            String::from("--extra-section"),
+            format!(".splash={}", splash.display()),
+            String::from("--stub-size-limit"),
+            (1024 * 1024).to_string(),
+        ];
+        if enforce {
+            args.push(String::from("--enforce-stub-size-limit"));
+        }
+        args
+    };
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![&generation_link],
+        args(false),
+    )?;
+    assert!(output0.status.success());
+    let stdout = String::from_utf8(output0.stdout)?;
+    assert!(stdout.contains("Warning: The assembled image is"));
+    assert!(stdout.contains("more than the limit of 1048576 bytes"));
+
+    let output1 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![&generation_link],
+        args(true),
+    )?;
+    assert!(!output1.status.success());
+    assert!(String::from_utf8(output1.stderr)?.contains("more than the limit of 1048576 bytes"));
+
+    Ok(())
+}
@@ -0,0 +1,74 @@
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn install_symlinked_kernel() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let kernel = bootspec_path(&generation_link, "kernel")?;
+    let kernel_link = tmpdir.path().join("kernel-link");
+    symlink(&kernel, &kernel_link)?;
+    set_bootspec_path(&generation_link, "kernel", &kernel_link)?;
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), vec![generation_link])?;
+    assert!(output0.status.success());
+
+    let installed_kernel = fs::read_dir(esp_mountpoint.path().join("EFI/nixos"))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .find(|path| path.to_string_lossy().ends_with("-bzImage.efi"))
+        .context("Kernel was not installed")?;
+    assert_eq!(
+        common::text_section(&installed_kernel)?,
+        common::text_section(&kernel)?
+    );
+
+    Ok(())
+}
+
+#[test]
+fn reject_dangling_kernel_symlink() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let kernel_link = tmpdir.path().join("kernel-link");
+    symlink(tmpdir.path().join("does-not-exist"), &kernel_link)?;
+    set_bootspec_path(&generation_link, "kernel", &kernel_link)?;
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), vec![generation_link])?;
+    assert!(!output0.status.success());
+    assert!(String::from_utf8(output0.stderr)?.contains("dangling symlink"));
+
+    Ok(())
+}
+
+fn bootspec_path(generation_link: &Path, key: &str) -> Result<PathBuf> {
+    let bootspec: serde_json::Value =
+        serde_json::from_slice(&fs::read(generation_link.join("boot.json"))?)?;
+    bootspec["v1"][key]
+        .as_str()
+        .map(PathBuf::from)
+        .with_context(|| format!("Bootspec has no {key}"))
+}
+
+fn set_bootspec_path(generation_link: &Path, key: &str, path: &Path) -> Result<()> {
+    let bootspec_path = generation_link.join("boot.json");
+    let mut bootspec: serde_json::Value = serde_json::from_slice(&fs::read(&bootspec_path)?)?;
+    bootspec["v1"][key] = serde_json::json!(path);
+    fs::write(bootspec_path, serde_json::to_vec(&bootspec)?)?;
+    Ok(())
+}
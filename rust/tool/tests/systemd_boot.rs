@@ -0,0 +1,93 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn refuse_to_install_stub_as_systemd_boot() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    // Build a lanzaboote image by installing the generation once.
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), vec![&generation_link])?;
+    assert!(output0.status.success());
+    let stub = esp_mountpoint
+        .path()
+        .join("EFI/Linux/nixos-generation-1.efi");
+
+    // Replace systemd-boot in the toplevel with this image.
+    let bootspec: serde_json::Value =
+        serde_json::from_slice(&fs::read(generation_link.join("boot.json"))?)?;
+    let toplevel = PathBuf::from(
+        bootspec["v1"]["toplevel"]
+            .as_str()
+            .expect("Missing toplevel in bootspec"),
+    );
+    let systemd = toplevel.join("systemd");
+    fs::remove_file(&systemd)?;
+    let boot_efi = systemd.join("lib/systemd/boot/efi");
+    fs::create_dir_all(&boot_efi)?;
+    fs::copy(stub, boot_efi.join("systemd-bootx64.efi"))?;
+
+    let fresh_esp_mountpoint = tempdir()?;
+    let output1 =
+        common::lanzaboote_install(0, fresh_esp_mountpoint.path(), vec![generation_link])?;
+    assert!(!output1.status.success());
+    let stderr = String::from_utf8(output1.stderr)?;
+    assert!(stderr.contains("Is it a lanzaboote stub instead of systemd-boot?"));
+
+    Ok(())
+}
+
+#[test]
+fn install_custom_systemd_boot() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    // Any PE binary that is not a lanzaboote stub serves as a custom systemd-boot build.
+    let test_systemd = common::systemd_location_from_env()?;
+    let custom_systemd_boot = PathBuf::from(format!(
+        "{test_systemd}/lib/systemd/boot/efi/linuxx64.efi.stub"
+    ));
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        [
+            OsStr::new("--systemd-boot"),
+            custom_systemd_boot.as_os_str(),
+        ],
+    )?;
+    assert!(output0.status.success());
+
+    let custom_data = fs::read(&custom_systemd_boot)?;
+    for path in ["EFI/systemd/systemd-bootx64.efi", "EFI/BOOT/BOOTX64.EFI"] {
+        let installed_data = fs::read(esp_mountpoint.path().join(path))?;
+        assert_eq!(
+            common::pe_section(&installed_data, ".text"),
+            common::pe_section(&custom_data, ".text"),
+            "{path} is not the custom systemd-boot"
+        );
+
+        let installed_pe = goblin::pe::PE::parse(&installed_data)?;
+        let signed = installed_pe
+            .header
+            .optional_header
+            .map(|header| header.data_directories.get_certificate_table().is_some())
+            .unwrap_or_default();
+        assert!(signed, "{path} is not signed");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,45 @@
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn install_verbatim_binary_without_signing_it() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let test_systemd = common::systemd_location_from_env()?;
+    let systemd_boot = format!("{test_systemd}/lib/systemd/boot/efi/systemd-bootx64.efi");
+    // Any PE binary serves as a stand-in for a shim that is already signed.
+    let shim = format!("{test_systemd}/lib/systemd/boot/efi/linuxx64.efi.stub");
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        [
+            String::from("--fallback-loader"),
+            format!("aa64={shim}"),
+            String::from("--install-verbatim"),
+            shim.clone(),
+        ],
+    )?;
+    assert!(output0.status.success());
+
+    let esp = esp_mountpoint.path();
+    assert_eq!(
+        fs::read(esp.join("EFI/BOOT/BOOTAA64.EFI"))?,
+        fs::read(&shim)?
+    );
+    assert_ne!(
+        fs::read(esp.join("EFI/systemd/systemd-bootx64.efi"))?,
+        fs::read(&systemd_boot)?
+    );
+
+    Ok(())
+}
@@ -0,0 +1,156 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn detect_kernel_that_does_not_match_the_stub() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), vec![generation_link])?;
+    assert!(output0.status.success());
+
+    let output1 = common::lanzaboote_verify(esp_mountpoint.path())?;
+    assert!(output1.status.success());
+
+    let kernel = fs::read_dir(esp_mountpoint.path().join("EFI/nixos"))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .find(|path| path.to_string_lossy().ends_with("-bzImage.efi"))
+        .expect("Kernel was not installed");
+    let mut data = fs::read(&kernel)?;
+    data.push(0);
+    fs::write(&kernel, data)?;
+
+    let output2 = common::lanzaboote_verify(esp_mountpoint.path())?;
+    assert!(!output2.status.success());
+    let stdout = String::from_utf8(output2.stdout)?;
+    assert!(stdout.contains("does not match the hash embedded into the stub"));
+
+    Ok(())
+}
+
+#[test]
+fn parallel_verification_matches_serial_verification() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links = (1..=4)
+        .map(|version| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), version)
+                .expect("Failed to setup generation link")
+        })
+        .collect::<Vec<_>>();
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), generation_links)?;
+    assert!(output0.status.success());
+
+    let serial = lanzaboote_verify_with_concurrency(esp_mountpoint.path(), 1)?;
+    let parallel = lanzaboote_verify_with_concurrency(esp_mountpoint.path(), 3)?;
+    assert!(serial.status.success());
+    assert!(parallel.status.success());
+    assert_eq!(serial.stdout, parallel.stdout);
+    // systemd-boot, the fallback boot loader and the four stubs.
+    assert_eq!(String::from_utf8(serial.stdout)?.lines().count(), 6);
+
+    Ok(())
+}
+
+#[test]
+fn flag_default_entry_of_pruned_generation() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links = [1, 2]
+        .into_iter()
+        .map(|version| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), version)
+                .expect("Failed to setup generation link")
+        })
+        .collect::<Vec<_>>();
+    let loader_conf = esp_mountpoint.path().join("loader/loader.conf");
+
+    let output0 = common::lanzaboote_install(1, esp_mountpoint.path(), generation_links)?;
+    assert!(output0.status.success());
+
+    fs::write(&loader_conf, "default nixos-generation-2.efi\n")?;
+    let output1 = Command::cargo_bin("lzbt")?
+        .arg("verify")
+        .arg("--public-key")
+        .arg("tests/fixtures/uefi-keys/db.pem")
+        .arg(esp_mountpoint.path())
+        .output()?;
+    assert!(output1.status.success());
+    assert!(String::from_utf8(output1.stdout)?.contains("Default entry nixos-generation-2.efi: OK"));
+
+    fs::write(&loader_conf, "default nixos-generation-1.efi\n")?;
+    let output2 = common::lanzaboote_verify(esp_mountpoint.path())?;
+    assert!(!output2.status.success());
+    let stdout = String::from_utf8(output2.stdout)?;
+    assert!(stdout.contains("Default entry nixos-generation-1.efi"));
+    assert!(stdout.contains("does not exist"));
+
+    Ok(())
+}
+
+#[test]
+fn detect_unsigned_boot_loader() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), vec![generation_link])?;
+    assert!(output0.status.success());
+
+    let test_systemd = common::systemd_location_from_env()?;
+    let fallback = esp_mountpoint.path().join("EFI/BOOT/BOOTX64.EFI");
+    fs::copy(
+        format!("{test_systemd}/lib/systemd/boot/efi/systemd-bootx64.efi"),
+        &fallback,
+    )?;
+
+    let output1 = Command::cargo_bin("lzbt")?
+        .arg("verify")
+        .arg("--public-key")
+        .arg("tests/fixtures/uefi-keys/db.pem")
+        .arg("--json")
+        .arg(esp_mountpoint.path())
+        .output()?;
+    assert!(!output1.status.success());
+    let report: serde_json::Value = serde_json::from_slice(&output1.stdout)?;
+    let loaders = report["loaders"].as_array().expect("Missing boot loaders");
+    assert_eq!(loaders.len(), 2);
+    for loader in loaders {
+        let unsigned = loader["loader"].as_str() == fallback.to_str();
+        assert_eq!(loader["error"].is_string(), unsigned);
+    }
+    assert!(report["stubs"][0]["error"].is_null());
+
+    Ok(())
+}
+
+fn lanzaboote_verify_with_concurrency(
+    esp_mountpoint: &Path,
+    concurrency: usize,
+) -> Result<std::process::Output> {
+    let output = Command::cargo_bin("lzbt")?
+        .arg("verify")
+        .arg("--concurrency")
+        .arg(concurrency.to_string())
+        .arg(esp_mountpoint)
+        .output()?;
+    print!("{}", String::from_utf8_lossy(&output.stdout));
+    print!("{}", String::from_utf8_lossy(&output.stderr));
+    Ok(output)
+}
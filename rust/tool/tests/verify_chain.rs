@@ -0,0 +1,146 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command as StdCommand, Output};
+
+use anyhow::Result;
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+mod common;
+
+use common::{EFI_CERT_X509_GUID, IMAGE_SECURITY_DATABASE};
+
+const PUBLIC_KEY: &str = "tests/fixtures/uefi-keys/db.pem";
+
+/// Write a signature database `db` that contains only the test certificate and no `dbx`.
+fn enroll_test_certificate(efivars: &Path) -> Result<()> {
+    let certificate = common::certificate_der(Path::new(PUBLIC_KEY))?;
+    common::write_db(efivars, &[&certificate])?;
+    match fs::remove_file(efivars.join(format!("dbx-{IMAGE_SECURITY_DATABASE}"))) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+fn lanzaboote_verify_chain(esp_mountpoint: &Path, efivars: &Path) -> Result<Output> {
+    let output = Command::cargo_bin("lzbt")?
+        .arg("verify-chain")
+        .arg("--efivars")
+        .arg(efivars)
+        .arg("--public-key")
+        .arg(PUBLIC_KEY)
+        .arg(esp_mountpoint)
+        .output()?;
+    print!("{}", String::from_utf8_lossy(&output.stdout));
+    print!("{}", String::from_utf8_lossy(&output.stderr));
+    Ok(output)
+}
+
+/// The lines of the report of links at which the chain is broken.
+fn broken_links(output: &Output) -> Result<Vec<String>> {
+    Ok(String::from_utf8(output.stdout.clone())?
+        .lines()
+        .filter(|line| !line.ends_with(": OK"))
+        .map(String::from)
+        .collect())
+}
+
+#[test]
+fn report_every_broken_link_of_the_chain() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), vec![generation_link])?;
+    assert!(output0.status.success());
+
+    let efivars = tempdir()?;
+    enroll_test_certificate(efivars.path())?;
+
+    let esp = esp_mountpoint.path().canonicalize()?;
+    let systemd_boot = esp.join("EFI/systemd/systemd-bootx64.efi");
+    let fallback = esp.join("EFI/BOOT/BOOTX64.EFI");
+    let stub = esp.join("EFI/Linux/nixos-generation-1.efi");
+    let kernel = fs::read_dir(esp.join("EFI/nixos"))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<PathBuf>, _>>()?
+        .into_iter()
+        .find(|path| path.to_string_lossy().ends_with("-kernel.efi"))
+        .expect("Kernel was not installed");
+
+    // The complete chain holds.
+    let output1 = lanzaboote_verify_chain(&esp, efivars.path())?;
+    assert!(output1.status.success());
+    assert!(broken_links(&output1)?.is_empty());
+    let stdout = String::from_utf8(output1.stdout)?;
+    assert!(stdout.contains("Firmware db: OK"));
+    assert!(stdout.contains("Firmware dbx: OK"));
+    assert!(stdout.contains(&format!("systemd-boot {}: OK", systemd_boot.display())));
+    assert!(stdout.contains(&format!("Fallback boot loader {}: OK", fallback.display())));
+    assert!(stdout.contains(&format!("Signature of {}: OK", stub.display())));
+    assert!(stdout.contains(&format!("Contents of {}: OK", stub.display())));
+
+    // Break one link after the other and restore it afterwards.
+    let unsigned = |path: &Path| -> Result<()> {
+        let status = StdCommand::new("sbattach")
+            .arg("--remove")
+            .arg(path)
+            .status()?;
+        assert!(status.success());
+        Ok(())
+    };
+    let breaks: [(&str, &Path, &dyn Fn(&Path) -> Result<()>); 6] = [
+        ("Firmware db", efivars.path(), &|efivars| {
+            fs::remove_file(efivars.join(format!("db-{IMAGE_SECURITY_DATABASE}")))?;
+            Ok(())
+        }),
+        ("Firmware dbx", efivars.path(), &|efivars| {
+            let certificate = common::certificate_der(Path::new(PUBLIC_KEY))?;
+            common::write_efi_variable(
+                efivars,
+                "dbx",
+                IMAGE_SECURITY_DATABASE,
+                &common::signature_list(EFI_CERT_X509_GUID, &certificate),
+            )
+        }),
+        ("systemd-boot", systemd_boot.as_path(), &unsigned),
+        ("Fallback boot loader", fallback.as_path(), &unsigned),
+        ("Signature of", stub.as_path(), &unsigned),
+        ("Contents of", kernel.as_path(), &|kernel| {
+            let mut data = fs::read(kernel)?;
+            data.push(0);
+            fs::write(kernel, data)?;
+            Ok(())
+        }),
+    ];
+    for (link, target, break_link) in breaks {
+        let backup = tmpdir.path().join("backup");
+        if target.is_file() {
+            fs::copy(target, &backup)?;
+        }
+        break_link(target)?;
+
+        let output = lanzaboote_verify_chain(&esp, efivars.path())?;
+        assert!(!output.status.success());
+        let broken = broken_links(&output)?;
+        assert_eq!(
+            broken.len(),
+            1,
+            "Expected only {link} to be broken: {broken:?}"
+        );
+        assert!(
+            broken[0].starts_with(link),
+            "{link} is not broken: {broken:?}"
+        );
+
+        if target.is_dir() {
+            enroll_test_certificate(target)?;
+        } else {
+            fs::copy(&backup, target)?;
+        }
+    }
+
+    Ok(())
+}
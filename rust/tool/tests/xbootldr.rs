@@ -0,0 +1,75 @@
+use std::ffi::OsStr;
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn split_installation_between_esp_and_xbootldr() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let xbootldr_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        [
+            OsStr::new("--xbootldr"),
+            xbootldr_mountpoint.path().as_os_str(),
+        ],
+    )?;
+    assert!(output0.status.success());
+
+    // The boot loader and its configuration stay on the ESP.
+    let esp = esp_mountpoint.path();
+    assert!(esp.join("EFI/systemd/systemd-bootx64.efi").exists());
+    assert!(esp.join("EFI/BOOT/BOOTX64.EFI").exists());
+    assert!(esp.join("loader/random-seed").exists());
+    assert!(!esp.join("EFI/Linux").exists());
+    assert!(!esp.join("EFI/nixos").exists());
+
+    // The kernels, initrds and stubs are installed to the XBOOTLDR partition.
+    let xbootldr = xbootldr_mountpoint.path();
+    let stub_data = fs::read(xbootldr.join("EFI/Linux/nixos-generation-1.efi"))?;
+    assert!(xbootldr.join("EFI/nixos/manifest.json").exists());
+
+    // The stub references the kernel relative to the partition it is installed to.
+    let kernel_path = common::pe_section(&stub_data, ".kernelp").expect("Missing .kernelp");
+    let kernel_path = String::from_utf8_lossy(kernel_path);
+    assert!(kernel_path.starts_with("\\EFI\\nixos\\"));
+    assert!(xbootldr
+        .join(kernel_path.trim_start_matches('\\').replace('\\', "/"))
+        .exists());
+
+    Ok(())
+}
+
+#[test]
+fn refuse_missing_xbootldr() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+    // E.g. the partition is not mounted and the mountpoint does not exist.
+    let xbootldr = tmpdir.path().join("boot");
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        [OsStr::new("--xbootldr"), xbootldr.as_os_str()],
+    )?;
+    assert!(!output0.status.success());
+    assert!(String::from_utf8(output0.stderr)?.contains("Failed to open XBOOTLDR partition"));
+    assert!(!xbootldr.exists());
+    assert!(!esp_mountpoint.path().join("EFI/Linux").exists());
+
+    Ok(())
+}